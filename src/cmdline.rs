@@ -0,0 +1,122 @@
+//! Kernel command line given by the bootloader: space separated `key=value` parameters and flags.
+//! Double quotes keep spaces in a value, `key="a b"`. Config keys given there override the
+//! config file, and a few parameters are read by the kernel itself:
+//! - `loglevel=` sets `kernel.loglevel`
+//! - `root=` is the device of the system partition
+//! - `init=` is the path of sysinit
+//! - `nosmp` is accepted, only the boot CPU is started anyway
+
+use alloc::{string::String, vec::Vec};
+
+use crate::{data::assign_once::AssignOnce, obsiboot::MAX_BOOT_STRING_LEN};
+
+/// Longer command lines are cut
+pub const MAX_COMMAND_LINE_LEN: usize = MAX_BOOT_STRING_LEN;
+/// Parameters after this many are ignored
+pub const MAX_COMMAND_LINE_PARAMETERS: usize = 128;
+
+/// Command line parameters that are shorthands for config keys
+pub const CONFIG_ALIASES: &[(&str, &str)] = &[
+    ("loglevel", "kernel.loglevel"),
+    ("keymap", "keyboard.keymap"),
+];
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct KernelCommandLine {
+    /// As given, cut to `MAX_COMMAND_LINE_LEN`
+    raw: String,
+    /// `None` value for flags
+    parameters: Vec<(String, Option<String>)>,
+}
+
+impl KernelCommandLine {
+    pub fn parse(text: &str) -> Self {
+        let mut end = text.len().min(MAX_COMMAND_LINE_LEN);
+        while !text.is_char_boundary(end) {
+            end -= 1;
+        }
+        let raw = text[..end].trim();
+
+        let mut parameters = Vec::new();
+        let mut chars = raw.chars().peekable();
+        while parameters.len() < MAX_COMMAND_LINE_PARAMETERS {
+            while chars.next_if(|c| c.is_whitespace()).is_some() {}
+            if chars.peek().is_none() {
+                break;
+            }
+            let mut key = String::new();
+            let mut value = None::<String>;
+            let mut quoted = false;
+            while let Some(c) = chars.next_if(|c| quoted || !c.is_whitespace()) {
+                match (c, value.as_mut()) {
+                    ('"', _) => quoted = !quoted,
+                    ('=', None) if !quoted => value = Some(String::new()),
+                    (c, Some(value)) => value.push(c),
+                    (c, None) => key.push(c),
+                }
+            }
+            if !key.is_empty() {
+                parameters.push((key, value));
+            }
+        }
+        Self {
+            raw: String::from(raw),
+            parameters,
+        }
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.raw
+    }
+
+    /// Value of the last `key=value`, empty for a flag
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.parameters
+            .iter()
+            .rev()
+            .find(|(name, _)| name == key)
+            .map(|(_, value)| value.as_deref().unwrap_or(""))
+    }
+
+    pub fn has_flag(&self, flag: &str) -> bool {
+        self.parameters
+            .iter()
+            .any(|(name, value)| name == flag && value.is_none())
+    }
+
+    /// The `key=value` parameters, in order
+    pub fn values(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.parameters
+            .iter()
+            .filter_map(|(name, value)| Some((name.as_str(), value.as_deref()?)))
+    }
+}
+
+static KERNEL_COMMAND_LINE: AssignOnce<KernelCommandLine> = AssignOnce::new();
+
+pub fn init_command_line(text: &str) {
+    KERNEL_COMMAND_LINE.set(KernelCommandLine::parse(text));
+}
+
+/// Empty if the bootloader didn't give one
+pub fn get_command_line() -> &'static KernelCommandLine {
+    static EMPTY: KernelCommandLine = KernelCommandLine {
+        raw: String::new(),
+        parameters: Vec::new(),
+    };
+    KERNEL_COMMAND_LINE.get().unwrap_or(&EMPTY)
+}
+
+/// Device of the system partition, `root=`
+pub fn root_device() -> Option<&'static str> {
+    get_command_line()
+        .get("root")
+        .filter(|root| !root.is_empty())
+}
+
+/// Path of sysinit, `init=`
+pub fn init_path() -> Option<&'static str> {
+    get_command_line()
+        .get("init")
+        .filter(|init| init.starts_with('/'))
+}
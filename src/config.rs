@@ -1,60 +1,376 @@
-use alloc::string::String;
-use serde::{Deserialize, Serialize};
+use alloc::{string::String, vec::Vec};
+use spin::Once;
 
 use crate::{
-    data::{alloc_boxed_slice, file::File, permissions::Permissions},
+    cmdline::{get_command_line, KernelCommandLine, CONFIG_ALIASES},
+    data::{
+        alloc_boxed_slice,
+        file::File,
+        permissions::{Credentials, Permissions},
+    },
     drivers::vfs::OPEN_MODE_READ,
+    log_info, log_warn,
 };
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct KernelBaseConfig {
-    pub kernel_log_file: String,
-    pub sysinit_stdout: String,
-    pub sysinit_stderr: String,
+pub const KERNEL_CONFIG_PATH: &str = "/system/campix.cfg";
+pub const MAX_BASE_CONFIG_SIZE: u64 = 4096;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConfigValueKind {
+    String,
+    Bool,
+    U64,
+    /// Absolute path, must start with '/'
+    Path,
 }
 
-pub const MAX_BASE_CONFIG_SIZE: u64 = 4096;
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConfigValue {
+    String(String),
+    Bool(bool),
+    U64(u64),
+    Path(String),
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct ConfigKey {
+    pub name: &'static str,
+    pub kind: ConfigValueKind,
+    /// Parsed like a value from the config file
+    pub default: &'static str,
+}
 
-static mut KERNEL_CONFIG: Option<KernelBaseConfig> = None;
+impl ConfigValueKind {
+    pub fn parse(&self, value: &str) -> Option<ConfigValue> {
+        match self {
+            Self::String => Some(ConfigValue::String(String::from(unquote(value)))),
+            Self::Bool => match value {
+                "true" | "yes" | "on" | "1" => Some(ConfigValue::Bool(true)),
+                "false" | "no" | "off" | "0" => Some(ConfigValue::Bool(false)),
+                _ => None,
+            },
+            Self::U64 => match value.strip_prefix("0x") {
+                Some(hex) => u64::from_str_radix(hex, 16).ok(),
+                None => value.parse().ok(),
+            }
+            .map(ConfigValue::U64),
+            Self::Path => {
+                let path = unquote(value);
+                path.starts_with('/')
+                    .then(|| ConfigValue::Path(String::from(path)))
+            }
+        }
+    }
 
-pub fn init_kernel_config() {
-    let Some(stats) = File::get_stats("/system/etc/base").unwrap() else {
-        panic!("Kernel base config at /system/etc/base not found !");
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::String => "string",
+            Self::Bool => "bool",
+            Self::U64 => "integer",
+            Self::Path => "path",
+        }
+    }
+}
+
+fn unquote(value: &str) -> &str {
+    value
+        .strip_prefix('"')
+        .and_then(|value| value.strip_suffix('"'))
+        .unwrap_or(value)
+}
+
+trait FromConfigValue {
+    fn from_config_value(value: &ConfigValue) -> Self;
+}
+
+impl FromConfigValue for String {
+    fn from_config_value(value: &ConfigValue) -> Self {
+        match value {
+            ConfigValue::String(s) | ConfigValue::Path(s) => s.clone(),
+            _ => unreachable!(),
+        }
+    }
+}
+
+impl FromConfigValue for bool {
+    fn from_config_value(value: &ConfigValue) -> Self {
+        match value {
+            ConfigValue::Bool(b) => *b,
+            _ => unreachable!(),
+        }
+    }
+}
+
+impl FromConfigValue for u64 {
+    fn from_config_value(value: &ConfigValue) -> Self {
+        match value {
+            ConfigValue::U64(n) => *n,
+            _ => unreachable!(),
+        }
+    }
+}
+
+macro_rules! config_field_type {
+    (String) => {
+        String
     };
-    if stats.size > MAX_BASE_CONFIG_SIZE {
-        panic!("Kernel base config at /system/etc/base too big !");
+    (Path) => {
+        String
+    };
+    (Bool) => {
+        bool
+    };
+    (U64) => {
+        u64
+    };
+}
+
+/// Declares a config key together with its `KernelBaseConfig` field and default value
+macro_rules! kernel_config_schema {
+    ($($(#[$meta:meta])* $field:ident: $kind:ident = $name:literal, default $default:literal;)*) => {
+        pub const KERNEL_CONFIG_SCHEMA: &[ConfigKey] = &[$(ConfigKey {
+            name: $name,
+            kind: ConfigValueKind::$kind,
+            default: $default,
+        }),*];
+
+        #[derive(Debug, Clone)]
+        pub struct KernelBaseConfig {
+            $($(#[$meta])* pub $field: config_field_type!($kind),)*
+            /// Parallel to `KERNEL_CONFIG_SCHEMA`
+            values: Vec<ConfigValue>,
+        }
+
+        impl KernelBaseConfig {
+            fn from_values(values: Vec<ConfigValue>) -> Self {
+                Self {
+                    $($field: FromConfigValue::from_config_value(
+                        &values[schema_index($name).unwrap()],
+                    ),)*
+                    values,
+                }
+            }
+        }
+    };
+}
+
+kernel_config_schema! {
+    /// Falls back to lpt1 in debug builds (/dev/null otherwise) if it doesn't exist
+    kernel_log_file: Path = "kernel.log_file", default "/system/kernel.log";
+    sysinit_stdout: Path = "sysinit.stdout", default "/dev/null";
+    sysinit_stderr: Path = "sysinit.stderr", default "/dev/null";
+    /// Default level of the module log filter, see /dev/loglevel
+    log_level: String = "kernel.loglevel", default "info";
+    /// How long learned ARP neighbors stay valid
+    arp_cache_timeout_ms: U64 = "net.arp_cache_timeout_ms", default "60000";
+    /// Size in bytes the kernel log ring may grow to, older records are dropped past it
+    log_buffer_limit: U64 = "kernel.log_buffer_limit", default "0x40000";
+    /// Level of the records written to /dev/kmsg without a "<N>" prefix
+    kmsg_write_level: U64 = "kmsg.write_level", default "6";
+    /// Run the boot self tests before sysinit, debug builds always run them
+    ktest_enabled: Bool = "ktest.enabled", default "false";
+    /// Exit qemu through isa-debug-exit once the self tests are done
+    ktest_exit_qemu: Bool = "ktest.exit_qemu", default "false";
+    /// Overflow the kernel stack after the self tests to check the fault dump, debug builds only
+    ktest_stack_overflow: Bool = "ktest.stack_overflow", default "false";
+    /// Block device the self tests may overwrite, like a sparse qemu disk of more than 16 GiB
+    ktest_scratch_disk: String = "ktest.scratch_disk", default "";
+    /// Block device holding an image made by `mke2fs -t ext2 -b 1024 -O sparse_super`, the self
+    /// tests compare its layout with the one of the ext2 driver
+    ktest_mke2fs_image: String = "ktest.mke2fs_image", default "";
+    /// Port of qemu's isa-debug-exit device
+    qemu_exit_port: U64 = "qemu.exit_port", default "0xf4";
+    /// Exit qemu through isa-debug-exit on kernel panics
+    panic_exits_qemu: Bool = "panic.exit_qemu", default "false";
+    /// Try to reset the machine on kernel panics instead of halting
+    reboot_on_panic: Bool = "panic.reboot", default "false";
+    /// When off, new processes can only make syscalls through int 0x80
+    fast_syscalls: Bool = "syscall.fast", default "true";
+    /// Log the cycles of getpid round trips, per syscall entry
+    syscall_benchmark: Bool = "syscall.benchmark", default "false";
+    /// Most memory the loaded segments of an executable may take, in bytes
+    exec_max_image_size: U64 = "exec.max_image_size", default "0x10000000";
+    /// Size of /dev/ram0 in bytes, 0 for no ram disk
+    ramdisk_size: U64 = "ramdisk.size", default "0";
+    /// Keymap loaded at boot, see /dev/keymap. Empty for the built-in US layout
+    keymap: String = "keyboard.keymap", default "";
+}
+
+fn schema_index(name: &str) -> Option<usize> {
+    KERNEL_CONFIG_SCHEMA.iter().position(|key| key.name == name)
+}
+
+impl KernelBaseConfig {
+    pub fn defaults() -> Self {
+        Self::from_values(
+            KERNEL_CONFIG_SCHEMA
+                .iter()
+                .map(|key| {
+                    key.kind
+                        .parse(key.default)
+                        .unwrap_or_else(|| panic!("Bad default for config key {}", key.name))
+                })
+                .collect(),
+        )
     }
 
-    let base_file =
-        File::open("/system/etc/base", OPEN_MODE_READ, Permissions::from_u64(0)).unwrap();
+    /// Parses `key = value` lines, '#' starts a comment line.
+    /// Bad lines and unknown keys are reported and skipped, their keys keep the default
+    pub fn parse(source: &str, text: &str) -> Self {
+        let mut config = Self::defaults();
+        for (index, line) in text.lines().enumerate() {
+            let line_number = index + 1;
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let Some((name, value)) = line.split_once('=') else {
+                log_warn!(target: "config", "{}:{}: expected `key = value`", source, line_number);
+                continue;
+            };
+            let (name, value) = (name.trim(), value.trim());
+            let Some(index) = schema_index(name) else {
+                log_warn!(target: "config", "{}:{}: unknown key {}", source, line_number, name);
+                continue;
+            };
+            let kind = KERNEL_CONFIG_SCHEMA[index].kind;
+            match kind.parse(value) {
+                Some(value) => config.values[index] = value,
+                None => log_warn!(
+                    target: "config",
+                    "{}:{}: {} expects a {} value, got {}",
+                    source,
+                    line_number,
+                    name,
+                    kind.name(),
+                    value
+                ),
+            }
+        }
+        Self::from_values(config.values)
+    }
 
-    let mut buffer = alloc_boxed_slice(stats.size as usize);
+    /// Applies the `key=value` parameters of the command line naming config keys, or their
+    /// aliases. Bad values are reported and skipped, other parameters are left to their users
+    pub fn apply_command_line(self, command_line: &KernelCommandLine) -> Self {
+        let mut values = self.values;
+        for (name, value) in command_line.values() {
+            let name = CONFIG_ALIASES
+                .iter()
+                .find(|(alias, _)| *alias == name)
+                .map_or(name, |(_, key)| *key);
+            let Some(index) = schema_index(name) else {
+                continue;
+            };
+            let kind = KERNEL_CONFIG_SCHEMA[index].kind;
+            match kind.parse(value) {
+                Some(value) => values[index] = value,
+                None => log_warn!(
+                    target: "config",
+                    "command line: {} expects a {} value, got {}",
+                    name,
+                    kind.name(),
+                    value
+                ),
+            }
+        }
+        Self::from_values(values)
+    }
+
+    pub fn get(&self, name: &str) -> Option<&ConfigValue> {
+        schema_index(name).map(|index| &self.values[index])
+    }
+
+    /// Works for string and path keys
+    pub fn get_str(&self, name: &str) -> Option<&str> {
+        match self.get(name)? {
+            ConfigValue::String(s) | ConfigValue::Path(s) => Some(s),
+            _ => None,
+        }
+    }
 
-    let read = base_file.read(&mut buffer).unwrap();
+    pub fn get_path(&self, name: &str) -> Option<&str> {
+        match self.get(name)? {
+            ConfigValue::Path(path) => Some(path),
+            _ => None,
+        }
+    }
 
-    if read != stats.size {
-        panic!(
-            "Failed to read kernel base config at /system/etc/base, read {} bytes instead of {}",
-            read, stats.size
-        );
+    pub fn get_bool(&self, name: &str) -> Option<bool> {
+        match self.get(name)? {
+            ConfigValue::Bool(b) => Some(*b),
+            _ => None,
+        }
     }
 
-    let config = match serde_json::from_slice(&buffer) {
-        Ok(config) => config,
+    pub fn get_u64(&self, name: &str) -> Option<u64> {
+        match self.get(name)? {
+            ConfigValue::U64(n) => Some(*n),
+            _ => None,
+        }
+    }
+}
+
+static KERNEL_CONFIG: Once<KernelBaseConfig> = Once::new();
+
+fn read_kernel_config_file() -> Option<String> {
+    let stats = match File::get_stats(KERNEL_CONFIG_PATH) {
+        Ok(Some(stats)) => stats,
+        Ok(None) => {
+            log_info!(target: "config", "{} not found, using defaults", KERNEL_CONFIG_PATH);
+            return None;
+        }
         Err(err) => {
-            panic!(
-                "Failed to parse kernel base config at /system/etc/base: {:#?}",
-                err
-            );
+            log_warn!(target: "config", "Failed to stat {}: {:?}", KERNEL_CONFIG_PATH, err);
+            return None;
         }
     };
+    if stats.size > MAX_BASE_CONFIG_SIZE {
+        log_warn!(target: "config", "{} is too big, using defaults", KERNEL_CONFIG_PATH);
+        return None;
+    }
 
-    unsafe {
-        KERNEL_CONFIG = Some(config);
+    let file = File::open(
+        KERNEL_CONFIG_PATH,
+        OPEN_MODE_READ,
+        Permissions::from_u64(0),
+        Credentials::Kernel,
+    )
+    .ok()?;
+    let mut buffer = alloc_boxed_slice(stats.size as usize);
+    match file.read(&mut buffer) {
+        Ok(read) if read == stats.size => {}
+        result => {
+            log_warn!(target: "config", "Failed to read {}: {:?}", KERNEL_CONFIG_PATH, result);
+            return None;
+        }
+    }
+
+    match String::from_utf8(buffer.into_vec()) {
+        Ok(text) => Some(text),
+        Err(_) => {
+            log_warn!(target: "config", "{} is not valid utf-8, using defaults", KERNEL_CONFIG_PATH);
+            None
+        }
     }
 }
 
-#[allow(static_mut_refs)]
+/// The config file, overridden by the command line
+pub fn init_kernel_config() {
+    let config = match read_kernel_config_file() {
+        Some(text) => KernelBaseConfig::parse(KERNEL_CONFIG_PATH, &text),
+        None => KernelBaseConfig::defaults(),
+    }
+    .apply_command_line(get_command_line());
+
+    KERNEL_CONFIG.call_once(|| config);
+}
+
 pub fn get_kernel_config() -> &'static KernelBaseConfig {
-    unsafe { KERNEL_CONFIG.as_ref().unwrap() }
+    KERNEL_CONFIG.get().unwrap()
+}
+
+/// `None` before `init_kernel_config`, for code that can run that early like the panic handler
+pub fn try_get_kernel_config() -> Option<&'static KernelBaseConfig> {
+    KERNEL_CONFIG.get()
 }
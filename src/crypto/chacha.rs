@@ -0,0 +1,120 @@
+pub const CHACHA_KEY_SIZE: usize = 32;
+pub const CHACHA_NONCE_SIZE: usize = 8;
+pub const CHACHA_BLOCK_SIZE: usize = 64;
+
+const CHACHA_CONSTANTS: [u32; 4] = [0x6170_7865, 0x3320_646e, 0x7962_2d32, 0x6b20_6574];
+const CHACHA_ROUNDS: usize = 20;
+
+#[inline(always)]
+fn quarter_round(state: &mut [u32; 16], a: usize, b: usize, c: usize, d: usize) {
+    state[a] = state[a].wrapping_add(state[b]);
+    state[d] = (state[d] ^ state[a]).rotate_left(16);
+    state[c] = state[c].wrapping_add(state[d]);
+    state[b] = (state[b] ^ state[c]).rotate_left(12);
+    state[a] = state[a].wrapping_add(state[b]);
+    state[d] = (state[d] ^ state[a]).rotate_left(8);
+    state[c] = state[c].wrapping_add(state[d]);
+    state[b] = (state[b] ^ state[c]).rotate_left(7);
+}
+
+/// ChaCha20 block function with a 64 bit block counter and a 64 bit nonce
+pub fn chacha20_block(
+    key: &[u8; CHACHA_KEY_SIZE],
+    nonce: &[u8; CHACHA_NONCE_SIZE],
+    counter: u64,
+    out: &mut [u8; CHACHA_BLOCK_SIZE],
+) {
+    let mut input = [0u32; 16];
+    input[0..4].copy_from_slice(&CHACHA_CONSTANTS);
+    for (i, word) in key.as_chunks::<4>().0.iter().enumerate() {
+        input[4 + i] = u32::from_le_bytes(*word);
+    }
+    input[12] = counter as u32;
+    input[13] = (counter >> 32) as u32;
+    input[14] = u32::from_le_bytes([nonce[0], nonce[1], nonce[2], nonce[3]]);
+    input[15] = u32::from_le_bytes([nonce[4], nonce[5], nonce[6], nonce[7]]);
+
+    let mut state = input;
+    for _ in 0..CHACHA_ROUNDS / 2 {
+        quarter_round(&mut state, 0, 4, 8, 12);
+        quarter_round(&mut state, 1, 5, 9, 13);
+        quarter_round(&mut state, 2, 6, 10, 14);
+        quarter_round(&mut state, 3, 7, 11, 15);
+        quarter_round(&mut state, 0, 5, 10, 15);
+        quarter_round(&mut state, 1, 6, 11, 12);
+        quarter_round(&mut state, 2, 7, 8, 13);
+        quarter_round(&mut state, 3, 4, 9, 14);
+    }
+
+    for (i, chunk) in out.as_chunks_mut::<4>().0.iter_mut().enumerate() {
+        chunk.copy_from_slice(&state[i].wrapping_add(input[i]).to_le_bytes());
+    }
+}
+
+/// Pseudo random generator producing the ChaCha20 keystream.
+/// After each call to `fill_bytes` the key is replaced with fresh keystream so that
+/// a leaked state doesn't reveal previous outputs.
+pub struct ChaCha20Rng {
+    key: [u8; CHACHA_KEY_SIZE],
+    nonce: [u8; CHACHA_NONCE_SIZE],
+    counter: u64,
+    block: [u8; CHACHA_BLOCK_SIZE],
+    block_pos: usize,
+}
+
+impl ChaCha20Rng {
+    pub fn new(key: [u8; CHACHA_KEY_SIZE], nonce: [u8; CHACHA_NONCE_SIZE]) -> Self {
+        Self {
+            key,
+            nonce,
+            counter: 0,
+            block: [0; CHACHA_BLOCK_SIZE],
+            block_pos: CHACHA_BLOCK_SIZE,
+        }
+    }
+
+    /// Mixes new key material into the current key
+    pub fn reseed(&mut self, seed: &[u8; CHACHA_KEY_SIZE]) {
+        let mut fresh = [0u8; CHACHA_KEY_SIZE];
+        self.fill_keystream(&mut fresh);
+        for (k, (f, s)) in self.key.iter_mut().zip(fresh.iter().zip(seed.iter())) {
+            *k = f ^ s;
+        }
+        self.counter = 0;
+        self.block_pos = CHACHA_BLOCK_SIZE;
+    }
+
+    fn fill_keystream(&mut self, buf: &mut [u8]) {
+        let mut written = 0;
+        while written < buf.len() {
+            if self.block_pos == CHACHA_BLOCK_SIZE {
+                chacha20_block(&self.key, &self.nonce, self.counter, &mut self.block);
+                self.counter = self.counter.wrapping_add(1);
+                self.block_pos = 0;
+            }
+            let n = (CHACHA_BLOCK_SIZE - self.block_pos).min(buf.len() - written);
+            buf[written..written + n]
+                .copy_from_slice(&self.block[self.block_pos..self.block_pos + n]);
+            // Don't keep used keystream around
+            self.block[self.block_pos..self.block_pos + n].fill(0);
+            self.block_pos += n;
+            written += n;
+        }
+    }
+
+    pub fn fill_bytes(&mut self, buf: &mut [u8]) {
+        self.fill_keystream(buf);
+
+        let mut next_key = [0u8; CHACHA_KEY_SIZE];
+        self.fill_keystream(&mut next_key);
+        self.key = next_key;
+        self.counter = 0;
+        self.block_pos = CHACHA_BLOCK_SIZE;
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        let mut bytes = [0u8; 8];
+        self.fill_bytes(&mut bytes);
+        u64::from_le_bytes(bytes)
+    }
+}
@@ -0,0 +1,58 @@
+use spin::Mutex;
+
+use crate::{
+    crypto::chacha::{ChaCha20Rng, CHACHA_KEY_SIZE, CHACHA_NONCE_SIZE},
+    memory::entropy,
+};
+
+/// Largest amount of bytes generated while holding the generator lock
+pub const RNG_CHUNK_SIZE: usize = 256;
+/// Amount of bytes generated before the generator gets reseeded from the entropy source
+pub const RNG_RESEED_INTERVAL: u64 = 1024 * 1024;
+
+struct KernelRng {
+    rng: ChaCha20Rng,
+    since_reseed: u64,
+}
+
+static KERNEL_RNG: Mutex<Option<KernelRng>> = Mutex::new(None);
+
+fn entropy_seed() -> [u8; CHACHA_KEY_SIZE] {
+    let mut seed = [0u8; CHACHA_KEY_SIZE];
+    entropy::fill_random(&mut seed);
+    seed
+}
+
+impl KernelRng {
+    fn new() -> Self {
+        let mut nonce = [0u8; CHACHA_NONCE_SIZE];
+        entropy::fill_random(&mut nonce);
+        Self {
+            rng: ChaCha20Rng::new(entropy_seed(), nonce),
+            since_reseed: 0,
+        }
+    }
+
+    fn fill(&mut self, buf: &mut [u8]) {
+        if self.since_reseed >= RNG_RESEED_INTERVAL {
+            self.rng.reseed(&entropy_seed());
+            self.since_reseed = 0;
+        }
+        self.rng.fill_bytes(buf);
+        self.since_reseed += buf.len() as u64;
+    }
+}
+
+/// Fills `buf` from the kernel ChaCha20 generator, never blocks
+pub fn fill_bytes(buf: &mut [u8]) {
+    for chunk in buf.chunks_mut(RNG_CHUNK_SIZE) {
+        let mut guard = KERNEL_RNG.lock();
+        guard.get_or_insert_with(KernelRng::new).fill(chunk);
+    }
+}
+
+pub fn next_u64() -> u64 {
+    let mut bytes = [0u8; 8];
+    fill_bytes(&mut bytes);
+    u64::from_le_bytes(bytes)
+}
@@ -1,32 +1,22 @@
-use core::cell::UnsafeCell;
-
-use spin::Mutex;
+use spin::Once;
 
+/// A value set once, then read without locking
 #[derive(Debug, Default)]
 pub struct AssignOnce<T> {
-    value: UnsafeCell<Option<T>>,
-    lock: Mutex<()>,
+    value: Once<T>,
 }
 
 impl<T> AssignOnce<T> {
     pub const fn new() -> Self {
-        Self {
-            value: UnsafeCell::new(None),
-            lock: Mutex::new(()),
-        }
+        Self { value: Once::new() }
     }
 
     pub fn get(&self) -> Option<&T> {
-        unsafe { self.value.as_ref_unchecked() }.as_ref()
+        self.value.get()
     }
 
+    /// Only the first value is kept
     pub fn set(&self, value: T) {
-        let guard = self.lock.lock();
-        unsafe {
-            *self.value.as_mut_unchecked() = Some(value);
-        }
-        drop(guard);
+        self.value.call_once(|| value);
     }
 }
-
-unsafe impl<T: Sync> Sync for AssignOnce<T> {}
@@ -89,6 +89,21 @@ impl Bitmap {
         self.data.fill(0);
     }
 
+    /// Counts the set bits with an index in `0..end`
+    pub fn count_set_until(&self, end: usize) -> usize {
+        let end = end.min(self.size);
+        let full_bytes = end / 8;
+        let mut count = self.data[0..full_bytes]
+            .iter()
+            .map(|b| b.count_ones() as usize)
+            .sum();
+        if !end.is_multiple_of(8) {
+            let mask = (1u8 << (end % 8)) - 1;
+            count += (self.data[full_bytes] & mask).count_ones() as usize;
+        }
+        count
+    }
+
     pub fn find_first_unset(&self) -> Option<usize> {
         let mut bit_index = 0;
 
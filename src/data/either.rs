@@ -27,7 +27,8 @@ impl<A, B> Either<A, B> {
         }
     }
 
-    pub fn convert<C, F1, F2>(self, f1: F1, f2: F2) -> C
+    /// Consumes the value through `f1` or `f2`, which return the same type
+    pub fn either<C, F1, F2>(self, f1: F1, f2: F2) -> C
     where
         F1: FnOnce(A) -> C,
         F2: FnOnce(B) -> C,
@@ -38,7 +39,16 @@ impl<A, B> Either<A, B> {
         }
     }
 
-    pub fn left_map<C, F>(self, f: F) -> Either<C, B>
+    /// Same as `either`
+    pub fn convert<C, F1, F2>(self, f1: F1, f2: F2) -> C
+    where
+        F1: FnOnce(A) -> C,
+        F2: FnOnce(B) -> C,
+    {
+        self.either(f1, f2)
+    }
+
+    pub fn map_left<C, F>(self, f: F) -> Either<C, B>
     where
         F: FnOnce(A) -> C,
     {
@@ -48,7 +58,7 @@ impl<A, B> Either<A, B> {
         }
     }
 
-    pub fn right_map<C, F>(self, f: F) -> Either<A, C>
+    pub fn map_right<C, F>(self, f: F) -> Either<A, C>
     where
         F: FnOnce(B) -> C,
     {
@@ -58,6 +68,74 @@ impl<A, B> Either<A, B> {
         }
     }
 
+    /// Same as `map_left`
+    pub fn left_map<C, F>(self, f: F) -> Either<C, B>
+    where
+        F: FnOnce(A) -> C,
+    {
+        self.map_left(f)
+    }
+
+    /// Same as `map_right`
+    pub fn right_map<C, F>(self, f: F) -> Either<A, C>
+    where
+        F: FnOnce(B) -> C,
+    {
+        self.map_right(f)
+    }
+
+    pub fn is_left(&self) -> bool {
+        matches!(self, Either::A(_))
+    }
+
+    pub fn is_right(&self) -> bool {
+        matches!(self, Either::B(_))
+    }
+
+    pub fn left(&self) -> Option<&A> {
+        match self {
+            Either::A(a) => Some(a),
+            Either::B(_) => None,
+        }
+    }
+
+    pub fn right(&self) -> Option<&B> {
+        match self {
+            Either::A(_) => None,
+            Either::B(b) => Some(b),
+        }
+    }
+
+    pub fn left_mut(&mut self) -> Option<&mut A> {
+        match self {
+            Either::A(a) => Some(a),
+            Either::B(_) => None,
+        }
+    }
+
+    pub fn right_mut(&mut self) -> Option<&mut B> {
+        match self {
+            Either::A(_) => None,
+            Either::B(b) => Some(b),
+        }
+    }
+
+    /// The left value, or the right one as the error
+    pub fn try_into_left(self) -> Result<A, B> {
+        match self {
+            Either::A(a) => Ok(a),
+            Either::B(b) => Err(b),
+        }
+    }
+
+    /// The right value, or the left one as the error
+    pub fn try_into_right(self) -> Result<B, A> {
+        match self {
+            Either::A(a) => Err(a),
+            Either::B(b) => Ok(b),
+        }
+    }
+
     pub fn transpose(self) -> Either<B, A> {
         match self {
             Either::A(a) => Either::B(a),
@@ -65,20 +143,30 @@ impl<A, B> Either<A, B> {
         }
     }
 
-    pub fn referenced(&self) -> Either<&A, &B> {
+    pub fn as_ref(&self) -> Either<&A, &B> {
         match self {
             Either::A(v) => Either::A(v),
             Either::B(v) => Either::B(v),
         }
     }
 
-    pub fn referenced_mut(&mut self) -> Either<&mut A, &mut B> {
+    pub fn as_mut(&mut self) -> Either<&mut A, &mut B> {
         match self {
             Either::A(v) => Either::A(v),
             Either::B(v) => Either::B(v),
         }
     }
 
+    /// Same as `as_ref`
+    pub fn referenced(&self) -> Either<&A, &B> {
+        self.as_ref()
+    }
+
+    /// Same as `as_mut`
+    pub fn referenced_mut(&mut self) -> Either<&mut A, &mut B> {
+        self.as_mut()
+    }
+
     pub fn get_left(self) -> Option<A> {
         match self {
             Either::A(v) => Some(v),
@@ -125,15 +213,41 @@ impl<A: PartialEq, B: PartialEq> PartialEq for Either<A, B> {
 
 impl<A: Eq, B: Eq> Eq for Either<A, B> {}
 
-impl<A, B> AsRef<Either<A, B>> for Either<A, B> {
-    fn as_ref(&self) -> &Either<A, B> {
-        self
+/// `Ok` is the left side
+impl<A, B> From<Result<A, B>> for Either<A, B> {
+    fn from(value: Result<A, B>) -> Self {
+        match value {
+            Ok(a) => Either::A(a),
+            Err(b) => Either::B(b),
+        }
     }
 }
 
-impl<A, B> AsMut<Either<A, B>> for Either<A, B> {
-    fn as_mut(&mut self) -> &mut Either<A, B> {
-        self
+impl<A, B> From<Either<A, B>> for Result<A, B> {
+    fn from(value: Either<A, B>) -> Self {
+        value.try_into_left()
+    }
+}
+
+impl<A, B> Iterator for Either<A, B>
+where
+    A: Iterator,
+    B: Iterator<Item = A::Item>,
+{
+    type Item = A::Item;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self {
+            Either::A(a) => a.next(),
+            Either::B(b) => b.next(),
+        }
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        match self {
+            Either::A(a) => a.size_hint(),
+            Either::B(b) => b.size_hint(),
+        }
     }
 }
 
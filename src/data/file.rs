@@ -1,12 +1,16 @@
-use core::fmt::Debug;
+use core::{fmt::Debug, mem::ManuallyDrop};
 
-use alloc::{string::String, vec::Vec};
+use alloc::vec::Vec;
 
 use crate::{
-    data::permissions::Permissions,
+    data::{
+        path::VfsPath,
+        permissions::{Credentials, Permissions},
+    },
     drivers::vfs::{
-        get_vfs, Arcrwb, FileStat, FileSystem, PathTraverse, SeekPosition, VfsError, VfsFile,
-        VfsFileKind,
+        close_path_handle, get_vfs, split_parent, Arcrwb, DeviceNumber, FileStat, FileSystem,
+        SeekPosition, Vfs, VfsError, VfsFile, VfsFileKind, OPEN_MODE_CREATE,
+        OPEN_MODE_FAIL_IF_EXISTS,
     },
 };
 
@@ -16,13 +20,15 @@ pub struct File {
     fs: Arcrwb<dyn FileSystem>,
     file: VfsFile,
     handle: u64,
+    /// Opened through `Vfs::open_path`, which counts it as open on its file system
+    path_handle: bool,
 }
 
 impl Debug for File {
     fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         f.debug_struct("File")
             .field("mode", &self.mode)
-            .field("path", &self.path.iter().collect::<String>())
+            .field("path", &VfsPath::from(&self.path))
             .field("handle", &self.handle)
             .finish()
     }
@@ -44,52 +50,53 @@ impl File {
             fs,
             file,
             handle,
+            path_handle: false,
         }
     }
 
-    // TODO: Add create_perms on FileSystem interface
-    pub fn open(path: &str, mode: u64, _create_perms: Permissions) -> Result<File, VfsError> {
-        let path = path.chars().collect::<Vec<char>>();
-        let fs = get_vfs();
-        let mut guard = fs.write();
-        let file = guard.get_file(&path)?;
-        let fs = guard
-            .get_fs_by_id(file.fs())
-            .ok_or(VfsError::FileSystemNotMounted)?;
-        drop(guard);
-        let mut guard = fs.write();
-        let handle = guard.fopen(&file, mode)?;
-        drop(guard);
+    pub fn open(
+        path: &str,
+        mode: u64,
+        create_perms: Permissions,
+        credentials: Credentials,
+    ) -> Result<File, VfsError> {
+        Self::open0(
+            VfsPath::from(path).to_chars(),
+            mode,
+            create_perms,
+            credentials,
+        )
+    }
 
+    pub fn open0(
+        path: Vec<char>,
+        mode: u64,
+        create_perms: Permissions,
+        credentials: Credentials,
+    ) -> Result<File, VfsError> {
+        let opened = Vfs::open_path(&get_vfs(), &path, mode, create_perms, credentials)?;
         Ok(File {
             mode,
             path,
-            fs,
-            file,
-            handle,
+            fs: opened.fs,
+            file: opened.file,
+            handle: opened.handle,
+            path_handle: true,
         })
     }
 
     pub fn open_raw(
         path: &[char],
         mode: u64,
-        _create_perms: Permissions,
+        create_perms: Permissions,
+        credentials: Credentials,
     ) -> Result<(Arcrwb<dyn FileSystem>, u64, VfsFile), VfsError> {
-        let fs = get_vfs();
-        let mut guard = fs.write();
-        let file = guard.get_file(path)?;
-        let fs = guard
-            .get_fs_by_id(file.fs())
-            .ok_or(VfsError::FileSystemNotMounted)?;
-        drop(guard);
-        let mut guard = fs.write();
-        let handle = guard.fopen(&file, mode)?;
-        drop(guard);
-        Ok((fs, handle, file))
+        let opened = Vfs::open_path(&get_vfs(), path, mode, create_perms, credentials)?;
+        Ok((opened.fs, opened.handle, opened.file))
     }
 
     pub fn get_stats(path: &str) -> Result<Option<FileStat>, VfsError> {
-        let path = path.chars().collect::<Vec<char>>();
+        let path = VfsPath::from(path).to_chars();
         let fs = get_vfs();
         let mut guard = fs.write();
         guard.get_stats(&path)
@@ -101,66 +108,139 @@ impl File {
         guard.get_stats(path)
     }
 
-    pub fn create(path: &str, mode: u64, _perms: Permissions) -> Result<File, VfsError> {
-        let path = path.chars().collect::<Vec<char>>();
-        let name_start = path
-            .iter()
-            .rposition(|c| *c == '/')
-            .ok_or(VfsError::InvalidArgument)?;
+    pub fn create(
+        path: &str,
+        mode: u64,
+        perms: Permissions,
+        credentials: Credentials,
+    ) -> Result<File, VfsError> {
+        let path = VfsPath::from(path).to_chars();
+        let (fs, handle, file) = Self::create_raw(&path, mode, perms, credentials)?;
+        Ok(File {
+            mode,
+            path,
+            fs,
+            file,
+            handle,
+            path_handle: true,
+        })
+    }
+
+    /// Creates and opens a new file owned by `credentials`, fails if it already exists
+    pub fn create_raw(
+        path: &[char],
+        mode: u64,
+        perms: Permissions,
+        credentials: Credentials,
+    ) -> Result<(Arcrwb<dyn FileSystem>, u64, VfsFile), VfsError> {
+        let mode = mode | OPEN_MODE_CREATE | OPEN_MODE_FAIL_IF_EXISTS;
+        let opened = Vfs::open_path(&get_vfs(), path, mode, perms, credentials)?;
+        Ok((opened.fs, opened.handle, opened.file))
+    }
+
+    pub fn delete(path: &str, credentials: Credentials) -> Result<(), VfsError> {
+        let path = VfsPath::from(path).to_chars();
+        Self::delete0(&path, credentials)
+    }
+
+    pub fn delete0(path: &[char], credentials: Credentials) -> Result<(), VfsError> {
+        Vfs::unlink_path(&get_vfs(), path, credentials)
+    }
 
-        let dirname = &path[..name_start];
-        let filename = &path[name_start + 1..];
-        if filename.is_empty() {
-            return Err(VfsError::InvalidArgument);
+    /// Deletes `path` and everything below it, without crossing into other file systems.
+    /// Fails before deleting anything if a file system is mounted below `path`
+    pub fn remove_dir_all(path: &str) -> Result<(), VfsError> {
+        let path = VfsPath::from(path).to_chars();
+        let vfs = get_vfs();
+        let entries = Vfs::walk(&vfs, &path)?
+            .max_depth(usize::MAX)
+            .cross_mounts(false)
+            .collect::<Result<Vec<_>, _>>()?;
+        if entries
+            .iter()
+            .skip(1)
+            .any(|(_, file)| file.is_mount_point())
+        {
+            return Err(VfsError::ActionNotAllowed);
+        }
+        // Children come after their parent
+        for (path, _) in entries.iter().rev() {
+            Vfs::unlink_path(&vfs, path, Credentials::Kernel)?;
         }
+        Ok(())
+    }
+
+    /// Creates `new_path` as another name of the file at `existing`, on the same file system
+    pub fn link0(
+        existing: &[char],
+        new_path: &[char],
+        credentials: Credentials,
+    ) -> Result<(), VfsError> {
+        let (dirname, filename) = split_parent(new_path).ok_or(VfsError::InvalidArgument)?;
+        let filename = VfsPath::from(filename);
+        let filename = filename.file_name().ok_or(VfsError::InvalidArgument)?;
 
         let fs = get_vfs();
         let mut guard = fs.write();
-
-        let directory = guard.get_file(dirname)?;
+        let file = guard.get_file(&VfsPath::from(existing))?;
+        let directory = guard.get_file(&VfsPath::from(dirname))?.resolve_mount()?;
+        guard.check_access(dirname, credentials, |stat| credentials.can_create_in(stat))?;
+        guard.check_writable(new_path)?;
+        if file.fs() != directory.fs() {
+            return Err(VfsError::FileSystemMismatch);
+        }
 
         let fs = guard
             .get_fs_by_id(directory.fs())
             .ok_or(VfsError::FileSystemNotMounted)?;
         drop(guard);
         let mut guard = fs.write();
-        // TODO: Use perms
-        let file = guard.create_child(&directory, filename, VfsFileKind::File)?;
-        let handle = guard.fopen(&file, mode)?;
+        guard.link(&file, &directory, filename)?;
         drop(guard);
-
-        Ok(File {
-            mode,
-            path,
-            fs,
-            file,
-            handle,
-        })
+        Ok(())
     }
 
-    pub fn delete(path: &str) -> Result<(), VfsError> {
-        let path = path.chars().collect::<Vec<char>>();
-        Self::delete0(&path)
-    }
+    /// Creates a device node at `path`, which opens the devfs device registered under `number`
+    pub fn mknod0(
+        path: &[char],
+        block: bool,
+        number: DeviceNumber,
+        credentials: Credentials,
+    ) -> Result<(), VfsError> {
+        let (dirname, filename) = split_parent(path).ok_or(VfsError::InvalidArgument)?;
+        let filename = VfsPath::from(filename);
+        let filename = filename.file_name().ok_or(VfsError::InvalidArgument)?;
 
-    pub fn delete0(path: &[char]) -> Result<(), VfsError> {
         let fs = get_vfs();
         let mut guard = fs.write();
-        let file = guard.get_file(path)?;
+        let directory = guard.get_file(&VfsPath::from(dirname))?.resolve_mount()?;
+        guard.check_access(dirname, credentials, |stat| credentials.can_create_in(stat))?;
+        guard.check_writable(path)?;
         let fs = guard
-            .get_fs_by_id(file.fs())
+            .get_fs_by_id(directory.fs())
             .ok_or(VfsError::FileSystemNotMounted)?;
         drop(guard);
+
         let mut guard = fs.write();
-        guard.delete_file(&file)?;
-        drop(guard);
+        match guard.get_child(&directory, filename) {
+            Ok(_) => return Err(VfsError::FileAlreadyExists),
+            Err(VfsError::PathNotFound) => {}
+            Err(e) => return Err(e),
+        }
+        guard.create_child(
+            &directory,
+            filename,
+            VfsFileKind::DeviceNode { block, number },
+        )?;
         Ok(())
     }
 
     pub fn mkdir0(path: Vec<char>) -> Result<Directory, VfsError> {
+        let vfs_path = VfsPath::from(&path);
         let fs = get_vfs();
-        let wguard: &mut dyn FileSystem = &mut **fs.write();
-        let mut traverse = PathTraverse::new_owned(&path, wguard)?;
+        let mut wguard = fs.write();
+        wguard.check_writable(&path)?;
+        let mut traverse = wguard.path_traverse(&vfs_path)?;
         let mut made_dir = false;
         loop {
             match traverse.find_next() {
@@ -194,7 +274,7 @@ impl File {
     }
 
     pub fn mkdir(path: &str) -> Result<Directory, VfsError> {
-        let path = path.chars().collect::<Vec<char>>();
+        let path = VfsPath::from(path).to_chars();
         Self::mkdir0(path)
     }
 
@@ -215,6 +295,7 @@ impl File {
             file: entry.entry.clone(),
             handle,
             mode,
+            path_handle: false,
         })
     }
 
@@ -245,18 +326,45 @@ impl File {
         self.fs.clone()
     }
 
+    /// Whether the handle has to be closed with `close_path_handle`
+    pub fn is_path_handle(&self) -> bool {
+        self.path_handle
+    }
+
+    /// Gives the handle to the caller, who closes it
+    pub fn into_handle(self) -> (Arcrwb<dyn FileSystem>, u64) {
+        let mut file = ManuallyDrop::new(self);
+        unsafe {
+            core::ptr::drop_in_place(&mut file.path);
+            core::ptr::drop_in_place(&mut file.file);
+            (core::ptr::read(&file.fs), file.handle)
+        }
+    }
+
     /// Writes the buffer to the file at the current position, incrementing the position by the amount of bytes written, and returns the number of bytes written
     pub fn write(&mut self, buf: &[u8]) -> Result<u64, VfsError> {
         let mut guard = self.fs.write();
         guard.fwrite(self.handle, buf)
     }
 
+    /// Same as `write`, but returns `None` instead of waiting when the file system is busy
+    pub fn try_write(&mut self, buf: &[u8]) -> Option<Result<u64, VfsError>> {
+        let mut guard = self.fs.try_write()?;
+        Some(guard.fwrite(self.handle, buf))
+    }
+
     /// Reads contents from the file at the current position, incrementing the position by the amount of bytes read, and returns the number of bytes read, reading at most enough bytes to fill the buffer
     pub fn read(&self, buf: &mut [u8]) -> Result<u64, VfsError> {
         let mut guard = self.fs.write();
         guard.fread(self.handle, buf)
     }
 
+    /// Reads at `offset`, leaving the position of the file unchanged
+    pub fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<u64, VfsError> {
+        let mut guard = self.fs.write();
+        guard.fread_at(self.handle, offset, buf)
+    }
+
     /// Seeks to a specific position in the file, returning the new position or an error if the position is invalid
     pub fn seek(&self, position: SeekPosition) -> Result<u64, VfsError> {
         let mut guard = self.fs.write();
@@ -273,8 +381,11 @@ impl File {
     /// # Safety
     /// Safe but all subsequent calls to functions on this File will return errors
     pub unsafe fn _close(&mut self) -> Result<(), VfsError> {
-        let mut guard = self.fs.write();
-        guard.fclose(self.handle)?;
+        if core::mem::take(&mut self.path_handle) {
+            close_path_handle(&self.fs, self.handle)?;
+        } else {
+            self.fs.write().fclose(self.handle)?;
+        }
         self.handle = 0;
         Ok(())
     }
@@ -294,10 +405,13 @@ impl File {
     }
 
     pub fn list_directory(path: &str) -> Result<Vec<DirectoryEntry>, VfsError> {
-        let path = path.chars().collect::<Vec<char>>();
+        Self::list_directory0(&VfsPath::from(path).to_chars())
+    }
+
+    pub fn list_directory0(path: &[char]) -> Result<Vec<DirectoryEntry>, VfsError> {
         let fs = get_vfs();
         let guard: &mut dyn FileSystem = &mut **fs.write();
-        let directory = guard.get_file(&path)?;
+        let directory = guard.get_file(&VfsPath::from(path))?;
         if directory.is_mount_point() {
             let fs = directory
                 .get_mounted_fs()
@@ -308,7 +422,7 @@ impl File {
                 .list_children(&directory)?
                 .iter()
                 .map(|entry| DirectoryEntry {
-                    full_name: [&path, &['/'] as &[char], entry.name()].concat(),
+                    full_name: [path, &['/'] as &[char], entry.name()].concat(),
                     entry: entry.clone(),
                 })
                 .collect::<Vec<_>>());
@@ -320,7 +434,7 @@ impl File {
             .list_children(&directory)?
             .iter()
             .map(|entry| DirectoryEntry {
-                full_name: [&path, &['/'] as &[char], entry.name()].concat(),
+                full_name: [path, &['/'] as &[char], entry.name()].concat(),
                 entry: entry.clone(),
             })
             .collect::<Vec<_>>())
@@ -335,7 +449,7 @@ impl Drop for File {
 
 #[derive(Debug)]
 pub struct Directory {
-    path: String,
+    path: Vec<char>,
     vfsfile: VfsFile,
 }
 
@@ -350,13 +464,13 @@ impl Directory {
             }
         }
         Self {
-            path: value.iter().collect::<String>(),
+            path: value,
             vfsfile,
         }
     }
 
     pub fn list(&self) -> Result<Vec<DirectoryEntry>, VfsError> {
-        File::list_directory(&self.path)
+        File::list_directory0(&self.path)
     }
 
     pub fn get_vfs_file(&self) -> &VfsFile {
@@ -388,6 +502,10 @@ impl DirectoryEntry {
         &self.full_name
     }
 
+    pub fn get_vfs_file(&self) -> &VfsFile {
+        &self.entry
+    }
+
     pub fn open(&self, mode: u64) -> Result<File, VfsError> {
         File::open_entry(self, mode)
     }
@@ -402,7 +520,7 @@ impl DirectoryEntry {
     }
 
     pub fn of(path: &str) -> Result<DirectoryEntry, VfsError> {
-        let mut path = path.chars().collect::<Vec<char>>();
+        let mut path = VfsPath::from(path).to_chars();
         while let Some(c) = path.last() {
             if *c == '/' {
                 path.pop();
@@ -412,7 +530,7 @@ impl DirectoryEntry {
         }
         let fs = get_vfs();
         let guard: &mut dyn FileSystem = &mut **fs.write();
-        let directory = guard.get_file(&path)?;
+        let directory = guard.get_file(&VfsPath::from(&path))?;
         if directory.is_mount_point() {
             let fs = directory
                 .get_mounted_fs()
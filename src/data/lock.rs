@@ -0,0 +1,522 @@
+//! Spin locks checked in debug builds. Every CPU keeps the locks it holds in its `PerCpu`, which
+//! catches:
+//! - a CPU taking again a lock it holds exclusively, or taking exclusively a lock it reads, which
+//!   would spin forever. The kernel panics with both call sites
+//! - locks taken against the `LockClass` order, logged with both call sites
+//! - a lock spun on `SPIN_TIMEOUT` times, logged with the call site of its holder
+//!
+//! In release builds they are plain spin locks.
+
+use core::{
+    fmt::Debug,
+    ops::{Deref, DerefMut},
+};
+
+use spin::{MutexGuard, RwLockReadGuard, RwLockWriteGuard};
+
+#[cfg(debug_assertions)]
+use core::{
+    panic::Location,
+    sync::atomic::{AtomicPtr, AtomicU64, AtomicU8, Ordering},
+};
+#[cfg(debug_assertions)]
+use spin::Mutex;
+
+#[cfg(debug_assertions)]
+use crate::{
+    interrupts::without_interrupts,
+    log_error,
+    percpu::{core_id, get_per_cpu, is_per_cpu_initialized},
+};
+
+/// Spins before the holder of a lock is reported
+pub const SPIN_TIMEOUT: u64 = 100_000_000;
+/// Locks a CPU can hold at once, more aren't checked
+pub const MAX_HELD_LOCKS: usize = 32;
+/// Lock order violations are logged once per pair of call sites, for this many pairs
+pub const MAX_REPORTED_VIOLATIONS: usize = 64;
+
+/// Locks are taken in the order of their class, a CPU holding a lock can only take locks of the
+/// same or a later class. `Unordered` locks are only checked for self-deadlocks
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum LockClass {
+    Unordered,
+    Vfs,
+    FileSystem,
+    Ext2Scratch,
+    Ext2BlockCache,
+    Ext2Device,
+    Scheduler,
+}
+
+#[cfg(debug_assertions)]
+#[derive(Debug, Clone, Copy)]
+struct HeldLock {
+    lock: usize,
+    class: LockClass,
+    exclusive: bool,
+    location: &'static Location<'static>,
+}
+
+/// The locks held by a CPU
+#[derive(Debug, Clone)]
+pub struct HeldLocks {
+    #[cfg(debug_assertions)]
+    locks: [Option<HeldLock>; MAX_HELD_LOCKS],
+}
+
+impl Default for HeldLocks {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl HeldLocks {
+    pub const fn new() -> Self {
+        Self {
+            #[cfg(debug_assertions)]
+            locks: [None; MAX_HELD_LOCKS],
+        }
+    }
+
+    pub fn count(&self) -> usize {
+        #[cfg(debug_assertions)]
+        {
+            self.locks.iter().filter(|held| held.is_some()).count()
+        }
+        #[cfg(not(debug_assertions))]
+        0
+    }
+
+    /// Called before the CPU jumps to userland, which abandons the kernel stack and the guards on
+    /// it. Locks still held then are never released
+    pub fn leave_kernel(&mut self) {
+        #[cfg(debug_assertions)]
+        for held in self.locks.iter_mut().filter_map(Option::take) {
+            log_error!(
+                target: "lock",
+                "{:?} lock taken at {} is still held when leaving the kernel",
+                held.class,
+                held.location
+            );
+        }
+    }
+}
+
+/// Lock order violations found since boot
+#[cfg(debug_assertions)]
+static ORDER_VIOLATIONS: AtomicU64 = AtomicU64::new(0);
+#[cfg(debug_assertions)]
+static REPORTED_VIOLATIONS: Mutex<([(usize, usize); MAX_REPORTED_VIOLATIONS], usize)> =
+    Mutex::new(([(0, 0); MAX_REPORTED_VIOLATIONS], 0));
+
+/// Whether the violation between the two call sites wasn't logged yet
+#[cfg(debug_assertions)]
+fn first_violation(held: &'static Location<'static>, taken: &'static Location<'static>) -> bool {
+    let pair = (held as *const _ as usize, taken as *const _ as usize);
+    let mut guard = REPORTED_VIOLATIONS.lock();
+    let (reported, count) = &mut *guard;
+    if reported[..*count].contains(&pair) {
+        return false;
+    }
+    if *count < MAX_REPORTED_VIOLATIONS {
+        reported[*count] = pair;
+        *count += 1;
+    }
+    true
+}
+
+pub fn order_violations() -> u64 {
+    #[cfg(debug_assertions)]
+    {
+        ORDER_VIOLATIONS.load(Ordering::Relaxed)
+    }
+    #[cfg(not(debug_assertions))]
+    0
+}
+
+#[cfg(debug_assertions)]
+fn with_held_locks<R>(f: impl FnOnce(&mut [Option<HeldLock>; MAX_HELD_LOCKS]) -> R) -> Option<R> {
+    if !is_per_cpu_initialized() {
+        return None;
+    }
+    // An interrupt handler taking a lock must not see the list half updated
+    let result = without_interrupts(|| f(&mut get_per_cpu().held_locks.locks));
+    Some(result)
+}
+
+/// Removes the lock from the held locks when dropped, after the guard released it
+#[cfg(debug_assertions)]
+struct HeldToken {
+    lock: usize,
+}
+
+#[cfg(debug_assertions)]
+impl Drop for HeldToken {
+    fn drop(&mut self) {
+        with_held_locks(|locks| {
+            if let Some(slot) = locks
+                .iter_mut()
+                .rev()
+                .find(|held| held.is_some_and(|held| held.lock == self.lock))
+            {
+                *slot = None;
+            }
+        });
+    }
+}
+
+#[cfg(debug_assertions)]
+struct LockDebug {
+    class: LockClass,
+    /// Call site and CPU of the last acquisition
+    holder: AtomicPtr<Location<'static>>,
+    holder_core: AtomicU8,
+}
+
+#[cfg(debug_assertions)]
+impl LockDebug {
+    const fn new(class: LockClass) -> Self {
+        Self {
+            class,
+            holder: AtomicPtr::new(core::ptr::null_mut()),
+            holder_core: AtomicU8::new(0),
+        }
+    }
+
+    fn holder(&self) -> Option<&'static Location<'static>> {
+        unsafe { self.holder.load(Ordering::Relaxed).as_ref() }
+    }
+
+    fn check(&self, lock: usize, exclusive: bool, location: &'static Location<'static>) {
+        with_held_locks(|locks| {
+            for held in locks.iter().flatten() {
+                if held.lock == lock && (exclusive || held.exclusive) {
+                    panic!(
+                        "Deadlock: {:?} lock taken at {} is taken again at {}",
+                        self.class, held.location, location
+                    );
+                }
+                if self.class != LockClass::Unordered
+                    && held.class != LockClass::Unordered
+                    && held.class > self.class
+                {
+                    ORDER_VIOLATIONS.fetch_add(1, Ordering::Relaxed);
+                    if !first_violation(held.location, location) {
+                        continue;
+                    }
+                    log_error!(
+                        target: "lock",
+                        "Lock order violation: {:?} lock taken at {} while holding the {:?} lock taken at {}",
+                        self.class,
+                        location,
+                        held.class,
+                        held.location
+                    );
+                }
+            }
+        });
+    }
+
+    fn acquired(
+        &self,
+        lock: usize,
+        exclusive: bool,
+        location: &'static Location<'static>,
+    ) -> HeldToken {
+        self.holder
+            .store(location as *const _ as *mut _, Ordering::Relaxed);
+        with_held_locks(|locks| {
+            if let Some(slot) = locks.iter_mut().find(|held| held.is_none()) {
+                *slot = Some(HeldLock {
+                    lock,
+                    class: self.class,
+                    exclusive,
+                    location,
+                });
+            }
+            self.holder_core.store(core_id(), Ordering::Relaxed);
+        });
+        HeldToken { lock }
+    }
+
+    fn spin<G>(
+        &self,
+        lock: usize,
+        exclusive: bool,
+        location: &'static Location<'static>,
+        mut try_lock: impl FnMut() -> Option<G>,
+    ) -> (G, HeldToken) {
+        self.check(lock, exclusive, location);
+        let mut spins = 0u64;
+        let guard = loop {
+            if let Some(guard) = try_lock() {
+                break guard;
+            }
+            spins += 1;
+            if spins == SPIN_TIMEOUT {
+                if let Some(holder) = self.holder() {
+                    log_error!(
+                        target: "lock",
+                        "Spun {} times at {} on the {:?} lock taken at {} by core {}",
+                        spins,
+                        location,
+                        self.class,
+                        holder,
+                        self.holder_core.load(Ordering::Relaxed)
+                    );
+                }
+            }
+            core::hint::spin_loop();
+        };
+        (guard, self.acquired(lock, exclusive, location))
+    }
+}
+
+pub struct DebugRwLock<T: ?Sized> {
+    #[cfg(debug_assertions)]
+    debug: LockDebug,
+    inner: spin::RwLock<T>,
+}
+
+pub struct DebugRwLockReadGuard<'a, T: ?Sized + 'a> {
+    guard: RwLockReadGuard<'a, T>,
+    #[cfg(debug_assertions)]
+    _held: HeldToken,
+}
+
+pub struct DebugRwLockWriteGuard<'a, T: ?Sized + 'a> {
+    guard: RwLockWriteGuard<'a, T>,
+    #[cfg(debug_assertions)]
+    _held: HeldToken,
+}
+
+impl<T> DebugRwLock<T> {
+    pub const fn new(value: T) -> Self {
+        Self::with_class(value, LockClass::Unordered)
+    }
+
+    #[allow(unused_variables)]
+    pub const fn with_class(value: T, class: LockClass) -> Self {
+        Self {
+            #[cfg(debug_assertions)]
+            debug: LockDebug::new(class),
+            inner: spin::RwLock::new(value),
+        }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.inner.into_inner()
+    }
+}
+
+impl<T: ?Sized> DebugRwLock<T> {
+    #[cfg(debug_assertions)]
+    fn addr(&self) -> usize {
+        self as *const Self as *const u8 as usize
+    }
+
+    #[inline]
+    #[track_caller]
+    pub fn read(&self) -> DebugRwLockReadGuard<'_, T> {
+        #[cfg(debug_assertions)]
+        {
+            let (guard, held) = self.debug.spin(self.addr(), false, Location::caller(), || {
+                self.inner.try_read()
+            });
+            DebugRwLockReadGuard { guard, _held: held }
+        }
+        #[cfg(not(debug_assertions))]
+        DebugRwLockReadGuard {
+            guard: self.inner.read(),
+        }
+    }
+
+    #[inline]
+    #[track_caller]
+    pub fn write(&self) -> DebugRwLockWriteGuard<'_, T> {
+        #[cfg(debug_assertions)]
+        {
+            let (guard, held) = self.debug.spin(self.addr(), true, Location::caller(), || {
+                self.inner.try_write()
+            });
+            DebugRwLockWriteGuard { guard, _held: held }
+        }
+        #[cfg(not(debug_assertions))]
+        DebugRwLockWriteGuard {
+            guard: self.inner.write(),
+        }
+    }
+
+    /// Not checked, it can't deadlock
+    #[inline]
+    #[track_caller]
+    pub fn try_read(&self) -> Option<DebugRwLockReadGuard<'_, T>> {
+        let guard = self.inner.try_read()?;
+        Some(DebugRwLockReadGuard {
+            guard,
+            #[cfg(debug_assertions)]
+            _held: self.debug.acquired(self.addr(), false, Location::caller()),
+        })
+    }
+
+    /// Not checked, it can't deadlock
+    #[inline]
+    #[track_caller]
+    pub fn try_write(&self) -> Option<DebugRwLockWriteGuard<'_, T>> {
+        let guard = self.inner.try_write()?;
+        Some(DebugRwLockWriteGuard {
+            guard,
+            #[cfg(debug_assertions)]
+            _held: self.debug.acquired(self.addr(), true, Location::caller()),
+        })
+    }
+
+    /// Whether this CPU holds the lock
+    pub fn is_held_here(&self) -> bool {
+        #[cfg(debug_assertions)]
+        {
+            with_held_locks(|locks| locks.iter().flatten().any(|held| held.lock == self.addr()))
+                .unwrap_or(false)
+        }
+        #[cfg(not(debug_assertions))]
+        false
+    }
+}
+
+impl<T: ?Sized + Debug> Debug for DebugRwLock<T> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        self.inner.fmt(f)
+    }
+}
+
+impl<T: Default> Default for DebugRwLock<T> {
+    fn default() -> Self {
+        Self::new(T::default())
+    }
+}
+
+impl<T: ?Sized> Deref for DebugRwLockReadGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+
+impl<T: ?Sized> Deref for DebugRwLockWriteGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+
+impl<T: ?Sized> DerefMut for DebugRwLockWriteGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.guard
+    }
+}
+
+pub struct DebugMutex<T: ?Sized> {
+    #[cfg(debug_assertions)]
+    debug: LockDebug,
+    inner: spin::Mutex<T>,
+}
+
+pub struct DebugMutexGuard<'a, T: ?Sized + 'a> {
+    guard: MutexGuard<'a, T>,
+    #[cfg(debug_assertions)]
+    _held: HeldToken,
+}
+
+impl<T> DebugMutex<T> {
+    pub const fn new(value: T) -> Self {
+        Self::with_class(value, LockClass::Unordered)
+    }
+
+    #[allow(unused_variables)]
+    pub const fn with_class(value: T, class: LockClass) -> Self {
+        Self {
+            #[cfg(debug_assertions)]
+            debug: LockDebug::new(class),
+            inner: spin::Mutex::new(value),
+        }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.inner.into_inner()
+    }
+}
+
+impl<T: ?Sized> DebugMutex<T> {
+    #[cfg(debug_assertions)]
+    fn addr(&self) -> usize {
+        self as *const Self as *const u8 as usize
+    }
+
+    #[inline]
+    #[track_caller]
+    pub fn lock(&self) -> DebugMutexGuard<'_, T> {
+        #[cfg(debug_assertions)]
+        {
+            let (guard, held) = self.debug.spin(self.addr(), true, Location::caller(), || {
+                self.inner.try_lock()
+            });
+            DebugMutexGuard { guard, _held: held }
+        }
+        #[cfg(not(debug_assertions))]
+        DebugMutexGuard {
+            guard: self.inner.lock(),
+        }
+    }
+
+    /// Not checked, it can't deadlock
+    #[inline]
+    #[track_caller]
+    pub fn try_lock(&self) -> Option<DebugMutexGuard<'_, T>> {
+        let guard = self.inner.try_lock()?;
+        Some(DebugMutexGuard {
+            guard,
+            #[cfg(debug_assertions)]
+            _held: self.debug.acquired(self.addr(), true, Location::caller()),
+        })
+    }
+
+    /// Whether this CPU holds the lock
+    pub fn is_held_here(&self) -> bool {
+        #[cfg(debug_assertions)]
+        {
+            with_held_locks(|locks| locks.iter().flatten().any(|held| held.lock == self.addr()))
+                .unwrap_or(false)
+        }
+        #[cfg(not(debug_assertions))]
+        false
+    }
+}
+
+impl<T: ?Sized + Debug> Debug for DebugMutex<T> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        self.inner.fmt(f)
+    }
+}
+
+impl<T: Default> Default for DebugMutex<T> {
+    fn default() -> Self {
+        Self::new(T::default())
+    }
+}
+
+impl<T: ?Sized> Deref for DebugMutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+
+impl<T: ?Sized> DerefMut for DebugMutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.guard
+    }
+}
@@ -7,27 +7,48 @@ pub mod bitmap;
 pub mod bitset_enum;
 pub mod either;
 pub mod file;
+pub mod lock;
 pub mod partition;
+pub mod path;
 pub mod permissions;
 pub mod regs;
 
+/// Panics when memory runs out, for small allocations made while booting
 pub fn alloc_boxed_slice<T>(count: usize) -> Box<[T]> {
-    let layout = Layout::array::<T>(count.max(1)).unwrap();
-    let ptr = unsafe { alloc(layout) as *mut T };
-    if ptr.is_null() {
-        panic!(
-            "Failed to allocate memory for boxed slice of {} elements of type {}. Layout: {:#?}",
+    match try_alloc_boxed_slice(count) {
+        Some(slice) => slice,
+        None => panic!(
+            "Failed to allocate memory for boxed slice of {} elements of type {}",
             count,
             core::any::type_name::<T>(),
-            layout
-        );
+        ),
+    }
+}
+
+/// `None` when memory runs out, the contents are uninitialized
+pub fn try_alloc_boxed_slice<T>(count: usize) -> Option<Box<[T]>> {
+    let layout = Layout::array::<T>(count.max(1)).ok()?;
+    let ptr = unsafe { alloc(layout) as *mut T };
+    if ptr.is_null() {
+        return None;
     }
     unsafe {
         let slice: *mut [T] = core::ptr::slice_from_raw_parts_mut(ptr, count);
-        Box::from_raw(slice)
+        Some(Box::from_raw(slice))
     }
 }
 
+/// Tries once more after `reclaim` gave memory back
+pub fn try_alloc_boxed_slice_or_reclaim<T>(
+    count: usize,
+    reclaim: impl FnOnce(),
+) -> Option<Box<[T]>> {
+    try_alloc_boxed_slice(count).or_else(|| {
+        reclaim();
+        try_alloc_boxed_slice(count)
+    })
+}
+
 pub fn calloc_boxed_slice<T: Default>(count: usize) -> Box<[T]> {
     let mut slice = alloc_boxed_slice(count);
     for item in slice.iter_mut() {
@@ -36,6 +57,14 @@ pub fn calloc_boxed_slice<T: Default>(count: usize) -> Box<[T]> {
     slice
 }
 
+pub fn try_calloc_boxed_slice<T: Default>(count: usize) -> Option<Box<[T]>> {
+    let mut slice = try_alloc_boxed_slice(count)?;
+    for item in slice.iter_mut() {
+        *item = Default::default();
+    }
+    Some(slice)
+}
+
 pub fn decimal_chars_to_u64(chars: &[char]) -> Option<u64> {
     let mut result: u64 = 0;
     for &c in chars {
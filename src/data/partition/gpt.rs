@@ -166,7 +166,15 @@ impl GUIDPartitionTable {
                 first_lba: entry.first_lba,
                 last_lba: entry.last_lba,
                 flags: entry.flags,
-                name: name.iter().map(|c| *c as char).collect(),
+                name: char::decode_utf16(
+                    name.as_chunks::<2>()
+                        .0
+                        .iter()
+                        .map(|unit| u16::from_le_bytes(*unit))
+                        .take_while(|unit| *unit != 0),
+                )
+                .map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER))
+                .collect(),
             };
 
             table.partitions.push(partition);
@@ -2,8 +2,6 @@ use alloc::vec::Vec;
 
 use crate::drivers::vfs::{Arcrwb, BlockDevice, VfsError};
 
-use super::either::Either;
-
 pub mod gpt;
 pub mod mbr;
 
@@ -97,11 +95,10 @@ impl PartitionManager {
         self.generation = guard.get_generation();
         drop(guard);
 
-        let partition_scheme = match gpt::GUIDPartitionTable::read(dev) {
-            None => PartitionScheme::None,
-            Some(Either::A(gpt)) => PartitionScheme::GPT(gpt),
-            Some(Either::B(mbr)) => PartitionScheme::MBR(mbr),
-        };
+        let partition_scheme = gpt::GUIDPartitionTable::read(dev)
+            .map_or(PartitionScheme::None, |scheme| {
+                scheme.either(PartitionScheme::GPT, PartitionScheme::MBR)
+            });
 
         self.scheme = partition_scheme;
         Ok(())
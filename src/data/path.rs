@@ -0,0 +1,290 @@
+//! Paths of the vfs, stored as the bytes file systems keep in their directories
+//!
+//! Names don't have to be UTF-8, they are compared and stored byte for byte. Code still using
+//! `[char]` paths converts with `From<&[char]>` and `to_chars`, where a char below 256 stands for
+//! the byte of the same value, like the paths copied from user space.
+
+use core::{borrow::Borrow, fmt};
+
+use alloc::{string::String, vec::Vec};
+
+/// A single path component, never empty and without `/`
+#[repr(transparent)]
+#[derive(PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct VfsName([u8]);
+
+impl VfsName {
+    /// `None` if `bytes` isn't a valid name
+    pub fn new(bytes: &[u8]) -> Option<&VfsName> {
+        if bytes.is_empty() || bytes.iter().any(|b| *b == b'/' || *b == 0) {
+            None
+        } else {
+            Some(Self::from_bytes_unchecked(bytes))
+        }
+    }
+
+    fn from_bytes_unchecked(bytes: &[u8]) -> &VfsName {
+        // Same layout, VfsName is a transparent wrapper
+        unsafe { &*(bytes as *const [u8] as *const VfsName) }
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+
+    pub fn is_dot(&self) -> bool {
+        &self.0 == b"."
+    }
+
+    pub fn is_dot_dot(&self) -> bool {
+        &self.0 == b".."
+    }
+
+    pub fn to_chars(&self) -> Vec<char> {
+        bytes_to_chars(&self.0)
+    }
+}
+
+impl fmt::Debug for VfsName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", String::from_utf8_lossy(&self.0))
+    }
+}
+
+impl fmt::Display for VfsName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&String::from_utf8_lossy(&self.0))
+    }
+}
+
+/// `/` separated names, absolute when starting with `/`
+#[derive(Clone, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct VfsPath(Vec<u8>);
+
+impl VfsPath {
+    pub const fn new() -> Self {
+        Self(Vec::new())
+    }
+
+    pub fn from_bytes(bytes: Vec<u8>) -> Self {
+        Self(bytes)
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.0
+    }
+
+    pub fn is_absolute(&self) -> bool {
+        self.0.first() == Some(&b'/')
+    }
+
+    /// Whether the path has no components, like `/` or an empty path
+    pub fn is_root(&self) -> bool {
+        self.components().next().is_none()
+    }
+
+    /// Iterates over the names, without allocating. Repeated and trailing slashes are skipped,
+    /// `.` and `..` are kept
+    pub fn components(&self) -> Components<'_> {
+        Components::new(&self.0)
+    }
+
+    pub fn push(&mut self, name: &VfsName) {
+        if !self.0.is_empty() && self.0.last() != Some(&b'/') {
+            self.0.push(b'/');
+        }
+        self.0.extend_from_slice(name.as_bytes());
+    }
+
+    /// `other` relative to this path, or `other` itself when it is absolute
+    pub fn join(&self, other: &VfsPath) -> VfsPath {
+        if other.is_absolute() {
+            return other.clone();
+        }
+        let mut path = self.clone();
+        for name in other.components() {
+            path.push(name);
+        }
+        path
+    }
+
+    /// The last name of the path, `None` for the root
+    pub fn file_name(&self) -> Option<&VfsName> {
+        self.components().next_back()
+    }
+
+    /// The path without its last name, `None` for the root
+    pub fn parent(&self) -> Option<VfsPath> {
+        let mut components = self.components();
+        components.next_back()?;
+        let mut end = components.end();
+        while end > 1 && self.0[end - 1] == b'/' {
+            end -= 1;
+        }
+        Some(VfsPath(self.0[..end].to_vec()))
+    }
+
+    /// Resolves `.` and `..` and removes repeated slashes, without looking at the file systems.
+    /// `..` at the root stays at the root, leading ones are kept for relative paths
+    pub fn normalize(&self) -> VfsPath {
+        let absolute = self.is_absolute();
+        let mut names: Vec<&VfsName> = Vec::new();
+        for name in self.components() {
+            if name.is_dot() {
+                continue;
+            }
+            if name.is_dot_dot() {
+                match names.last() {
+                    Some(last) if !last.is_dot_dot() => {
+                        names.pop();
+                        continue;
+                    }
+                    _ if absolute => continue,
+                    _ => (),
+                }
+            }
+            names.push(name);
+        }
+
+        let mut path = VfsPath(Vec::with_capacity(self.0.len()));
+        if absolute {
+            path.0.push(b'/');
+        }
+        for name in names {
+            path.push(name);
+        }
+        path
+    }
+
+    pub fn to_chars(&self) -> Vec<char> {
+        bytes_to_chars(&self.0)
+    }
+}
+
+impl From<&[char]> for VfsPath {
+    /// Chars below 256 are bytes, other chars are encoded as UTF-8
+    fn from(chars: &[char]) -> Self {
+        let mut bytes = Vec::with_capacity(chars.len());
+        for c in chars {
+            match u8::try_from(*c) {
+                Ok(byte) => bytes.push(byte),
+                Err(_) => bytes.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes()),
+            }
+        }
+        Self(bytes)
+    }
+}
+
+impl From<&Vec<char>> for VfsPath {
+    fn from(chars: &Vec<char>) -> Self {
+        Self::from(chars.as_slice())
+    }
+}
+
+impl From<&str> for VfsPath {
+    fn from(path: &str) -> Self {
+        Self(path.as_bytes().to_vec())
+    }
+}
+
+impl From<&VfsName> for VfsPath {
+    fn from(name: &VfsName) -> Self {
+        Self(name.as_bytes().to_vec())
+    }
+}
+
+/// Lets maps keyed by paths be searched with a name
+impl Borrow<[u8]> for VfsPath {
+    fn borrow(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl fmt::Debug for VfsPath {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", String::from_utf8_lossy(&self.0))
+    }
+}
+
+impl fmt::Display for VfsPath {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&String::from_utf8_lossy(&self.0))
+    }
+}
+
+fn bytes_to_chars(bytes: &[u8]) -> Vec<char> {
+    bytes.iter().map(|b| *b as char).collect()
+}
+
+/// Names of a path, from either end
+#[derive(Debug, Clone)]
+pub struct Components<'a> {
+    path: &'a [u8],
+    /// Bytes before this were iterated from the front
+    start: usize,
+    /// Bytes from this were iterated from the back
+    end: usize,
+}
+
+impl<'a> Components<'a> {
+    pub fn new(path: &'a [u8]) -> Self {
+        Self {
+            path,
+            start: 0,
+            end: path.len(),
+        }
+    }
+
+    /// The part of the path that wasn't iterated yet
+    pub fn rest(&self) -> &'a [u8] {
+        &self.path[self.start..self.end]
+    }
+
+    /// Position in the path where the names iterated from the back begin
+    pub fn end(&self) -> usize {
+        self.end
+    }
+
+    /// Whether every name was iterated
+    pub fn is_done(&self) -> bool {
+        self.rest().iter().all(|b| *b == b'/')
+    }
+}
+
+impl<'a> Iterator for Components<'a> {
+    type Item = &'a VfsName;
+
+    fn next(&mut self) -> Option<&'a VfsName> {
+        while self.start < self.end && self.path[self.start] == b'/' {
+            self.start += 1;
+        }
+        if self.start == self.end {
+            return None;
+        }
+        let begin = self.start;
+        while self.start < self.end && self.path[self.start] != b'/' {
+            self.start += 1;
+        }
+        Some(VfsName::from_bytes_unchecked(&self.path[begin..self.start]))
+    }
+}
+
+impl<'a> DoubleEndedIterator for Components<'a> {
+    fn next_back(&mut self) -> Option<&'a VfsName> {
+        while self.end > self.start && self.path[self.end - 1] == b'/' {
+            self.end -= 1;
+        }
+        if self.start == self.end {
+            return None;
+        }
+        let finish = self.end;
+        while self.end > self.start && self.path[self.end - 1] != b'/' {
+            self.end -= 1;
+        }
+        Some(VfsName::from_bytes_unchecked(&self.path[self.end..finish]))
+    }
+}
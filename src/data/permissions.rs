@@ -1,15 +1,18 @@
-pub const OWNER_READ: u64 = 1 << 0;
-pub const OWNER_WRITE: u64 = 1 << 1;
-pub const OWNER_EXECUTE: u64 = 1 << 2;
-pub const GROUP_READ: u64 = 1 << 3;
-pub const GROUP_WRITE: u64 = 1 << 4;
-pub const GROUP_EXECUTE: u64 = 1 << 5;
-pub const OTHER_READ: u64 = 1 << 6;
-pub const OTHER_WRITE: u64 = 1 << 7;
-pub const OTHER_EXECUTE: u64 = 1 << 8;
-pub const STICKY_BIT: u64 = 1 << 9;
-pub const SETUID_BIT: u64 = 1 << 10;
-pub const SETGID_BIT: u64 = 1 << 11;
+use crate::drivers::vfs::{FileStat, OPEN_MODE_READ, OPEN_MODE_WRITE};
+
+// Same layout as unix permissions, so ext2 modes and user supplied modes can be used as is
+pub const OTHER_EXECUTE: u64 = 0o0001;
+pub const OTHER_WRITE: u64 = 0o0002;
+pub const OTHER_READ: u64 = 0o0004;
+pub const GROUP_EXECUTE: u64 = 0o0010;
+pub const GROUP_WRITE: u64 = 0o0020;
+pub const GROUP_READ: u64 = 0o0040;
+pub const OWNER_EXECUTE: u64 = 0o0100;
+pub const OWNER_WRITE: u64 = 0o0200;
+pub const OWNER_READ: u64 = 0o0400;
+pub const STICKY_BIT: u64 = 0o1000;
+pub const SETGID_BIT: u64 = 0o2000;
+pub const SETUID_BIT: u64 = 0o4000;
 
 pub const EXTENDED_PERMISSIONS: u64 = 1 << 63;
 
@@ -22,9 +25,9 @@ pub enum PermissionLevel {
 impl PermissionLevel {
     pub const fn get_standard_shift(&self) -> u64 {
         match self {
-            PermissionLevel::Owner => 0,
+            PermissionLevel::Owner => 6,
             PermissionLevel::Group => 3,
-            PermissionLevel::Other => 6,
+            PermissionLevel::Other => 0,
         }
     }
 }
@@ -38,9 +41,9 @@ pub enum PermissionType {
 impl PermissionType {
     pub const fn get_standard_value(&self) -> u64 {
         match self {
-            PermissionType::Read => OWNER_READ,
-            PermissionType::Write => OWNER_WRITE,
-            PermissionType::Execute => OWNER_EXECUTE,
+            PermissionType::Read => OTHER_READ,
+            PermissionType::Write => OTHER_WRITE,
+            PermissionType::Execute => OTHER_EXECUTE,
         }
     }
 }
@@ -75,3 +78,113 @@ macro_rules! permissions {
         permissions
     }};
 }
+
+/// Who is accessing a file, every access check of the kernel goes through this
+#[derive(Debug, Clone, Copy)]
+pub enum Credentials<'a> {
+    /// Accesses made by the kernel itself, never checked
+    Kernel,
+    User {
+        uid: u32,
+        gid: u32,
+        supplementary_gids: &'a [u32],
+    },
+}
+
+impl Credentials<'_> {
+    /// The kernel and uid 0 bypass the permission bits
+    pub fn is_privileged(&self) -> bool {
+        match self {
+            Credentials::Kernel => true,
+            Credentials::User { uid, .. } => *uid == 0,
+        }
+    }
+
+    fn owns(&self, stat: &FileStat) -> bool {
+        match self {
+            Credentials::Kernel => true,
+            Credentials::User { uid, .. } => stat.owner_id == *uid as u64,
+        }
+    }
+
+    fn in_group(&self, gid: u64) -> bool {
+        match self {
+            Credentials::Kernel => true,
+            Credentials::User {
+                gid: own_gid,
+                supplementary_gids,
+                ..
+            } => {
+                *own_gid as u64 == gid
+                    || supplementary_gids.iter().any(|group| *group as u64 == gid)
+            }
+        }
+    }
+
+    /// Checks the owner, group then other bits of the file. A privileged caller still needs
+    /// one execute bit to execute a file, but can search any directory
+    pub fn can(&self, stat: &FileStat, permission: PermissionType) -> bool {
+        if matches!(self, Credentials::Kernel) {
+            return true;
+        }
+        let permissions = Permissions::from_u64(stat.permissions);
+        if self.is_privileged() {
+            return !matches!(permission, PermissionType::Execute)
+                || stat.is_directory
+                || permissions.0 & (OWNER_EXECUTE | GROUP_EXECUTE | OTHER_EXECUTE) != 0;
+        }
+
+        let level = if self.owns(stat) {
+            PermissionLevel::Owner
+        } else if self.in_group(stat.group_id) {
+            PermissionLevel::Group
+        } else {
+            PermissionLevel::Other
+        };
+        permissions.can(level, permission)
+    }
+
+    /// Read and write bits needed by an `OPEN_MODE_*` mode
+    pub fn can_open(&self, stat: &FileStat, open_mode: u64) -> bool {
+        (open_mode & OPEN_MODE_READ == 0 || self.can(stat, PermissionType::Read))
+            && (open_mode & OPEN_MODE_WRITE == 0 || self.can(stat, PermissionType::Write))
+    }
+
+    pub fn can_create_in(&self, directory: &FileStat) -> bool {
+        self.can(directory, PermissionType::Write) && self.can(directory, PermissionType::Execute)
+    }
+
+    /// Only the owner of a file may change its permission bits
+    pub fn can_change_mode(&self, stat: &FileStat) -> bool {
+        self.is_privileged() || self.owns(stat)
+    }
+
+    /// The owner may only change the group to one of its own groups, giving a file away is privileged
+    pub fn can_change_owner(&self, stat: &FileStat, uid: Option<u32>, gid: Option<u32>) -> bool {
+        if self.is_privileged() {
+            return true;
+        }
+        self.owns(stat)
+            && uid.is_none_or(|uid| uid as u64 == stat.owner_id)
+            && gid.is_none_or(|gid| gid as u64 == stat.group_id || self.in_group(gid as u64))
+    }
+
+    /// Setting the times to the current time is also allowed with write access,
+    /// any other time needs ownership
+    pub fn can_set_times(&self, stat: &FileStat, explicit: bool) -> bool {
+        self.is_privileged()
+            || self.owns(stat)
+            || (!explicit && self.can(stat, PermissionType::Write))
+    }
+
+    /// In a sticky directory, only the owner of the file or of the directory may delete it
+    pub fn can_delete(&self, directory: &FileStat, file: &FileStat) -> bool {
+        if !self.can_create_in(directory) {
+            return false;
+        }
+        directory.permissions & STICKY_BIT == 0
+            || self.is_privileged()
+            || self.owns(file)
+            || self.owns(directory)
+    }
+}
@@ -1,3 +1,32 @@
+/// Monitor coprocessor, `wait` traps with TS too
+pub const CR0_MP: u64 = 1 << 1;
+/// No FPU, every x87/SSE instruction traps
+pub const CR0_EM: u64 = 1 << 2;
+/// Task switched, the next x87/SSE instruction traps with #NM
+pub const CR0_TS: u64 = 1 << 3;
+/// Native FPU error reporting
+pub const CR0_NE: u64 = 1 << 5;
+
+pub struct Cr0;
+
+impl Cr0 {
+    /// # Safety
+    /// Caller must ensure the code is running in ring 0 <br>
+    /// Reads the value of the CR0 register
+    pub unsafe fn read() -> u64 {
+        let mut cr0: u64;
+        core::arch::asm!("mov {}, cr0", out(reg) cr0, options(readonly, nostack, preserves_flags));
+        cr0
+    }
+
+    /// # Safety
+    /// Caller must ensure the code is running in ring 0 <br>
+    /// Modifies the value of the CR0 register
+    pub unsafe fn write(cr0: u64) {
+        core::arch::asm!("mov cr0, {}", in(reg) cr0, options(nostack, preserves_flags));
+    }
+}
+
 pub struct Cr2;
 
 impl Cr2 {
@@ -37,3 +66,32 @@ impl Cr3 {
         core::arch::asm!("mov cr2, {}", in(reg) cr3, options(nostack, preserves_flags))
     }
 }
+
+/// fxsave/fxrstor save the SSE state, SSE instructions are allowed
+pub const CR4_OSFXSR: u64 = 1 << 9;
+/// SIMD floating point exceptions raise #XM instead of #UD
+pub const CR4_OSXMMEXCPT: u64 = 1 << 10;
+/// Enables `rdfsbase`/`wrfsbase`/`rdgsbase`/`wrgsbase`, in every ring
+pub const CR4_FSGSBASE: u64 = 1 << 16;
+/// Enables xsave and XCR0
+pub const CR4_OSXSAVE: u64 = 1 << 18;
+
+pub struct Cr4;
+
+impl Cr4 {
+    /// # Safety
+    /// Caller must ensure the code is running in ring 0 <br>
+    /// Reads the value of the CR4 register
+    pub unsafe fn read() -> u64 {
+        let mut cr4: u64;
+        core::arch::asm!("mov {}, cr4", out(reg) cr4, options(readonly, nostack, preserves_flags));
+        cr4
+    }
+
+    /// # Safety
+    /// Caller must ensure the code is running in ring 0 <br>
+    /// Modifies the value of the CR4 register
+    pub unsafe fn write(cr4: u64) {
+        core::arch::asm!("mov cr4, {}", in(reg) cr4, options(nostack, preserves_flags));
+    }
+}
@@ -1,11 +1,43 @@
-use crate::process::memory::{get_address_space, VirtualAddressSpace};
+use core::{
+    arch::x86_64::{__cpuid, __cpuid_count},
+    sync::atomic::{AtomicBool, Ordering},
+};
 
-use super::msr::{rdmsr, wrmsr};
+use crate::percpu::is_per_cpu_base;
+
+use super::{
+    cr::{Cr4, CR4_FSGSBASE},
+    msr::{rdmsr, wrmsr},
+};
 
 pub const IA32_FS_BASE: u32 = 0xC000_0100;
 pub const IA32_GS_BASE: u32 = 0xC000_0101;
 pub const IA32_KERNEL_GS_BASE: u32 = 0xC000_0102;
 
+const CPUID_7_EBX_FSGSBASE: u32 = 1 << 0;
+
+static FSGSBASE: AtomicBool = AtomicBool::new(false);
+
+/// Uses the fs/gs base instructions instead of the MSRs if the CPU has them. Userland can then
+/// change its bases without a syscall, the scheduler saves them when it switches threads
+///
+/// # Safety
+/// Caller must ensure the code is running in ring 0
+pub unsafe fn init_fsgsbase() -> bool {
+    #[allow(unused_unsafe)]
+    let supported =
+        unsafe { __cpuid(0).eax >= 7 && __cpuid_count(7, 0).ebx & CPUID_7_EBX_FSGSBASE != 0 };
+    if supported {
+        Cr4::write(Cr4::read() | CR4_FSGSBASE);
+        FSGSBASE.store(true, Ordering::Relaxed);
+    }
+    supported
+}
+
+pub fn fsgsbase_enabled() -> bool {
+    FSGSBASE.load(Ordering::Relaxed)
+}
+
 pub struct FsBase;
 pub struct GsBase;
 pub struct KernelGsBase;
@@ -14,12 +46,21 @@ impl FsBase {
     /// # Safety
     /// Caller must ensure the code is running in ring 0
     pub unsafe fn get() -> u64 {
+        if fsgsbase_enabled() {
+            let base: u64;
+            core::arch::asm!("rdfsbase {}", out(reg) base, options(nomem, nostack, preserves_flags));
+            return base;
+        }
         rdmsr(IA32_FS_BASE)
     }
 
     /// # Safety
     /// Caller must ensure the code is running in ring 0
     pub unsafe fn set(value: u64) {
+        if fsgsbase_enabled() {
+            core::arch::asm!("wrfsbase {}", in(reg) value, options(nomem, nostack, preserves_flags));
+            return;
+        }
         wrmsr(IA32_FS_BASE, value)
     }
 }
@@ -28,12 +69,21 @@ impl GsBase {
     /// # Safety
     /// Caller must ensure the code is running in ring 0
     pub unsafe fn get() -> u64 {
+        if fsgsbase_enabled() {
+            let base: u64;
+            core::arch::asm!("rdgsbase {}", out(reg) base, options(nomem, nostack, preserves_flags));
+            return base;
+        }
         rdmsr(IA32_GS_BASE)
     }
 
     /// # Safety
     /// Caller must ensure the code is running in ring 0
     pub unsafe fn set(value: u64) {
+        if fsgsbase_enabled() {
+            core::arch::asm!("wrgsbase {}", in(reg) value, options(nomem, nostack, preserves_flags));
+            return;
+        }
         wrmsr(IA32_GS_BASE, value)
     }
 
@@ -42,11 +92,8 @@ impl GsBase {
     /// # Safety
     /// Caller must ensure the code is running in ring 0
     pub unsafe fn use_kernel_base() -> bool {
-        let current_base = Self::get();
-        if matches!(
-            get_address_space(current_base),
-            Some(VirtualAddressSpace::HigherHalf(..))
-        ) {
+        // Userland can set any base with wrgsbase, only the per-CPU data is the kernel one
+        if is_per_cpu_base(Self::get()) {
             // already using kernel base
             return false;
         }
@@ -60,11 +107,7 @@ impl GsBase {
     /// # Safety
     /// Caller must ensure the code is running in ring 0
     pub unsafe fn use_user_base() -> bool {
-        let current_base = Self::get();
-        if matches!(
-            get_address_space(current_base),
-            Some(VirtualAddressSpace::HigherHalf(..))
-        ) {
+        if is_per_cpu_base(Self::get()) {
             // using kernel base
             core::arch::asm!("swapgs");
             return true;
@@ -1,3 +1,4 @@
+pub const IA32_APIC_BASE: u32 = 0x1B;
 pub const IA32_EFER: u32 = 0xC0000080;
 pub const STAR: u32 = 0xC0000081;
 pub const LSTAR: u32 = 0xC0000082;
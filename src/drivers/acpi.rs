@@ -0,0 +1,249 @@
+//! Just enough ACPI to power the machine off: finds the FADT and the \_S5 sleep type in the DSDT
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use crate::{
+    bios::get_bda,
+    io::{inw, iowait, outb, outw},
+    log_info, log_warn,
+    paging::{
+        align_down, get_kernel_page_table, physical_to_virtual, DIRECT_MAPPING_OFFSET,
+        PAGE_ACCESSED, PAGE_PRESENT, PAGE_SIZE,
+    },
+};
+
+const RSDP_SIGNATURE: &[u8; 8] = b"RSD PTR ";
+const RSDP_V1_LEN: usize = 20;
+const RSDP_V2_LEN: usize = 36;
+const SDT_HEADER_LEN: usize = 36;
+
+const BIOS_AREA_START: u64 = 0xE0000;
+const BIOS_AREA_END: u64 = 0x100000;
+const EBDA_SEARCH_LEN: u64 = 1024;
+
+const FADT_DSDT: usize = 40;
+const FADT_SMI_COMMAND: usize = 48;
+const FADT_ACPI_ENABLE: usize = 52;
+const FADT_PM1A_CONTROL: usize = 64;
+const FADT_PM1B_CONTROL: usize = 68;
+const FADT_X_DSDT: usize = 140;
+
+const PM1_SCI_EN: u16 = 1 << 0;
+const PM1_SLP_EN: u16 = 1 << 13;
+const PM1_SLP_TYP_SHIFT: u16 = 10;
+const PM1_SLP_TYP_MASK: u16 = 0b111;
+
+const AML_NAME_OP: u8 = 0x08;
+const AML_BYTE_PREFIX: u8 = 0x0A;
+const AML_PACKAGE_OP: u8 = 0x12;
+const AML_ROOT_CHAR: u8 = b'\\';
+
+#[derive(Debug, Clone, Copy)]
+pub struct AcpiPowerInfo {
+    pub pm1a_control: u16,
+    /// 0 when the chipset only has one PM1 block
+    pub pm1b_control: u16,
+    pub slp_typ_a: u16,
+    pub slp_typ_b: u16,
+    pub smi_command: u16,
+    pub acpi_enable: u8,
+}
+
+/// Makes sure `len` bytes at `phys` can be read through the direct mapping,
+/// firmware tables usually live in reserved memory that isn't mapped
+fn map_physical(phys: u64, len: usize) -> &'static [u8] {
+    let mut page_table = get_kernel_page_table().lock();
+    let mut page = align_down(phys, PAGE_SIZE as u64);
+    while page < phys + len as u64 {
+        let virt = page + DIRECT_MAPPING_OFFSET;
+        if page_table.translate(virt).is_none() {
+            unsafe { page_table.map_4kb(virt, page, PAGE_PRESENT | PAGE_ACCESSED, true) };
+        }
+        page += PAGE_SIZE as u64;
+    }
+    unsafe { core::slice::from_raw_parts(physical_to_virtual(phys) as *const u8, len) }
+}
+
+fn checksum_ok(data: &[u8]) -> bool {
+    data.iter().fold(0u8, |sum, b| sum.wrapping_add(*b)) == 0
+}
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+fn read_u64(data: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(data[offset..offset + 8].try_into().unwrap())
+}
+
+fn find_rsdp_in(start: u64, len: u64) -> Option<&'static [u8]> {
+    let area = map_physical(start, len as usize);
+    (0..=area.len().saturating_sub(RSDP_V2_LEN))
+        .step_by(16)
+        .map(|offset| &area[offset..])
+        .find(|rsdp| rsdp.starts_with(RSDP_SIGNATURE) && checksum_ok(&rsdp[..RSDP_V1_LEN]))
+        .map(|rsdp| &rsdp[..RSDP_V2_LEN])
+}
+
+/// RSDP given by the bootloader, 0 to search for it
+static BOOT_RSDP: AtomicU64 = AtomicU64::new(0);
+
+/// Uses the RSDP found by the bootloader instead of searching the BIOS areas
+pub fn set_boot_rsdp(phys: u64) {
+    BOOT_RSDP.store(phys, Ordering::Relaxed);
+}
+
+fn find_rsdp() -> Option<&'static [u8]> {
+    let boot_rsdp = BOOT_RSDP.load(Ordering::Relaxed);
+    if boot_rsdp != 0 {
+        let rsdp = map_physical(boot_rsdp, RSDP_V2_LEN);
+        if rsdp.starts_with(RSDP_SIGNATURE) && checksum_ok(&rsdp[..RSDP_V1_LEN]) {
+            return Some(rsdp);
+        }
+        log_warn!(target: "acpi", "Invalid RSDP from the bootloader at {:#x}", boot_rsdp);
+    }
+    let ebda = ({ get_bda().ebda_base_addr } as u64) << 4;
+    if ebda != 0 {
+        if let Some(rsdp) = find_rsdp_in(ebda, EBDA_SEARCH_LEN) {
+            return Some(rsdp);
+        }
+    }
+    find_rsdp_in(BIOS_AREA_START, BIOS_AREA_END - BIOS_AREA_START)
+}
+
+/// Returns the whole table, header included, if its checksum is valid
+fn map_table(phys: u64) -> Option<&'static [u8]> {
+    let header = map_physical(phys, SDT_HEADER_LEN);
+    let len = read_u32(header, 4) as usize;
+    if len < SDT_HEADER_LEN {
+        return None;
+    }
+    let table = map_physical(phys, len);
+    checksum_ok(table).then_some(table)
+}
+
+fn find_table(signature: &[u8; 4]) -> Option<&'static [u8]> {
+    let rsdp = find_rsdp()?;
+    let revision = rsdp[15];
+    let xsdt = if revision >= 2 { read_u64(rsdp, 24) } else { 0 };
+    let (root, entry_size) = if xsdt != 0 {
+        (map_table(xsdt)?, 8)
+    } else {
+        (map_table(read_u32(rsdp, 16) as u64)?, 4)
+    };
+
+    root[SDT_HEADER_LEN..]
+        .chunks_exact(entry_size)
+        .map(|entry| match entry_size {
+            8 => read_u64(entry, 0),
+            _ => read_u32(entry, 0) as u64,
+        })
+        .filter_map(map_table)
+        .find(|table| table.starts_with(signature))
+}
+
+/// Reads a ZeroOp, OneOp or byte constant
+fn read_aml_byte(aml: &[u8], position: &mut usize) -> Option<u16> {
+    if *aml.get(*position)? == AML_BYTE_PREFIX {
+        *position += 1;
+    }
+    let value = *aml.get(*position)?;
+    *position += 1;
+    Some(value as u16)
+}
+
+/// Looks for `Name(_S5_, Package() { SLP_TYPa, SLP_TYPb, ... })` without interpreting the AML
+fn find_s5_sleep_types(aml: &[u8]) -> Option<(u16, u16)> {
+    for i in 1..aml.len().saturating_sub(5) {
+        if &aml[i..i + 4] != b"_S5_" || aml[i + 4] != AML_PACKAGE_OP {
+            continue;
+        }
+        let named = aml[i - 1] == AML_NAME_OP
+            || (i >= 2 && aml[i - 2] == AML_NAME_OP && aml[i - 1] == AML_ROOT_CHAR);
+        if !named {
+            continue;
+        }
+
+        // PkgLength is 1 to 4 bytes, followed by NumElements
+        let mut position = i + 5;
+        let pkg_length_bytes = ((*aml.get(position)? & 0xC0) >> 6) as usize + 1;
+        position += pkg_length_bytes + 1;
+
+        let slp_typ_a = read_aml_byte(aml, &mut position)?;
+        let slp_typ_b = read_aml_byte(aml, &mut position)?;
+        return Some((slp_typ_a, slp_typ_b));
+    }
+    None
+}
+
+pub fn find_power_info() -> Option<AcpiPowerInfo> {
+    let Some(fadt) = find_table(b"FACP") else {
+        log_warn!(target: "acpi", "FADT not found");
+        return None;
+    };
+
+    let x_dsdt = if fadt.len() >= FADT_X_DSDT + 8 {
+        read_u64(fadt, FADT_X_DSDT)
+    } else {
+        0
+    };
+    let dsdt_phys = if x_dsdt != 0 {
+        x_dsdt
+    } else {
+        read_u32(fadt, FADT_DSDT) as u64
+    };
+    let Some(dsdt) = map_table(dsdt_phys) else {
+        log_warn!(target: "acpi", "Invalid DSDT at {:#x}", dsdt_phys);
+        return None;
+    };
+    let Some((slp_typ_a, slp_typ_b)) = find_s5_sleep_types(&dsdt[SDT_HEADER_LEN..]) else {
+        log_warn!(target: "acpi", "\\_S5 not found in the DSDT");
+        return None;
+    };
+
+    Some(AcpiPowerInfo {
+        pm1a_control: read_u32(fadt, FADT_PM1A_CONTROL) as u16,
+        pm1b_control: read_u32(fadt, FADT_PM1B_CONTROL) as u16,
+        slp_typ_a,
+        slp_typ_b,
+        smi_command: read_u32(fadt, FADT_SMI_COMMAND) as u16,
+        acpi_enable: fadt[FADT_ACPI_ENABLE],
+    })
+}
+
+/// Some firmwares start in legacy mode, where SLP_EN is ignored until ACPI is enabled
+fn enable_acpi_mode(info: &AcpiPowerInfo) {
+    if inw(info.pm1a_control) & PM1_SCI_EN != 0 || info.smi_command == 0 || info.acpi_enable == 0 {
+        return;
+    }
+    outb(info.smi_command, info.acpi_enable);
+    for _ in 0..300 {
+        if inw(info.pm1a_control) & PM1_SCI_EN != 0 {
+            return;
+        }
+        iowait();
+    }
+    log_warn!(target: "acpi", "Failed to enable ACPI mode");
+}
+
+/// Enters the S5 soft-off state, returns if the machine is still running afterwards
+pub fn power_off() {
+    let Some(info) = find_power_info() else {
+        return;
+    };
+    log_info!(target: "acpi", "Entering S5: {:x?}", info);
+    enable_acpi_mode(&info);
+
+    outw(
+        info.pm1a_control,
+        ((info.slp_typ_a & PM1_SLP_TYP_MASK) << PM1_SLP_TYP_SHIFT) | PM1_SLP_EN,
+    );
+    if info.pm1b_control != 0 {
+        outw(
+            info.pm1b_control,
+            ((info.slp_typ_b & PM1_SLP_TYP_MASK) << PM1_SLP_TYP_SHIFT) | PM1_SLP_EN,
+        );
+    }
+    iowait();
+    log_warn!(target: "acpi", "Still running after entering S5");
+}
@@ -0,0 +1,161 @@
+//! Loop devices: a regular file exposed as a block device at `/dev/loopN`, so file system images
+//! can be mounted like partitions.
+//!
+//! `attach_loop` wraps an open file and publishes the device, `detach_loop` removes it once
+//! nothing has it open anymore. A mounted file system keeps its device open, so a loop device
+//! can't be detached while it is mounted.
+
+use alloc::{boxed::Box, collections::BTreeMap, format, sync::Arc, vec::Vec};
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use spin::Mutex;
+
+use crate::{
+    data::file::File,
+    drivers::{
+        fs::virt::devfs::with_devfs,
+        vfs::{
+            arcrwb_new_from_box, Arcrwb, BlockDevice, DeviceNumber, FileSystem, SeekPosition,
+            VfsError, OPEN_MODE_WRITE,
+        },
+    },
+};
+
+use super::node::BlockDeviceNodeProvider;
+
+/// Same major number as the linux loop devices
+pub const LOOP_MAJOR: u32 = 7;
+pub const LOOP_DEFAULT_BLOCK_SIZE: u64 = 512;
+pub const MAX_LOOP_DEVICES: u32 = 8;
+
+/// Open handles of each attached device, keyed by its index
+static LOOP_DEVICES: Mutex<BTreeMap<u32, Arc<AtomicUsize>>> = Mutex::new(BTreeMap::new());
+
+/// Blocks of a regular file. A last partial block is left out
+#[derive(Debug)]
+pub struct LoopBlockDevice {
+    file: Mutex<File>,
+    block_size: u64,
+    block_count: u64,
+}
+
+impl LoopBlockDevice {
+    /// `block_size` must be a power of two of at least 512 bytes
+    pub fn new(file: File, block_size: u64) -> Result<Self, VfsError> {
+        if block_size < 512 || !block_size.is_power_of_two() {
+            return Err(VfsError::InvalidArgument);
+        }
+        let stat = file.stats()?;
+        if !stat.is_file || stat.is_directory {
+            return Err(VfsError::NotFile);
+        }
+        Ok(Self {
+            file: Mutex::new(file),
+            block_size,
+            block_count: stat.size / block_size,
+        })
+    }
+
+    fn check_access(&self, lba: u64, len: usize) -> Result<u64, VfsError> {
+        if lba >= self.block_count {
+            return Err(VfsError::OutOfBounds);
+        }
+        if (len as u64) < self.block_size {
+            return Err(VfsError::BadBufferSize);
+        }
+        Ok(lba * self.block_size)
+    }
+}
+
+impl BlockDevice for LoopBlockDevice {
+    fn get_generation(&self) -> u64 {
+        0
+    }
+
+    fn get_block_size(&self) -> u64 {
+        self.block_size
+    }
+
+    fn get_block_count(&self) -> u64 {
+        self.block_count
+    }
+
+    fn read_block(&self, lba: u64, buf: &mut [u8]) -> Result<u64, VfsError> {
+        let offset = self.check_access(lba, buf.len())?;
+        let buf = &mut buf[..self.block_size as usize];
+        let file = self.file.lock();
+        file.seek(SeekPosition::FromStart(offset))?;
+        let mut read = 0;
+        while read < buf.len() {
+            match file.read(&mut buf[read..])? {
+                0 => return Err(VfsError::ShortRead),
+                count => read += count as usize,
+            }
+        }
+        Ok(self.block_size)
+    }
+
+    fn write_block(&mut self, lba: u64, buf: &[u8]) -> Result<u64, VfsError> {
+        let offset = self.check_access(lba, buf.len())?;
+        let buf = &buf[..self.block_size as usize];
+        let mut file = self.file.lock();
+        if file.get_open_mode() & OPEN_MODE_WRITE == 0 {
+            return Err(VfsError::ReadOnly);
+        }
+        file.seek(SeekPosition::FromStart(offset))?;
+        let mut written = 0;
+        while written < buf.len() {
+            match file.write(&buf[written..])? {
+                0 => return Err(VfsError::OutOfSpace),
+                count => written += count as usize,
+            }
+        }
+        Ok(self.block_size)
+    }
+
+    fn flush(&mut self) -> Result<(), VfsError> {
+        self.file.lock().flush()
+    }
+}
+
+fn loop_name(index: u32) -> Vec<char> {
+    format!("loop{index}").chars().collect()
+}
+
+/// Publishes `file` as the first free `/dev/loopN` and returns N. The file is written through
+/// if it was opened for writing
+pub fn attach_loop(file: File, block_size: u64) -> Result<u32, VfsError> {
+    let device: Arcrwb<dyn BlockDevice> =
+        arcrwb_new_from_box(Box::new(LoopBlockDevice::new(file, block_size)?));
+    let mut devices = LOOP_DEVICES.lock();
+    let index = (0..MAX_LOOP_DEVICES)
+        .find(|index| !devices.contains_key(index))
+        .ok_or(VfsError::OutOfSpace)?;
+    let opens = Arc::new(AtomicUsize::new(0));
+    let name = loop_name(index);
+    with_devfs(|_, devfs| {
+        let provider =
+            BlockDeviceNodeProvider::new(devfs.os_id(), name.clone(), device, opens.clone());
+        devfs.insert_vfile(arcrwb_new_from_box(Box::new(provider)), &name);
+        devfs.register_device_number(true, DeviceNumber::new(LOOP_MAJOR, index), &name);
+        Ok(())
+    })?;
+    devices.insert(index, opens);
+    Ok(index)
+}
+
+/// Removes `/dev/loopN` and closes its file, fails with `VfsError::Busy` while the device is open
+/// (mounted)
+pub fn detach_loop(index: u32) -> Result<(), VfsError> {
+    let mut devices = LOOP_DEVICES.lock();
+    let opens = devices.get(&index).ok_or(VfsError::NoSuchDevice)?;
+    if opens.load(Ordering::Acquire) != 0 {
+        return Err(VfsError::Busy);
+    }
+    with_devfs(|_, devfs| {
+        devfs.remove_hook(&loop_name(index));
+        Ok(())
+    })?;
+    devices.remove(&index);
+    Ok(())
+}
@@ -3,12 +3,16 @@ use pata::{is_pata_device, PataDevfsDriver};
 
 use super::{fs::virt::devfs::DevFs, pci, vfs::arcrwb_new_from_box};
 
+pub mod loop_device;
+pub mod node;
 pub mod pata;
+pub mod queue;
+pub mod ramdisk;
 
 pub fn init_disk_drivers(vfs: &mut DevFs) {
-    if let Some(pci_device) = pci::device_iterator().find(|pci_device| is_pata_device(pci_device)) {
+    if let Some(pci_device) = pci::device_iterator().find(is_pata_device) {
         vfs.register_driver(arcrwb_new_from_box(Box::new(PataDevfsDriver::new(
-            *pci_device,
+            pci_device,
         ))))
         .unwrap();
     }
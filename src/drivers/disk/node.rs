@@ -0,0 +1,135 @@
+//! Devfs files of block devices that aren't published by a driver, like the loop devices and the
+//! ram disks. The open count lets their owner refuse to remove a device that is in use.
+
+use alloc::{boxed::Box, sync::Arc, vec::Vec};
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::{
+    drivers::{
+        fs::virt::devfs::{VirtualDeviceFile, VirtualDeviceFileProvider},
+        vfs::{
+            arcrwb_new_from_box, Arcrwb, BlockDevice, BlockDeviceAsCharacterDevice,
+            CharacterDevice, FileStat, SeekPosition, VfsError, VfsFile, VfsFileKind,
+            VfsSpecificFileData, FLAG_VIRTUAL, FLAG_VIRTUAL_BLOCK_DEVICE, OPEN_MODE_FAIL_IF_EXISTS,
+        },
+    },
+    permissions,
+};
+
+#[derive(Debug)]
+pub struct BlockDeviceNodeProvider {
+    devfs_os_id: u64,
+    name: Vec<char>,
+    device: Arcrwb<dyn BlockDevice>,
+    opens: Arc<AtomicUsize>,
+}
+
+impl BlockDeviceNodeProvider {
+    pub fn new(
+        devfs_os_id: u64,
+        name: Vec<char>,
+        device: Arcrwb<dyn BlockDevice>,
+        opens: Arc<AtomicUsize>,
+    ) -> Self {
+        Self {
+            devfs_os_id,
+            name,
+            device,
+            opens,
+        }
+    }
+}
+
+/// Bytes of the device at a position
+#[derive(Debug)]
+pub struct BlockDeviceNodeFile {
+    block: Arcrwb<dyn BlockDevice>,
+    device: BlockDeviceAsCharacterDevice,
+    position: u64,
+    opens: Arc<AtomicUsize>,
+}
+
+fn node_stat(device: &Arcrwb<dyn BlockDevice>) -> FileStat {
+    let guard = device.read();
+    FileStat {
+        size: guard.get_block_count() * guard.get_block_size(),
+        is_directory: false,
+        is_symlink: false,
+        is_file: true,
+        permissions: permissions!(Owner:Read, Owner:Write).to_u64(),
+        owner_id: 0,
+        group_id: 0,
+        created_at: 0,
+        modified_at: 0,
+        flags: FLAG_VIRTUAL | FLAG_VIRTUAL_BLOCK_DEVICE,
+    }
+}
+
+impl VirtualDeviceFileProvider for BlockDeviceNodeProvider {
+    fn open(&mut self, mode: u64) -> Result<Arcrwb<dyn VirtualDeviceFile>, VfsError> {
+        if mode & OPEN_MODE_FAIL_IF_EXISTS != 0 {
+            return Err(VfsError::FileAlreadyExists);
+        }
+        self.opens.fetch_add(1, Ordering::AcqRel);
+        Ok(arcrwb_new_from_box(Box::new(BlockDeviceNodeFile {
+            block: self.device.clone(),
+            device: BlockDeviceAsCharacterDevice::new(self.device.clone()),
+            position: 0,
+            opens: self.opens.clone(),
+        })))
+    }
+
+    fn stat(&self) -> Result<FileStat, VfsError> {
+        Ok(node_stat(&self.device))
+    }
+
+    /// A block device, so it can be mounted
+    fn vfs_file(&self) -> Result<VfsFile, VfsError> {
+        Ok(VfsFile::new(
+            VfsFileKind::BlockDevice {
+                device: self.device.clone(),
+            },
+            self.name.clone(),
+            0,
+            self.devfs_os_id,
+            self.devfs_os_id,
+            Arc::new(VfsSpecificFileData),
+        ))
+    }
+}
+
+impl VirtualDeviceFile for BlockDeviceNodeFile {
+    fn stat(&self) -> Result<FileStat, VfsError> {
+        Ok(node_stat(&self.block))
+    }
+
+    fn close(&mut self) -> Result<(), VfsError> {
+        self.opens.fetch_sub(1, Ordering::AcqRel);
+        Ok(())
+    }
+
+    fn seek(&mut self, position: SeekPosition) -> Result<u64, VfsError> {
+        self.position = self.device.seek_position(position, self.position)?;
+        Ok(self.position)
+    }
+
+    fn pos(&self) -> Result<u64, VfsError> {
+        Ok(self.position)
+    }
+
+    fn truncate(&mut self) -> Result<u64, VfsError> {
+        Err(VfsError::ActionNotAllowed)
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> Result<u64, VfsError> {
+        self.device.read_at_position(&mut self.position, buf)
+    }
+
+    fn write(&mut self, buf: &[u8]) -> Result<u64, VfsError> {
+        self.device.write_at_position(&mut self.position, buf)
+    }
+
+    fn flush(&mut self) -> Result<(), VfsError> {
+        self.device.flush()
+    }
+}
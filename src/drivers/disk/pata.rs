@@ -1,19 +1,28 @@
-use alloc::{boxed::Box, collections::BTreeSet, format, string::String, sync::Arc, vec::Vec};
+use alloc::{
+    boxed::Box,
+    collections::{BTreeMap, BTreeSet},
+    format,
+    string::{String, ToString},
+    sync::Arc,
+    vec::Vec,
+};
 use spin::RwLock;
 
 use crate::{
     data::partition::{BlockDeviceRange, Partition, PartitionManager},
     drivers::{
+        disk::queue::{BlockRequest, BlockRequestKind, RequestQueue},
         fs::virt::devfs::{fseek_helper, DevFs, DevFsDriver, DevFsHook, DevFsHookKind},
         pci::PciDevice,
         vfs::{
-            arcrwb_new_from_box, BlockDevice, FileStat, FileSystem, FsSpecificFileData,
-            SubBlockDevice, VfsError, VfsFile, VfsFileKind, FLAG_PARTITIONED_DEVICE,
-            FLAG_PHYSICAL_BLOCK_DEVICE, OPEN_MODE_APPEND, OPEN_MODE_READ,
+            arcrwb_new_from_box, Arcrwb, BlockDevice, DeviceNumber, FileStat, FileSystem,
+            FsSpecificFileData, SubBlockDevice, VfsError, VfsFile, VfsFileKind,
+            FLAG_PARTITIONED_DEVICE, FLAG_PHYSICAL_BLOCK_DEVICE, OPEN_MODE_APPEND, OPEN_MODE_READ,
+            OPEN_MODE_WRITE,
         },
     },
     io::{inb, inw, outb, outw},
-    permissions,
+    log_info, log_warn, permissions,
 };
 
 pub fn is_pata_device(pci_device: &PciDevice) -> bool {
@@ -31,6 +40,8 @@ pub enum PataErrtype {
     DeviceBusy,
     Timeout,
     BadSector,
+    /// The drive set ERR in the status register after a read command
+    ReadError,
     Unknown,
     NoDevice,
 }
@@ -63,10 +74,11 @@ pub struct PataController {
     generation: u64,
 
     partition_manager: PartitionManager,
+    queue: Arc<RequestQueue>,
 }
 
 impl PataController {
-    const fn new(bus: PataBus, drive: PataDrive) -> Self {
+    fn new(bus: PataBus, drive: PataDrive) -> Self {
         let (base_io, control_io) = match bus {
             PataBus::Primary => (0x1F0, 0x3F6),
             PataBus::Secondary => (0x170, 0x376),
@@ -79,6 +91,7 @@ impl PataController {
             identify_data: [0; 256],
             generation: 0,
             partition_manager: PartitionManager::new(),
+            queue: Arc::new(RequestQueue::new()),
         }
     }
 
@@ -137,6 +150,38 @@ impl PataController {
         false
     }
 
+    /// Waits for the drive to ask for the data, `error` if it sets ERR or DF instead
+    fn wait_data(&self, error: PataErrtype) -> Result<(), PataErrtype> {
+        for _ in 0..100000 {
+            let status = inb(self.base_io + 7);
+            if status & 0x80 != 0 {
+                continue;
+            }
+            if status & 0x21 != 0 {
+                return Err(error);
+            }
+            if status & 0x08 != 0 {
+                return Ok(());
+            }
+        }
+        Err(PataErrtype::Timeout)
+    }
+
+    /// Registers 2 to 5 are FIFOs of two bytes, the high bytes go first: the count's, then
+    /// LBA[31:24], LBA[39:32] and LBA[47:40]. Then the count's low byte, LBA[7:0], LBA[15:8] and
+    /// LBA[23:16]
+    fn program_lba48(&self, lba: u64, count: u16) {
+        outb(self.base_io + 2, (count >> 8) as u8);
+        outb(self.base_io + 3, ((lba >> 24) & 0xFF) as u8);
+        outb(self.base_io + 4, ((lba >> 32) & 0xFF) as u8);
+        outb(self.base_io + 5, ((lba >> 40) & 0xFF) as u8);
+
+        outb(self.base_io + 2, (count & 0xFF) as u8);
+        outb(self.base_io + 3, (lba & 0xFF) as u8);
+        outb(self.base_io + 4, ((lba >> 8) & 0xFF) as u8);
+        outb(self.base_io + 5, ((lba >> 16) & 0xFF) as u8);
+    }
+
     pub fn get_generation(&self) -> u64 {
         self.generation
     }
@@ -152,21 +197,9 @@ impl PataController {
 
         outb(self.base_io + 1, 0); // Features
 
-        outb(self.base_io + 2, ((lba >> 40) & 0xFF) as u8); // Sector Count High
-        outb(self.base_io + 3, ((lba >> 24) & 0xFF) as u8); // LBA High
-        outb(self.base_io + 4, ((lba >> 32) & 0xFF) as u8); // LBA Mid
-        outb(self.base_io + 5, ((lba >> 40) & 0xFF) as u8); // LBA Low
-
-        outb(self.base_io + 2, 1); // Sector Count Low (read 1 sector)
-        outb(self.base_io + 3, (lba & 0xFF) as u8);
-        outb(self.base_io + 4, ((lba >> 8) & 0xFF) as u8);
-        outb(self.base_io + 5, ((lba >> 16) & 0xFF) as u8);
-
+        self.program_lba48(lba, 1);
         outb(self.base_io + 7, 0x24); // READ SECTORS EXT (0x24)
-
-        if !self.wait_drq() {
-            return Err(PataErrtype::Timeout);
-        }
+        self.wait_data(PataErrtype::ReadError)?;
 
         unsafe {
             let data_port = self.base_io;
@@ -189,21 +222,9 @@ impl PataController {
 
         outb(self.base_io + 1, 0); // Features
 
-        outb(self.base_io + 2, ((lba >> 40) & 0xFF) as u8);
-        outb(self.base_io + 3, ((lba >> 24) & 0xFF) as u8);
-        outb(self.base_io + 4, ((lba >> 32) & 0xFF) as u8);
-        outb(self.base_io + 5, ((lba >> 40) & 0xFF) as u8);
-
-        outb(self.base_io + 2, 1); // Sector Count
-        outb(self.base_io + 3, (lba & 0xFF) as u8);
-        outb(self.base_io + 4, ((lba >> 8) & 0xFF) as u8);
-        outb(self.base_io + 5, ((lba >> 16) & 0xFF) as u8);
-
+        self.program_lba48(lba, 1);
         outb(self.base_io + 7, 0x34); // WRITE SECTORS EXT (0x34)
-
-        if !self.wait_drq() {
-            return Err(PataErrtype::Timeout);
-        }
+        self.wait_data(PataErrtype::DeviceFault)?;
 
         unsafe {
             let data_port = self.base_io;
@@ -213,6 +234,38 @@ impl PataController {
             }
         }
 
+        // The drive reports write errors once the sector is taken
+        if !self.wait_busy() {
+            return Err(PataErrtype::Timeout);
+        }
+        if inb(self.base_io + 7) & 0x21 != 0 {
+            return Err(PataErrtype::DeviceFault);
+        }
+        Ok(())
+    }
+
+    /// Writes the write cache of the drive back to the media, with FLUSH CACHE EXT when the drive
+    /// supports it
+    pub fn flush_cache(&mut self) -> Result<(), PataErrtype> {
+        self.select_drive();
+        if !self.wait_busy() {
+            return Err(PataErrtype::DeviceBusy);
+        }
+
+        if self.identify_data[83] & (1 << 13) != 0 {
+            outb(self.base_io + 7, 0xEA); // FLUSH CACHE EXT (0xEA)
+        } else {
+            outb(self.base_io + 7, 0xE7); // FLUSH CACHE (0xE7)
+        }
+
+        if !self.wait_busy() {
+            return Err(PataErrtype::Timeout);
+        }
+        let status = inb(self.base_io + 7);
+        if status & 0x21 != 0 {
+            // ERR or DF, the sector that failed is left in the LBA registers
+            return Err(PataErrtype::DeviceFault);
+        }
         Ok(())
     }
 
@@ -247,9 +300,39 @@ impl PataController {
         Ok(())
     }
 
+    /// Decodes an IDENTIFY string, each word holds two characters in big endian order
+    fn identify_string(&self, words: core::ops::Range<usize>) -> String {
+        let mut string = String::new();
+        for word in self.identify_data[words].iter() {
+            for c in word.to_be_bytes() {
+                if c.is_ascii_graphic() || c == b' ' {
+                    string.push(c as char);
+                }
+            }
+        }
+        string.trim().to_string()
+    }
+
+    pub fn get_model(&self) -> String {
+        self.identify_string(27..47)
+    }
+
+    pub fn get_serial(&self) -> String {
+        self.identify_string(10..20)
+    }
+
+    /// Sector count from words 100-103 when the drive supports LBA48, the 28 bit count in words
+    /// 60-61 otherwise
     pub fn get_disk_params(&self) -> PataDiskParams {
-        let sector_count =
-            ((self.identify_data[61] as u64) << 16) | (self.identify_data[60] as u64);
+        let words = if self.identify_data[83] & (1 << 10) != 0 {
+            &self.identify_data[100..104]
+        } else {
+            &self.identify_data[60..62]
+        };
+        let sector_count = words
+            .iter()
+            .rev()
+            .fold(0u64, |count, &word| (count << 16) | word as u64);
 
         PataDiskParams { sector_count }
     }
@@ -282,6 +365,9 @@ pub struct PataDevfsDriver {
     controller_ps: Arc<RwLock<PataController>>,
     controller_sm: Arc<RwLock<PataController>>,
     controller_ss: Arc<RwLock<PataController>>,
+
+    /// `disk/by-id` names currently published for each controller, with their partition count
+    published_ids: BTreeMap<Vec<char>, (String, usize)>,
 }
 
 impl PataDevfsDriver {
@@ -289,6 +375,7 @@ impl PataDevfsDriver {
         let driver = Self {
             pci_device,
             handles: BTreeSet::new(),
+            published_ids: BTreeMap::new(),
             controller_pm: Arc::new(RwLock::new(PataController::new(
                 PataBus::Primary,
                 PataDrive::Master,
@@ -316,11 +403,112 @@ impl PataDevfsDriver {
     pub fn get_pci_device(&self) -> PciDevice {
         self.pci_device
     }
+
+    /// The controller a hook opens, hooks carry the index of their controller as device key
+    fn controller(&self, device_key: u64) -> Option<&Arc<RwLock<PataController>>> {
+        [
+            &self.controller_pm,
+            &self.controller_ps,
+            &self.controller_sm,
+            &self.controller_ss,
+        ]
+        .get(device_key as usize)
+        .copied()
+    }
+
+    fn unpublish_id(&mut self, dev_fs: &mut DevFs, name: &[char]) {
+        if let Some((id_name, partitions)) = self.published_ids.remove(name) {
+            for i in 0..partitions {
+                dev_fs.remove_hook(
+                    &format!("disk/by-id/{id_name}-part{}", i + 1)
+                        .chars()
+                        .collect::<Vec<_>>(),
+                );
+            }
+            dev_fs.remove_hook(&format!("disk/by-id/{id_name}").chars().collect::<Vec<_>>());
+        }
+    }
 }
 
 #[derive(Debug)]
 struct PataBlockDevice {
     controller: Arc<RwLock<PataController>>,
+    /// Shared by every device of the controller
+    queue: Arc<RequestQueue>,
+    /// Generation of the media the device was made for
+    generation: u64,
+}
+
+impl PataBlockDevice {
+    fn new(controller: Arc<RwLock<PataController>>) -> Self {
+        let (queue, generation) = {
+            let guard = controller.read();
+            (guard.queue.clone(), guard.generation)
+        };
+        Self {
+            controller,
+            queue,
+            generation,
+        }
+    }
+
+    fn check_generation(&self) -> Result<(), VfsError> {
+        if self.controller.read().generation != self.generation {
+            return Err(VfsError::StaleDevice);
+        }
+        Ok(())
+    }
+
+    /// Runs a dispatch of the queue, one sector at a time. Barriers flush the write cache
+    fn dispatch(
+        controller: &RwLock<PataController>,
+        kind: BlockRequestKind,
+        lba: u64,
+        buf: &mut [u8],
+    ) -> Result<(), VfsError> {
+        if kind == BlockRequestKind::Barrier {
+            return Self::flush_controller(controller);
+        }
+        Self::check_range(controller, lba, buf.len() as u64 / 512)?;
+        for (sector, lba) in buf.as_chunks_mut::<512>().0.iter_mut().zip(lba..) {
+            let result = match kind {
+                BlockRequestKind::Read => controller.read().read_sector(lba, sector),
+                _ => controller.write().write_sector(lba, sector),
+            };
+            if let Err(e) = result {
+                log_warn!(target: "pata", "{:?} of sector {} failed: {:?}", kind, lba, e);
+                return Err(VfsError::DriverError(Box::new(e)));
+            }
+        }
+        Ok(())
+    }
+
+    fn check_range(
+        controller: &RwLock<PataController>,
+        lba: u64,
+        count: u64,
+    ) -> Result<(), VfsError> {
+        let sector_count = controller.read().get_disk_params().sector_count;
+        match lba.checked_add(count) {
+            Some(end) if end <= sector_count => Ok(()),
+            _ => Err(VfsError::OutOfBounds),
+        }
+    }
+
+    fn flush_controller(controller: &RwLock<PataController>) -> Result<(), VfsError> {
+        if let Err(e) = controller.write().flush_cache() {
+            log_warn!(target: "pata", "cache flush failed: {:?}", e);
+            return Err(VfsError::DriverError(Box::new(e)));
+        }
+        Ok(())
+    }
+
+    fn submit_and_wait(&self, request: BlockRequest) -> Result<Vec<u8>, VfsError> {
+        self.queue
+            .submit_and_wait(request, 512, &mut |kind, lba, buf| {
+                Self::dispatch(&self.controller, kind, lba, buf)
+            })
+    }
 }
 
 impl BlockDevice for PataBlockDevice {
@@ -333,23 +521,35 @@ impl BlockDevice for PataBlockDevice {
     }
 
     fn flush(&mut self) -> Result<(), VfsError> {
-        Ok(())
+        self.flush_cache()
+    }
+
+    /// Goes through the queue, so it waits for the writes queued before
+    fn flush_cache(&mut self) -> Result<(), VfsError> {
+        self.submit_and_wait(BlockRequest::barrier()).map(|_| ())
     }
 
     fn get_block_count(&self) -> u64 {
-        self.controller.read().size_bytes() / 512
+        self.controller.read().get_disk_params().sector_count
+    }
+
+    fn description(&self) -> Option<String> {
+        let controller = self.controller.read();
+        Some(format!(
+            "{}_{}",
+            controller.get_model(),
+            controller.get_serial()
+        ))
     }
 
     fn read_block(&self, lba: u64, buf: &mut [u8]) -> Result<u64, VfsError> {
         if buf.len() < 512 {
             return Err(VfsError::BadBufferSize);
         }
-        let mut data: [u8; 512] = [0; 512];
-        self.controller
-            .read()
-            .read_sector(lba, &mut data)
-            .map_err(|e| VfsError::DriverError(Box::new(e)))?;
-        buf.copy_from_slice(&data);
+        self.check_generation()?;
+        Self::check_range(&self.controller, lba, 1)?;
+        let data = self.submit_and_wait(BlockRequest::read(lba, 1))?;
+        buf[..512].copy_from_slice(&data);
         Ok(512)
     }
 
@@ -357,14 +557,23 @@ impl BlockDevice for PataBlockDevice {
         if buf.len() != 512 {
             return Err(VfsError::BadBufferSize);
         }
-        let mut data: [u8; 512] = [0; 512];
-        data.copy_from_slice(buf);
-        self.controller
-            .write()
-            .write_sector(lba, &data)
-            .map_err(|e| VfsError::DriverError(Box::new(e)))?;
+        self.check_generation()?;
+        Self::check_range(&self.controller, lba, 1)?;
+        self.submit_and_wait(BlockRequest::write(lba, 1, buf.to_vec()))?;
         Ok(512)
     }
+
+    fn submit(&mut self, request: BlockRequest) {
+        if request.kind != BlockRequestKind::Barrier {
+            if let Err(err) = self.check_generation() {
+                return request.fail(err);
+            }
+        }
+        self.queue.push(request);
+        self.queue.kick(512, &mut |kind, lba, buf| {
+            Self::dispatch(&self.controller, kind, lba, buf)
+        });
+    }
 }
 
 #[derive(Debug)]
@@ -376,6 +585,14 @@ pub struct PataSpecificFileData {
 
 impl FsSpecificFileData for PataSpecificFileData {}
 
+/// Stable name of a drive under `/dev/disk/by-id`, `ata-<description>`
+fn by_id_name(device: &dyn BlockDevice) -> String {
+    format!("ata-{}", device.description().unwrap_or_default())
+        .chars()
+        .map(|c| if c == ' ' || c == '/' { '_' } else { c })
+        .collect()
+}
+
 const PATA: u64 = u64::from_be_bytes([0, 0, 0, 0, b'p', b'a', b't', b'a']);
 
 #[derive(Debug, Clone)]
@@ -399,40 +616,54 @@ impl DevFsDriver for PataDevfsDriver {
         dev_fs: &mut DevFs,
         pci_device: &PciDevice,
         device_id: usize,
-    ) -> Result<(), VfsError> {
+    ) -> Result<Vec<Vec<char>>, VfsError> {
         if !self.handles_device(dev_fs, pci_device) {
             return Err(VfsError::ActionNotAllowed);
         }
-        for (name, controller) in [
+        let mut hooks = Vec::new();
+        // Same numbers as the linux IDE disks, partitions follow their disk
+        for (device_key, (name, controller, number)) in [
             (
                 "pata_pm".chars().collect::<Vec<_>>(),
                 self.controller_pm.clone(),
+                DeviceNumber::new(3, 0),
             ),
             (
                 "pata_ps".chars().collect::<Vec<_>>(),
                 self.controller_ps.clone(),
+                DeviceNumber::new(3, 64),
             ),
             (
                 "pata_sm".chars().collect::<Vec<_>>(),
                 self.controller_sm.clone(),
+                DeviceNumber::new(22, 0),
             ),
             (
                 "pata_ss".chars().collect::<Vec<_>>(),
                 self.controller_ss.clone(),
+                DeviceNumber::new(22, 64),
             ),
-        ] {
+        ]
+        .into_iter()
+        .enumerate()
+        {
+            let device_key = device_key as u64;
             let guard = controller.write();
             let reload_partitions = if let Some(last_parts) = guard.outdated_partitions() {
                 let sname = name.iter().collect::<String>();
                 for i in 0..last_parts.len() {
                     dev_fs.remove_hook(&format!("{sname}_p{i}").chars().collect::<Vec<_>>());
                 }
+                self.unpublish_id(dev_fs, &name);
                 if !guard.is_present() {
+                    drop(guard);
+                    log_info!(target: "pata", "{}: media removed", sname);
                     dev_fs.remove_hook(&name);
                     continue;
                 }
                 true
             } else if !guard.is_present() {
+                self.unpublish_id(dev_fs, &name);
                 dev_fs.remove_hook(&name);
                 continue;
             } else {
@@ -441,10 +672,9 @@ impl DevFsDriver for PataDevfsDriver {
             let generation = guard.generation;
             let (bus, drive) = (guard.bus, guard.drive);
             drop(guard);
-            let device: Arc<RwLock<Box<dyn BlockDevice>>> =
-                arcrwb_new_from_box(Box::new(PataBlockDevice {
-                    controller: controller.clone(),
-                }));
+            let device: Arcrwb<dyn BlockDevice> =
+                arcrwb_new_from_box(Box::new(PataBlockDevice::new(controller.clone())));
+            let id_name = by_id_name(&**device.read());
             if reload_partitions {
                 let sname = name.iter().collect::<String>();
                 let mut manager = PartitionManager::new();
@@ -452,10 +682,15 @@ impl DevFsDriver for PataDevfsDriver {
 
                 for (i, partition) in manager.get_partitions().iter().enumerate() {
                     let name = format!("{sname}_p{i}");
+                    dev_fs.register_device_number(
+                        true,
+                        DeviceNumber::new(number.major, number.minor + 1 + i as u32),
+                        &name.chars().collect::<Vec<_>>(),
+                    );
 
                     let range = partition.as_device_range();
 
-                    let device: Arc<RwLock<Box<dyn BlockDevice>>> = arcrwb_new_from_box(Box::new(
+                    let device: Arcrwb<dyn BlockDevice> = arcrwb_new_from_box(Box::new(
                         SubBlockDevice::new(device.clone(), range.start, range.end),
                     ));
 
@@ -471,6 +706,11 @@ impl DevFsDriver for PataDevfsDriver {
                             partition: Some(partition.clone()),
                         }),
                     );
+                    let by_id_name = format!("{id_name}-part{}", i + 1);
+                    let by_id_file = file.clone_renamed(by_id_name.chars().collect());
+                    let by_id_path = format!("disk/by-id/{by_id_name}")
+                        .chars()
+                        .collect::<Vec<_>>();
                     dev_fs.replace_hook(
                         name.chars().collect(),
                         self.driver_id(),
@@ -478,8 +718,31 @@ impl DevFsDriver for PataDevfsDriver {
                         DevFsHookKind::Device,
                         generation,
                         i as u64,
+                        device_key,
+                    );
+                    dev_fs.replace_hook(
+                        by_id_path.clone(),
+                        self.driver_id(),
+                        by_id_file,
+                        DevFsHookKind::Device,
+                        generation,
+                        i as u64,
+                        device_key,
                     );
+                    hooks.push(name.chars().collect());
+                    hooks.push(by_id_path);
                 }
+                log_info!(
+                    target: "pata",
+                    "{}: {} ({} partitions)",
+                    sname,
+                    id_name,
+                    manager.get_partitions().len()
+                );
+                self.published_ids.insert(
+                    name.clone(),
+                    (id_name.clone(), manager.get_partitions().len()),
+                );
 
                 let mut guard = controller.write();
                 guard.partition_manager = manager;
@@ -497,67 +760,96 @@ impl DevFsDriver for PataDevfsDriver {
                     partition: None,
                 }),
             );
+            let by_id_file = file.clone_renamed(id_name.chars().collect());
+            let by_id_path = format!("disk/by-id/{id_name}").chars().collect::<Vec<_>>();
+            dev_fs.register_device_number(true, number, &name);
             dev_fs.replace_hook(
-                name,
+                name.clone(),
                 self.driver_id(),
                 file,
                 DevFsHookKind::Device,
                 generation,
                 device_id as u64,
+                device_key,
+            );
+            dev_fs.replace_hook(
+                by_id_path.clone(),
+                self.driver_id(),
+                by_id_file,
+                DevFsHookKind::Device,
+                generation,
+                device_id as u64,
+                device_key,
             );
+            hooks.push(name.clone());
+            hooks.push(by_id_path);
+            self.published_ids.entry(name).or_insert((id_name, 0));
         }
-        Ok(())
+        Ok(hooks)
     }
 
     fn handles_device(&self, _dev_fs: &mut DevFs, pci_device: &PciDevice) -> bool {
         self.pci_device == *pci_device
     }
 
+    fn poll_devices(&mut self, dev_fs: &mut DevFs) -> Result<(), VfsError> {
+        let mut changed = false;
+        for controller in [
+            &self.controller_pm,
+            &self.controller_ps,
+            &self.controller_sm,
+            &self.controller_ss,
+        ] {
+            let mut guard = controller.write();
+            let last_identify = guard.identify_data;
+            if guard.identify().is_err() {
+                guard.identify_data = [0; 256];
+            }
+            if guard.identify_data != last_identify {
+                // Invalidates the open handles and the partitions of the previous media
+                guard.generation += 1;
+                changed = true;
+            }
+        }
+        if changed {
+            let pci_device = self.pci_device;
+            let device_id = dev_fs
+                .device_index(&pci_device)
+                .ok_or(VfsError::ActionNotAllowed)?;
+            dev_fs.refresh_hooks(self, &pci_device, device_id)?;
+        }
+        Ok(())
+    }
+
     fn fopen(
         &mut self,
         dev_fs: &mut DevFs,
         hook: Arc<DevFsHook>,
         mode: u64,
     ) -> Result<u64, VfsError> {
-        let controller = if hook.file.name().get(0..7) == Some(&['p', 'a', 't', 'a', '_', 'p', 'm'])
-        {
-            &self.controller_pm
-        } else if hook.file.name().get(0..7) == Some(&['p', 'a', 't', 'a', '_', 'p', 's']) {
-            &self.controller_ps
-        } else if hook.file.name().get(0..7) == Some(&['p', 'a', 't', 'a', '_', 's', 'm']) {
-            &self.controller_sm
-        } else if hook.file.name().get(0..7) == Some(&['p', 'a', 't', 'a', '_', 's', 's']) {
-            &self.controller_ss
-        } else {
-            return Err(VfsError::PathNotFound);
-        };
+        // The same device can be hooked under several names (pata_pm, disk/by-id/...), so the
+        // hook's key picks the controller and the file data the partition
+        let data = hook.file.get_fs_specific_data();
+        let data = data
+            .as_any()
+            .downcast_ref::<PataSpecificFileData>()
+            .ok_or(VfsError::PathNotFound)?;
+        let controller = self
+            .controller(hook.device_key)
+            .ok_or(VfsError::PathNotFound)?;
 
         let guard = controller.read();
         if !guard.is_present() {
             return Err(VfsError::PathNotFound);
         }
 
-        let disk_range = if hook.file.name().get(7..9) == Some(&['_', 'p']) {
-            if let Some(partition_i) = hook
-                .file
-                .name()
-                .get(9..)
-                .and_then(|s| s.iter().collect::<String>().parse::<usize>().ok())
-            {
-                let partition = guard
-                    .partition_manager
-                    .get_partition(partition_i)
-                    .ok_or(VfsError::PathNotFound)?;
-                partition.as_device_range()
-            } else {
-                return Err(VfsError::PathNotFound);
-            }
-        } else {
-            guard.get_range()
+        let disk_range = match &data.partition {
+            Some(partition) => partition.as_device_range(),
+            None => guard.get_range(),
         };
         drop(guard);
 
-        if mode & OPEN_MODE_APPEND != 0 {
+        if mode & OPEN_MODE_APPEND != 0 || mode & (OPEN_MODE_READ | OPEN_MODE_WRITE) == 0 {
             return Err(VfsError::InvalidOpenMode);
         }
 
@@ -591,12 +883,14 @@ impl DevFsDriver for PataDevfsDriver {
                 .get_handle_data::<PataFsFileHandle>(handle)
                 .ok_or(VfsError::BadHandle)?)
         };
-        let controller = handle_data.controller.read();
+        let mut controller = handle_data.controller.write();
         if controller.generation != handle_data.generation {
             return Err(VfsError::BadHandle);
         }
 
-        Ok(())
+        controller
+            .flush_cache()
+            .map_err(|e| VfsError::DriverError(Box::new(e)))
     }
 
     fn fsync(&mut self, dev_fs: &mut DevFs, handle: u64) -> Result<(), VfsError> {
@@ -634,16 +928,19 @@ impl DevFsDriver for PataDevfsDriver {
                 .get_handle_data::<PataFsFileHandle>(handle)
                 .ok_or(VfsError::BadHandle)?)
         };
+        if handle_data.mode & OPEN_MODE_READ == 0 {
+            return Err(VfsError::ActionNotAllowed);
+        }
+
         let controller = handle_data.controller.read();
+        if controller.generation != handle_data.generation {
+            return Err(VfsError::BadHandle);
+        }
 
         if !controller.is_present() {
             return Err(VfsError::PathNotFound);
         }
 
-        if handle_data.mode & OPEN_MODE_READ == 0 {
-            return Err(VfsError::ActionNotAllowed);
-        }
-
         let range_size_bytes = (handle_data.disk_range.end - handle_data.disk_range.start) * 512;
 
         let mut bytes_read = 0;
@@ -687,7 +984,7 @@ impl DevFsDriver for PataDevfsDriver {
                 .get_handle_data::<PataFsFileHandle>(handle)
                 .ok_or(VfsError::BadHandle)?)
         };
-        if (handle_data.mode & OPEN_MODE_READ) == 0 {
+        if handle_data.mode & OPEN_MODE_WRITE == 0 {
             return Err(VfsError::ActionNotAllowed);
         }
 
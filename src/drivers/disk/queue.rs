@@ -0,0 +1,324 @@
+//! Request queue between the file systems and a block driver
+//!
+//! Requests on adjacent or overlapping blocks are merged into one dispatch, and dispatches follow
+//! C-SCAN order: up from the last dispatched lba, then wrapping to the lowest one. Nothing is moved
+//! across a barrier, nor across an earlier request on the same blocks unless both are reads.
+//!
+//! The queue is drained by the thread that submits to it, unless another thread already is, which
+//! then picks up the new request too. A blocked thread resumes by running its syscall again, which
+//! a driver call in the middle of a file system operation can't do, so `wait` keeps the thread on
+//! the CPU.
+
+use core::{
+    arch::asm,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use alloc::{sync::Arc, vec, vec::Vec};
+use spin::Mutex;
+
+use crate::{
+    data::regs::rflags::{RFlag, RFlags},
+    drivers::vfs::{BlockDevice, VfsError},
+};
+
+/// Blocks a merged dispatch is limited to
+const MAX_MERGED_BLOCKS: u64 = 256;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlockRequestKind {
+    Read,
+    Write,
+    /// Completes once every request queued before it is on the media
+    Barrier,
+}
+
+/// Where the result of a request is delivered
+#[derive(Debug, Default)]
+pub struct BlockCompletion {
+    result: Mutex<Option<Result<Vec<u8>, VfsError>>>,
+}
+
+// Errors can hold a driver error, which is only ever debug printed
+unsafe impl Send for BlockCompletion {}
+unsafe impl Sync for BlockCompletion {}
+
+impl BlockCompletion {
+    pub fn is_done(&self) -> bool {
+        self.result.lock().is_some()
+    }
+
+    fn complete(&self, result: Result<Vec<u8>, VfsError>) {
+        *self.result.lock() = Some(result);
+    }
+
+    /// The result of a finished request, `None` while it runs
+    pub fn take_result(&self) -> Option<Result<Vec<u8>, VfsError>> {
+        self.result.lock().take()
+    }
+
+    /// Waits until the request is done, reads return the blocks read. The CPU sleeps until the
+    /// next interrupt between checks, or spins while interrupts are off, like before they are
+    /// set up
+    pub fn wait(&self) -> Result<Vec<u8>, VfsError> {
+        loop {
+            if let Some(result) = self.take_result() {
+                return result;
+            }
+            if RFlags::read().has(RFlag::InterruptFlag) {
+                unsafe {
+                    asm!("hlt");
+                }
+            } else {
+                core::hint::spin_loop();
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct BlockRequest {
+    pub kind: BlockRequestKind,
+    pub lba: u64,
+    pub count: u64,
+    /// Blocks written, empty for other kinds
+    data: Vec<u8>,
+    completion: Arc<BlockCompletion>,
+}
+
+impl BlockRequest {
+    fn new(kind: BlockRequestKind, lba: u64, count: u64, data: Vec<u8>) -> Self {
+        Self {
+            kind,
+            lba,
+            count,
+            data,
+            completion: Arc::new(BlockCompletion::default()),
+        }
+    }
+
+    pub fn read(lba: u64, count: u64) -> Self {
+        Self::new(BlockRequestKind::Read, lba, count, Vec::new())
+    }
+
+    /// `data` must hold exactly `count` blocks
+    pub fn write(lba: u64, count: u64, data: Vec<u8>) -> Self {
+        Self::new(BlockRequestKind::Write, lba, count, data)
+    }
+
+    pub fn barrier() -> Self {
+        Self::new(BlockRequestKind::Barrier, 0, 0, Vec::new())
+    }
+
+    pub fn completion(&self) -> Arc<BlockCompletion> {
+        self.completion.clone()
+    }
+
+    fn end(&self) -> u64 {
+        self.lba + self.count
+    }
+
+    fn overlaps(&self, other: &BlockRequest) -> bool {
+        self.lba < other.end() && other.lba < self.end()
+    }
+
+    /// Moves the request `offset` blocks further, for devices that are a range of another one
+    pub fn offset_by(mut self, offset: u64) -> Self {
+        self.lba += offset;
+        self
+    }
+
+    /// Completes the request right away with an error, without running it
+    pub fn fail(self, error: VfsError) {
+        self.completion.complete(Err(error));
+    }
+
+    /// Runs the request with the synchronous methods of `device`
+    pub fn run_on<D: BlockDevice + ?Sized>(self, device: &mut D) {
+        let block_size = device.get_block_size() as usize;
+        let result = match self.kind {
+            BlockRequestKind::Read => {
+                let mut data = vec![0; self.count as usize * block_size];
+                data.chunks_mut(block_size)
+                    .zip(self.lba..)
+                    .try_for_each(|(block, lba)| device.read_block(lba, block).map(|_| ()))
+                    .map(|_| data)
+            }
+            BlockRequestKind::Write if self.data.len() != self.count as usize * block_size => {
+                Err(VfsError::BadBufferSize)
+            }
+            BlockRequestKind::Write => self
+                .data
+                .chunks(block_size)
+                .zip(self.lba..)
+                .try_for_each(|(block, lba)| device.write_block(lba, block).map(|_| ()))
+                .map(|_| Vec::new()),
+            BlockRequestKind::Barrier => device.flush_cache().map(|_| Vec::new()),
+        };
+        self.completion.complete(result);
+    }
+}
+
+/// Runs one dispatch on the driver: a read or write of `buf.len()` bytes from `lba`, or a barrier
+/// with an empty buffer
+pub type BlockDispatch<'a> =
+    dyn FnMut(BlockRequestKind, u64, &mut [u8]) -> Result<(), VfsError> + 'a;
+
+#[derive(Debug, Default)]
+pub struct RequestQueue {
+    pending: Mutex<Vec<BlockRequest>>,
+    /// Lba following the last dispatch, where the elevator resumes
+    head: AtomicU64,
+    /// Held by the thread draining the queue
+    draining: Mutex<()>,
+}
+
+impl RequestQueue {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn push(&self, request: BlockRequest) {
+        self.pending.lock().push(request);
+    }
+
+    /// Drains the queue with `dispatch`, unless another thread already is
+    pub fn kick(&self, block_size: u64, dispatch: &mut BlockDispatch) {
+        loop {
+            let Some(guard) = self.draining.try_lock() else {
+                return;
+            };
+            loop {
+                let batch = core::mem::take(&mut *self.pending.lock());
+                if batch.is_empty() {
+                    break;
+                }
+                self.run_batch(batch, block_size, dispatch);
+            }
+            drop(guard);
+            // A request pushed before the guard was released may have seen it held
+            if self.pending.lock().is_empty() {
+                return;
+            }
+        }
+    }
+
+    /// Queues `request` and drains the queue, then waits for the request
+    pub fn submit_and_wait(
+        &self,
+        request: BlockRequest,
+        block_size: u64,
+        dispatch: &mut BlockDispatch,
+    ) -> Result<Vec<u8>, VfsError> {
+        let completion = request.completion();
+        self.push(request);
+        self.kick(block_size, dispatch);
+        completion.wait()
+    }
+
+    fn run_batch(&self, batch: Vec<BlockRequest>, block_size: u64, dispatch: &mut BlockDispatch) {
+        let mut group: Vec<BlockRequest> = Vec::new();
+        for request in batch {
+            if request.kind == BlockRequestKind::Write
+                && request.data.len() != request.count as usize * block_size as usize
+            {
+                request.fail(VfsError::BadBufferSize);
+                continue;
+            }
+            if request.kind != BlockRequestKind::Barrier && request.count == 0 {
+                request.completion.complete(Ok(Vec::new()));
+                continue;
+            }
+            if request.kind == BlockRequestKind::Barrier {
+                self.run_group(core::mem::take(&mut group), block_size, dispatch);
+                let result = dispatch(BlockRequestKind::Barrier, 0, &mut []);
+                request.completion.complete(result.map(|_| Vec::new()));
+                continue;
+            }
+            // Overlapping writes stay in submission order, so the last one wins
+            if group.iter().any(|queued| {
+                (queued.kind == BlockRequestKind::Write || request.kind == BlockRequestKind::Write)
+                    && queued.overlaps(&request)
+            }) {
+                self.run_group(core::mem::take(&mut group), block_size, dispatch);
+            }
+            group.push(request);
+        }
+        self.run_group(group, block_size, dispatch);
+    }
+
+    /// Runs requests that can be reordered freely, in C-SCAN order and merged
+    fn run_group(
+        &self,
+        mut group: Vec<BlockRequest>,
+        block_size: u64,
+        dispatch: &mut BlockDispatch,
+    ) {
+        let head = self.head.load(Ordering::Relaxed);
+        group.sort_by_key(|request| (request.lba < head, request.lba));
+
+        let mut requests = group.into_iter().peekable();
+        while let Some(first) = requests.next() {
+            let kind = first.kind;
+            let begin = first.lba;
+            let wrapped = begin < head;
+            let mut end = first.end();
+            let mut run = vec![first];
+            while let Some(next) = requests.peek() {
+                // Runs never cross the wrap point, the lowest lbas come after it
+                if next.kind != kind
+                    || (next.lba < head) != wrapped
+                    || next.lba < begin
+                    || next.lba > end
+                    || next.end().max(end) - begin > MAX_MERGED_BLOCKS
+                {
+                    break;
+                }
+                end = end.max(next.end());
+                run.extend(requests.next());
+            }
+            self.head.store(end, Ordering::Relaxed);
+            Self::run_merged(run, begin, end, block_size as usize, dispatch);
+        }
+    }
+
+    /// Runs requests covering `begin..end` in a single dispatch, one by one if it fails
+    fn run_merged(
+        mut run: Vec<BlockRequest>,
+        begin: u64,
+        end: u64,
+        block_size: usize,
+        dispatch: &mut BlockDispatch,
+    ) {
+        let kind = run[0].kind;
+        let mut buf = vec![0; (end - begin) as usize * block_size];
+        if kind == BlockRequestKind::Write {
+            for request in run.iter() {
+                let offset = (request.lba - begin) as usize * block_size;
+                buf[offset..offset + request.data.len()].copy_from_slice(&request.data);
+            }
+        }
+
+        match dispatch(kind, begin, &mut buf) {
+            Ok(()) => {
+                for request in run {
+                    let data = match kind {
+                        BlockRequestKind::Read => {
+                            let offset = (request.lba - begin) as usize * block_size;
+                            buf[offset..offset + request.count as usize * block_size].to_vec()
+                        }
+                        _ => Vec::new(),
+                    };
+                    request.completion.complete(Ok(data));
+                }
+            }
+            Err(e) if run.len() == 1 => run.pop().unwrap().fail(e),
+            Err(_) => {
+                for request in run {
+                    let (begin, end) = (request.lba, request.end());
+                    Self::run_merged(vec![request], begin, end, block_size, dispatch);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,175 @@
+//! Block devices kept in memory, published as `/dev/ramN`. The boot creates `/dev/ram0` when the
+//! `ramdisk.size` config key isn't 0.
+//!
+//! The memory is allocated a page at a time when a block is first written, blocks that were never
+//! written read as zeros.
+
+use alloc::{
+    alloc::{alloc_zeroed, dealloc},
+    boxed::Box,
+    format,
+    sync::Arc,
+    vec::Vec,
+};
+use core::{
+    alloc::Layout,
+    ptr::NonNull,
+    sync::atomic::{AtomicU32, AtomicUsize, Ordering},
+};
+
+use crate::{
+    drivers::{
+        fs::virt::devfs::with_devfs,
+        vfs::{arcrwb_new_from_box, Arcrwb, BlockDevice, DeviceNumber, FileSystem, VfsError},
+    },
+    paging::PAGE_SIZE,
+};
+
+use super::node::BlockDeviceNodeProvider;
+
+/// Same major number as the linux ram disks
+pub const RAMDISK_MAJOR: u32 = 1;
+pub const RAMDISK_BLOCK_SIZE: u64 = 512;
+
+const BLOCKS_PER_PAGE: u64 = PAGE_SIZE as u64 / RAMDISK_BLOCK_SIZE;
+
+static NEXT_RAMDISK: AtomicU32 = AtomicU32::new(0);
+
+#[derive(Debug)]
+struct RamDiskPage(NonNull<u8>);
+
+// The page is only reached through the disk that owns it
+unsafe impl Send for RamDiskPage {}
+unsafe impl Sync for RamDiskPage {}
+
+impl RamDiskPage {
+    const LAYOUT: Layout = match Layout::from_size_align(PAGE_SIZE, PAGE_SIZE) {
+        Ok(layout) => layout,
+        Err(_) => panic!("bad page layout"),
+    };
+
+    fn new() -> Option<Self> {
+        NonNull::new(unsafe { alloc_zeroed(Self::LAYOUT) }).map(Self)
+    }
+
+    fn block(&self, index: u64) -> &[u8] {
+        let offset = (index % BLOCKS_PER_PAGE * RAMDISK_BLOCK_SIZE) as usize;
+        unsafe {
+            core::slice::from_raw_parts(self.0.as_ptr().add(offset), RAMDISK_BLOCK_SIZE as usize)
+        }
+    }
+
+    fn block_mut(&mut self, index: u64) -> &mut [u8] {
+        let offset = (index % BLOCKS_PER_PAGE * RAMDISK_BLOCK_SIZE) as usize;
+        unsafe {
+            core::slice::from_raw_parts_mut(
+                self.0.as_ptr().add(offset),
+                RAMDISK_BLOCK_SIZE as usize,
+            )
+        }
+    }
+}
+
+impl Drop for RamDiskPage {
+    fn drop(&mut self) {
+        unsafe { dealloc(self.0.as_ptr(), Self::LAYOUT) }
+    }
+}
+
+#[derive(Debug)]
+pub struct RamDisk {
+    pages: Vec<Option<RamDiskPage>>,
+    block_count: u64,
+    generation: u64,
+}
+
+impl RamDisk {
+    /// `size` is rounded up to a whole block
+    pub fn new(size: u64) -> Result<Self, VfsError> {
+        if size == 0 {
+            return Err(VfsError::InvalidArgument);
+        }
+        let block_count = size.div_ceil(RAMDISK_BLOCK_SIZE);
+        let mut pages = Vec::new();
+        pages.resize_with(block_count.div_ceil(BLOCKS_PER_PAGE) as usize, || None);
+        Ok(Self {
+            pages,
+            block_count,
+            generation: 0,
+        })
+    }
+
+    /// Acts as a media change, what was opened on the disk before goes stale
+    pub fn bump_generation(&mut self) {
+        self.generation += 1;
+    }
+
+    fn check_access(&self, lba: u64, len: usize) -> Result<(), VfsError> {
+        if lba >= self.block_count {
+            return Err(VfsError::OutOfBounds);
+        }
+        if (len as u64) < RAMDISK_BLOCK_SIZE {
+            return Err(VfsError::BadBufferSize);
+        }
+        Ok(())
+    }
+}
+
+impl BlockDevice for RamDisk {
+    fn get_generation(&self) -> u64 {
+        self.generation
+    }
+
+    fn get_block_size(&self) -> u64 {
+        RAMDISK_BLOCK_SIZE
+    }
+
+    fn get_block_count(&self) -> u64 {
+        self.block_count
+    }
+
+    fn read_block(&self, lba: u64, buf: &mut [u8]) -> Result<u64, VfsError> {
+        self.check_access(lba, buf.len())?;
+        let buf = &mut buf[..RAMDISK_BLOCK_SIZE as usize];
+        match &self.pages[(lba / BLOCKS_PER_PAGE) as usize] {
+            Some(page) => buf.copy_from_slice(page.block(lba)),
+            None => buf.fill(0),
+        }
+        Ok(RAMDISK_BLOCK_SIZE)
+    }
+
+    fn write_block(&mut self, lba: u64, buf: &[u8]) -> Result<u64, VfsError> {
+        self.check_access(lba, buf.len())?;
+        let slot = &mut self.pages[(lba / BLOCKS_PER_PAGE) as usize];
+        let page = match slot {
+            Some(page) => page,
+            None => slot.insert(RamDiskPage::new().ok_or(VfsError::OutOfSpace)?),
+        };
+        page.block_mut(lba)
+            .copy_from_slice(&buf[..RAMDISK_BLOCK_SIZE as usize]);
+        Ok(RAMDISK_BLOCK_SIZE)
+    }
+
+    fn flush(&mut self) -> Result<(), VfsError> {
+        Ok(())
+    }
+}
+
+/// Publishes a new ram disk of `size` bytes as `/dev/ramN` and returns N
+pub fn create_ramdisk(size: u64) -> Result<u32, VfsError> {
+    let device: Arcrwb<dyn BlockDevice> = arcrwb_new_from_box(Box::new(RamDisk::new(size)?));
+    let index = NEXT_RAMDISK.fetch_add(1, Ordering::AcqRel);
+    let name = format!("ram{index}").chars().collect::<Vec<_>>();
+    with_devfs(|_, devfs| {
+        let provider = BlockDeviceNodeProvider::new(
+            devfs.os_id(),
+            name.clone(),
+            device,
+            Arc::new(AtomicUsize::new(0)),
+        );
+        devfs.insert_vfile(arcrwb_new_from_box(Box::new(provider)), &name);
+        devfs.register_device_number(true, DeviceNumber::new(RAMDISK_MAJOR, index), &name);
+        Ok(())
+    })?;
+    Ok(index)
+}
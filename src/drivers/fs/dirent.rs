@@ -0,0 +1,107 @@
+//! Records read from directory handles. `fread` on a directory fills the buffer with whole records:
+//! the inode (u64), the entry type (u8), the name length (u16), then the name, little endian and
+//! unpadded.
+//!
+//! The position of a directory handle is an opaque offset of its file system, `fseek` only takes
+//! `FromStart` of a position the handle gave before (0 rewinds) or `FromCurrent(0)` to read it.
+
+use crate::drivers::vfs::{SeekPosition, VfsError, VfsFileKind};
+
+/// Entry types, the linux `DT_*` values
+pub const DT_UNKNOWN: u8 = 0;
+pub const DT_FIFO: u8 = 1;
+pub const DT_CHR: u8 = 2;
+pub const DT_DIR: u8 = 4;
+pub const DT_BLK: u8 = 6;
+pub const DT_REG: u8 = 8;
+pub const DT_LNK: u8 = 10;
+pub const DT_SOCK: u8 = 12;
+
+const HEADER_SIZE: usize = 8 + 1 + 2;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DirectoryRecord<'a> {
+    pub inode: u64,
+    pub kind: u8,
+    pub name: &'a [u8],
+}
+
+impl<'a> DirectoryRecord<'a> {
+    pub fn size(&self) -> usize {
+        HEADER_SIZE + self.name.len()
+    }
+
+    /// The record at the start of `buf` and its size
+    pub fn parse(buf: &'a [u8]) -> Option<(Self, usize)> {
+        let header = buf.get(..HEADER_SIZE)?;
+        let name_len = u16::from_le_bytes([header[9], header[10]]) as usize;
+        let record = Self {
+            inode: u64::from_le_bytes(header[..8].try_into().ok()?),
+            kind: header[8],
+            name: buf.get(HEADER_SIZE..HEADER_SIZE + name_len)?,
+        };
+        Some((record, HEADER_SIZE + name_len))
+    }
+
+    fn write(&self, buf: &mut [u8]) {
+        buf[..8].copy_from_slice(&self.inode.to_le_bytes());
+        buf[8] = self.kind;
+        buf[9..HEADER_SIZE].copy_from_slice(&(self.name.len() as u16).to_le_bytes());
+        buf[HEADER_SIZE..self.size()].copy_from_slice(self.name);
+    }
+}
+
+/// Type of an entry from the kind of its `VfsFile`
+pub fn record_kind(kind: &VfsFileKind) -> u8 {
+    match kind {
+        VfsFileKind::File => DT_REG,
+        VfsFileKind::Directory | VfsFileKind::MountPoint { .. } => DT_DIR,
+        VfsFileKind::BlockDevice { .. } | VfsFileKind::DeviceNode { block: true, .. } => DT_BLK,
+        VfsFileKind::CharacterDevice { .. } | VfsFileKind::DeviceNode { block: false, .. } => {
+            DT_CHR
+        }
+        VfsFileKind::Pipe { .. } => DT_FIFO,
+        VfsFileKind::Symlink => DT_LNK,
+    }
+}
+
+/// New position of a directory handle at `current`
+pub fn seek_directory(position: SeekPosition, current: u64) -> Result<u64, VfsError> {
+    match position {
+        SeekPosition::FromStart(position) => Ok(position),
+        SeekPosition::FromCurrent(0) => Ok(current),
+        _ => Err(VfsError::InvalidSeekPosition),
+    }
+}
+
+/// Fills a buffer with whole records
+#[derive(Debug)]
+pub struct DirectoryRecordWriter<'a> {
+    buf: &'a mut [u8],
+    written: usize,
+}
+
+impl<'a> DirectoryRecordWriter<'a> {
+    pub fn new(buf: &'a mut [u8]) -> Self {
+        Self { buf, written: 0 }
+    }
+
+    /// Returns false once the buffer is full, the handle then stays on this entry.
+    /// Fails if not even the first record fits
+    pub fn push(&mut self, record: &DirectoryRecord) -> Result<bool, VfsError> {
+        let end = self.written + record.size();
+        if end > self.buf.len() {
+            return match self.written {
+                0 => Err(VfsError::BadBufferSize),
+                _ => Ok(false),
+            };
+        }
+        record.write(&mut self.buf[self.written..end]);
+        self.written = end;
+        Ok(true)
+    }
+
+    pub fn written(&self) -> u64 {
+        self.written as u64
+    }
+}
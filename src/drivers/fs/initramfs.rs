@@ -0,0 +1,214 @@
+//! Initial file system loaded by the bootloader. The CPIO archive is unpacked into a tmpfs
+//! mounted at `INITRD_MOUNT_POINT`, which the kernel searches for sysinit before the system
+//! partition
+
+use alloc::{boxed::Box, vec::Vec};
+
+use crate::{
+    cmdline::init_path,
+    data::{
+        file::File,
+        path::{VfsName, VfsPath},
+    },
+    drivers::{
+        fs::{
+            mount::{mount_system_fallback, MountError, SYSTEM_MOUNT_POINT},
+            virt::tmpfs::{TmpFs, TmpNode, TmpNodeData},
+        },
+        vfs::{get_vfs, VfsError},
+    },
+    formats::cpio::{CpioArchive, CpioEntryKind, CpioError},
+    log_info, log_warn,
+    obsiboot::ObsiBootKernelParameters,
+    paging::physical_to_virtual,
+};
+
+pub const INITRD_MOUNT_POINT: &str = "/initrd";
+pub const INITRD_SYSINIT: &str = "/initrd/sysinit";
+
+#[derive(Debug)]
+pub enum InitramfsError {
+    Cpio(CpioError),
+    Vfs(VfsError),
+}
+
+impl From<CpioError> for InitramfsError {
+    fn from(value: CpioError) -> Self {
+        Self::Cpio(value)
+    }
+}
+
+impl From<VfsError> for InitramfsError {
+    fn from(value: VfsError) -> Self {
+        Self::Vfs(value)
+    }
+}
+
+fn mount_name() -> Vec<char> {
+    INITRD_MOUNT_POINT.trim_matches('/').chars().collect()
+}
+
+/// Finds the directory `components` of the path, creating the missing ones
+fn make_parents<'a>(
+    fs: &mut TmpFs,
+    components: impl Iterator<Item = &'a VfsName>,
+) -> Result<u64, VfsError> {
+    let mut directory = fs.root_ino();
+    for name in components {
+        directory = match fs.lookup(directory, name) {
+            Ok(ino) => ino,
+            Err(VfsError::PathNotFound) => fs.insert(
+                directory,
+                name,
+                TmpNode::new(TmpNodeData::Directory(Default::default()), 0o755, 0, 0, 0),
+            )?,
+            Err(e) => return Err(e),
+        };
+    }
+    Ok(directory)
+}
+
+/// Unpacks the directories, files and symlinks of a newc archive into `fs`, with their modes and
+/// owners. Other entries and the data of hard links are skipped. Returns the entries unpacked
+pub fn unpack_cpio(fs: &mut TmpFs, archive: &[u8]) -> Result<usize, InitramfsError> {
+    let mut unpacked = 0;
+    for entry in CpioArchive::new(archive) {
+        let entry = entry?;
+        let components = entry
+            .path()
+            .split(|b| *b == b'/')
+            .filter(|name| !name.is_empty())
+            .map(|name| VfsName::new(name).ok_or(VfsError::InvalidArgument))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let data = match entry.kind() {
+            CpioEntryKind::Directory => TmpNodeData::Directory(Default::default()),
+            CpioEntryKind::File => TmpNodeData::File(entry.data.to_vec()),
+            CpioEntryKind::Symlink => TmpNodeData::Symlink(entry.data.to_vec()),
+            CpioEntryKind::Other => {
+                log_warn!(
+                    target: "initramfs",
+                    "Skipping {}, mode {:o}",
+                    entry.path().escape_ascii(),
+                    entry.mode
+                );
+                continue;
+            }
+        };
+        let node = TmpNode::new(
+            data,
+            entry.permissions(),
+            entry.uid,
+            entry.gid,
+            entry.mtime as u64,
+        );
+
+        let Some((name, parents)) = components.split_last() else {
+            // The root itself, only its attributes are kept
+            let root = fs.node_mut(fs.root_ino())?;
+            root.mode = node.mode;
+            (root.uid, root.gid) = (node.uid, node.gid);
+            continue;
+        };
+        let parent = make_parents(fs, parents.iter().copied())?;
+        match fs.lookup(parent, name) {
+            // Created earlier as the parent of another entry
+            Ok(ino) if matches!(node.data, TmpNodeData::Directory(_)) => {
+                let existing = fs.node_mut(ino)?;
+                if !matches!(existing.data, TmpNodeData::Directory(_)) {
+                    return Err(VfsError::FileAlreadyExists.into());
+                }
+                existing.mode = node.mode;
+                (existing.uid, existing.gid) = (node.uid, node.gid);
+                existing.modified_at = node.modified_at;
+            }
+            Ok(_) => return Err(VfsError::FileAlreadyExists.into()),
+            Err(VfsError::PathNotFound) => {
+                fs.insert(parent, name, node)?;
+            }
+            Err(e) => return Err(e.into()),
+        }
+        unpacked += 1;
+    }
+    Ok(unpacked)
+}
+
+/// Unpacks the archive loaded by the bootloader and mounts it at `INITRD_MOUNT_POINT`.
+/// Returns false if there is no archive or it is invalid
+pub fn mount_initramfs(obsiboot: &ObsiBootKernelParameters) -> bool {
+    let Some((address, size)) = obsiboot.initramfs() else {
+        return false;
+    };
+    let archive = unsafe {
+        core::slice::from_raw_parts(physical_to_virtual(address) as *const u8, size as usize)
+    };
+
+    let mut fs = TmpFs::new();
+    let unpacked = match unpack_cpio(&mut fs, archive) {
+        Ok(unpacked) => unpacked,
+        Err(err) => {
+            log_warn!(target: "initramfs", "Invalid initramfs archive: {:?}", err);
+            return false;
+        }
+    };
+    if let Err(err) = get_vfs().write().mount(&mount_name(), Box::new(fs), 0) {
+        log_warn!(target: "initramfs", "Failed to mount the initramfs: {:?}", err);
+        return false;
+    }
+    log_info!(
+        target: "initramfs",
+        "Unpacked {} entries ({} bytes) at {}",
+        unpacked,
+        size,
+        INITRD_MOUNT_POINT
+    );
+    true
+}
+
+fn is_mounted(path: &str) -> bool {
+    let path = VfsPath::from(path);
+    get_vfs()
+        .read()
+        .list_mounted_fs()
+        .iter()
+        .any(|mount| mount.path == path)
+}
+
+pub fn is_initramfs_mounted() -> bool {
+    is_mounted(INITRD_MOUNT_POINT)
+}
+
+/// The `init=` of the command line, or the first sysinit found in the initramfs then in the
+/// system partition. The initramfs is released with `switch_root` unless sysinit is in it
+pub fn find_sysinit() -> &'static str {
+    if let Some(init) = init_path() {
+        let in_initramfs = init
+            .strip_prefix(INITRD_MOUNT_POINT)
+            .is_some_and(|rest| rest.starts_with('/'));
+        if !in_initramfs {
+            if let Err(err) = switch_root() {
+                log_warn!(target: "initramfs", "switch_root failed: {:?}", err);
+            }
+        }
+        return init;
+    }
+    if is_initramfs_mounted() && File::get_stats(INITRD_SYSINIT).is_ok_and(|s| s.is_some()) {
+        return INITRD_SYSINIT;
+    }
+    if let Err(err) = switch_root() {
+        log_warn!(target: "initramfs", "switch_root failed: {:?}", err);
+    }
+    "/system/sysinit"
+}
+
+/// Leaves the initramfs for the system partition: mounts `SYSTEM_MOUNT_POINT` if it isn't yet,
+/// then detaches the initramfs, freed once its last open file is closed
+pub fn switch_root() -> Result<(), MountError> {
+    if !is_mounted(SYSTEM_MOUNT_POINT) {
+        mount_system_fallback()?;
+    }
+    if is_initramfs_mounted() {
+        get_vfs().write().unmount_lazy(&mount_name())?;
+    }
+    Ok(())
+}
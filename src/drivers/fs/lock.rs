@@ -0,0 +1,131 @@
+//! Advisory whole-file locks, as taken by flock.
+//! A lock belongs to an open file, so every fd duplicated from it shares the lock
+
+use alloc::{collections::BTreeMap, sync::Arc, vec::Vec};
+use spin::Mutex;
+
+use crate::process::wait::WaitQueue;
+
+/// (file system os_id, `FileSystem::file_identity`)
+pub type FileLockKey = (u64, u64);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileLockKind {
+    Shared,
+    Exclusive,
+}
+
+#[derive(Debug, Default)]
+struct FileLock {
+    shared: Vec<u64>,
+    exclusive: Option<u64>,
+    waiters: Arc<WaitQueue>,
+}
+
+impl FileLock {
+    fn is_free(&self) -> bool {
+        self.shared.is_empty() && self.exclusive.is_none()
+    }
+
+    fn remove_owner(&mut self, owner: u64) -> bool {
+        let had_shared = self.shared.len();
+        self.shared.retain(|o| *o != owner);
+        let had_exclusive = self.exclusive.take_if(|o| *o == owner).is_some();
+        had_exclusive || had_shared != self.shared.len()
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct FileLocks {
+    locks: BTreeMap<FileLockKey, FileLock>,
+}
+
+impl FileLocks {
+    pub const fn new() -> Self {
+        Self {
+            locks: BTreeMap::new(),
+        }
+    }
+
+    /// Takes or converts the lock of `owner` on `key`. On conflict the lock `owner` already held
+    /// is dropped, like linux does, so two owners upgrading a shared lock can't deadlock, and the
+    /// queue to wait on is returned
+    pub fn try_lock(
+        &mut self,
+        key: FileLockKey,
+        owner: u64,
+        kind: FileLockKind,
+    ) -> Result<(), Arc<WaitQueue>> {
+        let lock = self.locks.entry(key).or_default();
+        let conflict = match kind {
+            FileLockKind::Shared => lock.exclusive.is_some_and(|o| o != owner),
+            FileLockKind::Exclusive => {
+                lock.exclusive.is_some_and(|o| o != owner)
+                    || lock.shared.iter().any(|o| *o != owner)
+            }
+        };
+        if conflict {
+            if lock.remove_owner(owner) {
+                lock.waiters.wake_all();
+            }
+            return Err(lock.waiters.clone());
+        }
+
+        let downgraded = lock.exclusive == Some(owner) && kind == FileLockKind::Shared;
+        lock.remove_owner(owner);
+        match kind {
+            FileLockKind::Shared => lock.shared.push(owner),
+            FileLockKind::Exclusive => lock.exclusive = Some(owner),
+        }
+        if downgraded {
+            lock.waiters.wake_all();
+        }
+        Ok(())
+    }
+
+    pub fn unlock(&mut self, key: FileLockKey, owner: u64) {
+        if let Some(lock) = self.locks.get_mut(&key) {
+            if lock.remove_owner(owner) {
+                lock.waiters.wake_all();
+            }
+        }
+        self.remove_if_unused(key);
+    }
+
+    /// Drops every lock of `owner`, when its open file is closed
+    pub fn release_owner(&mut self, owner: u64) {
+        let keys = self.locks.keys().copied().collect::<Vec<_>>();
+        for key in keys {
+            self.unlock(key, owner);
+        }
+    }
+
+    /// Drops every lock on the file system `fs`, when it is unmounted
+    pub fn release_fs(&mut self, fs: u64) {
+        self.locks.retain(|(lock_fs, _), lock| {
+            if *lock_fs == fs {
+                lock.waiters.wake_all();
+            }
+            *lock_fs != fs
+        });
+    }
+
+    pub fn holders(&self, key: FileLockKey) -> (usize, Option<u64>) {
+        self.locks
+            .get(&key)
+            .map(|lock| (lock.shared.len(), lock.exclusive))
+            .unwrap_or_default()
+    }
+
+    fn remove_if_unused(&mut self, key: FileLockKey) {
+        if self
+            .locks
+            .get(&key)
+            .is_some_and(|lock| lock.is_free() && Arc::strong_count(&lock.waiters) == 1)
+        {
+            self.locks.remove(&key);
+        }
+    }
+}
+
+pub static FILE_LOCKS: Mutex<FileLocks> = Mutex::new(FileLocks::new());
@@ -1,2 +1,6 @@
+pub mod dirent;
+pub mod initramfs;
+pub mod lock;
+pub mod mount;
 pub mod phys;
 pub mod virt;
@@ -0,0 +1,465 @@
+//! Boot mount table. The system partition is found and mounted first, then the entries of
+//! `FSTAB_PATH` are mounted in order
+
+use core::num::NonZeroUsize;
+
+use alloc::{
+    boxed::Box,
+    string::{String, ToString},
+    vec::Vec,
+};
+
+use crate::{
+    cmdline::root_device,
+    data::{
+        alloc_boxed_slice,
+        file::File,
+        partition::{Partition, PartitionManager},
+        path::VfsPath,
+        permissions::{Credentials, Permissions},
+    },
+    drivers::{
+        disk::pata::PataSpecificFileData,
+        fs::{initramfs::is_initramfs_mounted, phys::ext2::Ext2Volume},
+        vfs::{
+            arcrwb_new_from_box, get_vfs, FileSystem, SubBlockDevice, VfsError, MS_RDONLY,
+            OPEN_MODE_READ, OPEN_MODE_WRITE,
+        },
+    },
+    log_error, log_info, log_warn,
+};
+
+/// `device mountpoint fstype [options]` lines, '#' starts a comment line
+pub const FSTAB_PATH: &str = "/system/etc/fstab";
+pub const MAX_FSTAB_SIZE: u64 = 4096;
+
+pub const SYSTEM_MOUNT_POINT: &str = "/system";
+/// Disk searched for the system partition when the table doesn't mount it
+pub const SYSTEM_DISK: &str = "/dev/pata_pm";
+/// Blocks prefetched by the system partition on sequential reads
+pub const SYSTEM_READ_AHEAD_BLOCKS: usize = 32;
+
+const DEFAULT_CACHE_SIZE: NonZeroUsize = NonZeroUsize::new(1024 * 1024).unwrap();
+
+pub type FileSystemConstructor = fn(&File, &MountOptions) -> Result<Box<dyn FileSystem>, VfsError>;
+
+/// File system drivers that can be mounted from a device, by fstype
+pub const FILE_SYSTEM_CONSTRUCTORS: &[(&str, FileSystemConstructor)] = &[("ext2", mount_ext2)];
+
+#[derive(Debug, Clone)]
+pub struct MountOptions {
+    pub read_only: bool,
+    /// In bytes
+    pub block_cache_size: NonZeroUsize,
+    /// In bytes
+    pub block_bitmap_cache_size: NonZeroUsize,
+    /// In bytes
+    pub inode_bitmap_cache_size: NonZeroUsize,
+    pub read_ahead_blocks: usize,
+    /// Barriers between the metadata write stages, see the ext2 `order` module
+    pub barriers: bool,
+    /// Report the writes that could reach the disk out of order
+    pub check_order: bool,
+}
+
+impl Default for MountOptions {
+    fn default() -> Self {
+        Self {
+            read_only: false,
+            block_cache_size: DEFAULT_CACHE_SIZE,
+            block_bitmap_cache_size: DEFAULT_CACHE_SIZE,
+            inode_bitmap_cache_size: DEFAULT_CACHE_SIZE,
+            read_ahead_blocks: SYSTEM_READ_AHEAD_BLOCKS,
+            barriers: true,
+            check_order: false,
+        }
+    }
+}
+
+impl MountOptions {
+    /// Comma separated `ro`, `rw`, `defaults`, `cache=`, `block_bitmap_cache=`,
+    /// `inode_bitmap_cache=` (in bytes), `read_ahead=` (in blocks), `barrier`, `nobarrier` and
+    /// `check_order`
+    pub fn parse(text: &str) -> Result<Self, MountError> {
+        let mut options = Self::default();
+        for option in text.split(',').filter(|option| !option.is_empty()) {
+            let (name, value) = match option.split_once('=') {
+                Some((name, value)) => (name, Some(value)),
+                None => (option, None),
+            };
+            let size = || {
+                value
+                    .and_then(|value| value.parse::<usize>().ok())
+                    .and_then(NonZeroUsize::new)
+                    .ok_or(MountError::BadOption(option.to_string()))
+            };
+            match name {
+                "defaults" => {}
+                "ro" => options.read_only = true,
+                "rw" => options.read_only = false,
+                "barrier" => options.barriers = true,
+                "nobarrier" => options.barriers = false,
+                "check_order" => options.check_order = true,
+                "cache" => options.block_cache_size = size()?,
+                "block_bitmap_cache" => options.block_bitmap_cache_size = size()?,
+                "inode_bitmap_cache" => options.inode_bitmap_cache_size = size()?,
+                "read_ahead" => {
+                    options.read_ahead_blocks = value
+                        .and_then(|value| value.parse().ok())
+                        .ok_or(MountError::BadOption(option.to_string()))?
+                }
+                _ => return Err(MountError::BadOption(option.to_string())),
+            }
+        }
+        Ok(options)
+    }
+}
+
+#[derive(Debug)]
+pub enum MountError {
+    BadEntry(&'static str),
+    BadOption(String),
+    UnknownFileSystem(String),
+    DeviceNotFound(String),
+    NotBlockDevice(String),
+    Vfs(VfsError),
+}
+
+impl From<VfsError> for MountError {
+    fn from(value: VfsError) -> Self {
+        MountError::Vfs(value)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct MountEntry {
+    /// A devfs path, `PARTLABEL=<name>` or `PARTUUID=<guid>` of a GPT partition
+    pub device: String,
+    /// Absolute path
+    pub mount_point: String,
+    pub fs_type: String,
+    pub options: MountOptions,
+}
+
+impl MountEntry {
+    pub fn parse(line: &str) -> Result<Self, MountError> {
+        let mut fields = line.split_whitespace();
+        let (Some(device), Some(mount_point), Some(fs_type)) =
+            (fields.next(), fields.next(), fields.next())
+        else {
+            return Err(MountError::BadEntry(
+                "expected `device mountpoint fstype [options]`",
+            ));
+        };
+        let options = MountOptions::parse(fields.next().unwrap_or_default())?;
+        if fields.next().is_some() {
+            return Err(MountError::BadEntry("trailing fields"));
+        }
+        if !mount_point.starts_with('/') || mount_point.trim_matches('/').is_empty() {
+            return Err(MountError::BadEntry(
+                "mount point must be an absolute path below /",
+            ));
+        }
+        Ok(Self {
+            device: device.to_string(),
+            mount_point: mount_point.to_string(),
+            fs_type: fs_type.to_string(),
+            options,
+        })
+    }
+
+    fn mount_name(&self) -> Vec<char> {
+        self.mount_point.trim_matches('/').chars().collect()
+    }
+}
+
+/// Parses the table, bad lines are reported and skipped
+pub fn parse_mount_table(source: &str, text: &str) -> Vec<(usize, MountEntry)> {
+    let mut entries = Vec::new();
+    for (index, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        match MountEntry::parse(line) {
+            Ok(entry) => entries.push((index + 1, entry)),
+            Err(err) => log_warn!(target: "mount", "{}:{}: {:?}", source, index + 1, err),
+        }
+    }
+    entries
+}
+
+fn mount_ext2(device: &File, options: &MountOptions) -> Result<Box<dyn FileSystem>, VfsError> {
+    // The volume keeps its own handle on the device
+    let device = File::open0(
+        device.get_path().clone(),
+        device.get_open_mode(),
+        Permissions::from_u64(0),
+        Credentials::Kernel,
+    )?;
+    let mut volume = Ext2Volume::from_device(
+        device,
+        options.block_cache_size,
+        options.block_bitmap_cache_size,
+        options.inode_bitmap_cache_size,
+        options.read_ahead_blocks,
+    )?;
+    volume.set_ordered_writes(options.barriers);
+    volume.set_validate_write_order(options.check_order);
+    Ok(Box::new(volume))
+}
+
+/// Parses the usual text form, the first three groups are little endian
+fn parse_guid(text: &str) -> Option<[u8; 16]> {
+    let groups = text.split('-').collect::<Vec<_>>();
+    if groups.iter().map(|group| group.len()).ne([8, 4, 4, 4, 12]) {
+        return None;
+    }
+    let mut guid = [0u8; 16];
+    let mut offset = 0;
+    for (i, group) in groups.iter().enumerate() {
+        let bytes = (0..group.len())
+            .step_by(2)
+            .map(|j| u8::from_str_radix(group.get(j..j + 2)?, 16).ok())
+            .collect::<Option<Vec<u8>>>()?;
+        let target = &mut guid[offset..offset + bytes.len()];
+        target.copy_from_slice(&bytes);
+        if i < 3 {
+            target.reverse();
+        }
+        offset += bytes.len();
+    }
+    Some(guid)
+}
+
+/// The devfs path of the device named by an entry
+fn resolve_device(spec: &str) -> Result<String, MountError> {
+    if spec.starts_with('/') {
+        return Ok(spec.to_string());
+    }
+    let wanted: Box<dyn Fn(&Partition) -> bool> = if let Some(label) =
+        spec.strip_prefix("PARTLABEL=")
+    {
+        let label = label.chars().collect::<Vec<char>>();
+        Box::new(
+            move |partition| matches!(partition, Partition::GPT(entry, _) if entry.name == label),
+        )
+    } else if let Some(guid) = spec.strip_prefix("PARTUUID=") {
+        let guid = parse_guid(guid).ok_or(MountError::BadEntry("bad PARTUUID"))?;
+        Box::new(
+            move |partition| matches!(partition, Partition::GPT(entry, _) if entry.unique_guid == guid),
+        )
+    } else {
+        return Err(MountError::BadEntry("unknown device specification"));
+    };
+
+    File::list_directory("/dev")?
+        .iter()
+        .find(|entry| {
+            let data = entry.get_vfs_file().get_fs_specific_data();
+            (*data)
+                .as_any()
+                .downcast_ref::<PataSpecificFileData>()
+                .and_then(|data| data.partition.as_ref())
+                .is_some_and(&wanted)
+        })
+        .map(|entry| entry.full_name().iter().collect())
+        .ok_or(MountError::DeviceNotFound(spec.to_string()))
+}
+
+pub fn mount_entry(entry: &MountEntry) -> Result<(), MountError> {
+    let flags = if entry.options.read_only {
+        MS_RDONLY
+    } else {
+        0
+    };
+    mount_device(
+        &resolve_device(&entry.device)?,
+        &entry.mount_name(),
+        &entry.fs_type,
+        &entry.options,
+        flags,
+    )
+}
+
+/// Mounts the file system of type `fs_type` on the block device at `device` (a devfs device or
+/// a device node) at `target`. `MS_RDONLY` in `flags` or `options` makes the mount read-only
+pub fn mount_device(
+    device: &str,
+    target: &[char],
+    fs_type: &str,
+    options: &MountOptions,
+    flags: u64,
+) -> Result<(), MountError> {
+    let (_, constructor) = FILE_SYSTEM_CONSTRUCTORS
+        .iter()
+        .find(|(name, _)| *name == fs_type)
+        .ok_or(MountError::UnknownFileSystem(fs_type.to_string()))?;
+
+    let file = get_vfs()
+        .write()
+        .get_file(&VfsPath::from(device))
+        .map_err(|e| match e {
+            VfsError::PathNotFound => MountError::DeviceNotFound(device.to_string()),
+            e => MountError::Vfs(e),
+        })?;
+    if !file.is_block_device() && !matches!(file.get_device_node(), Some((true, _))) {
+        return Err(MountError::NotBlockDevice(device.to_string()));
+    }
+
+    let mut options = options.clone();
+    options.read_only |= flags & MS_RDONLY != 0;
+    let flags = if options.read_only {
+        flags | MS_RDONLY
+    } else {
+        flags
+    };
+
+    // A read-only mount keeps a writable device when it can, to be remounted read-write later
+    let open = |mode| File::open(device, mode, Permissions::from_u64(0), Credentials::Kernel);
+    let device = match open(OPEN_MODE_READ | OPEN_MODE_WRITE) {
+        Ok(device) => device,
+        Err(_) if options.read_only => open(OPEN_MODE_READ)?,
+        Err(e) => return Err(e.into()),
+    };
+    let fs = constructor(&device, &options)?;
+    drop(device);
+
+    get_vfs().write().mount(target, fs, flags)?;
+    Ok(())
+}
+
+/// Mounts the first ext2 partition of `SYSTEM_DISK`, used when the table has no entry for
+/// `SYSTEM_MOUNT_POINT` or it fails. The `root=` of the command line replaces `SYSTEM_DISK`, and
+/// can also be the partition itself
+pub fn mount_system_fallback() -> Result<(), MountError> {
+    let name = SYSTEM_MOUNT_POINT
+        .trim_matches('/')
+        .chars()
+        .collect::<Vec<_>>();
+    let disk_path = root_device().unwrap_or(SYSTEM_DISK);
+    if root_device().is_some()
+        && mount_device(disk_path, &name, "ext2", &MountOptions::default(), 0).is_ok()
+    {
+        return Ok(());
+    }
+
+    let vfs = get_vfs();
+    let disk = vfs
+        .write()
+        .get_file(&VfsPath::from(disk_path))
+        .ok()
+        .and_then(|file| file.get_block_device())
+        .ok_or(MountError::DeviceNotFound(disk_path.to_string()))?;
+
+    let mut partitions = PartitionManager::new();
+    partitions.reload_partitions(disk.clone())?;
+    let options = MountOptions::default();
+    let ext2 = partitions
+        .get_partitions()
+        .iter()
+        .find_map(|partition| {
+            let range = partition.as_device_range();
+            Ext2Volume::from_block_device(
+                arcrwb_new_from_box(Box::new(SubBlockDevice::new(
+                    disk.clone(),
+                    range.start,
+                    range.end,
+                ))),
+                options.block_cache_size,
+                options.block_bitmap_cache_size,
+                options.inode_bitmap_cache_size,
+                options.read_ahead_blocks,
+            )
+            .ok()
+        })
+        .ok_or(MountError::DeviceNotFound(disk_path.to_string()))?;
+
+    vfs.write().mount(&name, Box::new(ext2), 0)?;
+    Ok(())
+}
+
+fn read_mount_table() -> Option<String> {
+    let stats = File::get_stats(FSTAB_PATH).ok()??;
+    if stats.size > MAX_FSTAB_SIZE {
+        log_warn!(target: "mount", "{} is too big, ignoring it", FSTAB_PATH);
+        return None;
+    }
+    let file = File::open(
+        FSTAB_PATH,
+        OPEN_MODE_READ,
+        Permissions::from_u64(0),
+        Credentials::Kernel,
+    )
+    .ok()?;
+    let mut buffer = alloc_boxed_slice(stats.size as usize);
+    match file.read(&mut buffer) {
+        Ok(read) if read == stats.size => {}
+        result => {
+            log_warn!(target: "mount", "Failed to read {}: {:?}", FSTAB_PATH, result);
+            return None;
+        }
+    }
+    String::from_utf8(buffer.into_vec()).ok()
+}
+
+/// Mounts the system partition, then every entry of the table. A failing entry is reported
+/// and the remaining ones are still mounted. Panics if there is no system partition
+pub fn mount_all() {
+    if let Err(err) = mount_system_fallback() {
+        if !is_initramfs_mounted() {
+            panic!("Failed to mount the system partition: {:?}", err);
+        }
+        // The initramfs can mount it later
+        log_warn!(target: "mount", "Failed to mount the system partition: {:?}", err);
+        return;
+    }
+    let Some(text) = read_mount_table() else {
+        log_info!(target: "mount", "No mount table at {}", FSTAB_PATH);
+        return;
+    };
+
+    for (line, entry) in parse_mount_table(FSTAB_PATH, &text) {
+        if entry.mount_name()
+            == SYSTEM_MOUNT_POINT
+                .trim_matches('/')
+                .chars()
+                .collect::<Vec<_>>()
+        {
+            // Remount the system partition with the options of the table
+            let name = entry.mount_name();
+            if let Err(err) = get_vfs().write().unmount(&name) {
+                log_error!(
+                    target: "mount",
+                    "{}:{}: failed to unmount the fallback {}: {:?}",
+                    FSTAB_PATH, line, SYSTEM_MOUNT_POINT, err
+                );
+                continue;
+            }
+            if let Err(err) = mount_entry(&entry) {
+                log_error!(
+                    target: "mount",
+                    "{}:{}: {} on {}: {:?}, using the fallback",
+                    FSTAB_PATH, line, entry.device, entry.mount_point, err
+                );
+                if let Err(err) = mount_system_fallback() {
+                    panic!("Failed to mount the system partition: {:?}", err);
+                }
+            }
+            continue;
+        }
+
+        match mount_entry(&entry) {
+            Ok(()) => log_info!(
+                target: "mount",
+                "Mounted {} ({}) on {}",
+                entry.device, entry.fs_type, entry.mount_point
+            ),
+            Err(err) => log_error!(
+                target: "mount",
+                "{}:{}: {} on {}: {:?}",
+                FSTAB_PATH, line, entry.device, entry.mount_point, err
+            ),
+        }
+    }
+}
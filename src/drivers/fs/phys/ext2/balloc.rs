@@ -58,6 +58,17 @@ impl BlockAllocator {
         &mut self.diff_usage
     }
 
+    /// Blocks allocated minus freed since the counters were last updated
+    pub fn diff_usage(&self) -> i64 {
+        self.diff_usage
+    }
+
+    /// Number of free blocks in the group according to the bitmap
+    pub fn count_free(&self) -> u32 {
+        let total = self.max_block_exclusive - self.min_block_inclusive;
+        total - self.bitmap.count_set_until(total as usize) as u32
+    }
+
     pub fn read_all(&mut self, volume: &mut Ext2Volume) -> Result<(), VfsError> {
         let slice = self.bitmap.as_mut_slice();
         for (i, lba) in (self.bitmap_begin_inclusive..self.bitmap_end_exclusive).enumerate() {
@@ -67,6 +78,10 @@ impl BlockAllocator {
         Ok(())
     }
 
+    pub fn is_dirty(&self) -> bool {
+        self.dirty_blocks_bitmap.find_first_set().is_some()
+    }
+
     pub fn write_dirty(&mut self, volume: &mut Ext2Volume) -> Result<(), VfsError> {
         for (i, lba) in (self.bitmap_begin_inclusive..self.bitmap_end_exclusive).enumerate() {
             if self.dirty_blocks_bitmap.get_bit(i).unwrap_or(false) {
@@ -81,7 +96,9 @@ impl BlockAllocator {
     }
 
     pub fn alloc_block(&mut self) -> Result<u32, VfsError> {
-        match self.bitmap.find_first_unset() {
+        let blocks = (self.max_block_exclusive - self.min_block_inclusive) as usize;
+        // The bits past the end of the group should be set, but don't trust them
+        match self.bitmap.find_first_unset().filter(|bit| *bit < blocks) {
             Some(bit_index) => {
                 self.bitmap.set_bit(bit_index, true);
                 self.diff_usage += 1;
@@ -119,7 +136,7 @@ impl BlockAllocator {
 
 impl Drop for BlockAllocator {
     fn drop(&mut self) {
-        if self.dirty_blocks_bitmap.find_first_set().is_some() {
+        if self.is_dirty() {
             panic!("Dropping block allocator with dirty blocks !!");
         }
     }
@@ -0,0 +1,148 @@
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use crate::{
+    data::{
+        file::File,
+        lock::{DebugMutex, LockClass},
+    },
+    drivers::{
+        disk::queue::BlockRequest,
+        vfs::{
+            Arcrwb, BlockDevice, BlockDeviceAsCharacterDevice, CharacterDevice, SeekPosition,
+            VfsError, OPEN_MODE_APPEND, OPEN_MODE_READ, OPEN_MODE_WRITE,
+        },
+    },
+};
+
+#[derive(Debug)]
+enum Backing {
+    File(File),
+    /// Accesses smaller than a device block are aggregated by the wrapper
+    Block {
+        device: Arcrwb<dyn BlockDevice>,
+        bytes: BlockDeviceAsCharacterDevice,
+    },
+}
+
+/// Byte addressed access to the storage of a volume
+#[derive(Debug)]
+pub struct Ext2Device {
+    backing: Backing,
+    /// Held across a file seek and the access that follows
+    seek_lock: DebugMutex<()>,
+    /// Set once an access found the media changed
+    stale: AtomicBool,
+}
+
+impl Ext2Device {
+    pub fn from_file(file: File) -> Result<Self, VfsError> {
+        if (file.get_open_mode() & OPEN_MODE_READ) == 0
+            || (file.get_open_mode() & OPEN_MODE_APPEND) == OPEN_MODE_APPEND
+        {
+            return Err(VfsError::InvalidOpenMode);
+        }
+        Ok(Self {
+            backing: Backing::File(file),
+            seek_lock: DebugMutex::with_class((), LockClass::Ext2Device),
+            stale: AtomicBool::new(false),
+        })
+    }
+
+    pub fn from_block_device(device: Arcrwb<dyn BlockDevice>) -> Self {
+        Self {
+            backing: Backing::Block {
+                bytes: BlockDeviceAsCharacterDevice::new(device.clone()),
+                device,
+            },
+            seek_lock: DebugMutex::with_class((), LockClass::Ext2Device),
+            stale: AtomicBool::new(false),
+        }
+    }
+
+    pub fn is_stale(&self) -> bool {
+        self.stale.load(Ordering::Relaxed)
+    }
+
+    fn track<T>(&self, result: Result<T, VfsError>) -> Result<T, VfsError> {
+        if let Err(VfsError::StaleDevice) = result {
+            self.stale.store(true, Ordering::Relaxed);
+        }
+        result
+    }
+
+    pub fn is_writable(&self) -> bool {
+        if self.is_stale() {
+            return false;
+        }
+        match &self.backing {
+            Backing::File(file) => (file.get_open_mode() & OPEN_MODE_WRITE) != 0,
+            Backing::Block { .. } => true,
+        }
+    }
+
+    pub fn size(&self) -> Result<u64, VfsError> {
+        match &self.backing {
+            Backing::File(file) => Ok(file.stats()?.size),
+            Backing::Block { bytes, .. } => Ok(bytes.get_size()),
+        }
+    }
+
+    /// The block device holding the volume, if the backing file is a device node
+    pub fn block_device(&self) -> Option<Arcrwb<dyn BlockDevice>> {
+        match &self.backing {
+            Backing::File(file) => file.get_vfs_file().get_block_device(),
+            Backing::Block { device, .. } => Some(device.clone()),
+        }
+    }
+
+    pub fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<u64, VfsError> {
+        let result = match &self.backing {
+            Backing::File(file) => {
+                let _guard = self.seek_lock.lock();
+                file.seek(SeekPosition::FromStart(offset))
+                    .and_then(|_| file.read(buf))
+            }
+            Backing::Block { bytes, .. } => bytes.read_chars(offset, buf),
+        };
+        self.track(result)
+    }
+
+    pub fn write_at(&mut self, offset: u64, buf: &[u8]) -> Result<u64, VfsError> {
+        let result = match &mut self.backing {
+            Backing::File(file) => {
+                let _guard = self.seek_lock.lock();
+                file.seek(SeekPosition::FromStart(offset))
+                    .and_then(|_| file.write(buf))
+            }
+            Backing::Block { bytes, .. } => bytes.write_chars(offset, buf),
+        };
+        self.track(result)
+    }
+
+    pub fn flush(&mut self) -> Result<(), VfsError> {
+        match &mut self.backing {
+            Backing::File(file) => file.flush(),
+            Backing::Block { bytes, .. } => bytes.flush(),
+        }
+    }
+
+    /// Waits for every write queued before, so the next ones can't reach the disk ahead of them
+    pub fn barrier(&mut self) -> Result<(), VfsError> {
+        match &mut self.backing {
+            Backing::File(file) => file.flush(),
+            Backing::Block { device, .. } => {
+                let request = BlockRequest::barrier();
+                let completion = request.completion();
+                device.write().submit(request);
+                self.track(completion.wait().map(|_| ()))
+            }
+        }
+    }
+
+    pub fn close(&mut self) -> Result<(), VfsError> {
+        match &mut self.backing {
+            Backing::File(file) => unsafe { file._close() },
+            Backing::Block { .. } => Ok(()),
+        }
+    }
+}
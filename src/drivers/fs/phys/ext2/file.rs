@@ -1,15 +1,22 @@
-use alloc::{boxed::Box, vec::Vec};
-
-use crate::{
-    data::alloc_boxed_slice,
-    drivers::{
-        fs::virt::devfs::fseek_helper,
-        vfs::{BlockDevice, SeekPosition, VfsError, OPEN_MODE_WRITE},
+use alloc::{boxed::Box, collections::BTreeMap, vec::Vec};
+
+use crate::drivers::{
+    fs::{
+        dirent::{
+            DirectoryRecord, DirectoryRecordWriter, DT_BLK, DT_CHR, DT_DIR, DT_FIFO, DT_LNK,
+            DT_REG, DT_SOCK, DT_UNKNOWN,
+        },
+        virt::devfs::fseek_helper,
+    },
+    time::get_unix_timestamp,
+    vfs::{
+        Arcrwb, BlockDevice, FileSystem, SeekPosition, VfsError, OPEN_MODE_READ, OPEN_MODE_WRITE,
     },
 };
 
 use super::{
-    inode::{CachedInodeReadingLocation, Inode},
+    inode::{CachedInodeReadingLocation, Inode, InodeType},
+    order::WriteStage,
     superblock::RequiredFeature,
     Ext2Volume,
 };
@@ -21,6 +28,34 @@ struct BlockCacheInfo {
     dirty: bool,
 }
 
+/// Number of consecutive in-order block reads before read-ahead kicks in
+const READ_AHEAD_TRIGGER: u32 = 2;
+
+#[derive(Debug, Clone, Copy, Default)]
+struct ReadAheadState {
+    last_block: Option<u32>,
+    sequential_reads: u32,
+    /// Blocks up to this index (exclusive) have already been prefetched
+    prefetched_until: u32,
+}
+
+/// Handle of an open device node, reads and writes go to the device opened on the devfs
+#[derive(Debug, Clone)]
+pub struct DeviceNodeHandle {
+    pub inode_i: u32,
+    pub devfs: Arcrwb<dyn FileSystem>,
+    pub handle: u64,
+}
+
+/// Handle of an open directory, reads give `DirectoryRecord`s. The inode is read again on each
+/// read so entries created since show up after a rewind
+#[derive(Debug, Clone)]
+pub struct DirectoryHandle {
+    pub inode: Inode,
+    /// Index of the next entry in the directory file
+    pub position: u64,
+}
+
 #[derive(Debug, Clone)]
 pub struct FileHandle {
     location: CachedInodeReadingLocation,
@@ -30,31 +65,71 @@ pub struct FileHandle {
 
     block_cache: Box<[u8]>,
     block_cache_info: Option<BlockCacheInfo>,
+    read_ahead: ReadAheadState,
 }
 
 impl FileHandle {
     pub fn new(volume: &mut Ext2Volume, inode: Inode, open_mode: u64) -> Result<Self, VfsError> {
-        let bs = volume.get_block_size();
         let size = inode.get_size(volume);
         Ok(Self {
             location: CachedInodeReadingLocation::new(volume, inode)?,
             offset: 0,
             size,
-            block_cache: alloc_boxed_slice::<u8>(bs as usize),
+            block_cache: volume.alloc_block_buffer()?,
             block_cache_info: None,
             open_mode,
+            read_ahead: ReadAheadState::default(),
         })
     }
 
+    /// Feeds the sequential read detector with the block that was just loaded, and prefetches the following blocks when needed
+    fn read_ahead(&mut self, volume: &mut Ext2Volume) -> Result<(), VfsError> {
+        let count = volume.get_read_ahead_blocks();
+        let block = self.location.current_block_idx();
+        let state = &mut self.read_ahead;
+
+        match state.last_block {
+            Some(last) if last + 1 == block => state.sequential_reads += 1,
+            Some(last) if last == block => {}
+            _ => {
+                state.sequential_reads = 0;
+                state.prefetched_until = 0;
+            }
+        }
+        state.last_block = Some(block);
+
+        // Prefetch again once we're halfway through the previously prefetched window
+        if count == 0
+            || state.sequential_reads < READ_AHEAD_TRIGGER
+            || block + count / 2 < state.prefetched_until
+        {
+            return Ok(());
+        }
+
+        let blocks = self.location.peek_following_blocks(count);
+        self.read_ahead.prefetched_until = block + 1 + blocks.len() as u32;
+        volume.prefetch_blocks(&blocks)
+    }
+
+    /// The cached block goes first, the tables and inode referencing it after
     pub fn flush(&mut self, volume: &mut Ext2Volume) -> Result<(), VfsError> {
-        self.location.flush(volume)?;
         if let Some(info) = &mut self.block_cache_info {
             if info.dirty {
+                volume.order_write(Self::data_stage(self.location.get_inode()))?;
                 self.location.write_block(volume, &self.block_cache)?;
                 info.dirty = false;
             }
         }
-        Ok(())
+        self.location.flush(volume)
+    }
+
+    /// The blocks of a directory are its entries
+    fn data_stage(inode: &Inode) -> WriteStage {
+        if inode.inode_type == InodeType::Directory {
+            WriteStage::DirectoryEntry
+        } else {
+            WriteStage::Data
+        }
     }
 
     fn dirty(&mut self) {
@@ -90,12 +165,17 @@ impl FileHandle {
             .try_into()
             .map_err(|e| VfsError::DriverError(Box::new(e)))?;
 
+        let mut freed = 0u32;
         while self.location.block_count() > new_block_count {
-            self.location.free_last_block(volume)?;
+            freed += self.location.free_last_block(volume)?;
         }
 
         self.size = new_size;
-        self.location.get_inode_mut().set_size(volume, new_size);
+        let inode = self.location.get_inode_mut();
+        inode.set_size(volume, new_size)?;
+        inode.sectors_count = inode
+            .sectors_count
+            .saturating_sub(freed.saturating_mul(volume.sectors_per_block));
         volume.update_inode(self.get_inode())?;
 
         self.flush(volume)?;
@@ -117,7 +197,7 @@ impl FileHandle {
             .try_into()
             .map_err(|e| VfsError::DriverError(Box::new(e)))?;
 
-        let mut diff_alloc = 0;
+        let mut diff_alloc = 0u32;
         while self.location.block_count() < new_block_count {
             let allocated_block_count = self.location.allocate_new_block(volume)?;
             diff_alloc += allocated_block_count;
@@ -125,9 +205,12 @@ impl FileHandle {
 
         self.size = new_size;
         let inode = self.location.get_inode_mut();
-        inode.set_size(volume, new_size);
-        inode.sectors_count += diff_alloc * volume.sectors_per_block;
-        volume.update_inode(self.get_inode())?;
+        inode.set_size(volume, new_size)?;
+        inode.sectors_count = diff_alloc
+            .checked_mul(volume.sectors_per_block)
+            .and_then(|sectors| inode.sectors_count.checked_add(sectors))
+            .ok_or(VfsError::MaximumSizeReached)?;
+        volume.mark_inode_dirty(self.get_inode());
 
         self.flush(volume)?;
         self.block_cache_info = None;
@@ -146,14 +229,30 @@ impl FileHandle {
             .try_into()
             .map_err(|e| VfsError::DriverError(Box::new(e)))?;
 
+        if let Some(last) = self.read_ahead.last_block {
+            if block_offset != last && block_offset != last + 1 {
+                // Random access, reset the sequential read detector
+                self.read_ahead = ReadAheadState::default();
+            }
+        }
+
         self.offset = new_offset;
         self.location.seek(volume, block_offset)?;
         self.internal_update_buffer(volume)?;
         Ok(())
     }
 
+    /// Bytes of the file in the cached block, 0 if there is none
+    fn cached_block_len(&self) -> u64 {
+        self.block_cache_info.map_or(0, |info| info.size as u64)
+    }
+
     pub fn read(&mut self, volume: &mut Ext2Volume, buffer: &mut [u8]) -> Result<u64, VfsError> {
-        let max_count = (buffer.len() as u64).min(self.size - self.offset);
+        // The end of the file can be in the middle of the buffer, or before the offset
+        let max_count = (buffer.len() as u64).min(self.size.saturating_sub(self.offset));
+        if max_count == 0 {
+            return Ok(0);
+        }
         self.flush(volume)?;
         let bs = volume.get_block_size();
         let current_block = (self.offset / bs) as u32;
@@ -165,8 +264,7 @@ impl FileHandle {
         if let Some(info) = self.block_cache_info {
             if current_block == info.block {
                 let curr_off = self.offset % bs;
-                let block_rem = bs - curr_off;
-                let to_copy = max_count.min(block_rem);
+                let to_copy = max_count.min(bs - curr_off);
 
                 buffer[0..to_copy as usize].copy_from_slice(
                     &self.block_cache[curr_off as usize..(curr_off + to_copy) as usize],
@@ -179,9 +277,14 @@ impl FileHandle {
                 if !self.location.advance(volume)? {
                     break;
                 }
+                self.read_ahead(volume)?;
                 self.internal_update_buffer(volume)?;
 
-                let rem_copy = (max_count - read).min(info.size as u64);
+                // Every block has its own length, only the last one can be short
+                let rem_copy = (max_count - read).min(self.cached_block_len());
+                if rem_copy == 0 {
+                    break;
+                }
                 buffer[read as usize..(read + rem_copy) as usize]
                     .copy_from_slice(&self.block_cache[0..rem_copy as usize]);
                 read += rem_copy;
@@ -195,7 +298,7 @@ impl FileHandle {
     pub fn write(&mut self, volume: &mut Ext2Volume, buffer: &[u8]) -> Result<u64, VfsError> {
         let bs = volume.get_block_size();
         let max_size = self.size.checked_next_multiple_of(bs).unwrap_or(self.size);
-        let max_count = (buffer.len() as u64).min(max_size - self.offset);
+        let max_count = (buffer.len() as u64).min(max_size.saturating_sub(self.offset));
         let begin_offset = self.offset;
         self.flush(volume)?;
         let current_block = (self.offset / bs) as u32;
@@ -223,10 +326,18 @@ impl FileHandle {
                 if !self.location.advance(volume)? {
                     break;
                 }
-                let rem_copy = (max_count - written).min(info.size as u64);
+                // The whole block is writable, the size grows to cover what is written
+                let rem_copy = (max_count - written).min(bs);
                 if rem_copy != bs {
                     // If not writing a full block, we need to update the block cache
                     self.internal_update_buffer(volume)?;
+                } else {
+                    // Every byte of the cache is overwritten, only its block changes
+                    self.block_cache_info = Some(BlockCacheInfo {
+                        block: self.location.current_block_idx(),
+                        size: bs as u32,
+                        dirty: true,
+                    });
                 }
 
                 self.block_cache[0..rem_copy as usize]
@@ -240,14 +351,140 @@ impl FileHandle {
 
         let new_size: u64 = self.size.max(begin_offset + written);
         if new_size != self.size {
+            self.location.get_inode_mut().set_size(volume, new_size)?;
             self.size = new_size;
-            self.location.get_inode_mut().set_size(volume, new_size);
-            volume.update_inode(self.get_inode())?;
+        }
+        if written > 0 {
+            self.modified(volume);
+        }
+
+        Ok(written)
+    }
+
+    /// Reads at `offset` without moving the handle, the cached block and read-ahead state are kept
+    pub fn read_at(
+        &mut self,
+        volume: &mut Ext2Volume,
+        offset: u64,
+        buffer: &mut [u8],
+    ) -> Result<u64, VfsError> {
+        let mut block = volume.alloc_block_buffer()?;
+        self.read_at_through(volume, offset, buffer, &mut block)
+    }
+
+    /// `read_at` staging the blocks read in `block`, one block long
+    pub fn read_at_through(
+        &mut self,
+        volume: &mut Ext2Volume,
+        offset: u64,
+        buffer: &mut [u8],
+        block: &mut [u8],
+    ) -> Result<u64, VfsError> {
+        if offset >= self.size {
+            return Ok(0);
+        }
+        let max_count = (buffer.len() as u64).min(self.size - offset);
+        // Dirty data and indirection tables must reach the disk before reading around them
+        self.flush(volume)?;
+
+        let bs = volume.get_block_size();
+        let mut read = 0;
+        while read < max_count {
+            let position = offset + read;
+            let in_block = position % bs;
+            let to_copy = (bs - in_block).min(max_count - read);
+
+            let address = self
+                .location
+                .block_address(volume, (position / bs) as u32)?;
+            if address == 0 {
+                block.fill(0);
+            } else {
+                volume.read_block(address as u64, block)?;
+            }
+            buffer[read as usize..(read + to_copy) as usize]
+                .copy_from_slice(&block[in_block as usize..(in_block + to_copy) as usize]);
+            read += to_copy;
+        }
+
+        Ok(read)
+    }
+
+    /// Writes at `offset` without moving the handle. Growing the file allocates blocks through
+    /// the handle, which drops its cached block
+    pub fn write_at(
+        &mut self,
+        volume: &mut Ext2Volume,
+        offset: u64,
+        buffer: &[u8],
+    ) -> Result<u64, VfsError> {
+        let mut block = volume.alloc_block_buffer()?;
+        self.write_at_through(volume, offset, buffer, &mut block)
+    }
+
+    /// `write_at` staging the partially written blocks in `block`, one block long
+    pub fn write_at_through(
+        &mut self,
+        volume: &mut Ext2Volume,
+        offset: u64,
+        buffer: &[u8],
+        block: &mut [u8],
+    ) -> Result<u64, VfsError> {
+        let end = offset
+            .checked_add(buffer.len() as u64)
+            .ok_or(VfsError::InvalidArgument)?;
+        if end > self.size {
+            self.grow(volume, end)?;
+        }
+        self.flush(volume)?;
+
+        let bs = volume.get_block_size();
+        let mut written = 0;
+        while written < buffer.len() as u64 {
+            let position = offset + written;
+            let block_idx = (position / bs) as u32;
+            let in_block = position % bs;
+            let to_copy = (bs - in_block).min(buffer.len() as u64 - written);
+            let source = &buffer[written as usize..(written + to_copy) as usize];
+
+            let address = self.location.block_address(volume, block_idx)?;
+            if address == 0 {
+                return Err(VfsError::InvalidDataStructure);
+            }
+            volume.order_write(Self::data_stage(self.location.get_inode()))?;
+            if to_copy == bs {
+                volume.write_block(address as u64, source)?;
+            } else {
+                volume.read_block(address as u64, block)?;
+                block[in_block as usize..(in_block + to_copy) as usize].copy_from_slice(source);
+                volume.write_block(address as u64, block)?;
+            }
+
+            // Keep the cached block in sync, it was flushed so it stays clean
+            if let Some(info) = self.block_cache_info {
+                if info.block == block_idx {
+                    self.block_cache[in_block as usize..(in_block + to_copy) as usize]
+                        .copy_from_slice(source);
+                }
+            }
+            written += to_copy;
+        }
+        if written > 0 {
+            self.modified(volume);
         }
 
         Ok(written)
     }
 
+    /// Updates the modification times, the inode is written back later
+    fn modified(&mut self, volume: &mut Ext2Volume) {
+        let now = get_unix_timestamp() as u32;
+        let inode = self.location.get_inode_mut();
+        inode.mtime = now;
+        inode.ctime = now;
+        volume.mark_inode_dirty(self.get_inode());
+    }
+
     pub fn get_position(&self) -> u64 {
         self.offset
     }
@@ -268,12 +505,17 @@ impl FileHandle {
         self.location.get_inode()
     }
 
+    pub fn get_inode_mut(&mut self) -> &mut Inode {
+        self.location.get_inode_mut()
+    }
+
     pub fn get_open_mode(&self) -> u64 {
         self.open_mode
     }
 }
 
 #[repr(u8)]
+#[derive(Debug, Clone, Copy)]
 pub enum DirectoryEntryType {
     Unknown = 0,
     File = 1,
@@ -303,6 +545,22 @@ impl TryFrom<u8> for DirectoryEntryType {
     }
 }
 
+impl DirectoryEntryType {
+    /// The type in directory handle records
+    pub fn record_kind(&self) -> u8 {
+        match self {
+            DirectoryEntryType::Unknown => DT_UNKNOWN,
+            DirectoryEntryType::File => DT_REG,
+            DirectoryEntryType::Directory => DT_DIR,
+            DirectoryEntryType::CharacterDevice => DT_CHR,
+            DirectoryEntryType::BlockDevice => DT_BLK,
+            DirectoryEntryType::BufferFile => DT_FIFO,
+            DirectoryEntryType::SocketFile => DT_SOCK,
+            DirectoryEntryType::Symlink => DT_LNK,
+        }
+    }
+}
+
 #[repr(C, packed)]
 #[derive(Debug, Clone, Copy)]
 struct DirectoryEntryRaw {
@@ -315,11 +573,12 @@ struct DirectoryEntryRaw {
 #[derive(Debug, Clone)]
 pub struct DirectoryEntry {
     inode: u32,
-    name: Vec<char>,
+    /// Bytes as stored on disk, not necessarily UTF-8
+    name: Vec<u8>,
 }
 
 impl DirectoryEntry {
-    pub fn name(&self) -> &[char] {
+    pub fn name(&self) -> &[u8] {
         &self.name
     }
 
@@ -327,7 +586,7 @@ impl DirectoryEntry {
         self.inode
     }
 
-    pub fn has_name(&self, name: &[char]) -> bool {
+    pub fn has_name(&self, name: &[u8]) -> bool {
         self.name == name
     }
 }
@@ -356,7 +615,7 @@ impl<'a> DirectoryIterator<'a> {
         if size % bs != 0 {
             return Err(VfsError::InvalidDataStructure);
         }
-        let buffer = alloc_boxed_slice::<u8>(bs);
+        let buffer = volume.alloc_block_buffer()?;
         let handle = FileHandle::new(volume, inode, open_mode)?;
         Ok(Self {
             volume,
@@ -388,6 +647,42 @@ impl<'a> DirectoryIterator<'a> {
         Ok(idx)
     }
 
+    /// Continues at `index`, a value `get_index` gave before
+    pub fn seek(&mut self, index: usize) -> Result<(), VfsError> {
+        if index < self.size {
+            let bs = self.volume.block_size as usize;
+            let block_start = (index - index % bs) as u64;
+            self.handle
+                .seek(self.volume, SeekPosition::FromStart(block_start))?;
+            self.buffer_idx = usize::MAX;
+        }
+        self.idx = index;
+        self.last_entry_offset = None;
+        Ok(())
+    }
+
+    /// Fills `buf` with the records of the next entries, `get_index` then gives the first entry
+    /// that wasn't read
+    pub fn read_records(&mut self, buf: &mut [u8]) -> Result<u64, VfsError> {
+        let mut writer = DirectoryRecordWriter::new(buf);
+        loop {
+            let index = self.idx;
+            let Some(next) = self.next() else {
+                break;
+            };
+            let record = DirectoryRecord {
+                inode: next.entry.inode as u64,
+                kind: next.entry_type.record_kind(),
+                name: &next.entry.name,
+            };
+            if !writer.push(&record)? {
+                self.idx = index;
+                break;
+            }
+        }
+        Ok(writer.written())
+    }
+
     pub fn move_to_entry(&mut self, entry: &DirectoryIteratorEntry) -> Result<(), VfsError> {
         self.idx = entry.offset as usize;
         self.read_buffer()?;
@@ -399,7 +694,7 @@ impl<'a> DirectoryIterator<'a> {
         let idx = self.read_buffer()?;
 
         let mut entry_raw = unsafe {
-            core::ptr::read_volatile(self.buffer.as_ptr().add(self.idx) as *const DirectoryEntryRaw)
+            core::ptr::read_volatile(self.buffer.as_ptr().add(idx) as *const DirectoryEntryRaw)
         };
 
         let name_len = if self.have_type_field {
@@ -431,7 +726,7 @@ impl<'a> DirectoryIterator<'a> {
 
         unsafe {
             core::ptr::write_volatile(
-                self.buffer.as_ptr().add(self.idx) as *mut DirectoryEntryRaw,
+                self.buffer.as_ptr().add(idx) as *mut DirectoryEntryRaw,
                 entry_raw,
             );
         };
@@ -648,11 +943,18 @@ impl<'a> DirectoryIterator<'a> {
 #[derive(Debug)]
 pub struct DirectoryIteratorEntry {
     entry: DirectoryEntry,
+    entry_type: DirectoryEntryType,
     prev_entry_offset: Option<u64>,
     offset: u64,
     rec_len: u64,
 }
 
+impl DirectoryIteratorEntry {
+    pub fn into_entry(self) -> DirectoryEntry {
+        self.entry
+    }
+}
+
 impl<'a> Iterator for DirectoryIterator<'a> {
     type Item = DirectoryIteratorEntry;
 
@@ -662,11 +964,13 @@ impl<'a> Iterator for DirectoryIterator<'a> {
                 return None;
             }
             let idx = self.read_buffer().ok()?;
+            if idx + size_of::<DirectoryEntryRaw>() > self.buffer.len() {
+                return None;
+            }
 
+            // The index is in the whole directory, `idx` in the block read
             let entry_raw = unsafe {
-                core::ptr::read_volatile(
-                    self.buffer.as_ptr().add(self.idx) as *const DirectoryEntryRaw
-                )
+                core::ptr::read_volatile(self.buffer.as_ptr().add(idx) as *const DirectoryEntryRaw)
             };
 
             let name_len = if self.have_type_field {
@@ -676,7 +980,13 @@ impl<'a> Iterator for DirectoryIterator<'a> {
             };
 
             let name_offset = idx + size_of::<DirectoryEntryRaw>();
-            let name = &self.buffer[name_offset..(name_offset + name_len)];
+            let name = self.buffer.get(name_offset..(name_offset + name_len))?;
+            let entry_type = if self.have_type_field {
+                DirectoryEntryType::try_from(entry_raw.type_or_len_hi)
+                    .unwrap_or(DirectoryEntryType::Unknown)
+            } else {
+                DirectoryEntryType::Unknown
+            };
 
             let begin_offset = self.idx as u64;
             let rec_len = entry_raw.entry_size as u64;
@@ -687,8 +997,9 @@ impl<'a> Iterator for DirectoryIterator<'a> {
                 return Some(DirectoryIteratorEntry {
                     entry: DirectoryEntry {
                         inode: entry_raw.inode,
-                        name: name.iter().map(|c| *c as char).collect(),
+                        name: name.to_vec(),
                     },
+                    entry_type,
                     offset: begin_offset,
                     prev_entry_offset: last_offset,
                     rec_len,
@@ -703,28 +1014,24 @@ impl<'a> Iterator for DirectoryIterator<'a> {
 
 #[derive(Debug, Clone)]
 pub struct Directory {
-    pub entries: Vec<DirectoryEntry>,
     pub inode: Inode,
 }
 
 impl Directory {
-    pub fn new(volume: &mut Ext2Volume, inode: Inode, open_mode: u64) -> Result<Self, VfsError> {
-        let iterator = DirectoryIterator::new(volume, inode.clone(), open_mode)?;
-        Ok(Self {
-            entries: iterator.map(|v| v.entry).collect(),
-            inode,
-        })
+    pub fn new(inode: Inode) -> Self {
+        Self { inode }
     }
 
     pub fn delete_entry(
         volume: &mut Ext2Volume,
         inode: &Inode,
         entry_inode: u32,
+        name: &[u8],
     ) -> Result<(), VfsError> {
         let mut iterator = DirectoryIterator::new(volume, inode.clone(), OPEN_MODE_WRITE)?;
 
         while let Some(next) = iterator.next() {
-            if next.entry.inode == entry_inode {
+            if next.entry.inode == entry_inode && next.entry.has_name(name) {
                 iterator.delete_entry(next)?;
                 return Ok(());
             }
@@ -733,3 +1040,47 @@ impl Directory {
         Err(VfsError::EntryNotFound)
     }
 }
+
+/// The entries of a directory, the name lookup map is only built once a child is looked up
+#[derive(Debug)]
+pub struct DirectoryIndex {
+    entries: Vec<DirectoryEntry>,
+    by_name: Option<BTreeMap<Vec<u8>, u32>>,
+}
+
+impl DirectoryIndex {
+    pub fn read(volume: &mut Ext2Volume, inode: Inode) -> Result<Self, VfsError> {
+        let iterator = DirectoryIterator::new(volume, inode, OPEN_MODE_READ)?;
+        Ok(Self {
+            entries: iterator.map(|v| v.entry).collect(),
+            by_name: None,
+        })
+    }
+
+    pub fn entries(&self) -> &[DirectoryEntry] {
+        &self.entries
+    }
+
+    pub fn lookup(&mut self, name: &[u8]) -> Option<u32> {
+        let entries = &self.entries;
+        self.by_name
+            .get_or_insert_with(|| entries.iter().map(|e| (e.name.clone(), e.inode)).collect())
+            .get(name)
+            .copied()
+    }
+
+    pub fn insert(&mut self, entry: DirectoryEntry) {
+        if let Some(by_name) = &mut self.by_name {
+            by_name.insert(entry.name.clone(), entry.inode);
+        }
+        self.entries.push(entry);
+    }
+
+    pub fn remove(&mut self, inode: u32, name: &[u8]) {
+        self.entries
+            .retain(|e| !(e.inode == inode && e.has_name(name)));
+        if let Some(by_name) = &mut self.by_name {
+            by_name.remove(name);
+        }
+    }
+}
@@ -0,0 +1,572 @@
+//! Consistency check of an unmounted volume.
+//!
+//! The group metadata is claimed first, then the blocks of every inode marked in use, so a block
+//! reached twice or outside of the volume is found. Directories are parsed entry by entry, and
+//! the free counts are recomputed from the bitmaps. Only the counts and the entries pointing to
+//! unused inodes are repaired, everything else is reported.
+
+use alloc::{string::String, vec, vec::Vec};
+use core::{fmt::Display, num::NonZeroUsize, ops::Range};
+
+use crate::{
+    data::{bitmap::Bitmap, file::File},
+    drivers::vfs::{BlockDevice, VfsError},
+};
+
+use super::{
+    blockgroup::BLOCK_GROUP_DESCRIPTOR_SIZE,
+    inode::{InodeType, RawInode},
+    superblock::RequiredFeature,
+    Ext2Volume,
+};
+
+const CHECK_CACHE_SIZE: NonZeroUsize = NonZeroUsize::new(64 * 1024).unwrap();
+const ROOT_INODE: u32 = 2;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FsckFinding {
+    /// A bitmap or inode table outside of the volume, or over other metadata
+    BadGroupMetadata {
+        group: u32,
+        block: u32,
+    },
+    BadInodeType {
+        inode: u32,
+        mode: u16,
+    },
+    BlockOutOfRange {
+        inode: u32,
+        block: u32,
+    },
+    BlockClaimedTwice {
+        inode: u32,
+        block: u32,
+    },
+    /// In use but free in the block bitmap
+    BlockMarkedFree {
+        block: u32,
+    },
+    /// Blocks marked in use that nothing references
+    LeakedBlocks {
+        group: u32,
+        count: u32,
+    },
+    BadDirectoryEntry {
+        directory: u32,
+        block: u32,
+        offset: u32,
+    },
+    /// An entry naming an inode that isn't in use
+    DanglingEntry {
+        directory: u32,
+        name: String,
+        inode: u32,
+    },
+    GroupCountMismatch {
+        group: u32,
+        field: &'static str,
+        recorded: u32,
+        actual: u32,
+    },
+    SuperblockCountMismatch {
+        field: &'static str,
+        recorded: u32,
+        actual: u32,
+    },
+}
+
+impl Display for FsckFinding {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Self::BadGroupMetadata { group, block } => {
+                write!(f, "group {group}: bad metadata block {block}")
+            }
+            Self::BadInodeType { inode, mode } => {
+                write!(f, "inode {inode}: bad type in mode {mode:#o}")
+            }
+            Self::BlockOutOfRange { inode, block } => {
+                write!(f, "inode {inode}: block {block} is out of the volume")
+            }
+            Self::BlockClaimedTwice { inode, block } => {
+                write!(f, "inode {inode}: block {block} is already in use")
+            }
+            Self::BlockMarkedFree { block } => {
+                write!(f, "block {block} is in use but marked free")
+            }
+            Self::LeakedBlocks { group, count } => {
+                write!(
+                    f,
+                    "group {group}: {count} blocks marked in use aren't referenced"
+                )
+            }
+            Self::BadDirectoryEntry {
+                directory,
+                block,
+                offset,
+            } => write!(
+                f,
+                "directory {directory}: bad entry at offset {offset} of block {block}"
+            ),
+            Self::DanglingEntry {
+                directory,
+                name,
+                inode,
+            } => write!(
+                f,
+                "directory {directory}: entry {name:?} names unused inode {inode}"
+            ),
+            Self::GroupCountMismatch {
+                group,
+                field,
+                recorded,
+                actual,
+            } => write!(
+                f,
+                "group {group}: {field} is {recorded}, should be {actual}"
+            ),
+            Self::SuperblockCountMismatch {
+                field,
+                recorded,
+                actual,
+            } => write!(f, "superblock: {field} is {recorded}, should be {actual}"),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct FsckReport {
+    pub findings: Vec<FsckFinding>,
+    /// Findings that were fixed
+    pub repaired: usize,
+}
+
+impl FsckReport {
+    pub fn is_clean(&self) -> bool {
+        self.findings.is_empty()
+    }
+}
+
+struct Checker<'a> {
+    volume: &'a mut Ext2Volume,
+    fix: bool,
+    /// Blocks referenced by the metadata or an inode
+    claimed: Bitmap,
+    /// Inodes marked in use with a valid type
+    in_use: Bitmap,
+    is_directory: Bitmap,
+    directories: Vec<(u32, Vec<u32>)>,
+    report: FsckReport,
+}
+
+impl Checker<'_> {
+    fn block_size(&self) -> usize {
+        self.volume.block_size as usize
+    }
+
+    fn read_block(&self, block: u32) -> Result<Vec<u8>, VfsError> {
+        let mut buffer = vec![0u8; self.block_size()];
+        self.volume.read_block(block as u64, &mut buffer)?;
+        Ok(buffer)
+    }
+
+    /// `blocks` bits of the bitmap starting at block `start`
+    fn read_bitmap(&self, start: u32, bits: u32) -> Result<Vec<u8>, VfsError> {
+        let blocks = bits.div_ceil(8).div_ceil(self.volume.block_size);
+        let mut bitmap = Vec::new();
+        for block in start..start + blocks {
+            bitmap.extend(self.read_block(block)?);
+        }
+        Ok(bitmap)
+    }
+
+    fn group_blocks(&self, group: u32) -> Range<u32> {
+        let start = self.volume.group_first_block(group);
+        start..(start + self.volume.blocks_per_group).min(self.volume.block_count)
+    }
+
+    fn claim(&mut self, block: u32) -> bool {
+        if self.claimed.get_bit(block as usize) == Some(false) {
+            self.claimed.set_bit(block as usize, true);
+            true
+        } else {
+            false
+        }
+    }
+
+    fn check_groups(&mut self) {
+        // The boot block, and the superblock when it isn't in a group
+        for block in 0..self.group_blocks(0).start {
+            self.claim(block);
+        }
+        let inode_table_blocks = (self.volume.superblock.inodes_per_group
+            * self.volume.inode_size as u32)
+            .div_ceil(self.volume.block_size);
+        let descriptor_blocks = (self.volume.block_group_count * BLOCK_GROUP_DESCRIPTOR_SIZE)
+            .div_ceil(self.volume.block_size);
+        for group in 0..self.volume.block_group_count {
+            let Some(descriptor) = self.volume.get_block_group_descriptor(group) else {
+                continue;
+            };
+            let start = self.group_blocks(group).start;
+            let bitmap_blocks = |bits: u32| bits.div_ceil(8).div_ceil(self.volume.block_size);
+            let mut ranges = vec![
+                descriptor.block_usage_bitmap
+                    ..descriptor.block_usage_bitmap
+                        + bitmap_blocks(self.group_blocks(group).len() as u32),
+                descriptor.inode_usage_bitmap
+                    ..descriptor.inode_usage_bitmap
+                        + bitmap_blocks(self.volume.superblock.inodes_per_group),
+                descriptor.inode_table_block..descriptor.inode_table_block + inode_table_blocks,
+            ];
+            if self.volume.block_group_contains_metadata_backup(group) {
+                ranges.push(start..start + 1 + descriptor_blocks);
+            }
+            for block in ranges.into_iter().flatten() {
+                if block >= self.volume.block_count || !self.claim(block) {
+                    self.report
+                        .findings
+                        .push(FsckFinding::BadGroupMetadata { group, block });
+                    break;
+                }
+            }
+        }
+    }
+
+    /// Claims `block` and, for indirect blocks, what they point to. Data blocks of directories
+    /// are collected in `data`
+    fn walk_block(
+        &mut self,
+        inode: u32,
+        block: u32,
+        depth: u32,
+        data: &mut Option<Vec<u32>>,
+    ) -> Result<(), VfsError> {
+        if block == 0 {
+            return Ok(());
+        }
+        if block >= self.volume.block_count {
+            self.report
+                .findings
+                .push(FsckFinding::BlockOutOfRange { inode, block });
+            return Ok(());
+        }
+        if !self.claim(block) {
+            self.report
+                .findings
+                .push(FsckFinding::BlockClaimedTwice { inode, block });
+            return Ok(());
+        }
+        if depth == 0 {
+            if let Some(data) = data {
+                data.push(block);
+            }
+            return Ok(());
+        }
+        let table = self.read_block(block)?;
+        for pointer in table.as_chunks::<4>().0 {
+            self.walk_block(inode, u32::from_le_bytes(*pointer), depth - 1, data)?;
+        }
+        Ok(())
+    }
+
+    fn check_inode(&mut self, inode: u32, raw: RawInode) -> Result<(), VfsError> {
+        let mode = raw.type_and_permissions;
+        let kind = [
+            InodeType::FIFO,
+            InodeType::CharacterDevice,
+            InodeType::Directory,
+            InodeType::BlockDevice,
+            InodeType::File,
+            InodeType::Symlink,
+            InodeType::Socket,
+        ]
+        .into_iter()
+        .find(|kind| *kind as u16 == mode & 0xF000);
+        let Some(kind) = kind else {
+            self.report
+                .findings
+                .push(FsckFinding::BadInodeType { inode, mode });
+            return Ok(());
+        };
+        self.in_use.set_bit(inode as usize, true);
+        self.is_directory
+            .set_bit(inode as usize, kind == InodeType::Directory);
+
+        let has_blocks = match kind {
+            InodeType::Directory | InodeType::File => true,
+            // Short targets are kept in the inode
+            InodeType::Symlink => raw.sectors_count != 0,
+            _ => false,
+        };
+        if !has_blocks {
+            return Ok(());
+        }
+        let mut data = (kind == InodeType::Directory).then(Vec::new);
+        for block in raw.direct_block_pointers {
+            self.walk_block(inode, block, 0, &mut data)?;
+        }
+        for (depth, block) in [
+            raw.single_indirect_block_pointer,
+            raw.double_indirect_block_pointer,
+            raw.triple_indirect_block_pointer,
+        ]
+        .into_iter()
+        .enumerate()
+        {
+            self.walk_block(inode, block, depth as u32 + 1, &mut data)?;
+        }
+        if let Some(data) = data {
+            self.directories.push((inode, data));
+        }
+        Ok(())
+    }
+
+    fn check_inodes(&mut self) -> Result<(), VfsError> {
+        let per_group = self.volume.superblock.inodes_per_group;
+        let first = self.volume.superblock.first_non_reserved_inode.max(1);
+        for group in 0..self.volume.block_group_count {
+            let Some(descriptor) = self.volume.get_block_group_descriptor(group) else {
+                continue;
+            };
+            let (min, max) = self.volume.get_inode_range_for_group(group);
+            let bitmap = self.read_bitmap(descriptor.inode_usage_bitmap, per_group)?;
+            for inode in min..max {
+                let bit = (inode - min) as usize;
+                if bitmap[bit / 8] & (1 << (bit % 8)) == 0 {
+                    continue;
+                }
+                // Reserved inodes belong to features this driver doesn't have
+                if inode < first && inode != ROOT_INODE {
+                    self.in_use.set_bit(inode as usize, true);
+                    continue;
+                }
+                let raw = self.volume.read_raw_inode(inode)?;
+                self.check_inode(inode, raw)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn check_directory_block(&mut self, directory: u32, block: u32) -> Result<(), VfsError> {
+        let type_field = self
+            .volume
+            .superblock
+            .get_required_features()
+            .has(RequiredFeature::DirectoryEntriesHaveTypeField);
+        let mut data = self.read_block(block)?;
+        let size = data.len();
+        let mut offset = 0;
+        let mut previous: Option<usize> = None;
+        let mut changed = false;
+        while offset < size {
+            let field = |at: usize, len: usize| &data[offset + at..offset + at + len];
+            let entry_len = if offset + 8 <= size {
+                u16::from_le_bytes(field(4, 2).try_into().unwrap()) as usize
+            } else {
+                0
+            };
+            let name_len = if offset + 8 > size {
+                0
+            } else if type_field {
+                data[offset + 6] as usize
+            } else {
+                u16::from_le_bytes(field(6, 2).try_into().unwrap()) as usize
+            };
+            if entry_len < 8
+                || !entry_len.is_multiple_of(4)
+                || offset + entry_len > size
+                || 8 + name_len > entry_len
+            {
+                self.report.findings.push(FsckFinding::BadDirectoryEntry {
+                    directory,
+                    block,
+                    offset: offset as u32,
+                });
+                break;
+            }
+
+            let inode = u32::from_le_bytes(field(0, 4).try_into().unwrap());
+            let name = field(8, name_len).to_vec();
+            let used = inode != 0 && self.in_use.get_bit(inode as usize) == Some(true);
+            if inode != 0 && !used {
+                self.report.findings.push(FsckFinding::DanglingEntry {
+                    directory,
+                    name: String::from_utf8_lossy(&name).into_owned(),
+                    inode,
+                });
+                if self.fix && name != b"." && name != b".." {
+                    // Like a deletion: merged into the previous entry, or emptied when first
+                    match previous {
+                        Some(previous) => {
+                            let previous_len =
+                                u16::from_le_bytes([data[previous + 4], data[previous + 5]]);
+                            let merged = previous_len + entry_len as u16;
+                            data[previous + 4..previous + 6].copy_from_slice(&merged.to_le_bytes());
+                        }
+                        None => data[offset..offset + 4].fill(0),
+                    }
+                    changed = true;
+                    self.report.repaired += 1;
+                    offset += entry_len;
+                    continue;
+                }
+            }
+            previous = Some(offset);
+            offset += entry_len;
+        }
+        if changed {
+            self.volume.write_block(block as u64, &data)?;
+        }
+        Ok(())
+    }
+
+    fn check_directories(&mut self) -> Result<(), VfsError> {
+        for (directory, blocks) in core::mem::take(&mut self.directories) {
+            for block in blocks {
+                self.check_directory_block(directory, block)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn count_mismatch(
+        &mut self,
+        group: Option<u32>,
+        field: &'static str,
+        recorded: u32,
+        actual: u32,
+    ) {
+        if recorded == actual {
+            return;
+        }
+        self.report.findings.push(match group {
+            Some(group) => FsckFinding::GroupCountMismatch {
+                group,
+                field,
+                recorded,
+                actual,
+            },
+            None => FsckFinding::SuperblockCountMismatch {
+                field,
+                recorded,
+                actual,
+            },
+        });
+        if self.fix {
+            self.report.repaired += 1;
+        }
+    }
+
+    /// Compares the bitmaps with the claimed blocks, then the free counts with the bitmaps
+    fn check_counts(&mut self) -> Result<(), VfsError> {
+        let (mut free_blocks, mut free_inodes) = (0, 0);
+        for group in 0..self.volume.block_group_count {
+            let Some(mut descriptor) = self.volume.get_block_group_descriptor(group) else {
+                continue;
+            };
+            let blocks = self.group_blocks(group);
+            let bitmap = self.read_bitmap(descriptor.block_usage_bitmap, blocks.len() as u32)?;
+            let (mut free, mut leaked) = (0, 0);
+            for (bit, block) in blocks.clone().enumerate() {
+                let marked = bitmap[bit / 8] & (1 << (bit % 8)) != 0;
+                let claimed = self.claimed.get_bit(block as usize) == Some(true);
+                match (marked, claimed) {
+                    (false, true) => self
+                        .report
+                        .findings
+                        .push(FsckFinding::BlockMarkedFree { block }),
+                    (true, false) => leaked += 1,
+                    _ => {}
+                }
+                free += !marked as u32;
+            }
+            if leaked != 0 {
+                self.report.findings.push(FsckFinding::LeakedBlocks {
+                    group,
+                    count: leaked,
+                });
+            }
+
+            let (min, max) = self.volume.get_inode_range_for_group(group);
+            let bitmap = self.read_bitmap(
+                descriptor.inode_usage_bitmap,
+                self.volume.superblock.inodes_per_group,
+            )?;
+            let mut inodes_free = 0;
+            let mut directories = 0;
+            for inode in min..max {
+                let bit = (inode - min) as usize;
+                if bitmap[bit / 8] & (1 << (bit % 8)) == 0 {
+                    inodes_free += 1;
+                } else if self.is_directory.get_bit(inode as usize) == Some(true) {
+                    directories += 1;
+                }
+            }
+
+            let recorded = (
+                descriptor.free_blocks_count,
+                descriptor.free_inodes_count,
+                descriptor.directory_count,
+            );
+            self.count_mismatch(Some(group), "free blocks", recorded.0 as u32, free);
+            self.count_mismatch(Some(group), "free inodes", recorded.1 as u32, inodes_free);
+            self.count_mismatch(Some(group), "directories", recorded.2 as u32, directories);
+            if self.fix && recorded != (free as u16, inodes_free as u16, directories as u16) {
+                descriptor.free_blocks_count = free as u16;
+                descriptor.free_inodes_count = inodes_free as u16;
+                descriptor.directory_count = directories as u16;
+                self.volume.set_block_group_descriptor(group, descriptor)?;
+            }
+            free_blocks += free;
+            free_inodes += inodes_free;
+        }
+
+        let mut superblock = self.volume.superblock.clone();
+        let recorded = (superblock.unallocated_blocks, superblock.unallocated_inodes);
+        self.count_mismatch(None, "free blocks", recorded.0, free_blocks);
+        self.count_mismatch(None, "free inodes", recorded.1, free_inodes);
+        if self.fix && recorded != (free_blocks, free_inodes) {
+            superblock.unallocated_blocks = free_blocks;
+            superblock.unallocated_inodes = free_inodes;
+            self.volume.set_superblock(superblock)?;
+        }
+        Ok(())
+    }
+}
+
+impl Ext2Volume {
+    /// Checks the volume on `device`, which must not be mounted. With `fix`, the device must be
+    /// opened for writing, and wrong free counts and entries naming unused inodes are repaired
+    pub fn check(device: File, fix: bool) -> Result<FsckReport, VfsError> {
+        let mut volume = Self::from_device(
+            device,
+            CHECK_CACHE_SIZE,
+            CHECK_CACHE_SIZE,
+            CHECK_CACHE_SIZE,
+            0,
+        )?;
+        if fix && volume.read_only {
+            return Err(VfsError::ReadOnly);
+        }
+        let block_count = volume.block_count as usize;
+        let inodes_count = volume.superblock.inodes_count as usize;
+        let mut checker = Checker {
+            volume: &mut volume,
+            fix,
+            claimed: Bitmap::new(block_count),
+            in_use: Bitmap::new(inodes_count + 1),
+            is_directory: Bitmap::new(inodes_count + 1),
+            directories: Vec::new(),
+            report: FsckReport::default(),
+        };
+        checker.check_groups();
+        checker.check_inodes()?;
+        checker.check_directories()?;
+        checker.check_counts()?;
+        let report = core::mem::take(&mut checker.report);
+        if fix {
+            volume.flush()?;
+        }
+        Ok(report)
+    }
+}
@@ -49,6 +49,17 @@ impl InodeAllocator {
         &mut self.diff_usage
     }
 
+    /// Inodes allocated minus freed since the counters were last updated
+    pub fn diff_usage(&self) -> i64 {
+        self.diff_usage
+    }
+
+    /// Number of free inodes in the group according to the bitmap
+    pub fn count_free(&self) -> u32 {
+        let total = self.max_inode_exclusive - self.min_inode_inclusive;
+        total - self.bitmap.count_set_until(total as usize) as u32
+    }
+
     pub fn read_all(&mut self, volume: &mut Ext2Volume) -> Result<(), VfsError> {
         let slice = self.bitmap.as_mut_slice();
         for (i, lba) in (self.bitmap_begin_inclusive..self.bitmap_end_exclusive).enumerate() {
@@ -58,6 +69,10 @@ impl InodeAllocator {
         Ok(())
     }
 
+    pub fn is_dirty(&self) -> bool {
+        self.dirty_blocks_bitmap.find_first_set().is_some()
+    }
+
     pub fn write_dirty(&mut self, volume: &mut Ext2Volume) -> Result<(), VfsError> {
         for (i, lba) in (self.bitmap_begin_inclusive..self.bitmap_end_exclusive).enumerate() {
             if self.dirty_blocks_bitmap.get_bit(i).unwrap_or(false) {
@@ -109,7 +124,7 @@ impl InodeAllocator {
 
 impl Drop for InodeAllocator {
     fn drop(&mut self) {
-        if self.dirty_blocks_bitmap.find_first_set().is_some() {
+        if self.is_dirty() {
             panic!("Dropping inode allocator with dirty blocks !!");
         }
     }
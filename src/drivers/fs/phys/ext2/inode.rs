@@ -1,12 +1,11 @@
-use alloc::{boxed::Box, format};
+use alloc::{boxed::Box, format, vec::Vec};
 
 use crate::{
-    data::alloc_boxed_slice,
     debuggable_bitset_enum,
-    drivers::vfs::{BlockDevice, VfsError},
+    drivers::vfs::{BlockDevice, DeviceNumber, FileStat, VfsError},
 };
 
-use super::{superblock::ROFeature, Ext2Error, Ext2Volume};
+use super::{order::WriteStage, superblock::ROFeature, Ext2Error, Ext2Volume};
 
 #[repr(C, packed)]
 #[derive(Debug, Clone)]
@@ -156,6 +155,11 @@ impl Inode {
         }
     }
 
+    /// Whether the file changed since it was last accessed
+    pub fn atime_stale(&self) -> bool {
+        self.atime < self.mtime || self.atime < self.ctime
+    }
+
     pub fn get_size(&self, volume: &Ext2Volume) -> u64 {
         if self.inode_type == InodeType::File
             && volume
@@ -169,7 +173,8 @@ impl Inode {
         }
     }
 
-    pub fn set_size(&mut self, volume: &Ext2Volume, size: u64) {
+    /// Fails if the size needs the high 32 bits but the inode can't hold them
+    pub fn set_size(&mut self, volume: &Ext2Volume, size: u64) -> Result<(), VfsError> {
         if self.inode_type == InodeType::File
             && volume
                 .get_superblock()
@@ -177,8 +182,84 @@ impl Inode {
                 .has(ROFeature::FileSize64)
         {
             self.size_hi_or_dir_acl = (size >> 32) as u32;
+        } else if size > u32::MAX as u64 {
+            return Err(VfsError::MaximumSizeReached);
         }
         self.size_lo = size as u32;
+        Ok(())
+    }
+
+    /// The high 16 bits of the owner are kept in the OS dependent value 2, like Linux does
+    pub fn full_uid(&self) -> u32 {
+        self.uid as u32 | (u16::from_le_bytes([self.ossv2[4], self.ossv2[5]]) as u32) << 16
+    }
+
+    pub fn full_gid(&self) -> u32 {
+        self.gid as u32 | (u16::from_le_bytes([self.ossv2[6], self.ossv2[7]]) as u32) << 16
+    }
+
+    /// `size` is passed in because an open handle may know a newer size than the inode
+    pub fn stat(&self, size: u64) -> FileStat {
+        FileStat {
+            size,
+            // ext2 permissions are unix permissions, Campix kernel's permissions uses the same lower 16 bits as unix permissions
+            permissions: self.permissions.get() as u64,
+            flags: 0,
+            created_at: self.ctime as u64,
+            modified_at: self.mtime as u64,
+            is_directory: self.inode_type == InodeType::Directory,
+            is_symlink: self.inode_type == InodeType::Symlink,
+            is_file: self.inode_type == InodeType::File,
+            owner_id: self.full_uid() as u64,
+            group_id: self.full_gid() as u64,
+        }
+    }
+
+    pub fn set_owner(&mut self, uid: u32, gid: u32) {
+        self.uid = uid as u16;
+        self.gid = gid as u16;
+        self.ossv2[4..6].copy_from_slice(&((uid >> 16) as u16).to_le_bytes());
+        self.ossv2[6..8].copy_from_slice(&((gid >> 16) as u16).to_le_bytes());
+    }
+
+    /// Short symlink targets are kept in the block pointers instead of a data block
+    pub fn is_fast_symlink(&self) -> bool {
+        self.inode_type == InodeType::Symlink && self.sectors_count == 0
+    }
+
+    /// Target of a fast symlink, the block pointers as bytes
+    pub fn inline_data(&self) -> [u8; 60] {
+        let mut data = [0; 60];
+        let pointers = self.direct_block_pointers.iter().chain([
+            &self.single_indirect_block_pointer,
+            &self.double_indirect_block_pointer,
+            &self.triple_indirect_block_pointer,
+        ]);
+        for (chunk, pointer) in data.as_chunks_mut::<4>().0.iter_mut().zip(pointers) {
+            chunk.copy_from_slice(&pointer.to_le_bytes());
+        }
+        data
+    }
+
+    /// Device inodes keep their number in the first block pointer if major and minor fit in 8
+    /// bits, else in the second one with the first left at 0, like Linux does
+    pub fn device_number(&self) -> DeviceNumber {
+        let [old, new, ..] = self.direct_block_pointers;
+        if old != 0 {
+            DeviceNumber::new((old >> 8) & 0xff, old & 0xff)
+        } else {
+            DeviceNumber::new((new & 0xfff00) >> 8, (new & 0xff) | ((new >> 12) & 0xfff00))
+        }
+    }
+
+    pub fn set_device_number(&mut self, number: DeviceNumber) {
+        let DeviceNumber { major, minor } = number;
+        self.direct_block_pointers = [0; 12];
+        if major < 256 && minor < 256 {
+            self.direct_block_pointers[0] = (major << 8) | minor;
+        } else {
+            self.direct_block_pointers[1] = (minor & 0xff) | (major << 8) | ((minor & !0xff) << 12);
+        }
     }
 }
 
@@ -377,9 +458,9 @@ impl CachedInodeReadingLocation {
     pub fn new(ext2: &Ext2Volume, inode: Inode) -> Result<Self, VfsError> {
         let size = ext2.get_block_size();
         let location = InodeReadingLocation::new(ext2.get_block_size() as u32 / 4, 0);
-        let table1 = alloc_boxed_slice::<u8>(size as usize);
-        let table2 = alloc_boxed_slice::<u8>(size as usize);
-        let table3 = alloc_boxed_slice::<u8>(size as usize);
+        let table1 = ext2.alloc_block_buffer()?;
+        let table2 = ext2.alloc_block_buffer()?;
+        let table3 = ext2.alloc_block_buffer()?;
 
         let max_block_exclusive: i64 = inode
             .get_size(ext2)
@@ -413,6 +494,7 @@ impl CachedInodeReadingLocation {
         dirty: &mut bool,
     ) -> Result<(), VfsError> {
         if *dirty && current_addr != 0 && current_addr != to_load_addr {
+            ext2.order_write(WriteStage::Inode)?;
             if ext2.write_block(current_addr as u64, table)? != ext2.block_size as u64 {
                 return Err(VfsError::UnknownError);
             }
@@ -575,19 +657,118 @@ impl CachedInodeReadingLocation {
         })
     }
 
+    /// Returns up to `count` block addresses following the current one, without loading new indirection tables
+    pub fn peek_following_blocks(&self, count: u32) -> Vec<u32> {
+        let mut blocks = Vec::new();
+        let mut location = self.location;
+
+        while (blocks.len() as u32) < count {
+            if location.current_block_idx() as i64 >= self.max_block_exclusive - 1
+                || !location.advance()
+            {
+                break;
+            }
+            let block = match (self.location.location, location.location) {
+                (_, InodeReadingLocationInfo::Direct(direct)) => {
+                    self.inode.direct_block_pointers[direct as usize]
+                }
+                (InodeReadingLocationInfo::Single(_), InodeReadingLocationInfo::Single(single)) => {
+                    match self.follow1(single) {
+                        Ok(block) => block,
+                        Err(_) => break,
+                    }
+                }
+                (
+                    InodeReadingLocationInfo::Double(a1, _),
+                    InodeReadingLocationInfo::Double(b1, double),
+                ) if a1 == b1 => match self.follow2(double) {
+                    Ok(block) => block,
+                    Err(_) => break,
+                },
+                (
+                    InodeReadingLocationInfo::Triple(a1, a2, _),
+                    InodeReadingLocationInfo::Triple(b1, b2, triple),
+                ) if a1 == b1 && a2 == b2 => match self.follow3(triple) {
+                    Ok(block) => block,
+                    Err(_) => break,
+                },
+                // Crossing into another indirection table
+                _ => break,
+            };
+            if block == 0 {
+                break;
+            }
+            blocks.push(block);
+        }
+
+        blocks
+    }
+
+    /// Address of the data block `block_idx`, 0 for a hole. The indirection tables are read
+    /// without replacing the cached ones, so the current location is left alone
+    pub fn block_address(&self, ext2: &Ext2Volume, block_idx: u32) -> Result<u32, VfsError> {
+        let location = InodeReadingLocation::new(self.block_size as u32 / 4, block_idx);
+        let mut table = ext2.alloc_block_buffer()?;
+        let mut follow = |table_addr: u32, idx: u32| -> Result<u32, VfsError> {
+            if table_addr == 0 {
+                return Ok(0);
+            }
+            ext2.read_block(table_addr as u64, &mut table)?;
+            let entry = idx as usize * 4;
+            Ok(u32::from_le_bytes([
+                table[entry],
+                table[entry + 1],
+                table[entry + 2],
+                table[entry + 3],
+            ]))
+        };
+
+        Ok(match location.location {
+            InodeReadingLocationInfo::Direct(direct) => {
+                self.inode.direct_block_pointers[direct as usize]
+            }
+            InodeReadingLocationInfo::Single(single) => {
+                follow(self.inode.single_indirect_block_pointer, single)?
+            }
+            InodeReadingLocationInfo::Double(double1, double2) => {
+                let table = follow(self.inode.double_indirect_block_pointer, double1)?;
+                follow(table, double2)?
+            }
+            InodeReadingLocationInfo::Triple(triple1, triple2, triple3) => {
+                let table = follow(self.inode.triple_indirect_block_pointer, triple1)?;
+                let table = follow(table, triple2)?;
+                follow(table, triple3)?
+            }
+        })
+    }
+
     pub fn read_block(&mut self, ext2: &Ext2Volume, buffer: &mut [u8]) -> Result<u64, VfsError> {
         let bs = ext2.get_block_size();
         if buffer.len() < bs as usize {
             return Err(VfsError::BadBufferSize);
         }
         let block = self.get_next_block()?;
-        let block_idx = self.location.current_block_idx();
-        ext2.read_block(block as u64, buffer)?;
-        if (block_idx as i64) < self.max_block_exclusive - 1 {
-            Ok(bs)
+        if block == 0 {
+            // A hole
+            buffer[..bs as usize].fill(0);
+        } else {
+            ext2.read_block(block as u64, buffer)?;
+        }
+        Ok(self.current_block_len(ext2))
+    }
+
+    /// Bytes of the file in the current block, only the last one can be partial
+    fn current_block_len(&self, ext2: &Ext2Volume) -> u64 {
+        let bs = ext2.get_block_size();
+        if (self.location.current_block_idx() as i64) < self.max_block_exclusive - 1 {
+            bs
         } else {
-            let read = (self.inode.size_lo as u64) % bs;
-            Ok(if read == 0 { bs } else { read })
+            let len = self.inode.get_size(ext2) % bs;
+            if len == 0 {
+                bs
+            } else {
+                len
+            }
         }
     }
 
@@ -597,14 +778,12 @@ impl CachedInodeReadingLocation {
             return Err(VfsError::BadBufferSize);
         }
         let block = self.get_next_block()?;
-        let block_idx = self.location.current_block_idx();
-        ext2.write_block(block as u64, buffer)?;
-        if (block_idx as i64) < self.max_block_exclusive - 1 {
-            Ok(bs)
-        } else {
-            let write = (self.inode.size_lo as u64) % bs;
-            Ok(if write == 0 { bs } else { write })
+        if block == 0 {
+            // Holes aren't filled in
+            return Err(VfsError::InvalidDataStructure);
         }
+        ext2.write_block(block as u64, buffer)?;
+        Ok(self.current_block_len(ext2))
     }
 
     pub fn advance(&mut self, ext2: &mut Ext2Volume) -> Result<bool, VfsError> {
@@ -649,21 +828,28 @@ impl CachedInodeReadingLocation {
         self.max_block_exclusive as u32
     }
 
-    pub fn free_last_block(&mut self, ext2: &mut Ext2Volume) -> Result<(), VfsError> {
+    /// Frees `block` unless it is 0, returns the number of blocks freed
+    fn dealloc(ext2: &mut Ext2Volume, block: u32) -> Result<u32, VfsError> {
+        if block == 0 {
+            return Ok(0);
+        }
+        let group = ext2.block_group(block);
+        ext2.get_block_allocator_for_group(group)?
+            .ok_or(VfsError::DriverError(Box::new(format!(
+                "No block allocator for group {group}"
+            ))))?
+            .dealloc_block(block)?;
+        Ok(1)
+    }
+
+    /// Returns the number of blocks freed, indirection tables included. Holes free nothing
+    pub fn free_last_block(&mut self, ext2: &mut Ext2Volume) -> Result<u32, VfsError> {
         if self.max_block_exclusive == 0 {
-            return Ok(());
+            return Ok(0);
         }
         self.seek(ext2, self.max_block_exclusive as u32 - 1)?;
 
-        let block = self.get_next_block()?;
-        let group = (block - 1) / ext2.blocks_per_group;
-
-        let balloc = ext2
-            .get_block_allocator_for_group(group)?
-            .ok_or(VfsError::DriverError(Box::new(format!(
-                "No block allocator for group {group}"
-            ))))?;
-        balloc.dealloc_block(block)?;
+        let mut freed = Self::dealloc(ext2, self.get_next_block()?)?;
         unsafe {
             match self.location.location {
                 InodeReadingLocationInfo::Direct(direct) => {
@@ -676,14 +862,15 @@ impl CachedInodeReadingLocation {
                     if idx0 == 0 {
                         self.inode.single_indirect_block_pointer = 0;
                         self.inode_dirty = true;
-                        balloc.dealloc_block(self.table1_addr)?;
+                        freed += Self::dealloc(ext2, self.table1_addr)?;
                     }
                 }
                 InodeReadingLocationInfo::Double(idx0, idx1) => {
                     *(self.table2.as_mut_ptr() as *mut u32).add(idx1 as usize) = 0;
                     self.table2_dirty = true;
                     if idx1 == 0 {
-                        balloc.dealloc_block(
+                        freed += Self::dealloc(
+                            ext2,
                             *(self.table1.as_mut_ptr() as *mut u32).add(idx0 as usize),
                         )?;
                         *(self.table1.as_mut_ptr() as *mut u32).add(idx0 as usize) = 0;
@@ -691,7 +878,7 @@ impl CachedInodeReadingLocation {
                         if idx0 == 0 {
                             self.inode.double_indirect_block_pointer = 0;
                             self.inode_dirty = true;
-                            balloc.dealloc_block(self.table1_addr)?;
+                            freed += Self::dealloc(ext2, self.table1_addr)?;
                         }
                     }
                 }
@@ -699,13 +886,15 @@ impl CachedInodeReadingLocation {
                     *(self.table3.as_mut_ptr() as *mut u32).add(idx2 as usize) = 0;
                     self.table3_dirty = true;
                     if idx2 == 0 {
-                        balloc.dealloc_block(
+                        freed += Self::dealloc(
+                            ext2,
                             *(self.table2.as_mut_ptr() as *mut u32).add(idx1 as usize),
                         )?;
                         *(self.table2.as_mut_ptr() as *mut u32).add(idx1 as usize) = 0;
                         self.table2_dirty = true;
                         if idx1 == 0 {
-                            balloc.dealloc_block(
+                            freed += Self::dealloc(
+                                ext2,
                                 *(self.table1.as_mut_ptr() as *mut u32).add(idx0 as usize),
                             )?;
                             *(self.table1.as_mut_ptr() as *mut u32).add(idx0 as usize) = 0;
@@ -713,7 +902,7 @@ impl CachedInodeReadingLocation {
                             if idx0 == 0 {
                                 self.inode.triple_indirect_block_pointer = 0;
                                 self.inode_dirty = true;
-                                balloc.dealloc_block(self.table1_addr)?;
+                                freed += Self::dealloc(ext2, self.table1_addr)?;
                             }
                         }
                     }
@@ -722,18 +911,20 @@ impl CachedInodeReadingLocation {
         }
 
         self.max_block_exclusive -= 1;
-        Ok(())
+        Ok(freed)
     }
 
     pub fn allocate_new_block(&mut self, ext2: &mut Ext2Volume) -> Result<u32, VfsError> {
+        // The goal group is the one of the last block, or of the inode for an empty file
         let mut group = if self.max_block_exclusive == 0 {
             self.seek(ext2, 0)?;
-            0
+            ext2.get_inode_group(self.inode.inode_i)
         } else {
             self.seek(ext2, self.max_block_exclusive as u32 - 1)?;
 
+            // Allocate near the last block, or from the first group after a hole
             let block = self.get_next_block()?;
-            let group = (block - 1) / ext2.blocks_per_group;
+            let group = ext2.block_group(block);
 
             if !self.location.advance() {
                 return Err(VfsError::MaximumSizeReached);
@@ -741,8 +932,8 @@ impl CachedInodeReadingLocation {
             group
         };
 
-        let current_sector_count = self.block_count() * ext2.sectors_per_block;
-        let max_next_count = current_sector_count as u64 + 4 * ext2.sectors_per_block as u64;
+        // Up to 4 blocks with the indirection tables
+        let max_next_count = self.inode.sectors_count as u64 + 4 * ext2.sectors_per_block as u64;
         if max_next_count > u32::MAX as u64 {
             return Err(VfsError::MaximumSizeReached);
         }
@@ -753,23 +944,10 @@ impl CachedInodeReadingLocation {
             group: &mut u32,
             alloc_count: &mut u32,
         ) -> Result<u32, VfsError> {
-            let balloc =
-                ext2.get_block_allocator_for_group(*group)?
-                    .ok_or(VfsError::DriverError(Box::new(format!(
-                        "No block allocator for group {group}"
-                    ))))?;
-            match balloc.alloc_block() {
-                Err(_) => {
-                    let block = ext2.alloc_block_any()?;
-                    *group = (block - 1) / ext2.blocks_per_group;
-                    *alloc_count += 1;
-                    Ok(block)
-                }
-                Ok(b) => {
-                    *alloc_count += 1;
-                    Ok(b)
-                }
-            }
+            let block = ext2.alloc_block_near(*group)?;
+            *group = ext2.block_group(block);
+            *alloc_count += 1;
+            Ok(block)
         }
 
         match self.location.location {
@@ -850,6 +1028,12 @@ impl CachedInodeReadingLocation {
     }
 
     pub fn flush(&mut self, ext2: &mut Ext2Volume) -> Result<(), VfsError> {
+        if (self.table1_dirty && self.table1_addr != 0)
+            || (self.table2_dirty && self.table2_addr != 0)
+            || (self.table3_dirty && self.table3_addr != 0)
+        {
+            ext2.order_write(WriteStage::Inode)?;
+        }
         if self.table1_dirty && self.table1_addr != 0 {
             ext2.write_block(self.table1_addr as u64, &self.table1)?;
             self.table1_dirty = false;
@@ -863,7 +1047,7 @@ impl CachedInodeReadingLocation {
             self.table3_dirty = false;
         }
         if self.inode_dirty {
-            ext2.update_inode(&self.inode)?;
+            ext2.mark_inode_dirty(&self.inode);
             self.inode_dirty = false;
         }
         Ok(())
@@ -0,0 +1,364 @@
+//! Formats a device with an empty ext2 volume of 1 KiB blocks, the root directory holding only
+//! `.` and `..`.
+//!
+//! Groups are numbered from block 1, like the driver does. Each one starts with a copy of the
+//! superblock and the descriptor table if it keeps a backup, then come its block bitmap, inode
+//! bitmap and inode table:
+//!
+//! ```text
+//! block 0                  boot block, left untouched
+//! group g, from 1 + 8192g  [superblock, descriptor table], block bitmap, inode bitmap,
+//!                          inode table, data
+//! ```
+//!
+//! The root directory takes the first data block of group 0.
+
+use alloc::vec;
+use core::ops::Range;
+
+use crate::{
+    data::file::File,
+    drivers::{
+        time::get_unix_timestamp,
+        vfs::{Arcrwb, BlockDevice, VfsError},
+    },
+    memory::entropy::fill_random,
+};
+
+use super::{
+    blockgroup::{RawBlockGroupDescriptor, BLOCK_GROUP_DESCRIPTOR_SIZE},
+    device::Ext2Device,
+    inode::RawInode,
+    is_sparse_backup_group,
+    superblock::{
+        FsState, FsStateFlag, OnErrorBehavior, OptionalFeatures, OsId, ROFeature, ROFeatures,
+        RequiredFeature, RequiredFeatures, Superblock, SUPERBLOCK_SIGNATURE,
+    },
+    Ext2Volume,
+};
+
+const BLOCK_SIZE: u32 = 1024;
+const INODE_SIZE: u32 = 128;
+const INODES_PER_BLOCK: u32 = BLOCK_SIZE / INODE_SIZE;
+/// Blocks a one block bitmap can describe
+const BLOCKS_PER_GROUP: u32 = BLOCK_SIZE * 8;
+const MIN_BLOCKS: u32 = 64;
+const MIN_INODES_PER_GROUP: u32 = 32;
+const FIRST_NON_RESERVED_INODE: u32 = 11;
+const ROOT_INODE: u32 = 2;
+const DIRECTORY_ENTRY_TYPE: u8 = 2;
+/// Blocks zeroed by a single write of the inode tables
+const ZERO_CHUNK_BLOCKS: u32 = 16;
+
+#[derive(Debug, Clone)]
+pub struct MkfsOptions {
+    /// One inode for this many bytes of the device
+    pub bytes_per_inode: u32,
+    /// Share of the blocks kept for root, in percent
+    pub reserved_percent: u32,
+    /// Keep superblock backups only in groups 0, 1 and the powers of 3, 5 and 7
+    pub sparse_super: bool,
+    pub volume_name: [u8; 16],
+}
+
+impl Default for MkfsOptions {
+    fn default() -> Self {
+        Self {
+            bytes_per_inode: 4096,
+            reserved_percent: 5,
+            sparse_super: true,
+            volume_name: [0; 16],
+        }
+    }
+}
+
+/// Where everything goes, computed from the device size
+#[derive(Debug)]
+struct Layout {
+    blocks_count: u32,
+    group_count: u32,
+    inodes_per_group: u32,
+    inode_table_blocks: u32,
+    descriptor_blocks: u32,
+    sparse_super: bool,
+}
+
+impl Layout {
+    fn new(size: u64, options: &MkfsOptions) -> Result<Self, VfsError> {
+        if !size.is_multiple_of(BLOCK_SIZE as u64)
+            || options.bytes_per_inode < BLOCK_SIZE
+            || options.reserved_percent > 50
+        {
+            return Err(VfsError::InvalidArgument);
+        }
+        let blocks_count = u32::try_from(size / BLOCK_SIZE as u64)
+            .ok()
+            .filter(|count| *count >= MIN_BLOCKS)
+            .ok_or(VfsError::InvalidArgument)?;
+        // Same count as the driver, block 0 isn't in a group
+        let group_count = (blocks_count - 1).div_ceil(BLOCKS_PER_GROUP);
+        let inodes = size / options.bytes_per_inode as u64;
+        let inodes_per_group = ((inodes / group_count as u64) as u32)
+            .max(MIN_INODES_PER_GROUP)
+            .next_multiple_of(INODES_PER_BLOCK)
+            .min(BLOCKS_PER_GROUP);
+
+        let layout = Self {
+            blocks_count,
+            group_count,
+            inodes_per_group,
+            inode_table_blocks: inodes_per_group / INODES_PER_BLOCK,
+            descriptor_blocks: (group_count * BLOCK_GROUP_DESCRIPTOR_SIZE).div_ceil(BLOCK_SIZE),
+            sparse_super: options.sparse_super,
+        };
+        // Group 0 also holds the root directory
+        for group in 0..group_count {
+            if layout.group_blocks(group).len() as u32
+                <= layout.metadata_blocks(group) + (group == 0) as u32
+            {
+                return Err(VfsError::InvalidArgument);
+            }
+        }
+        Ok(layout)
+    }
+
+    fn group_blocks(&self, group: u32) -> Range<u32> {
+        let start = 1 + group * BLOCKS_PER_GROUP;
+        start..(start + BLOCKS_PER_GROUP).min(self.blocks_count)
+    }
+
+    fn has_backup(&self, group: u32) -> bool {
+        !self.sparse_super || is_sparse_backup_group(group)
+    }
+
+    fn backup_blocks(&self, group: u32) -> u32 {
+        if self.has_backup(group) {
+            1 + self.descriptor_blocks
+        } else {
+            0
+        }
+    }
+
+    fn metadata_blocks(&self, group: u32) -> u32 {
+        self.backup_blocks(group) + 2 + self.inode_table_blocks
+    }
+
+    fn block_bitmap(&self, group: u32) -> u32 {
+        self.group_blocks(group).start + self.backup_blocks(group)
+    }
+
+    fn inode_bitmap(&self, group: u32) -> u32 {
+        self.block_bitmap(group) + 1
+    }
+
+    fn inode_table(&self, group: u32) -> u32 {
+        self.block_bitmap(group) + 2
+    }
+
+    fn root_directory_block(&self) -> u32 {
+        self.inode_table(0) + self.inode_table_blocks
+    }
+
+    /// Free blocks and inodes once formatted
+    fn free_counts(&self, group: u32) -> (u32, u32) {
+        let blocks = self.group_blocks(group).len() as u32 - self.metadata_blocks(group);
+        if group == 0 {
+            (
+                blocks - 1,
+                self.inodes_per_group - (FIRST_NON_RESERVED_INODE - 1),
+            )
+        } else {
+            (blocks, self.inodes_per_group)
+        }
+    }
+}
+
+fn put<T>(image: &mut [u8], offset: usize, value: T) {
+    assert!(offset + size_of::<T>() <= image.len());
+    unsafe { core::ptr::write_unaligned(image.as_mut_ptr().add(offset) as *mut T, value) }
+}
+
+fn set_bits(bitmap: &mut [u8], bits: Range<u32>) {
+    for bit in bits {
+        bitmap[(bit / 8) as usize] |= 1 << (bit % 8);
+    }
+}
+
+fn put_directory_entry(block: &mut [u8], offset: usize, name: &[u8], rec_len: u16) {
+    put(block, offset, ROOT_INODE);
+    put(block, offset + 4, rec_len);
+    put(block, offset + 6, name.len() as u8);
+    put(block, offset + 7, DIRECTORY_ENTRY_TYPE);
+    block[offset + 8..offset + 8 + name.len()].copy_from_slice(name);
+}
+
+fn write_block(device: &mut Ext2Device, block: u32, data: &[u8]) -> Result<(), VfsError> {
+    device.write_at(block as u64 * BLOCK_SIZE as u64, data)?;
+    Ok(())
+}
+
+fn format_device(mut device: Ext2Device, options: &MkfsOptions) -> Result<(), VfsError> {
+    if !device.is_writable() {
+        return Err(VfsError::ReadOnly);
+    }
+    let layout = Layout::new(device.size()?, options)?;
+    let now = get_unix_timestamp() as u32;
+
+    let mut descriptors = vec![0u8; (layout.descriptor_blocks * BLOCK_SIZE) as usize];
+    let (mut free_blocks, mut free_inodes) = (0, 0);
+    for group in 0..layout.group_count {
+        let (blocks, inodes) = layout.free_counts(group);
+        free_blocks += blocks;
+        free_inodes += inodes;
+        put(
+            &mut descriptors,
+            (group * BLOCK_GROUP_DESCRIPTOR_SIZE) as usize,
+            RawBlockGroupDescriptor {
+                block_usage_bitmap: layout.block_bitmap(group),
+                inode_usage_bitmap: layout.inode_bitmap(group),
+                inode_table_block: layout.inode_table(group),
+                free_blocks_count: blocks as u16,
+                free_inodes_count: inodes as u16,
+                directory_count: (group == 0) as u16,
+                padding: [0; 2],
+                unused: [0; 12],
+            },
+        );
+    }
+
+    let mut fs_state = FsState::empty();
+    fs_state.set(FsStateFlag::Clean);
+    let mut required_features = RequiredFeatures::empty();
+    required_features.set(RequiredFeature::DirectoryEntriesHaveTypeField);
+    let mut ro_features = ROFeatures::empty();
+    ro_features.set(ROFeature::FileSize64);
+    if layout.sparse_super {
+        ro_features.set(ROFeature::SparseDescriptorTables);
+    }
+    let mut fs_id = [0; 16];
+    fill_random(&mut fs_id);
+    let mut superblock = Superblock {
+        inodes_count: layout.inodes_per_group * layout.group_count,
+        blocks_count: layout.blocks_count,
+        su_reserved: (layout.blocks_count as u64 * options.reserved_percent as u64 / 100) as u32,
+        unallocated_blocks: free_blocks,
+        unallocated_inodes: free_inodes,
+        superblock_block: 1,
+        log_block_size: 0,
+        log_fragment_size: 0,
+        blocks_per_group: BLOCKS_PER_GROUP,
+        fragments_per_group: BLOCKS_PER_GROUP,
+        inodes_per_group: layout.inodes_per_group,
+        last_mount_time: 0,
+        last_write_time: now,
+        mount_count_since_fsck: 0,
+        max_mount_count_before_fsck: u16::MAX,
+        signature: SUPERBLOCK_SIGNATURE,
+        fs_state,
+        on_error_behavior: OnErrorBehavior::Continue,
+        minor_version_level: 0,
+        last_fsck_time: now,
+        fsck_interval: 0,
+        os_id: OsId::Linux,
+        major_version_level: 1,
+        user_id_reserved_blocks: 0,
+        group_id_reserved_blocks: 0,
+        first_non_reserved_inode: FIRST_NON_RESERVED_INODE,
+        inode_struct_size: INODE_SIZE as u16,
+        this_block_group: 0,
+        optional_features: OptionalFeatures::empty(),
+        required_features,
+        readonly_or_support_features: ro_features,
+        fs_id,
+        volume_name: options.volume_name,
+        last_mount_path: [0; 64],
+        compression_algorithm: 0,
+        file_block_preallocate_count: 0,
+        directory_block_preallocate_count: 0,
+        padding0: [0; 2],
+        journal_id: [0; 16],
+        journal_inode: 0,
+        journal_device: 0,
+        head_of_orphan_inode_list: 0,
+        hash_seed: [0; 4],
+        hash_version: 0,
+        padding1: [0; 3],
+        default_mount_options: 0,
+        first_meta_bg: 0,
+    };
+
+    let zeros = vec![0u8; (ZERO_CHUNK_BLOCKS * BLOCK_SIZE) as usize];
+    let mut block = vec![0u8; BLOCK_SIZE as usize];
+    for group in 0..layout.group_count {
+        let blocks = layout.group_blocks(group);
+        if layout.has_backup(group) {
+            superblock.this_block_group = group as u16;
+            block.fill(0);
+            put(&mut block, 0, superblock.clone());
+            write_block(&mut device, blocks.start, &block)?;
+            write_block(&mut device, blocks.start + 1, &descriptors)?;
+        }
+
+        // Bit n is block n of the group. The bits past the end of the volume are set too
+        let used = layout.metadata_blocks(group) + (group == 0) as u32;
+        block.fill(0);
+        set_bits(&mut block, 0..used);
+        set_bits(&mut block, blocks.len() as u32..BLOCKS_PER_GROUP);
+        write_block(&mut device, layout.block_bitmap(group), &block)?;
+
+        // Bit n is inode n + 1 of the group
+        block.fill(0);
+        if group == 0 {
+            set_bits(&mut block, 0..FIRST_NON_RESERVED_INODE - 1);
+        }
+        set_bits(&mut block, layout.inodes_per_group..BLOCK_SIZE * 8);
+        write_block(&mut device, layout.inode_bitmap(group), &block)?;
+
+        let table = layout.inode_table(group);
+        for chunk in (0..layout.inode_table_blocks).step_by(ZERO_CHUNK_BLOCKS as usize) {
+            let count = ZERO_CHUNK_BLOCKS.min(layout.inode_table_blocks - chunk);
+            write_block(
+                &mut device,
+                table + chunk,
+                &zeros[..(count * BLOCK_SIZE) as usize],
+            )?;
+        }
+    }
+
+    let mut root = RawInode::empty();
+    root.type_and_permissions = 0x4000 | 0o755;
+    root.atime = now;
+    root.ctime = now;
+    root.mtime = now;
+    // `.` and the `..` of the root itself
+    root.links_count = 2;
+    root.size_lo = BLOCK_SIZE;
+    root.sectors_count = BLOCK_SIZE / 512;
+    root.direct_block_pointers[0] = layout.root_directory_block();
+    block.fill(0);
+    put(&mut block, ((ROOT_INODE - 1) * INODE_SIZE) as usize, root);
+    write_block(&mut device, layout.inode_table(0), &block)?;
+
+    block.fill(0);
+    put_directory_entry(&mut block, 0, b".", 12);
+    put_directory_entry(&mut block, 12, b"..", (BLOCK_SIZE - 12) as u16);
+    write_block(&mut device, layout.root_directory_block(), &block)?;
+
+    device.flush()
+}
+
+impl Ext2Volume {
+    /// Makes an empty volume of the whole device, which must be opened for writing. Every group
+    /// needs room for its metadata, so sizes just past a multiple of 8 MiB are refused
+    pub fn format(device: File, options: MkfsOptions) -> Result<(), VfsError> {
+        format_device(Ext2Device::from_file(device)?, &options)
+    }
+}
+
+/// Formats a block device of at least 64 KiB with the default options
+pub fn mkfs_ext2_minimal(device: &Arcrwb<dyn BlockDevice>) -> Result<(), VfsError> {
+    format_device(
+        Ext2Device::from_block_device(device.clone()),
+        &MkfsOptions::default(),
+    )
+}
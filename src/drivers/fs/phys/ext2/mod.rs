@@ -1,7 +1,12 @@
-use core::num::NonZeroUsize;
+use core::{
+    cmp::Reverse,
+    num::NonZeroUsize,
+    sync::atomic::{AtomicU64, Ordering},
+};
 
 use alloc::{
     boxed::Box,
+    collections::{BTreeMap, BTreeSet},
     format,
     string::{String, ToString},
     sync::Arc,
@@ -9,37 +14,109 @@ use alloc::{
 };
 use balloc::BlockAllocator;
 use blockgroup::{BlockGroupDescriptor, RawBlockGroupDescriptor, BLOCK_GROUP_DESCRIPTOR_SIZE};
-use file::{Directory, DirectoryEntryType, DirectoryIterator, FileHandle};
+use device::Ext2Device;
+use file::{
+    DeviceNodeHandle, Directory, DirectoryEntry, DirectoryEntryType, DirectoryHandle,
+    DirectoryIndex, DirectoryIterator, FileHandle,
+};
 use ialloc::InodeAllocator;
 use inode::{
     Inode, InodeFlags, InodePermission, InodePermissions, InodeReadingLocation, InodeType, RawInode,
 };
 use lru::LruCache;
-use spin::RwLock;
+use order::{WriteOrder, WriteStage};
 use superblock::{
-    OptionalFeatures, ROFeature, ROFeatures, RequiredFeature, RequiredFeatures, Superblock,
-    SUPERBLOCK_SIGNATURE,
+    FsStateFlag, OptionalFeatures, ROFeature, ROFeatures, RequiredFeature, RequiredFeatures,
+    Superblock, SUPERBLOCK_SIGNATURE,
 };
 
 use crate::{
-    data::{alloc_boxed_slice, either::Either, file::File},
+    data::{
+        alloc_boxed_slice,
+        either::Either,
+        file::File,
+        lock::{DebugMutex, DebugRwLock, DebugRwLockWriteGuard, LockClass},
+        path::{VfsName, VfsPath},
+        try_alloc_boxed_slice, try_alloc_boxed_slice_or_reclaim,
+    },
     drivers::{
+        fs::{dirent::seek_directory, virt::devfs::open_device_node},
         time::get_unix_timestamp,
         vfs::{
-            default_get_file_implementation, Arcrwb, BlockDevice, FileHandleAllocator, FileStat,
-            FileSystem, FsSpecificFileData, SeekPosition, Vfs, VfsError, VfsFile, VfsFileKind,
-            WeakArcrwb, OPEN_MODE_APPEND, OPEN_MODE_NO_RESIZE, OPEN_MODE_READ, OPEN_MODE_WRITE,
+            copy_through_buffer, default_get_file_implementation, Arcrwb, BlockDevice,
+            FileHandleAllocator, FileStat, FileSystem, FsSpecificFileData, FsStat, PollEvents,
+            SeekPosition, Vfs, VfsError, VfsFile, VfsFileKind, WeakArcrwb, OPEN_MODE_APPEND,
+            OPEN_MODE_CREATE, OPEN_MODE_NO_RESIZE, OPEN_MODE_READ, OPEN_MODE_WRITE,
+            POLL_ALWAYS_READY,
         },
     },
+    log_debug, log_info, log_warn,
+    process::wait::WaitQueue,
 };
 
 pub mod balloc;
 pub mod blockgroup;
+pub mod device;
 pub mod file;
+pub mod fsck;
 pub mod ialloc;
 pub mod inode;
+pub mod mkfs;
+pub mod order;
 pub mod superblock;
 
+/// How deep the recovery pass walks the directory tree
+const RECOVERY_MAX_DEPTH: usize = 16;
+
+/// Most links an inode can have, same limit as the linux driver
+const LINK_MAX: u16 = 32000;
+
+/// Longest symlink target read, linux's PATH_MAX
+const SYMLINK_MAX_TARGET: u64 = 4096;
+
+/// Number of directories whose entries are kept in memory per volume
+const DIRECTORY_CACHE_SIZE: usize = 64;
+
+/// The block cache is split in independently locked shards, block `lba` goes in `lba % BLOCK_CACHE_SHARDS`
+const BLOCK_CACHE_SHARDS: usize = 8;
+
+#[derive(Debug)]
+pub struct BlockCacheStats {
+    pub hits: AtomicU64,
+    pub misses: AtomicU64,
+    /// Accesses that found their shard locked by another thread
+    pub lock_waits: AtomicU64,
+}
+
+/// Block cache counters of every ext2 volume, only counted in debug builds
+pub static BLOCK_CACHE_STATS: BlockCacheStats = BlockCacheStats {
+    hits: AtomicU64::new(0),
+    misses: AtomicU64::new(0),
+    lock_waits: AtomicU64::new(0),
+};
+
+#[derive(Debug)]
+pub struct BlockAllocStats {
+    /// Blocks allocated in the group asked for
+    pub in_goal: AtomicU64,
+    /// Blocks allocated in another group, the goal was full or there was none
+    pub elsewhere: AtomicU64,
+}
+
+/// Block allocation locality of every ext2 volume, only counted in debug builds
+pub static BLOCK_ALLOC_STATS: BlockAllocStats = BlockAllocStats {
+    in_goal: AtomicU64::new(0),
+    elsewhere: AtomicU64::new(0),
+};
+
+fn count_stat(counter: &AtomicU64) {
+    if cfg!(debug_assertions) {
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+type BlockCacheShard = DebugRwLock<LruCache<u32, Box<[u8]>>>;
+
 #[derive(Debug)]
 pub enum Ext2Error {
     BadSuperblockMagic(u16),
@@ -70,7 +147,7 @@ impl From<Ext2Error> for VfsError {
 
 #[derive(Debug)]
 pub struct Ext2Volume {
-    device: File,
+    device: Ext2Device,
     read_only: bool,
     superblock: Superblock,
 
@@ -85,9 +162,18 @@ pub struct Ext2Volume {
     inode_size: u16,
     inodes_per_block: u32,
 
-    block_cache: RwLock<LruCache<u32, Box<[u8]>>>,
+    block_cache: Box<[BlockCacheShard]>,
+    /// One block, for the inode and descriptor updates that change part of a block
+    scratch_block: DebugMutex<Box<[u8]>>,
+    read_ahead_blocks: u32,
     group_block_bitmap_caches: LruCache<u32, BlockAllocator>,
     group_inode_bitmap_caches: LruCache<u32, InodeAllocator>,
+    directory_cache: LruCache<u32, DirectoryIndex>,
+    write_order: WriteOrder,
+    /// Inodes changed in memory only, written back on flush, fsync or periodically
+    dirty_inodes: BTreeMap<u32, Inode>,
+    /// Inodes whose access time was updated since the mount
+    atime_updated: BTreeSet<u32>,
 
     // VFS stuff
     root_dir_fs_data: Option<Arc<Ext2FsSpecificFileData>>,
@@ -121,18 +207,49 @@ impl Ext2Volume {
     }
 
     /// cache_size is in bytes, gets rounded up to the next integer multiple of the block size
+    /// read_ahead_blocks is the number of blocks prefetched on sequential reads (0 disables read-ahead), capped to half the block cache
     pub fn from_device(
         device: File,
         block_cache_size: NonZeroUsize,
         block_usage_bitmap_cache_size: NonZeroUsize,
         inode_usage_bitmap_cache_size: NonZeroUsize,
+        read_ahead_blocks: usize,
     ) -> Result<Self, VfsError> {
-        if (device.get_open_mode() & OPEN_MODE_READ) == 0
-            || (device.get_open_mode() & OPEN_MODE_APPEND) == OPEN_MODE_APPEND
-        {
-            return Err(VfsError::InvalidOpenMode);
-        }
-        let stats = device.stats()?;
+        Self::new(
+            Ext2Device::from_file(device)?,
+            block_cache_size,
+            block_usage_bitmap_cache_size,
+            inode_usage_bitmap_cache_size,
+            read_ahead_blocks,
+        )
+    }
+
+    /// Same as `from_device`, but reads and writes go straight to `device`, like a partition
+    /// from the partition manager
+    pub fn from_block_device(
+        device: Arcrwb<dyn BlockDevice>,
+        block_cache_size: NonZeroUsize,
+        block_usage_bitmap_cache_size: NonZeroUsize,
+        inode_usage_bitmap_cache_size: NonZeroUsize,
+        read_ahead_blocks: usize,
+    ) -> Result<Self, VfsError> {
+        Self::new(
+            Ext2Device::from_block_device(device),
+            block_cache_size,
+            block_usage_bitmap_cache_size,
+            inode_usage_bitmap_cache_size,
+            read_ahead_blocks,
+        )
+    }
+
+    fn new(
+        device: Ext2Device,
+        block_cache_size: NonZeroUsize,
+        block_usage_bitmap_cache_size: NonZeroUsize,
+        inode_usage_bitmap_cache_size: NonZeroUsize,
+        read_ahead_blocks: usize,
+    ) -> Result<Self, VfsError> {
+        let device_size = device.size()?;
 
         let superblock = Superblock::from_device(&device)?;
 
@@ -141,12 +258,20 @@ impl Ext2Volume {
         }
         let block_size = 1024u32 << superblock.log_block_size;
         let block_count = superblock.blocks_count;
+        // The superblock is always at byte 1024
+        if superblock.superblock_block != (block_size == 1024) as u32 {
+            return Err(Ext2Error::BadSuperblock {
+                reason: "first data block doesn't match the block size",
+                superblock: Box::new(superblock),
+            }
+            .into());
+        }
         let sectors_per_block = block_size / 512;
 
-        if stats.size != (block_size as u64) * (block_count as u64) {
+        if device_size != (block_size as u64) * (block_count as u64) {
             return Err(Ext2Error::BadDeviceSize {
                 expected: (block_size as u64) * (block_count as u64),
-                actual: stats.size,
+                actual: device_size,
             }
             .into());
         }
@@ -163,8 +288,8 @@ impl Ext2Volume {
             .into());
         }
 
-        let read_only = (device.get_open_mode() & OPEN_MODE_WRITE) == 0
-            || (ro_features & Self::supported_ro_features()) != ro_features;
+        let read_only =
+            !device.is_writable() || (ro_features & Self::supported_ro_features()) != ro_features;
 
         let block_group_count = Self::count_block_groups(&superblock)?;
 
@@ -199,9 +324,15 @@ impl Ext2Volume {
             .into());
         }
 
-        let block_lru = LruCache::new(
-            NonZeroUsize::new(block_cache_size.get().div_ceil(block_size as usize)).unwrap(), // Guaranteed to be non-zero
-        );
+        let cached_blocks = block_cache_size.get().div_ceil(block_size as usize);
+        let shard_capacity = NonZeroUsize::new(cached_blocks.div_ceil(BLOCK_CACHE_SHARDS)).unwrap(); // Guaranteed to be non-zero
+        let block_cache = (0..BLOCK_CACHE_SHARDS)
+            .map(|_| {
+                DebugRwLock::with_class(LruCache::new(shard_capacity), LockClass::Ext2BlockCache)
+            })
+            .collect::<Box<[_]>>();
+
+        let read_ahead_blocks = read_ahead_blocks.min(cached_blocks / 2) as u32;
 
         let block_bitmaps_lru = LruCache::new(
             NonZeroUsize::new(block_usage_bitmap_cache_size.get().div_ceil(
@@ -229,9 +360,18 @@ impl Ext2Volume {
             block_group_descriptor_table: Vec::new(),
             inode_size,
             inodes_per_block,
-            block_cache: RwLock::new(block_lru),
+            block_cache,
+            scratch_block: DebugMutex::with_class(
+                alloc_boxed_slice(block_size as usize),
+                LockClass::Ext2Scratch,
+            ),
+            read_ahead_blocks,
             group_block_bitmap_caches: block_bitmaps_lru,
             group_inode_bitmap_caches: inode_bitmaps_lru,
+            directory_cache: LruCache::new(NonZeroUsize::new(DIRECTORY_CACHE_SIZE).unwrap()),
+            write_order: WriteOrder::default(),
+            dirty_inodes: BTreeMap::new(),
+            atime_updated: BTreeSet::new(),
             // VFS stuff
             root_dir_fs_data: None,
             os_id: 0,
@@ -246,6 +386,190 @@ impl Ext2Volume {
         Ok(ext2)
     }
 
+    pub fn get_read_ahead_blocks(&self) -> u32 {
+        self.read_ahead_blocks
+    }
+
+    /// Whether barriers are issued between the write stages, see `order`
+    pub fn set_ordered_writes(&mut self, ordered: bool) {
+        self.write_order.ordered = ordered;
+    }
+
+    /// Reports the writes that could reach the disk ahead of an earlier stage
+    pub fn set_validate_write_order(&mut self, validate: bool) {
+        self.write_order.validate = validate;
+    }
+
+    pub fn get_write_order(&self) -> &WriteOrder {
+        &self.write_order
+    }
+
+    /// Starts writing `stage`. Earlier stages are made durable first when writes are ordered, and
+    /// the bitmaps too before anything that can reference what they allocate
+    pub fn order_write(&mut self, stage: WriteStage) -> Result<(), VfsError> {
+        if self.write_order.ordered && stage >= WriteStage::Inode {
+            self.write_dirty_bitmaps()?;
+        }
+        if self.write_order.enter(stage) && self.write_order.ordered {
+            self.device.barrier()?;
+            self.write_order.barrier();
+        }
+        Ok(())
+    }
+
+    /// Goes read-only once the device reported a media change. What is still cached for writing
+    /// belongs to the old media and is dropped
+    fn check_stale_device(&mut self) {
+        if self.read_only || !self.device.is_stale() {
+            return;
+        }
+        log_warn!(target: "ext2", "the device changed under the volume, now read-only");
+        self.read_only = true;
+        self.dirty_inodes.clear();
+        self.group_block_bitmap_caches.clear();
+        self.group_inode_bitmap_caches.clear();
+    }
+
+    /// Flushes the device, which also orders everything written before
+    fn flush_device(&mut self) -> Result<(), VfsError> {
+        self.device.flush()?;
+        self.write_order.barrier();
+        Ok(())
+    }
+
+    /// Writes the dirty bitmap blocks of the cached allocators, they stay cached
+    fn write_dirty_bitmaps(&mut self) -> Result<(), VfsError> {
+        let block_groups = self
+            .group_block_bitmap_caches
+            .iter()
+            .filter(|(_, allocator)| allocator.is_dirty())
+            .map(|(group, _)| *group)
+            .collect::<Vec<_>>();
+        let inode_groups = self
+            .group_inode_bitmap_caches
+            .iter()
+            .filter(|(_, allocator)| allocator.is_dirty())
+            .map(|(group, _)| *group)
+            .collect::<Vec<_>>();
+        if block_groups.is_empty() && inode_groups.is_empty() {
+            return Ok(());
+        }
+
+        self.order_write(WriteStage::Allocation)?;
+        for group in block_groups {
+            if let Some(mut allocator) = self.group_block_bitmap_caches.pop(&group) {
+                let written = allocator.write_dirty(self);
+                // Just popped, nothing gets evicted
+                self.group_block_bitmap_caches.push(group, allocator);
+                written?;
+            }
+        }
+        for group in inode_groups {
+            if let Some(mut allocator) = self.group_inode_bitmap_caches.pop(&group) {
+                let written = allocator.write_dirty(self);
+                self.group_inode_bitmap_caches.push(group, allocator);
+                written?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Loads the given blocks into the block cache, physically contiguous blocks are read with a single device read
+    pub fn prefetch_blocks(&self, blocks: &[u32]) -> Result<(), VfsError> {
+        let bs = self.block_size as usize;
+        let is_cached = |lba: u32| self.block_cache_shard_of(lba).read().contains(&lba);
+
+        let mut i = 0;
+        while i < blocks.len() {
+            let begin = blocks[i];
+            if begin == 0 || begin >= self.block_count || is_cached(begin) {
+                i += 1;
+                continue;
+            }
+            let mut run = 1;
+            while i + run < blocks.len()
+                && blocks[i + run] == begin + run as u32
+                && blocks[i + run] < self.block_count
+                && !is_cached(blocks[i + run])
+            {
+                run += 1;
+            }
+
+            // Read-ahead is skipped rather than evicting cached blocks
+            let Some(mut data) = try_alloc_boxed_slice::<u8>(run * bs) else {
+                return Ok(());
+            };
+            let read = self
+                .device
+                .read_at(self.block_size as u64 * begin as u64, &mut data)?
+                as usize;
+
+            for (j, chunk) in data[0..read - read % bs].chunks_exact(bs).enumerate() {
+                let Some(mut slice) = try_alloc_boxed_slice::<u8>(bs) else {
+                    return Ok(());
+                };
+                slice.copy_from_slice(chunk);
+                let lba = begin + j as u32;
+                self.lock_block_cache_shard(lba).push(lba, slice);
+            }
+
+            i += run;
+        }
+
+        Ok(())
+    }
+
+    /// A block sized buffer. When memory runs out the block cache is emptied and the allocation
+    /// tried once more
+    pub fn alloc_block_buffer(&self) -> Result<Box<[u8]>, VfsError> {
+        try_alloc_boxed_slice_or_reclaim(self.block_size as usize, || self.shrink_block_cache())
+            .ok_or(VfsError::OutOfMemory)
+    }
+
+    /// Drops every cached block, the cache is write through so none is dirty
+    fn shrink_block_cache(&self) {
+        for shard in self.block_cache.iter() {
+            shard.write().clear();
+        }
+    }
+
+    /// Runs `f` with the scratch block, which it can't use again through the volume
+    fn with_scratch_block<R>(&mut self, f: impl FnOnce(&mut Self, &mut [u8]) -> R) -> R {
+        let mut block = core::mem::take(&mut *self.scratch_block.lock());
+        let result = f(self, &mut block);
+        *self.scratch_block.lock() = block;
+        result
+    }
+
+    fn block_cache_shard_of(&self, lba: u32) -> &BlockCacheShard {
+        &self.block_cache[lba as usize % BLOCK_CACHE_SHARDS]
+    }
+
+    fn lock_block_cache_shard(
+        &self,
+        lba: u32,
+    ) -> DebugRwLockWriteGuard<'_, LruCache<u32, Box<[u8]>>> {
+        let shard = self.block_cache_shard_of(lba);
+        match shard.try_write() {
+            Some(guard) => guard,
+            None => {
+                count_stat(&BLOCK_CACHE_STATS.lock_waits);
+                shard.write()
+            }
+        }
+    }
+
+    /// Opens that could change the volume fail upfront when it's read-only, instead of on the
+    /// first block written back
+    fn check_open_mode(&self, mode: u64) -> Result<(), VfsError> {
+        if (self.read_only || self.device.is_stale())
+            && mode & (OPEN_MODE_WRITE | OPEN_MODE_APPEND | OPEN_MODE_CREATE) != 0
+        {
+            return Err(VfsError::ReadOnly);
+        }
+        Ok(())
+    }
+
     pub fn get_superblock(&self) -> &Superblock {
         &self.superblock
     }
@@ -255,14 +579,9 @@ impl Ext2Volume {
         let table_size = entry_count * BLOCK_GROUP_DESCRIPTOR_SIZE;
 
         let mut table = alloc::vec![0u8; table_size as usize];
-        let start_byte = if self.block_size == 1024 {
-            2048
-        } else {
-            self.block_size as u64
-        };
+        let start_byte = (self.group_first_block(0) as u64 + 1) * self.block_size as u64;
 
-        self.device.seek(SeekPosition::FromStart(start_byte))?;
-        self.device.read(&mut table)?;
+        self.device.read_at(start_byte, &mut table)?;
 
         self.block_group_descriptor_table
             .reserve_exact(entry_count as usize);
@@ -288,7 +607,11 @@ impl Ext2Volume {
                 superblock: Box::new(superblock.clone()),
             });
         }
-        let r1 = superblock.blocks_count.div_ceil(bpg);
+        // The blocks before the first group don't count
+        let r1 = superblock
+            .blocks_count
+            .saturating_sub(superblock.superblock_block)
+            .div_ceil(bpg);
         let r2 = superblock.inodes_count.div_ceil(ipg);
         if r1 != r2 {
             Err(Ext2Error::BadBlockGroupDescriptorTableEntrySize(r1, r2))
@@ -309,10 +632,25 @@ impl Ext2Volume {
     fn get_inode_range_for_group(&self, group: u32) -> (u32, u32) {
         let start = group * self.superblock.inodes_per_group;
         let end = (group + 1) * self.superblock.inodes_per_group;
-        (start + 1, (end + 1).min(self.superblock.inodes_count))
+        (start + 1, (end + 1).min(self.superblock.inodes_count + 1))
     }
 
     pub fn get_inode(&self, inode: u32, parent_inode: Option<u32>) -> Result<Inode, VfsError> {
+        if let Some(dirty) = self.dirty_inodes.get(&inode) {
+            return Ok(Inode {
+                parent_inode,
+                ..dirty.clone()
+            });
+        }
+        Ok(Inode::from_raw(
+            self.read_raw_inode(inode)?,
+            inode,
+            parent_inode,
+        ))
+    }
+
+    /// The inode as stored in the inode table, without the changes kept in memory
+    pub fn read_raw_inode(&self, inode: u32) -> Result<RawInode, VfsError> {
         if inode == 0 || inode > self.superblock.inodes_count {
             Err(Ext2Error::BadInodeIndex(inode))?;
         }
@@ -329,16 +667,14 @@ impl Ext2Volume {
         let block_index = index / self.inodes_per_block;
         let offset_in_block = (index % self.inodes_per_block) * (self.inode_size as u32);
 
-        let mut buffer = alloc::vec![0u8; self.block_size as usize];
+        let mut buffer = self.scratch_block.lock();
         self.read_block((block + block_index) as u64, &mut buffer)?;
 
-        let raw = unsafe {
+        Ok(unsafe {
             core::ptr::read_volatile(
                 buffer.as_ptr().add(offset_in_block as usize) as *const RawInode
             )
-        };
-
-        Ok(Inode::from_raw(raw, inode, parent_inode))
+        })
     }
 
     pub fn update_inode(&mut self, inode: &Inode) -> Result<(), VfsError> {
@@ -347,7 +683,40 @@ impl Ext2Volume {
         self.update_inode_raw(inode.inode_i, raw)
     }
 
+    /// Keeps the change in memory, the inode is written back later with the others
+    pub fn mark_inode_dirty(&mut self, inode: &Inode) {
+        self.dirty_inodes.insert(inode.inode_i, inode.clone());
+    }
+
+    /// Writes the inode now if it has changes kept in memory
+    pub fn write_inode(&mut self, inode_i: u32) -> Result<(), VfsError> {
+        match self.dirty_inodes.remove(&inode_i) {
+            Some(inode) => self.update_inode(&inode),
+            None => Ok(()),
+        }
+    }
+
+    pub fn write_dirty_inodes(&mut self) -> Result<(), VfsError> {
+        while let Some((_, inode)) = self.dirty_inodes.pop_first() {
+            self.update_inode(&inode)?;
+        }
+        Ok(())
+    }
+
+    /// Relatime: the access time is written once per mount, or again once the file was modified
+    /// after the last access
+    pub fn touch_atime(&mut self, inode: &mut Inode) {
+        if self.read_only || (self.atime_updated.contains(&inode.inode_i) && !inode.atime_stale()) {
+            return;
+        }
+        self.atime_updated.insert(inode.inode_i);
+        inode.atime = get_unix_timestamp() as u32;
+        self.mark_inode_dirty(inode);
+    }
+
+    /// Overrides any change of the inode kept in memory
     pub fn update_inode_raw(&mut self, inode_i: u32, raw_inode: RawInode) -> Result<(), VfsError> {
+        self.dirty_inodes.remove(&inode_i);
         let group = self.get_inode_group(inode_i);
         let index = self.get_inode_index_in_group(inode_i);
 
@@ -360,17 +729,138 @@ impl Ext2Volume {
         let block_index = index / self.inodes_per_block;
         let offset_in_block = (index % self.inodes_per_block) * (self.inode_size as u32);
 
-        let mut buffer = alloc::vec![0u8; self.block_size as usize];
-        self.read_block((block + block_index) as u64, &mut buffer)?;
+        self.order_write(WriteStage::Inode)?;
+        self.with_scratch_block(|ext2, buffer| {
+            ext2.read_block((block + block_index) as u64, buffer)?;
+            unsafe {
+                core::ptr::write_volatile(
+                    buffer.as_ptr().add(offset_in_block as usize) as *mut RawInode,
+                    raw_inode,
+                )
+            };
+            ext2.write_block((block + block_index) as u64, buffer)?;
+            Ok(())
+        })
+    }
+
+    /// Changes the inode of an open file, in the handle and on disk. The inode change time is updated too
+    fn update_handle_inode(
+        &mut self,
+        handle: u64,
+        change: impl FnOnce(&mut Inode),
+    ) -> Result<(), VfsError> {
+        if self.read_only {
+            return Err(VfsError::ActionNotAllowed);
+        }
+        if let Some(device) = self.device_node_handle(handle) {
+            let mut inode = self.get_inode(device.inode_i, None)?;
+            change(&mut inode);
+            inode.ctime = get_unix_timestamp() as u32;
+            return self.update_inode(&inode);
+        }
+        let data = unsafe {
+            &mut *self
+                .handles
+                .get_handle_data::<FileHandle>(handle)
+                .ok_or(VfsError::BadHandle)?
+        };
+        let inode = data.get_inode_mut();
+        change(inode);
+        inode.ctime = get_unix_timestamp() as u32;
+        self.update_inode(data.get_inode())
+    }
+
+    /// The devfs handle behind `handle`, if it is the handle of a device node
+    fn device_node_handle(&self, handle: u64) -> Option<DeviceNodeHandle> {
         unsafe {
-            core::ptr::write_volatile(
-                buffer.as_ptr().add(offset_in_block as usize) as *mut RawInode,
-                raw_inode,
-            )
+            self.handles
+                .get_handle_data::<DeviceNodeHandle>(handle)
+                .map(|data| (*data).clone())
+        }
+    }
+
+    fn directory_handle(&self, handle: u64) -> Option<DirectoryHandle> {
+        unsafe {
+            self.handles
+                .get_handle_data::<DirectoryHandle>(handle)
+                .map(|data| (*data).clone())
+        }
+    }
+
+    /// Reads the records of the directory from the position of the handle
+    fn read_directory(
+        &mut self,
+        handle: u64,
+        directory: DirectoryHandle,
+        buf: &mut [u8],
+    ) -> Result<u64, VfsError> {
+        let inode = self.get_inode(directory.inode.inode_i, directory.inode.parent_inode)?;
+        let mut iterator = DirectoryIterator::new(self, inode.clone(), OPEN_MODE_READ)?;
+        iterator.seek(directory.position as usize)?;
+        let read = iterator.read_records(buf)?;
+        let position = iterator.get_index() as u64;
+        drop(iterator);
+
+        let data = unsafe {
+            &mut *self
+                .handles
+                .get_handle_data::<DirectoryHandle>(handle)
+                .ok_or(VfsError::BadHandle)?
         };
-        self.write_block((block + block_index) as u64, &buffer)?;
+        data.inode = inode;
+        data.position = position;
+        Ok(read)
+    }
 
-        Ok(())
+    /// `fread_at` of a file handle, `block` is the staging buffer of the volume blocks
+    fn read_handle_at(
+        &mut self,
+        handle: u64,
+        offset: u64,
+        buf: &mut [u8],
+        block: &mut [u8],
+    ) -> Result<u64, VfsError> {
+        let data = unsafe {
+            &mut *self
+                .handles
+                .get_handle_data::<FileHandle>(handle)
+                .ok_or(VfsError::BadHandle)?
+        };
+        if data.get_open_mode() & OPEN_MODE_READ == 0 {
+            return Err(VfsError::ActionNotAllowed);
+        }
+        let read = data.read_at_through(self, offset, buf, block)?;
+        self.touch_atime(data.get_inode_mut());
+        Ok(read)
+    }
+
+    /// `fwrite_at` of a file handle, `block` is the staging buffer of the volume blocks
+    fn write_handle_at(
+        &mut self,
+        handle: u64,
+        offset: u64,
+        buf: &[u8],
+        block: &mut [u8],
+    ) -> Result<u64, VfsError> {
+        let data = unsafe {
+            &mut *self
+                .handles
+                .get_handle_data::<FileHandle>(handle)
+                .ok_or(VfsError::BadHandle)?
+        };
+        if data.get_open_mode() & OPEN_MODE_WRITE == 0 {
+            return Err(VfsError::ActionNotAllowed);
+        }
+        let checked_buf = if data.get_open_mode() & OPEN_MODE_NO_RESIZE == OPEN_MODE_NO_RESIZE {
+            let max_pos = data.get_size();
+            if offset > max_pos {
+                return Err(VfsError::ActionNotAllowed);
+            }
+            &buf[0..(max_pos - offset).min(buf.len() as u64) as usize]
+        } else {
+            buf
+        };
+        data.write_at_through(self, offset, checked_buf, block)
     }
 
     pub fn get_file_handle(&mut self, inode: Inode, mode: u64) -> Result<FileHandle, VfsError> {
@@ -388,10 +878,18 @@ impl Ext2Volume {
         let size = inode.get_size(self);
         let (data, kind) = match inode.inode_type {
             InodeType::Directory => (
-                Either::new_right(Directory::new(self, inode, OPEN_MODE_READ)?),
+                Either::new_right(Directory::new(inode)),
                 VfsFileKind::Directory,
             ),
             InodeType::File => (Either::new_left(inode), VfsFileKind::File),
+            InodeType::BlockDevice | InodeType::CharacterDevice => {
+                let kind = VfsFileKind::DeviceNode {
+                    block: inode.inode_type == InodeType::BlockDevice,
+                    number: inode.device_number(),
+                };
+                (Either::new_left(inode), kind)
+            }
+            InodeType::Symlink => (Either::new_left(inode), VfsFileKind::Symlink),
             _ => Err(VfsError::UnknownError)?,
         };
 
@@ -409,8 +907,30 @@ impl Ext2Volume {
         ))
     }
 
-    fn dealloc_inode(&mut self, inode: Inode) -> Result<(), VfsError> {
+    /// The cached entries of directory `dir_inode`, read from disk on a miss
+    fn directory_index(&mut self, dir_inode: u32) -> Result<&mut DirectoryIndex, VfsError> {
+        if !self.directory_cache.contains(&dir_inode) {
+            let index = DirectoryIndex::read(self, self.get_inode(dir_inode, None)?)?;
+            self.directory_cache.push(dir_inode, index);
+        }
+        Ok(self.directory_cache.get_mut(&dir_inode).unwrap()) // Just inserted
+    }
+
+    fn directory_entries(&mut self, dir_inode: u32) -> Result<Vec<DirectoryEntry>, VfsError> {
+        Ok(self.directory_index(dir_inode)?.entries().to_vec())
+    }
+
+    fn dealloc_inode(&mut self, mut inode: Inode) -> Result<(), VfsError> {
         let inode_i = inode.inode_i;
+        self.directory_cache.pop(&inode_i);
+        if matches!(
+            inode.inode_type,
+            InodeType::BlockDevice | InodeType::CharacterDevice
+        ) || inode.is_fast_symlink()
+        {
+            // The block pointers hold the device number or the target, there are no blocks to free
+            inode.direct_block_pointers = [0; 12];
+        }
         let mut handle = self.get_file_handle(inode, OPEN_MODE_READ | OPEN_MODE_WRITE)?;
         // deallocate all the blocks
         handle.truncate(self, 0)?;
@@ -427,8 +947,17 @@ impl Ext2Volume {
         Ok(())
     }
 
-    fn delete_inode(&mut self, inode: &Inode) -> Result<(), VfsError> {
-        if !matches!(inode.inode_type, InodeType::File | InodeType::Directory) {
+    /// Removes the directory entry `name` pointing at `inode`, the inode is only freed once its
+    /// last link is gone
+    fn delete_inode(&mut self, inode: &Inode, name: &[u8]) -> Result<(), VfsError> {
+        if !matches!(
+            inode.inode_type,
+            InodeType::File
+                | InodeType::Directory
+                | InodeType::BlockDevice
+                | InodeType::CharacterDevice
+                | InodeType::Symlink
+        ) {
             // TODO: Not implemented
             return Err(VfsError::ActionNotAllowed);
         }
@@ -439,22 +968,21 @@ impl Ext2Volume {
                 inode.inode_i
             ))))?;
 
-        let mut parent_inode = self.get_inode(parent, None)?;
-        parent_inode.links_count -= 1;
-        self.update_inode(&parent_inode)?;
-
         let dir_inode = self.get_inode(parent, None)?;
-        Directory::delete_entry(self, &dir_inode, inode.inode_i)?;
-
-        let mut new_inode = inode.clone();
-        let mut t = get_unix_timestamp() as u32;
-        if t == 0 {
-            t = 1;
+        Directory::delete_entry(self, &dir_inode, inode.inode_i, name)?;
+        if let Some(index) = self.directory_cache.get_mut(&parent) {
+            index.remove(inode.inode_i, name);
         }
-        new_inode.dtime = t;
+
+        // Other links may have changed the count since `inode` was read
+        let mut new_inode = self.get_inode(inode.inode_i, Some(parent))?;
+        let t = get_unix_timestamp() as u32;
 
         match inode.inode_type {
-            InodeType::File => {
+            InodeType::File
+            | InodeType::BlockDevice
+            | InodeType::CharacterDevice
+            | InodeType::Symlink => {
                 new_inode.links_count -= 1;
             }
             InodeType::Directory => {
@@ -480,14 +1008,34 @@ impl Ext2Volume {
         }
 
         if new_inode.links_count == 0 {
+            new_inode.dtime = t.max(1);
             self.dealloc_inode(new_inode)?;
         } else {
+            new_inode.ctime = t;
             self.update_inode(&new_inode)?;
+            self.sync_open_links_count(&new_inode);
         }
 
         Ok(())
     }
 
+    /// Open handles keep their own copy of the inode and write it back, keep their link count
+    /// in sync so they don't undo a link or unlink
+    fn sync_open_links_count(&mut self, inode: &Inode) {
+        for handle in self.handles.iter() {
+            let Some(data) = (unsafe { self.handles.get_handle_data::<FileHandle>(*handle) })
+            else {
+                continue;
+            };
+            let data = unsafe { &mut *data };
+            if data.get_inode().inode_i == inode.inode_i {
+                let open_inode = data.get_inode_mut();
+                open_inode.links_count = inode.links_count;
+                open_inode.ctime = inode.ctime;
+            }
+        }
+    }
+
     fn allocate_inode(
         &mut self,
         uid: u16,
@@ -527,7 +1075,7 @@ impl Ext2Volume {
         &mut self,
         dir_inode: u32,
         inode_i: u32,
-        name: &[char],
+        name: &VfsName,
         entry_type: DirectoryEntryType,
     ) -> Result<(), VfsError> {
         let mut inode = self.get_inode(inode_i, None)?;
@@ -536,9 +1084,10 @@ impl Ext2Volume {
 
         let inode = self.get_inode(dir_inode, Some(dir_inode))?;
         let mut iterator = DirectoryIterator::new(self, inode, OPEN_MODE_READ | OPEN_MODE_WRITE)?;
-        let name = name.iter().map(|c| *c as u8).collect::<Vec<u8>>();
-
-        iterator.insert_entry(inode_i, &name, entry_type)?;
+        let entry = iterator.insert_entry(inode_i, name.as_bytes(), entry_type)?;
+        if let Some(index) = self.directory_cache.get_mut(&dir_inode) {
+            index.insert(entry.into_entry());
+        }
 
         Ok(())
     }
@@ -561,6 +1110,7 @@ impl Ext2Volume {
         let entry_self = iterator.insert_entry(inode_i, b".", DirectoryEntryType::Directory)?;
         iterator.move_to_entry(&entry_self)?;
         iterator.insert_entry(parent_inode, b"..", DirectoryEntryType::Directory)?;
+        self.directory_cache.pop(&inode_i);
         Ok(())
     }
 
@@ -571,83 +1121,39 @@ impl Ext2Volume {
     }
 
     pub fn block_group_contains_metadata_backup(&self, group: u32) -> bool {
-        #[inline(always)]
-        fn is_pow(a: u32, b: u32) -> bool {
-            let mut num = b;
-            while a > num {
-                num = match num.checked_mul(b) {
-                    Some(v) => v,
-                    None => return false,
-                };
-            }
-            num == a
-        }
-        !self
-            .superblock
-            .get_ro_features()
-            .has(ROFeature::SparseDescriptorTables)
-            || (group <= 1 || is_pow(group, 3) || is_pow(group, 5) || is_pow(group, 7))
+        group < self.block_group_count
+            && (!self
+                .superblock
+                .get_ro_features()
+                .has(ROFeature::SparseDescriptorTables)
+                || is_sparse_backup_group(group))
     }
 
-    fn get_backup_groups(&self) -> Box<dyn Iterator<Item = u32>> {
+    /// Groups holding the superblock and descriptor table or a backup of them, group 0 first
+    fn backup_groups(&self) -> Vec<u32> {
         if self
             .superblock
             .get_ro_features()
             .has(ROFeature::SparseDescriptorTables)
         {
-            if self.block_group_count <= 1 {
-                return Box::new(0..self.block_group_count);
-            }
-            let mut vec: Vec<u32> = alloc::vec![0];
-            if self.block_group_count > 1 {
-                vec.push(1);
-            }
-            let mut pow3: u32 = 1;
-            let mut pow5: u32 = 1;
-            let mut pow7: u32 = 1;
-            loop {
-                pow3 = match pow3.checked_mul(3) {
-                    None => break,
-                    Some(v) => {
-                        if v > self.block_group_count {
-                            break;
-                        }
-                        vec.push(v);
-                        v
-                    }
-                }
-            }
-            loop {
-                pow5 = match pow5.checked_mul(5) {
-                    None => break,
-                    Some(v) => {
-                        if v > self.block_group_count {
-                            break;
-                        }
-                        vec.push(v);
-                        v
-                    }
-                }
-            }
-            loop {
-                pow7 = match pow7.checked_mul(7) {
-                    None => break,
-                    Some(v) => {
-                        if v > self.block_group_count {
-                            break;
-                        }
-                        vec.push(v);
-                        v
-                    }
-                }
-            }
-            Box::new(vec.into_iter())
+            sparse_backup_groups(self.block_group_count).collect()
         } else {
-            return Box::new(0..self.block_group_descriptor_table.len() as u32);
+            (0..self.block_group_count).collect()
         }
     }
 
+    /// First block of the group, where its superblock backup goes. Block 0 holds the superblock
+    /// with 1K blocks, so the groups begin at block 1, and at block 0 with bigger blocks
+    pub fn group_first_block(&self, group: u32) -> u32 {
+        self.superblock.superblock_block + group * self.blocks_per_group
+    }
+
+    pub fn block_group(&self, block: u32) -> u32 {
+        block.saturating_sub(self.superblock.superblock_block) / self.blocks_per_group
+    }
+
     pub fn set_superblock(&mut self, superblock: Superblock) -> Result<(), VfsError> {
+        self.check_stale_device();
         if self.read_only {
             return Err(VfsError::ActionNotAllowed);
         }
@@ -655,17 +1161,24 @@ impl Ext2Volume {
 
         let mut buffer = alloc::vec![0u8; size_of::<Superblock>()];
         unsafe {
-            core::ptr::write_volatile(buffer.as_mut_ptr() as *mut Superblock, superblock);
+            core::ptr::write_volatile(buffer.as_mut_ptr() as *mut Superblock, superblock.clone());
         }
 
-        self.device.seek(SeekPosition::FromStart(1024))?;
-        self.device.write(&buffer)?;
+        self.order_write(WriteStage::Superblock)?;
+        self.write_order.record_write();
+        self.device.write_at(1024, &buffer)?;
 
-        for backup_group in self.get_backup_groups().as_mut().skip(1) {
-            let lba = (backup_group as u64) * (self.blocks_per_group as u64) + 1;
+        // Backups tell which group they're in
+        for backup_group in self.backup_groups().into_iter().skip(1) {
+            let mut backup = superblock.clone();
+            backup.this_block_group = backup_group as u16;
+            unsafe {
+                core::ptr::write_volatile(buffer.as_mut_ptr() as *mut Superblock, backup);
+            }
+            let lba = self.group_first_block(backup_group) as u64;
+            self.write_order.record_write();
             self.device
-                .seek(SeekPosition::FromStart(self.block_size as u64 * lba))?;
-            self.device.write(&buffer)?;
+                .write_at(self.block_size as u64 * lba, &buffer)?;
         }
 
         Ok(())
@@ -685,22 +1198,23 @@ impl Ext2Volume {
         let block_index = byte_index / self.block_size as usize;
         let offset_in_block = byte_index % self.block_size as usize;
 
-        let mut buffer = alloc::vec![0u8; self.block_size as usize];
-        unsafe {
-            for backup_group in self.get_backup_groups().as_mut() {
-                let backup = (backup_group as u64) * (self.blocks_per_group as u64)
-                    + (block_index as u64)
-                    + 2; // Because superblock is at +1
-
-                self.read_block(backup, &mut buffer)?;
-                core::ptr::write_volatile(
-                    buffer.as_mut_ptr().add(offset_in_block) as *mut RawBlockGroupDescriptor,
-                    descriptor.to_raw(),
-                );
-                self.write_block(backup, &buffer)?;
+        self.order_write(WriteStage::Allocation)?;
+        self.with_scratch_block(|ext2, buffer| {
+            for backup_group in ext2.backup_groups() {
+                // The table follows the superblock
+                let backup = ext2.group_first_block(backup_group) as u64 + 1 + block_index as u64;
+
+                ext2.read_block(backup, buffer)?;
+                unsafe {
+                    core::ptr::write_volatile(
+                        buffer.as_mut_ptr().add(offset_in_block) as *mut RawBlockGroupDescriptor,
+                        descriptor.to_raw(),
+                    )
+                };
+                ext2.write_block(backup, buffer)?;
             }
-        }
-        Ok(())
+            Ok(())
+        })
     }
 
     pub fn get_block_allocator_for_group<'a: 'b, 'b>(
@@ -721,7 +1235,7 @@ impl Ext2Volume {
             return Ok(None);
         };
 
-        let min_block_inclusive = group * self.blocks_per_group + 1;
+        let min_block_inclusive = self.group_first_block(group);
         let max_block_exclusive =
             (min_block_inclusive + self.blocks_per_group).min(self.block_count);
         let blocks = max_block_exclusive - min_block_inclusive;
@@ -759,26 +1273,63 @@ impl Ext2Volume {
         group: u32,
         mut allocator: BlockAllocator,
     ) -> Result<(), VfsError> {
+        self.order_write(WriteStage::Allocation)?;
         allocator.write_dirty(self)?;
 
         let diff = *allocator.get_diff_usage();
         *allocator.get_diff_usage() = 0;
 
-        let mut superblock = self.get_superblock().clone();
-        superblock.unallocated_blocks = ((superblock.unallocated_blocks as i64) - diff) as u32;
-        self.set_superblock(superblock)?;
-
         let mut descriptor = self.get_block_group_descriptor(group).unwrap();
         descriptor.free_blocks_count = ((descriptor.free_blocks_count as i64) - diff) as u16;
         self.set_block_group_descriptor(group, descriptor)?;
 
+        // The counters summarize the bitmap, they go last
+        let mut superblock = self.get_superblock().clone();
+        superblock.unallocated_blocks = ((superblock.unallocated_blocks as i64) - diff) as u32;
+        self.set_superblock(superblock)?;
+
         Ok(())
     }
 
+    /// Free blocks of the group, minus the allocations its descriptor doesn't count yet
+    fn group_free_blocks(&self, group: u32) -> u32 {
+        let Some(descriptor) = self.get_block_group_descriptor(group) else {
+            return 0;
+        };
+        let pending = self
+            .group_block_bitmap_caches
+            .peek(&group)
+            .map_or(0, |allocator| allocator.diff_usage());
+        (descriptor.free_blocks_count as i64 - pending).max(0) as u32
+    }
+
+    /// Allocates in `goal` if it has room, else like `alloc_block_any`
+    pub fn alloc_block_near(&mut self, goal: u32) -> Result<u32, VfsError> {
+        if self.group_free_blocks(goal) != 0 {
+            if let Some(allocator) = self.get_block_allocator_for_group(goal)? {
+                if let Ok(block) = allocator.alloc_block() {
+                    count_stat(&BLOCK_ALLOC_STATS.in_goal);
+                    return Ok(block);
+                }
+            }
+        }
+        self.alloc_block_any()
+    }
+
+    /// Allocates in the group with the most free blocks. Groups are tried in that order, so a
+    /// wrong descriptor count only costs a scan of their bitmap
     pub fn alloc_block_any(&mut self) -> Result<u32, VfsError> {
-        for group in 0..self.block_group_count {
+        if self.read_only {
+            return Err(VfsError::ReadOnly);
+        }
+        let mut groups = (0..self.block_group_count)
+            .map(|group| (self.group_free_blocks(group), group))
+            .collect::<Vec<_>>();
+        groups.sort_unstable_by_key(|(free, group)| (Reverse(*free), *group));
+        for (_, group) in groups {
             if let Some(allocator) = self.get_block_allocator_for_group(group)? {
                 if let Ok(block) = allocator.alloc_block() {
+                    count_stat(&BLOCK_ALLOC_STATS.elsewhere);
                     return Ok(block);
                 }
             }
@@ -841,19 +1392,21 @@ impl Ext2Volume {
         group: u32,
         mut allocator: InodeAllocator,
     ) -> Result<(), VfsError> {
+        self.order_write(WriteStage::Allocation)?;
         allocator.write_dirty(self)?;
 
         let diff = *allocator.get_diff_usage();
         *allocator.get_diff_usage() = 0;
 
-        let mut superblock = self.get_superblock().clone();
-        superblock.unallocated_inodes = ((superblock.unallocated_inodes as i64) - diff) as u32;
-        self.set_superblock(superblock)?;
-
         let mut descriptor = self.get_block_group_descriptor(group).unwrap();
         descriptor.free_inodes_count = ((descriptor.free_inodes_count as i64) - diff) as u16;
         self.set_block_group_descriptor(group, descriptor)?;
 
+        // The counters summarize the bitmap, they go last
+        let mut superblock = self.get_superblock().clone();
+        superblock.unallocated_inodes = ((superblock.unallocated_inodes as i64) - diff) as u32;
+        self.set_superblock(superblock)?;
+
         Ok(())
     }
 
@@ -871,18 +1424,158 @@ impl Ext2Volume {
     #[inline(always)]
     fn init_root_inode_cache(&mut self) -> Result<(), VfsError> {
         self.root_dir_fs_data = Some(Arc::new(Ext2FsSpecificFileData {
-            value: Either::B(Directory::new(
-                self,
-                self.get_inode(2, None)?,
-                OPEN_MODE_READ,
-            )?),
+            value: Either::B(Directory::new(self.get_inode(2, None)?)),
         }));
         Ok(())
     }
+
+    /// Marks the volume as in use, running a recovery pass first if it wasn't cleanly unmounted
+    fn begin_mount_state(&mut self) -> Result<(), VfsError> {
+        let mut superblock = self.superblock.clone();
+        let state = superblock.fs_state;
+
+        if state.has(FsStateFlag::Error) {
+            log_warn!(target: "ext2", "volume has errors, fsck recommended");
+        }
+        if !state.has(FsStateFlag::Clean) {
+            log_warn!(
+                target: "ext2",
+                "volume wasn't cleanly unmounted (state = {:?})",
+                state
+            );
+            if !self.read_only {
+                self.recover()?;
+                superblock = self.superblock.clone();
+            }
+        }
+
+        let mounts = superblock.mount_count_since_fsck;
+        // -1 (or 0) means no limit
+        let max_mounts = superblock.max_mount_count_before_fsck as i16;
+        if max_mounts > 0 && mounts >= max_mounts as u16 {
+            log_warn!(
+                target: "ext2",
+                "volume was mounted {} times without being checked (max {}), fsck recommended",
+                mounts, max_mounts
+            );
+        }
+
+        if self.read_only {
+            return Ok(());
+        }
+
+        let mut state = superblock.fs_state;
+        state.unset(FsStateFlag::Clean);
+        superblock.fs_state = state;
+        superblock.mount_count_since_fsck = mounts.wrapping_add(1);
+        superblock.last_mount_time = get_unix_timestamp() as u32;
+        self.set_superblock(superblock)?;
+        self.flush_device()
+    }
+
+    /// Writes the clean state back, everything must already be flushed
+    fn end_mount_state(&mut self) -> Result<(), VfsError> {
+        if self.read_only {
+            return Ok(());
+        }
+        let mut superblock = self.superblock.clone();
+        let mut state = superblock.fs_state;
+        state.set(FsStateFlag::Clean);
+        superblock.fs_state = state;
+        superblock.last_write_time = get_unix_timestamp() as u32;
+        self.set_superblock(superblock)?;
+        self.flush_device()
+    }
+
+    fn recover(&mut self) -> Result<(), VfsError> {
+        let mut free_blocks = 0u32;
+        let mut free_inodes = 0u32;
+
+        for group in 0..self.block_group_count {
+            let Some(mut descriptor) = self.get_block_group_descriptor(group) else {
+                continue;
+            };
+            if let Some(allocator) = self.get_block_allocator_for_group(group)? {
+                descriptor.free_blocks_count = allocator.count_free() as u16;
+            }
+            if let Some(allocator) = self.get_inode_allocator_for_group(group)? {
+                descriptor.free_inodes_count = allocator.count_free() as u16;
+            }
+            free_blocks += descriptor.free_blocks_count as u32;
+            free_inodes += descriptor.free_inodes_count as u32;
+            self.set_block_group_descriptor(group, descriptor)?;
+        }
+
+        let mut superblock = self.superblock.clone();
+        let (old_blocks, old_inodes) =
+            (superblock.unallocated_blocks, superblock.unallocated_inodes);
+        if old_blocks != free_blocks || old_inodes != free_inodes {
+            log_info!(
+                target: "ext2",
+                "recovered free counts: blocks {} -> {}, inodes {} -> {}",
+                old_blocks, free_blocks, old_inodes, free_inodes
+            );
+        }
+        superblock.unallocated_blocks = free_blocks;
+        superblock.unallocated_inodes = free_inodes;
+        self.set_superblock(superblock)?;
+
+        let root = self.get_inode(2, Some(2))?;
+        self.recover_reachable_inodes(root, 0)
+    }
+
+    /// Inodes that are still referenced by a directory can't be deleted, clear their deletion time
+    fn recover_reachable_inodes(&mut self, dir_inode: Inode, depth: usize) -> Result<(), VfsError> {
+        let parent = dir_inode.inode_i;
+
+        for entry in self.directory_entries(parent)?.iter() {
+            if entry.has_name(b".") || entry.has_name(b"..") {
+                continue;
+            }
+            let Ok(mut inode) = self.get_inode(entry.inode(), Some(parent)) else {
+                continue;
+            };
+            if inode.dtime != 0 {
+                log_debug!(
+                    target: "ext2",
+                    "clearing deletion time of reachable inode {}",
+                    inode.inode_i
+                );
+                inode.dtime = 0;
+                self.update_inode(&inode)?;
+            }
+            if inode.inode_type == InodeType::Directory && depth < RECOVERY_MAX_DEPTH {
+                self.recover_reachable_inodes(inode, depth + 1)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Whether `group` holds a copy of the superblock and descriptor table when the descriptor tables
+/// are sparse: 0, 1 and the powers of 3, 5 and 7
+pub fn is_sparse_backup_group(group: u32) -> bool {
+    let is_power_of = |base: u32| {
+        let mut rest = group;
+        while rest.is_multiple_of(base) {
+            rest /= base;
+        }
+        rest == 1
+    };
+    group <= 1 || [3, 5, 7].into_iter().any(is_power_of)
+}
+
+/// The groups below `group_count` holding a copy of the superblock and descriptor table when the
+/// descriptor tables are sparse, in increasing order
+pub fn sparse_backup_groups(group_count: u32) -> impl Iterator<Item = u32> {
+    (0..group_count).filter(|group| is_sparse_backup_group(*group))
 }
 
 impl BlockDevice for Ext2Volume {
     fn flush(&mut self) -> Result<(), VfsError> {
+        self.check_stale_device();
+        self.write_dirty_inodes()?;
+
         let groups = self
             .group_block_bitmap_caches
             .iter()
@@ -907,7 +1600,7 @@ impl BlockDevice for Ext2Volume {
             }
         }
 
-        self.device.flush()
+        self.flush_device()
     }
 
     fn get_generation(&self) -> u64 {
@@ -931,20 +1624,23 @@ impl BlockDevice for Ext2Volume {
         }
         let lba32 = lba as u32;
 
-        let mut wguard = self.block_cache.write();
+        let mut wguard = self.lock_block_cache_shard(lba32);
         if let Some(cached) = wguard.get(&lba32) {
             buf.copy_from_slice(cached);
+            count_stat(&BLOCK_CACHE_STATS.hits);
             return Ok(self.block_size as u64);
         }
-
-        self.device
-            .seek(SeekPosition::FromStart(self.block_size as u64 * lba))?;
-
-        let mut slice = alloc_boxed_slice::<u8>(self.block_size as usize);
-        let read = self.device.read(&mut slice)?;
+        count_stat(&BLOCK_CACHE_STATS.misses);
+        // Emptying the cache to make room takes this shard again
+        drop(wguard);
+
+        let mut slice = self.alloc_block_buffer()?;
+        let read = self
+            .device
+            .read_at(self.block_size as u64 * lba, &mut slice)?;
         buf[0..read as usize].copy_from_slice(&slice[0..read as usize]);
 
-        wguard.push(lba32, slice);
+        self.lock_block_cache_shard(lba32).push(lba32, slice);
 
         Ok(read)
     }
@@ -953,16 +1649,21 @@ impl BlockDevice for Ext2Volume {
         if buf.len() < self.block_size as usize {
             return Err(VfsError::BadBufferSize);
         }
+        self.check_stale_device();
         if self.read_only {
             return Err(VfsError::ActionNotAllowed);
         }
-        let mut wguard = self.block_cache.write();
-
-        self.device
-            .seek(SeekPosition::FromStart(self.block_size as u64 * lba))?;
-        let written = self.device.write(&buf[0..self.block_size as usize])?;
+        self.write_order.record_write();
+        let written = self
+            .device
+            .write_at(
+                self.block_size as u64 * lba,
+                &buf[0..self.block_size as usize],
+            )
+            .inspect_err(|_| self.check_stale_device())?;
 
         let lba32 = lba as u32;
+        let mut wguard = self.lock_block_cache_shard(lba32);
 
         if let Some(cached) = wguard.get_mut(&lba32) {
             cached.copy_from_slice(&buf[0..written as usize]);
@@ -1000,7 +1701,64 @@ impl FileSystem for Ext2Volume {
     }
 
     fn host_block_device(&mut self) -> Option<Arcrwb<dyn BlockDevice>> {
-        None
+        self.device.block_device()
+    }
+
+    /// Going read-only writes everything back and marks the volume clean. Going back to
+    /// read-write needs a writable device and no read-only feature this driver doesn't support
+    fn set_read_only(&mut self, read_only: bool) -> Result<(), VfsError> {
+        if read_only == self.read_only {
+            return Ok(());
+        }
+        let mounted = self.mount_point.is_some();
+        if read_only {
+            if mounted {
+                self.flush()?;
+                self.end_mount_state()?;
+            }
+            self.read_only = true;
+            return Ok(());
+        }
+
+        let ro_features = self.superblock.readonly_or_support_features;
+        if !self.device.is_writable()
+            || (ro_features & Self::supported_ro_features()) != ro_features
+        {
+            return Err(VfsError::ReadOnly);
+        }
+        self.read_only = false;
+        if mounted {
+            self.begin_mount_state()?;
+        }
+        Ok(())
+    }
+
+    /// The superblock counters only catch up with the cached bitmaps when those are evicted,
+    /// their pending allocations are taken off here
+    fn statfs(&mut self) -> Result<FsStat, VfsError> {
+        let pending_blocks: i64 = self
+            .group_block_bitmap_caches
+            .iter()
+            .map(|(_, allocator)| allocator.diff_usage())
+            .sum();
+        let pending_inodes: i64 = self
+            .group_inode_bitmap_caches
+            .iter()
+            .map(|(_, allocator)| allocator.diff_usage())
+            .sum();
+        let superblock = self.get_superblock();
+        let free_blocks = (superblock.unallocated_blocks as i64 - pending_blocks).max(0) as u64;
+        let free_inodes = (superblock.unallocated_inodes as i64 - pending_inodes).max(0) as u64;
+        Ok(FsStat {
+            fs_type: SUPERBLOCK_SIGNATURE as u64,
+            block_size: self.block_size as u64,
+            total_blocks: superblock.blocks_count as u64,
+            free_blocks,
+            available_blocks: free_blocks.saturating_sub(superblock.su_reserved as u64),
+            total_inodes: superblock.inodes_count as u64,
+            free_inodes,
+            max_name_length: 255,
+        })
     }
 
     fn get_root(&mut self) -> Result<VfsFile, VfsError> {
@@ -1024,7 +1782,7 @@ impl FileSystem for Ext2Volume {
         ))
     }
 
-    fn get_child(&mut self, file: &VfsFile, child: &[char]) -> Result<VfsFile, VfsError> {
+    fn get_child(&mut self, file: &VfsFile, child: &VfsName) -> Result<VfsFile, VfsError> {
         if file.fs() != self.os_id() {
             return Err(VfsError::FileSystemMismatch);
         }
@@ -1034,21 +1792,12 @@ impl FileSystem for Ext2Volume {
             .downcast_ref::<Ext2FsSpecificFileData>()
             .ok_or(VfsError::FileSystemMismatch)?;
 
-        match &data.value {
-            Either::A(_) => Err(VfsError::NotDirectory),
-            Either::B(dir) => dir
-                .entries
-                .iter()
-                .find(|e| e.has_name(child))
-                .map(|e| {
-                    self.get_file_for_inode(
-                        e.inode(),
-                        Some(dir.inode.inode_i),
-                        [file.name(), e.name()].concat(),
-                    )
-                })
-                .ok_or(VfsError::PathNotFound)?,
-        }
+        let dir = data.value.right().ok_or(VfsError::NotDirectory)?;
+        let inode_i = self
+            .directory_index(dir.inode.inode_i)?
+            .lookup(child.as_bytes())
+            .ok_or(VfsError::PathNotFound)?;
+        self.get_file_for_inode(inode_i, Some(dir.inode.inode_i), child.to_chars())
     }
 
     fn list_children(&mut self, file: &VfsFile) -> Result<Vec<VfsFile>, VfsError> {
@@ -1061,23 +1810,19 @@ impl FileSystem for Ext2Volume {
             .downcast_ref::<Ext2FsSpecificFileData>()
             .ok_or(VfsError::FileSystemMismatch)?;
 
-        match &data.value {
-            Either::A(_) => Err(VfsError::NotDirectory),
-            Either::B(dir) => {
-                let mut files = Vec::new();
-                for e in dir.entries.iter() {
-                    if e.has_name(&['.']) || e.has_name(&['.', '.']) {
-                        continue;
-                    }
-                    files.push(self.get_file_for_inode(
-                        e.inode(),
-                        Some(dir.inode.inode_i),
-                        e.name().to_vec(),
-                    )?);
-                }
-                Ok(files)
+        let dir = data.value.right().ok_or(VfsError::NotDirectory)?;
+        let mut files = Vec::new();
+        for e in self.directory_entries(dir.inode.inode_i)?.iter() {
+            if e.has_name(b".") || e.has_name(b"..") {
+                continue;
             }
+            files.push(self.get_file_for_inode(
+                e.inode(),
+                Some(dir.inode.inode_i),
+                VfsPath::from_bytes(e.name().to_vec()).to_chars(),
+            )?);
         }
+        Ok(files)
     }
 
     default_get_file_implementation!();
@@ -1092,41 +1837,19 @@ impl FileSystem for Ext2Volume {
             .downcast_ref::<Ext2FsSpecificFileData>()
             .ok_or(VfsError::FileSystemMismatch)?;
 
-        match &data.value {
-            Either::A(inode) => Ok(FileStat {
-                size: inode.get_size(self),
-                permissions: inode.permissions.get() as u64,
-                flags: 0,
-                created_at: inode.ctime as u64,
-                modified_at: inode.atime as u64,
-                is_directory: false,
-                is_symlink: false,
-                is_file: true,
-                owner_id: inode.uid as u64,
-                group_id: inode.gid as u64,
-            }),
-            Either::B(dir) => {
-                let inode = &dir.inode;
-                Ok(FileStat {
-                    size: 0,
-                    permissions: inode.permissions.get() as u64,
-                    flags: 0,
-                    created_at: inode.ctime as u64,
-                    modified_at: inode.atime as u64,
-                    is_directory: true,
-                    is_symlink: false,
-                    is_file: false,
-                    owner_id: inode.uid as u64,
-                    group_id: inode.gid as u64,
-                })
-            }
-        }
+        // The inode was read when `file` was looked up, it may have been written since
+        let inode = match &data.value {
+            Either::A(inode) => inode,
+            Either::B(dir) => &dir.inode,
+        };
+        let inode = self.get_inode(inode.inode_i, inode.parent_inode)?;
+        Ok(inode.stat(inode.get_size(self)))
     }
 
     fn create_child(
         &mut self,
         directory: &VfsFile,
-        name: &[char],
+        name: &VfsName,
         kind: VfsFileKind,
     ) -> Result<VfsFile, VfsError> {
         if directory.fs() != self.os_id() {
@@ -1166,7 +1889,7 @@ impl FileSystem for Ext2Volume {
                     self.init_root_inode_cache()?;
                 }
 
-                self.get_file_for_inode(inode, Some(parent_inode), name.to_vec())
+                self.get_file_for_inode(inode, Some(parent_inode), name.to_chars())
             }
             VfsFileKind::Directory => {
                 let inode = self.allocate_inode(
@@ -1192,7 +1915,38 @@ impl FileSystem for Ext2Volume {
                     self.init_root_inode_cache()?;
                 }
 
-                self.get_file_for_inode(inode, Some(parent_inode), name.to_vec())
+                self.get_file_for_inode(inode, Some(parent_inode), name.to_chars())
+            }
+            VfsFileKind::DeviceNode { block, number } => {
+                let (inode_type, entry_type) = if block {
+                    (InodeType::BlockDevice, DirectoryEntryType::BlockDevice)
+                } else {
+                    (
+                        InodeType::CharacterDevice,
+                        DirectoryEntryType::CharacterDevice,
+                    )
+                };
+                let inode_i = self.allocate_inode(
+                    0,
+                    0,
+                    inode_type,
+                    *InodePermissions::empty()
+                        .set(InodePermission::OwnerRead)
+                        .set(InodePermission::OwnerWrite),
+                    InodeFlags::empty(),
+                    None,
+                )?;
+                let mut inode = self.get_inode(inode_i, None)?;
+                inode.set_device_number(number);
+                self.update_inode(&inode)?;
+
+                self.add_inode_to_directory(parent_inode, inode_i, name, entry_type)?;
+
+                if directory.name() == ['/'] {
+                    self.init_root_inode_cache()?;
+                }
+
+                self.get_file_for_inode(inode_i, Some(parent_inode), name.to_chars())
             }
             _ => Err(VfsError::ActionNotAllowed),
         }
@@ -1211,17 +1965,22 @@ impl FileSystem for Ext2Volume {
 
         match &data.value {
             Either::A(inode) => {
-                self.delete_inode(inode)?;
+                self.delete_inode(inode, VfsPath::from(file.name()).as_bytes())?;
 
                 if inode.parent_inode == Some(2) {
                     self.init_root_inode_cache()?;
                 }
             }
             Either::B(directory) => {
-                if directory.entries.len() > 2 {
+                if self
+                    .directory_index(directory.inode.inode_i)?
+                    .entries()
+                    .len()
+                    > 2
+                {
                     return Err(VfsError::DirectoryNotEmpty);
                 }
-                self.delete_inode(&directory.inode)?;
+                self.delete_inode(&directory.inode, VfsPath::from(file.name()).as_bytes())?;
 
                 if directory.inode.parent_inode == Some(2) {
                     self.init_root_inode_cache()?;
@@ -1232,6 +1991,124 @@ impl FileSystem for Ext2Volume {
         Ok(())
     }
 
+    fn read_link(&mut self, file: &VfsFile) -> Result<VfsPath, VfsError> {
+        if file.fs() != self.os_id() {
+            return Err(VfsError::FileSystemMismatch);
+        }
+
+        let data = file.get_fs_specific_data();
+        let data: &Ext2FsSpecificFileData = (*data)
+            .as_any()
+            .downcast_ref::<Ext2FsSpecificFileData>()
+            .ok_or(VfsError::FileSystemMismatch)?;
+        let Some(inode) = data.value.left() else {
+            return Err(VfsError::InvalidArgument);
+        };
+        if inode.inode_type != InodeType::Symlink {
+            return Err(VfsError::InvalidArgument);
+        }
+
+        let inode = self.get_inode(inode.inode_i, inode.parent_inode)?;
+        let size = inode.get_size(self);
+        if size > SYMLINK_MAX_TARGET {
+            return Err(VfsError::NameTooLong);
+        }
+        let target = if inode.is_fast_symlink() {
+            let data = inode.inline_data();
+            data.get(..size as usize)
+                .ok_or(VfsError::InvalidDataStructure)?
+                .to_vec()
+        } else {
+            let mut target = alloc::vec![0; size as usize];
+            let mut handle = self.get_file_handle(inode, OPEN_MODE_READ)?;
+            if handle.read(self, &mut target)? != size {
+                return Err(VfsError::ShortRead);
+            }
+            target
+        };
+        Ok(VfsPath::from_bytes(target))
+    }
+
+    fn link(
+        &mut self,
+        existing: &VfsFile,
+        new_parent: &VfsFile,
+        new_name: &VfsName,
+    ) -> Result<VfsFile, VfsError> {
+        if existing.fs() != self.os_id() || new_parent.fs() != self.os_id() {
+            return Err(VfsError::FileSystemMismatch);
+        }
+        if !new_parent.is_directory() {
+            return Err(VfsError::NotDirectory);
+        }
+        if self.read_only {
+            return Err(VfsError::ReadOnly);
+        }
+
+        let data = existing.get_fs_specific_data();
+        let data: &Ext2FsSpecificFileData = (*data)
+            .as_any()
+            .downcast_ref::<Ext2FsSpecificFileData>()
+            .ok_or(VfsError::FileSystemMismatch)?;
+        let Some(inode) = data.value.left() else {
+            return Err(VfsError::ActionNotAllowed);
+        };
+        if inode.inode_type != InodeType::File {
+            return Err(VfsError::ActionNotAllowed);
+        }
+
+        let parent_data = new_parent.get_fs_specific_data();
+        let parent_data: &Ext2FsSpecificFileData = (*parent_data)
+            .as_any()
+            .downcast_ref::<Ext2FsSpecificFileData>()
+            .ok_or(VfsError::FileSystemMismatch)?;
+        let Some(directory) = parent_data.value.right() else {
+            return Err(VfsError::NotDirectory);
+        };
+        let parent_inode = directory.inode.inode_i;
+
+        if self
+            .directory_index(parent_inode)?
+            .lookup(new_name.as_bytes())
+            .is_some()
+        {
+            return Err(VfsError::FileAlreadyExists);
+        }
+
+        if self.get_inode(inode.inode_i, None)?.links_count >= LINK_MAX {
+            return Err(VfsError::MaximumSizeReached);
+        }
+        self.add_inode_to_directory(
+            parent_inode,
+            inode.inode_i,
+            new_name,
+            DirectoryEntryType::File,
+        )?;
+        let mut linked = self.get_inode(inode.inode_i, Some(parent_inode))?;
+        linked.ctime = get_unix_timestamp() as u32;
+        self.update_inode(&linked)?;
+        self.sync_open_links_count(&linked);
+
+        if parent_inode == 2 {
+            self.init_root_inode_cache()?;
+        }
+
+        self.get_file_for_inode(linked.inode_i, Some(parent_inode), new_name.to_chars())
+    }
+
+    fn file_identity(&mut self, file: &VfsFile) -> u64 {
+        let data = file.get_fs_specific_data();
+        match (*data).as_any().downcast_ref::<Ext2FsSpecificFileData>() {
+            Some(Ext2FsSpecificFileData {
+                value: Either::A(inode),
+            }) => inode.inode_i as u64,
+            Some(Ext2FsSpecificFileData {
+                value: Either::B(dir),
+            }) => dir.inode.inode_i as u64,
+            None => Arc::as_ptr(&data) as *const () as u64,
+        }
+    }
+
     fn on_mount(
         &mut self,
         mount_point: &VfsFile,
@@ -1241,8 +2118,10 @@ impl FileSystem for Ext2Volume {
         self.mount_point = Some(mount_point.clone());
         self.root_fs = Some(root_fs);
         self.os_id = os_id;
+        self.handles.set_owner(os_id);
 
         self.init_root_inode_cache()?;
+        self.begin_mount_state()?;
 
         self.get_root()
     }
@@ -1255,7 +2134,11 @@ impl FileSystem for Ext2Volume {
             .collect::<Vec<u64>>()
             .into_iter()
         {
-            self.fflush(handle)?;
+            // Devices flush on close if they need to, directories have nothing to flush
+            if self.device_node_handle(handle).is_none() && self.directory_handle(handle).is_none()
+            {
+                self.fflush(handle)?;
+            }
             self.fclose(handle)?;
         }
         Ok(true)
@@ -1263,10 +2146,11 @@ impl FileSystem for Ext2Volume {
 
     fn on_unmount(&mut self) -> Result<(), VfsError> {
         self.flush()?;
+        self.end_mount_state()?;
         self.mount_point = None;
         self.root_fs = None;
         self.os_id = 0;
-        unsafe { self.device._close()? };
+        self.device.close()?;
         Ok(())
     }
 
@@ -1285,19 +2169,50 @@ impl FileSystem for Ext2Volume {
             .downcast_ref::<Ext2FsSpecificFileData>()
             .ok_or(VfsError::FileSystemMismatch)?;
 
-        match &data.value {
-            Either::A(inode) => match file.kind() {
-                VfsFileKind::File => {
-                    let handle = FileHandle::new(self, inode.clone(), mode)?;
-                    Ok(self.handles.alloc_file_handle::<FileHandle>(handle))
+        let inode = match &data.value {
+            Either::A(inode) => inode,
+            Either::B(directory) => &directory.inode,
+        };
+        match file.kind() {
+            VfsFileKind::Directory => {
+                if mode & (OPEN_MODE_WRITE | OPEN_MODE_APPEND | OPEN_MODE_CREATE) != 0 {
+                    return Err(VfsError::NotFile);
                 }
-                _ => Err(VfsError::NotFile),
-            },
-            Either::B(_) => Err(VfsError::NotFile),
+                let inode = self.get_inode(inode.inode_i, inode.parent_inode)?;
+                Ok(self
+                    .handles
+                    .alloc_file_handle(DirectoryHandle { inode, position: 0 }))
+            }
+            VfsFileKind::File => {
+                self.check_open_mode(mode)?;
+                // The inode of `file` was read when it was looked up, links or attributes
+                // may have changed since
+                let inode = self.get_inode(inode.inode_i, inode.parent_inode)?;
+                let handle = FileHandle::new(self, inode, mode)?;
+                Ok(self.handles.alloc_file_handle::<FileHandle>(handle))
+            }
+            VfsFileKind::DeviceNode { block, number } => {
+                let (devfs, handle) = open_device_node(*block, *number, mode)?;
+                Ok(self.handles.alloc_file_handle(DeviceNodeHandle {
+                    inode_i: inode.inode_i,
+                    devfs,
+                    handle,
+                }))
+            }
+            _ => Err(VfsError::NotFile),
         }
     }
 
     fn fclose(&mut self, handle: u64) -> Result<(), VfsError> {
+        if let Some(device) = self.device_node_handle(handle) {
+            device.devfs.write().fclose(device.handle)?;
+            self.handles.dealloc_file_handle::<DeviceNodeHandle>(handle);
+            return Ok(());
+        }
+        if self.directory_handle(handle).is_some() {
+            self.handles.dealloc_file_handle::<DirectoryHandle>(handle);
+            return Ok(());
+        }
         let data = unsafe {
             &mut *self
                 .handles
@@ -1311,6 +2226,14 @@ impl FileSystem for Ext2Volume {
     }
 
     fn fseek(&mut self, handle: u64, position: SeekPosition) -> Result<u64, VfsError> {
+        if let Some(device) = self.device_node_handle(handle) {
+            return device.devfs.write().fseek(device.handle, position);
+        }
+        if let Some(data) = unsafe { self.handles.get_handle_data::<DirectoryHandle>(handle) } {
+            let data = unsafe { &mut *data };
+            data.position = seek_directory(position, data.position)?;
+            return Ok(data.position);
+        }
         let data = unsafe {
             &mut *self
                 .handles
@@ -1322,6 +2245,12 @@ impl FileSystem for Ext2Volume {
     }
 
     fn fread(&mut self, handle: u64, buf: &mut [u8]) -> Result<u64, VfsError> {
+        if let Some(device) = self.device_node_handle(handle) {
+            return device.devfs.write().fread(device.handle, buf);
+        }
+        if let Some(directory) = self.directory_handle(handle) {
+            return self.read_directory(handle, directory, buf);
+        }
         let data = unsafe {
             &mut *self
                 .handles
@@ -1331,10 +2260,15 @@ impl FileSystem for Ext2Volume {
         if data.get_open_mode() & OPEN_MODE_READ == 0 {
             return Err(VfsError::ActionNotAllowed);
         }
-        data.read(self, buf)
+        let read = data.read(self, buf)?;
+        self.touch_atime(data.get_inode_mut());
+        Ok(read)
     }
 
     fn fwrite(&mut self, handle: u64, buf: &[u8]) -> Result<u64, VfsError> {
+        if let Some(device) = self.device_node_handle(handle) {
+            return device.devfs.write().fwrite(device.handle, buf);
+        }
         let data = unsafe {
             &mut *self
                 .handles
@@ -1344,6 +2278,9 @@ impl FileSystem for Ext2Volume {
         if data.get_open_mode() & OPEN_MODE_WRITE == 0 {
             return Err(VfsError::ActionNotAllowed);
         }
+        if data.get_open_mode() & OPEN_MODE_APPEND != 0 {
+            data.seek(self, SeekPosition::FromEnd(0))?;
+        }
         let checked_buf = if data.get_open_mode() & OPEN_MODE_NO_RESIZE == OPEN_MODE_NO_RESIZE {
             let pos = data.get_position();
             let max_pos = data.get_size();
@@ -1363,7 +2300,84 @@ impl FileSystem for Ext2Volume {
         data.write(self, checked_buf)
     }
 
+    fn fseekable(&mut self, handle: u64) -> bool {
+        if let Some(device) = self.device_node_handle(handle) {
+            return device.devfs.write().fseekable(device.handle);
+        }
+        self.directory_handle(handle).is_some()
+            || unsafe { self.handles.get_handle_data::<FileHandle>(handle).is_some() }
+    }
+
+    fn fread_at(&mut self, handle: u64, offset: u64, buf: &mut [u8]) -> Result<u64, VfsError> {
+        if let Some(device) = self.device_node_handle(handle) {
+            return device.devfs.write().fread_at(device.handle, offset, buf);
+        }
+        let mut block = self.alloc_block_buffer()?;
+        self.read_handle_at(handle, offset, buf, &mut block)
+    }
+
+    fn fwrite_at(&mut self, handle: u64, offset: u64, buf: &[u8]) -> Result<u64, VfsError> {
+        if let Some(device) = self.device_node_handle(handle) {
+            return device.devfs.write().fwrite_at(device.handle, offset, buf);
+        }
+        let mut block = self.alloc_block_buffer()?;
+        self.write_handle_at(handle, offset, buf, &mut block)
+    }
+
+    /// Copies a block at a time through one scratch block, cut at the block boundaries of `dst`
+    /// so only its head and tail blocks are read back before being written
+    fn fcopy_range(
+        &mut self,
+        src: u64,
+        src_offset: u64,
+        dst: u64,
+        dst_offset: u64,
+        len: u64,
+    ) -> Result<u64, VfsError> {
+        if self.device_node_handle(src).is_some() || self.device_node_handle(dst).is_some() {
+            return copy_through_buffer(
+                self,
+                len,
+                |fs, done, buf| fs.fread_at(src, src_offset + done, buf),
+                |fs, done, buf| fs.fwrite_at(dst, dst_offset + done, buf),
+            );
+        }
+        let bs = self.block_size as u64;
+        let mut scratch = self.alloc_block_buffer()?;
+        let mut block = self.alloc_block_buffer()?;
+        let mut copied = 0;
+        while copied < len {
+            let position = dst_offset + copied;
+            let chunk = (bs - position % bs).min(len - copied) as usize;
+            let read = match self.read_handle_at(
+                src,
+                src_offset + copied,
+                &mut scratch[..chunk],
+                &mut block,
+            ) {
+                Ok(0) => break,
+                Ok(read) => read,
+                Err(e) if copied == 0 => return Err(e),
+                Err(_) => break,
+            };
+            let written =
+                match self.write_handle_at(dst, position, &scratch[..read as usize], &mut block) {
+                    Ok(written) => written,
+                    Err(e) if copied == 0 => return Err(e),
+                    Err(_) => break,
+                };
+            copied += written;
+            if written < read {
+                break;
+            }
+        }
+        Ok(copied)
+    }
+
     fn ftruncate(&mut self, handle: u64) -> Result<u64, VfsError> {
+        if let Some(device) = self.device_node_handle(handle) {
+            return device.devfs.write().ftruncate(device.handle);
+        }
         let data = unsafe {
             &mut *self
                 .handles
@@ -1380,7 +2394,41 @@ impl FileSystem for Ext2Volume {
         Ok(data.get_size())
     }
 
+    fn fchmod(&mut self, handle: u64, mode: u64) -> Result<(), VfsError> {
+        self.update_handle_inode(handle, |inode| {
+            inode.permissions = InodePermissions::from((mode & 0o7777) as u16);
+        })
+    }
+
+    fn fchown(&mut self, handle: u64, uid: Option<u32>, gid: Option<u32>) -> Result<(), VfsError> {
+        self.update_handle_inode(handle, |inode| {
+            inode.set_owner(
+                uid.unwrap_or(inode.full_uid()),
+                gid.unwrap_or(inode.full_gid()),
+            );
+        })
+    }
+
+    fn futimens(
+        &mut self,
+        handle: u64,
+        atime: Option<u64>,
+        mtime: Option<u64>,
+    ) -> Result<(), VfsError> {
+        self.update_handle_inode(handle, |inode| {
+            if let Some(atime) = atime {
+                inode.atime = atime as u32;
+            }
+            if let Some(mtime) = mtime {
+                inode.mtime = mtime as u32;
+            }
+        })
+    }
+
     fn fflush(&mut self, handle: u64) -> Result<(), VfsError> {
+        if let Some(device) = self.device_node_handle(handle) {
+            return device.devfs.write().fflush(device.handle);
+        }
         let data = unsafe {
             &mut *self
                 .handles
@@ -1390,33 +2438,65 @@ impl FileSystem for Ext2Volume {
         if data.get_open_mode() & OPEN_MODE_WRITE == 0 {
             return Err(VfsError::ActionNotAllowed);
         }
-        data.flush(self)
+        data.flush(self)?;
+        self.write_inode(data.get_inode().inode_i)
     }
 
-    fn fsync(&mut self, _handle: u64) -> Result<(), VfsError> {
-        Err(VfsError::ActionNotAllowed)
+    /// The data of the file reaches the media, with its inode and the bitmaps allocating it
+    fn fsync(&mut self, handle: u64) -> Result<(), VfsError> {
+        if let Some(device) = self.device_node_handle(handle) {
+            return device.devfs.write().fsync(device.handle);
+        }
+        let data = unsafe {
+            &mut *self
+                .handles
+                .get_handle_data::<FileHandle>(handle)
+                .ok_or(VfsError::BadHandle)?
+        };
+        if self.read_only {
+            return Ok(());
+        }
+        data.flush(self)?;
+        self.write_dirty_bitmaps()?;
+        self.write_inode(data.get_inode().inode_i)?;
+        self.flush_device()
+    }
+
+    fn writeback(&mut self) -> Result<(), VfsError> {
+        self.write_dirty_inodes()
+    }
+
+    fn shrink_caches(&self) {
+        self.shrink_block_cache();
+    }
+
+    fn fpoll(&mut self, handle: u64, events: PollEvents) -> Result<PollEvents, VfsError> {
+        match self.device_node_handle(handle) {
+            Some(device) => device.devfs.write().fpoll(device.handle, events),
+            None => Ok(events & POLL_ALWAYS_READY),
+        }
+    }
+
+    fn fwait_queue(&mut self, handle: u64) -> Result<Option<Arc<WaitQueue>>, VfsError> {
+        match self.device_node_handle(handle) {
+            Some(device) => device.devfs.write().fwait_queue(device.handle),
+            None => Ok(None),
+        }
     }
 
     fn fstat(&self, handle: u64) -> Result<FileStat, VfsError> {
+        if let Some(device) = self.device_node_handle(handle) {
+            return device.devfs.read().fstat(device.handle);
+        }
+        if let Some(directory) = self.directory_handle(handle) {
+            return Ok(directory.inode.stat(directory.inode.get_size(self)));
+        }
         let data = unsafe {
             &*self
                 .handles
                 .get_handle_data::<FileHandle>(handle)
                 .ok_or(VfsError::BadHandle)?
         };
-        let inode = data.get_inode();
-        Ok(FileStat {
-            size: data.get_size(),
-            // ext2 permissions are unix permissions, Campix kernel's permissions uses the same lower 16 bits as unix permissions
-            permissions: inode.permissions.get() as u64,
-            flags: 0,
-            created_at: inode.ctime as u64,
-            modified_at: inode.atime as u64,
-            is_directory: false,
-            is_symlink: false,
-            is_file: true,
-            owner_id: inode.uid as u64,
-            group_id: inode.gid as u64,
-        })
+        Ok(data.get_inode().stat(data.get_size()))
     }
 }
@@ -0,0 +1,85 @@
+//! Order in which updates reach the disk
+//!
+//! An update is written in stages: the data blocks, then the bitmaps and group descriptors that
+//! allocate them, the inode and indirection tables that reference them, the directory entry that
+//! references the inode, and last the superblock counters. Whenever a stage is written after an
+//! earlier one, the volume first issues a barrier, so the device can't reorder them. After a crash,
+//! nothing on disk references a block or inode that isn't allocated: at worst some are leaked until
+//! the next fsck.
+//!
+//! Frees aren't ordered: a bitmap can be written before the inode that stopped referencing the
+//! blocks it frees.
+//!
+//! Barriers can be turned off per volume with the `nobarrier` mount option. With `check_order`,
+//! every write that could overtake an earlier stage is logged, and asserted on in debug builds.
+
+use crate::log_error;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum WriteStage {
+    Data,
+    /// Bitmaps and group descriptors
+    Allocation,
+    /// Inodes and indirection tables
+    Inode,
+    DirectoryEntry,
+    Superblock,
+}
+
+#[derive(Debug)]
+pub struct WriteOrder {
+    /// Barriers are issued between stages
+    pub ordered: bool,
+    /// Writes that can overtake an earlier stage are reported
+    pub validate: bool,
+    /// Stage of the writes being issued
+    stage: WriteStage,
+    /// Earliest stage written since the last barrier
+    earliest: Option<WriteStage>,
+    violations: u64,
+}
+
+impl Default for WriteOrder {
+    fn default() -> Self {
+        Self {
+            ordered: true,
+            validate: false,
+            stage: WriteStage::Data,
+            earliest: None,
+            violations: 0,
+        }
+    }
+}
+
+impl WriteOrder {
+    /// Starts writing `stage`, returns whether a barrier is needed first
+    pub fn enter(&mut self, stage: WriteStage) -> bool {
+        self.stage = stage;
+        self.earliest.is_some_and(|earliest| earliest < stage)
+    }
+
+    pub fn barrier(&mut self) {
+        self.earliest = None;
+    }
+
+    /// Called for every write reaching the device
+    pub fn record_write(&mut self) {
+        if let Some(earliest) = self.earliest {
+            if self.validate && earliest < self.stage {
+                self.violations += 1;
+                log_error!(
+                    target: "ext2",
+                    "{:?} write issued without a barrier after a {:?} write",
+                    self.stage, earliest
+                );
+                debug_assert!(false, "ext2 write order violated");
+            }
+        }
+        self.earliest = Some(self.earliest.map_or(self.stage, |e| e.min(self.stage)));
+    }
+
+    /// Writes reported so far, only counted when validating
+    pub fn violations(&self) -> u64 {
+        self.violations
+    }
+}
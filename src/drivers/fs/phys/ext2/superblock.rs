@@ -1,19 +1,19 @@
 use core::fmt::Debug;
 
-use crate::{
-    data::file::File,
-    debuggable_bitset_enum,
-    drivers::vfs::{SeekPosition, VfsError},
-};
+use crate::{debuggable_bitset_enum, drivers::vfs::VfsError};
+
+use super::device::Ext2Device;
 
 pub const SUPERBLOCK_SIGNATURE: u16 = 0xEF53;
 
-#[repr(u16)]
-#[derive(Debug, Clone, Copy)]
-pub enum FsState {
-    Clean = 1,
-    Error = 2,
-}
+debuggable_bitset_enum!(
+    u16,
+    pub enum FsStateFlag {
+        Clean = 1,
+        Error = 2,
+    },
+    FsState
+);
 
 #[repr(u16)]
 #[derive(Debug, Clone, Copy)]
@@ -131,10 +131,9 @@ pub struct Superblock {
 }
 
 impl Superblock {
-    pub fn from_device(device: &File) -> Result<Superblock, VfsError> {
+    pub fn from_device(device: &Ext2Device) -> Result<Superblock, VfsError> {
         let mut data = [0u8; 1024];
-        device.seek(SeekPosition::FromStart(1024))?;
-        device.read(&mut data)?;
+        device.read_at(1024, &mut data)?;
         Ok(unsafe { core::ptr::read_volatile(data.as_ptr() as *const Superblock) })
     }
 
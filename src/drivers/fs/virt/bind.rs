@@ -0,0 +1,210 @@
+use alloc::{string::String, string::ToString, sync::Arc, vec::Vec};
+
+use crate::{
+    data::path::{VfsName, VfsPath},
+    drivers::vfs::{
+        Arcrwb, BlockDevice, FileStat, FileSystem, FsStat, PollEvents, SeekPosition, Vfs, VfsError,
+        VfsFile, VfsFileKind, WeakArcrwb,
+    },
+    process::wait::WaitQueue,
+};
+
+/// A directory of another file system mounted again somewhere else. Files keep the os_id of
+/// the source file system, so only lookups go through here
+#[derive(Debug)]
+pub struct BindFs {
+    source: Arcrwb<dyn FileSystem>,
+    /// Path of the bound directory from the root of `source`
+    prefix: VfsPath,
+    os_id: u64,
+    mnt: Option<VfsFile>,
+    root_fs: Option<WeakArcrwb<Vfs>>,
+}
+
+impl BindFs {
+    pub fn new(source: Arcrwb<dyn FileSystem>, prefix: VfsPath) -> Self {
+        Self {
+            source,
+            prefix,
+            os_id: 0,
+            mnt: None,
+            root_fs: None,
+        }
+    }
+}
+
+impl FileSystem for BindFs {
+    fn os_id(&mut self) -> u64 {
+        self.os_id
+    }
+
+    fn fs_type(&mut self) -> String {
+        "bind".to_string()
+    }
+
+    fn fs_flush(&mut self) -> Result<(), VfsError> {
+        self.source.write().fs_flush()
+    }
+
+    fn host_block_device(&mut self) -> Option<Arcrwb<dyn BlockDevice>> {
+        self.source.write().host_block_device()
+    }
+
+    fn statfs(&mut self) -> Result<FsStat, VfsError> {
+        self.source.write().statfs()
+    }
+
+    fn get_root(&mut self) -> Result<VfsFile, VfsError> {
+        self.source.write().get_file(&self.prefix)
+    }
+
+    fn get_mount_point(&mut self) -> Result<Option<VfsFile>, VfsError> {
+        Ok(self.mnt.clone())
+    }
+
+    fn get_child(&mut self, file: &VfsFile, child: &VfsName) -> Result<VfsFile, VfsError> {
+        self.source.write().get_child(file, child)
+    }
+
+    fn list_children(&mut self, file: &VfsFile) -> Result<Vec<VfsFile>, VfsError> {
+        self.source.write().list_children(file)
+    }
+
+    fn get_file(&mut self, path: &VfsPath) -> Result<VfsFile, VfsError> {
+        let mut full = self.prefix.clone();
+        for name in path.components() {
+            full.push(name);
+        }
+        self.source.write().get_file(&full)
+    }
+
+    fn get_stats(&mut self, file: &VfsFile) -> Result<FileStat, VfsError> {
+        self.source.write().get_stats(file)
+    }
+
+    fn create_child(
+        &mut self,
+        directory: &VfsFile,
+        name: &VfsName,
+        kind: VfsFileKind,
+    ) -> Result<VfsFile, VfsError> {
+        self.source.write().create_child(directory, name, kind)
+    }
+
+    fn delete_file(&mut self, file: &VfsFile) -> Result<(), VfsError> {
+        self.source.write().delete_file(file)
+    }
+
+    fn link(
+        &mut self,
+        existing: &VfsFile,
+        new_parent: &VfsFile,
+        new_name: &VfsName,
+    ) -> Result<VfsFile, VfsError> {
+        self.source.write().link(existing, new_parent, new_name)
+    }
+
+    fn file_identity(&mut self, file: &VfsFile) -> u64 {
+        self.source.write().file_identity(file)
+    }
+
+    fn on_mount(
+        &mut self,
+        mount_point: &VfsFile,
+        os_id: u64,
+        root_fs: WeakArcrwb<Vfs>,
+    ) -> Result<VfsFile, VfsError> {
+        self.root_fs = Some(root_fs);
+        self.mnt = Some(mount_point.clone());
+        self.os_id = os_id;
+        self.get_root()
+    }
+
+    fn on_pre_unmount(&mut self) -> Result<bool, VfsError> {
+        Ok(true)
+    }
+
+    /// The handles belong to the source, which stays mounted
+    fn on_unmount(&mut self) -> Result<(), VfsError> {
+        self.mnt = None;
+        self.os_id = 0;
+        Ok(())
+    }
+
+    fn get_vfs(&mut self) -> Result<WeakArcrwb<Vfs>, VfsError> {
+        self.root_fs.clone().ok_or(VfsError::FileSystemNotMounted)
+    }
+
+    fn fopen(&mut self, file: &VfsFile, mode: u64) -> Result<u64, VfsError> {
+        self.source.write().fopen(file, mode)
+    }
+
+    fn fclose(&mut self, handle: u64) -> Result<(), VfsError> {
+        self.source.write().fclose(handle)
+    }
+
+    fn fseek(&mut self, handle: u64, position: SeekPosition) -> Result<u64, VfsError> {
+        self.source.write().fseek(handle, position)
+    }
+
+    fn fread(&mut self, handle: u64, buf: &mut [u8]) -> Result<u64, VfsError> {
+        self.source.write().fread(handle, buf)
+    }
+
+    fn fwrite(&mut self, handle: u64, buf: &[u8]) -> Result<u64, VfsError> {
+        self.source.write().fwrite(handle, buf)
+    }
+
+    fn fseekable(&mut self, handle: u64) -> bool {
+        self.source.write().fseekable(handle)
+    }
+
+    fn fread_at(&mut self, handle: u64, offset: u64, buf: &mut [u8]) -> Result<u64, VfsError> {
+        self.source.write().fread_at(handle, offset, buf)
+    }
+
+    fn fwrite_at(&mut self, handle: u64, offset: u64, buf: &[u8]) -> Result<u64, VfsError> {
+        self.source.write().fwrite_at(handle, offset, buf)
+    }
+
+    fn fflush(&mut self, handle: u64) -> Result<(), VfsError> {
+        self.source.write().fflush(handle)
+    }
+
+    fn fsync(&mut self, handle: u64) -> Result<(), VfsError> {
+        self.source.write().fsync(handle)
+    }
+
+    fn fstat(&self, handle: u64) -> Result<FileStat, VfsError> {
+        self.source.read().fstat(handle)
+    }
+
+    fn ftruncate(&mut self, handle: u64) -> Result<u64, VfsError> {
+        self.source.write().ftruncate(handle)
+    }
+
+    fn fchmod(&mut self, handle: u64, mode: u64) -> Result<(), VfsError> {
+        self.source.write().fchmod(handle, mode)
+    }
+
+    fn fchown(&mut self, handle: u64, uid: Option<u32>, gid: Option<u32>) -> Result<(), VfsError> {
+        self.source.write().fchown(handle, uid, gid)
+    }
+
+    fn futimens(
+        &mut self,
+        handle: u64,
+        atime: Option<u64>,
+        mtime: Option<u64>,
+    ) -> Result<(), VfsError> {
+        self.source.write().futimens(handle, atime, mtime)
+    }
+
+    fn fpoll(&mut self, handle: u64, events: PollEvents) -> Result<PollEvents, VfsError> {
+        self.source.write().fpoll(handle, events)
+    }
+
+    fn fwait_queue(&mut self, handle: u64) -> Result<Option<Arc<WaitQueue>>, VfsError> {
+        self.source.write().fwait_queue(handle)
+    }
+}
@@ -2,19 +2,31 @@ use core::fmt::Debug;
 
 use alloc::{
     boxed::Box,
-    collections::BTreeMap,
+    collections::{BTreeMap, BTreeSet, VecDeque},
     string::{String, ToString},
     sync::Arc,
     vec::Vec,
 };
-use spin::RwLock;
-
-use crate::drivers::{
-    pci::{self, PciDevice},
-    vfs::{
-        Arcrwb, AsAny, BlockDevice, FileHandleAllocator, FileStat, FileSystem, PathTraverse,
-        SeekPosition, Vfs, VfsError, VfsFile, VfsFileKind, VfsSpecificFileData, WeakArcrwb,
+use spin::{Lazy, Mutex};
+
+use crate::{
+    data::path::{VfsName, VfsPath},
+    drivers::{
+        fs::{
+            dirent::{record_kind, seek_directory, DirectoryRecord, DirectoryRecordWriter},
+            virt::files::dev_pci::insert_pci_files,
+        },
+        pci::{self, PciDevice},
+        vfs::{
+            arcrwb_new_from_box, get_vfs, Arcrwb, AsAny, BlockDevice, CharacterDevice,
+            DeviceNumber, FileHandleAllocator, FileStat, FileSystem, FsSpecificFileData, FsStat,
+            PathTraverse, PollEvents, SeekPosition, Vfs, VfsError, VfsFile, VfsFileKind,
+            VfsSpecificFileData, WeakArcrwb, FLAG_SYSTEM, FLAG_VIRTUAL, OPEN_MODE_APPEND,
+            OPEN_MODE_CREATE, OPEN_MODE_FAIL_IF_EXISTS, OPEN_MODE_WRITE, POLL_ALWAYS_READY,
+        },
     },
+    permissions,
+    process::wait::WaitQueue,
 };
 
 pub const fn fseek_helper(seek: SeekPosition, current_position: u64, len: u64) -> Option<u64> {
@@ -56,12 +68,14 @@ pub const fn fseek_helper(seek: SeekPosition, current_position: u64, len: u64) -
 pub trait DevFsDriver: Send + Sync + Debug + AsAny {
     fn driver_id(&self) -> u64;
     fn handles_device(&self, dev_fs: &mut DevFs, pci_device: &PciDevice) -> bool;
+    /// Returns the paths of the hooks it added or replaced, go through `DevFs::refresh_hooks`
+    /// so the devfs can remove them when the driver is unregistered
     fn refresh_device_hooks(
         &mut self,
         dev_fs: &mut DevFs,
         pci_device: &PciDevice,
         device_id: usize,
-    ) -> Result<(), VfsError>;
+    ) -> Result<Vec<Vec<char>>, VfsError>;
 
     fn fopen(
         &mut self,
@@ -82,6 +96,12 @@ pub trait DevFsDriver: Send + Sync + Debug + AsAny {
         handle: u64,
         position: SeekPosition,
     ) -> Result<u64, VfsError>;
+
+    /// Called periodically so the driver can detect media changes, bump the generation of
+    /// the affected devices and refresh its hooks
+    fn poll_devices(&mut self, _dev_fs: &mut DevFs) -> Result<(), VfsError> {
+        Ok(())
+    }
 }
 
 pub trait VirtualDeviceFile: Debug + Send + Sync + AsAny {
@@ -100,6 +120,24 @@ pub trait VirtualDeviceFile: Debug + Send + Sync + AsAny {
     fn sync(&mut self) -> Result<(), VfsError> {
         Ok(())
     }
+
+    /// Character devices are readable and writable at any time unless they say otherwise
+    fn poll(&self, events: PollEvents) -> PollEvents {
+        events & POLL_ALWAYS_READY
+    }
+
+    fn wait_queue(&self) -> Option<Arc<WaitQueue>> {
+        None
+    }
+
+    /// Whether operations failing with `WouldBlock` sleep on `wait_queue` instead
+    fn blocks(&self) -> bool {
+        false
+    }
+
+    fn ioctl(&mut self, _request: u64, _arg: &mut [u8]) -> Result<u64, VfsError> {
+        Err(VfsError::NotTerminal)
+    }
 }
 
 pub trait VirtualDeviceFileProvider: Debug + Send + Sync + AsAny {
@@ -108,6 +146,104 @@ pub trait VirtualDeviceFileProvider: Debug + Send + Sync + AsAny {
     fn stat(&self) -> Result<FileStat, VfsError>;
 }
 
+/// Publishes a character device as a devfs file. Its handles keep the position of seekable
+/// devices, streams can't seek
+#[derive(Debug)]
+pub struct CharacterDeviceProvider {
+    devfs_os_id: u64,
+    name: Vec<char>,
+    device: Arcrwb<dyn CharacterDevice>,
+    stat: FileStat,
+}
+
+impl CharacterDeviceProvider {
+    pub fn new(
+        devfs_os_id: u64,
+        name: Vec<char>,
+        device: Arcrwb<dyn CharacterDevice>,
+        stat: FileStat,
+    ) -> Self {
+        Self {
+            devfs_os_id,
+            name,
+            device,
+            stat,
+        }
+    }
+}
+
+impl VirtualDeviceFileProvider for CharacterDeviceProvider {
+    fn open(&mut self, mode: u64) -> Result<Arcrwb<dyn VirtualDeviceFile>, VfsError> {
+        if mode & OPEN_MODE_FAIL_IF_EXISTS != 0 {
+            return Err(VfsError::FileAlreadyExists);
+        }
+        Ok(arcrwb_new_from_box(Box::new(CharacterDeviceFile {
+            device: self.device.clone(),
+            position: 0,
+            stat: self.stat.clone(),
+        })))
+    }
+
+    fn vfs_file(&self) -> Result<VfsFile, VfsError> {
+        Ok(VfsFile::new(
+            VfsFileKind::File,
+            self.name.clone(),
+            0,
+            self.devfs_os_id,
+            self.devfs_os_id,
+            Arc::new(VfsSpecificFileData),
+        ))
+    }
+
+    fn stat(&self) -> Result<FileStat, VfsError> {
+        Ok(self.stat.clone())
+    }
+}
+
+#[derive(Debug)]
+pub struct CharacterDeviceFile {
+    device: Arcrwb<dyn CharacterDevice>,
+    position: u64,
+    stat: FileStat,
+}
+
+impl VirtualDeviceFile for CharacterDeviceFile {
+    fn stat(&self) -> Result<FileStat, VfsError> {
+        Ok(self.stat.clone())
+    }
+
+    fn close(&mut self) -> Result<(), VfsError> {
+        Ok(())
+    }
+
+    fn seek(&mut self, position: SeekPosition) -> Result<u64, VfsError> {
+        self.position = self.device.read().seek_position(position, self.position)?;
+        Ok(self.position)
+    }
+
+    fn pos(&self) -> Result<u64, VfsError> {
+        Ok(self.position)
+    }
+
+    fn truncate(&mut self) -> Result<u64, VfsError> {
+        Err(VfsError::ActionNotAllowed)
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> Result<u64, VfsError> {
+        self.device.read().read_at_position(&mut self.position, buf)
+    }
+
+    fn write(&mut self, buf: &[u8]) -> Result<u64, VfsError> {
+        self.device
+            .write()
+            .write_at_position(&mut self.position, buf)
+    }
+
+    fn flush(&mut self) -> Result<(), VfsError> {
+        self.device.write().flush()
+    }
+}
+
 #[derive(Debug)]
 pub enum DevFsHookKind {
     Device,
@@ -118,10 +254,13 @@ pub enum DevFsHookKind {
 #[derive(Debug)]
 pub struct DevFsHook {
     pub driver: Arcrwb<dyn DevFsDriver>,
+    pub driver_id: u64,
     pub file: VfsFile,
     pub kind: DevFsHookKind,
     pub generation: u64,
     pub device_id: u64,
+    /// Driver defined, tells the driver which of its devices the hook opens whatever its name
+    pub device_key: u64,
 }
 
 #[derive(Debug, Clone)]
@@ -130,13 +269,134 @@ pub enum DevFsVirtualFileHook {
     VirtualFile(Arcrwb<dyn VirtualDeviceFileProvider>),
 }
 
+/// Splits a `/` separated path relative to the devfs root into its components
+pub fn devfs_path(path: &[char]) -> Vec<Vec<u8>> {
+    VfsPath::from(path)
+        .components()
+        .map(|part| part.as_bytes().to_vec())
+        .collect()
+}
+
+fn devfs_path_name(path: &[Vec<u8>]) -> Vec<char> {
+    VfsPath::from_bytes(path.join(&b'/')).to_chars()
+}
+
+/// Directories and virtual files of the devfs, `path` is relative to the devfs root
+#[derive(Debug)]
+struct DevFsNodeData {
+    path: Vec<Vec<u8>>,
+}
+
+impl FsSpecificFileData for DevFsNodeData {}
+
+/// Maximum number of events kept in the devfs event ring, older events are dropped first
+pub const DEVFS_EVENT_RING_SIZE: usize = 256;
+
+pub const DEVFS_MAGIC: u64 = 0x1373;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DevFsEventKind {
+    Add,
+    Remove,
+    Change,
+}
+
+impl DevFsEventKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DevFsEventKind::Add => "add",
+            DevFsEventKind::Remove => "remove",
+            DevFsEventKind::Change => "change",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct DevFsEvent {
+    pub sequence: u64,
+    pub kind: DevFsEventKind,
+    pub driver: u64,
+    pub path: Vec<char>,
+    pub generation: u64,
+}
+
+impl DevFsEvent {
+    /// Text form read from `/dev/events`: `<sequence> <kind> <driver> <generation> <path>\n`
+    pub fn format(&self) -> String {
+        alloc::format!(
+            "{} {} {:#x} {} {}\n",
+            self.sequence,
+            self.kind.as_str(),
+            self.driver,
+            self.generation,
+            VfsPath::from(&self.path)
+        )
+    }
+}
+
+#[derive(Debug)]
+struct DevFsEventRing {
+    events: VecDeque<DevFsEvent>,
+    next_sequence: u64,
+}
+
+static DEVFS_EVENTS: Mutex<DevFsEventRing> = Mutex::new(DevFsEventRing {
+    events: VecDeque::new(),
+    next_sequence: 0,
+});
+
+/// Woken every time an event is pushed
+pub static DEVFS_EVENT_WAITERS: Lazy<Arc<WaitQueue>> = Lazy::new(|| Arc::new(WaitQueue::new()));
+
+fn push_devfs_event(kind: DevFsEventKind, driver: u64, path: &[Vec<u8>], generation: u64) {
+    let mut ring = DEVFS_EVENTS.lock();
+    if ring.events.len() >= DEVFS_EVENT_RING_SIZE {
+        ring.events.pop_front();
+    }
+    let sequence = ring.next_sequence;
+    ring.next_sequence += 1;
+    ring.events.push_back(DevFsEvent {
+        sequence,
+        kind,
+        driver,
+        path: devfs_path_name(path),
+        generation,
+    });
+    drop(ring);
+    DEVFS_EVENT_WAITERS.wake_all();
+}
+
+/// Returns the oldest event with a sequence number of at least `sequence`.
+/// If the reader fell behind, the events that were dropped from the ring are skipped
+pub fn next_devfs_event(sequence: u64) -> Option<DevFsEvent> {
+    let ring = DEVFS_EVENTS.lock();
+    let first = ring.events.front()?.sequence;
+    let index = sequence.saturating_sub(first) as usize;
+    ring.events.get(index).cloned()
+}
+
+/// Sequence number the next event will get, new readers start from here
+pub fn devfs_event_sequence() -> u64 {
+    DEVFS_EVENTS.lock().next_sequence
+}
+
 #[derive(Debug)]
 pub struct DevFs {
     devices: Vec<PciDevice>,
-    hooks: BTreeMap<Vec<char>, DevFsVirtualFileHook>,
+    /// Hooks are keyed by their path components, intermediate directories only exist implicitly.
+    /// The `VfsFile` of a nested hook must be named after its full path relative to the devfs root (`disk/by-id/...`)
+    hooks: BTreeMap<Vec<Vec<u8>>, DevFsVirtualFileHook>,
+    /// Directories that exist without hooks below them, for other file systems to be mounted on
+    directories: BTreeSet<Vec<Vec<u8>>>,
     handles: FileHandleAllocator,
 
     drivers: BTreeMap<u64, Arcrwb<dyn DevFsDriver>>,
+    /// Paths of the hooks each driver added
+    driver_hooks: BTreeMap<u64, BTreeSet<Vec<Vec<u8>>>>,
+    /// Paths of the devices drivers gave a number, keyed by whether they are block devices
+    device_numbers: BTreeMap<(bool, DeviceNumber), Vec<char>>,
+    /// PCI scan the files below `pci` were made from, 0 before they are
+    pci_generation: u64,
 
     os_id: u64,
     parent_fs_os_id: u64,
@@ -144,6 +404,28 @@ pub struct DevFs {
     root_fs: Option<WeakArcrwb<Vfs>>,
 }
 
+/// Handle of an open directory, the position is the index of the next child
+#[derive(Debug, Clone)]
+struct DevFsDirectoryHandle {
+    path: Vec<Vec<u8>>,
+    position: u64,
+}
+
+fn directory_stat() -> FileStat {
+    FileStat {
+        size: 0,
+        created_at: 0,
+        modified_at: 0,
+        permissions: permissions!(Owner:Read, Owner:Execute, Group:Read, Group:Execute, Other:Read, Other:Execute).to_u64(),
+        is_file: false,
+        is_directory: true,
+        is_symlink: false,
+        owner_id: 0,
+        group_id: 0,
+        flags: FLAG_VIRTUAL | FLAG_SYSTEM,
+    }
+}
+
 #[repr(C)]
 #[derive(Debug, Clone)]
 pub struct DevFsHandleData<T: Sized + Clone + Debug> {
@@ -163,14 +445,47 @@ impl DevFs {
         self.drivers.insert(driver_id, driver.clone());
         for (id, device) in self.devices.clone().iter().enumerate() {
             if guard.handles_device(self, device) {
-                guard.refresh_device_hooks(self, device, id)?;
+                self.refresh_hooks(&mut **guard, device, id)?;
             }
         }
 
         Ok(())
     }
 
-    /// Adds a hook to the devfs, and returns the previous one if any
+    /// Removes a driver and the hooks it still owns, open handles keep working until closed
+    pub fn unregister_driver(&mut self, driver_id: u64) -> Result<(), VfsError> {
+        self.drivers
+            .remove(&driver_id)
+            .ok_or(VfsError::ActionNotAllowed)?;
+        for path in self.driver_hooks.remove(&driver_id).unwrap_or_default() {
+            if matches!(self.hooks.get(&path), Some(DevFsVirtualFileHook::Hook(hook)) if hook.driver_id == driver_id)
+            {
+                self.remove_hook(&devfs_path_name(&path));
+            }
+        }
+        self.device_numbers
+            .retain(|_, path| self.hooks.contains_key(&devfs_path(path)));
+        Ok(())
+    }
+
+    /// Runs `refresh_device_hooks` of `driver` and records the hooks it owns
+    pub fn refresh_hooks(
+        &mut self,
+        driver: &mut dyn DevFsDriver,
+        pci_device: &PciDevice,
+        device_id: usize,
+    ) -> Result<(), VfsError> {
+        let paths = driver.refresh_device_hooks(self, pci_device, device_id)?;
+        self.driver_hooks
+            .entry(driver.driver_id())
+            .or_default()
+            .extend(paths.iter().map(|path| devfs_path(path)));
+        Ok(())
+    }
+
+    /// Adds a hook to the devfs, and returns the previous one if any.
+    /// `path` is relative to the devfs root and can contain `/` to create nested entries
+    #[allow(clippy::too_many_arguments)]
     pub fn replace_hook(
         &mut self,
         path: Vec<char>,
@@ -179,29 +494,219 @@ impl DevFs {
         kind: DevFsHookKind,
         generation: u64,
         device_id: u64,
+        device_key: u64,
     ) -> Option<DevFsVirtualFileHook> {
+        let driver_id = driver;
         let driver = self.drivers.get(&driver)?.clone();
         let hook = Arc::new(DevFsHook {
             driver,
+            driver_id,
             file,
             kind,
             generation,
             device_id,
+            device_key,
         });
-        self.hooks
-            .insert(path, DevFsVirtualFileHook::Hook(hook.clone()))
+        let path = devfs_path(&path);
+        let previous = self
+            .hooks
+            .insert(path.clone(), DevFsVirtualFileHook::Hook(hook.clone()));
+        match &previous {
+            None => push_devfs_event(DevFsEventKind::Add, driver_id, &path, generation),
+            Some(DevFsVirtualFileHook::Hook(old)) if old.generation == generation => {}
+            Some(_) => push_devfs_event(DevFsEventKind::Change, driver_id, &path, generation),
+        }
+        previous
     }
 
+    /// Removes a hook, intermediate directories that become empty disappear with it
     pub fn remove_hook(&mut self, path: &[char]) -> Option<DevFsVirtualFileHook> {
-        self.hooks.remove(path)
+        let path = devfs_path(path);
+        let previous = self.hooks.remove(&path);
+        if let Some(DevFsVirtualFileHook::Hook(hook)) = &previous {
+            push_devfs_event(
+                DevFsEventKind::Remove,
+                hook.driver_id,
+                &path,
+                hook.generation,
+            );
+        }
+        previous
+    }
+
+    /// Lets device nodes of other file systems with this number open the device at `path`
+    pub fn register_device_number(&mut self, block: bool, number: DeviceNumber, path: &[char]) {
+        self.device_numbers.insert((block, number), path.to_vec());
+    }
+
+    /// Opens the device registered under `number`, `VfsError::NoSuchDevice` if there is none
+    pub fn open_device_number(
+        &mut self,
+        block: bool,
+        number: DeviceNumber,
+        mode: u64,
+    ) -> Result<u64, VfsError> {
+        let path = self
+            .device_numbers
+            .get(&(block, number))
+            .ok_or(VfsError::NoSuchDevice)?
+            .clone();
+        let file = match self.get_file(&VfsPath::from(&path)) {
+            Ok(file) => file,
+            Err(VfsError::PathNotFound) => return Err(VfsError::NoSuchDevice),
+            Err(e) => return Err(e),
+        };
+        if file.is_block_device() != block {
+            return Err(VfsError::NoSuchDevice);
+        }
+        self.fopen(&file, mode)
+    }
+
+    pub fn device_index(&self, pci_device: &PciDevice) -> Option<usize> {
+        self.devices.iter().position(|device| device == pci_device)
+    }
+
+    /// Lets every driver look for media changes
+    pub fn poll_devices(&mut self) -> Result<(), VfsError> {
+        for driver in self.drivers.values().cloned().collect::<Vec<_>>() {
+            driver.write().poll_devices(self)?;
+        }
+        Ok(())
     }
 
     pub fn insert_vfile(&mut self, provider: Arcrwb<dyn VirtualDeviceFileProvider>, path: &[char]) {
+        self.hooks.insert(
+            devfs_path(path),
+            DevFsVirtualFileHook::VirtualFile(provider),
+        );
+    }
+
+    /// Adds an empty directory, `path` is relative to the devfs root
+    pub fn insert_directory(&mut self, path: &[char]) {
+        self.directories.insert(devfs_path(path));
+    }
+
+    /// Makes the `pci` directory again after a rescan, files already open keep their device
+    fn sync_pci_directory(&mut self) {
+        let generation = pci::scan_generation();
+        if generation == self.pci_generation {
+            return;
+        }
+        self.pci_generation = generation;
+        self.hooks
+            .retain(|path, _| path.first().map(Vec::as_slice) != Some(b"pci"));
+        insert_pci_files(self);
+    }
+
+    fn path_of(file: &VfsFile) -> Option<Vec<Vec<u8>>> {
+        if file.name() == ['/'] {
+            return Some(Vec::new());
+        }
+        let data = file.get_fs_specific_data();
+        (*data)
+            .as_any()
+            .downcast_ref::<DevFsNodeData>()
+            .map(|data| data.path.clone())
+    }
+
+    /// The hook of a file given out by `get_child` or `list_children`. Files of drivers keep their
+    /// own fs specific data, they are found by it
+    fn hook_of(&self, file: &VfsFile) -> Option<&DevFsVirtualFileHook> {
+        if let Some(path) = Self::path_of(file) {
+            return self.hooks.get(&path);
+        }
+        let data = Arc::as_ptr(&file.get_fs_specific_data()) as *const ();
+        let name = VfsPath::from(file.name()).into_bytes();
         self.hooks
-            .insert(path.to_vec(), DevFsVirtualFileHook::VirtualFile(provider));
+            .iter()
+            .find(|(path, hook)| match hook {
+                DevFsVirtualFileHook::Hook(hook) => {
+                    path.last() == Some(&name)
+                        && Arc::as_ptr(&hook.file.get_fs_specific_data()) as *const () == data
+                }
+                DevFsVirtualFileHook::VirtualFile(_) => false,
+            })
+            .map(|(_, hook)| hook)
+    }
+
+    /// Returns whether `path` is an intermediate directory, i.e. some hook lives below it
+    fn is_directory(&self, path: &[Vec<u8>]) -> bool {
+        path.is_empty()
+            || self.directories.contains(path)
+            || self
+                .hooks
+                .range(path.to_vec()..)
+                .next()
+                .is_some_and(|(k, _)| k.len() > path.len() && k.starts_with(path))
+    }
+
+    /// Files right below the directory `path`
+    fn children_of(&self, path: &[Vec<u8>]) -> Result<Vec<VfsFile>, VfsError> {
+        let mut children = Vec::new();
+        let mut last_directory: Option<&[u8]> = None;
+        for (key, hook) in self.hooks.range(path.to_vec()..) {
+            if !key.starts_with(path) {
+                break;
+            }
+            if key.len() == path.len() + 1 {
+                children.push(Self::hook_file(key, hook)?);
+            } else if key.len() > path.len() + 1 {
+                let name = &key[path.len()];
+                if last_directory != Some(name) {
+                    last_directory = Some(name);
+                    children.push(self.directory_file(&key[0..path.len() + 1]));
+                }
+            }
+        }
+        for directory in self.directories.range(path.to_vec()..) {
+            if !directory.starts_with(path) {
+                break;
+            }
+            if directory.len() == path.len() + 1
+                && !children
+                    .iter()
+                    .any(|child| child.name() == devfs_path_name(&directory[path.len()..]))
+            {
+                children.push(self.directory_file(directory));
+            }
+        }
+        Ok(children)
     }
 
-    pub fn alloc_file_handle<T: Sized + Clone + Debug>(
+    fn directory_file(&self, path: &[Vec<u8>]) -> VfsFile {
+        VfsFile::new(
+            VfsFileKind::Directory,
+            devfs_path_name(&path[path.len() - 1..]),
+            0,
+            self.os_id,
+            self.os_id,
+            Arc::new(DevFsNodeData {
+                path: path.to_vec(),
+            }),
+        )
+    }
+
+    fn hook_file(path: &[Vec<u8>], hook: &DevFsVirtualFileHook) -> Result<VfsFile, VfsError> {
+        let name = devfs_path_name(&path[path.len() - 1..]);
+        match hook {
+            DevFsVirtualFileHook::Hook(hook) => Ok(hook.file.clone_renamed(name)),
+            DevFsVirtualFileHook::VirtualFile(provider) => {
+                let file = provider.read().vfs_file()?;
+                Ok(VfsFile::new(
+                    file.kind().clone(),
+                    name,
+                    file.size(),
+                    file.parent_fs(),
+                    file.fs(),
+                    Arc::new(DevFsNodeData {
+                        path: path.to_vec(),
+                    }),
+                ))
+            }
+        }
+    }
+
+    pub fn alloc_file_handle<T: Sized + Clone + Debug + 'static>(
         &mut self,
         data: T,
         hook: Arc<DevFsHook>,
@@ -209,7 +714,7 @@ impl DevFs {
         self.alloc_file_handle_raw(data, Some(hook))
     }
 
-    fn alloc_file_handle_raw<T: Sized + Clone + Debug>(
+    fn alloc_file_handle_raw<T: Sized + Clone + Debug + 'static>(
         &mut self,
         data: T,
         hook: Option<Arc<DevFsHook>>,
@@ -220,12 +725,42 @@ impl DevFs {
 
     /// # Safety
     /// Caller must ensure that the handle is valid and was allocated using `alloc_file_handle` with the same `T` type
-    pub unsafe fn get_handle_data<T: Sized + Clone + Debug>(&self, handle: u64) -> Option<*mut T> {
+    pub unsafe fn get_handle_data<T: Sized + Clone + Debug + 'static>(
+        &self,
+        handle: u64,
+    ) -> Option<*mut T> {
         let handle_data = self.handles.get_handle_data::<DevFsHandleData<T>>(handle)?;
         Some(&mut (*handle_data).data as *mut T)
     }
 
-    pub fn dealloc_file_handle<T: Sized + Clone + Debug>(&mut self, handle: u64) {
+    fn directory_handle(&self, handle: u64) -> Option<*mut DevFsDirectoryHandle> {
+        unsafe { self.handles.get_handle_data::<DevFsDirectoryHandle>(handle) }
+    }
+
+    /// Fills `buf` with the records of the children from the position of the handle
+    fn read_directory(
+        &mut self,
+        directory: &mut DevFsDirectoryHandle,
+        buf: &mut [u8],
+    ) -> Result<u64, VfsError> {
+        let children = self.children_of(&directory.path)?;
+        let mut writer = DirectoryRecordWriter::new(buf);
+        for child in children.iter().skip(directory.position as usize) {
+            let name = VfsPath::from(child.name()).into_bytes();
+            let record = DirectoryRecord {
+                inode: self.file_identity(child),
+                kind: record_kind(child.kind()),
+                name: &name,
+            };
+            if !writer.push(&record)? {
+                break;
+            }
+            directory.position += 1;
+        }
+        Ok(writer.written())
+    }
+
+    pub fn dealloc_file_handle<T: Sized + Clone + Debug + 'static>(&mut self, handle: u64) {
         self.handles
             .dealloc_file_handle::<DevFsHandleData<T>>(handle);
     }
@@ -265,7 +800,7 @@ impl FileSystem for DevFs {
     fn create_child(
         &mut self,
         _directory: &VfsFile,
-        _name: &[char],
+        _name: &VfsName,
         _kind: VfsFileKind,
     ) -> Result<VfsFile, VfsError> {
         Err(VfsError::ReadOnly)
@@ -275,17 +810,21 @@ impl FileSystem for DevFs {
         Err(VfsError::ReadOnly)
     }
 
-    fn get_child(&mut self, file: &VfsFile, child: &[char]) -> Result<VfsFile, VfsError> {
+    fn get_child(&mut self, file: &VfsFile, child: &VfsName) -> Result<VfsFile, VfsError> {
         if file.fs() != self.os_id() {
             return Err(VfsError::FileSystemMismatch);
         }
-        if file.name() != ['/'] {
-            return Err(VfsError::PathNotFound);
+        self.sync_pci_directory();
+        let mut path = Self::path_of(file).ok_or(VfsError::NotDirectory)?;
+        if !self.is_directory(&path) {
+            return Err(VfsError::NotDirectory);
         }
+        path.push(child.as_bytes().to_vec());
 
-        match self.hooks.get(child).ok_or(VfsError::PathNotFound)? {
-            DevFsVirtualFileHook::Hook(hook) => Ok(hook.file.clone()),
-            DevFsVirtualFileHook::VirtualFile(file) => Ok(file.read().vfs_file()?),
+        match self.hooks.get(&path) {
+            Some(hook) => Self::hook_file(&path, hook),
+            None if self.is_directory(&path) => Ok(self.directory_file(&path)),
+            None => Err(VfsError::PathNotFound),
         }
     }
 
@@ -293,23 +832,19 @@ impl FileSystem for DevFs {
         if file.fs() != self.os_id() {
             return Err(VfsError::FileSystemMismatch);
         }
-        if file.name() != ['/'] {
-            return Ok(Vec::new());
+        self.sync_pci_directory();
+        let path = Self::path_of(file).ok_or(VfsError::NotDirectory)?;
+        if !self.is_directory(&path) {
+            return Err(VfsError::NotDirectory);
         }
-        self.hooks
-            .values()
-            .map(|hook| match hook {
-                DevFsVirtualFileHook::Hook(hook) => Ok(hook.file.clone()),
-                DevFsVirtualFileHook::VirtualFile(file) => file.read().vfs_file(),
-            })
-            .collect::<Result<Vec<_>, _>>()
+        self.children_of(&path)
     }
 
     fn fs_type(&mut self) -> String {
         "devices".to_string()
     }
 
-    fn get_file(&mut self, path: &[char]) -> Result<VfsFile, VfsError> {
+    fn get_file(&mut self, path: &VfsPath) -> Result<VfsFile, VfsError> {
         let mut traverse = PathTraverse::new_owned(path, self)?;
         loop {
             let result = traverse.find_next()?;
@@ -320,6 +855,9 @@ impl FileSystem for DevFs {
     }
 
     fn get_stats(&mut self, file: &VfsFile) -> Result<FileStat, VfsError> {
+        if file.is_directory() {
+            return Ok(directory_stat());
+        }
         let handle = self.fopen(file, 0)?;
         let stats = self.fstat(handle);
         self.fclose(handle)?;
@@ -339,6 +877,10 @@ impl FileSystem for DevFs {
         None
     }
 
+    fn statfs(&mut self) -> Result<FsStat, VfsError> {
+        Ok(FsStat::virtual_fs(DEVFS_MAGIC, self.hooks.len() as u64))
+    }
+
     fn on_mount(
         &mut self,
         mount_point: &VfsFile,
@@ -349,6 +891,7 @@ impl FileSystem for DevFs {
         self.parent_fs_os_id = mount_point.fs();
         self.mnt = Some(mount_point.clone());
         self.os_id = os_id;
+        self.handles.set_owner(os_id);
         self.get_root()
     }
 
@@ -375,11 +918,17 @@ impl FileSystem for DevFs {
         if file.fs() != self.os_id() {
             return Err(VfsError::FileSystemMismatch);
         }
-        if file.name() == ['/'] {
-            return Err(VfsError::ActionNotAllowed);
+        if file.is_directory() {
+            if mode & (OPEN_MODE_WRITE | OPEN_MODE_APPEND | OPEN_MODE_CREATE) != 0 {
+                return Err(VfsError::NotFile);
+            }
+            let path = Self::path_of(file).ok_or(VfsError::NotDirectory)?;
+            return Ok(self
+                .handles
+                .alloc_file_handle(DevFsDirectoryHandle { path, position: 0 }));
         }
 
-        let hook = self.hooks.get(file.name()).ok_or(VfsError::PathNotFound)?;
+        let hook = self.hook_of(file).ok_or(VfsError::PathNotFound)?;
 
         match hook {
             DevFsVirtualFileHook::Hook(hook) => {
@@ -405,6 +954,11 @@ impl FileSystem for DevFs {
     }
 
     fn fclose(&mut self, handle: u64) -> Result<(), VfsError> {
+        if self.directory_handle(handle).is_some() {
+            self.handles
+                .dealloc_file_handle::<DevFsDirectoryHandle>(handle);
+            return Ok(());
+        }
         let dhandle = get_handle_data!(self, handle);
         match &dhandle.hook {
             Some(hook) => {
@@ -426,6 +980,11 @@ impl FileSystem for DevFs {
     }
 
     fn fseek(&mut self, handle: u64, position: SeekPosition) -> Result<u64, VfsError> {
+        if let Some(directory) = self.directory_handle(handle) {
+            let directory = unsafe { &mut *directory };
+            directory.position = seek_directory(position, directory.position)?;
+            return Ok(directory.position);
+        }
         let dhandle = get_handle_data!(self, handle);
         match &dhandle.hook {
             Some(hook) => {
@@ -458,6 +1017,9 @@ impl FileSystem for DevFs {
     }
 
     fn fread(&mut self, handle: u64, buf: &mut [u8]) -> Result<u64, VfsError> {
+        if let Some(directory) = self.directory_handle(handle) {
+            return self.read_directory(unsafe { &mut *directory }, buf);
+        }
         let dhandle = get_handle_data!(self, handle);
         match &dhandle.hook {
             Some(hook) => {
@@ -518,7 +1080,7 @@ impl FileSystem for DevFs {
                     .devices
                     .get(device_id)
                     .ok_or(VfsError::ActionNotAllowed)?;
-                (*wguard).refresh_device_hooks(self, &device, device_id)?;
+                self.refresh_hooks(&mut **wguard, &device, device_id)?;
 
                 Ok(())
             }
@@ -529,7 +1091,45 @@ impl FileSystem for DevFs {
         }
     }
 
+    fn fpoll(&mut self, handle: u64, events: PollEvents) -> Result<PollEvents, VfsError> {
+        let dhandle = get_handle_data!(self, handle);
+        match &dhandle.hook {
+            Some(_) => Ok(events & POLL_ALWAYS_READY),
+            None => Ok(dhandle.data.read().poll(events)),
+        }
+    }
+
+    fn fwait_queue(&mut self, handle: u64) -> Result<Option<Arc<WaitQueue>>, VfsError> {
+        let dhandle = get_handle_data!(self, handle);
+        match &dhandle.hook {
+            Some(_) => Ok(None),
+            None => Ok(dhandle.data.read().wait_queue()),
+        }
+    }
+
+    fn fblocks(&mut self, handle: u64) -> bool {
+        let dhandle = unsafe {
+            self.handles
+                .get_handle_data::<DevFsHandleData<Arcrwb<dyn VirtualDeviceFile>>>(handle)
+        };
+        // Safety: handles are only freed with the devfs locked
+        dhandle.is_some_and(|dhandle| unsafe {
+            (*dhandle).hook.is_none() && (*dhandle).data.read().blocks()
+        })
+    }
+
+    fn fioctl(&mut self, handle: u64, request: u64, arg: &mut [u8]) -> Result<u64, VfsError> {
+        let dhandle = get_handle_data!(self, handle);
+        match &dhandle.hook {
+            Some(_) => Err(VfsError::NotTerminal),
+            None => dhandle.data.write().ioctl(request, arg),
+        }
+    }
+
     fn fstat(&self, handle: u64) -> Result<FileStat, VfsError> {
+        if self.directory_handle(handle).is_some() {
+            return Ok(directory_stat());
+        }
         let dhandle = get_handle_data!(self, handle);
         match &dhandle.hook {
             Some(hook) => {
@@ -546,11 +1146,45 @@ impl FileSystem for DevFs {
     }
 }
 
+/// The devfs mounted at /dev, device nodes are opened through it without the Vfs lock
+static DEVFS: Mutex<Option<WeakArcrwb<dyn FileSystem>>> = Mutex::new(None);
+
+/// Opens the device of a device node, returns the devfs and the handle it gave
+pub fn open_device_node(
+    block: bool,
+    number: DeviceNumber,
+    mode: u64,
+) -> Result<(Arcrwb<dyn FileSystem>, u64), VfsError> {
+    with_devfs(|fs, devfs| Ok((fs.clone(), devfs.open_device_number(block, number, mode)?)))
+}
+
+/// Runs `f` on the devfs mounted at /dev, with it locked
+pub fn with_devfs<T>(
+    f: impl FnOnce(&Arcrwb<dyn FileSystem>, &mut DevFs) -> Result<T, VfsError>,
+) -> Result<T, VfsError> {
+    let fs = DEVFS
+        .lock()
+        .as_ref()
+        .and_then(|fs| fs.upgrade())
+        .ok_or(VfsError::NoSuchDevice)?;
+    let mut wguard = fs.write();
+    let devfs = &mut **wguard;
+    let devfs = devfs
+        .as_any_mut()
+        .downcast_mut::<DevFs>()
+        .ok_or(VfsError::NoSuchDevice)?;
+    f(&fs, devfs)
+}
+
 pub fn init_devfs(vfs: &mut Vfs) {
     let fs = DevFs {
         devices: pci::get_devices(),
         hooks: BTreeMap::new(),
+        directories: BTreeSet::new(),
         drivers: BTreeMap::new(),
+        driver_hooks: BTreeMap::new(),
+        device_numbers: BTreeMap::new(),
+        pci_generation: 0,
         handles: FileHandleAllocator::default(),
         mnt: None,
         os_id: 0,
@@ -560,10 +1194,14 @@ pub fn init_devfs(vfs: &mut Vfs) {
 
     let dev = "dev".chars().collect::<Vec<char>>();
 
-    vfs.mount(&dev, Box::new(fs)).unwrap();
+    vfs.mount(&dev, Box::new(fs), 0).unwrap();
 
-    let fs: Arc<RwLock<Box<dyn FileSystem>>> =
-        vfs.get_file(&dev).unwrap().get_mounted_fs().unwrap();
+    let fs: Arcrwb<dyn FileSystem> = vfs
+        .get_file(&VfsPath::from(&dev))
+        .unwrap()
+        .get_mounted_fs()
+        .unwrap();
+    *DEVFS.lock() = Some(Arc::downgrade(&fs));
 
     let mut wguard = fs.write();
     let devfs = &mut **wguard;
@@ -572,3 +1210,31 @@ pub fn init_devfs(vfs: &mut Vfs) {
     crate::drivers::init_vfiles(devfs);
     crate::drivers::fs::virt::files::init_vfiles(devfs);
 }
+
+/// Runs `DevFs::poll_devices` on the devfs mounted at /dev.
+/// Gives up if a lock is already taken, the next poll will retry
+pub fn poll_devices() {
+    let vfs = get_vfs();
+    let Some(mut vguard) = vfs.try_write() else {
+        return;
+    };
+    let Ok(dev) = vguard.get_file(&VfsPath::from("dev")) else {
+        return;
+    };
+    drop(vguard);
+    let Some(fs) = dev.get_mounted_fs() else {
+        return;
+    };
+    let Some(mut wguard) = fs.try_write() else {
+        return;
+    };
+    let devfs = &mut **wguard;
+    let result = match devfs.as_any_mut().downcast_mut::<DevFs>() {
+        Some(devfs) => devfs.poll_devices(),
+        None => Ok(()),
+    };
+    drop(wguard);
+    if let Err(err) = result {
+        crate::println!("Device poll failed: {:?}", err);
+    }
+}
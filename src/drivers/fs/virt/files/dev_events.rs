@@ -0,0 +1,152 @@
+use alloc::{boxed::Box, sync::Arc};
+
+use crate::{
+    drivers::{
+        fs::virt::devfs::{
+            devfs_event_sequence, next_devfs_event, VirtualDeviceFile, VirtualDeviceFileProvider,
+            DEVFS_EVENT_WAITERS,
+        },
+        vfs::{
+            arcrwb_new_from_box, Arcrwb, FileStat, PollEvent, PollEvents, SeekPosition, VfsError,
+            VfsFile, VfsFileKind, VfsSpecificFileData, FLAG_SYSTEM, FLAG_VIRTUAL,
+            FLAG_VIRTUAL_CHARACTER_DEVICE, OPEN_MODE_FAIL_IF_EXISTS,
+        },
+    },
+    permissions,
+    process::wait::WaitQueue,
+};
+
+/// Each open file only sees the events emitted after it was opened
+#[derive(Debug)]
+pub struct DevEvents {
+    next_sequence: u64,
+}
+
+#[derive(Debug)]
+pub struct DevEventsProvider {
+    devfs_os_id: u64,
+}
+
+impl DevEventsProvider {
+    pub fn new(devfs_os_id: u64) -> Self {
+        Self { devfs_os_id }
+    }
+}
+
+impl VirtualDeviceFileProvider for DevEventsProvider {
+    fn open(&mut self, mode: u64) -> Result<Arcrwb<dyn VirtualDeviceFile>, VfsError> {
+        if mode & OPEN_MODE_FAIL_IF_EXISTS != 0 {
+            Err(VfsError::FileAlreadyExists)
+        } else {
+            Ok(arcrwb_new_from_box(Box::new(DevEvents {
+                next_sequence: devfs_event_sequence(),
+            })))
+        }
+    }
+
+    fn stat(&self) -> Result<FileStat, VfsError> {
+        Ok(FileStat {
+            size: 0,
+            is_directory: false,
+            is_symlink: false,
+            is_file: true,
+            permissions: permissions!(Owner:Read, Group:Read, Other:Read).to_u64(),
+            owner_id: 0,
+            group_id: 0,
+            created_at: 0,
+            modified_at: 0,
+            flags: FLAG_VIRTUAL | FLAG_VIRTUAL_CHARACTER_DEVICE | FLAG_SYSTEM,
+        })
+    }
+
+    fn vfs_file(&self) -> Result<VfsFile, VfsError> {
+        Ok(VfsFile::new(
+            VfsFileKind::File,
+            "events".chars().collect(),
+            0,
+            self.devfs_os_id,
+            self.devfs_os_id,
+            Arc::new(VfsSpecificFileData),
+        ))
+    }
+}
+
+impl VirtualDeviceFile for DevEvents {
+    fn stat(&self) -> Result<FileStat, VfsError> {
+        Ok(FileStat {
+            size: 0,
+            is_directory: false,
+            is_symlink: false,
+            is_file: true,
+            permissions: permissions!(Owner:Read, Group:Read, Other:Read).to_u64(),
+            owner_id: 0,
+            group_id: 0,
+            created_at: 0,
+            modified_at: 0,
+            flags: FLAG_VIRTUAL | FLAG_VIRTUAL_CHARACTER_DEVICE | FLAG_SYSTEM,
+        })
+    }
+
+    fn close(&mut self) -> Result<(), VfsError> {
+        Ok(())
+    }
+
+    fn seek(&mut self, position: SeekPosition) -> Result<u64, VfsError> {
+        if matches!(
+            position,
+            SeekPosition::FromStart(0) | SeekPosition::FromCurrent(0) | SeekPosition::FromEnd(0)
+        ) {
+            Ok(0)
+        } else {
+            Err(VfsError::InvalidSeekPosition)
+        }
+    }
+
+    fn pos(&self) -> Result<u64, VfsError> {
+        Ok(0)
+    }
+
+    fn truncate(&mut self) -> Result<u64, VfsError> {
+        Ok(0)
+    }
+
+    /// Reads as many whole events as fit in `buf`, one per line
+    fn read(&mut self, buf: &mut [u8]) -> Result<u64, VfsError> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let mut written = 0;
+        while let Some(event) = next_devfs_event(self.next_sequence) {
+            let line = event.format();
+            if written + line.len() > buf.len() {
+                if written == 0 {
+                    return Err(VfsError::BadBufferSize);
+                }
+                break;
+            }
+            buf[written..written + line.len()].copy_from_slice(line.as_bytes());
+            written += line.len();
+            self.next_sequence = event.sequence + 1;
+        }
+        if written == 0 {
+            return Err(VfsError::WouldBlock);
+        }
+        Ok(written as u64)
+    }
+
+    fn write(&mut self, _buf: &[u8]) -> Result<u64, VfsError> {
+        Err(VfsError::ActionNotAllowed)
+    }
+
+    fn poll(&self, events: PollEvents) -> PollEvents {
+        let mut ready = PollEvents::empty();
+        if next_devfs_event(self.next_sequence).is_some() {
+            ready.set(PollEvent::In);
+        }
+        events & ready
+    }
+
+    fn wait_queue(&self) -> Option<Arc<WaitQueue>> {
+        Some(DEVFS_EVENT_WAITERS.clone())
+    }
+}
@@ -0,0 +1,124 @@
+use alloc::{boxed::Box, format, sync::Arc, vec::Vec};
+
+use crate::{
+    drivers::{
+        fs::virt::devfs::{fseek_helper, VirtualDeviceFile, VirtualDeviceFileProvider},
+        keyboard::keymap::{keymap_path, set_keymap, KeymapError, BUILTIN_KEYMAP},
+        vfs::{
+            arcrwb_new_from_box, Arcrwb, FileStat, SeekPosition, VfsError, VfsFile, VfsFileKind,
+            VfsSpecificFileData, FLAG_SYSTEM, FLAG_VIRTUAL, OPEN_MODE_FAIL_IF_EXISTS,
+        },
+    },
+    log_warn, permissions,
+};
+
+/// Reads return the keymap loaded when the file was opened, writing a path loads another
+#[derive(Debug)]
+pub struct DevKeymap {
+    data: Vec<u8>,
+    position: u64,
+}
+
+#[derive(Debug)]
+pub struct DevKeymapProvider {
+    devfs_os_id: u64,
+}
+
+impl DevKeymapProvider {
+    pub fn new(devfs_os_id: u64) -> Self {
+        Self { devfs_os_id }
+    }
+}
+
+fn keymap_stat(size: u64) -> FileStat {
+    FileStat {
+        size,
+        is_directory: false,
+        is_symlink: false,
+        is_file: true,
+        permissions: permissions!(Owner:Read, Owner:Write, Group:Read, Other:Read).to_u64(),
+        owner_id: 0,
+        group_id: 0,
+        created_at: 0,
+        modified_at: 0,
+        flags: FLAG_VIRTUAL | FLAG_SYSTEM,
+    }
+}
+
+impl VirtualDeviceFileProvider for DevKeymapProvider {
+    fn open(&mut self, mode: u64) -> Result<Arcrwb<dyn VirtualDeviceFile>, VfsError> {
+        if mode & OPEN_MODE_FAIL_IF_EXISTS != 0 {
+            return Err(VfsError::FileAlreadyExists);
+        }
+        let path = keymap_path();
+        Ok(arcrwb_new_from_box(Box::new(DevKeymap {
+            data: format!("{}\n", path.as_deref().unwrap_or(BUILTIN_KEYMAP)).into_bytes(),
+            position: 0,
+        })))
+    }
+
+    fn stat(&self) -> Result<FileStat, VfsError> {
+        Ok(keymap_stat(0))
+    }
+
+    fn vfs_file(&self) -> Result<VfsFile, VfsError> {
+        Ok(VfsFile::new(
+            VfsFileKind::File,
+            "keymap".chars().collect(),
+            0,
+            self.devfs_os_id,
+            self.devfs_os_id,
+            Arc::new(VfsSpecificFileData),
+        ))
+    }
+}
+
+impl VirtualDeviceFile for DevKeymap {
+    fn stat(&self) -> Result<FileStat, VfsError> {
+        Ok(keymap_stat(self.data.len() as u64))
+    }
+
+    fn close(&mut self) -> Result<(), VfsError> {
+        Ok(())
+    }
+
+    fn seek(&mut self, position: SeekPosition) -> Result<u64, VfsError> {
+        self.position = fseek_helper(position, self.position, self.data.len() as u64)
+            .ok_or(VfsError::InvalidSeekPosition)?;
+        Ok(self.position)
+    }
+
+    fn pos(&self) -> Result<u64, VfsError> {
+        Ok(self.position)
+    }
+
+    fn truncate(&mut self) -> Result<u64, VfsError> {
+        Ok(0)
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> Result<u64, VfsError> {
+        let start = (self.position as usize).min(self.data.len());
+        let len = buf.len().min(self.data.len() - start);
+        buf[..len].copy_from_slice(&self.data[start..start + len]);
+        self.position += len as u64;
+        Ok(len as u64)
+    }
+
+    /// The keymap stays when the new one can't be loaded, file errors are returned as is
+    fn write(&mut self, buf: &[u8]) -> Result<u64, VfsError> {
+        let text = core::str::from_utf8(buf).map_err(|_| VfsError::InvalidArgument)?;
+        let path = match text.trim() {
+            "" => return Err(VfsError::InvalidArgument),
+            BUILTIN_KEYMAP => None,
+            path => Some(path),
+        };
+        match set_keymap(path) {
+            Ok(()) => Ok(buf.len() as u64),
+            Err(KeymapError::Read(err)) => Err(err),
+            Err(err) => {
+                log_warn!(target: "keyboard", "bad keymap {}: {:?}", text.trim(), err);
+                Err(VfsError::InvalidArgument)
+            }
+        }
+    }
+}
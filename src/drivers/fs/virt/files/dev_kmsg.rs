@@ -0,0 +1,142 @@
+use alloc::{boxed::Box, sync::Arc};
+
+use crate::{
+    drivers::{
+        fs::virt::devfs::{VirtualDeviceFile, VirtualDeviceFileProvider},
+        vfs::{
+            arcrwb_new_from_box, Arcrwb, FileStat, PollEvent, PollEvents, SeekPosition, VfsError,
+            VfsFile, VfsFileKind, VfsSpecificFileData, FLAG_SYSTEM, FLAG_VIRTUAL,
+            FLAG_VIRTUAL_CHARACTER_DEVICE, OPEN_MODE_FAIL_IF_EXISTS,
+        },
+    },
+    log::{get_stdout, kmsg_write_level, KernelLogLevel},
+    permissions,
+};
+
+/// Each open file reads the kernel log from the oldest retained record
+#[derive(Debug)]
+pub struct DevKmsg {
+    next_sequence: u64,
+}
+
+#[derive(Debug)]
+pub struct DevKmsgProvider {
+    devfs_os_id: u64,
+}
+
+impl DevKmsgProvider {
+    pub fn new(devfs_os_id: u64) -> Self {
+        Self { devfs_os_id }
+    }
+}
+
+fn kmsg_stat() -> FileStat {
+    FileStat {
+        size: 0,
+        is_directory: false,
+        is_symlink: false,
+        is_file: true,
+        permissions: permissions!(Owner:Read, Owner:Write, Group:Read).to_u64(),
+        owner_id: 0,
+        group_id: 0,
+        created_at: 0,
+        modified_at: 0,
+        flags: FLAG_VIRTUAL | FLAG_VIRTUAL_CHARACTER_DEVICE | FLAG_SYSTEM,
+    }
+}
+
+/// Splits an optional "<N>" level prefix from a line written by userspace
+fn parse_kmsg_line(line: &[u8]) -> (KernelLogLevel, &[u8]) {
+    if let [b'<', level @ b'0'..=b'7', b'>', message @ ..] = line {
+        if let Some(level) = KernelLogLevel::from_u8(level - b'0') {
+            return (level, message);
+        }
+    }
+    (kmsg_write_level(), line)
+}
+
+impl VirtualDeviceFileProvider for DevKmsgProvider {
+    fn open(&mut self, mode: u64) -> Result<Arcrwb<dyn VirtualDeviceFile>, VfsError> {
+        if mode & OPEN_MODE_FAIL_IF_EXISTS != 0 {
+            Err(VfsError::FileAlreadyExists)
+        } else {
+            Ok(arcrwb_new_from_box(Box::new(DevKmsg { next_sequence: 0 })))
+        }
+    }
+
+    fn stat(&self) -> Result<FileStat, VfsError> {
+        Ok(kmsg_stat())
+    }
+
+    fn vfs_file(&self) -> Result<VfsFile, VfsError> {
+        Ok(VfsFile::new(
+            VfsFileKind::File,
+            "kmsg".chars().collect(),
+            0,
+            self.devfs_os_id,
+            self.devfs_os_id,
+            Arc::new(VfsSpecificFileData),
+        ))
+    }
+}
+
+impl VirtualDeviceFile for DevKmsg {
+    fn stat(&self) -> Result<FileStat, VfsError> {
+        Ok(kmsg_stat())
+    }
+
+    fn close(&mut self) -> Result<(), VfsError> {
+        Ok(())
+    }
+
+    /// Seeking to the start rewinds to the oldest retained record
+    fn seek(&mut self, position: SeekPosition) -> Result<u64, VfsError> {
+        match position {
+            SeekPosition::FromStart(0) => {
+                self.next_sequence = 0;
+                Ok(0)
+            }
+            SeekPosition::FromCurrent(0) => Ok(0),
+            _ => Err(VfsError::InvalidSeekPosition),
+        }
+    }
+
+    fn pos(&self) -> Result<u64, VfsError> {
+        Ok(0)
+    }
+
+    fn truncate(&mut self) -> Result<u64, VfsError> {
+        Err(VfsError::ActionNotAllowed)
+    }
+
+    /// Reads one record per call, fails if `buf` can't hold it whole
+    fn read(&mut self, buf: &mut [u8]) -> Result<u64, VfsError> {
+        let record = get_stdout()
+            .read_record(self.next_sequence)
+            .ok_or(VfsError::WouldBlock)?;
+        let line = record.format();
+        if line.len() > buf.len() {
+            return Err(VfsError::BadBufferSize);
+        }
+        buf[..line.len()].copy_from_slice(line.as_bytes());
+        self.next_sequence = record.sequence + 1;
+        Ok(line.len() as u64)
+    }
+
+    /// Every line becomes a record, records only go to the ring
+    fn write(&mut self, buf: &[u8]) -> Result<u64, VfsError> {
+        for line in buf.split(|c| *c == b'\n').filter(|line| !line.is_empty()) {
+            let (level, message) = parse_kmsg_line(line);
+            get_stdout().inject_record(level, message);
+        }
+        Ok(buf.len() as u64)
+    }
+
+    fn poll(&self, events: PollEvents) -> PollEvents {
+        let mut ready: PollEvents = PollEvent::Out.into();
+        if get_stdout().read_record(self.next_sequence).is_some() {
+            ready.set(PollEvent::In);
+        }
+        events & ready
+    }
+}
@@ -0,0 +1,129 @@
+use alloc::{boxed::Box, sync::Arc, vec::Vec};
+
+use crate::{
+    drivers::{
+        fs::virt::devfs::{fseek_helper, VirtualDeviceFile, VirtualDeviceFileProvider},
+        vfs::{
+            arcrwb_new_from_box, Arcrwb, FileStat, SeekPosition, VfsError, VfsFile, VfsFileKind,
+            VfsSpecificFileData, FLAG_SYSTEM, FLAG_VIRTUAL, OPEN_MODE_FAIL_IF_EXISTS,
+        },
+    },
+    log::{format_log_levels, set_log_level, LogLevel},
+    permissions,
+};
+
+/// Reads return the levels at the time the file was opened, writes take effect immediately
+#[derive(Debug)]
+pub struct DevLogLevel {
+    data: Vec<u8>,
+    position: u64,
+}
+
+#[derive(Debug)]
+pub struct DevLogLevelProvider {
+    devfs_os_id: u64,
+}
+
+impl DevLogLevelProvider {
+    pub fn new(devfs_os_id: u64) -> Self {
+        Self { devfs_os_id }
+    }
+}
+
+fn loglevel_stat(size: u64) -> FileStat {
+    FileStat {
+        size,
+        is_directory: false,
+        is_symlink: false,
+        is_file: true,
+        permissions: permissions!(Owner:Read, Owner:Write, Group:Read, Other:Read).to_u64(),
+        owner_id: 0,
+        group_id: 0,
+        created_at: 0,
+        modified_at: 0,
+        flags: FLAG_VIRTUAL | FLAG_SYSTEM,
+    }
+}
+
+/// Accepts "module=level", or a bare level (or "default=level") for modules without their own
+fn apply_loglevel_line(line: &str) -> Result<(), VfsError> {
+    let (target, level) = match line.split_once('=') {
+        Some(("default", level)) => (None, level),
+        Some((target, level)) => (Some(target.trim()), level),
+        None => (None, line),
+    };
+    if target == Some("") {
+        return Err(VfsError::InvalidArgument);
+    }
+    let level = LogLevel::from_name(level.trim()).ok_or(VfsError::InvalidArgument)?;
+    set_log_level(target, level)
+}
+
+impl VirtualDeviceFileProvider for DevLogLevelProvider {
+    fn open(&mut self, mode: u64) -> Result<Arcrwb<dyn VirtualDeviceFile>, VfsError> {
+        if mode & OPEN_MODE_FAIL_IF_EXISTS != 0 {
+            Err(VfsError::FileAlreadyExists)
+        } else {
+            Ok(arcrwb_new_from_box(Box::new(DevLogLevel {
+                data: format_log_levels().into_bytes(),
+                position: 0,
+            })))
+        }
+    }
+
+    fn stat(&self) -> Result<FileStat, VfsError> {
+        Ok(loglevel_stat(0))
+    }
+
+    fn vfs_file(&self) -> Result<VfsFile, VfsError> {
+        Ok(VfsFile::new(
+            VfsFileKind::File,
+            "loglevel".chars().collect(),
+            0,
+            self.devfs_os_id,
+            self.devfs_os_id,
+            Arc::new(VfsSpecificFileData),
+        ))
+    }
+}
+
+impl VirtualDeviceFile for DevLogLevel {
+    fn stat(&self) -> Result<FileStat, VfsError> {
+        Ok(loglevel_stat(self.data.len() as u64))
+    }
+
+    fn close(&mut self) -> Result<(), VfsError> {
+        Ok(())
+    }
+
+    fn seek(&mut self, position: SeekPosition) -> Result<u64, VfsError> {
+        self.position = fseek_helper(position, self.position, self.data.len() as u64)
+            .ok_or(VfsError::InvalidSeekPosition)?;
+        Ok(self.position)
+    }
+
+    fn pos(&self) -> Result<u64, VfsError> {
+        Ok(self.position)
+    }
+
+    fn truncate(&mut self) -> Result<u64, VfsError> {
+        Ok(0)
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> Result<u64, VfsError> {
+        let start = (self.position as usize).min(self.data.len());
+        let len = buf.len().min(self.data.len() - start);
+        buf[..len].copy_from_slice(&self.data[start..start + len]);
+        self.position += len as u64;
+        Ok(len as u64)
+    }
+
+    /// One setting per line, stops at the first invalid one
+    fn write(&mut self, buf: &[u8]) -> Result<u64, VfsError> {
+        let text = core::str::from_utf8(buf).map_err(|_| VfsError::InvalidArgument)?;
+        for line in text.lines().map(str::trim).filter(|line| !line.is_empty()) {
+            apply_loglevel_line(line)?;
+        }
+        Ok(buf.len() as u64)
+    }
+}
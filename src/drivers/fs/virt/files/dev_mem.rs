@@ -0,0 +1,132 @@
+use alloc::{boxed::Box, sync::Arc};
+
+use crate::{
+    drivers::{
+        fs::virt::devfs::{VirtualDeviceFile, VirtualDeviceFileProvider},
+        vfs::{
+            arcrwb_new_from_box, Arcrwb, FileStat, SeekPosition, VfsError, VfsFile, VfsFileKind,
+            VfsSpecificFileData, FLAG_SYSTEM, FLAG_VIRTUAL, FLAG_VIRTUAL_CHARACTER_DEVICE,
+            OPEN_MODE_FAIL_IF_EXISTS,
+        },
+    },
+    memory::mem::is_allocatable_memory,
+    paging::DIRECT_MAPPING_OFFSET,
+    permissions,
+};
+
+/// Physical memory, the position is the physical address. Only the memory given to the
+/// allocator can be accessed, the kernel image, boot page tables and reserved ranges can't
+#[derive(Debug)]
+pub struct DevMem {
+    position: u64,
+}
+
+#[derive(Debug)]
+pub struct DevMemProvider {
+    devfs_os_id: u64,
+}
+
+impl DevMemProvider {
+    pub fn new(devfs_os_id: u64) -> Self {
+        Self { devfs_os_id }
+    }
+}
+
+/// Only root can open it
+fn mem_stat() -> FileStat {
+    FileStat {
+        size: 0,
+        is_directory: false,
+        is_symlink: false,
+        is_file: true,
+        permissions: permissions!(Owner:Read, Owner:Write).to_u64(),
+        owner_id: 0,
+        group_id: 0,
+        created_at: 0,
+        modified_at: 0,
+        flags: FLAG_VIRTUAL | FLAG_VIRTUAL_CHARACTER_DEVICE | FLAG_SYSTEM,
+    }
+}
+
+impl VirtualDeviceFileProvider for DevMemProvider {
+    fn open(&mut self, mode: u64) -> Result<Arcrwb<dyn VirtualDeviceFile>, VfsError> {
+        if mode & OPEN_MODE_FAIL_IF_EXISTS != 0 {
+            Err(VfsError::FileAlreadyExists)
+        } else {
+            Ok(arcrwb_new_from_box(Box::new(DevMem { position: 0 })))
+        }
+    }
+
+    fn stat(&self) -> Result<FileStat, VfsError> {
+        Ok(mem_stat())
+    }
+
+    fn vfs_file(&self) -> Result<VfsFile, VfsError> {
+        Ok(VfsFile::new(
+            VfsFileKind::File,
+            "mem".chars().collect(),
+            0,
+            self.devfs_os_id,
+            self.devfs_os_id,
+            Arc::new(VfsSpecificFileData),
+        ))
+    }
+}
+
+impl DevMem {
+    /// Direct mapping of the `len` bytes at the position, which must all be accessible
+    fn mapping(&self, len: usize) -> Result<*mut u8, VfsError> {
+        let end = self
+            .position
+            .checked_add(len as u64)
+            .ok_or(VfsError::OutOfBounds)?;
+        if !is_allocatable_memory(self.position, end) {
+            return Err(VfsError::PermissionDenied);
+        }
+        Ok((self.position + DIRECT_MAPPING_OFFSET) as *mut u8)
+    }
+}
+
+impl VirtualDeviceFile for DevMem {
+    fn stat(&self) -> Result<FileStat, VfsError> {
+        Ok(mem_stat())
+    }
+
+    fn close(&mut self) -> Result<(), VfsError> {
+        Ok(())
+    }
+
+    fn seek(&mut self, position: SeekPosition) -> Result<u64, VfsError> {
+        self.position = match position {
+            SeekPosition::FromStart(position) => position,
+            SeekPosition::FromCurrent(offset) => self
+                .position
+                .checked_add_signed(offset)
+                .ok_or(VfsError::InvalidSeekPosition)?,
+            SeekPosition::FromEnd(_) => return Err(VfsError::InvalidSeekPosition),
+        };
+        Ok(self.position)
+    }
+
+    fn pos(&self) -> Result<u64, VfsError> {
+        Ok(self.position)
+    }
+
+    fn truncate(&mut self) -> Result<u64, VfsError> {
+        Err(VfsError::ActionNotAllowed)
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> Result<u64, VfsError> {
+        let source = self.mapping(buf.len())?;
+        unsafe { core::ptr::copy_nonoverlapping(source, buf.as_mut_ptr(), buf.len()) };
+        self.position += buf.len() as u64;
+        Ok(buf.len() as u64)
+    }
+
+    fn write(&mut self, buf: &[u8]) -> Result<u64, VfsError> {
+        let destination = self.mapping(buf.len())?;
+        unsafe { core::ptr::copy_nonoverlapping(buf.as_ptr(), destination, buf.len()) };
+        self.position += buf.len() as u64;
+        Ok(buf.len() as u64)
+    }
+}
@@ -0,0 +1,110 @@
+use alloc::{boxed::Box, sync::Arc, vec::Vec};
+
+use crate::{
+    drivers::{
+        fs::virt::devfs::{fseek_helper, VirtualDeviceFile, VirtualDeviceFileProvider},
+        vfs::{
+            arcrwb_new_from_box, Arcrwb, FileStat, SeekPosition, VfsError, VfsFile, VfsFileKind,
+            VfsSpecificFileData, FLAG_SYSTEM, FLAG_VIRTUAL, OPEN_MODE_FAIL_IF_EXISTS,
+        },
+    },
+    net::format_config,
+    permissions,
+};
+
+/// The network configuration at the time the file was opened
+#[derive(Debug)]
+pub struct DevNetConfig {
+    data: Vec<u8>,
+    position: u64,
+}
+
+#[derive(Debug)]
+pub struct DevNetConfigProvider {
+    devfs_os_id: u64,
+}
+
+impl DevNetConfigProvider {
+    pub fn new(devfs_os_id: u64) -> Self {
+        Self { devfs_os_id }
+    }
+}
+
+fn net_config_stat(size: u64) -> FileStat {
+    FileStat {
+        size,
+        is_directory: false,
+        is_symlink: false,
+        is_file: true,
+        permissions: permissions!(Owner:Read, Group:Read, Other:Read).to_u64(),
+        owner_id: 0,
+        group_id: 0,
+        created_at: 0,
+        modified_at: 0,
+        flags: FLAG_VIRTUAL | FLAG_SYSTEM,
+    }
+}
+
+impl VirtualDeviceFileProvider for DevNetConfigProvider {
+    fn open(&mut self, mode: u64) -> Result<Arcrwb<dyn VirtualDeviceFile>, VfsError> {
+        if mode & OPEN_MODE_FAIL_IF_EXISTS != 0 {
+            Err(VfsError::FileAlreadyExists)
+        } else {
+            Ok(arcrwb_new_from_box(Box::new(DevNetConfig {
+                data: format_config().into_bytes(),
+                position: 0,
+            })))
+        }
+    }
+
+    fn stat(&self) -> Result<FileStat, VfsError> {
+        Ok(net_config_stat(0))
+    }
+
+    fn vfs_file(&self) -> Result<VfsFile, VfsError> {
+        Ok(VfsFile::new(
+            VfsFileKind::File,
+            "config".chars().collect(),
+            0,
+            self.devfs_os_id,
+            self.devfs_os_id,
+            Arc::new(VfsSpecificFileData),
+        ))
+    }
+}
+
+impl VirtualDeviceFile for DevNetConfig {
+    fn stat(&self) -> Result<FileStat, VfsError> {
+        Ok(net_config_stat(self.data.len() as u64))
+    }
+
+    fn close(&mut self) -> Result<(), VfsError> {
+        Ok(())
+    }
+
+    fn seek(&mut self, position: SeekPosition) -> Result<u64, VfsError> {
+        self.position = fseek_helper(position, self.position, self.data.len() as u64)
+            .ok_or(VfsError::InvalidSeekPosition)?;
+        Ok(self.position)
+    }
+
+    fn pos(&self) -> Result<u64, VfsError> {
+        Ok(self.position)
+    }
+
+    fn truncate(&mut self) -> Result<u64, VfsError> {
+        Err(VfsError::ActionNotAllowed)
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> Result<u64, VfsError> {
+        let start = (self.position as usize).min(self.data.len());
+        let len = buf.len().min(self.data.len() - start);
+        buf[..len].copy_from_slice(&self.data[start..start + len]);
+        self.position += len as u64;
+        Ok(len as u64)
+    }
+
+    fn write(&mut self, _buf: &[u8]) -> Result<u64, VfsError> {
+        Err(VfsError::ActionNotAllowed)
+    }
+}
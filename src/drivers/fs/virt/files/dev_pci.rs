@@ -0,0 +1,423 @@
+//! `/dev/pci/<bus>/<device>.<function>/`, one directory per device of the last PCI scan, numbers
+//! in hex. Each has `config` for the config space, `vendor`, `device` and `class` as text, and a
+//! `resource<N>` per implemented BAR: its start, length and flags, the linux `IORESOURCE_*` ones.
+//! Writing anything to `/dev/pci/rescan` scans the buses again, the tree follows on the next
+//! lookup.
+
+use alloc::{boxed::Box, format, string::String, sync::Arc, vec::Vec};
+
+use crate::{
+    drivers::{
+        fs::virt::devfs::{fseek_helper, DevFs, VirtualDeviceFile, VirtualDeviceFileProvider},
+        pci::{self, PciBar, PciDevice, PciScanEntry, PCI_CONFIG_SPACE_SIZE},
+        vfs::{
+            arcrwb_new_from_box, Arcrwb, FileStat, FileSystem, SeekPosition, VfsError, VfsFile,
+            VfsFileKind, VfsSpecificFileData, FLAG_SYSTEM, FLAG_VIRTUAL, OPEN_MODE_FAIL_IF_EXISTS,
+        },
+    },
+    permissions,
+};
+
+const IORESOURCE_IO: u64 = 0x100;
+const IORESOURCE_MEM: u64 = 0x200;
+const IORESOURCE_PREFETCH: u64 = 0x2000;
+const IORESOURCE_MEM_64: u64 = 0x0010_0000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PciAttribute {
+    Config,
+    Vendor,
+    Device,
+    Class,
+    /// Described BAR
+    Resource(u8),
+}
+
+impl PciAttribute {
+    fn name(&self) -> String {
+        match self {
+            Self::Config => "config".into(),
+            Self::Vendor => "vendor".into(),
+            Self::Device => "device".into(),
+            Self::Class => "class".into(),
+            Self::Resource(n) => format!("resource{n}"),
+        }
+    }
+}
+
+/// Directory of `device` in `/dev/pci`
+pub fn pci_device_path(device: &PciDevice) -> String {
+    format!(
+        "pci/{:02x}/{:02x}.{:x}",
+        device.bus, device.device, device.function
+    )
+}
+
+/// "start length flags", in hex
+pub fn format_resource(bar: &PciBar) -> String {
+    let (start, length, flags) = match *bar {
+        PciBar::Memory {
+            address,
+            size,
+            prefetchable,
+            is_64bit,
+        } => {
+            let mut flags = IORESOURCE_MEM;
+            if prefetchable {
+                flags |= IORESOURCE_PREFETCH;
+            }
+            if is_64bit {
+                flags |= IORESOURCE_MEM_64;
+            }
+            (address, size, flags)
+        }
+        PciBar::Io { port, size } => (port as u64, size as u64, IORESOURCE_IO),
+    };
+    format!("{start:#018x} {length:#018x} {flags:#010x}\n")
+}
+
+/// Fails once the device is gone from the last scan or stops answering
+fn check_present(device: &PciDevice) -> Result<(), VfsError> {
+    let scanned = pci::find_entry(device.bus, device.device, device.function)
+        .is_some_and(|entry| entry.device == *device);
+    if scanned && device.is_present() {
+        Ok(())
+    } else {
+        Err(VfsError::NoSuchDevice)
+    }
+}
+
+fn attribute_stat(attribute: PciAttribute, size: u64) -> FileStat {
+    let permissions = match attribute {
+        PciAttribute::Config => permissions!(Owner:Read, Owner:Write, Group:Read, Other:Read),
+        _ => permissions!(Owner:Read, Group:Read, Other:Read),
+    };
+    FileStat {
+        size,
+        is_directory: false,
+        is_symlink: false,
+        is_file: true,
+        permissions: permissions.to_u64(),
+        owner_id: 0,
+        group_id: 0,
+        created_at: 0,
+        modified_at: 0,
+        flags: FLAG_VIRTUAL | FLAG_SYSTEM,
+    }
+}
+
+#[derive(Debug)]
+pub struct DevPciProvider {
+    devfs_os_id: u64,
+    entry: PciScanEntry,
+    attribute: PciAttribute,
+}
+
+impl DevPciProvider {
+    pub fn new(devfs_os_id: u64, entry: PciScanEntry, attribute: PciAttribute) -> Self {
+        Self {
+            devfs_os_id,
+            entry,
+            attribute,
+        }
+    }
+
+    /// Text of the attributes other than the config space
+    fn text(&self) -> Vec<u8> {
+        let device = &self.entry.device;
+        match self.attribute {
+            PciAttribute::Config => Vec::new(),
+            PciAttribute::Vendor => format!("{:#06x}\n", device.vendor_id).into_bytes(),
+            PciAttribute::Device => format!("{:#06x}\n", device.device_id).into_bytes(),
+            PciAttribute::Class => format!(
+                "{:#08x}\n",
+                (device.class as u32) << 16 | (device.subclass as u32) << 8 | device.prog_if as u32
+            )
+            .into_bytes(),
+            PciAttribute::Resource(n) => self.entry.bars[n as usize]
+                .as_ref()
+                .map(format_resource)
+                .unwrap_or_default()
+                .into_bytes(),
+        }
+    }
+}
+
+impl VirtualDeviceFileProvider for DevPciProvider {
+    fn open(&mut self, mode: u64) -> Result<Arcrwb<dyn VirtualDeviceFile>, VfsError> {
+        if mode & OPEN_MODE_FAIL_IF_EXISTS != 0 {
+            return Err(VfsError::FileAlreadyExists);
+        }
+        check_present(&self.entry.device)?;
+        Ok(match self.attribute {
+            PciAttribute::Config => arcrwb_new_from_box(Box::new(DevPciConfig {
+                device: self.entry.device,
+                position: 0,
+            })),
+            attribute => arcrwb_new_from_box(Box::new(DevPciText {
+                attribute,
+                data: self.text(),
+                position: 0,
+            })),
+        })
+    }
+
+    fn stat(&self) -> Result<FileStat, VfsError> {
+        let size = match self.attribute {
+            PciAttribute::Config => PCI_CONFIG_SPACE_SIZE,
+            _ => self.text().len(),
+        };
+        Ok(attribute_stat(self.attribute, size as u64))
+    }
+
+    fn vfs_file(&self) -> Result<VfsFile, VfsError> {
+        Ok(VfsFile::new(
+            VfsFileKind::File,
+            self.attribute.name().chars().collect(),
+            0,
+            self.devfs_os_id,
+            self.devfs_os_id,
+            Arc::new(VfsSpecificFileData),
+        ))
+    }
+}
+
+/// The text of an attribute when the file was opened
+#[derive(Debug)]
+pub struct DevPciText {
+    attribute: PciAttribute,
+    data: Vec<u8>,
+    position: u64,
+}
+
+impl VirtualDeviceFile for DevPciText {
+    fn stat(&self) -> Result<FileStat, VfsError> {
+        Ok(attribute_stat(self.attribute, self.data.len() as u64))
+    }
+
+    fn close(&mut self) -> Result<(), VfsError> {
+        Ok(())
+    }
+
+    fn seek(&mut self, position: SeekPosition) -> Result<u64, VfsError> {
+        self.position = fseek_helper(position, self.position, self.data.len() as u64)
+            .ok_or(VfsError::InvalidSeekPosition)?;
+        Ok(self.position)
+    }
+
+    fn pos(&self) -> Result<u64, VfsError> {
+        Ok(self.position)
+    }
+
+    fn truncate(&mut self) -> Result<u64, VfsError> {
+        Err(VfsError::ActionNotAllowed)
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> Result<u64, VfsError> {
+        let start = (self.position as usize).min(self.data.len());
+        let len = buf.len().min(self.data.len() - start);
+        buf[..len].copy_from_slice(&self.data[start..start + len]);
+        self.position += len as u64;
+        Ok(len as u64)
+    }
+
+    fn write(&mut self, _buf: &[u8]) -> Result<u64, VfsError> {
+        Err(VfsError::ActionNotAllowed)
+    }
+}
+
+/// The config space, read and written a dword at a time through the configuration ports
+#[derive(Debug)]
+pub struct DevPciConfig {
+    device: PciDevice,
+    position: u64,
+}
+
+impl DevPciConfig {
+    /// Bytes between the position and the end of the config space, at most `len`
+    fn range(&self, len: usize) -> core::ops::Range<usize> {
+        let start = (self.position as usize).min(PCI_CONFIG_SPACE_SIZE);
+        start..start + len.min(PCI_CONFIG_SPACE_SIZE - start)
+    }
+}
+
+impl VirtualDeviceFile for DevPciConfig {
+    fn stat(&self) -> Result<FileStat, VfsError> {
+        Ok(attribute_stat(
+            PciAttribute::Config,
+            PCI_CONFIG_SPACE_SIZE as u64,
+        ))
+    }
+
+    fn close(&mut self) -> Result<(), VfsError> {
+        Ok(())
+    }
+
+    fn seek(&mut self, position: SeekPosition) -> Result<u64, VfsError> {
+        self.position = fseek_helper(position, self.position, PCI_CONFIG_SPACE_SIZE as u64)
+            .ok_or(VfsError::InvalidSeekPosition)?;
+        Ok(self.position)
+    }
+
+    fn pos(&self) -> Result<u64, VfsError> {
+        Ok(self.position)
+    }
+
+    fn truncate(&mut self) -> Result<u64, VfsError> {
+        Err(VfsError::ActionNotAllowed)
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> Result<u64, VfsError> {
+        check_present(&self.device)?;
+        let range = self.range(buf.len());
+        for (out, offset) in buf.iter_mut().zip(range.clone()) {
+            let dword = self.device.read_config((offset & !3) as u8);
+            *out = (dword >> ((offset % 4) * 8)) as u8;
+        }
+        self.position += range.len() as u64;
+        Ok(range.len() as u64)
+    }
+
+    /// Bytes that don't cover a whole dword are merged with the rest of it, read back first
+    fn write(&mut self, buf: &[u8]) -> Result<u64, VfsError> {
+        check_present(&self.device)?;
+        let range = self.range(buf.len());
+        if range.is_empty() && !buf.is_empty() {
+            return Err(VfsError::OutOfSpace);
+        }
+        let mut offset = range.start;
+        while offset < range.end {
+            let aligned = offset & !3;
+            let end = (aligned + 4).min(range.end);
+            let mut bytes = if offset == aligned && end == aligned + 4 {
+                [0; 4]
+            } else {
+                self.device.read_config(aligned as u8).to_le_bytes()
+            };
+            bytes[offset - aligned..end - aligned]
+                .copy_from_slice(&buf[offset - range.start..end - range.start]);
+            self.device
+                .write_config(aligned as u8, u32::from_le_bytes(bytes));
+            offset = end;
+        }
+        self.position += range.len() as u64;
+        Ok(range.len() as u64)
+    }
+}
+
+/// Writing anything to it rescans the buses
+#[derive(Debug)]
+pub struct DevPciRescanProvider {
+    devfs_os_id: u64,
+}
+
+impl DevPciRescanProvider {
+    pub fn new(devfs_os_id: u64) -> Self {
+        Self { devfs_os_id }
+    }
+}
+
+fn rescan_stat() -> FileStat {
+    FileStat {
+        size: 0,
+        is_directory: false,
+        is_symlink: false,
+        is_file: true,
+        permissions: permissions!(Owner:Write).to_u64(),
+        owner_id: 0,
+        group_id: 0,
+        created_at: 0,
+        modified_at: 0,
+        flags: FLAG_VIRTUAL | FLAG_SYSTEM,
+    }
+}
+
+impl VirtualDeviceFileProvider for DevPciRescanProvider {
+    fn open(&mut self, mode: u64) -> Result<Arcrwb<dyn VirtualDeviceFile>, VfsError> {
+        if mode & OPEN_MODE_FAIL_IF_EXISTS != 0 {
+            return Err(VfsError::FileAlreadyExists);
+        }
+        Ok(arcrwb_new_from_box(Box::new(DevPciRescan)))
+    }
+
+    fn stat(&self) -> Result<FileStat, VfsError> {
+        Ok(rescan_stat())
+    }
+
+    fn vfs_file(&self) -> Result<VfsFile, VfsError> {
+        Ok(VfsFile::new(
+            VfsFileKind::File,
+            "rescan".chars().collect(),
+            0,
+            self.devfs_os_id,
+            self.devfs_os_id,
+            Arc::new(VfsSpecificFileData),
+        ))
+    }
+}
+
+#[derive(Debug)]
+pub struct DevPciRescan;
+
+impl VirtualDeviceFile for DevPciRescan {
+    fn stat(&self) -> Result<FileStat, VfsError> {
+        Ok(rescan_stat())
+    }
+
+    fn close(&mut self) -> Result<(), VfsError> {
+        Ok(())
+    }
+
+    fn seek(&mut self, _position: SeekPosition) -> Result<u64, VfsError> {
+        Ok(0)
+    }
+
+    fn pos(&self) -> Result<u64, VfsError> {
+        Ok(0)
+    }
+
+    fn truncate(&mut self) -> Result<u64, VfsError> {
+        Ok(0)
+    }
+
+    fn read(&mut self, _buf: &mut [u8]) -> Result<u64, VfsError> {
+        Ok(0)
+    }
+
+    fn write(&mut self, buf: &[u8]) -> Result<u64, VfsError> {
+        if !buf.is_empty() {
+            pci::rescan();
+        }
+        Ok(buf.len() as u64)
+    }
+}
+
+/// Adds the files of the devices of the last scan and the rescan file
+pub fn insert_pci_files(devfs: &mut DevFs) {
+    let os_id = devfs.os_id();
+    devfs.insert_vfile(
+        arcrwb_new_from_box(Box::new(DevPciRescanProvider::new(os_id))),
+        &"pci/rescan".chars().collect::<Vec<char>>(),
+    );
+    for entry in pci::scan_entries_snapshot() {
+        let directory = pci_device_path(&entry.device);
+        let resources = (0..entry.bars.len() as u8)
+            .filter(|n| entry.bars[*n as usize].is_some())
+            .map(PciAttribute::Resource);
+        for attribute in [
+            PciAttribute::Config,
+            PciAttribute::Vendor,
+            PciAttribute::Device,
+            PciAttribute::Class,
+        ]
+        .into_iter()
+        .chain(resources)
+        {
+            let path = format!("{}/{}", directory, attribute.name());
+            devfs.insert_vfile(
+                arcrwb_new_from_box(Box::new(DevPciProvider::new(os_id, entry, attribute))),
+                &path.chars().collect::<Vec<char>>(),
+            );
+        }
+    }
+}
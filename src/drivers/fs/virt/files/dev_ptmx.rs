@@ -0,0 +1,128 @@
+use alloc::{boxed::Box, sync::Arc};
+
+use crate::{
+    drivers::{
+        fs::virt::{
+            devfs::{VirtualDeviceFile, VirtualDeviceFileProvider},
+            ptyfs::{alloc_pty, Pty},
+        },
+        vfs::{
+            arcrwb_new_from_box, Arcrwb, FileStat, PollEvents, SeekPosition, VfsError, VfsFile,
+            VfsFileKind, VfsSpecificFileData, FLAG_SYSTEM, FLAG_VIRTUAL,
+            FLAG_VIRTUAL_CHARACTER_DEVICE, OPEN_MODE_FAIL_IF_EXISTS, OPEN_MODE_NONBLOCK,
+            POLL_ALWAYS_REPORTED,
+        },
+    },
+    permissions,
+    process::wait::WaitQueue,
+};
+
+/// Master end of a pseudo terminal, every open allocates a new pair
+#[derive(Debug)]
+pub struct DevPtmx {
+    pty: Arc<Pty>,
+    nonblocking: bool,
+}
+
+#[derive(Debug)]
+pub struct DevPtmxProvider {
+    devfs_os_id: u64,
+}
+
+impl DevPtmxProvider {
+    pub fn new(devfs_os_id: u64) -> Self {
+        Self { devfs_os_id }
+    }
+}
+
+fn ptmx_stat() -> FileStat {
+    FileStat {
+        size: 0,
+        is_directory: false,
+        is_symlink: false,
+        is_file: true,
+        permissions:
+            permissions!(Owner:Read, Owner:Write, Group:Read, Group:Write, Other:Read, Other:Write)
+                .to_u64(),
+        owner_id: 0,
+        group_id: 0,
+        created_at: 0,
+        modified_at: 0,
+        flags: FLAG_VIRTUAL | FLAG_VIRTUAL_CHARACTER_DEVICE | FLAG_SYSTEM,
+    }
+}
+
+impl VirtualDeviceFileProvider for DevPtmxProvider {
+    fn open(&mut self, mode: u64) -> Result<Arcrwb<dyn VirtualDeviceFile>, VfsError> {
+        if mode & OPEN_MODE_FAIL_IF_EXISTS != 0 {
+            Err(VfsError::FileAlreadyExists)
+        } else {
+            Ok(arcrwb_new_from_box(Box::new(DevPtmx {
+                pty: alloc_pty(),
+                nonblocking: mode & OPEN_MODE_NONBLOCK != 0,
+            })))
+        }
+    }
+
+    fn stat(&self) -> Result<FileStat, VfsError> {
+        Ok(ptmx_stat())
+    }
+
+    fn vfs_file(&self) -> Result<VfsFile, VfsError> {
+        Ok(VfsFile::new(
+            VfsFileKind::File,
+            "ptmx".chars().collect(),
+            0,
+            self.devfs_os_id,
+            self.devfs_os_id,
+            Arc::new(VfsSpecificFileData),
+        ))
+    }
+}
+
+impl VirtualDeviceFile for DevPtmx {
+    fn stat(&self) -> Result<FileStat, VfsError> {
+        Ok(ptmx_stat())
+    }
+
+    fn close(&mut self) -> Result<(), VfsError> {
+        self.pty.close_master();
+        Ok(())
+    }
+
+    fn seek(&mut self, _position: SeekPosition) -> Result<u64, VfsError> {
+        Err(VfsError::InvalidSeekPosition)
+    }
+
+    fn pos(&self) -> Result<u64, VfsError> {
+        Ok(0)
+    }
+
+    fn truncate(&mut self) -> Result<u64, VfsError> {
+        Err(VfsError::ActionNotAllowed)
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> Result<u64, VfsError> {
+        self.pty.master_read(buf)
+    }
+
+    fn write(&mut self, buf: &[u8]) -> Result<u64, VfsError> {
+        self.pty.master_write(buf)
+    }
+
+    fn poll(&self, events: PollEvents) -> PollEvents {
+        self.pty.master_poll() & (events | POLL_ALWAYS_REPORTED)
+    }
+
+    fn wait_queue(&self) -> Option<Arc<WaitQueue>> {
+        Some(self.pty.wait_queue())
+    }
+
+    fn blocks(&self) -> bool {
+        !self.nonblocking
+    }
+
+    fn ioctl(&mut self, request: u64, arg: &mut [u8]) -> Result<u64, VfsError> {
+        self.pty.ioctl(true, request, arg)
+    }
+}
@@ -0,0 +1,115 @@
+use alloc::{boxed::Box, sync::Arc};
+
+use crate::{
+    crypto::rng,
+    drivers::{
+        fs::virt::devfs::{VirtualDeviceFile, VirtualDeviceFileProvider},
+        vfs::{
+            arcrwb_new_from_box, Arcrwb, FileStat, SeekPosition, VfsError, VfsFile, VfsFileKind,
+            VfsSpecificFileData, FLAG_SYSTEM, FLAG_VIRTUAL, FLAG_VIRTUAL_CHARACTER_DEVICE,
+            OPEN_MODE_FAIL_IF_EXISTS,
+        },
+    },
+    permissions,
+};
+
+#[derive(Debug)]
+pub struct DevRandom;
+
+/// Backs both `/dev/random` and `/dev/urandom`, reads never block
+#[derive(Debug)]
+pub struct DevRandomProvider {
+    devfs_os_id: u64,
+    name: &'static str,
+}
+
+impl DevRandomProvider {
+    pub fn new(devfs_os_id: u64, name: &'static str) -> Self {
+        Self { devfs_os_id, name }
+    }
+}
+
+impl VirtualDeviceFileProvider for DevRandomProvider {
+    fn open(&mut self, mode: u64) -> Result<Arcrwb<dyn VirtualDeviceFile>, VfsError> {
+        if mode & OPEN_MODE_FAIL_IF_EXISTS != 0 {
+            Err(VfsError::FileAlreadyExists)
+        } else {
+            Ok(arcrwb_new_from_box(Box::new(DevRandom)))
+        }
+    }
+
+    fn stat(&self) -> Result<FileStat, VfsError> {
+        Ok(FileStat {
+            size: 0,
+            is_directory: false,
+            is_symlink: false,
+            is_file: true,
+            permissions: permissions!(Owner:Read, Owner:Write, Group:Read, Other:Read).to_u64(),
+            owner_id: 0,
+            group_id: 0,
+            created_at: 0,
+            modified_at: 0,
+            flags: FLAG_VIRTUAL | FLAG_VIRTUAL_CHARACTER_DEVICE | FLAG_SYSTEM,
+        })
+    }
+
+    fn vfs_file(&self) -> Result<VfsFile, VfsError> {
+        Ok(VfsFile::new(
+            VfsFileKind::File,
+            self.name.chars().collect(),
+            0,
+            self.devfs_os_id,
+            self.devfs_os_id,
+            Arc::new(VfsSpecificFileData),
+        ))
+    }
+}
+
+impl VirtualDeviceFile for DevRandom {
+    fn stat(&self) -> Result<FileStat, VfsError> {
+        Ok(FileStat {
+            size: 0,
+            is_directory: false,
+            is_symlink: false,
+            is_file: true,
+            permissions: permissions!(Owner:Read, Owner:Write, Group:Read, Other:Read).to_u64(),
+            owner_id: 0,
+            group_id: 0,
+            created_at: 0,
+            modified_at: 0,
+            flags: FLAG_VIRTUAL | FLAG_VIRTUAL_CHARACTER_DEVICE | FLAG_SYSTEM,
+        })
+    }
+
+    fn close(&mut self) -> Result<(), VfsError> {
+        Ok(())
+    }
+
+    fn seek(&mut self, position: SeekPosition) -> Result<u64, VfsError> {
+        if matches!(
+            position,
+            SeekPosition::FromStart(0) | SeekPosition::FromCurrent(0) | SeekPosition::FromEnd(0)
+        ) {
+            Ok(0)
+        } else {
+            Err(VfsError::InvalidSeekPosition)
+        }
+    }
+
+    fn pos(&self) -> Result<u64, VfsError> {
+        Ok(0)
+    }
+
+    fn truncate(&mut self) -> Result<u64, VfsError> {
+        Ok(0)
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> Result<u64, VfsError> {
+        rng::fill_bytes(buf);
+        Ok(buf.len() as u64)
+    }
+
+    fn write(&mut self, buf: &[u8]) -> Result<u64, VfsError> {
+        Ok(buf.len() as u64)
+    }
+}
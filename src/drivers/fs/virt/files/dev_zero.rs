@@ -0,0 +1,111 @@
+use alloc::{boxed::Box, sync::Arc};
+
+use crate::{
+    drivers::{
+        fs::virt::devfs::{VirtualDeviceFile, VirtualDeviceFileProvider},
+        vfs::{
+            arcrwb_new_from_box, Arcrwb, FileStat, SeekPosition, VfsError, VfsFile, VfsFileKind,
+            VfsSpecificFileData, FLAG_SYSTEM, FLAG_VIRTUAL, FLAG_VIRTUAL_CHARACTER_DEVICE,
+            OPEN_MODE_FAIL_IF_EXISTS,
+        },
+    },
+    permissions,
+};
+
+/// Reads return zeros. Writes are discarded, or fail with `OutOfSpace` for `/dev/full`
+#[derive(Debug)]
+pub struct DevZero {
+    full: bool,
+}
+
+/// Backs both `/dev/zero` and `/dev/full`
+#[derive(Debug)]
+pub struct DevZeroProvider {
+    devfs_os_id: u64,
+    full: bool,
+}
+
+impl DevZeroProvider {
+    pub fn new(devfs_os_id: u64, full: bool) -> Self {
+        Self { devfs_os_id, full }
+    }
+}
+
+fn zero_stat() -> FileStat {
+    FileStat {
+        size: 0,
+        is_directory: false,
+        is_symlink: false,
+        is_file: true,
+        permissions:
+            permissions!(Owner:Read, Owner:Write, Group:Read, Group:Write, Other:Read, Other:Write)
+                .to_u64(),
+        owner_id: 0,
+        group_id: 0,
+        created_at: 0,
+        modified_at: 0,
+        flags: FLAG_VIRTUAL | FLAG_VIRTUAL_CHARACTER_DEVICE | FLAG_SYSTEM,
+    }
+}
+
+impl VirtualDeviceFileProvider for DevZeroProvider {
+    fn open(&mut self, mode: u64) -> Result<Arcrwb<dyn VirtualDeviceFile>, VfsError> {
+        if mode & OPEN_MODE_FAIL_IF_EXISTS != 0 {
+            Err(VfsError::FileAlreadyExists)
+        } else {
+            Ok(arcrwb_new_from_box(Box::new(DevZero { full: self.full })))
+        }
+    }
+
+    fn stat(&self) -> Result<FileStat, VfsError> {
+        Ok(zero_stat())
+    }
+
+    fn vfs_file(&self) -> Result<VfsFile, VfsError> {
+        let name = if self.full { "full" } else { "zero" };
+        Ok(VfsFile::new(
+            VfsFileKind::File,
+            name.chars().collect(),
+            0,
+            self.devfs_os_id,
+            self.devfs_os_id,
+            Arc::new(VfsSpecificFileData),
+        ))
+    }
+}
+
+impl VirtualDeviceFile for DevZero {
+    fn stat(&self) -> Result<FileStat, VfsError> {
+        Ok(zero_stat())
+    }
+
+    fn close(&mut self) -> Result<(), VfsError> {
+        Ok(())
+    }
+
+    /// Any position is accepted, it stays 0
+    fn seek(&mut self, _position: SeekPosition) -> Result<u64, VfsError> {
+        Ok(0)
+    }
+
+    fn pos(&self) -> Result<u64, VfsError> {
+        Ok(0)
+    }
+
+    fn truncate(&mut self) -> Result<u64, VfsError> {
+        Ok(0)
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> Result<u64, VfsError> {
+        buf.fill(0);
+        Ok(buf.len() as u64)
+    }
+
+    fn write(&mut self, buf: &[u8]) -> Result<u64, VfsError> {
+        if self.full {
+            Err(VfsError::OutOfSpace)
+        } else {
+            Ok(buf.len() as u64)
+        }
+    }
+}
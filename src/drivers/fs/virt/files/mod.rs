@@ -1,17 +1,99 @@
-use alloc::boxed::Box;
+use alloc::{boxed::Box, vec::Vec};
 
 use crate::drivers::{
-    fs::virt::{devfs::DevFs, files::dev_null::DevNullProvider},
-    vfs::{arcrwb_new_from_box, FileSystem},
+    fs::virt::{
+        devfs::DevFs,
+        files::{
+            dev_events::DevEventsProvider, dev_keymap::DevKeymapProvider,
+            dev_kmsg::DevKmsgProvider, dev_loglevel::DevLogLevelProvider, dev_mem::DevMemProvider,
+            dev_net_config::DevNetConfigProvider, dev_null::DevNull, dev_ptmx::DevPtmxProvider,
+            dev_random::DevRandomProvider, dev_zero::DevZeroProvider,
+        },
+    },
+    vfs::{arcrwb_new_from_box, DeviceNumber, FileSystem},
 };
 
+pub mod dev_events;
+pub mod dev_keymap;
+pub mod dev_kmsg;
+pub mod dev_loglevel;
+pub mod dev_mem;
+pub mod dev_net_config;
 pub mod dev_null;
+pub mod dev_pci;
+pub mod dev_ptmx;
+pub mod dev_random;
+pub mod dev_zero;
 
 pub fn init_vfiles(devfs: &mut DevFs) {
     let os_id = devfs.os_id();
 
     devfs.insert_vfile(
-        arcrwb_new_from_box(Box::new(DevNullProvider::new(os_id))),
+        arcrwb_new_from_box(Box::new(DevNull::provider(os_id))),
         &['n', 'u', 'l', 'l'],
     );
+    devfs.insert_vfile(
+        arcrwb_new_from_box(Box::new(DevZeroProvider::new(os_id, false))),
+        &['z', 'e', 'r', 'o'],
+    );
+    devfs.insert_vfile(
+        arcrwb_new_from_box(Box::new(DevZeroProvider::new(os_id, true))),
+        &['f', 'u', 'l', 'l'],
+    );
+    devfs.insert_vfile(
+        arcrwb_new_from_box(Box::new(DevMemProvider::new(os_id))),
+        &['m', 'e', 'm'],
+    );
+    devfs.insert_vfile(
+        arcrwb_new_from_box(Box::new(DevRandomProvider::new(os_id, "random"))),
+        &['r', 'a', 'n', 'd', 'o', 'm'],
+    );
+    devfs.insert_vfile(
+        arcrwb_new_from_box(Box::new(DevRandomProvider::new(os_id, "urandom"))),
+        &['u', 'r', 'a', 'n', 'd', 'o', 'm'],
+    );
+    devfs.insert_vfile(
+        arcrwb_new_from_box(Box::new(DevEventsProvider::new(os_id))),
+        &['e', 'v', 'e', 'n', 't', 's'],
+    );
+    devfs.insert_vfile(
+        arcrwb_new_from_box(Box::new(DevKmsgProvider::new(os_id))),
+        &['k', 'm', 's', 'g'],
+    );
+    devfs.insert_vfile(
+        arcrwb_new_from_box(Box::new(DevLogLevelProvider::new(os_id))),
+        &"loglevel".chars().collect::<Vec<char>>(),
+    );
+    devfs.insert_vfile(
+        arcrwb_new_from_box(Box::new(DevKeymapProvider::new(os_id))),
+        &"keymap".chars().collect::<Vec<char>>(),
+    );
+    devfs.insert_vfile(
+        arcrwb_new_from_box(Box::new(DevNetConfigProvider::new(os_id))),
+        &"net/config".chars().collect::<Vec<char>>(),
+    );
+
+    devfs.insert_vfile(
+        arcrwb_new_from_box(Box::new(DevPtmxProvider::new(os_id))),
+        &"ptmx".chars().collect::<Vec<char>>(),
+    );
+    // Mount point of the ptyfs
+    devfs.insert_directory(&"pts".chars().collect::<Vec<char>>());
+
+    // Same numbers as the linux memory devices
+    for (minor, name) in [
+        (1, "mem"),
+        (3, "null"),
+        (5, "zero"),
+        (7, "full"),
+        (8, "random"),
+        (9, "urandom"),
+        (11, "kmsg"),
+    ] {
+        devfs.register_device_number(
+            false,
+            DeviceNumber::new(1, minor),
+            &name.chars().collect::<Vec<char>>(),
+        );
+    }
 }
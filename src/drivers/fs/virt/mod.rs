@@ -1,3 +1,9 @@
+pub mod bind;
 pub mod devfs;
 pub mod files;
 pub mod pipefs;
+pub mod procfs;
+pub mod ptyfs;
+pub mod timerfd;
+pub mod tmpfs;
+pub mod unixsock;
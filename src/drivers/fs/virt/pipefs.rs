@@ -3,18 +3,23 @@ use alloc::string::ToString;
 use alloc::sync::Arc;
 use alloc::vec;
 use alloc::{boxed::Box, string::String, vec::Vec};
-use spin::rwlock::RwLock;
 
 use crate::data::file::File;
-use crate::data::{calloc_boxed_slice, decimal_chars_to_u64};
+use crate::data::path::VfsName;
+use crate::data::{calloc_boxed_slice, decimal_chars_to_u64, try_alloc_boxed_slice_or_reclaim};
 use crate::drivers::vfs::{
-    default_get_file_implementation, get_vfs, FileHandleAllocator, FileStat, FsSpecificFileData,
-    PipeMode, SeekPosition, Vfs, VfsFileKind, WeakArcrwb, FLAG_SYSTEM, FLAG_VIRTUAL,
-    OPEN_MODE_APPEND, OPEN_MODE_CREATE, OPEN_MODE_FAIL_IF_EXISTS, OPEN_MODE_READ, OPEN_MODE_WRITE,
+    arcrwb_new, default_get_file_implementation, get_vfs, shrink_file_system_caches,
+    FileHandleAllocator, FileStat, FsSpecificFileData, PipeMode, SeekPosition, Vfs, VfsFileKind,
+    WeakArcrwb, FLAG_SYSTEM, FLAG_VIRTUAL, OPEN_MODE_APPEND, OPEN_MODE_CREATE,
+    OPEN_MODE_FAIL_IF_EXISTS, OPEN_MODE_READ, OPEN_MODE_WRITE,
 };
 
-use crate::drivers::vfs::{Arcrwb, BlockDevice, FileSystem, VfsError, VfsFile};
+use crate::drivers::vfs::{
+    Arcrwb, BlockDevice, FileSystem, FsStat, PollEvent, PollEvents, VfsError, VfsFile,
+    POLL_ALWAYS_REPORTED,
+};
 use crate::permissions;
+use crate::process::wait::WaitQueue;
 
 #[derive(Debug)]
 pub struct Pipe {
@@ -28,6 +33,9 @@ pub struct Pipe {
     pub readers: u64,
     pub writers: u64,
     pub closed: bool,
+
+    /// Woken when data is read or written, or when an end is closed
+    pub wait_queue: Arc<WaitQueue>,
 }
 
 macro_rules! impl_pipe_create {
@@ -42,8 +50,8 @@ macro_rules! impl_pipe_create {
             .ok_or(VfsError::FileSystemNotMounted)?;
         let mut pipefs_guard = pipefs.write();
 
-        let rfile = pipefs_guard.get_child(pipe_vfs_file, &['r'])?;
-        let wfile = pipefs_guard.get_child(pipe_vfs_file, &['w'])?;
+        let rfile = pipefs_guard.get_child(pipe_vfs_file, VfsName::new(b"r").unwrap())?;
+        let wfile = pipefs_guard.get_child(pipe_vfs_file, VfsName::new(b"w").unwrap())?;
 
         let (Some((_, _, rid)), Some((_, _, wid))) = (rfile.get_pipe(), wfile.get_pipe()) else {
             return Err(VfsError::InvalidArgument);
@@ -65,8 +73,19 @@ macro_rules! impl_pipe_create {
 
 impl Pipe {
     pub fn new_anonymous(buf_size: usize) -> Pipe {
+        Self::with_buffer(calloc_boxed_slice(buf_size))
+    }
+
+    /// Fails instead of panicking when memory runs out
+    pub fn try_new_anonymous(buf_size: usize) -> Result<Pipe, VfsError> {
+        let data = try_alloc_boxed_slice_or_reclaim(buf_size, shrink_file_system_caches)
+            .ok_or(VfsError::OutOfMemory)?;
+        Ok(Self::with_buffer(data))
+    }
+
+    fn with_buffer(data: Box<[u8]>) -> Pipe {
         Pipe {
-            data: calloc_boxed_slice(buf_size),
+            data,
             data_len: 0,
             write_pos: 0,
             read_pos: 0,
@@ -75,6 +94,7 @@ impl Pipe {
             readers: 0,
             writers: 0,
             closed: false,
+            wait_queue: Arc::new(WaitQueue::new()),
         }
     }
 
@@ -90,18 +110,21 @@ impl Pipe {
     pub fn create() -> Result<(u64, File, File), VfsError> {
         unsafe {
             let pipe_dir = File::mkdir0("/pipes/a".chars().collect::<Vec<char>>())?;
-            let (rid, r, w, pipe_vfs_file, pipefs, rfile, wfile) = impl_pipe_create!(pipe_dir);
+            let (rid, r, w, _, pipefs, rfile, wfile) = impl_pipe_create!(pipe_dir);
+            let dir_path = alloc::format!("/pipes/{rid}")
+                .chars()
+                .collect::<Vec<char>>();
 
             let reader = File::unsafe_from_raw(
                 OPEN_MODE_READ,
-                [pipe_vfs_file.name(), &['/', 'r']].concat(),
+                [&dir_path, &['/', 'r'] as &[char]].concat(),
                 pipefs.clone(),
                 rfile,
                 r,
             );
             let writer = File::unsafe_from_raw(
                 OPEN_MODE_WRITE,
-                [pipe_vfs_file.name(), &['/', 'w']].concat(),
+                [&dir_path, &['/', 'w'] as &[char]].concat(),
                 pipefs.clone(),
                 wfile,
                 w,
@@ -175,6 +198,8 @@ pub struct PipeFsHandle {
     pipe_id: u64,
 }
 
+pub const PIPEFS_MAGIC: u64 = 0x5049_5045;
+
 #[derive(Debug)]
 pub struct PipeFs {
     os_id: u64,
@@ -215,6 +240,10 @@ impl FileSystem for PipeFs {
         None
     }
 
+    fn statfs(&mut self) -> Result<FsStat, VfsError> {
+        Ok(FsStat::virtual_fs(PIPEFS_MAGIC, self.pipes.len() as u64))
+    }
+
     fn get_root(&mut self) -> Result<VfsFile, VfsError> {
         Ok(VfsFile::new(
             VfsFileKind::Directory,
@@ -235,7 +264,8 @@ impl FileSystem for PipeFs {
         ))
     }
 
-    fn get_child(&mut self, file: &VfsFile, child: &[char]) -> Result<VfsFile, VfsError> {
+    fn get_child(&mut self, file: &VfsFile, child: &VfsName) -> Result<VfsFile, VfsError> {
+        let child = &child.to_chars()[..];
         if file.fs() != self.os_id {
             return Err(VfsError::FileSystemMismatch);
         }
@@ -447,7 +477,7 @@ impl FileSystem for PipeFs {
     fn create_child(
         &mut self,
         directory: &VfsFile,
-        _name: &[char],
+        _name: &VfsName,
         kind: VfsFileKind,
     ) -> Result<VfsFile, VfsError> {
         if directory.fs() != self.os_id {
@@ -464,10 +494,8 @@ impl FileSystem for PipeFs {
                 let id = self.next_pipe_id;
                 self.next_pipe_id += 1;
 
-                self.pipes.insert(
-                    id,
-                    Arc::new(RwLock::new(Box::new(Pipe::new_anonymous(64 * 1024)))),
-                );
+                let pipe = Pipe::try_new_anonymous(64 * 1024)?;
+                self.pipes.insert(id, arcrwb_new(pipe));
 
                 Ok(VfsFile::new(
                     kind,
@@ -496,6 +524,7 @@ impl FileSystem for PipeFs {
         self.parent_fs_os_id = mount_point.fs();
         self.mnt = Some(mount_point.clone());
         self.os_id = os_id;
+        self.handles.set_owner(os_id);
         self.get_root()
     }
 
@@ -603,7 +632,9 @@ impl FileSystem for PipeFs {
                         self.pipes.remove(&(*handle).pipe_id);
                     }
                 }
+                let wait_queue = wguard.wait_queue.clone();
                 drop(wguard);
+                wait_queue.wake_all();
             } else {
                 let mut wguard = (*handle).pipe.write();
                 wguard.writers -= 1;
@@ -613,7 +644,9 @@ impl FileSystem for PipeFs {
                         self.pipes.remove(&(*handle).pipe_id);
                     }
                 }
+                let wait_queue = wguard.wait_queue.clone();
                 drop(wguard);
+                wait_queue.wake_all();
             }
         }
 
@@ -644,7 +677,9 @@ impl FileSystem for PipeFs {
                     }
                     return Err(VfsError::WouldBlock);
                 }
-                Ok(wguard.read(buf) as u64)
+                let read = wguard.read(buf);
+                wguard.wait_queue.wake_all();
+                Ok(read as u64)
             } else {
                 Err(VfsError::ActionNotAllowed)
             }
@@ -666,7 +701,9 @@ impl FileSystem for PipeFs {
                 if wguard.is_full() {
                     return Err(VfsError::WouldBlock);
                 }
-                Ok(wguard.write(buf) as u64)
+                let written = wguard.write(buf);
+                wguard.wait_queue.wake_all();
+                Ok(written as u64)
             } else {
                 Err(VfsError::ActionNotAllowed)
             }
@@ -725,6 +762,47 @@ impl FileSystem for PipeFs {
     fn ftruncate(&mut self, _handle: u64) -> Result<u64, VfsError> {
         Err(VfsError::ActionNotAllowed)
     }
+
+    fn fpoll(&mut self, handle: u64, events: PollEvents) -> Result<PollEvents, VfsError> {
+        unsafe {
+            let handle = self
+                .handles
+                .get_handle_data::<PipeFsHandle>(handle)
+                .ok_or(VfsError::BadHandle)?;
+
+            let pipe = (*handle).pipe.read();
+            let mut ready = PollEvents::empty();
+            match (*handle).mode {
+                PipeMode::Read => {
+                    if !pipe.is_empty() {
+                        ready.set(PollEvent::In);
+                    }
+                    if pipe.writers == 0 {
+                        ready.set(PollEvent::HangUp);
+                    }
+                }
+                PipeMode::Write => {
+                    if pipe.readers == 0 {
+                        ready.set(PollEvent::Error);
+                    } else if !pipe.is_full() {
+                        ready.set(PollEvent::Out);
+                    }
+                }
+            }
+            Ok(ready & (events | POLL_ALWAYS_REPORTED))
+        }
+    }
+
+    fn fwait_queue(&mut self, handle: u64) -> Result<Option<Arc<WaitQueue>>, VfsError> {
+        unsafe {
+            let handle = self
+                .handles
+                .get_handle_data::<PipeFsHandle>(handle)
+                .ok_or(VfsError::BadHandle)?;
+
+            Ok(Some((*handle).pipe.read().wait_queue.clone()))
+        }
+    }
 }
 
 pub fn init_pipefs(vfs: &mut Vfs) {
@@ -739,5 +817,5 @@ pub fn init_pipefs(vfs: &mut Vfs) {
     };
 
     let pipes = "pipes".chars().collect::<Vec<char>>();
-    vfs.mount(&pipes, Box::new(fs)).unwrap();
+    vfs.mount(&pipes, Box::new(fs), 0).unwrap();
 }
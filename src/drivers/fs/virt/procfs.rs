@@ -0,0 +1,723 @@
+use alloc::format;
+use alloc::string::ToString;
+use alloc::sync::Arc;
+use alloc::{boxed::Box, string::String, vec::Vec};
+use core::fmt::Write;
+use core::sync::atomic::Ordering;
+
+use crate::cmdline::get_command_line;
+use crate::data::decimal_chars_to_u64;
+use crate::data::path::VfsName;
+use crate::drivers::fs::phys::ext2::{BLOCK_ALLOC_STATS, BLOCK_CACHE_STATS};
+use crate::drivers::fs::virt::devfs::fseek_helper;
+use crate::drivers::vfs::{
+    default_get_file_implementation, get_vfs, Arcrwb, BlockDevice, FileHandleAllocator, FileStat,
+    FileSystem, FsSpecificFileData, FsStat, SeekPosition, Vfs, VfsError, VfsFile, VfsFileKind,
+    WeakArcrwb, FLAG_READ_ONLY, FLAG_SYSTEM, FLAG_VIRTUAL, MS_RDONLY, OPEN_MODE_APPEND,
+    OPEN_MODE_CREATE, OPEN_MODE_FAIL_IF_EXISTS, OPEN_MODE_WRITE,
+};
+use crate::interrupts::handlers::irq::irq0_timer::get_uptime_ticks;
+use crate::interrupts::handlers::syscall::linux::resources::ticks_to_clock_t;
+use crate::interrupts::pit::ticks_to_ms;
+use crate::log::stdout_stats;
+use crate::memory::buddy_alloc::PAGE_SIZE;
+use crate::memory::mem::get_memory_stats;
+use crate::percpu::{cpu_stats, total_idle_ticks};
+use crate::permissions;
+use crate::process::limits::{Resource, RLIM_INFINITY};
+use crate::process::proc::{Process, TaskState};
+use crate::process::scheduler::SCHEDULER;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProcFsSpecificFileData {
+    ProcFsRoot,
+    ProcFsCmdline,
+    ProcFsMeminfo,
+    ProcFsMounts,
+    ProcFsUptime,
+    ProcFsStat,
+    ProcFsLogStat,
+    ProcFsProcess(u32),
+    ProcFsProcessStatus(u32),
+    ProcFsProcessCmdline(u32),
+    ProcFsProcessMaps(u32),
+    ProcFsProcessStat(u32),
+    ProcFsProcessLimits(u32),
+}
+
+impl FsSpecificFileData for ProcFsSpecificFileData {}
+
+impl ProcFsSpecificFileData {
+    fn pid(&self) -> Option<u32> {
+        match self {
+            Self::ProcFsProcess(pid)
+            | Self::ProcFsProcessStatus(pid)
+            | Self::ProcFsProcessCmdline(pid)
+            | Self::ProcFsProcessMaps(pid)
+            | Self::ProcFsProcessStat(pid)
+            | Self::ProcFsProcessLimits(pid) => Some(*pid),
+            _ => None,
+        }
+    }
+
+    fn is_directory(&self) -> bool {
+        matches!(self, Self::ProcFsRoot | Self::ProcFsProcess(_))
+    }
+}
+
+const PROCFS_ROOT_FILES: [(&str, ProcFsSpecificFileData); 6] = [
+    ("cmdline", ProcFsSpecificFileData::ProcFsCmdline),
+    ("meminfo", ProcFsSpecificFileData::ProcFsMeminfo),
+    ("mounts", ProcFsSpecificFileData::ProcFsMounts),
+    ("uptime", ProcFsSpecificFileData::ProcFsUptime),
+    ("stat", ProcFsSpecificFileData::ProcFsStat),
+    ("logstat", ProcFsSpecificFileData::ProcFsLogStat),
+];
+
+const PROCFS_PROCESS_FILES: [&str; 5] = ["status", "cmdline", "maps", "stat", "limits"];
+
+/// The contents of a file at the time it was opened
+#[derive(Debug, Clone)]
+pub struct ProcFsHandle {
+    data: Vec<u8>,
+    position: u64,
+}
+
+/// Read-only view of the kernel state, with one directory per live process.
+/// Files are generated when opened, so a handle always sees a consistent snapshot.
+#[derive(Debug)]
+pub struct ProcFs {
+    os_id: u64,
+    parent_fs_os_id: u64,
+    mnt: Option<VfsFile>,
+    root_fs: Option<WeakArcrwb<Vfs>>,
+
+    handles: FileHandleAllocator,
+}
+
+pub const PROCFS_MOUNT: [char; 4] = ['p', 'r', 'o', 'c'];
+pub const PROCFS_MAGIC: u64 = 0x9FA0;
+
+fn process_file_data(pid: u32, name: &[char]) -> Option<ProcFsSpecificFileData> {
+    let name = name.iter().collect::<String>();
+    match name.as_str() {
+        "status" => Some(ProcFsSpecificFileData::ProcFsProcessStatus(pid)),
+        "cmdline" => Some(ProcFsSpecificFileData::ProcFsProcessCmdline(pid)),
+        "maps" => Some(ProcFsSpecificFileData::ProcFsProcessMaps(pid)),
+        "stat" => Some(ProcFsSpecificFileData::ProcFsProcessStat(pid)),
+        "limits" => Some(ProcFsSpecificFileData::ProcFsProcessLimits(pid)),
+        _ => None,
+    }
+}
+
+fn task_state_name(state: &TaskState) -> &'static str {
+    match state {
+        TaskState::Init => "N (new)",
+        TaskState::Running => "R (running)",
+        TaskState::Paused => "T (paused)",
+        TaskState::Zombie { .. } => "Z (zombie)",
+        TaskState::Dead => "X (dead)",
+    }
+}
+
+fn generate_status(process: &Process) -> String {
+    let state = task_state_name(&process.state.lock());
+    let access = process.access.lock().clone();
+    let threads = process.threads.lock().len();
+
+    let mut out = String::new();
+    let _ = writeln!(out, "Name:\t{}", process.name);
+    let _ = writeln!(out, "State:\t{}", state);
+    let _ = writeln!(out, "Pid:\t{}", process.pid);
+    let _ = writeln!(out, "PPid:\t{}", process.parent_pid);
+    let _ = writeln!(out, "Uid:\t{}\t{}", access.uid, access.euid);
+    let _ = writeln!(out, "Gid:\t{}\t{}", access.gid, access.egid);
+    let _ = write!(out, "Groups:\t");
+    for (i, gid) in access.supplementary_gids.iter().enumerate() {
+        let _ = write!(out, "{}{}", if i == 0 { "" } else { " " }, gid);
+    }
+    let _ = writeln!(out);
+    let _ = writeln!(out, "Threads:\t{}", threads);
+    out
+}
+
+/// Arguments are each terminated by a nul byte
+fn generate_cmdline(process: &Process) -> Vec<u8> {
+    let mut out = Vec::new();
+    for arg in process.cmdline.iter() {
+        out.extend_from_slice(arg.as_bytes());
+        out.push(0);
+    }
+    out
+}
+
+fn generate_maps(process: &Process) -> String {
+    let mut pages = process
+        .allocated_code
+        .lock()
+        .allocs
+        .iter()
+        .map(|(virt, _)| *virt)
+        .collect::<Vec<u64>>();
+    pages.sort_unstable();
+
+    // Code pages are mapped one by one, merge the contiguous ones
+    let mut regions: Vec<(u64, u64, String)> = Vec::new();
+    for page in pages {
+        match regions.last_mut() {
+            Some((_, end, _)) if *end == page => *end += PAGE_SIZE,
+            _ => regions.push((page, page + PAGE_SIZE, String::new())),
+        }
+    }
+
+    for thread in process.threads.lock().iter() {
+        let stack = thread.stack.lock();
+        let name = if thread.tid == process.pid {
+            "[stack]".to_string()
+        } else {
+            format!("[stack:{}]", thread.tid)
+        };
+        regions.push((stack.stack_top - stack.stack_size, stack.stack_top, name));
+    }
+
+    let mut out = String::new();
+    for (start, end, name) in regions {
+        let _ = writeln!(out, "{:016x}-{:016x} {}", start, end, name);
+    }
+    out
+}
+
+/// The fields of linux's /proc/<pid>/stat up to rss, the untracked ones are 0
+fn generate_stat(process: &Process) -> String {
+    let state = task_state_name(&process.state.lock())
+        .chars()
+        .next()
+        .unwrap_or('?');
+    let threads = process.threads.lock().len();
+    let times = process.cpu_times();
+    let resident_pages = process.resident_pages();
+
+    format!(
+        "{} ({}) {} {} 0 0 0 0 0 0 0 0 0 {} {} 0 0 20 0 {} 0 0 {} {}\n",
+        process.pid,
+        process.name,
+        state,
+        process.parent_pid,
+        ticks_to_clock_t(times.user),
+        ticks_to_clock_t(times.kernel),
+        threads,
+        resident_pages * PAGE_SIZE,
+        resident_pages
+    )
+}
+
+/// Only the tracked limits are listed
+fn generate_limits(process: &Process) -> String {
+    let limits = process.limits.lock().clone();
+    let value = |limit: u64| match limit {
+        RLIM_INFINITY => "unlimited".to_string(),
+        limit => limit.to_string(),
+    };
+
+    let mut out = String::new();
+    let _ = writeln!(
+        out,
+        "{:<26}{:<21}{:<21}{:<10}",
+        "Limit", "Soft Limit", "Hard Limit", "Units"
+    );
+    for resource in Resource::ALL {
+        let (name, unit) = resource.description();
+        let limit = limits.get(resource);
+        let _ = writeln!(
+            out,
+            "{:<26}{:<21}{:<21}{:<10}",
+            name,
+            value(limit.soft),
+            value(limit.hard),
+            unit
+        );
+    }
+    out
+}
+
+fn generate_meminfo() -> String {
+    let stats = get_memory_stats();
+    let kb = |pages: u64| pages * PAGE_SIZE / 1024;
+
+    let mut out = String::new();
+    let _ = writeln!(out, "MemTotal:\t{} kB", kb(stats.total_pages));
+    let _ = writeln!(out, "MemFree:\t{} kB", kb(stats.free_pages));
+    let _ = writeln!(
+        out,
+        "MemUsed:\t{} kB",
+        kb(stats.total_pages - stats.free_pages)
+    );
+    let _ = writeln!(
+        out,
+        "HeapAllocated:\t{} kB",
+        stats.heap_allocated_bytes.div_ceil(1024)
+    );
+    if cfg!(debug_assertions) {
+        let cache = &BLOCK_CACHE_STATS;
+        let _ = writeln!(
+            out,
+            "Ext2CacheHits:\t{}",
+            cache.hits.load(Ordering::Relaxed)
+        );
+        let _ = writeln!(
+            out,
+            "Ext2CacheMisses:\t{}",
+            cache.misses.load(Ordering::Relaxed)
+        );
+        let _ = writeln!(
+            out,
+            "Ext2CacheLockWaits:\t{}",
+            cache.lock_waits.load(Ordering::Relaxed)
+        );
+        let alloc = &BLOCK_ALLOC_STATS;
+        let _ = writeln!(
+            out,
+            "Ext2AllocInGoal:\t{}",
+            alloc.in_goal.load(Ordering::Relaxed)
+        );
+        let _ = writeln!(
+            out,
+            "Ext2AllocElsewhere:\t{}",
+            alloc.elsewhere.load(Ordering::Relaxed)
+        );
+    }
+    out
+}
+
+/// The capacity goes in the options. File systems locked right now, like this one while the file
+/// is generated, are listed without it, and so are bind mounts which would lock their source
+fn generate_mounts() -> String {
+    let mounts = get_vfs().read().list_mounted_fs();
+
+    let mut out = String::new();
+    for mount in mounts {
+        let stat = (mount.fs_type != "bind")
+            .then_some(mount.fs)
+            .and_then(|fs| fs.upgrade())
+            .and_then(|fs| fs.try_write().map(|mut guard| guard.statfs()))
+            .and_then(|stat| stat.ok());
+        let mode = if mount.flags & MS_RDONLY != 0 {
+            "ro"
+        } else {
+            "rw"
+        };
+        let _ = write!(
+            out,
+            "{} {} {} {}",
+            mount.fs_type, mount.path, mount.fs_type, mode
+        );
+        if let Some(stat) = stat {
+            let _ = write!(
+                out,
+                ",bsize={},blocks={},bfree={},bavail={},files={},ffree={}",
+                stat.block_size,
+                stat.total_blocks,
+                stat.free_blocks,
+                stat.available_blocks,
+                stat.total_inodes,
+                stat.free_inodes
+            );
+        }
+        let _ = writeln!(out, " 0 0");
+    }
+    out
+}
+
+/// Uptime and the idle time summed over every CPU
+fn generate_uptime() -> String {
+    let ms = ticks_to_ms(get_uptime_ticks());
+    let idle_ms = ticks_to_ms(total_idle_ticks());
+    format!(
+        "{}.{:02} {}.{:02}\n",
+        ms / 1000,
+        (ms % 1000) / 10,
+        idle_ms / 1000,
+        (idle_ms % 1000) / 10
+    )
+}
+
+/// Idle time (in clock ticks) and context switches of each CPU, then the switches of all of them
+/// on the `ctxt` line like linux
+fn generate_cpu_stat() -> String {
+    let stats = cpu_stats();
+    let mut out = String::new();
+    for stat in stats.iter() {
+        let _ = writeln!(
+            out,
+            "cpu{} idle {} switches {}",
+            stat.core_id,
+            ticks_to_clock_t(stat.idle_ticks),
+            stat.context_switches
+        );
+    }
+    let total = stats.iter().map(|stat| stat.context_switches).sum::<u64>();
+    let _ = writeln!(out, "ctxt {}", total);
+    out
+}
+
+/// Bytes of the kernel log buffer, see `KernelStdoutStats`
+fn generate_log_stat() -> String {
+    let stats = stdout_stats();
+    let mut out = String::new();
+    let _ = writeln!(out, "Written:\t{}", stats.written);
+    let _ = writeln!(out, "Dropped:\t{}", stats.dropped);
+    let _ = writeln!(out, "HighWater:\t{}", stats.high_water);
+    let _ = writeln!(out, "Size:\t{}", stats.size);
+    let _ = writeln!(out, "Limit:\t{}", stats.limit);
+    out
+}
+
+impl ProcFs {
+    fn file(&self, name: &[char], data: ProcFsSpecificFileData) -> VfsFile {
+        VfsFile::new(
+            if data.is_directory() {
+                VfsFileKind::Directory
+            } else {
+                VfsFileKind::File
+            },
+            name.to_vec(),
+            0,
+            self.os_id,
+            self.os_id,
+            Arc::new(data),
+        )
+    }
+
+    fn file_data(&self, file: &VfsFile) -> Result<ProcFsSpecificFileData, VfsError> {
+        if file.fs() != self.os_id {
+            return Err(VfsError::FileSystemMismatch);
+        }
+        let d = file.get_fs_specific_data();
+        let data = *(*d)
+            .as_any()
+            .downcast_ref::<ProcFsSpecificFileData>()
+            .ok_or(VfsError::FileSystemMismatch)?;
+        Ok(data)
+    }
+
+    /// Files of an exited process disappear with it
+    fn process_of(data: &ProcFsSpecificFileData) -> Result<Option<Arc<Process>>, VfsError> {
+        match data.pid() {
+            Some(pid) => SCHEDULER
+                .get_process(pid)
+                .map(Some)
+                .ok_or(VfsError::PathNotFound),
+            None => Ok(None),
+        }
+    }
+
+    fn generate(data: &ProcFsSpecificFileData) -> Result<Vec<u8>, VfsError> {
+        let process = Self::process_of(data)?;
+        Ok(match (data, process) {
+            (ProcFsSpecificFileData::ProcFsCmdline, _) => {
+                alloc::format!("{}\n", get_command_line().as_str()).into_bytes()
+            }
+            (ProcFsSpecificFileData::ProcFsMeminfo, _) => generate_meminfo().into_bytes(),
+            (ProcFsSpecificFileData::ProcFsMounts, _) => generate_mounts().into_bytes(),
+            (ProcFsSpecificFileData::ProcFsUptime, _) => generate_uptime().into_bytes(),
+            (ProcFsSpecificFileData::ProcFsStat, _) => generate_cpu_stat().into_bytes(),
+            (ProcFsSpecificFileData::ProcFsLogStat, _) => generate_log_stat().into_bytes(),
+            (ProcFsSpecificFileData::ProcFsProcessStatus(_), Some(process)) => {
+                generate_status(&process).into_bytes()
+            }
+            (ProcFsSpecificFileData::ProcFsProcessCmdline(_), Some(process)) => {
+                generate_cmdline(&process)
+            }
+            (ProcFsSpecificFileData::ProcFsProcessMaps(_), Some(process)) => {
+                generate_maps(&process).into_bytes()
+            }
+            (ProcFsSpecificFileData::ProcFsProcessStat(_), Some(process)) => {
+                generate_stat(&process).into_bytes()
+            }
+            (ProcFsSpecificFileData::ProcFsProcessLimits(_), Some(process)) => {
+                generate_limits(&process).into_bytes()
+            }
+            _ => return Err(VfsError::NotFile),
+        })
+    }
+
+    fn handle(&self, handle: u64) -> Result<*mut ProcFsHandle, VfsError> {
+        unsafe {
+            self.handles
+                .get_handle_data::<ProcFsHandle>(handle)
+                .ok_or(VfsError::BadHandle)
+        }
+    }
+}
+
+impl FileSystem for ProcFs {
+    fn os_id(&mut self) -> u64 {
+        self.os_id
+    }
+
+    fn fs_type(&mut self) -> String {
+        "procfs".to_string()
+    }
+
+    fn fs_flush(&mut self) -> Result<(), VfsError> {
+        Err(VfsError::ActionNotAllowed)
+    }
+
+    fn host_block_device(&mut self) -> Option<Arcrwb<dyn BlockDevice>> {
+        None
+    }
+
+    fn statfs(&mut self) -> Result<FsStat, VfsError> {
+        Ok(FsStat::virtual_fs(PROCFS_MAGIC, 0))
+    }
+
+    fn get_root(&mut self) -> Result<VfsFile, VfsError> {
+        Ok(VfsFile::new(
+            VfsFileKind::Directory,
+            alloc::vec!['/'],
+            0,
+            self.parent_fs_os_id,
+            self.os_id,
+            Arc::new(ProcFsSpecificFileData::ProcFsRoot),
+        ))
+    }
+
+    fn get_mount_point(&mut self) -> Result<Option<VfsFile>, VfsError> {
+        Ok(Some(
+            self.mnt
+                .as_ref()
+                .ok_or(VfsError::FileSystemNotMounted)?
+                .clone(),
+        ))
+    }
+
+    fn get_child(&mut self, file: &VfsFile, child: &VfsName) -> Result<VfsFile, VfsError> {
+        let child = &child.to_chars()[..];
+        match self.file_data(file)? {
+            ProcFsSpecificFileData::ProcFsRoot => {
+                for (name, data) in PROCFS_ROOT_FILES.iter() {
+                    if name.chars().eq(child.iter().copied()) {
+                        return Ok(self.file(child, *data));
+                    }
+                }
+                let pid = decimal_chars_to_u64(child)
+                    .and_then(|pid| u32::try_from(pid).ok())
+                    .ok_or(VfsError::PathNotFound)?;
+                // Reject aliases such as "01"
+                if !pid.to_string().chars().eq(child.iter().copied())
+                    || SCHEDULER.get_process(pid).is_none()
+                {
+                    return Err(VfsError::PathNotFound);
+                }
+                Ok(self.file(child, ProcFsSpecificFileData::ProcFsProcess(pid)))
+            }
+            ProcFsSpecificFileData::ProcFsProcess(pid) => {
+                if SCHEDULER.get_process(pid).is_none() {
+                    return Err(VfsError::PathNotFound);
+                }
+                let data = process_file_data(pid, child).ok_or(VfsError::PathNotFound)?;
+                Ok(self.file(child, data))
+            }
+            _ => Err(VfsError::NotDirectory),
+        }
+    }
+
+    fn list_children(&mut self, file: &VfsFile) -> Result<Vec<VfsFile>, VfsError> {
+        match self.file_data(file)? {
+            ProcFsSpecificFileData::ProcFsRoot => {
+                let mut children = PROCFS_ROOT_FILES
+                    .iter()
+                    .map(|(name, data)| self.file(&name.chars().collect::<Vec<char>>(), *data))
+                    .collect::<Vec<VfsFile>>();
+                for process in SCHEDULER.list_processes() {
+                    let name = process.pid.to_string().chars().collect::<Vec<char>>();
+                    children
+                        .push(self.file(&name, ProcFsSpecificFileData::ProcFsProcess(process.pid)));
+                }
+                Ok(children)
+            }
+            ProcFsSpecificFileData::ProcFsProcess(pid) => {
+                if SCHEDULER.get_process(pid).is_none() {
+                    return Err(VfsError::PathNotFound);
+                }
+                Ok(PROCFS_PROCESS_FILES
+                    .iter()
+                    .filter_map(|name| {
+                        let name = name.chars().collect::<Vec<char>>();
+                        let data = process_file_data(pid, &name)?;
+                        Some(self.file(&name, data))
+                    })
+                    .collect())
+            }
+            _ => Err(VfsError::NotDirectory),
+        }
+    }
+
+    default_get_file_implementation!();
+
+    fn get_stats(&mut self, file: &VfsFile) -> Result<FileStat, VfsError> {
+        let data = self.file_data(file)?;
+        let (owner_id, group_id) = match Self::process_of(&data)? {
+            Some(process) => {
+                let access = process.access.lock();
+                (access.euid as u64, access.egid as u64)
+            }
+            None => (0, 0),
+        };
+        let is_directory = data.is_directory();
+        Ok(FileStat {
+            size: 0,
+            created_at: 0,
+            modified_at: 0,
+            permissions: if is_directory {
+                permissions!(Owner:Read, Owner:Execute, Group:Read, Group:Execute, Other:Read, Other:Execute).to_u64()
+            } else {
+                permissions!(Owner:Read, Group:Read, Other:Read).to_u64()
+            },
+            is_file: !is_directory,
+            is_directory,
+            is_symlink: false,
+            owner_id,
+            group_id,
+            flags: FLAG_VIRTUAL | FLAG_SYSTEM | FLAG_READ_ONLY,
+        })
+    }
+
+    fn create_child(
+        &mut self,
+        _directory: &VfsFile,
+        _name: &VfsName,
+        _kind: VfsFileKind,
+    ) -> Result<VfsFile, VfsError> {
+        Err(VfsError::ReadOnly)
+    }
+
+    fn delete_file(&mut self, _file: &VfsFile) -> Result<(), VfsError> {
+        Err(VfsError::ReadOnly)
+    }
+
+    fn on_mount(
+        &mut self,
+        mount_point: &VfsFile,
+        os_id: u64,
+        root_fs: WeakArcrwb<Vfs>,
+    ) -> Result<VfsFile, VfsError> {
+        self.root_fs = Some(root_fs);
+        self.parent_fs_os_id = mount_point.fs();
+        self.mnt = Some(mount_point.clone());
+        self.os_id = os_id;
+        self.handles.set_owner(os_id);
+        self.get_root()
+    }
+
+    fn on_pre_unmount(&mut self) -> Result<bool, VfsError> {
+        Ok(true)
+    }
+
+    fn on_unmount(&mut self) -> Result<(), VfsError> {
+        self.mnt = None;
+        self.os_id = 0;
+        self.parent_fs_os_id = 0;
+        for h in self.handles.iter().copied().collect::<Vec<u64>>() {
+            self.fclose(h)?;
+        }
+        Ok(())
+    }
+
+    fn get_vfs(&mut self) -> Result<WeakArcrwb<Vfs>, VfsError> {
+        Ok(self
+            .root_fs
+            .as_ref()
+            .ok_or(VfsError::FileSystemNotMounted)?
+            .clone())
+    }
+
+    fn fopen(&mut self, file: &VfsFile, mode: u64) -> Result<u64, VfsError> {
+        if mode & (OPEN_MODE_WRITE | OPEN_MODE_APPEND | OPEN_MODE_CREATE) != 0 {
+            return Err(VfsError::ReadOnly);
+        }
+        if mode & OPEN_MODE_FAIL_IF_EXISTS != 0 {
+            return Err(VfsError::FileAlreadyExists);
+        }
+        let data = Self::generate(&self.file_data(file)?)?;
+        Ok(self
+            .handles
+            .alloc_file_handle(ProcFsHandle { data, position: 0 }))
+    }
+
+    fn fclose(&mut self, handle: u64) -> Result<(), VfsError> {
+        if self.handles.dealloc_file_handle::<ProcFsHandle>(handle) {
+            Ok(())
+        } else {
+            Err(VfsError::BadHandle)
+        }
+    }
+
+    fn fseek(&mut self, handle: u64, position: SeekPosition) -> Result<u64, VfsError> {
+        let handle = unsafe { &mut *self.handle(handle)? };
+        handle.position = fseek_helper(position, handle.position, handle.data.len() as u64)
+            .ok_or(VfsError::InvalidSeekPosition)?;
+        Ok(handle.position)
+    }
+
+    fn fread(&mut self, handle: u64, buf: &mut [u8]) -> Result<u64, VfsError> {
+        let handle = unsafe { &mut *self.handle(handle)? };
+        let start = (handle.position as usize).min(handle.data.len());
+        let len = buf.len().min(handle.data.len() - start);
+        buf[..len].copy_from_slice(&handle.data[start..start + len]);
+        handle.position += len as u64;
+        Ok(len as u64)
+    }
+
+    fn fwrite(&mut self, handle: u64, _buf: &[u8]) -> Result<u64, VfsError> {
+        self.handle(handle)?;
+        Err(VfsError::ReadOnly)
+    }
+
+    fn fflush(&mut self, handle: u64) -> Result<(), VfsError> {
+        self.handle(handle)?;
+        Ok(())
+    }
+
+    fn fsync(&mut self, handle: u64) -> Result<(), VfsError> {
+        self.handle(handle)?;
+        Ok(())
+    }
+
+    fn fseekable(&mut self, handle: u64) -> bool {
+        self.handle(handle).is_ok()
+    }
+
+    fn fstat(&self, handle: u64) -> Result<FileStat, VfsError> {
+        let handle = unsafe { &*self.handle(handle)? };
+        Ok(FileStat {
+            size: handle.data.len() as u64,
+            created_at: 0,
+            modified_at: 0,
+            permissions: permissions!(Owner:Read, Group:Read, Other:Read).to_u64(),
+            is_file: true,
+            is_directory: false,
+            is_symlink: false,
+            owner_id: 0,
+            group_id: 0,
+            flags: FLAG_VIRTUAL | FLAG_SYSTEM | FLAG_READ_ONLY,
+        })
+    }
+
+    fn ftruncate(&mut self, handle: u64) -> Result<u64, VfsError> {
+        self.handle(handle)?;
+        Err(VfsError::ReadOnly)
+    }
+}
+
+pub fn init_procfs(vfs: &mut Vfs) {
+    let fs = ProcFs {
+        os_id: 0,
+        parent_fs_os_id: 0,
+        mnt: None,
+        root_fs: None,
+        handles: FileHandleAllocator::default(),
+    };
+
+    vfs.mount(&PROCFS_MOUNT, Box::new(fs), 0).unwrap();
+}
@@ -0,0 +1,566 @@
+//! Pseudo terminals, mounted at `/dev/pts`
+//!
+//! Opening `/dev/ptmx` allocates a pair: the master end is the returned file, the slave end
+//! appears as `/dev/pts/<n>` once unlocked with `TIOCSPTLCK`. What the master writes goes through
+//! the line discipline to the slave readers, what the slave writes goes through output processing
+//! to the master.
+
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::string::ToString;
+use alloc::sync::Arc;
+use alloc::{boxed::Box, string::String, vec::Vec};
+use spin::Mutex;
+
+use crate::data::decimal_chars_to_u64;
+use crate::data::path::VfsName;
+use crate::drivers::tty::{
+    process_output, LineDiscipline, Termios, WinSize, TCGETS, TCSETS, TCSETSF, TCSETSW, TIOCGPTN,
+    TIOCGWINSZ, TIOCSPTLCK, TIOCSWINSZ, TTY_BUFFER_SIZE,
+};
+use crate::drivers::vfs::{
+    default_get_file_implementation, Arcrwb, BlockDevice, FileHandleAllocator, FileStat,
+    FileSystem, FsSpecificFileData, FsStat, PollEvent, PollEvents, SeekPosition, Vfs, VfsError,
+    VfsFile, VfsFileKind, WeakArcrwb, FLAG_SYSTEM, FLAG_VIRTUAL, FLAG_VIRTUAL_CHARACTER_DEVICE,
+    OPEN_MODE_NONBLOCK, POLL_ALWAYS_REPORTED,
+};
+use crate::permissions;
+use crate::process::wait::WaitQueue;
+
+#[derive(Debug)]
+struct PtyState {
+    termios: Termios,
+    winsize: WinSize,
+    /// Input from the master to the slave
+    discipline: LineDiscipline,
+    /// Output from the slave and echo, read by the master
+    output: VecDeque<u8>,
+    master_open: bool,
+    slaves: u64,
+    /// The slave was opened at least once
+    slave_opened: bool,
+    /// The slave can't be opened until the master unlocks it
+    locked: bool,
+}
+
+#[derive(Debug)]
+pub struct Pty {
+    index: u64,
+    state: Mutex<PtyState>,
+    /// Woken when data moves in either direction or an end is closed
+    wait_queue: Arc<WaitQueue>,
+}
+
+impl Pty {
+    pub fn new(index: u64) -> Self {
+        Self {
+            index,
+            state: Mutex::new(PtyState {
+                termios: Termios::default(),
+                winsize: WinSize::default(),
+                discipline: LineDiscipline::new(),
+                output: VecDeque::new(),
+                master_open: true,
+                slaves: 0,
+                slave_opened: false,
+                locked: true,
+            }),
+            wait_queue: Arc::new(WaitQueue::new()),
+        }
+    }
+
+    pub fn index(&self) -> u64 {
+        self.index
+    }
+
+    pub fn wait_queue(&self) -> Arc<WaitQueue> {
+        self.wait_queue.clone()
+    }
+
+    /// Fails once the slave hung up, after every open slave was closed
+    pub fn master_read(&self, buf: &mut [u8]) -> Result<u64, VfsError> {
+        let mut state = self.state.lock();
+        if state.output.is_empty() {
+            if state.slave_opened && state.slaves == 0 {
+                return Err(VfsError::HungUp);
+            }
+            return Err(VfsError::WouldBlock);
+        }
+        let read = state.output.len().min(buf.len());
+        for (byte, slot) in state.output.drain(..read).zip(buf.iter_mut()) {
+            *slot = byte;
+        }
+        drop(state);
+        self.wait_queue.wake_all();
+        Ok(read as u64)
+    }
+
+    /// Input typed on the terminal
+    pub fn master_write(&self, buf: &[u8]) -> Result<u64, VfsError> {
+        let mut state = self.state.lock();
+        let state = &mut *state;
+        let mut written = 0;
+        let mut echo = Vec::new();
+        for byte in buf {
+            if !state.discipline.can_receive() {
+                break;
+            }
+            // Signals aren't delivered yet, the discipline only flushes the input
+            let _ = state.discipline.receive(&state.termios, *byte, &mut echo);
+            written += 1;
+        }
+        // Echo that doesn't fit is lost, like on a terminal nobody reads
+        process_output(&state.termios, &echo, &mut state.output, TTY_BUFFER_SIZE);
+        if written == 0 && !buf.is_empty() {
+            return Err(VfsError::WouldBlock);
+        }
+        self.wait_queue.wake_all();
+        Ok(written as u64)
+    }
+
+    /// Reads return end of file once the master is closed
+    pub fn slave_read(&self, buf: &mut [u8]) -> Result<u64, VfsError> {
+        let mut state = self.state.lock();
+        let state = &mut *state;
+        match state.discipline.read(&state.termios, buf) {
+            Some(read) => {
+                self.wait_queue.wake_all();
+                Ok(read as u64)
+            }
+            None if !state.master_open => Ok(0),
+            None => Err(VfsError::WouldBlock),
+        }
+    }
+
+    /// Output of the programs running on the terminal
+    pub fn slave_write(&self, buf: &[u8]) -> Result<u64, VfsError> {
+        let mut state = self.state.lock();
+        let state = &mut *state;
+        if !state.master_open {
+            return Err(VfsError::HungUp);
+        }
+        let written = process_output(&state.termios, buf, &mut state.output, TTY_BUFFER_SIZE);
+        if written == 0 && !buf.is_empty() {
+            return Err(VfsError::WouldBlock);
+        }
+        self.wait_queue.wake_all();
+        Ok(written as u64)
+    }
+
+    pub fn master_poll(&self) -> PollEvents {
+        let state = self.state.lock();
+        let mut ready = PollEvents::empty();
+        if !state.output.is_empty() {
+            ready.set(PollEvent::In);
+        }
+        if state.discipline.can_receive() {
+            ready.set(PollEvent::Out);
+        }
+        if state.slave_opened && state.slaves == 0 {
+            ready.set(PollEvent::HangUp);
+        }
+        ready
+    }
+
+    pub fn slave_poll(&self) -> PollEvents {
+        let state = self.state.lock();
+        let mut ready = PollEvents::empty();
+        if state.discipline.is_readable(&state.termios) {
+            ready.set(PollEvent::In);
+        }
+        if !state.master_open {
+            ready.set(PollEvent::HangUp);
+        } else if state.output.len() < TTY_BUFFER_SIZE {
+            ready.set(PollEvent::Out);
+        }
+        ready
+    }
+
+    /// Terminal ioctls, `TIOCGPTN` and `TIOCSPTLCK` only work on the master
+    pub fn ioctl(&self, master: bool, request: u64, arg: &mut [u8]) -> Result<u64, VfsError> {
+        let mut state = self.state.lock();
+        let state = &mut *state;
+        match request {
+            TCGETS => copy_out(arg, &state.termios.to_bytes())?,
+            TCSETS | TCSETSW | TCSETSF => {
+                let termios = Termios::from_bytes(arg).ok_or(VfsError::BadBufferSize)?;
+                if request == TCSETSF {
+                    state.discipline.flush();
+                }
+                state.discipline.set_termios(&state.termios, &termios);
+                state.termios = termios;
+                self.wait_queue.wake_all();
+            }
+            TIOCGWINSZ => copy_out(arg, &state.winsize.to_bytes())?,
+            TIOCSWINSZ => {
+                state.winsize = WinSize::from_bytes(arg).ok_or(VfsError::BadBufferSize)?;
+            }
+            TIOCGPTN if master => copy_out(arg, &(self.index as u32).to_ne_bytes())?,
+            TIOCSPTLCK if master => {
+                let lock = arg.get(..4).ok_or(VfsError::BadBufferSize)?;
+                state.locked = lock != [0; 4];
+            }
+            _ => return Err(VfsError::NotTerminal),
+        }
+        Ok(0)
+    }
+
+    pub fn open_slave(&self) -> Result<(), VfsError> {
+        let mut state = self.state.lock();
+        if !state.master_open || state.locked {
+            return Err(VfsError::HungUp);
+        }
+        state.slaves += 1;
+        state.slave_opened = true;
+        Ok(())
+    }
+
+    pub fn close_slave(&self) {
+        let mut state = self.state.lock();
+        state.slaves -= 1;
+        let unused = state.slaves == 0 && !state.master_open;
+        drop(state);
+        if unused {
+            PTYS.lock().remove(&self.index);
+        }
+        self.wait_queue.wake_all();
+    }
+
+    /// The slave end hangs up, it is freed once its last file is closed
+    pub fn close_master(&self) {
+        let mut state = self.state.lock();
+        state.master_open = false;
+        let unused = state.slaves == 0;
+        drop(state);
+        if unused {
+            PTYS.lock().remove(&self.index);
+        }
+        self.wait_queue.wake_all();
+    }
+
+    fn is_visible(&self) -> bool {
+        let state = self.state.lock();
+        state.master_open && !state.locked
+    }
+}
+
+fn copy_out(arg: &mut [u8], value: &[u8]) -> Result<(), VfsError> {
+    arg.get_mut(..value.len())
+        .ok_or(VfsError::BadBufferSize)?
+        .copy_from_slice(value);
+    Ok(())
+}
+
+/// Every allocated pair, by index
+static PTYS: Mutex<BTreeMap<u64, Arc<Pty>>> = Mutex::new(BTreeMap::new());
+
+/// Allocates a pair with the lowest free index, its master is open
+pub fn alloc_pty() -> Arc<Pty> {
+    let mut ptys = PTYS.lock();
+    let index = (0..)
+        .zip(ptys.keys())
+        .find(|(expected, index)| expected != *index)
+        .map_or(ptys.len() as u64, |(expected, _)| expected);
+    let pty = Arc::new(Pty::new(index));
+    ptys.insert(index, pty.clone());
+    pty
+}
+
+fn get_pty(index: u64) -> Option<Arc<Pty>> {
+    PTYS.lock().get(&index).cloned()
+}
+
+#[derive(Debug, Clone)]
+pub struct PtyFsHandle {
+    pty: Arc<Pty>,
+    nonblocking: bool,
+}
+
+#[derive(Debug)]
+pub enum PtyFsSpecificFileData {
+    Root,
+    Slave(u64),
+}
+
+impl FsSpecificFileData for PtyFsSpecificFileData {}
+
+pub const DEVPTS_MAGIC: u64 = 0x1CD1;
+
+/// The slave ends of the allocated pairs
+#[derive(Debug)]
+pub struct PtyFs {
+    os_id: u64,
+    parent_fs_os_id: u64,
+    mnt: Option<VfsFile>,
+    root_fs: Option<WeakArcrwb<Vfs>>,
+
+    handles: FileHandleAllocator,
+}
+
+impl PtyFs {
+    fn slave_file(&self, index: u64) -> VfsFile {
+        VfsFile::new(
+            VfsFileKind::File,
+            index.to_string().chars().collect(),
+            0,
+            self.os_id,
+            self.os_id,
+            Arc::new(PtyFsSpecificFileData::Slave(index)),
+        )
+    }
+
+    fn slave_stat(&self) -> FileStat {
+        FileStat {
+            size: 0,
+            created_at: 0,
+            modified_at: 0,
+            permissions: permissions!(Owner:Read, Owner:Write, Group:Write).to_u64(),
+            is_file: true,
+            is_directory: false,
+            is_symlink: false,
+            owner_id: 0,
+            group_id: 0,
+            flags: FLAG_VIRTUAL | FLAG_VIRTUAL_CHARACTER_DEVICE | FLAG_SYSTEM,
+        }
+    }
+
+    fn handle(&self, handle: u64) -> Result<&PtyFsHandle, VfsError> {
+        unsafe {
+            Ok(&*self
+                .handles
+                .get_handle_data::<PtyFsHandle>(handle)
+                .ok_or(VfsError::BadHandle)?)
+        }
+    }
+}
+
+impl FileSystem for PtyFs {
+    fn os_id(&mut self) -> u64 {
+        self.os_id
+    }
+
+    fn fs_type(&mut self) -> String {
+        "devpts".to_string()
+    }
+
+    fn fs_flush(&mut self) -> Result<(), VfsError> {
+        Ok(())
+    }
+
+    fn host_block_device(&mut self) -> Option<Arcrwb<dyn BlockDevice>> {
+        None
+    }
+
+    fn statfs(&mut self) -> Result<FsStat, VfsError> {
+        Ok(FsStat::virtual_fs(
+            DEVPTS_MAGIC,
+            self.handles.count() as u64,
+        ))
+    }
+
+    fn get_root(&mut self) -> Result<VfsFile, VfsError> {
+        Ok(VfsFile::new(
+            VfsFileKind::Directory,
+            alloc::vec!['/'],
+            0,
+            self.parent_fs_os_id,
+            self.os_id,
+            Arc::new(PtyFsSpecificFileData::Root),
+        ))
+    }
+
+    fn get_mount_point(&mut self) -> Result<Option<VfsFile>, VfsError> {
+        Ok(Some(
+            self.mnt
+                .as_ref()
+                .ok_or(VfsError::FileSystemNotMounted)?
+                .clone(),
+        ))
+    }
+
+    fn get_child(&mut self, file: &VfsFile, child: &VfsName) -> Result<VfsFile, VfsError> {
+        if file.fs() != self.os_id {
+            return Err(VfsError::FileSystemMismatch);
+        }
+        if file.name() != ['/'] {
+            return Err(VfsError::NotDirectory);
+        }
+        let index = decimal_chars_to_u64(&child.to_chars()).ok_or(VfsError::PathNotFound)?;
+        match get_pty(index) {
+            Some(pty) if pty.is_visible() => Ok(self.slave_file(index)),
+            _ => Err(VfsError::PathNotFound),
+        }
+    }
+
+    fn list_children(&mut self, file: &VfsFile) -> Result<Vec<VfsFile>, VfsError> {
+        if file.fs() != self.os_id {
+            return Err(VfsError::FileSystemMismatch);
+        }
+        if file.name() != ['/'] {
+            return Err(VfsError::NotDirectory);
+        }
+        let ptys = PTYS.lock().values().cloned().collect::<Vec<_>>();
+        Ok(ptys
+            .iter()
+            .filter(|pty| pty.is_visible())
+            .map(|pty| self.slave_file(pty.index()))
+            .collect())
+    }
+
+    default_get_file_implementation!();
+
+    fn get_stats(&mut self, file: &VfsFile) -> Result<FileStat, VfsError> {
+        if file.fs() != self.os_id {
+            return Err(VfsError::FileSystemMismatch);
+        }
+        if file.is_directory() {
+            return Ok(FileStat {
+                size: 0,
+                created_at: 0,
+                modified_at: 0,
+                permissions: permissions!(Owner:Read, Owner:Execute, Group:Read, Group:Execute, Other:Read, Other:Execute).to_u64(),
+                is_file: false,
+                is_directory: true,
+                is_symlink: false,
+                owner_id: 0,
+                group_id: 0,
+                flags: FLAG_VIRTUAL | FLAG_SYSTEM,
+            });
+        }
+        Ok(self.slave_stat())
+    }
+
+    fn create_child(
+        &mut self,
+        _directory: &VfsFile,
+        _name: &VfsName,
+        _kind: VfsFileKind,
+    ) -> Result<VfsFile, VfsError> {
+        Err(VfsError::ActionNotAllowed)
+    }
+
+    fn delete_file(&mut self, _file: &VfsFile) -> Result<(), VfsError> {
+        Err(VfsError::ActionNotAllowed)
+    }
+
+    fn on_mount(
+        &mut self,
+        mount_point: &VfsFile,
+        os_id: u64,
+        root_fs: WeakArcrwb<Vfs>,
+    ) -> Result<VfsFile, VfsError> {
+        self.root_fs = Some(root_fs);
+        self.parent_fs_os_id = mount_point.fs();
+        self.mnt = Some(mount_point.clone());
+        self.os_id = os_id;
+        self.handles.set_owner(os_id);
+        self.get_root()
+    }
+
+    fn on_pre_unmount(&mut self) -> Result<bool, VfsError> {
+        Ok(self.handles.iter().next().is_none())
+    }
+
+    fn on_unmount(&mut self) -> Result<(), VfsError> {
+        self.mnt = None;
+        self.os_id = 0;
+        self.parent_fs_os_id = 0;
+        Ok(())
+    }
+
+    fn get_vfs(&mut self) -> Result<WeakArcrwb<Vfs>, VfsError> {
+        Ok(self
+            .root_fs
+            .as_ref()
+            .ok_or(VfsError::FileSystemNotMounted)?
+            .clone())
+    }
+
+    fn fopen(&mut self, file: &VfsFile, mode: u64) -> Result<u64, VfsError> {
+        if file.fs() != self.os_id {
+            return Err(VfsError::FileSystemMismatch);
+        }
+        let d = file.get_fs_specific_data();
+        let data = (*d)
+            .as_any()
+            .downcast_ref::<PtyFsSpecificFileData>()
+            .ok_or(VfsError::FileSystemMismatch)?;
+
+        match data {
+            PtyFsSpecificFileData::Slave(index) => {
+                let pty = get_pty(*index).ok_or(VfsError::PathNotFound)?;
+                pty.open_slave()?;
+                Ok(self.handles.alloc_file_handle(PtyFsHandle {
+                    pty,
+                    nonblocking: mode & OPEN_MODE_NONBLOCK != 0,
+                }))
+            }
+            PtyFsSpecificFileData::Root => Err(VfsError::NotFile),
+        }
+    }
+
+    fn fclose(&mut self, handle: u64) -> Result<(), VfsError> {
+        let pty = self.handle(handle)?.pty.clone();
+        self.handles.dealloc_file_handle::<PtyFsHandle>(handle);
+        pty.close_slave();
+        Ok(())
+    }
+
+    fn fseek(&mut self, _handle: u64, _position: SeekPosition) -> Result<u64, VfsError> {
+        Err(VfsError::ActionNotAllowed)
+    }
+
+    fn fread(&mut self, handle: u64, buf: &mut [u8]) -> Result<u64, VfsError> {
+        self.handle(handle)?.pty.slave_read(buf)
+    }
+
+    fn fwrite(&mut self, handle: u64, buf: &[u8]) -> Result<u64, VfsError> {
+        self.handle(handle)?.pty.slave_write(buf)
+    }
+
+    fn fflush(&mut self, handle: u64) -> Result<(), VfsError> {
+        self.handle(handle).map(|_| ())
+    }
+
+    fn fsync(&mut self, handle: u64) -> Result<(), VfsError> {
+        self.handle(handle).map(|_| ())
+    }
+
+    fn fstat(&self, handle: u64) -> Result<FileStat, VfsError> {
+        self.handle(handle)?;
+        Ok(self.slave_stat())
+    }
+
+    fn ftruncate(&mut self, _handle: u64) -> Result<u64, VfsError> {
+        Err(VfsError::ActionNotAllowed)
+    }
+
+    fn fpoll(&mut self, handle: u64, events: PollEvents) -> Result<PollEvents, VfsError> {
+        let ready = self.handle(handle)?.pty.slave_poll();
+        Ok(ready & (events | POLL_ALWAYS_REPORTED))
+    }
+
+    fn fwait_queue(&mut self, handle: u64) -> Result<Option<Arc<WaitQueue>>, VfsError> {
+        Ok(Some(self.handle(handle)?.pty.wait_queue()))
+    }
+
+    fn fblocks(&mut self, handle: u64) -> bool {
+        self.handle(handle).is_ok_and(|handle| !handle.nonblocking)
+    }
+
+    fn fioctl(&mut self, handle: u64, request: u64, arg: &mut [u8]) -> Result<u64, VfsError> {
+        self.handle(handle)?.pty.ioctl(false, request, arg)
+    }
+}
+
+pub fn init_ptyfs(vfs: &mut Vfs) {
+    let fs = PtyFs {
+        handles: FileHandleAllocator::default(),
+        mnt: None,
+        os_id: 0,
+        parent_fs_os_id: 0,
+        root_fs: None,
+    };
+
+    let pts = "dev/pts".chars().collect::<Vec<char>>();
+    vfs.mount(&pts, Box::new(fs), 0).unwrap();
+}
@@ -0,0 +1,372 @@
+use alloc::string::ToString;
+use alloc::sync::Arc;
+use alloc::{boxed::Box, string::String, vec::Vec};
+use spin::Mutex;
+
+use crate::data::path::{VfsName, VfsPath};
+use crate::drivers::vfs::{
+    default_get_file_implementation, get_vfs, Arcrwb, BlockDevice, FileHandleAllocator, FileStat,
+    FileSystem, FsSpecificFileData, FsStat, PollEvent, PollEvents, SeekPosition, Vfs, VfsError,
+    VfsFile, VfsFileKind, WeakArcrwb, FLAG_SYSTEM, FLAG_VIRTUAL, POLL_ALWAYS_REPORTED,
+};
+use crate::interrupts::handlers::irq::irq0_timer::get_uptime_ticks;
+use crate::permissions;
+use crate::process::timer::{ExpirationCounter, TimerEvent, TimerId, TIMERS};
+use crate::process::wait::WaitQueue;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimerClock {
+    Realtime,
+    Monotonic,
+}
+
+/// Timer behind a timer fd, reads return how many times it expired since the last read
+#[derive(Debug)]
+pub struct TimerFd {
+    pub clock: TimerClock,
+    pub nonblocking: bool,
+    owner: u32,
+    timer: Option<TimerId>,
+    expirations: Arc<ExpirationCounter>,
+}
+
+impl TimerFd {
+    /// Ticks until the next expiration and interval, 0 when disarmed
+    fn current(&self) -> (u64, u64) {
+        let timers = TIMERS.lock();
+        match self.timer.and_then(|id| timers.get(id)) {
+            Some((expires, interval)) => (expires.saturating_sub(get_uptime_ticks()), interval),
+            None => (0, 0),
+        }
+    }
+
+    fn disarm(&mut self) -> (u64, u64) {
+        let current = self.current();
+        if let Some(id) = self.timer.take() {
+            TIMERS.lock().cancel(id);
+        }
+        current
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct TimerFdHandle {
+    timer: Arc<Mutex<TimerFd>>,
+}
+
+#[derive(Debug)]
+pub struct TimerFdSpecificFileData;
+
+impl FsSpecificFileData for TimerFdSpecificFileData {}
+
+/// Timer fds, only reachable through the timerfd syscalls
+#[derive(Debug)]
+pub struct TimerFdFs {
+    os_id: u64,
+    parent_fs_os_id: u64,
+    mnt: Option<VfsFile>,
+    root_fs: Option<WeakArcrwb<Vfs>>,
+
+    handles: FileHandleAllocator,
+}
+
+pub const TIMERFD_MOUNT: [char; 6] = ['t', 'i', 'm', 'e', 'r', 's'];
+pub const ANON_INODE_FS_MAGIC: u64 = 0x0904_1934;
+
+/// Returns the timer fd file system mounted at `/timers`
+pub fn get_timer_fd_fs() -> Result<Arcrwb<dyn FileSystem>, VfsError> {
+    let vfs = get_vfs();
+    let mut guard = vfs.write();
+    let file = guard.get_file(&VfsPath::from(&TIMERFD_MOUNT[..]))?;
+    drop(guard);
+    file.get_mounted_fs().ok_or(VfsError::FileSystemNotMounted)
+}
+
+impl TimerFdFs {
+    fn timer(&self, handle: u64) -> Result<Arc<Mutex<TimerFd>>, VfsError> {
+        unsafe {
+            let handle = self
+                .handles
+                .get_handle_data::<TimerFdHandle>(handle)
+                .ok_or(VfsError::BadHandle)?;
+            Ok((*handle).timer.clone())
+        }
+    }
+
+    /// A disarmed timer of the process `owner`
+    pub fn create_timer(&mut self, owner: u32, clock: TimerClock, nonblocking: bool) -> u64 {
+        self.handles.alloc_file_handle(TimerFdHandle {
+            timer: Arc::new(Mutex::new(TimerFd {
+                clock,
+                nonblocking,
+                owner,
+                timer: None,
+                expirations: Arc::new(ExpirationCounter::new()),
+            })),
+        })
+    }
+
+    pub fn clock(&self, handle: u64) -> Result<TimerClock, VfsError> {
+        Ok(self.timer(handle)?.lock().clock)
+    }
+
+    /// Ticks until the next expiration and interval, 0 when disarmed
+    pub fn get_time(&self, handle: u64) -> Result<(u64, u64), VfsError> {
+        Ok(self.timer(handle)?.lock().current())
+    }
+
+    /// Arms the timer to expire in `initial` ticks then every `interval` ticks, or disarms it if
+    /// `initial` is 0. The expirations not read yet are dropped, returns the previous setting
+    pub fn set_time(
+        &mut self,
+        handle: u64,
+        initial: u64,
+        interval: u64,
+    ) -> Result<(u64, u64), VfsError> {
+        let timer = self.timer(handle)?;
+        let mut timer = timer.lock();
+        let previous = timer.disarm();
+        timer.expirations.reset();
+        if initial != 0 {
+            timer.timer = Some(TIMERS.lock().add(
+                timer.owner,
+                get_uptime_ticks() + initial,
+                interval,
+                TimerEvent::Count(Arc::downgrade(&timer.expirations)),
+            ));
+        }
+        Ok(previous)
+    }
+}
+
+impl FileSystem for TimerFdFs {
+    fn os_id(&mut self) -> u64 {
+        self.os_id
+    }
+
+    fn fs_type(&mut self) -> String {
+        "timerfd".to_string()
+    }
+
+    fn fs_flush(&mut self) -> Result<(), VfsError> {
+        Err(VfsError::ActionNotAllowed)
+    }
+
+    fn host_block_device(&mut self) -> Option<Arcrwb<dyn BlockDevice>> {
+        None
+    }
+
+    fn statfs(&mut self) -> Result<FsStat, VfsError> {
+        Ok(FsStat::virtual_fs(
+            ANON_INODE_FS_MAGIC,
+            self.handles.count() as u64,
+        ))
+    }
+
+    fn get_root(&mut self) -> Result<VfsFile, VfsError> {
+        Ok(VfsFile::new(
+            VfsFileKind::Directory,
+            alloc::vec!['/'],
+            0,
+            self.parent_fs_os_id,
+            self.os_id,
+            Arc::new(TimerFdSpecificFileData),
+        ))
+    }
+
+    fn get_mount_point(&mut self) -> Result<Option<VfsFile>, VfsError> {
+        Ok(Some(
+            self.mnt
+                .as_ref()
+                .ok_or(VfsError::FileSystemNotMounted)?
+                .clone(),
+        ))
+    }
+
+    fn get_child(&mut self, file: &VfsFile, _child: &VfsName) -> Result<VfsFile, VfsError> {
+        if file.fs() != self.os_id {
+            return Err(VfsError::FileSystemMismatch);
+        }
+        if file.name() != ['/'] {
+            return Err(VfsError::NotDirectory);
+        }
+        Err(VfsError::PathNotFound)
+    }
+
+    fn list_children(&mut self, file: &VfsFile) -> Result<Vec<VfsFile>, VfsError> {
+        if file.fs() != self.os_id {
+            return Err(VfsError::FileSystemMismatch);
+        }
+        if file.name() != ['/'] {
+            return Err(VfsError::NotDirectory);
+        }
+        Ok(Vec::new())
+    }
+
+    default_get_file_implementation!();
+
+    fn get_stats(&mut self, file: &VfsFile) -> Result<FileStat, VfsError> {
+        if file.fs() != self.os_id {
+            return Err(VfsError::FileSystemMismatch);
+        }
+        Ok(FileStat {
+            size: 0,
+            created_at: 0,
+            modified_at: 0,
+            permissions: permissions!(Owner:Read, Group:Read, Other:Read).to_u64(),
+            is_file: false,
+            is_directory: true,
+            is_symlink: false,
+            owner_id: 0,
+            group_id: 0,
+            flags: FLAG_VIRTUAL | FLAG_SYSTEM,
+        })
+    }
+
+    fn create_child(
+        &mut self,
+        _directory: &VfsFile,
+        _name: &VfsName,
+        _kind: VfsFileKind,
+    ) -> Result<VfsFile, VfsError> {
+        Err(VfsError::ActionNotAllowed)
+    }
+
+    fn delete_file(&mut self, _file: &VfsFile) -> Result<(), VfsError> {
+        Err(VfsError::ActionNotAllowed)
+    }
+
+    fn on_mount(
+        &mut self,
+        mount_point: &VfsFile,
+        os_id: u64,
+        root_fs: WeakArcrwb<Vfs>,
+    ) -> Result<VfsFile, VfsError> {
+        self.root_fs = Some(root_fs);
+        self.parent_fs_os_id = mount_point.fs();
+        self.mnt = Some(mount_point.clone());
+        self.os_id = os_id;
+        self.handles.set_owner(os_id);
+        self.get_root()
+    }
+
+    fn on_pre_unmount(&mut self) -> Result<bool, VfsError> {
+        Ok(true)
+    }
+
+    fn on_unmount(&mut self) -> Result<(), VfsError> {
+        self.mnt = None;
+        self.os_id = 0;
+        self.parent_fs_os_id = 0;
+        for h in self.handles.iter().copied().collect::<Vec<u64>>() {
+            self.fclose(h)?;
+        }
+        Ok(())
+    }
+
+    fn get_vfs(&mut self) -> Result<WeakArcrwb<Vfs>, VfsError> {
+        Ok(self
+            .root_fs
+            .as_ref()
+            .ok_or(VfsError::FileSystemNotMounted)?
+            .clone())
+    }
+
+    fn fopen(&mut self, _file: &VfsFile, _mode: u64) -> Result<u64, VfsError> {
+        Err(VfsError::ActionNotAllowed)
+    }
+
+    fn fclose(&mut self, handle: u64) -> Result<(), VfsError> {
+        self.timer(handle)?.lock().disarm();
+        if self.handles.dealloc_file_handle::<TimerFdHandle>(handle) {
+            Ok(())
+        } else {
+            Err(VfsError::BadHandle)
+        }
+    }
+
+    fn fseek(&mut self, _handle: u64, _position: SeekPosition) -> Result<u64, VfsError> {
+        Err(VfsError::InvalidSeekPosition)
+    }
+
+    /// Reads the number of expirations as a `u64`
+    fn fread(&mut self, handle: u64, buf: &mut [u8]) -> Result<u64, VfsError> {
+        let timer = self.timer(handle)?;
+        let timer = timer.lock();
+        let buf = buf.get_mut(..8).ok_or(VfsError::BadBufferSize)?;
+        match timer.expirations.take() {
+            0 => Err(VfsError::WouldBlock),
+            count => {
+                buf.copy_from_slice(&count.to_ne_bytes());
+                Ok(8)
+            }
+        }
+    }
+
+    fn fwrite(&mut self, _handle: u64, _buf: &[u8]) -> Result<u64, VfsError> {
+        Err(VfsError::ActionNotAllowed)
+    }
+
+    fn fflush(&mut self, handle: u64) -> Result<(), VfsError> {
+        self.timer(handle)?;
+        Ok(())
+    }
+
+    fn fsync(&mut self, handle: u64) -> Result<(), VfsError> {
+        self.timer(handle)?;
+        Ok(())
+    }
+
+    fn fstat(&self, handle: u64) -> Result<FileStat, VfsError> {
+        self.timer(handle)?;
+        Ok(FileStat {
+            size: 0,
+            created_at: 0,
+            modified_at: 0,
+            permissions: permissions!(Owner:Read, Owner:Write).to_u64(),
+            is_file: true,
+            is_directory: false,
+            is_symlink: false,
+            owner_id: 0,
+            group_id: 0,
+            flags: FLAG_VIRTUAL | FLAG_SYSTEM,
+        })
+    }
+
+    fn ftruncate(&mut self, _handle: u64) -> Result<u64, VfsError> {
+        Err(VfsError::ActionNotAllowed)
+    }
+
+    fn fpoll(&mut self, handle: u64, events: PollEvents) -> Result<PollEvents, VfsError> {
+        let timer = self.timer(handle)?;
+        let ready = if timer.lock().expirations.count() != 0 {
+            PollEvent::In.into()
+        } else {
+            PollEvents::empty()
+        };
+        Ok(ready & (events | POLL_ALWAYS_REPORTED))
+    }
+
+    fn fwait_queue(&mut self, handle: u64) -> Result<Option<Arc<WaitQueue>>, VfsError> {
+        let timer = self.timer(handle)?;
+        let queue = timer.lock().expirations.wait_queue.clone();
+        Ok(Some(queue))
+    }
+
+    fn fblocks(&mut self, handle: u64) -> bool {
+        self.timer(handle)
+            .is_ok_and(|timer| !timer.lock().nonblocking)
+    }
+}
+
+pub fn init_timerfd_fs(vfs: &mut Vfs) {
+    let fs = TimerFdFs {
+        os_id: 0,
+        parent_fs_os_id: 0,
+        mnt: None,
+        root_fs: None,
+        handles: FileHandleAllocator::default(),
+    };
+
+    vfs.mount(&TIMERFD_MOUNT, Box::new(fs), 0).unwrap();
+}
@@ -0,0 +1,622 @@
+use alloc::collections::BTreeMap;
+use alloc::string::ToString;
+use alloc::sync::Arc;
+use alloc::{string::String, vec::Vec};
+
+use crate::data::path::{VfsName, VfsPath};
+use crate::drivers::fs::dirent::{
+    seek_directory, DirectoryRecord, DirectoryRecordWriter, DT_DIR, DT_LNK, DT_REG,
+};
+use crate::drivers::fs::virt::devfs::fseek_helper;
+use crate::drivers::time::get_unix_timestamp;
+use crate::drivers::vfs::{
+    default_get_file_implementation, Arcrwb, BlockDevice, FileHandleAllocator, FileStat,
+    FileSystem, FsSpecificFileData, FsStat, SeekPosition, Vfs, VfsError, VfsFile, VfsFileKind,
+    WeakArcrwb, FLAG_VIRTUAL, OPEN_MODE_APPEND, OPEN_MODE_CREATE, OPEN_MODE_NO_RESIZE,
+    OPEN_MODE_READ, OPEN_MODE_WRITE,
+};
+
+pub const TMPFS_MAGIC: u64 = 0x0102_1994;
+
+const ROOT_INO: u64 = 1;
+const MAX_NAME_LENGTH: u64 = 255;
+
+#[derive(Debug)]
+pub enum TmpNodeData {
+    File(Vec<u8>),
+    /// Children by name
+    Directory(BTreeMap<Vec<u8>, u64>),
+    /// Target of the link
+    Symlink(Vec<u8>),
+}
+
+#[derive(Debug)]
+pub struct TmpNode {
+    pub data: TmpNodeData,
+    /// Permission bits, `0o7777`
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub created_at: u64,
+    pub modified_at: u64,
+    nlink: u32,
+    /// Open handles, an unlinked node lives until the last one is closed
+    open: u32,
+}
+
+impl TmpNode {
+    pub fn new(data: TmpNodeData, mode: u32, uid: u32, gid: u32, mtime: u64) -> Self {
+        Self {
+            data,
+            mode: mode & 0o7777,
+            uid,
+            gid,
+            created_at: mtime,
+            modified_at: mtime,
+            nlink: 0,
+            open: 0,
+        }
+    }
+
+    fn size(&self) -> u64 {
+        match &self.data {
+            TmpNodeData::File(data) | TmpNodeData::Symlink(data) => data.len() as u64,
+            TmpNodeData::Directory(_) => 0,
+        }
+    }
+
+    fn stat(&self) -> FileStat {
+        FileStat {
+            size: self.size(),
+            created_at: self.created_at,
+            modified_at: self.modified_at,
+            permissions: self.mode as u64,
+            is_file: matches!(self.data, TmpNodeData::File(_)),
+            is_directory: matches!(self.data, TmpNodeData::Directory(_)),
+            is_symlink: matches!(self.data, TmpNodeData::Symlink(_)),
+            owner_id: self.uid as u64,
+            group_id: self.gid as u64,
+            flags: FLAG_VIRTUAL,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct TmpFsSpecificFileData {
+    ino: u64,
+    parent: u64,
+}
+
+impl FsSpecificFileData for TmpFsSpecificFileData {}
+
+#[derive(Debug, Clone)]
+struct TmpFsHandle {
+    ino: u64,
+    position: u64,
+    mode: u64,
+}
+
+/// File system kept in memory, holds the unpacked initramfs
+#[derive(Debug)]
+pub struct TmpFs {
+    os_id: u64,
+    parent_fs_os_id: u64,
+    mnt: Option<VfsFile>,
+    root_fs: Option<WeakArcrwb<Vfs>>,
+
+    nodes: BTreeMap<u64, TmpNode>,
+    next_ino: u64,
+    handles: FileHandleAllocator,
+}
+
+impl Default for TmpFs {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl TmpFs {
+    /// An empty file system, the root is owned by root with mode `0o755`
+    pub fn new() -> Self {
+        let mut root = TmpNode::new(
+            TmpNodeData::Directory(BTreeMap::new()),
+            0o755,
+            0,
+            0,
+            get_unix_timestamp(),
+        );
+        root.nlink = 1;
+        Self {
+            os_id: 0,
+            parent_fs_os_id: 0,
+            mnt: None,
+            root_fs: None,
+            nodes: BTreeMap::from([(ROOT_INO, root)]),
+            next_ino: ROOT_INO + 1,
+            handles: FileHandleAllocator::default(),
+        }
+    }
+
+    fn node(&self, ino: u64) -> Result<&TmpNode, VfsError> {
+        self.nodes.get(&ino).ok_or(VfsError::EntryNotFound)
+    }
+
+    pub fn node_mut(&mut self, ino: u64) -> Result<&mut TmpNode, VfsError> {
+        self.nodes.get_mut(&ino).ok_or(VfsError::EntryNotFound)
+    }
+
+    fn file_data(&self, file: &VfsFile) -> Result<(u64, u64), VfsError> {
+        if file.fs() != self.os_id {
+            return Err(VfsError::FileSystemMismatch);
+        }
+        let data = file.get_fs_specific_data();
+        let data = (*data)
+            .as_any()
+            .downcast_ref::<TmpFsSpecificFileData>()
+            .ok_or(VfsError::FileSystemMismatch)?;
+        Ok((data.ino, data.parent))
+    }
+
+    fn handle(&self, handle: u64) -> Result<TmpFsHandle, VfsError> {
+        unsafe {
+            let data = self
+                .handles
+                .get_handle_data::<TmpFsHandle>(handle)
+                .ok_or(VfsError::BadHandle)?;
+            // SAFETY: the handle is allocated, the data is copied out before `self` can change
+            Ok((*data).clone())
+        }
+    }
+
+    fn handle_mut(&mut self, handle: u64) -> Result<&mut TmpFsHandle, VfsError> {
+        unsafe {
+            let data = self
+                .handles
+                .get_handle_data::<TmpFsHandle>(handle)
+                .ok_or(VfsError::BadHandle)?;
+            // SAFETY: the handle is allocated and `&mut self` keeps any other reference to it out
+            Ok(&mut *data)
+        }
+    }
+
+    fn make_file(&self, ino: u64, parent: u64, name: Vec<char>) -> Result<VfsFile, VfsError> {
+        let node = self.node(ino)?;
+        let kind = match node.data {
+            TmpNodeData::Directory(_) => VfsFileKind::Directory,
+            TmpNodeData::File(_) => VfsFileKind::File,
+            TmpNodeData::Symlink(_) => VfsFileKind::Symlink,
+        };
+        Ok(VfsFile::new(
+            kind,
+            name,
+            node.size(),
+            self.parent_fs_os_id,
+            self.os_id,
+            Arc::new(TmpFsSpecificFileData { ino, parent }),
+        ))
+    }
+
+    fn children(&self, ino: u64) -> Result<&BTreeMap<Vec<u8>, u64>, VfsError> {
+        match &self.node(ino)?.data {
+            TmpNodeData::Directory(children) => Ok(children),
+            _ => Err(VfsError::NotDirectory),
+        }
+    }
+
+    fn children_mut(&mut self, ino: u64) -> Result<&mut BTreeMap<Vec<u8>, u64>, VfsError> {
+        match &mut self.node_mut(ino)?.data {
+            TmpNodeData::Directory(children) => Ok(children),
+            _ => Err(VfsError::NotDirectory),
+        }
+    }
+
+    /// Adds `node` to the directory `parent` under `name`, returns its inode number
+    pub fn insert(
+        &mut self,
+        parent: u64,
+        name: &VfsName,
+        mut node: TmpNode,
+    ) -> Result<u64, VfsError> {
+        if name.as_bytes().len() as u64 > MAX_NAME_LENGTH {
+            return Err(VfsError::NameTooLong);
+        }
+        if name.is_dot() || name.is_dot_dot() {
+            return Err(VfsError::InvalidArgument);
+        }
+        let ino = self.next_ino;
+        let children = self.children_mut(parent)?;
+        if children.contains_key(name.as_bytes()) {
+            return Err(VfsError::FileAlreadyExists);
+        }
+        children.insert(name.as_bytes().to_vec(), ino);
+        self.next_ino += 1;
+        node.nlink = 1;
+        self.nodes.insert(ino, node);
+        Ok(ino)
+    }
+
+    /// Inode number of the root directory
+    pub fn root_ino(&self) -> u64 {
+        ROOT_INO
+    }
+
+    /// Inode number of `name` in the directory `parent`
+    pub fn lookup(&self, parent: u64, name: &VfsName) -> Result<u64, VfsError> {
+        self.children(parent)?
+            .get(name.as_bytes())
+            .copied()
+            .ok_or(VfsError::PathNotFound)
+    }
+
+    /// Total size of the files, for `statfs`
+    fn used_bytes(&self) -> u64 {
+        self.nodes.values().map(TmpNode::size).sum()
+    }
+
+    fn release(&mut self, ino: u64) {
+        if self
+            .nodes
+            .get(&ino)
+            .is_some_and(|node| node.nlink == 0 && node.open == 0)
+        {
+            self.nodes.remove(&ino);
+        }
+    }
+}
+
+impl FileSystem for TmpFs {
+    fn os_id(&mut self) -> u64 {
+        self.os_id
+    }
+
+    fn fs_type(&mut self) -> String {
+        "tmpfs".to_string()
+    }
+
+    fn fs_flush(&mut self) -> Result<(), VfsError> {
+        Ok(())
+    }
+
+    fn host_block_device(&mut self) -> Option<Arcrwb<dyn BlockDevice>> {
+        None
+    }
+
+    fn statfs(&mut self) -> Result<FsStat, VfsError> {
+        Ok(FsStat {
+            total_blocks: self.used_bytes().div_ceil(4096),
+            max_name_length: MAX_NAME_LENGTH,
+            ..FsStat::virtual_fs(TMPFS_MAGIC, self.nodes.len() as u64)
+        })
+    }
+
+    fn get_root(&mut self) -> Result<VfsFile, VfsError> {
+        self.make_file(ROOT_INO, ROOT_INO, alloc::vec!['/'])
+    }
+
+    fn get_mount_point(&mut self) -> Result<Option<VfsFile>, VfsError> {
+        Ok(Some(
+            self.mnt
+                .as_ref()
+                .ok_or(VfsError::FileSystemNotMounted)?
+                .clone(),
+        ))
+    }
+
+    fn get_child(&mut self, file: &VfsFile, child: &VfsName) -> Result<VfsFile, VfsError> {
+        let (ino, _) = self.file_data(file)?;
+        let child_ino = self.lookup(ino, child)?;
+        self.make_file(child_ino, ino, child.to_chars())
+    }
+
+    fn list_children(&mut self, file: &VfsFile) -> Result<Vec<VfsFile>, VfsError> {
+        let (ino, _) = self.file_data(file)?;
+        self.children(ino)?
+            .iter()
+            .map(|(name, child)| {
+                let name = VfsName::new(name).ok_or(VfsError::InvalidDataStructure)?;
+                self.make_file(*child, ino, name.to_chars())
+            })
+            .collect()
+    }
+
+    default_get_file_implementation!();
+
+    fn get_stats(&mut self, file: &VfsFile) -> Result<FileStat, VfsError> {
+        let (ino, _) = self.file_data(file)?;
+        Ok(self.node(ino)?.stat())
+    }
+
+    fn create_child(
+        &mut self,
+        directory: &VfsFile,
+        name: &VfsName,
+        kind: VfsFileKind,
+    ) -> Result<VfsFile, VfsError> {
+        let (parent, _) = self.file_data(directory)?;
+        let (data, mode) = match kind {
+            VfsFileKind::File => (TmpNodeData::File(Vec::new()), 0o644),
+            VfsFileKind::Directory => (TmpNodeData::Directory(BTreeMap::new()), 0o755),
+            _ => return Err(VfsError::ActionNotAllowed),
+        };
+        let now = get_unix_timestamp();
+        let ino = self.insert(parent, name, TmpNode::new(data, mode, 0, 0, now))?;
+        self.node_mut(parent)?.modified_at = now;
+        self.make_file(ino, parent, name.to_chars())
+    }
+
+    fn delete_file(&mut self, file: &VfsFile) -> Result<(), VfsError> {
+        let (ino, parent) = self.file_data(file)?;
+        if ino == ROOT_INO {
+            return Err(VfsError::ActionNotAllowed);
+        }
+        if self
+            .children(ino)
+            .is_ok_and(|children| !children.is_empty())
+        {
+            return Err(VfsError::DirectoryNotEmpty);
+        }
+        let name = VfsPath::from(file.name()).into_bytes();
+        let children = self.children_mut(parent)?;
+        if children.get(&name) != Some(&ino) {
+            return Err(VfsError::EntryNotFound);
+        }
+        children.remove(&name);
+        self.node_mut(parent)?.modified_at = get_unix_timestamp();
+        let node = self.node_mut(ino)?;
+        node.nlink = node.nlink.saturating_sub(1);
+        self.release(ino);
+        Ok(())
+    }
+
+    fn link(
+        &mut self,
+        existing: &VfsFile,
+        new_parent: &VfsFile,
+        new_name: &VfsName,
+    ) -> Result<VfsFile, VfsError> {
+        let (ino, _) = self.file_data(existing)?;
+        let (parent, _) = self.file_data(new_parent)?;
+        if matches!(self.node(ino)?.data, TmpNodeData::Directory(_)) {
+            return Err(VfsError::ActionNotAllowed);
+        }
+        let children = self.children_mut(parent)?;
+        if children.contains_key(new_name.as_bytes()) {
+            return Err(VfsError::FileAlreadyExists);
+        }
+        children.insert(new_name.as_bytes().to_vec(), ino);
+        self.node_mut(ino)?.nlink += 1;
+        self.make_file(ino, parent, new_name.to_chars())
+    }
+
+    fn read_link(&mut self, file: &VfsFile) -> Result<VfsPath, VfsError> {
+        let (ino, _) = self.file_data(file)?;
+        match &self.node(ino)?.data {
+            TmpNodeData::Symlink(target) => Ok(VfsPath::from_bytes(target.clone())),
+            _ => Err(VfsError::InvalidArgument),
+        }
+    }
+
+    fn file_identity(&mut self, file: &VfsFile) -> u64 {
+        self.file_data(file).map_or(0, |(ino, _)| ino)
+    }
+
+    fn on_mount(
+        &mut self,
+        mount_point: &VfsFile,
+        os_id: u64,
+        root_fs: WeakArcrwb<Vfs>,
+    ) -> Result<VfsFile, VfsError> {
+        self.root_fs = Some(root_fs);
+        self.parent_fs_os_id = mount_point.fs();
+        self.mnt = Some(mount_point.clone());
+        self.os_id = os_id;
+        self.handles.set_owner(os_id);
+        self.get_root()
+    }
+
+    fn on_pre_unmount(&mut self) -> Result<bool, VfsError> {
+        Ok(true)
+    }
+
+    fn on_unmount(&mut self) -> Result<(), VfsError> {
+        for h in self.handles.iter().copied().collect::<Vec<u64>>() {
+            self.fclose(h)?;
+        }
+        self.mnt = None;
+        self.os_id = 0;
+        self.parent_fs_os_id = 0;
+        Ok(())
+    }
+
+    fn get_vfs(&mut self) -> Result<WeakArcrwb<Vfs>, VfsError> {
+        Ok(self
+            .root_fs
+            .as_ref()
+            .ok_or(VfsError::FileSystemNotMounted)?
+            .clone())
+    }
+
+    fn fopen(&mut self, file: &VfsFile, mode: u64) -> Result<u64, VfsError> {
+        let (ino, _) = self.file_data(file)?;
+        let node = self.node_mut(ino)?;
+        if matches!(node.data, TmpNodeData::Directory(_))
+            && mode & (OPEN_MODE_WRITE | OPEN_MODE_APPEND | OPEN_MODE_CREATE) != 0
+        {
+            return Err(VfsError::NotFile);
+        }
+        // Paths are resolved through symlinks, there is nothing to open in one
+        if matches!(node.data, TmpNodeData::Symlink(_)) {
+            return Err(VfsError::ActionNotAllowed);
+        }
+        node.open += 1;
+        Ok(self.handles.alloc_file_handle(TmpFsHandle {
+            ino,
+            position: 0,
+            mode,
+        }))
+    }
+
+    fn fclose(&mut self, handle: u64) -> Result<(), VfsError> {
+        let ino = self.handle(handle)?.ino;
+        if !self.handles.dealloc_file_handle::<TmpFsHandle>(handle) {
+            return Err(VfsError::BadHandle);
+        }
+        if let Some(node) = self.nodes.get_mut(&ino) {
+            node.open = node.open.saturating_sub(1);
+        }
+        self.release(ino);
+        Ok(())
+    }
+
+    fn fseek(&mut self, handle: u64, position: SeekPosition) -> Result<u64, VfsError> {
+        let data = self.handle(handle)?;
+        let node = self.node(data.ino)?;
+        let position = match node.data {
+            TmpNodeData::Directory(_) => seek_directory(position, data.position)?,
+            _ => fseek_helper(position, data.position, node.size())
+                .ok_or(VfsError::InvalidSeekPosition)?,
+        };
+        self.handle_mut(handle)?.position = position;
+        Ok(position)
+    }
+
+    fn fread(&mut self, handle: u64, buf: &mut [u8]) -> Result<u64, VfsError> {
+        let TmpFsHandle { ino, position, .. } = self.handle(handle)?;
+        if let TmpNodeData::Directory(children) = &self.node(ino)?.data {
+            // The position is the index of the next child in name order
+            let mut writer = DirectoryRecordWriter::new(buf);
+            let mut read = 0;
+            for (name, child) in children.iter().skip(position as usize) {
+                let kind = match self.node(*child)?.data {
+                    TmpNodeData::File(_) => DT_REG,
+                    TmpNodeData::Directory(_) => DT_DIR,
+                    TmpNodeData::Symlink(_) => DT_LNK,
+                };
+                let record = DirectoryRecord {
+                    inode: *child,
+                    kind,
+                    name,
+                };
+                if !writer.push(&record)? {
+                    break;
+                }
+                read += 1;
+            }
+            self.handle_mut(handle)?.position += read;
+            return Ok(writer.written());
+        }
+        let read = self.fread_at(handle, position, buf)?;
+        self.handle_mut(handle)?.position += read;
+        Ok(read)
+    }
+
+    fn fwrite(&mut self, handle: u64, buf: &[u8]) -> Result<u64, VfsError> {
+        let data = self.handle(handle)?;
+        let position = if data.mode & OPEN_MODE_APPEND != 0 {
+            self.node(data.ino)?.size()
+        } else {
+            data.position
+        };
+        let written = self.fwrite_at(handle, position, buf)?;
+        self.handle_mut(handle)?.position = position + written;
+        Ok(written)
+    }
+
+    fn fseekable(&mut self, handle: u64) -> bool {
+        self.handle(handle).is_ok()
+    }
+
+    fn fread_at(&mut self, handle: u64, offset: u64, buf: &mut [u8]) -> Result<u64, VfsError> {
+        let data = self.handle(handle)?;
+        if data.mode & OPEN_MODE_READ == 0 {
+            return Err(VfsError::ActionNotAllowed);
+        }
+        let TmpNodeData::File(contents) = &self.node(data.ino)?.data else {
+            return Err(VfsError::NotFile);
+        };
+        let start = (offset as usize).min(contents.len());
+        let count = buf.len().min(contents.len() - start);
+        buf[..count].copy_from_slice(&contents[start..start + count]);
+        Ok(count as u64)
+    }
+
+    fn fwrite_at(&mut self, handle: u64, offset: u64, buf: &[u8]) -> Result<u64, VfsError> {
+        let TmpFsHandle { ino, mode, .. } = self.handle(handle)?;
+        if mode & OPEN_MODE_WRITE == 0 {
+            return Err(VfsError::ActionNotAllowed);
+        }
+        let node = self.node_mut(ino)?;
+        let TmpNodeData::File(contents) = &mut node.data else {
+            return Err(VfsError::NotFile);
+        };
+        let offset = usize::try_from(offset).map_err(|_| VfsError::OutOfBounds)?;
+        let end = offset.checked_add(buf.len()).ok_or(VfsError::OutOfBounds)?;
+        if end > contents.len() {
+            if mode & OPEN_MODE_NO_RESIZE != 0 {
+                return Err(VfsError::ActionNotAllowed);
+            }
+            contents.resize(end, 0);
+        }
+        contents[offset..end].copy_from_slice(buf);
+        node.modified_at = get_unix_timestamp();
+        Ok(buf.len() as u64)
+    }
+
+    fn fflush(&mut self, handle: u64) -> Result<(), VfsError> {
+        self.handle(handle)?;
+        Ok(())
+    }
+
+    fn fsync(&mut self, handle: u64) -> Result<(), VfsError> {
+        self.handle(handle)?;
+        Ok(())
+    }
+
+    fn fstat(&self, handle: u64) -> Result<FileStat, VfsError> {
+        Ok(self.node(self.handle(handle)?.ino)?.stat())
+    }
+
+    fn ftruncate(&mut self, handle: u64) -> Result<u64, VfsError> {
+        let TmpFsHandle {
+            ino,
+            position,
+            mode,
+        } = self.handle(handle)?;
+        if mode & OPEN_MODE_WRITE == 0 || mode & OPEN_MODE_NO_RESIZE != 0 {
+            return Err(VfsError::ActionNotAllowed);
+        }
+        let node = self.node_mut(ino)?;
+        let TmpNodeData::File(contents) = &mut node.data else {
+            return Err(VfsError::NotFile);
+        };
+        contents.truncate(position as usize);
+        node.modified_at = get_unix_timestamp();
+        Ok(position)
+    }
+
+    fn fchmod(&mut self, handle: u64, mode: u64) -> Result<(), VfsError> {
+        let ino = self.handle(handle)?.ino;
+        self.node_mut(ino)?.mode = (mode & 0o7777) as u32;
+        Ok(())
+    }
+
+    fn fchown(&mut self, handle: u64, uid: Option<u32>, gid: Option<u32>) -> Result<(), VfsError> {
+        let ino = self.handle(handle)?.ino;
+        let node = self.node_mut(ino)?;
+        node.uid = uid.unwrap_or(node.uid);
+        node.gid = gid.unwrap_or(node.gid);
+        Ok(())
+    }
+
+    fn futimens(
+        &mut self,
+        handle: u64,
+        _atime: Option<u64>,
+        mtime: Option<u64>,
+    ) -> Result<(), VfsError> {
+        let ino = self.handle(handle)?.ino;
+        let node = self.node_mut(ino)?;
+        node.modified_at = mtime.unwrap_or(node.modified_at);
+        Ok(())
+    }
+}
@@ -0,0 +1,646 @@
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::string::ToString;
+use alloc::sync::Arc;
+use alloc::{boxed::Box, string::String, vec::Vec};
+use spin::rwlock::RwLock;
+
+use crate::data::path::{VfsName, VfsPath};
+use crate::drivers::fs::virt::pipefs::Pipe;
+use crate::drivers::vfs::{
+    arcrwb_new_from_box, default_get_file_implementation, get_vfs, Arcrwb, BlockDevice,
+    FileHandleAllocator, FileStat, FileSystem, FsSpecificFileData, FsStat, PollEvent, PollEvents,
+    SeekPosition, Vfs, VfsError, VfsFile, VfsFileKind, WeakArcrwb, FLAG_SYSTEM, FLAG_VIRTUAL,
+    POLL_ALWAYS_REPORTED,
+};
+use crate::permissions;
+use crate::process::wait::WaitQueue;
+
+pub const UNIX_SOCKET_BUFFER_SIZE: usize = 64 * 1024;
+pub const UNIX_SOCKET_MAX_BACKLOG: usize = 128;
+
+#[derive(Debug)]
+pub enum UnixSocketError {
+    Vfs(VfsError),
+    NotSocket,
+    InvalidState,
+    AddressInUse,
+    NameNotFound,
+    ConnectionRefused,
+    AlreadyConnected,
+    NotListening,
+    /// The operation has to wait, the caller can sleep on the queue and try again
+    WouldBlock(Arc<WaitQueue>),
+}
+
+impl From<VfsError> for UnixSocketError {
+    fn from(value: VfsError) -> Self {
+        UnixSocketError::Vfs(value)
+    }
+}
+
+/// One end of a connected stream, reads from `rx` and writes to `tx`.
+/// Both directions share the same wait queue
+#[derive(Debug, Clone)]
+pub struct UnixStreamEnd {
+    rx: Arcrwb<Pipe>,
+    tx: Arcrwb<Pipe>,
+    wait_queue: Arc<WaitQueue>,
+}
+
+impl UnixStreamEnd {
+    pub fn pair() -> (UnixStreamEnd, UnixStreamEnd) {
+        let wait_queue = Arc::new(WaitQueue::new());
+        let new_pipe = || {
+            let mut pipe = Pipe::new_anonymous(UNIX_SOCKET_BUFFER_SIZE);
+            pipe.readers = 1;
+            pipe.writers = 1;
+            pipe.wait_queue = wait_queue.clone();
+            arcrwb_new_from_box(Box::new(pipe))
+        };
+        let (a, b) = (new_pipe(), new_pipe());
+        (
+            UnixStreamEnd {
+                rx: a.clone(),
+                tx: b.clone(),
+                wait_queue: wait_queue.clone(),
+            },
+            UnixStreamEnd {
+                rx: b,
+                tx: a,
+                wait_queue,
+            },
+        )
+    }
+
+    fn read(&self, buf: &mut [u8]) -> Result<u64, VfsError> {
+        let mut rx = self.rx.write();
+        if rx.is_empty() {
+            if rx.writers == 0 {
+                // EOF
+                return Ok(0);
+            }
+            return Err(VfsError::WouldBlock);
+        }
+        let read = rx.read(buf);
+        drop(rx);
+        self.wait_queue.wake_all();
+        Ok(read as u64)
+    }
+
+    fn write(&self, buf: &[u8]) -> Result<u64, VfsError> {
+        let mut tx = self.tx.write();
+        if tx.readers == 0 {
+            return Err(VfsError::BrokenPipe);
+        }
+        if tx.is_full() {
+            return Err(VfsError::WouldBlock);
+        }
+        let written = tx.write(buf);
+        drop(tx);
+        self.wait_queue.wake_all();
+        Ok(written as u64)
+    }
+
+    fn poll(&self) -> PollEvents {
+        let mut ready = PollEvents::empty();
+        let rx = self.rx.read();
+        if !rx.is_empty() {
+            ready.set(PollEvent::In);
+        }
+        if rx.writers == 0 {
+            ready.set(PollEvent::HangUp);
+        }
+        drop(rx);
+        let tx = self.tx.read();
+        if tx.readers == 0 {
+            ready.set(PollEvent::Error);
+        } else if !tx.is_full() {
+            ready.set(PollEvent::Out);
+        }
+        ready
+    }
+
+    /// Shuts down both directions, the peer reads EOF once the buffer is drained
+    fn close(&self) {
+        let mut tx = self.tx.write();
+        tx.writers = 0;
+        tx.closed = true;
+        drop(tx);
+        let mut rx = self.rx.write();
+        rx.readers = 0;
+        rx.closed = true;
+        drop(rx);
+        self.wait_queue.wake_all();
+    }
+}
+
+#[derive(Debug)]
+pub enum UnixSocketState {
+    Unbound,
+    Bound(Vec<char>),
+    Listening {
+        name: Vec<char>,
+        backlog: VecDeque<UnixStreamEnd>,
+        max_backlog: usize,
+    },
+    Connected(UnixStreamEnd),
+}
+
+#[derive(Debug)]
+pub struct UnixStreamSocket {
+    pub state: UnixSocketState,
+    pub nonblocking: bool,
+    /// Woken when a connection is queued on or taken from a listening socket
+    pub wait_queue: Arc<WaitQueue>,
+}
+
+impl UnixStreamSocket {
+    fn new(state: UnixSocketState, nonblocking: bool) -> Self {
+        Self {
+            state,
+            nonblocking,
+            wait_queue: Arc::new(WaitQueue::new()),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct UnixSockFsHandle {
+    socket: Arc<RwLock<UnixStreamSocket>>,
+}
+
+#[derive(Debug)]
+pub enum UnixSockFsSpecificFileData {
+    UnixSockFsRoot,
+    UnixSockFsSocket(Vec<char>),
+}
+
+impl FsSpecificFileData for UnixSockFsSpecificFileData {}
+
+/// Local stream sockets. Names bound to a path appear as files in this file system,
+/// abstract names (starting with a nul char) live in the same namespace but are not listed.
+#[derive(Debug)]
+pub struct UnixSockFs {
+    os_id: u64,
+    parent_fs_os_id: u64,
+    mnt: Option<VfsFile>,
+    root_fs: Option<WeakArcrwb<Vfs>>,
+
+    handles: FileHandleAllocator,
+    names: BTreeMap<Vec<char>, Arc<RwLock<UnixStreamSocket>>>,
+}
+
+pub const UNIX_SOCKFS_MOUNT: [char; 7] = ['s', 'o', 'c', 'k', 'e', 't', 's'];
+pub const SOCKFS_MAGIC: u64 = 0x534F_434B;
+
+/// Returns the unix socket file system mounted at `/sockets`
+pub fn get_unix_sock_fs() -> Result<Arcrwb<dyn FileSystem>, VfsError> {
+    let vfs = get_vfs();
+    let mut guard = vfs.write();
+    let file = guard.get_file(&VfsPath::from(&UNIX_SOCKFS_MOUNT[..]))?;
+    drop(guard);
+    file.get_mounted_fs().ok_or(VfsError::FileSystemNotMounted)
+}
+
+impl UnixSockFs {
+    fn socket(&self, handle: u64) -> Result<Arc<RwLock<UnixStreamSocket>>, VfsError> {
+        unsafe {
+            let handle = self
+                .handles
+                .get_handle_data::<UnixSockFsHandle>(handle)
+                .ok_or(VfsError::BadHandle)?;
+            Ok((*handle).socket.clone())
+        }
+    }
+
+    fn alloc_socket(&mut self, socket: UnixStreamSocket) -> u64 {
+        self.handles.alloc_file_handle(UnixSockFsHandle {
+            socket: Arc::new(RwLock::new(socket)),
+        })
+    }
+
+    pub fn create_socket(&mut self, nonblocking: bool) -> u64 {
+        self.alloc_socket(UnixStreamSocket::new(UnixSocketState::Unbound, nonblocking))
+    }
+
+    /// Returns two handles connected to each other
+    pub fn create_pair(&mut self, nonblocking: bool) -> (u64, u64) {
+        let (a, b) = UnixStreamEnd::pair();
+        (
+            self.alloc_socket(UnixStreamSocket::new(
+                UnixSocketState::Connected(a),
+                nonblocking,
+            )),
+            self.alloc_socket(UnixStreamSocket::new(
+                UnixSocketState::Connected(b),
+                nonblocking,
+            )),
+        )
+    }
+
+    pub fn is_nonblocking(&self, handle: u64) -> Result<bool, VfsError> {
+        Ok(self.socket(handle)?.read().nonblocking)
+    }
+
+    pub fn bind(&mut self, handle: u64, name: Vec<char>) -> Result<(), UnixSocketError> {
+        let socket = self.socket(handle)?;
+        let mut guard = socket.write();
+        if !matches!(guard.state, UnixSocketState::Unbound) {
+            return Err(UnixSocketError::InvalidState);
+        }
+        if name.is_empty() || (name[0] != '\0' && name.contains(&'/')) {
+            return Err(VfsError::InvalidArgument.into());
+        }
+        if self.names.contains_key(&name) {
+            return Err(UnixSocketError::AddressInUse);
+        }
+        self.names.insert(name.clone(), socket.clone());
+        guard.state = UnixSocketState::Bound(name);
+        Ok(())
+    }
+
+    pub fn listen(&mut self, handle: u64, backlog: usize) -> Result<(), UnixSocketError> {
+        let socket = self.socket(handle)?;
+        let mut guard = socket.write();
+        let max_backlog = backlog.clamp(1, UNIX_SOCKET_MAX_BACKLOG);
+        match &mut guard.state {
+            UnixSocketState::Bound(name) => {
+                let name = core::mem::take(name);
+                guard.state = UnixSocketState::Listening {
+                    name,
+                    backlog: VecDeque::new(),
+                    max_backlog,
+                };
+                Ok(())
+            }
+            UnixSocketState::Listening {
+                max_backlog: max, ..
+            } => {
+                *max = max_backlog;
+                Ok(())
+            }
+            UnixSocketState::Connected(..) => Err(UnixSocketError::AlreadyConnected),
+            UnixSocketState::Unbound => Err(UnixSocketError::InvalidState),
+        }
+    }
+
+    /// Takes a pending connection and returns a new handle for it
+    pub fn accept(&mut self, handle: u64) -> Result<u64, UnixSocketError> {
+        let socket = self.socket(handle)?;
+        let mut guard = socket.write();
+        let nonblocking = guard.nonblocking;
+        let wait_queue = guard.wait_queue.clone();
+        let UnixSocketState::Listening { backlog, .. } = &mut guard.state else {
+            return Err(UnixSocketError::NotListening);
+        };
+        let Some(end) = backlog.pop_front() else {
+            return Err(UnixSocketError::WouldBlock(wait_queue));
+        };
+        drop(guard);
+        // A connect may be waiting for room in the backlog
+        wait_queue.wake_all();
+
+        Ok(self.alloc_socket(UnixStreamSocket::new(
+            UnixSocketState::Connected(end),
+            nonblocking,
+        )))
+    }
+
+    pub fn connect(&mut self, handle: u64, name: &[char]) -> Result<(), UnixSocketError> {
+        let socket = self.socket(handle)?;
+        let mut guard = socket.write();
+        match guard.state {
+            UnixSocketState::Unbound | UnixSocketState::Bound(..) => {}
+            UnixSocketState::Connected(..) => return Err(UnixSocketError::AlreadyConnected),
+            UnixSocketState::Listening { .. } => return Err(UnixSocketError::InvalidState),
+        }
+
+        let listener = self.names.get(name).ok_or(if name.first() == Some(&'\0') {
+            UnixSocketError::ConnectionRefused
+        } else {
+            UnixSocketError::NameNotFound
+        })?;
+        if Arc::ptr_eq(listener, &socket) {
+            return Err(UnixSocketError::ConnectionRefused);
+        }
+        let mut lguard = listener.write();
+        let wait_queue = lguard.wait_queue.clone();
+        let UnixSocketState::Listening {
+            backlog,
+            max_backlog,
+            ..
+        } = &mut lguard.state
+        else {
+            return Err(UnixSocketError::ConnectionRefused);
+        };
+        if backlog.len() >= *max_backlog {
+            return Err(UnixSocketError::WouldBlock(wait_queue));
+        }
+
+        let (ours, theirs) = UnixStreamEnd::pair();
+        backlog.push_back(theirs);
+        drop(lguard);
+        wait_queue.wake_all();
+
+        if let UnixSocketState::Bound(name) = &guard.state {
+            self.names.remove(name);
+        }
+        guard.state = UnixSocketState::Connected(ours);
+        Ok(())
+    }
+
+    fn socket_file(&self, name: &[char]) -> VfsFile {
+        VfsFile::new(
+            VfsFileKind::File,
+            name.to_vec(),
+            0,
+            self.os_id,
+            self.os_id,
+            Arc::new(UnixSockFsSpecificFileData::UnixSockFsSocket(name.to_vec())),
+        )
+    }
+}
+
+impl FileSystem for UnixSockFs {
+    fn os_id(&mut self) -> u64 {
+        self.os_id
+    }
+
+    fn fs_type(&mut self) -> String {
+        "unixsock".to_string()
+    }
+
+    fn fs_flush(&mut self) -> Result<(), VfsError> {
+        Err(VfsError::ActionNotAllowed)
+    }
+
+    fn host_block_device(&mut self) -> Option<Arcrwb<dyn BlockDevice>> {
+        None
+    }
+
+    fn statfs(&mut self) -> Result<FsStat, VfsError> {
+        Ok(FsStat::virtual_fs(
+            SOCKFS_MAGIC,
+            self.handles.count() as u64,
+        ))
+    }
+
+    fn get_root(&mut self) -> Result<VfsFile, VfsError> {
+        Ok(VfsFile::new(
+            VfsFileKind::Directory,
+            alloc::vec!['/'],
+            0,
+            self.parent_fs_os_id,
+            self.os_id,
+            Arc::new(UnixSockFsSpecificFileData::UnixSockFsRoot),
+        ))
+    }
+
+    fn get_mount_point(&mut self) -> Result<Option<VfsFile>, VfsError> {
+        Ok(Some(
+            self.mnt
+                .as_ref()
+                .ok_or(VfsError::FileSystemNotMounted)?
+                .clone(),
+        ))
+    }
+
+    fn get_child(&mut self, file: &VfsFile, child: &VfsName) -> Result<VfsFile, VfsError> {
+        let child = &child.to_chars()[..];
+        if file.fs() != self.os_id {
+            return Err(VfsError::FileSystemMismatch);
+        }
+        if file.name() != ['/'] {
+            return Err(VfsError::NotDirectory);
+        }
+        if child.first() == Some(&'\0') || !self.names.contains_key(child) {
+            return Err(VfsError::PathNotFound);
+        }
+        Ok(self.socket_file(child))
+    }
+
+    fn list_children(&mut self, file: &VfsFile) -> Result<Vec<VfsFile>, VfsError> {
+        if file.fs() != self.os_id {
+            return Err(VfsError::FileSystemMismatch);
+        }
+        if file.name() != ['/'] {
+            return Err(VfsError::NotDirectory);
+        }
+        Ok(self
+            .names
+            .keys()
+            .filter(|name| name.first() != Some(&'\0'))
+            .map(|name| self.socket_file(name))
+            .collect())
+    }
+
+    default_get_file_implementation!();
+
+    fn get_stats(&mut self, file: &VfsFile) -> Result<FileStat, VfsError> {
+        if file.fs() != self.os_id {
+            return Err(VfsError::FileSystemMismatch);
+        }
+        let d = file.get_fs_specific_data();
+        let data = (*d)
+            .as_any()
+            .downcast_ref::<UnixSockFsSpecificFileData>()
+            .ok_or(VfsError::FileSystemMismatch)?;
+
+        let is_directory = match data {
+            UnixSockFsSpecificFileData::UnixSockFsRoot => true,
+            UnixSockFsSpecificFileData::UnixSockFsSocket(name) => {
+                if !self.names.contains_key(name) {
+                    return Err(VfsError::PathNotFound);
+                }
+                false
+            }
+        };
+        Ok(FileStat {
+            size: 0,
+            created_at: 0,
+            modified_at: 0,
+            permissions: permissions!(Owner:Read, Owner:Write, Group:Read, Group:Write, Other:Read, Other:Write).to_u64(),
+            is_file: !is_directory,
+            is_directory,
+            is_symlink: false,
+            owner_id: 0,
+            group_id: 0,
+            flags: FLAG_VIRTUAL | FLAG_SYSTEM,
+        })
+    }
+
+    fn create_child(
+        &mut self,
+        _directory: &VfsFile,
+        _name: &VfsName,
+        _kind: VfsFileKind,
+    ) -> Result<VfsFile, VfsError> {
+        Err(VfsError::ActionNotAllowed)
+    }
+
+    fn delete_file(&mut self, _file: &VfsFile) -> Result<(), VfsError> {
+        Err(VfsError::ActionNotAllowed)
+    }
+
+    fn on_mount(
+        &mut self,
+        mount_point: &VfsFile,
+        os_id: u64,
+        root_fs: WeakArcrwb<Vfs>,
+    ) -> Result<VfsFile, VfsError> {
+        self.root_fs = Some(root_fs);
+        self.parent_fs_os_id = mount_point.fs();
+        self.mnt = Some(mount_point.clone());
+        self.os_id = os_id;
+        self.handles.set_owner(os_id);
+        self.get_root()
+    }
+
+    fn on_pre_unmount(&mut self) -> Result<bool, VfsError> {
+        Ok(true)
+    }
+
+    fn on_unmount(&mut self) -> Result<(), VfsError> {
+        self.mnt = None;
+        self.os_id = 0;
+        self.parent_fs_os_id = 0;
+        for h in self.handles.iter().copied().collect::<Vec<u64>>() {
+            self.fclose(h)?;
+        }
+        Ok(())
+    }
+
+    fn get_vfs(&mut self) -> Result<WeakArcrwb<Vfs>, VfsError> {
+        Ok(self
+            .root_fs
+            .as_ref()
+            .ok_or(VfsError::FileSystemNotMounted)?
+            .clone())
+    }
+
+    /// Sockets are only reachable through the socket syscalls
+    fn fopen(&mut self, _file: &VfsFile, _mode: u64) -> Result<u64, VfsError> {
+        Err(VfsError::ActionNotAllowed)
+    }
+
+    /// Closing a bound or listening socket releases its name, pending connections are reset
+    fn fclose(&mut self, handle: u64) -> Result<(), VfsError> {
+        let socket = self.socket(handle)?;
+        let mut guard = socket.write();
+        match core::mem::replace(&mut guard.state, UnixSocketState::Unbound) {
+            UnixSocketState::Unbound => {}
+            UnixSocketState::Bound(name) => {
+                self.names.remove(&name);
+            }
+            UnixSocketState::Listening { name, backlog, .. } => {
+                self.names.remove(&name);
+                for end in backlog.iter() {
+                    end.close();
+                }
+            }
+            UnixSocketState::Connected(end) => end.close(),
+        }
+        let wait_queue = guard.wait_queue.clone();
+        drop(guard);
+        wait_queue.wake_all();
+
+        if self.handles.dealloc_file_handle::<UnixSockFsHandle>(handle) {
+            Ok(())
+        } else {
+            Err(VfsError::BadHandle)
+        }
+    }
+
+    fn fseek(&mut self, _handle: u64, _position: SeekPosition) -> Result<u64, VfsError> {
+        Err(VfsError::InvalidSeekPosition)
+    }
+
+    fn fread(&mut self, handle: u64, buf: &mut [u8]) -> Result<u64, VfsError> {
+        let socket = self.socket(handle)?;
+        let guard = socket.read();
+        match &guard.state {
+            UnixSocketState::Connected(end) => end.read(buf),
+            _ => Err(VfsError::ActionNotAllowed),
+        }
+    }
+
+    fn fwrite(&mut self, handle: u64, buf: &[u8]) -> Result<u64, VfsError> {
+        let socket = self.socket(handle)?;
+        let guard = socket.read();
+        match &guard.state {
+            UnixSocketState::Connected(end) => end.write(buf),
+            _ => Err(VfsError::ActionNotAllowed),
+        }
+    }
+
+    fn fflush(&mut self, handle: u64) -> Result<(), VfsError> {
+        self.socket(handle)?;
+        Ok(())
+    }
+
+    fn fsync(&mut self, handle: u64) -> Result<(), VfsError> {
+        self.socket(handle)?;
+        Ok(())
+    }
+
+    fn fstat(&self, handle: u64) -> Result<FileStat, VfsError> {
+        let socket = self.socket(handle)?;
+        let guard = socket.read();
+        let size = match &guard.state {
+            UnixSocketState::Connected(end) => end.rx.read().readable_bytes() as u64,
+            _ => 0,
+        };
+        Ok(FileStat {
+            size,
+            created_at: 0,
+            modified_at: 0,
+            permissions: permissions!(Owner:Read, Owner:Write).to_u64(),
+            is_file: true,
+            is_directory: false,
+            is_symlink: false,
+            owner_id: 0,
+            group_id: 0,
+            flags: FLAG_VIRTUAL | FLAG_SYSTEM,
+        })
+    }
+
+    fn ftruncate(&mut self, _handle: u64) -> Result<u64, VfsError> {
+        Err(VfsError::ActionNotAllowed)
+    }
+
+    fn fpoll(&mut self, handle: u64, events: PollEvents) -> Result<PollEvents, VfsError> {
+        let socket = self.socket(handle)?;
+        let guard = socket.read();
+        let ready = match &guard.state {
+            UnixSocketState::Connected(end) => end.poll(),
+            UnixSocketState::Listening { backlog, .. } if !backlog.is_empty() => {
+                PollEvent::In.into()
+            }
+            UnixSocketState::Listening { .. } => PollEvents::empty(),
+            UnixSocketState::Unbound | UnixSocketState::Bound(..) => PollEvent::HangUp.into(),
+        };
+        Ok(ready & (events | POLL_ALWAYS_REPORTED))
+    }
+
+    fn fwait_queue(&mut self, handle: u64) -> Result<Option<Arc<WaitQueue>>, VfsError> {
+        let socket = self.socket(handle)?;
+        let guard = socket.read();
+        Ok(Some(match &guard.state {
+            UnixSocketState::Connected(end) => end.wait_queue.clone(),
+            _ => guard.wait_queue.clone(),
+        }))
+    }
+}
+
+pub fn init_unixsockfs(vfs: &mut Vfs) {
+    let fs = UnixSockFs {
+        os_id: 0,
+        parent_fs_os_id: 0,
+        mnt: None,
+        root_fs: None,
+        handles: FileHandleAllocator::default(),
+        names: BTreeMap::new(),
+    };
+
+    vfs.mount(&UNIX_SOCKFS_MOUNT, Box::new(fs), 0).unwrap();
+}
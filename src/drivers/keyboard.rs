@@ -4,9 +4,12 @@ use alloc::collections::{btree_map::Entry, BTreeMap};
 
 use crate::{
     debuggable_bitset_enum,
+    drivers::vga::console::{is_console_installed, send_control, ConsoleControl},
     process::{scheduler::SCHEDULER, ui::events::UiEvent},
 };
 
+pub mod keymap;
+
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub enum KeyboardEventKind {
     KeyDown,
@@ -96,6 +99,8 @@ pub enum Key {
     RightGui,
     Apps,
     Acpi(AcpiKey),
+    /// Dead key of a keymap, composes with the next character
+    Dead(char),
 }
 
 impl Key {
@@ -140,6 +145,32 @@ impl Key {
     }
 }
 
+/// UTF-8 text typed by a key press: at most a dead key that didn't compose and a character
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct KeyText {
+    bytes: [u8; 8],
+    len: u8,
+}
+
+impl KeyText {
+    pub fn push(&mut self, c: char) {
+        let len = self.len as usize;
+        self.len += c.encode_utf8(&mut self.bytes[len..]).len() as u8;
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes[..self.len as usize]
+    }
+
+    pub fn as_str(&self) -> &str {
+        core::str::from_utf8(self.as_bytes()).unwrap_or_default()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
 /// Represents a keyboard event
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct KeyboardEvent {
@@ -147,6 +178,7 @@ pub struct KeyboardEvent {
     pub modifiers: KeyModifiers,
     pub raw_key: Key,
     pub mapped_key: Key,
+    pub text: KeyText,
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -223,8 +255,26 @@ impl KeyboardLayout {
     }
 }
 
+/// Shift+PageUp/PageDown page through the console scrollback instead of reaching the focused thread
+fn console_control(event: &KeyboardEvent) -> Option<ConsoleControl> {
+    let shift =
+        event.modifiers.has(KeyModifier::LeftShift) || event.modifiers.has(KeyModifier::RightShift);
+    if !shift || event.kind == KeyboardEventKind::KeyUp || !is_console_installed() {
+        return None;
+    }
+    match event.raw_key {
+        Key::PageUp => Some(ConsoleControl::ScrollUp),
+        Key::PageDown => Some(ConsoleControl::ScrollDown),
+        _ => None,
+    }
+}
+
 /// Handles a keyboard event from the keyboard driver
 pub fn handle_keyboard_event(event: KeyboardEvent) {
+    if let Some(control) = console_control(&event) {
+        send_control(control);
+        return;
+    }
     if let Some(thread) = SCHEDULER.get_focused_thread() {
         let mut lock = thread.thread.ui_context.lock();
         lock.events.push_back(UiEvent::KeyboardEvent(event));
@@ -0,0 +1,327 @@
+//! Keymaps loaded at runtime, the built-in US layout is used while none is loaded and for the
+//! keys a keymap leaves out. `/dev/keymap` reads the path of the loaded keymap and loads the one
+//! at the path written to it, "builtin" goes back to the built-in layout.
+//!
+//! Keymaps are text, one definition per line and '#' starts a comment line:
+//!
+//! - `key <scancode> <plain> [<shift> [<altgr>]]`: the scancode is a set 1 make code in hex,
+//!   `0xe0xx` for the extended ones. Symbols are a character, `U+XXXX`, `-` for none, or
+//!   `dead:` followed by one of those for a dead key. Missing levels type the plain symbol
+//! - `compose <dead> <base> <result>`: the dead key followed by `base` types `result`
+//!
+//! A dead key followed by itself or a space types the accent, followed by a character it doesn't
+//! compose with it types both.
+
+use alloc::{
+    collections::BTreeMap,
+    string::{String, ToString},
+    vec::Vec,
+};
+use spin::Mutex;
+
+use crate::{
+    data::{
+        alloc_boxed_slice,
+        file::File,
+        permissions::{Credentials, Permissions},
+    },
+    drivers::vfs::{VfsError, OPEN_MODE_READ},
+    interrupts::without_interrupts,
+};
+
+use super::{Key, KeyModifier, KeyModifiers, KeyText, KeyboardEventKind, KeyboardLayout};
+
+pub const KEYMAP_MAX_SIZE: u64 = 64 * 1024;
+
+/// Name written to `/dev/keymap` to go back to the built-in layout
+pub const BUILTIN_KEYMAP: &str = "builtin";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeySymbol {
+    Char(char),
+    /// Types nothing, changes the next character
+    Dead(char),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeymapLevel {
+    Plain,
+    Shift,
+    AltGr,
+}
+
+impl KeymapLevel {
+    /// AltGr is the right alt key, it wins over shift
+    pub fn from_modifiers(modifiers: KeyModifiers) -> Self {
+        if modifiers.has(KeyModifier::RightAlt) {
+            Self::AltGr
+        } else if modifiers.has(KeyModifier::LeftShift) || modifiers.has(KeyModifier::RightShift) {
+            Self::Shift
+        } else {
+            Self::Plain
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeymapSyntaxError {
+    UnknownDirective,
+    BadScancode,
+    BadSymbol,
+    /// No symbol for a key, more than 3, or not exactly 3 for a composition
+    SymbolCount,
+    DuplicateScancode,
+    DuplicateComposition,
+    /// A composition for a character no key types as a dead key
+    NotDeadKey,
+}
+
+#[derive(Debug)]
+pub enum KeymapError {
+    Read(VfsError),
+    TooLarge,
+    NotUtf8,
+    /// Defines no key
+    Empty,
+    /// `line` starts at 1
+    Syntax {
+        line: usize,
+        reason: KeymapSyntaxError,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Keymap {
+    keys: BTreeMap<u16, [Option<KeySymbol>; 3]>,
+    compositions: BTreeMap<(char, char), char>,
+}
+
+fn parse_scancode(word: &str) -> Option<u16> {
+    let code = u16::from_str_radix(word.strip_prefix("0x")?, 16).ok()?;
+    let valid = code < 0x80 || (0xe000..0xe080).contains(&code);
+    (valid && code & 0x7f != 0).then_some(code)
+}
+
+fn parse_char(word: &str) -> Option<char> {
+    if let Some(hex) = word.strip_prefix("U+").filter(|hex| !hex.is_empty()) {
+        return char::from_u32(u32::from_str_radix(hex, 16).ok()?);
+    }
+    let mut chars = word.chars();
+    let c = chars.next()?;
+    chars.next().is_none().then_some(c)
+}
+
+fn parse_symbol(word: &str) -> Option<KeySymbol> {
+    match word.strip_prefix("dead:") {
+        Some(accent) => parse_char(accent).map(KeySymbol::Dead),
+        None => parse_char(word).map(KeySymbol::Char),
+    }
+}
+
+impl Keymap {
+    pub fn parse(text: &str) -> Result<Self, KeymapError> {
+        let mut keymap = Self {
+            keys: BTreeMap::new(),
+            compositions: BTreeMap::new(),
+        };
+        let mut dead_keys_composed = Vec::new();
+        for (index, line) in text.lines().enumerate() {
+            let syntax = |reason| KeymapError::Syntax {
+                line: index + 1,
+                reason,
+            };
+            let mut words = line.split_whitespace();
+            match words.next() {
+                None => {}
+                Some(word) if word.starts_with('#') => {}
+                Some("key") => {
+                    let scancode = words
+                        .next()
+                        .and_then(parse_scancode)
+                        .ok_or(syntax(KeymapSyntaxError::BadScancode))?;
+                    let words = words.collect::<Vec<_>>();
+                    if words.is_empty() || words.len() > 3 {
+                        return Err(syntax(KeymapSyntaxError::SymbolCount));
+                    }
+                    let mut levels = [None; 3];
+                    for (level, word) in levels.iter_mut().zip(words) {
+                        if word != "-" {
+                            *level = Some(
+                                parse_symbol(word).ok_or(syntax(KeymapSyntaxError::BadSymbol))?,
+                            );
+                        }
+                    }
+                    if keymap.keys.insert(scancode, levels).is_some() {
+                        return Err(syntax(KeymapSyntaxError::DuplicateScancode));
+                    }
+                }
+                Some("compose") => {
+                    let chars = words
+                        .map(|word| parse_char(word).ok_or(syntax(KeymapSyntaxError::BadSymbol)))
+                        .collect::<Result<Vec<_>, _>>()?;
+                    let [dead, base, result] = chars[..] else {
+                        return Err(syntax(KeymapSyntaxError::SymbolCount));
+                    };
+                    if keymap.compositions.insert((dead, base), result).is_some() {
+                        return Err(syntax(KeymapSyntaxError::DuplicateComposition));
+                    }
+                    dead_keys_composed.push((index + 1, dead));
+                }
+                Some(_) => return Err(syntax(KeymapSyntaxError::UnknownDirective)),
+            }
+        }
+        if keymap.keys.is_empty() {
+            return Err(KeymapError::Empty);
+        }
+        for (line, dead) in dead_keys_composed {
+            if !keymap.is_dead_key(dead) {
+                return Err(KeymapError::Syntax {
+                    line,
+                    reason: KeymapSyntaxError::NotDeadKey,
+                });
+            }
+        }
+        Ok(keymap)
+    }
+
+    fn is_dead_key(&self, accent: char) -> bool {
+        self.keys
+            .values()
+            .flatten()
+            .any(|symbol| *symbol == Some(KeySymbol::Dead(accent)))
+    }
+
+    /// `None` for the keys the keymap leaves to the built-in layout
+    pub fn symbol(&self, scancode: u16, level: KeymapLevel) -> Option<KeySymbol> {
+        let levels = self.keys.get(&scancode)?;
+        levels[level as usize].or(levels[0])
+    }
+
+    pub fn compose(&self, dead: char, base: char) -> Option<char> {
+        self.compositions.get(&(dead, base)).copied()
+    }
+}
+
+/// The dead key waiting for the next character
+#[derive(Debug, Default)]
+pub struct Composer {
+    pending: Option<char>,
+}
+
+impl Composer {
+    /// Text typed by a symbol
+    pub fn feed(&mut self, keymap: Option<&Keymap>, symbol: KeySymbol) -> KeyText {
+        let mut text = KeyText::default();
+        let (KeySymbol::Char(c) | KeySymbol::Dead(c)) = symbol;
+        let Some(pending) = self.pending.take() else {
+            match symbol {
+                KeySymbol::Char(c) => text.push(c),
+                KeySymbol::Dead(accent) => self.pending = Some(accent),
+            }
+            return text;
+        };
+        match keymap.and_then(|keymap| keymap.compose(pending, c)) {
+            Some(composed) => text.push(composed),
+            None if c == pending || c == ' ' => text.push(pending),
+            None => {
+                text.push(pending);
+                match symbol {
+                    KeySymbol::Char(c) => text.push(c),
+                    KeySymbol::Dead(accent) => self.pending = Some(accent),
+                }
+            }
+        }
+        text
+    }
+
+    pub fn is_pending(&self) -> bool {
+        self.pending.is_some()
+    }
+}
+
+#[derive(Debug)]
+struct ActiveKeymap {
+    keymap: Option<Keymap>,
+    path: Option<String>,
+    composer: Composer,
+}
+
+/// Taken by the keyboard interrupt, others take it with interrupts off
+static ACTIVE_KEYMAP: Mutex<ActiveKeymap> = Mutex::new(ActiveKeymap {
+    keymap: None,
+    path: None,
+    composer: Composer { pending: None },
+});
+
+pub fn load_keymap(path: &str) -> Result<Keymap, KeymapError> {
+    let file = File::open(
+        path,
+        OPEN_MODE_READ,
+        Permissions::from_u64(0),
+        Credentials::Kernel,
+    )
+    .map_err(KeymapError::Read)?;
+    let size = file.stats().map_err(KeymapError::Read)?.size;
+    if size > KEYMAP_MAX_SIZE {
+        return Err(KeymapError::TooLarge);
+    }
+    let mut buffer = alloc_boxed_slice(size as usize);
+    match file.read(&mut buffer) {
+        Ok(read) if read == size => {}
+        Ok(_) => return Err(KeymapError::Read(VfsError::ShortRead)),
+        Err(err) => return Err(KeymapError::Read(err)),
+    }
+    let text = core::str::from_utf8(&buffer).map_err(|_| KeymapError::NotUtf8)?;
+    Keymap::parse(text)
+}
+
+/// Loads the keymap at `path`, `None` goes back to the built-in layout. The current keymap stays
+/// if the new one can't be loaded
+pub fn set_keymap(path: Option<&str>) -> Result<(), KeymapError> {
+    let keymap = path.map(load_keymap).transpose()?;
+    without_interrupts(|| {
+        *ACTIVE_KEYMAP.lock() = ActiveKeymap {
+            keymap,
+            path: path.map(str::to_string),
+            composer: Composer::default(),
+        };
+    });
+    Ok(())
+}
+
+/// `None` for the built-in layout
+pub fn keymap_path() -> Option<String> {
+    without_interrupts(|| ACTIVE_KEYMAP.lock().path.clone())
+}
+
+/// Key and text of a key event, through the loaded keymap for the keys it has and `layout` for
+/// the others. Key releases type nothing
+pub fn translate(
+    layout: &KeyboardLayout,
+    scancode: u16,
+    key: Key,
+    kind: KeyboardEventKind,
+    modifiers: KeyModifiers,
+) -> (Key, KeyText) {
+    let mut active = ACTIVE_KEYMAP.lock();
+    let active = &mut *active;
+    let keymap = active.keymap.as_ref();
+    let mapped_key = match keymap
+        .and_then(|keymap| keymap.symbol(scancode, KeymapLevel::from_modifiers(modifiers)))
+    {
+        Some(KeySymbol::Char(c)) => Key::Character(c),
+        Some(KeySymbol::Dead(accent)) => Key::Dead(accent),
+        None => layout.map(key, modifiers),
+    };
+    if kind == KeyboardEventKind::KeyUp {
+        return (mapped_key, KeyText::default());
+    }
+    let symbol = match mapped_key {
+        Key::Dead(accent) => KeySymbol::Dead(accent),
+        key => match key.printable_char() {
+            Some(c) => KeySymbol::Char(c),
+            None => return (mapped_key, KeyText::default()),
+        },
+    };
+    (mapped_key, active.composer.feed(keymap, symbol))
+}
@@ -1,11 +1,13 @@
 use crate::drivers::{disk::init_disk_drivers, fs::virt::devfs::DevFs, vga::init_vga};
 
+pub mod acpi;
 pub mod disk;
 pub mod fs;
 pub mod keyboard;
 pub mod pci;
 pub mod ports;
 pub mod time;
+pub mod tty;
 pub mod vfs;
 pub mod vga;
 
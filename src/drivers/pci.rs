@@ -1,10 +1,46 @@
 use alloc::vec::Vec;
+use spin::{Mutex, RwLock};
 
-use crate::io::{inl, outl};
+use crate::{
+    interrupts::msi::{alloc_msi_vector, free_msi_vector, MsiAllocation, MsiError, MsiHandlerFn},
+    io::{inl, outl},
+};
 
 const PCI_CONFIG_ADDRESS: u16 = 0xCF8;
 const PCI_CONFIG_DATA: u16 = 0xCFC;
 
+const PCI_COMMAND: u8 = 0x04;
+const PCI_HEADER_TYPE: u8 = 0x0C;
+const PCI_BAR0: u8 = 0x10;
+const PCI_SECONDARY_BUS: u8 = 0x18;
+const PCI_CAPABILITIES_POINTER: u8 = 0x34;
+const PCI_INTERRUPT: u8 = 0x3C;
+
+const PCI_COMMAND_IO_SPACE: u16 = 1 << 0;
+const PCI_COMMAND_MEMORY_SPACE: u16 = 1 << 1;
+const PCI_COMMAND_BUS_MASTER: u16 = 1 << 2;
+const PCI_COMMAND_INTX_DISABLE: u16 = 1 << 10;
+/// In the status register, the upper half of the command dword
+const PCI_STATUS_CAPABILITIES: u32 = 1 << 20;
+
+pub const PCI_CAPABILITY_MSI: u8 = 0x05;
+
+/// Bits of the MSI message control register, the upper half of the capability header
+const MSI_CONTROL_ENABLE: u32 = 1 << 16;
+const MSI_CONTROL_MULTIPLE_ENABLE: u32 = 0b111 << 20;
+const MSI_CONTROL_64BIT: u32 = 1 << 23;
+const MSI_CONTROL_PER_VECTOR_MASK: u32 = 1 << 24;
+
+/// Capability lists are at most this long, a longer one loops
+const PCI_MAX_CAPABILITIES: usize = 48;
+
+/// Size of the config space reachable through the configuration ports
+pub const PCI_CONFIG_SPACE_SIZE: usize = 256;
+pub const PCI_MAX_BARS: usize = 6;
+
+/// The address and data ports must be used together
+static CONFIG_LOCK: Mutex<()> = Mutex::new(());
+
 /// Represents a detected PCI device
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub struct PciDevice {
@@ -16,9 +52,301 @@ pub struct PciDevice {
     pub class: u8,
     pub subclass: u8,
     pub prog_if: u8,
+    /// Layout of the config space, without the multi-function bit
+    pub header_type: u8,
     pub os_class_name: &'static str,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PciBar {
+    Memory {
+        address: u64,
+        size: u64,
+        prefetchable: bool,
+        /// Takes the next BAR too, for the upper half of the address
+        is_64bit: bool,
+    },
+    Io {
+        port: u32,
+        size: u32,
+    },
+}
+
+impl PciBar {
+    /// Decodes a BAR from its value and the value read back after writing all ones to it.
+    /// `high` and `sized_high` are the same for the next BAR, only used by 64-bit memory BARs.
+    /// `None` if the BAR isn't implemented
+    pub fn decode(value: u32, sized: u32, high: u32, sized_high: u32) -> Option<PciBar> {
+        if value & 1 != 0 {
+            let mut mask = sized & !0x3;
+            if mask == 0 {
+                return None;
+            }
+            // The upper half can read as zero when only 64K of ports are decoded
+            if mask & 0xFFFF_0000 == 0 {
+                mask |= 0xFFFF_0000;
+            }
+            return Some(PciBar::Io {
+                port: value & !0x3,
+                size: (!mask).wrapping_add(1),
+            });
+        }
+
+        let is_64bit = (value >> 1) & 0x3 == 0x2;
+        let prefetchable = value & 0x8 != 0;
+        let (address, mask) = if is_64bit {
+            (
+                ((high as u64) << 32) | (value & !0xF) as u64,
+                ((sized_high as u64) << 32) | (sized & !0xF) as u64,
+            )
+        } else {
+            (
+                (value & !0xF) as u64,
+                0xFFFF_FFFF_0000_0000 | (sized & !0xF) as u64,
+            )
+        };
+        if sized & !0xF == 0 && (!is_64bit || sized_high == 0) {
+            return None;
+        }
+        Some(PciBar::Memory {
+            address,
+            size: (!mask).wrapping_add(1),
+            prefetchable,
+            is_64bit,
+        })
+    }
+}
+
+/// An entry of the capability list, `offset` is where it starts in the config space
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PciCapability {
+    pub id: u8,
+    pub offset: u8,
+}
+
+pub struct PciCapabilities {
+    device: PciDevice,
+    next: u8,
+    seen: usize,
+}
+
+impl Iterator for PciCapabilities {
+    type Item = PciCapability;
+
+    fn next(&mut self) -> Option<PciCapability> {
+        if self.next < 0x40 || self.seen >= PCI_MAX_CAPABILITIES {
+            return None;
+        }
+        let offset = self.next;
+        let header = self.device.read_config(offset);
+        self.next = ((header >> 8) & 0xFC) as u8;
+        self.seen += 1;
+        Some(PciCapability {
+            id: header as u8,
+            offset,
+        })
+    }
+}
+
+impl PciDevice {
+    pub fn read_config(&self, offset: u8) -> u32 {
+        unsafe { read_config(self.bus, self.device, self.function, offset) }
+    }
+
+    pub fn write_config(&self, offset: u8, value: u32) {
+        unsafe { write_config(self.bus, self.device, self.function, offset, value) }
+    }
+
+    pub fn command(&self) -> u16 {
+        self.read_config(PCI_COMMAND) as u16
+    }
+
+    /// Writing zeroes to the status register leaves it unchanged
+    fn set_command(&self, command: u16) {
+        self.write_config(PCI_COMMAND, command as u32);
+    }
+
+    pub fn enable_bus_mastering(&self) {
+        self.set_command(self.command() | PCI_COMMAND_BUS_MASTER);
+    }
+
+    pub fn enable_memory_space(&self) {
+        self.set_command(self.command() | PCI_COMMAND_MEMORY_SPACE);
+    }
+
+    pub fn enable_io_space(&self) {
+        self.set_command(self.command() | PCI_COMMAND_IO_SPACE);
+    }
+
+    /// Number of BARs of the header type, bridges only have two
+    pub fn bar_count(&self) -> u8 {
+        match self.header_type {
+            0x00 => 6,
+            0x01 => 2,
+            _ => 0,
+        }
+    }
+
+    /// Reads and sizes BAR `n`. `None` if it isn't implemented, or holds the upper half of the
+    /// previous 64-bit BAR
+    pub fn read_bar(&self, n: u8) -> Option<PciBar> {
+        if n >= self.bar_count() {
+            return None;
+        }
+        if n > 0 {
+            let previous = self.read_config(PCI_BAR0 + (n - 1) * 4);
+            if previous & 1 == 0 && (previous >> 1) & 0x3 == 0x2 {
+                return None;
+            }
+        }
+
+        let offset = PCI_BAR0 + n * 4;
+        let value = self.read_config(offset);
+        let is_64bit = value & 1 == 0 && (value >> 1) & 0x3 == 0x2;
+        if is_64bit && n + 1 >= self.bar_count() {
+            return None;
+        }
+
+        // The device mustn't decode the all ones address while it is being sized
+        let command = self.command();
+        self.set_command(command & !(PCI_COMMAND_IO_SPACE | PCI_COMMAND_MEMORY_SPACE));
+
+        let sized = self.size_register(offset, value);
+        let (high, sized_high) = if is_64bit {
+            let high = self.read_config(offset + 4);
+            (high, self.size_register(offset + 4, high))
+        } else {
+            (0, 0)
+        };
+
+        self.set_command(command);
+        PciBar::decode(value, sized, high, sized_high)
+    }
+
+    fn size_register(&self, offset: u8, value: u32) -> u32 {
+        self.write_config(offset, 0xFFFF_FFFF);
+        let sized = self.read_config(offset);
+        self.write_config(offset, value);
+        sized
+    }
+
+    pub fn capabilities(&self) -> PciCapabilities {
+        let has_list = self.read_config(PCI_COMMAND) & PCI_STATUS_CAPABILITIES != 0;
+        let next = if has_list && self.header_type <= 0x01 {
+            (self.read_config(PCI_CAPABILITIES_POINTER) & 0xFC) as u8
+        } else {
+            0
+        };
+        PciCapabilities {
+            device: *self,
+            next,
+            seen: 0,
+        }
+    }
+
+    pub fn find_capability(&self, id: u8) -> Option<PciCapability> {
+        self.capabilities().find(|capability| capability.id == id)
+    }
+
+    /// Points the MSI capability at `allocation` with a single message, and stops the device
+    /// from using its interrupt pin
+    pub fn enable_msi(&self, allocation: &MsiAllocation) -> Result<(), MsiError> {
+        let offset = self
+            .find_capability(PCI_CAPABILITY_MSI)
+            .ok_or(MsiError::NoCapability)?
+            .offset;
+        let header = self.read_config(offset);
+
+        self.write_config(offset + 4, allocation.address as u32);
+        let data_offset = if header & MSI_CONTROL_64BIT != 0 {
+            self.write_config(offset + 8, (allocation.address >> 32) as u32);
+            offset + 12
+        } else {
+            offset + 8
+        };
+        // The upper half is reserved, or the extended message data which stays 0
+        self.write_config(data_offset, allocation.data & 0xFFFF);
+        if header & MSI_CONTROL_PER_VECTOR_MASK != 0 {
+            self.write_config(data_offset + 4, 0);
+        }
+
+        self.set_command(self.command() | PCI_COMMAND_INTX_DISABLE);
+        self.write_config(
+            offset,
+            (header & !MSI_CONTROL_MULTIPLE_ENABLE) | MSI_CONTROL_ENABLE,
+        );
+        Ok(())
+    }
+
+    /// Masks and disables MSI, the interrupt pin is used again
+    pub fn disable_msi(&self) {
+        let Some(capability) = self.find_capability(PCI_CAPABILITY_MSI) else {
+            return;
+        };
+        let offset = capability.offset;
+        let header = self.read_config(offset);
+        if header & MSI_CONTROL_PER_VECTOR_MASK != 0 {
+            let mask_offset = if header & MSI_CONTROL_64BIT != 0 {
+                offset + 16
+            } else {
+                offset + 12
+            };
+            self.write_config(mask_offset, 1);
+        }
+        self.write_config(offset, header & !MSI_CONTROL_ENABLE);
+        self.set_command(self.command() & !PCI_COMMAND_INTX_DISABLE);
+    }
+
+    /// Allocates a vector running `handler` and enables MSI with it. Drivers fall back to the
+    /// interrupt line when this fails
+    pub fn setup_msi(
+        &self,
+        cpu: u8,
+        handler: MsiHandlerFn,
+        context: u64,
+    ) -> Result<MsiAllocation, MsiError> {
+        if self.find_capability(PCI_CAPABILITY_MSI).is_none() {
+            return Err(MsiError::NoCapability);
+        }
+        let allocation = alloc_msi_vector(cpu, handler, context)?;
+        if let Err(e) = self.enable_msi(&allocation) {
+            free_msi_vector(&allocation);
+            return Err(e);
+        }
+        Ok(allocation)
+    }
+
+    pub fn teardown_msi(&self, allocation: &MsiAllocation) {
+        self.disable_msi();
+        free_msi_vector(allocation);
+    }
+
+    pub fn interrupt_line(&self) -> u8 {
+        self.read_config(PCI_INTERRUPT) as u8
+    }
+
+    /// 1 to 4 for INTA# to INTD#, 0 if the device doesn't use a pin
+    pub fn interrupt_pin(&self) -> u8 {
+        (self.read_config(PCI_INTERRUPT) >> 8) as u8
+    }
+
+    pub fn is_pci_bridge(&self) -> bool {
+        self.class == 0x06 && self.subclass == 0x04
+    }
+
+    /// The bus behind a PCI-to-PCI bridge
+    /// Whether the function still answers with the same vendor and device
+    pub fn is_present(&self) -> bool {
+        let vendor_device = self.read_config(0x00);
+        vendor_device == (self.vendor_id as u32) | ((self.device_id as u32) << 16)
+    }
+
+    pub fn secondary_bus(&self) -> Option<u8> {
+        self.is_pci_bridge()
+            .then(|| (self.read_config(PCI_SECONDARY_BUS) >> 8) as u8)
+    }
+}
+
 pub fn get_class_name(class: u8, subclass: u8, prog_if: u8) -> &'static str {
     match (class, subclass, prog_if) {
         (0x00, 0x00, _) => "Non-VGA-Compatible Unclassified Device",
@@ -241,73 +569,198 @@ pub fn get_class_name(class: u8, subclass: u8, prog_if: u8) -> &'static str {
     }
 }
 
-/// Reads a 32-bit config register from a PCI device
-unsafe fn read_config(bus: u8, device: u8, function: u8, offset: u8) -> u32 {
-    let address = (1u32 << 31)
+fn config_address(bus: u8, device: u8, function: u8, offset: u8) -> u32 {
+    (1u32 << 31)
         | ((bus as u32) << 16)
         | ((device as u32) << 11)
         | ((function as u32) << 8)
-        | ((offset as u32) & 0xFC);
-    outl(PCI_CONFIG_ADDRESS, address);
+        | ((offset as u32) & 0xFC)
+}
+
+/// Reads a 32-bit config register from a PCI device
+unsafe fn read_config(bus: u8, device: u8, function: u8, offset: u8) -> u32 {
+    let _guard = CONFIG_LOCK.lock();
+    outl(
+        PCI_CONFIG_ADDRESS,
+        config_address(bus, device, function, offset),
+    );
     inl(PCI_CONFIG_DATA)
 }
 
-/// Scans the entire PCI bus and returns all devices
+/// Writes a 32-bit config register of a PCI device
+unsafe fn write_config(bus: u8, device: u8, function: u8, offset: u8, value: u32) {
+    let _guard = CONFIG_LOCK.lock();
+    outl(
+        PCI_CONFIG_ADDRESS,
+        config_address(bus, device, function, offset),
+    );
+    outl(PCI_CONFIG_DATA, value);
+}
+
+fn probe_function(bus: u8, device: u8, function: u8) -> Option<PciDevice> {
+    let vendor_device = unsafe { read_config(bus, device, function, 0x00) };
+    let vendor_id = (vendor_device & 0xFFFF) as u16;
+    if vendor_id == 0xFFFF {
+        return None;
+    }
+
+    let device_id = ((vendor_device >> 16) & 0xFFFF) as u16;
+    let class_subclass = unsafe { read_config(bus, device, function, 0x08) };
+    let class = ((class_subclass >> 24) & 0xFF) as u8;
+    let subclass = ((class_subclass >> 16) & 0xFF) as u8;
+    let prog_if = ((class_subclass >> 8) & 0xFF) as u8;
+    let header_type = unsafe { read_config(bus, device, function, PCI_HEADER_TYPE) } >> 16;
+
+    Some(PciDevice {
+        bus,
+        device,
+        function,
+        vendor_id,
+        device_id,
+        class,
+        subclass,
+        prog_if,
+        header_type: (header_type & 0x7F) as u8,
+        os_class_name: get_class_name(class, subclass, prog_if),
+    })
+}
+
+fn is_multi_function(bus: u8, device: u8) -> bool {
+    let header_type = unsafe { read_config(bus, device, 0, PCI_HEADER_TYPE) } >> 16;
+    header_type & 0x80 != 0
+}
+
+/// Adds the devices of `bus` to `devices`, and those behind its PCI-to-PCI bridges
+fn scan_single_bus(bus: u8, devices: &mut Vec<PciDevice>, scanned: &mut [bool; 256]) {
+    if scanned[bus as usize] {
+        return;
+    }
+    scanned[bus as usize] = true;
+
+    for device in 0u8..32 {
+        let Some(first) = probe_function(bus, device, 0) else {
+            continue;
+        };
+        let functions = if is_multi_function(bus, device) { 8 } else { 1 };
+        let found = core::iter::once(first)
+            .chain((1..functions).filter_map(|function| probe_function(bus, device, function)))
+            .collect::<Vec<_>>();
+
+        for device_info in found {
+            devices.push(device_info);
+            if let Some(secondary) = device_info.secondary_bus().filter(|b| *b != 0) {
+                scan_single_bus(secondary, devices, scanned);
+            }
+        }
+    }
+}
+
+/// Scans the PCI buses reachable from the host bridges and returns all devices
 pub fn scan_bus() -> Vec<PciDevice> {
     let mut devices = Vec::new();
+    let mut scanned = [false; 256];
 
-    for bus in 0u8..=255 {
-        for device in 0u8..32 {
-            for function in 0u8..8 {
-                let vendor_device = unsafe { read_config(bus, device, function, 0x00) };
-                let vendor_id = (vendor_device & 0xFFFF) as u16;
-                if vendor_id == 0xFFFF {
-                    continue;
-                }
-
-                let device_id = ((vendor_device >> 16) & 0xFFFF) as u16;
-                let class_subclass = unsafe { read_config(bus, device, function, 0x08) };
-                let class = ((class_subclass >> 24) & 0xFF) as u8;
-                let subclass = ((class_subclass >> 16) & 0xFF) as u8;
-                let prog_if = ((class_subclass >> 8) & 0xFF) as u8;
-
-                let device_info = PciDevice {
-                    bus,
-                    device,
-                    function,
-                    vendor_id,
-                    device_id,
-                    class,
-                    subclass,
-                    prog_if,
-                    os_class_name: get_class_name(class, subclass, prog_if),
-                };
-
-                devices.push(device_info);
+    // A multi-function host bridge has one host controller per function, for the bus of the
+    // same number
+    if is_multi_function(0, 0) {
+        for function in 0u8..8 {
+            if probe_function(0, 0, function).is_some() {
+                scan_single_bus(function, &mut devices, &mut scanned);
             }
         }
+    } else {
+        scan_single_bus(0, &mut devices, &mut scanned);
     }
 
     devices
 }
 
-static mut PCI_DEVICES: Option<Vec<PciDevice>> = None;
+/// A device found by a scan, with the BARs sized then. Sizing turns decoding off for a moment,
+/// so it isn't done again for devices drivers may be using
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PciScanEntry {
+    pub device: PciDevice,
+    pub bars: [Option<PciBar>; PCI_MAX_BARS],
+}
 
-pub fn get_devices() -> Vec<PciDevice> {
-    device_iterator().cloned().collect()
+#[derive(Debug)]
+struct PciScan {
+    entries: Vec<PciScanEntry>,
+    /// Starts at 1 and goes up with every rescan
+    generation: u64,
 }
 
-pub fn device_iterator() -> impl Iterator<Item = &'static PciDevice> {
-    unsafe {
-        match PCI_DEVICES {
-            None => {}
-            Some(ref v) => {
-                return v.iter();
-            }
-        }
-        let devices = scan_bus();
-        PCI_DEVICES = Some(devices);
-        #[allow(static_mut_refs)]
-        PCI_DEVICES.as_ref().unwrap().iter()
+static PCI_SCAN: RwLock<Option<PciScan>> = RwLock::new(None);
+
+/// Scans the buses again, devices found by the previous scan keep their BARs
+fn scan_entries(previous: &[PciScanEntry]) -> Vec<PciScanEntry> {
+    scan_bus()
+        .into_iter()
+        .map(
+            |device| match previous.iter().find(|entry| entry.device == device) {
+                Some(entry) => *entry,
+                None => PciScanEntry {
+                    device,
+                    bars: core::array::from_fn(|n| device.read_bar(n as u8)),
+                },
+            },
+        )
+        .collect()
+}
+
+fn with_scan<R>(f: impl FnOnce(&PciScan) -> R) -> R {
+    if let Some(scan) = &*PCI_SCAN.read() {
+        return f(scan);
     }
+    let mut guard = PCI_SCAN.write();
+    let scan = guard.get_or_insert_with(|| PciScan {
+        entries: scan_entries(&[]),
+        generation: 1,
+    });
+    f(scan)
+}
+
+/// Devices and BARs of the last scan, the first call scans the buses
+pub fn scan_entries_snapshot() -> Vec<PciScanEntry> {
+    with_scan(|scan| scan.entries.clone())
+}
+
+/// Number of scans made, changes when the device list may have
+pub fn scan_generation() -> u64 {
+    with_scan(|scan| scan.generation)
+}
+
+/// Scans the buses again, for devices that were hot plugged or removed. Returns the number of
+/// devices found
+pub fn rescan() -> usize {
+    let previous = scan_entries_snapshot();
+    let entries = scan_entries(&previous);
+    let count = entries.len();
+    let mut guard = PCI_SCAN.write();
+    let generation = guard.as_ref().map_or(0, |scan| scan.generation) + 1;
+    *guard = Some(PciScan {
+        entries,
+        generation,
+    });
+    count
+}
+
+/// The device at `bus`, `device`, `function` in the last scan
+pub fn find_entry(bus: u8, device: u8, function: u8) -> Option<PciScanEntry> {
+    with_scan(|scan| {
+        scan.entries.iter().copied().find(|entry| {
+            (entry.device.bus, entry.device.device, entry.device.function)
+                == (bus, device, function)
+        })
+    })
+}
+
+pub fn get_devices() -> Vec<PciDevice> {
+    device_iterator().collect()
+}
+
+pub fn device_iterator() -> impl Iterator<Item = PciDevice> {
+    scan_entries_snapshot()
+        .into_iter()
+        .map(|entry| entry.device)
 }
@@ -1,14 +1,14 @@
-use alloc::{boxed::Box, sync::Arc};
+use alloc::boxed::Box;
+use spin::Once;
 
 use crate::{
     bios::get_bda,
     debuggable_bitset_enum,
     drivers::{
-        fs::virt::devfs::{DevFs, VirtualDeviceFile, VirtualDeviceFileProvider},
+        fs::virt::devfs::{CharacterDeviceProvider, DevFs},
         vfs::{
-            arcrwb_new_from_box, Arcrwb, FileStat, FileSystem, SeekPosition, VfsError, VfsFile,
-            VfsFileKind, VfsSpecificFileData, FLAG_PHYSICAL_CHARACTER_DEVICE, FLAG_SYSTEM,
-            FLAG_VIRTUAL, OPEN_MODE_FAIL_IF_EXISTS,
+            arcrwb_new_from_box, CharacterDevice, DeviceKind, DeviceNumber, FileStat, FileSystem,
+            VfsError, FLAG_PHYSICAL_CHARACTER_DEVICE, FLAG_SYSTEM, FLAG_VIRTUAL,
         },
     },
     io::{inb, iowait, outb},
@@ -107,193 +107,95 @@ impl ParallelPort {
     }
 }
 
-static mut LPTS: Option<[Option<ParallelPort>; 3]> = None;
+static LPTS: Once<[Option<ParallelPort>; 3]> = Once::new();
 
-#[allow(static_mut_refs)]
 pub fn get_lpts() -> [Option<ParallelPort>; 3] {
-    unsafe {
-        match &LPTS {
-            Some(lpts) => *lpts,
-            None => {
-                let bda = get_bda();
-                let mut lpts = [None, None, None];
-                for (i, (io_base, lpt)) in { bda.lpt_parallel_base_io }
-                    .iter()
-                    .zip(lpts.iter_mut())
-                    .enumerate()
-                {
-                    if *io_base != 0 {
-                        *lpt = Some(ParallelPort::new((i + 1) as u8, *io_base));
-                    }
-                }
-                LPTS = Some(lpts);
-                lpts
+    *LPTS.call_once(|| {
+        let bda = get_bda();
+        let mut lpts = [None, None, None];
+        for (i, (io_base, lpt)) in { bda.lpt_parallel_base_io }
+            .iter()
+            .zip(lpts.iter_mut())
+            .enumerate()
+        {
+            if *io_base != 0 {
+                *lpt = Some(ParallelPort::new((i + 1) as u8, *io_base));
             }
         }
-    }
+        lpts
+    })
 }
 
-#[allow(static_mut_refs)]
 pub fn lpt1() -> Option<ParallelPort> {
-    unsafe {
-        if let Some(lpts) = &LPTS {
-            lpts[0]
-        } else {
-            get_lpts()[0]
-        }
-    }
+    get_lpts()[0]
 }
 
-#[allow(static_mut_refs)]
 pub fn lpt2() -> Option<ParallelPort> {
-    unsafe {
-        if let Some(lpts) = &LPTS {
-            lpts[1]
-        } else {
-            get_lpts()[1]
-        }
-    }
+    get_lpts()[1]
 }
 
-#[allow(static_mut_refs)]
 pub fn lpt3() -> Option<ParallelPort> {
-    unsafe {
-        if let Some(lpts) = &LPTS {
-            lpts[2]
-        } else {
-            get_lpts()[2]
-        }
-    }
+    get_lpts()[2]
 }
 
-#[derive(Debug)]
-pub struct LptProvider {
-    lpt: ParallelPort,
-    devfs_os_id: u64,
-}
-
-impl VirtualDeviceFileProvider for LptProvider {
-    fn open(&mut self, mode: u64) -> Result<Arcrwb<dyn VirtualDeviceFile>, VfsError> {
-        if mode & OPEN_MODE_FAIL_IF_EXISTS != 0 {
-            Err(VfsError::FileAlreadyExists)
-        } else {
-            Ok(arcrwb_new_from_box(Box::new(self.lpt)))
-        }
-    }
-
-    fn stat(&self) -> Result<FileStat, VfsError> {
-        Ok(FileStat {
-            size: 0,
-            created_at: 0,
-            modified_at: 0,
-            permissions: permissions!(Owner:Write, Group:Write).to_u64(), // TODO: implement read
-            is_file: true,
-            is_directory: false,
-            is_symlink: false,
-            owner_id: 0,
-            group_id: 0,
-            flags: FLAG_VIRTUAL | FLAG_SYSTEM | FLAG_PHYSICAL_CHARACTER_DEVICE,
-        })
-    }
-
-    fn vfs_file(&self) -> Result<VfsFile, VfsError> {
-        Ok(VfsFile::new(
-            VfsFileKind::File,
-            alloc::vec!['l', 'p', 't', (b'0' + self.lpt.parallel_idx) as char],
-            0,
-            self.devfs_os_id,
-            self.devfs_os_id,
-            Arc::new(VfsSpecificFileData),
-        ))
-    }
-}
-
-impl VirtualDeviceFile for ParallelPort {
-    fn stat(&self) -> Result<FileStat, VfsError> {
-        Ok(FileStat {
-            size: 0,
-            created_at: 0,
-            modified_at: 0,
-            permissions: permissions!(Owner:Write, Group:Write).to_u64(), // TODO: implement read
-            is_file: true,
-            is_directory: false,
-            is_symlink: false,
-            owner_id: 0,
-            group_id: 0,
-            flags: FLAG_VIRTUAL | FLAG_SYSTEM | FLAG_PHYSICAL_CHARACTER_DEVICE,
-        })
-    }
-
-    fn close(&mut self) -> Result<(), VfsError> {
-        Ok(())
-    }
-
-    fn seek(&mut self, position: SeekPosition) -> Result<u64, VfsError> {
-        if matches!(
-            position,
-            SeekPosition::FromStart(0) | SeekPosition::FromCurrent(0) | SeekPosition::FromEnd(0)
-        ) {
-            Ok(0)
-        } else {
-            Err(VfsError::InvalidSeekPosition)
-        }
-    }
-
-    fn pos(&self) -> Result<u64, VfsError> {
-        Ok(0)
+impl CharacterDevice for ParallelPort {
+    fn get_generation(&self) -> u64 {
+        0
     }
 
-    fn truncate(&mut self) -> Result<u64, VfsError> {
-        Ok(0)
+    fn get_size(&self) -> u64 {
+        0
     }
 
-    fn read(&mut self, _buf: &mut [u8]) -> Result<u64, VfsError> {
+    // TODO: implement read
+    fn read_chars(&self, _offset: u64, _buf: &mut [u8]) -> Result<u64, VfsError> {
         Ok(0)
     }
 
-    fn write(&mut self, buf: &[u8]) -> Result<u64, VfsError> {
-        let count = buf.len();
-
+    fn write_chars(&mut self, _offset: u64, buf: &[u8]) -> Result<u64, VfsError> {
         for byte in buf {
             unsafe { self.write_byte(*byte) };
         }
+        Ok(buf.len() as u64)
+    }
 
-        Ok(count as u64)
+    fn flush(&mut self) -> Result<(), VfsError> {
+        Ok(())
     }
-}
 
-pub fn init_lpt_files(devfs: &mut DevFs) {
-    let lpt1 = lpt1();
-    let lpt2 = lpt2();
-    let lpt3 = lpt3();
+    fn device_kind(&self) -> DeviceKind {
+        DeviceKind::Stream
+    }
+}
 
-    let osid = devfs.os_id();
+/// Major number of linux parallel printers
+const LP_MAJOR: u32 = 6;
 
-    if let Some(lpt1) = lpt1 {
-        devfs.insert_vfile(
-            arcrwb_new_from_box(Box::new(LptProvider {
-                lpt: lpt1,
-                devfs_os_id: osid,
-            })),
-            &['l', 'p', 't', '1'],
-        );
-    }
-    if let Some(lpt2) = lpt2 {
-        devfs.insert_vfile(
-            arcrwb_new_from_box(Box::new(LptProvider {
-                lpt: lpt2,
-                devfs_os_id: osid,
-            })),
-            &['l', 'p', 't', '2'],
+pub fn init_lpt_files(devfs: &mut DevFs) {
+    for lpt in get_lpts().into_iter().flatten() {
+        let name = alloc::vec!['l', 'p', 't', (b'0' + lpt.parallel_idx) as char];
+        let provider = CharacterDeviceProvider::new(
+            devfs.os_id(),
+            name.clone(),
+            arcrwb_new_from_box(Box::new(lpt)),
+            FileStat {
+                size: 0,
+                created_at: 0,
+                modified_at: 0,
+                permissions: permissions!(Owner:Write, Group:Write).to_u64(),
+                is_file: true,
+                is_directory: false,
+                is_symlink: false,
+                owner_id: 0,
+                group_id: 0,
+                flags: FLAG_VIRTUAL | FLAG_SYSTEM | FLAG_PHYSICAL_CHARACTER_DEVICE,
+            },
         );
-    }
-    if let Some(lpt3) = lpt3 {
-        devfs.insert_vfile(
-            arcrwb_new_from_box(Box::new(LptProvider {
-                lpt: lpt3,
-                devfs_os_id: osid,
-            })),
-            &['l', 'p', 't', '3'],
+        devfs.insert_vfile(arcrwb_new_from_box(Box::new(provider)), &name);
+        devfs.register_device_number(
+            false,
+            DeviceNumber::new(LP_MAJOR, lpt.parallel_idx as u32 - 1),
+            &name,
         );
     }
 }
@@ -0,0 +1,358 @@
+//! Terminal settings and the line discipline between a terminal and the programs reading it
+//!
+//! Termios flags, control characters and ioctl numbers are the linux ones.
+
+use alloc::{collections::VecDeque, vec::Vec};
+
+pub const TCGETS: u64 = 0x5401;
+pub const TCSETS: u64 = 0x5402;
+pub const TCSETSW: u64 = 0x5403;
+pub const TCSETSF: u64 = 0x5404;
+pub const TIOCGWINSZ: u64 = 0x5413;
+pub const TIOCSWINSZ: u64 = 0x5414;
+pub const TIOCGPTN: u64 = 0x8004_5430;
+pub const TIOCSPTLCK: u64 = 0x4004_5431;
+
+pub const NCCS: usize = 19;
+/// Size of the kernel `struct termios`
+pub const TERMIOS_SIZE: usize = 16 + 1 + NCCS;
+pub const WINSIZE_SIZE: usize = 8;
+
+/// Bytes of input kept before writers have to wait, a canonical line is cut there
+pub const TTY_BUFFER_SIZE: usize = 4096;
+
+pub const VINTR: usize = 0;
+pub const VQUIT: usize = 1;
+pub const VERASE: usize = 2;
+pub const VKILL: usize = 3;
+pub const VEOF: usize = 4;
+pub const VMIN: usize = 6;
+pub const VSUSP: usize = 10;
+pub const VEOL: usize = 11;
+
+pub const IGNCR: u32 = 0o200;
+pub const ICRNL: u32 = 0o400;
+pub const INLCR: u32 = 0o100;
+pub const IXON: u32 = 0o2000;
+
+pub const OPOST: u32 = 0o1;
+pub const ONLCR: u32 = 0o4;
+
+pub const CS8: u32 = 0o60;
+pub const CREAD: u32 = 0o200;
+pub const HUPCL: u32 = 0o2000;
+pub const B38400: u32 = 0o17;
+
+pub const ISIG: u32 = 0o1;
+pub const ICANON: u32 = 0o2;
+pub const ECHO: u32 = 0o10;
+pub const ECHOE: u32 = 0o20;
+pub const ECHOK: u32 = 0o40;
+pub const ECHONL: u32 = 0o100;
+pub const NOFLSH: u32 = 0o200;
+pub const ECHOCTL: u32 = 0o1000;
+pub const ECHOKE: u32 = 0o4000;
+pub const IEXTEN: u32 = 0o100000;
+
+/// Size of the argument of the terminal ioctl `request`, `None` if it isn't one
+pub fn ioctl_argument_size(request: u64) -> Option<usize> {
+    match request {
+        TCGETS | TCSETS | TCSETSW | TCSETSF => Some(TERMIOS_SIZE),
+        TIOCGWINSZ | TIOCSWINSZ => Some(WINSIZE_SIZE),
+        TIOCGPTN | TIOCSPTLCK => Some(4),
+        _ => None,
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Termios {
+    pub iflag: u32,
+    pub oflag: u32,
+    pub cflag: u32,
+    pub lflag: u32,
+    pub line: u8,
+    pub cc: [u8; NCCS],
+}
+
+impl Default for Termios {
+    /// Same settings as a new linux terminal
+    fn default() -> Self {
+        let mut cc = [0; NCCS];
+        cc[VINTR] = 0x03;
+        cc[VQUIT] = 0x1C;
+        cc[VERASE] = 0x7F;
+        cc[VKILL] = 0x15;
+        cc[VEOF] = 0x04;
+        cc[VMIN] = 1;
+        cc[VSUSP] = 0x1A;
+        Self {
+            iflag: ICRNL | IXON,
+            oflag: OPOST | ONLCR,
+            cflag: B38400 | CS8 | CREAD | HUPCL,
+            lflag: ISIG | ICANON | ECHO | ECHOE | ECHOK | ECHOCTL | ECHOKE | IEXTEN,
+            line: 0,
+            cc,
+        }
+    }
+}
+
+impl Termios {
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() < TERMIOS_SIZE {
+            return None;
+        }
+        let flag = |index: usize| u32::from_ne_bytes(bytes[index * 4..][..4].try_into().unwrap());
+        let mut cc = [0; NCCS];
+        cc.copy_from_slice(&bytes[17..TERMIOS_SIZE]);
+        Some(Self {
+            iflag: flag(0),
+            oflag: flag(1),
+            cflag: flag(2),
+            lflag: flag(3),
+            line: bytes[16],
+            cc,
+        })
+    }
+
+    pub fn to_bytes(&self) -> [u8; TERMIOS_SIZE] {
+        let mut bytes = [0; TERMIOS_SIZE];
+        for (index, flag) in [self.iflag, self.oflag, self.cflag, self.lflag]
+            .into_iter()
+            .enumerate()
+        {
+            bytes[index * 4..][..4].copy_from_slice(&flag.to_ne_bytes());
+        }
+        bytes[16] = self.line;
+        bytes[17..].copy_from_slice(&self.cc);
+        bytes
+    }
+
+    pub fn is_canonical(&self) -> bool {
+        self.lflag & ICANON != 0
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct WinSize {
+    pub rows: u16,
+    pub cols: u16,
+    pub xpixel: u16,
+    pub ypixel: u16,
+}
+
+impl WinSize {
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() < WINSIZE_SIZE {
+            return None;
+        }
+        let field = |index: usize| u16::from_ne_bytes([bytes[index * 2], bytes[index * 2 + 1]]);
+        Some(Self {
+            rows: field(0),
+            cols: field(1),
+            xpixel: field(2),
+            ypixel: field(3),
+        })
+    }
+
+    pub fn to_bytes(&self) -> [u8; WINSIZE_SIZE] {
+        let mut bytes = [0; WINSIZE_SIZE];
+        for (index, field) in [self.rows, self.cols, self.xpixel, self.ypixel]
+            .into_iter()
+            .enumerate()
+        {
+            bytes[index * 2..][..2].copy_from_slice(&field.to_ne_bytes());
+        }
+        bytes
+    }
+}
+
+/// Signal a control character asks for, sent to the foreground process group
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TtySignal {
+    Interrupt,
+    Quit,
+    Suspend,
+}
+
+/// Input side of a terminal: edits lines in canonical mode and produces the echo
+#[derive(Debug, Default)]
+pub struct LineDiscipline {
+    /// Line being edited in canonical mode
+    line: Vec<u8>,
+    /// Bytes readers can take
+    ready: VecDeque<u8>,
+    /// Lengths of the lines in `ready` in canonical mode, a read stops at the end of one.
+    /// An empty line is an end of file
+    lines: VecDeque<usize>,
+}
+
+impl LineDiscipline {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Whether another byte of input fits
+    pub fn can_receive(&self) -> bool {
+        self.ready.len() + self.line.len() < TTY_BUFFER_SIZE
+    }
+
+    /// Processes a byte typed on the terminal, the echo is appended to `echo`
+    pub fn receive(
+        &mut self,
+        termios: &Termios,
+        byte: u8,
+        echo: &mut Vec<u8>,
+    ) -> Option<TtySignal> {
+        let byte = match byte {
+            b'\r' if termios.iflag & IGNCR != 0 => return None,
+            b'\r' if termios.iflag & ICRNL != 0 => b'\n',
+            b'\n' if termios.iflag & INLCR != 0 => b'\r',
+            byte => byte,
+        };
+        let echoing = termios.lflag & ECHO != 0;
+
+        if termios.lflag & ISIG != 0 && byte != 0 {
+            let signal = match byte {
+                _ if byte == termios.cc[VINTR] => Some(TtySignal::Interrupt),
+                _ if byte == termios.cc[VQUIT] => Some(TtySignal::Quit),
+                _ if byte == termios.cc[VSUSP] => Some(TtySignal::Suspend),
+                _ => None,
+            };
+            if let Some(signal) = signal {
+                if termios.lflag & NOFLSH == 0 {
+                    self.flush();
+                }
+                if echoing {
+                    Self::echo(termios, byte, echo);
+                }
+                return Some(signal);
+            }
+        }
+
+        if !termios.is_canonical() {
+            self.ready.push_back(byte);
+            if echoing {
+                Self::echo(termios, byte, echo);
+            }
+            return None;
+        }
+
+        if byte == termios.cc[VERASE] || byte == 0x08 {
+            if self.line.pop().is_some() && echoing && termios.lflag & ECHOE != 0 {
+                echo.extend_from_slice(b"\x08 \x08");
+            }
+        } else if byte == termios.cc[VKILL] {
+            if echoing && termios.lflag & ECHOK != 0 {
+                for _ in 0..self.line.len() {
+                    echo.extend_from_slice(b"\x08 \x08");
+                }
+            }
+            self.line.clear();
+        } else if byte == termios.cc[VEOF] {
+            self.commit_line();
+        } else if byte == b'\n' || (byte != 0 && byte == termios.cc[VEOL]) {
+            self.line.push(byte);
+            self.commit_line();
+            if echoing || termios.lflag & ECHONL != 0 {
+                echo.push(b'\n');
+            }
+        } else if self.can_receive() {
+            self.line.push(byte);
+            if echoing {
+                Self::echo(termios, byte, echo);
+            }
+        }
+        None
+    }
+
+    /// Control characters are echoed as `^X`
+    fn echo(termios: &Termios, byte: u8, echo: &mut Vec<u8>) {
+        if byte < 0x20 && byte != b'\t' && byte != b'\n' && termios.lflag & ECHOCTL != 0 {
+            echo.extend_from_slice(&[b'^', byte + 0x40]);
+        } else {
+            echo.push(byte);
+        }
+    }
+
+    fn commit_line(&mut self) {
+        self.lines.push_back(self.line.len());
+        self.ready.extend(self.line.drain(..));
+    }
+
+    /// Whether a read would return right away
+    pub fn is_readable(&self, termios: &Termios) -> bool {
+        if termios.is_canonical() {
+            !self.lines.is_empty()
+        } else {
+            !self.ready.is_empty() || termios.cc[VMIN] == 0
+        }
+    }
+
+    /// `None` when the reader has to wait. In canonical mode a read returns at most one line,
+    /// 0 bytes for an end of file
+    pub fn read(&mut self, termios: &Termios, buf: &mut [u8]) -> Option<usize> {
+        if !self.is_readable(termios) {
+            return None;
+        }
+        let available = if termios.is_canonical() {
+            self.lines[0]
+        } else {
+            self.ready.len()
+        };
+        let read = available.min(buf.len());
+        for (byte, slot) in self.ready.drain(..read).zip(buf.iter_mut()) {
+            *slot = byte;
+        }
+        if termios.is_canonical() {
+            if read == available {
+                self.lines.pop_front();
+            } else {
+                self.lines[0] -= read;
+            }
+        }
+        Some(read)
+    }
+
+    /// Applies new settings, leaving canonical mode makes the line being edited readable
+    pub fn set_termios(&mut self, old: &Termios, new: &Termios) {
+        if old.is_canonical() && !new.is_canonical() {
+            self.ready.extend(self.line.drain(..));
+            self.lines.clear();
+        } else if !old.is_canonical() && new.is_canonical() {
+            // Whatever was typed is one line
+            if !self.ready.is_empty() {
+                self.lines.push_back(self.ready.len());
+            }
+        }
+    }
+
+    /// Drops the pending input
+    pub fn flush(&mut self) {
+        self.line.clear();
+        self.ready.clear();
+        self.lines.clear();
+    }
+}
+
+/// Output processing of what programs write to the terminal. Returns how many bytes of `buf`
+/// were taken, `out` doesn't grow past `limit`
+pub fn process_output(
+    termios: &Termios,
+    buf: &[u8],
+    out: &mut VecDeque<u8>,
+    limit: usize,
+) -> usize {
+    let translate_newline = termios.oflag & OPOST != 0 && termios.oflag & ONLCR != 0;
+    for (written, byte) in buf.iter().enumerate() {
+        if *byte == b'\n' && translate_newline {
+            if out.len() + 2 > limit {
+                return written;
+            }
+            out.push_back(b'\r');
+        } else if out.len() >= limit {
+            return written;
+        }
+        out.push_back(*byte);
+    }
+    buf.len()
+}
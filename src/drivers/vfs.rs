@@ -1,4 +1,9 @@
-use core::{alloc::Layout, any::Any, fmt::Debug};
+use core::{
+    alloc::Layout,
+    any::{Any, TypeId},
+    fmt::Debug,
+    sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering},
+};
 
 use alloc::{
     alloc::{alloc, dealloc},
@@ -8,32 +13,59 @@ use alloc::{
     sync::{Arc, Weak},
     vec::Vec,
 };
-use spin::RwLock;
+use spin::{Mutex, Once};
 
 use crate::{
-    data::either::Either,
-    drivers::fs::virt::pipefs::{init_pipefs, Pipe},
+    data::{
+        either::Either,
+        lock::{DebugRwLock, LockClass},
+        path::{Components, VfsName, VfsPath},
+        permissions::{Credentials, Permissions},
+    },
+    debuggable_bitset_enum,
+    drivers::{
+        disk::queue::{BlockRequest, BlockRequestKind},
+        fs::{
+            lock::FILE_LOCKS,
+            virt::{
+                bind::BindFs,
+                pipefs::{init_pipefs, Pipe},
+                procfs::init_procfs,
+                ptyfs::init_ptyfs,
+                timerfd::init_timerfd_fs,
+                unixsock::init_unixsockfs,
+            },
+        },
+    },
+    log_error, log_warn,
+    memory::entropy::random_u64,
+    percpu::{core_id, is_per_cpu_initialized},
+    process::wait::WaitQueue,
 };
 
-use super::fs::virt::devfs::init_devfs;
+use super::fs::virt::devfs::{fseek_helper, init_devfs};
 
-pub type Arcrwb<T> = Arc<RwLock<Box<T>>>;
-pub type WeakArcrwb<T> = Weak<RwLock<Box<T>>>;
+pub type Arcrwb<T> = Arc<DebugRwLock<Box<T>>>;
+pub type WeakArcrwb<T> = Weak<DebugRwLock<Box<T>>>;
 
 pub fn arcrwb_new<T>(x: T) -> Arcrwb<T> {
-    Arcrwb::new(RwLock::new(Box::new(x)))
+    Arcrwb::new(DebugRwLock::new(Box::new(x)))
 }
 
 pub fn arcrwb_new_from_box<T: ?Sized>(x: Box<T>) -> Arcrwb<T> {
-    Arcrwb::new(RwLock::new(x))
+    Arcrwb::new(DebugRwLock::new(x))
+}
+
+pub fn arcrwb_with_class<T: ?Sized>(x: Box<T>, class: LockClass) -> Arcrwb<T> {
+    Arcrwb::new(DebugRwLock::with_class(x, class))
 }
 
 pub fn weak_arcrwb_new<T>(x: T) -> Arcrwb<T> {
-    Arcrwb::new(RwLock::new(Box::new(x)))
+    arcrwb_new(x)
 }
 
 pub fn weak_arcrwb_new_from_box<T: ?Sized>(x: Box<T>) -> Arcrwb<T> {
-    Arcrwb::new(RwLock::new(x))
+    arcrwb_new_from_box(x)
 }
 
 #[derive(Debug)]
@@ -54,6 +86,8 @@ pub enum VfsError {
     BadHandle,
     AlreadyMounted,
     OutOfSpace,
+    /// A kernel allocation failed
+    OutOfMemory,
     InvalidArgument,
     MaximumSizeReached,
     EntryNotFound,
@@ -65,15 +99,74 @@ pub enum VfsError {
     Done,
     WouldBlock,
     BrokenPipe,
+    /// A blocking operation was cut short by a signal
+    Interrupted,
+    PermissionDenied,
+    /// The file system still has open files
+    Busy,
+    /// No driver has the device of a device node
+    NoSuchDevice,
+    /// The ioctl isn't one of a terminal, or the file isn't one
+    NotTerminal,
+    /// The other end of a terminal was closed
+    HungUp,
+    /// The media changed since the device was opened, its generation moved on
+    StaleDevice,
+    /// Resolving a path followed more than `SYMLINK_MAX_HOPS` symlinks
+    SymlinkLoop,
     DriverError(Box<dyn core::fmt::Debug>),
 }
 
+// Same values as the linux poll events
+debuggable_bitset_enum!(
+    u16,
+    pub enum PollEvent {
+        In = 0x1,
+        Priority = 0x2,
+        Out = 0x4,
+        Error = 0x8,
+        HangUp = 0x10,
+        Invalid = 0x20,
+    },
+    PollEvents
+);
+
+/// Readiness of a handle that never blocks
+pub const POLL_ALWAYS_READY: PollEvents = PollEvents(
+    PollEvents::empty()
+        .set(PollEvent::In)
+        .set(PollEvent::Out)
+        .get(),
+);
+
+/// Events reported by poll even when they weren't requested
+pub const POLL_ALWAYS_REPORTED: PollEvents = PollEvents(
+    PollEvents::empty()
+        .set(PollEvent::Error)
+        .set(PollEvent::HangUp)
+        .set(PollEvent::Invalid)
+        .get(),
+);
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum PipeMode {
     Read,
     Write,
 }
 
+/// Linux style device number, char and block devices are numbered separately
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct DeviceNumber {
+    pub major: u32,
+    pub minor: u32,
+}
+
+impl DeviceNumber {
+    pub const fn new(major: u32, minor: u32) -> Self {
+        Self { major, minor }
+    }
+}
+
 #[derive(Clone)]
 pub enum VfsFileKind {
     File,
@@ -92,6 +185,14 @@ pub enum VfsFileKind {
         mode: PipeMode,
         pipe_id: u64,
     },
+    /// A device file stored on a file system, opening it opens the devfs device registered
+    /// under its number
+    DeviceNode {
+        block: bool,
+        number: DeviceNumber,
+    },
+    /// Paths through it continue at its target, see `FileSystem::read_link`
+    Symlink,
 }
 
 impl Debug for VfsFileKind {
@@ -103,6 +204,14 @@ impl Debug for VfsFileKind {
             VfsFileKind::CharacterDevice { .. } => write!(f, "CharacterDevice"),
             VfsFileKind::MountPoint { .. } => write!(f, "MountPoint"),
             VfsFileKind::Pipe { .. } => write!(f, "Pipe"),
+            VfsFileKind::Symlink => write!(f, "Symlink"),
+            VfsFileKind::DeviceNode { block, number } => write!(
+                f,
+                "DeviceNode({}, {}:{})",
+                if *block { "block" } else { "char" },
+                number.major,
+                number.minor
+            ),
         }
     }
 }
@@ -152,6 +261,14 @@ impl VfsFile {
         }
     }
 
+    /// Whether the node is a block device and its number
+    pub fn get_device_node(&self) -> Option<(bool, DeviceNumber)> {
+        match &self.kind {
+            VfsFileKind::DeviceNode { block, number } => Some((*block, *number)),
+            _ => None,
+        }
+    }
+
     pub fn get_fs_specific_data(&self) -> Arc<dyn FsSpecificFileData> {
         self.fs_specific.clone()
     }
@@ -175,6 +292,23 @@ impl VfsFile {
     pub fn is_mount_point(&self) -> bool {
         matches!(self.kind, VfsFileKind::MountPoint { .. })
     }
+
+    pub fn is_device_node(&self) -> bool {
+        matches!(self.kind, VfsFileKind::DeviceNode { .. })
+    }
+
+    pub fn is_symlink(&self) -> bool {
+        matches!(self.kind, VfsFileKind::Symlink)
+    }
+
+    /// The root of the file system mounted on this file, or the file itself when nothing is
+    /// mounted there
+    pub fn resolve_mount(self) -> Result<VfsFile, VfsError> {
+        match self.get_mounted_fs() {
+            Some(fs) => fs.write().get_root(),
+            None => Ok(self),
+        }
+    }
 }
 
 impl VfsFile {
@@ -200,6 +334,16 @@ impl VfsFile {
         &self.kind
     }
 
+    /// Returns the same file under another name
+    pub fn clone_renamed(&self, name: Vec<char>) -> VfsFile {
+        VfsFile {
+            name,
+            ..self.clone()
+        }
+    }
+
+    /// The last name of the file's path, `/` for the root of a file system. The full path is only
+    /// known to the lookup that found the file, see `PathTraverse::path`
     pub fn name(&self) -> &[char] {
         &self.name
     }
@@ -221,9 +365,36 @@ pub trait BlockDevice: Send + Sync + core::fmt::Debug + AsAny {
     fn get_generation(&self) -> u64;
     fn get_block_size(&self) -> u64;
     fn get_block_count(&self) -> u64;
+
+    /// Model and serial of the hardware behind the device, if it reports them
+    fn description(&self) -> Option<String> {
+        None
+    }
+
     fn read_block(&self, lba: u64, buf: &mut [u8]) -> Result<u64, VfsError>;
     fn write_block(&mut self, lba: u64, buf: &[u8]) -> Result<u64, VfsError>;
     fn flush(&mut self) -> Result<(), VfsError>;
+
+    /// Writes the volatile write cache of the device back to the media, devices without one have
+    /// nothing to do
+    fn flush_cache(&mut self) -> Result<(), VfsError> {
+        Ok(())
+    }
+
+    /// Queues `request`, its result is delivered through its completion. Devices without a
+    /// request queue run it right away
+    fn submit(&mut self, request: BlockRequest) {
+        request.run_on(self)
+    }
+}
+
+/// How handles move through a character device
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeviceKind {
+    /// Addressed by offset, each handle keeps its position
+    Seekable,
+    /// Bytes come and go in order, the offset is always 0 and seeking fails
+    Stream,
 }
 
 pub trait CharacterDevice: Send + Sync + core::fmt::Debug + AsAny {
@@ -232,6 +403,43 @@ pub trait CharacterDevice: Send + Sync + core::fmt::Debug + AsAny {
     fn read_chars(&self, offset: u64, buf: &mut [u8]) -> Result<u64, VfsError>;
     fn write_chars(&mut self, offset: u64, buf: &[u8]) -> Result<u64, VfsError>;
     fn flush(&mut self) -> Result<(), VfsError>;
+
+    fn device_kind(&self) -> DeviceKind {
+        DeviceKind::Seekable
+    }
+
+    /// Reads at the position of a handle and moves it past the bytes read
+    fn read_at_position(&self, position: &mut u64, buf: &mut [u8]) -> Result<u64, VfsError> {
+        match self.device_kind() {
+            DeviceKind::Seekable => {
+                let read = self.read_chars(*position, buf)?;
+                *position += read;
+                Ok(read)
+            }
+            DeviceKind::Stream => self.read_chars(0, buf),
+        }
+    }
+
+    /// Writes at the position of a handle and moves it past the bytes written
+    fn write_at_position(&mut self, position: &mut u64, buf: &[u8]) -> Result<u64, VfsError> {
+        match self.device_kind() {
+            DeviceKind::Seekable => {
+                let written = self.write_chars(*position, buf)?;
+                *position += written;
+                Ok(written)
+            }
+            DeviceKind::Stream => self.write_chars(0, buf),
+        }
+    }
+
+    /// New position of a handle at `current`, streams can't seek
+    fn seek_position(&self, position: SeekPosition, current: u64) -> Result<u64, VfsError> {
+        match self.device_kind() {
+            DeviceKind::Seekable => fseek_helper(position, current, self.get_size())
+                .ok_or(VfsError::InvalidSeekPosition),
+            DeviceKind::Stream => Err(VfsError::InvalidSeekPosition),
+        }
+    }
 }
 
 pub trait AsAny {
@@ -273,11 +481,19 @@ impl BlockDevice for SubBlockDevice {
         self.end_block - self.begin_block
     }
 
+    fn description(&self) -> Option<String> {
+        self.device.read().description()
+    }
+
     fn read_block(&self, lba: u64, buf: &mut [u8]) -> Result<u64, VfsError> {
         if lba >= self.get_block_count() {
             return Err(VfsError::OutOfBounds);
         }
-        self.device.read().read_block(lba, buf)
+        let guard = self.device.read();
+        if guard.get_generation() != self.generation {
+            return Err(VfsError::StaleDevice);
+        }
+        guard.read_block(self.begin_block + lba, buf)
     }
 
     fn write_block(&mut self, lba: u64, buf: &[u8]) -> Result<u64, VfsError> {
@@ -286,24 +502,58 @@ impl BlockDevice for SubBlockDevice {
         }
         let mut guard = self.device.write();
         if guard.get_generation() != self.generation {
-            return Err(VfsError::ActionNotAllowed);
+            return Err(VfsError::StaleDevice);
         }
-        guard.write_block(lba, buf)
+        guard.write_block(self.begin_block + lba, buf)
     }
 
     fn flush(&mut self) -> Result<(), VfsError> {
         self.device.write().flush()
     }
+
+    fn flush_cache(&mut self) -> Result<(), VfsError> {
+        self.device.write().flush_cache()
+    }
+
+    fn submit(&mut self, request: BlockRequest) {
+        if request.kind != BlockRequestKind::Barrier
+            && request.lba + request.count > self.get_block_count()
+        {
+            return request.fail(VfsError::OutOfBounds);
+        }
+        let mut guard = self.device.write();
+        if request.kind != BlockRequestKind::Barrier && guard.get_generation() != self.generation {
+            return request.fail(VfsError::StaleDevice);
+        }
+        guard.submit(request.offset_by(self.begin_block))
+    }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug)]
 pub struct BlockDeviceAsCharacterDevice {
     device: Arcrwb<dyn BlockDevice>,
+    /// Generation of the device when wrapped, accesses fail once it changes
+    generation: u64,
+    /// Holds the block of unaligned accesses, allocated on first use
+    scratch: Mutex<Vec<u8>>,
 }
 
 impl BlockDeviceAsCharacterDevice {
     pub fn new(device: Arcrwb<dyn BlockDevice>) -> Self {
-        Self { device }
+        let generation = device.read().get_generation();
+        Self {
+            device,
+            generation,
+            scratch: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Bytes that can be accessed from `offset`, at most `len`
+    fn clamp_len(size: u64, offset: u64, len: usize) -> Result<usize, VfsError> {
+        match size.checked_sub(offset) {
+            Some(remaining) => Ok((len as u64).min(remaining) as usize),
+            None => Err(VfsError::InvalidArgument),
+        }
     }
 }
 
@@ -321,25 +571,35 @@ impl CharacterDevice for BlockDeviceAsCharacterDevice {
         self.device.write().flush()
     }
 
+    /// Whole blocks are read straight into `buf`, only partial ones go through the scratch block
     fn read_chars(&self, mut offset: u64, buf: &mut [u8]) -> Result<u64, VfsError> {
-        let to_read = (buf.len() as u64).min(self.get_size() - offset) as usize;
+        let to_read = Self::clamp_len(self.get_size(), offset, buf.len())?;
         let mut read: usize = 0;
 
         let guard = self.device.read();
+        if guard.get_generation() != self.generation {
+            return Err(VfsError::StaleDevice);
+        }
 
         let block_size = guard.get_block_size() as usize;
-        let mut block = alloc::vec![0u8; block_size];
+        let mut scratch = None;
 
         while read < to_read {
-            let lba = offset / guard.get_block_size();
-            let pos = (offset % guard.get_block_size()) as usize;
+            let lba = offset / block_size as u64;
+            let pos = (offset % block_size as u64) as usize;
 
             let rem_read = to_read - read;
             let max_read_sector = block_size - pos;
             let to_read_sector = rem_read.min(max_read_sector);
 
-            guard.read_block(lba, &mut block)?;
-            buf[read..read + to_read_sector].copy_from_slice(&block[pos..pos + to_read_sector]);
+            if to_read_sector == block_size {
+                guard.read_block(lba, &mut buf[read..read + block_size])?;
+            } else {
+                let block = scratch.get_or_insert_with(|| self.scratch.lock());
+                block.resize(block_size, 0);
+                guard.read_block(lba, block)?;
+                buf[read..read + to_read_sector].copy_from_slice(&block[pos..pos + to_read_sector]);
+            }
 
             read += to_read_sector;
             offset += to_read_sector as u64;
@@ -347,30 +607,35 @@ impl CharacterDevice for BlockDeviceAsCharacterDevice {
         Ok(read as u64)
     }
 
+    /// Whole blocks are written straight from `buf`, only partial ones are read back first
     fn write_chars(&mut self, mut offset: u64, buf: &[u8]) -> Result<u64, VfsError> {
-        let to_write = (buf.len() as u64).min(self.get_size() - offset) as usize;
+        let to_write = Self::clamp_len(self.get_size(), offset, buf.len())?;
         let mut write: usize = 0;
 
         let mut guard = self.device.write();
+        if guard.get_generation() != self.generation {
+            return Err(VfsError::StaleDevice);
+        }
 
         let block_size = guard.get_block_size() as usize;
-        let mut block = alloc::vec![0u8; block_size];
+        let block = self.scratch.get_mut();
 
         while write < to_write {
-            let lba = offset / guard.get_block_size();
-            let pos = (offset % guard.get_block_size()) as usize;
+            let lba = offset / block_size as u64;
+            let pos = (offset % block_size as u64) as usize;
 
             let rem_write = to_write - write;
             let max_write_sector = block_size - pos;
             let to_write_sector = rem_write.min(max_write_sector);
 
-            if to_write_sector != block_size {
-                guard.read_block(lba, &mut block)?;
+            if to_write_sector == block_size {
+                guard.write_block(lba, &buf[write..write + block_size])?;
+            } else {
+                block.resize(block_size, 0);
+                guard.read_block(lba, block)?;
                 block[pos..pos + to_write_sector]
                     .copy_from_slice(&buf[write..write + to_write_sector]);
-                guard.write_block(lba, &block)?;
-            } else {
-                guard.write_block(lba, &buf[write..write + to_write_sector])?;
+                guard.write_block(lba, block)?;
             }
 
             write += to_write_sector;
@@ -386,6 +651,11 @@ pub const OPEN_MODE_APPEND: u64 = 1 << 2;
 pub const OPEN_MODE_NO_RESIZE: u64 = 1 << 3;
 pub const OPEN_MODE_CREATE: u64 = 1 << 4;
 pub const OPEN_MODE_FAIL_IF_EXISTS: u64 = 1 << 5;
+/// Operations fail with `WouldBlock` instead of waiting
+pub const OPEN_MODE_NONBLOCK: u64 = 1 << 6;
+
+/// Mount flag, the linux value. Writes through the mount fail with `VfsError::ReadOnly`
+pub const MS_RDONLY: u64 = 1;
 
 #[derive(Debug, Clone, Copy)]
 pub enum SeekPosition {
@@ -404,7 +674,7 @@ pub const FLAG_PHYSICAL_CHARACTER_DEVICE: u64 = 1 << 6;
 pub const FLAG_VIRTUAL_CHARACTER_DEVICE: u64 = 1 << 7;
 pub const FLAG_PARTITIONED_DEVICE: u64 = 1 << 8;
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct FileStat {
     pub size: u64,
     pub created_at: u64,
@@ -418,6 +688,37 @@ pub struct FileStat {
     pub flags: u64,
 }
 
+/// Capacity of a file system, counted in blocks of `block_size` bytes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FsStat {
+    /// Magic number of the file system type, the linux one
+    pub fs_type: u64,
+    pub block_size: u64,
+    pub total_blocks: u64,
+    pub free_blocks: u64,
+    /// Free blocks unprivileged users can allocate
+    pub available_blocks: u64,
+    pub total_inodes: u64,
+    pub free_inodes: u64,
+    pub max_name_length: u64,
+}
+
+impl FsStat {
+    /// A file system without storage holding `files` files
+    pub const fn virtual_fs(fs_type: u64, files: u64) -> Self {
+        Self {
+            fs_type,
+            block_size: 4096,
+            total_blocks: 0,
+            free_blocks: 0,
+            available_blocks: 0,
+            total_inodes: files,
+            free_inodes: 0,
+            max_name_length: 255,
+        }
+    }
+}
+
 pub trait FileSystem: Send + Sync + core::fmt::Debug + AsAny {
     /// Returns this file system's ID
     fn os_id(&mut self) -> u64;
@@ -438,13 +739,13 @@ pub trait FileSystem: Send + Sync + core::fmt::Debug + AsAny {
     fn get_mount_point(&mut self) -> Result<Option<VfsFile>, VfsError>;
 
     /// Finds a child of the given file
-    fn get_child(&mut self, file: &VfsFile, child: &[char]) -> Result<VfsFile, VfsError>;
+    fn get_child(&mut self, file: &VfsFile, child: &VfsName) -> Result<VfsFile, VfsError>;
 
     /// Lists the children of the given file if it is a directory
     fn list_children(&mut self, file: &VfsFile) -> Result<Vec<VfsFile>, VfsError>;
 
     /// Returns the file at the given path, from this file system's root
-    fn get_file(&mut self, path: &[char]) -> Result<VfsFile, VfsError>;
+    fn get_file(&mut self, path: &VfsPath) -> Result<VfsFile, VfsError>;
 
     /// Returns the stats of the given file
     fn get_stats(&mut self, file: &VfsFile) -> Result<FileStat, VfsError>;
@@ -453,13 +754,34 @@ pub trait FileSystem: Send + Sync + core::fmt::Debug + AsAny {
     fn create_child(
         &mut self,
         directory: &VfsFile,
-        name: &[char],
+        name: &VfsName,
         kind: VfsFileKind,
     ) -> Result<VfsFile, VfsError>;
 
     /// Deletes a file, or an empty directory
     fn delete_file(&mut self, file: &VfsFile) -> Result<(), VfsError>;
 
+    /// Adds a new name for an existing file, both must be on this file system and directories can't be linked
+    fn link(
+        &mut self,
+        _existing: &VfsFile,
+        _new_parent: &VfsFile,
+        _new_name: &VfsName,
+    ) -> Result<VfsFile, VfsError> {
+        Err(VfsError::ActionNotAllowed)
+    }
+
+    /// Target of a symlink, relative ones start from the directory holding it
+    fn read_link(&mut self, _file: &VfsFile) -> Result<VfsPath, VfsError> {
+        Err(VfsError::ActionNotAllowed)
+    }
+
+    /// Identifies the file for advisory locks, every open of the same file must give the same value.
+    /// The fs specific data is shared between lookups of a virtual file, so its address is enough
+    fn file_identity(&mut self, file: &VfsFile) -> u64 {
+        Arc::as_ptr(&file.get_fs_specific_data()) as *const () as u64
+    }
+
     /// Called when filesystem is mounted
     /// Returns the root directory of the mounted filesystem
     fn on_mount(
@@ -498,131 +820,204 @@ pub trait FileSystem: Send + Sync + core::fmt::Debug + AsAny {
     /// Returns the number of bytes written
     fn fwrite(&mut self, handle: u64, buf: &[u8]) -> Result<u64, VfsError>;
 
+    /// Whether the handle is a file with a position `fread_at` and `fwrite_at` can address,
+    /// streams like pipes and character devices aren't
+    fn fseekable(&mut self, _handle: u64) -> bool {
+        false
+    }
+
+    /// Reads from a file at `offset`, the position of the handle is left unchanged
+    /// Returns the number of bytes read
+    fn fread_at(&mut self, handle: u64, offset: u64, buf: &mut [u8]) -> Result<u64, VfsError> {
+        let position = self.fseek(handle, SeekPosition::FromCurrent(0))?;
+        self.fseek(handle, SeekPosition::FromStart(offset))?;
+        let read = self.fread(handle, buf);
+        self.fseek(handle, SeekPosition::FromStart(position))?;
+        read
+    }
+
+    /// Writes to a file at `offset`, the position of the handle is left unchanged
+    /// Returns the number of bytes written
+    fn fwrite_at(&mut self, handle: u64, offset: u64, buf: &[u8]) -> Result<u64, VfsError> {
+        let position = self.fseek(handle, SeekPosition::FromCurrent(0))?;
+        self.fseek(handle, SeekPosition::FromStart(offset))?;
+        let written = self.fwrite(handle, buf);
+        self.fseek(handle, SeekPosition::FromStart(position))?;
+        written
+    }
+
+    /// Copies `len` bytes from `src` at `src_offset` to `dst` at `dst_offset`, two handles of
+    /// this file system. The positions of the handles are left unchanged
+    /// Returns the number of bytes copied, short at the end of `src` or when `dst` can't grow
+    fn fcopy_range(
+        &mut self,
+        src: u64,
+        src_offset: u64,
+        dst: u64,
+        dst_offset: u64,
+        len: u64,
+    ) -> Result<u64, VfsError> {
+        copy_through_buffer(
+            self,
+            len,
+            |fs, done, buf| fs.fread_at(src, src_offset + done, buf),
+            |fs, done, buf| fs.fwrite_at(dst, dst_offset + done, buf),
+        )
+    }
+
     /// Flushes a file
     fn fflush(&mut self, handle: u64) -> Result<(), VfsError>;
 
     /// Synchronizes a file
     fn fsync(&mut self, handle: u64) -> Result<(), VfsError>;
 
+    /// Writes out the changes batched in memory, called periodically
+    fn writeback(&mut self) -> Result<(), VfsError> {
+        Ok(())
+    }
+
+    /// Frees the clean data cached in memory
+    fn shrink_caches(&self) {}
+
     /// Gets stats of a file
     fn fstat(&self, handle: u64) -> Result<FileStat, VfsError>;
 
     /// Truncates a file
     /// Returns the new size
     fn ftruncate(&mut self, handle: u64) -> Result<u64, VfsError>;
-}
 
-pub struct PathSplitter<'a> {
-    path: &'a [char],
-    idx: usize,
-    last_part: Option<&'a [char]>,
-}
+    /// Changes the permission bits (`0o7777`) of a file
+    fn fchmod(&mut self, _handle: u64, _mode: u64) -> Result<(), VfsError> {
+        Err(VfsError::ActionNotAllowed)
+    }
 
-pub struct PathSplitterPeek<'a, 'b>
-where
-    'a: 'b,
-{
-    splitter: &'b mut PathSplitter<'a>,
-    slice: &'a [char],
-    idx: usize,
-}
+    /// Changes the owner and group of a file, `None` keeps the current one
+    fn fchown(
+        &mut self,
+        _handle: u64,
+        _uid: Option<u32>,
+        _gid: Option<u32>,
+    ) -> Result<(), VfsError> {
+        Err(VfsError::ActionNotAllowed)
+    }
 
-impl<'a> PathSplitterPeek<'a, '_> {
-    pub fn apply(self) -> &'a [char] {
-        self.splitter.last_part = Some(self.slice);
-        self.splitter.idx = self.idx;
-        self.slice
+    /// Changes the access and modification times (unix seconds) of a file, `None` keeps the current one
+    fn futimens(
+        &mut self,
+        _handle: u64,
+        _atime: Option<u64>,
+        _mtime: Option<u64>,
+    ) -> Result<(), VfsError> {
+        Err(VfsError::ActionNotAllowed)
     }
 
-    pub fn get_path_part(&self) -> &'a [char] {
-        self.slice
+    /// Returns which of `events` are ready on a file, `POLL_ALWAYS_REPORTED` events are reported even if not requested.
+    /// Regular files are always ready
+    fn fpoll(&mut self, _handle: u64, events: PollEvents) -> Result<PollEvents, VfsError> {
+        Ok(events & POLL_ALWAYS_READY)
     }
-}
 
-impl<'a> PathSplitter<'a> {
-    pub fn new(path: &'a [char]) -> Self {
-        let mut idx = 0;
-        while idx < path.len() && path[idx] == '/' {
-            idx += 1;
-        }
-        Self {
-            path,
-            idx,
-            last_part: None,
-        }
+    /// Returns the queue woken when the readiness of a file may have changed, `None` if it never changes
+    fn fwait_queue(&mut self, _handle: u64) -> Result<Option<Arc<WaitQueue>>, VfsError> {
+        Ok(None)
     }
 
-    pub fn is_done(&self) -> bool {
-        self.idx >= self.path.len()
+    /// Whether reads and writes failing with `WouldBlock` should sleep on `fwait_queue` and try
+    /// again, instead of returning the error
+    fn fblocks(&mut self, _handle: u64) -> bool {
+        false
     }
 
-    pub fn peek<'b>(&'b mut self) -> Option<PathSplitterPeek<'a, 'b>>
-    where
-        'a: 'b,
-    {
-        if self.is_done() {
-            None
-        } else {
-            let mut idx = self.idx;
-            while idx < self.path.len() && self.path[idx] != '/' {
-                idx += 1;
-            }
-            let slice = &self.path[self.idx..idx];
-            while idx < self.path.len() && self.path[idx] == '/' {
-                idx += 1;
-            }
+    /// Device specific request, `arg` holds the argument and receives the result
+    fn fioctl(&mut self, _handle: u64, _request: u64, _arg: &mut [u8]) -> Result<u64, VfsError> {
+        Err(VfsError::NotTerminal)
+    }
 
-            Some(PathSplitterPeek {
-                splitter: self,
-                slice,
-                idx,
-            })
-        }
+    /// Capacity and usage of the file system
+    fn statfs(&mut self) -> Result<FsStat, VfsError> {
+        Ok(FsStat::virtual_fs(0, 0))
     }
 
-    pub fn next_part(&mut self) -> &'a [char] {
-        match self.peek() {
-            None => &self.path[self.idx..],
-            Some(peek) => peek.apply(),
-        }
+    /// Called when the mount switches between read-only and read-write, and before `on_mount`
+    /// for a read-only mount. Fails with `VfsError::ReadOnly` if it can't be written to
+    fn set_read_only(&mut self, _read_only: bool) -> Result<(), VfsError> {
+        Ok(())
     }
+}
 
-    pub fn last_part(&self) -> Option<&[char]> {
-        self.last_part
+/// Splits a path into its parent directory and final component, ignoring trailing slashes.
+/// `None` when there is no final component, like for `/`
+pub fn split_parent(path: &[char]) -> Option<(&[char], &[char])> {
+    let end = path.iter().rposition(|c| *c != '/')? + 1;
+    let path = &path[..end];
+    match path.iter().rposition(|c| *c == '/') {
+        Some(i) => Some((&path[..i], &path[i + 1..])),
+        None => Some((&[], path)),
     }
 }
 
 pub struct PathTraverse<'a, 'b> {
-    spliter: PathSplitter<'a>,
+    /// Names left to walk
+    rest: Components<'a>,
     fs: Either<Arcrwb<dyn FileSystem>, &'b mut dyn FileSystem>,
     curr: VfsFile,
+    /// Path walked so far, to find the mounts covering directories of other file systems
+    walked: VfsPath,
+    covering: Vec<(VfsPath, WeakArcrwb<dyn FileSystem>)>,
 }
 
 impl<'a, 'b> PathTraverse<'a, 'b> {
     pub fn new(
-        path: &'a [char],
+        path: &'a VfsPath,
         fs: Arcrwb<dyn FileSystem>,
     ) -> Result<PathTraverse<'a, 'b>, VfsError> {
         Ok(PathTraverse {
-            spliter: PathSplitter::new(path),
+            rest: path.components(),
             curr: fs.write().get_root()?,
             fs: Either::new_left(fs.clone()),
+            walked: VfsPath::from("/"),
+            covering: Vec::new(),
         })
     }
 
     pub fn new_owned(
-        path: &'a [char],
+        path: &'a VfsPath,
         fs: &'b mut dyn FileSystem,
     ) -> Result<PathTraverse<'a, 'b>, VfsError> {
         Ok(PathTraverse {
-            spliter: PathSplitter::new(path),
+            rest: path.components(),
             curr: fs.get_root()?,
             fs: Either::new_right(fs),
+            walked: VfsPath::from("/"),
+            covering: Vec::new(),
         })
     }
 
+    /// Switches to the file system mounted at the walked path, if one covers a directory there
+    fn cover(&mut self, next: VfsFile) -> Result<VfsFile, VfsError> {
+        if next.is_mount_point() {
+            return Ok(next);
+        }
+        let Some((_, fs)) = self.covering.iter().find(|(path, _)| *path == self.walked) else {
+            return Ok(next);
+        };
+        let fs = fs.upgrade().ok_or(VfsError::UnknownError)?;
+        let os_id = fs.write().os_id();
+        Ok(VfsTreeFileData::file(
+            VfsFileKind::MountPoint { mounted_fs: fs },
+            self.walked.clone(),
+            next.fs,
+            os_id,
+        ))
+    }
+
     pub fn is_done(&self) -> bool {
-        self.spliter.is_done()
+        self.rest.is_done()
+    }
+
+    /// Full path of the last file found, the files themselves only know their own name
+    pub fn path(&self) -> &VfsPath {
+        &self.walked
     }
 
     pub fn find_next(&mut self) -> Result<VfsFile, VfsError> {
@@ -637,18 +1032,20 @@ impl<'a, 'b> PathTraverse<'a, 'b> {
             self.fs = Either::new_left(fs.clone());
         }
 
-        let Some(peek) = self.spliter.peek() else {
+        let mut rest = self.rest.clone();
+        let Some(part) = rest.next() else {
             return Err(VfsError::Done);
         };
-        let part = peek.slice;
 
-        let next = self.fs.referenced_mut().convert(
+        let next = self.fs.as_mut().either(
             |fs| fs.write().get_child(&self.curr, part),
             |fs| fs.get_child(&self.curr, part),
         )?;
 
-        peek.apply();
+        self.rest = rest;
+        self.walked.push(part);
 
+        let next = self.cover(next)?;
         self.curr = next.clone();
         Ok(next)
     }
@@ -658,12 +1055,12 @@ impl<'a, 'b> PathTraverse<'a, 'b> {
             return Err(VfsError::Done);
         }
 
-        let Some(peek) = self.spliter.peek() else {
+        let mut rest = self.rest.clone();
+        let Some(part) = rest.next() else {
             return Err(VfsError::Done);
         };
-        let part = peek.slice;
 
-        let next = self.fs.referenced_mut().convert(
+        let next = self.fs.as_mut().either(
             |fs| {
                 fs.write()
                     .create_child(&self.curr, part, VfsFileKind::Directory)
@@ -671,25 +1068,36 @@ impl<'a, 'b> PathTraverse<'a, 'b> {
             |fs| fs.create_child(&self.curr, part, VfsFileKind::Directory),
         )?;
 
-        peek.apply();
+        self.rest = rest;
+        self.walked.push(part);
 
         self.curr = next.clone();
         Ok(next)
     }
 
-    pub fn extract_splitter(self) -> PathSplitter<'a> {
-        self.spliter
-    }
-
-    pub fn get_splitter(&self) -> &PathSplitter<'a> {
-        &self.spliter
+    /// The part of the path that wasn't walked yet
+    pub fn rest(&self) -> &'a [u8] {
+        self.rest.rest()
     }
 }
 
 #[derive(Debug)]
 pub struct MountNode {
-    children: BTreeMap<Vec<char>, MountNode>,
+    children: BTreeMap<VfsPath, MountNode>,
     contents: Option<WeakArcrwb<dyn FileSystem>>,
+    /// Type of the mounted file system, kept here so listing mounts doesn't lock every file system
+    fs_type: String,
+    /// `MS_*` flags of the mount
+    flags: u64,
+}
+
+/// A mounted file system, see `Vfs::list_mounted_fs`
+#[derive(Debug, Clone)]
+pub struct MountInfo {
+    pub path: VfsPath,
+    pub fs_type: String,
+    pub flags: u64,
+    pub fs: WeakArcrwb<dyn FileSystem>,
 }
 
 #[derive(Debug)]
@@ -709,28 +1117,27 @@ impl MountingPointsManager {
             tree: MountNode {
                 children: BTreeMap::new(),
                 contents: None,
+                fs_type: String::new(),
+                flags: 0,
             },
         }
     }
 
     pub fn register_fs(
         &mut self,
-        name: &[char],
+        name: &VfsPath,
         fs: Arcrwb<dyn FileSystem>,
+        flags: u64,
     ) -> Result<(), VfsError> {
-        let mut splitter = PathSplitter::new(name);
-
         let mut node = &mut self.tree;
-        while !splitter.is_done() {
-            if node.contents.is_some() {
-                return Err(VfsError::AlreadyMounted);
-            }
-            let part = splitter.next_part();
-            match node.children.entry(part.to_vec()) {
+        for part in name.components() {
+            match node.children.entry(VfsPath::from(part)) {
                 Entry::Vacant(entry) => {
                     node = entry.insert(MountNode {
                         children: BTreeMap::new(),
                         contents: None,
+                        fs_type: String::new(),
+                        flags: 0,
                     });
                 }
                 Entry::Occupied(entry) => {
@@ -743,59 +1150,172 @@ impl MountingPointsManager {
             return Err(VfsError::AlreadyMounted);
         }
 
+        node.fs_type = fs.write().fs_type();
         node.contents = Some(Arc::downgrade(&fs));
+        node.flags = flags;
+        Ok(())
+    }
+
+    /// Every mounted file system, parents before their children
+    pub fn list_mounted_fs(&self) -> Vec<MountInfo> {
+        let mut mounts = Vec::new();
+        Self::list_mounted_fs_recursive(&self.tree, &VfsPath::from("/"), &mut mounts);
+        mounts
+    }
+
+    fn list_mounted_fs_recursive(node: &MountNode, path: &VfsPath, mounts: &mut Vec<MountInfo>) {
+        if let Some(fs) = node.contents.as_ref() {
+            mounts.push(MountInfo {
+                path: path.clone(),
+                fs_type: node.fs_type.clone(),
+                flags: node.flags,
+                fs: fs.clone(),
+            });
+        }
+        for (name, child) in node.children.iter() {
+            Self::list_mounted_fs_recursive(child, &path.join(name), mounts);
+        }
+    }
+
+    /// Flags of the deepest mount on the way to `name`, the one its files are reached through
+    pub fn mount_flags(&self, name: &VfsPath) -> u64 {
+        let mut node = &self.tree;
+        let mut flags = node.flags;
+        for part in name.components() {
+            match node.children.get(part.as_bytes()) {
+                Some(child) => node = child,
+                None => break,
+            }
+            if node.contents.is_some() {
+                flags = node.flags;
+            }
+        }
+        flags
+    }
+
+    /// Replaces the flags of the mount exactly at `name`
+    pub fn set_mount_flags(&mut self, name: &VfsPath, flags: u64) -> Result<(), VfsError> {
+        let mut node = &mut self.tree;
+        for part in name.components() {
+            node = node
+                .children
+                .get_mut(part.as_bytes())
+                .ok_or(VfsError::PathNotFound)?;
+        }
+        if node.contents.is_none() {
+            return Err(VfsError::PathNotFound);
+        }
+        node.flags = flags;
         Ok(())
     }
 
+    /// Returns the path and type of every mounted file system, parents before their children
+    pub fn list_mounts(&self) -> Vec<(VfsPath, String)> {
+        let mut mounts = Vec::new();
+        Self::list_mounts_recursive(&self.tree, &VfsPath::from("/"), &mut mounts);
+        mounts
+    }
+
+    fn list_mounts_recursive(
+        node: &MountNode,
+        path: &VfsPath,
+        mounts: &mut Vec<(VfsPath, String)>,
+    ) {
+        if node.contents.is_some() {
+            mounts.push((path.clone(), node.fs_type.clone()));
+        }
+        for (name, child) in node.children.iter() {
+            Self::list_mounts_recursive(child, &path.join(name), mounts);
+        }
+    }
+
+    /// Mounts on a directory of another mounted file system, which the parent file system can't
+    /// know about
+    pub fn covering_mounts(&self) -> Vec<(VfsPath, WeakArcrwb<dyn FileSystem>)> {
+        let mut mounts = Vec::new();
+        Self::covering_mounts_recursive(&self.tree, &VfsPath::from("/"), false, &mut mounts);
+        mounts
+    }
+
+    fn covering_mounts_recursive(
+        node: &MountNode,
+        path: &VfsPath,
+        covered: bool,
+        mounts: &mut Vec<(VfsPath, WeakArcrwb<dyn FileSystem>)>,
+    ) {
+        if let Some(fs) = node.contents.as_ref().filter(|_| covered) {
+            mounts.push((path.clone(), fs.clone()));
+        }
+        for (name, child) in node.children.iter() {
+            Self::covering_mounts_recursive(
+                child,
+                &path.join(name),
+                covered || node.contents.is_some(),
+                mounts,
+            );
+        }
+    }
+
+    /// The deepest file system mounted on the way to `name`, with the names left below it
     pub fn search_fs<'a>(
         &self,
-        name: &'a [char],
-    ) -> Option<(WeakArcrwb<dyn FileSystem>, PathSplitter<'a>)> {
-        let mut splitter = PathSplitter::new(name);
+        name: &'a VfsPath,
+    ) -> Option<(WeakArcrwb<dyn FileSystem>, Components<'a>)> {
+        let mut components = name.components();
 
         let mut node = &self.tree;
-        while !splitter.is_done() {
-            let part = splitter.next_part();
-            match node.children.get(part) {
+        let mut rest = components.clone();
+        while let Some(part) = components.next() {
+            match node.children.get(part.as_bytes()) {
                 Some(child) => {
                     node = child;
+                    rest = components.clone();
                 }
-                None => {
-                    return node.contents.as_ref().map(|fs| (fs.clone(), splitter));
-                }
+                None => break,
             }
         }
 
-        node.contents.as_ref().map(|fs| (fs.clone(), splitter))
+        node.contents.as_ref().map(|fs| (fs.clone(), rest))
     }
 
-    pub fn remove_fs(&mut self, name: &[char]) -> Result<WeakArcrwb<dyn FileSystem>, VfsError> {
-        Self::remove_fs_recursive(&mut self.tree, PathSplitter::new(name))
+    /// The file system mounted exactly at `name`
+    pub fn get_fs(&self, name: &VfsPath) -> Option<WeakArcrwb<dyn FileSystem>> {
+        self.search_fs(name)
+            .filter(|(_, rest)| rest.is_done())
+            .map(|(fs, _)| fs)
+    }
+
+    /// Fails with `VfsError::Busy` while another file system is mounted below `name`
+    pub fn remove_fs(&mut self, name: &VfsPath) -> Result<WeakArcrwb<dyn FileSystem>, VfsError> {
+        Self::remove_fs_recursive(&mut self.tree, name.components())
     }
 
     fn remove_fs_recursive(
         node: &mut MountNode,
-        mut splitter: PathSplitter,
+        mut components: Components,
     ) -> Result<WeakArcrwb<dyn FileSystem>, VfsError> {
-        if splitter.is_done() {
-            match node.contents.take() {
-                Some(fs) => Ok(fs),
-                None => Err(VfsError::PathNotFound),
+        match components.next() {
+            None => {
+                if node.contents.is_some() && !node.children.is_empty() {
+                    return Err(VfsError::Busy);
+                }
+                match node.contents.take() {
+                    Some(fs) => Ok(fs),
+                    None => Err(VfsError::PathNotFound),
+                }
             }
-        } else {
-            let part = splitter.next_part();
-            match node.children.get_mut(part) {
+            Some(part) => match node.children.get_mut(part.as_bytes()) {
                 Some(child) => {
-                    let fs = Self::remove_fs_recursive(child, splitter)?;
+                    let fs = Self::remove_fs_recursive(child, components)?;
 
                     if child.children.is_empty() && child.contents.is_none() {
-                        node.children.remove(part);
+                        node.children.remove(part.as_bytes());
                     }
 
                     Ok(fs)
                 }
                 None => Err(VfsError::PathNotFound),
-            }
+            },
         }
     }
 }
@@ -805,6 +1325,8 @@ pub struct Vfs {
     fs_by_id: Arcrwb<BTreeMap<u64, Arcrwb<dyn FileSystem>>>,
 
     mounting_points_manager: MountingPointsManager,
+    /// os_id of every bind mount, to the os_id of its source file system
+    binds: BTreeMap<u64, u64>,
 
     root_fs: Option<WeakArcrwb<Vfs>>,
     os_id_count: u64,
@@ -820,69 +1342,282 @@ impl Vfs {
         self.fs_by_id.read().get(&id).cloned()
     }
 
+    pub fn mounted_file_systems(&self) -> Vec<Arcrwb<dyn FileSystem>> {
+        self.fs_by_id.read().values().cloned().collect()
+    }
+
+    pub fn list_mounts(&self) -> Vec<(VfsPath, String)> {
+        self.mounting_points_manager.list_mounts()
+    }
+
+    pub fn list_mounted_fs(&self) -> Vec<MountInfo> {
+        self.mounting_points_manager.list_mounted_fs()
+    }
+
+    /// Fails with `VfsError::ReadOnly` when `path` is reached through a read-only mount
+    pub fn check_writable(&self, path: &[char]) -> Result<(), VfsError> {
+        if self
+            .mounting_points_manager
+            .mount_flags(&VfsPath::from(path))
+            & MS_RDONLY
+            != 0
+        {
+            Err(VfsError::ReadOnly)
+        } else {
+            Ok(())
+        }
+    }
+
     fn register_fs(
         &mut self,
         os_id: u64,
-        name: &[char],
+        name: &VfsPath,
         ptr: &Arcrwb<dyn FileSystem>,
+        flags: u64,
     ) -> Result<(), VfsError> {
         let mut wguard = self.fs_by_id.write();
         wguard.insert(os_id, ptr.clone());
 
-        self.mounting_points_manager.register_fs(name, ptr.clone())
+        self.mounting_points_manager
+            .register_fs(name, ptr.clone(), flags)
+    }
+
+    /// Finds the file at `path` like `get_file`, the last name is only followed if it is a
+    /// symlink with `follow_last`
+    pub fn lookup(&mut self, path: &VfsPath, follow_last: bool) -> Result<VfsFile, VfsError> {
+        let mut path = path.clone();
+        let mut hops = 0;
+        loop {
+            let (link, parent, rest) = {
+                let mut traverse = self.path_traverse(&path)?;
+                if traverse.is_done() {
+                    drop(traverse);
+                    return self.get_root();
+                }
+                loop {
+                    let file = traverse.find_next()?;
+                    let done = traverse.is_done();
+                    if file.is_symlink() && (follow_last || !done) {
+                        let parent = traverse.path().parent().unwrap_or_default();
+                        let rest = traverse.rest();
+                        let start = rest.iter().position(|b| *b != b'/').unwrap_or(rest.len());
+                        break (file, parent, VfsPath::from_bytes(rest[start..].to_vec()));
+                    }
+                    if done {
+                        return Ok(file);
+                    }
+                }
+            };
+            hops += 1;
+            if hops > SYMLINK_MAX_HOPS {
+                return Err(VfsError::SymlinkLoop);
+            }
+            let target = self.read_link(&link)?;
+            path = parent.join(&target).join(&rest).normalize();
+        }
     }
 
-    pub fn mount(&mut self, name: &[char], fs: Box<dyn FileSystem>) -> Result<VfsFile, VfsError> {
+    /// Traverses `path` from the root, entering the file systems mounted on the way
+    pub fn path_traverse<'a>(
+        &mut self,
+        path: &'a VfsPath,
+    ) -> Result<PathTraverse<'a, '_>, VfsError> {
+        let covering = self.mounting_points_manager.covering_mounts();
+        let mut traverse = PathTraverse::new_owned(path, self)?;
+        traverse.covering = covering;
+        Ok(traverse)
+    }
+
+    /// Mounting inside another mounted file system needs an existing directory there. `flags`
+    /// are the `MS_*` flags of the mount
+    pub fn mount(
+        &mut self,
+        name: &[char],
+        fs: Box<dyn FileSystem>,
+        flags: u64,
+    ) -> Result<VfsFile, VfsError> {
         let root_fs = self.root_fs.clone().ok_or(VfsError::FileSystemNotMounted)?;
-        let name = name.to_vec();
+        let path = VfsPath::from(name);
+        if self
+            .mounting_points_manager
+            .search_fs(&path)
+            .is_some_and(|(_, rest)| !rest.is_done())
+            && !self.get_file(&path)?.is_directory()
+        {
+            return Err(VfsError::NotDirectory);
+        }
 
         let os_id = self.next_os_id();
-        let ptr = arcrwb_new_from_box(fs);
+        let ptr = arcrwb_with_class(fs, LockClass::FileSystem);
+        if flags & MS_RDONLY != 0 {
+            ptr.write().set_read_only(true)?;
+        }
 
-        self.register_fs(os_id, &name, &ptr)?;
+        self.register_fs(os_id, &path, &ptr, flags)?;
 
-        let mount_point = VfsFile {
-            kind: VfsFileKind::MountPoint {
+        let mount_point = VfsTreeFileData::file(
+            VfsFileKind::MountPoint {
                 mounted_fs: ptr.clone(),
             },
-            name,
-            size: 0,
-            parent_fs: self.os_id(),
-            fs: os_id,
-            fs_specific: Arc::new(VfsSpecificFileData),
-        };
+            path.normalize(),
+            self.os_id(),
+            os_id,
+        );
 
         (&mut **ptr.write() as &mut dyn FileSystem).on_mount(&mount_point, os_id, root_fs)?;
 
         Ok(mount_point)
     }
 
-    pub fn unmount(&mut self, name: &[char]) -> Result<(), VfsError> {
-        let fs = self.mounting_points_manager.remove_fs(name)?;
-        let Some(fs) = fs.upgrade() else {
-            return Err(VfsError::UnknownError);
-        };
+    /// Mounts the directory at `source` again at `target`. Only `source` is visible there, not
+    /// the file systems mounted below it. A read-only bind mount leaves `source` writable
+    pub fn bind_mount(
+        &mut self,
+        source: &[char],
+        target: &[char],
+        flags: u64,
+    ) -> Result<VfsFile, VfsError> {
+        let source = VfsPath::from(source);
+        let (fs, rest) = self
+            .mounting_points_manager
+            .search_fs(&source)
+            .ok_or(VfsError::ActionNotAllowed)?;
+        let fs = fs.upgrade().ok_or(VfsError::UnknownError)?;
+        let prefix = VfsPath::from_bytes(rest.rest().to_vec());
+
         let mut guard = fs.write();
+        let source_id = guard.os_id();
+        if !guard.get_file(&prefix)?.is_directory() {
+            return Err(VfsError::NotDirectory);
+        }
+        drop(guard);
 
-        let id = guard.os_id();
+        let mount_point = self.mount(target, Box::new(BindFs::new(fs, prefix)), flags)?;
+        self.binds.insert(mount_point.fs, source_id);
+        Ok(mount_point)
+    }
 
-        #[allow(clippy::never_loop)]
-        while !guard.on_pre_unmount()? {
-            // TODO: Let driver do their thing
-            break;
+    /// Changes the flags of the mount at `name`. Going read-only fails with `VfsError::Busy`
+    /// while files are open for writing on it, and the file system writes everything back
+    pub fn remount(&mut self, name: &[char], flags: u64) -> Result<(), VfsError> {
+        let path = VfsPath::from(name);
+        let fs = self
+            .mounting_points_manager
+            .get_fs(&path)
+            .ok_or(VfsError::PathNotFound)?
+            .upgrade()
+            .ok_or(VfsError::UnknownError)?;
+        let read_only = flags & MS_RDONLY != 0;
+        if read_only != (self.mounting_points_manager.mount_flags(&path) & MS_RDONLY != 0) {
+            let mut guard = fs.write();
+            if read_only && writable_path_handles(guard.os_id()) != 0 {
+                return Err(VfsError::Busy);
+            }
+            guard.set_read_only(read_only)?;
         }
-        guard.on_unmount()?;
+        self.mounting_points_manager.set_mount_flags(&path, flags)
+    }
 
-        {
-            let mut wguard = self.fs_by_id.write();
-            wguard.remove(&id);
+    /// Fails with `VfsError::Busy` while files opened through `open_path` are still open on it,
+    /// or while it is needed by another mount
+    pub fn unmount(&mut self, name: &[char]) -> Result<(), VfsError> {
+        let fs = self
+            .mounting_points_manager
+            .get_fs(&VfsPath::from(name))
+            .ok_or(VfsError::PathNotFound)?
+            .upgrade()
+            .ok_or(VfsError::UnknownError)?;
+        let id = fs.write().os_id();
+        if open_path_handles(id) != 0 {
+            return Err(VfsError::Busy);
         }
+        self.unmount_force(name)
+    }
+
+    /// Removes the mount point of a file system that no other mount needs
+    fn detach(&mut self, name: &[char]) -> Result<(Arcrwb<dyn FileSystem>, u64), VfsError> {
+        let name = VfsPath::from(name);
+        let fs = self
+            .mounting_points_manager
+            .get_fs(&name)
+            .ok_or(VfsError::PathNotFound)?
+            .upgrade()
+            .ok_or(VfsError::UnknownError)?;
+        let id = fs.write().os_id();
+        if self.binds.values().any(|source| *source == id) {
+            return Err(VfsError::Busy);
+        }
+        self.mounting_points_manager.remove_fs(&name)?;
+        self.binds.remove(&id);
+        Ok((fs, id))
+    }
+
+    /// Unmounts even if files are still open, the file system closes their handles
+    pub fn unmount_force(&mut self, name: &[char]) -> Result<(), VfsError> {
+        let (fs, _) = self.detach(name)?;
+        finish_unmount(&fs, &self.fs_by_id)
+    }
 
+    /// Detaches the mount point right away, the file system is unmounted once its last file
+    /// opened through `open_path` is closed
+    pub fn unmount_lazy(&mut self, name: &[char]) -> Result<(), VfsError> {
+        let (fs, id) = self.detach(name)?;
+
+        // Locked before the count is read so a concurrent last close finds the entry
+        let mut deferred = DEFERRED_UNMOUNTS.lock();
+        if open_path_handles(id) == 0 {
+            drop(deferred);
+            return finish_unmount(&fs, &self.fs_by_id);
+        }
+        deferred.push(DeferredUnmount {
+            id,
+            fs,
+            fs_by_id: self.fs_by_id.clone(),
+        });
         Ok(())
     }
 
+    /// Flushes every mounted file system, returns the last error after trying all of them
+    pub fn sync_all(&self) -> Result<(), VfsError> {
+        let filesystems = self.fs_by_id.read().values().cloned().collect::<Vec<_>>();
+        let mut result = Ok(());
+        for fs in filesystems {
+            match fs.write().fs_flush() {
+                Ok(()) | Err(VfsError::ActionNotAllowed) => {}
+                Err(e) => result = Err(e),
+            }
+        }
+        result
+    }
+
+    /// Unmounts every file system, children before their parents, and returns the ones that failed.
+    /// Open files don't prevent it
+    pub fn unmount_all(&mut self) -> Vec<(Vec<char>, VfsError)> {
+        let mut pending = self
+            .list_mounts()
+            .into_iter()
+            .rev()
+            .map(|(path, _)| path.to_chars())
+            .collect::<Vec<_>>();
+        loop {
+            let mut failed = Vec::new();
+            for path in pending.iter() {
+                if let Err(e) = self.unmount_force(path) {
+                    failed.push((path.clone(), e));
+                }
+            }
+            // A bind mount may come after its source, retry while some make progress
+            if failed.len() == pending.len()
+                || !failed.iter().any(|(_, e)| matches!(e, VfsError::Busy))
+            {
+                return failed;
+            }
+            pending = failed.into_iter().map(|(path, _)| path).collect();
+        }
+    }
+
     pub fn get_stats(&mut self, path: &[char]) -> Result<Option<FileStat>, VfsError> {
-        match self.get_file(path) {
+        match self.get_file(&VfsPath::from(path)) {
             Ok(file) => match file.get_mounted_fs() {
                 Some(fs) => {
                     let mut guard = fs.write();
@@ -902,6 +1637,373 @@ impl Vfs {
             Err(e) => Err(e),
         }
     }
+
+    /// Capacity of the file system `path` is on
+    pub fn statfs_path(&mut self, path: &[char]) -> Result<FsStat, VfsError> {
+        let file = self.get_file(&VfsPath::from(path))?;
+        let fs = match file.get_mounted_fs() {
+            Some(fs) => fs,
+            None => self
+                .get_fs_by_id(file.fs)
+                .ok_or(VfsError::FileSystemNotMounted)?,
+        };
+        let mut guard = fs.write();
+        guard.statfs()
+    }
+
+    pub fn stat_path(&mut self, path: &[char]) -> Result<FileStat, VfsError> {
+        self.get_stats(path)?.ok_or(VfsError::PathNotFound)
+    }
+
+    /// Runs `allowed` on the stats of `path`, the kernel skips the check
+    pub fn check_access(
+        &mut self,
+        path: &[char],
+        credentials: Credentials,
+        allowed: impl FnOnce(&FileStat) -> bool,
+    ) -> Result<(), VfsError> {
+        if matches!(credentials, Credentials::Kernel) {
+            return Ok(());
+        }
+        let stat = self.stat_path(path)?;
+        if allowed(&stat) {
+            Ok(())
+        } else {
+            Err(VfsError::PermissionDenied)
+        }
+    }
+
+    /// Opens `path` with the `OPEN_MODE_*` flags of `mode`, crossing mount points. With
+    /// `OPEN_MODE_CREATE` a missing file is created owned by `credentials` with `create_perms`.
+    /// The Vfs lock is released before `fopen`, which may need it, like procfs does
+    pub fn open_path(
+        vfs: &Arcrwb<Vfs>,
+        path: &[char],
+        mode: u64,
+        create_perms: Permissions,
+        credentials: Credentials,
+    ) -> Result<VfsOpenFile, VfsError> {
+        let mut guard = vfs.write();
+        let file = match guard.get_file(&VfsPath::from(path)) {
+            Ok(_) if mode & OPEN_MODE_FAIL_IF_EXISTS != 0 => {
+                return Err(VfsError::FileAlreadyExists)
+            }
+            Ok(file) => file.resolve_mount()?,
+            Err(VfsError::PathNotFound) if mode & OPEN_MODE_CREATE != 0 => {
+                drop(guard);
+                return Self::create_path(vfs, path, mode, create_perms, credentials);
+            }
+            Err(e) => return Err(e),
+        };
+        guard.check_access(path, credentials, |stat| credentials.can_open(stat, mode))?;
+        if mode & (OPEN_MODE_WRITE | OPEN_MODE_APPEND) != 0 && !file.is_device_node() {
+            guard.check_writable(path)?;
+        }
+        let fs = guard
+            .get_fs_by_id(file.fs())
+            .ok_or(VfsError::FileSystemNotMounted)?;
+        drop(guard);
+
+        let handle = fs.write().fopen(&file, mode)?;
+        acquire_path_handle(file.fs(), handle, mode);
+        Ok(VfsOpenFile { fs, file, handle })
+    }
+
+    fn create_path(
+        vfs: &Arcrwb<Vfs>,
+        path: &[char],
+        mode: u64,
+        perms: Permissions,
+        credentials: Credentials,
+    ) -> Result<VfsOpenFile, VfsError> {
+        let (dirname, filename) = split_parent(path).ok_or(VfsError::InvalidArgument)?;
+        let filename = VfsPath::from(filename);
+        let filename = filename.file_name().ok_or(VfsError::InvalidArgument)?;
+
+        let mut guard = vfs.write();
+        let directory = guard.get_file(&VfsPath::from(dirname))?.resolve_mount()?;
+        guard.check_access(dirname, credentials, |stat| credentials.can_create_in(stat))?;
+        guard.check_writable(path)?;
+        let fs = guard
+            .get_fs_by_id(directory.fs())
+            .ok_or(VfsError::FileSystemNotMounted)?;
+        drop(guard);
+
+        let mut guard = fs.write();
+        match guard.get_child(&directory, filename) {
+            Ok(_) => return Err(VfsError::FileAlreadyExists),
+            Err(VfsError::PathNotFound) => {}
+            Err(e) => return Err(e),
+        }
+        let file = guard.create_child(&directory, filename, VfsFileKind::File)?;
+        let handle = guard.fopen(&file, mode & !(OPEN_MODE_CREATE | OPEN_MODE_FAIL_IF_EXISTS))?;
+
+        // File systems without owners or permission bits keep their defaults
+        let attributes = match credentials {
+            Credentials::Kernel => guard.fchmod(handle, perms.to_u64()),
+            Credentials::User { uid, gid, .. } => guard
+                .fchown(handle, Some(uid), Some(gid))
+                .and_then(|_| guard.fchmod(handle, perms.to_u64())),
+        };
+        match attributes {
+            Ok(()) | Err(VfsError::ActionNotAllowed) => {}
+            Err(e) => {
+                let _ = guard.fclose(handle);
+                return Err(e);
+            }
+        }
+        drop(guard);
+
+        acquire_path_handle(file.fs(), handle, mode);
+        Ok(VfsOpenFile { fs, file, handle })
+    }
+
+    /// Walks the tree at `path` depth first, starting with `path` itself. Directories are only
+    /// listed once even if hard links or mounts lead to them again
+    pub fn walk(vfs: &Arcrwb<Vfs>, path: &[char]) -> Result<VfsWalk, VfsError> {
+        let mut guard = vfs.write();
+        let file = guard.get_file(&VfsPath::from(path))?;
+        let root_fs = file.clone().resolve_mount()?.fs();
+        drop(guard);
+
+        let end = path
+            .iter()
+            .rposition(|c| *c != '/')
+            .map_or(0, |end| end + 1);
+        Ok(VfsWalk {
+            vfs: vfs.clone(),
+            pending: alloc::vec![(path[..end].to_vec(), file, 0)],
+            visited: BTreeMap::new(),
+            error: None,
+            max_depth: WALK_DEFAULT_MAX_DEPTH,
+            cross_mounts: true,
+            root_fs,
+        })
+    }
+
+    /// Deletes the file or empty directory at `path`, `credentials` need to be allowed to
+    /// delete it from its parent directory. A symlink is deleted, not its target
+    pub fn unlink_path(
+        vfs: &Arcrwb<Vfs>,
+        path: &[char],
+        credentials: Credentials,
+    ) -> Result<(), VfsError> {
+        let mut guard = vfs.write();
+        let file = guard.lookup(&VfsPath::from(path), false)?;
+        guard.check_writable(path)?;
+        if !matches!(credentials, Credentials::Kernel) {
+            let stat = FileSystem::get_stats(&mut **guard, &file)?;
+            let parent = split_parent(path).map_or(&[][..], |(parent, _)| parent);
+            guard.check_access(parent, credentials, |directory| {
+                credentials.can_delete(directory, &stat)
+            })?;
+        }
+        let fs = guard
+            .get_fs_by_id(file.fs())
+            .ok_or(VfsError::FileSystemNotMounted)?;
+        drop(guard);
+
+        let result = fs.write().delete_file(&file);
+        result
+    }
+}
+
+/// Most symlinks followed resolving one path, like Linux
+pub const SYMLINK_MAX_HOPS: usize = 40;
+
+/// Deepest directory level `Vfs::walk` descends into by default
+pub const WALK_DEFAULT_MAX_DEPTH: usize = 64;
+
+/// Depth first walk of a directory tree, see `Vfs::walk`. The children of a directory are only
+/// listed when it is reached, and no lock is held between calls to `next`
+pub struct VfsWalk {
+    vfs: Arcrwb<Vfs>,
+    /// Entries left to yield with their depth, the next one last
+    pending: Vec<(Vec<char>, VfsFile, usize)>,
+    /// Directories already listed, keyed by (fs, `FileSystem::file_identity`). The files are kept
+    /// alive so identities derived from their address aren't reused
+    visited: BTreeMap<(u64, u64), VfsFile>,
+    /// Error listing the last directory, returned after the directory itself
+    error: Option<VfsError>,
+    max_depth: usize,
+    cross_mounts: bool,
+    root_fs: u64,
+}
+
+impl VfsWalk {
+    /// Entries deeper than `depth` levels below the start aren't yielded
+    pub fn max_depth(mut self, depth: usize) -> Self {
+        self.max_depth = depth;
+        self
+    }
+
+    /// Whether to descend into file systems mounted below the start, true by default.
+    /// Mount points are yielded either way
+    pub fn cross_mounts(mut self, cross: bool) -> Self {
+        self.cross_mounts = cross;
+        self
+    }
+
+    /// Runs `f` on the file system of `file`, the Vfs lock is released first unless `file`
+    /// belongs to the Vfs itself
+    fn with_fs<R>(
+        &self,
+        file: &VfsFile,
+        f: impl FnOnce(&mut dyn FileSystem) -> R,
+    ) -> Result<R, VfsError> {
+        let mut vfs = self.vfs.write();
+        if file.fs() == vfs.os_id() {
+            return Ok(f(&mut **vfs));
+        }
+        let fs = vfs
+            .get_fs_by_id(file.fs())
+            .ok_or(VfsError::FileSystemNotMounted)?;
+        drop(vfs);
+        let mut guard = fs.write();
+        Ok(f(&mut **guard))
+    }
+
+    fn push_children(
+        &mut self,
+        path: &[char],
+        file: &VfsFile,
+        depth: usize,
+    ) -> Result<(), VfsError> {
+        if !self.cross_mounts && depth > 0 && file.is_mount_point() {
+            return Ok(());
+        }
+        let directory = file.clone().resolve_mount()?;
+        if !self.cross_mounts && directory.fs() != self.root_fs {
+            return Ok(());
+        }
+
+        let identity = self.with_fs(&directory, |fs| fs.file_identity(&directory))?;
+        let key = (directory.fs(), identity);
+        if self.visited.contains_key(&key) {
+            return Ok(());
+        }
+        let children = self.with_fs(&directory, |fs| fs.list_children(&directory))??;
+        self.visited.insert(key, directory);
+        let covering = self.vfs.read().mounting_points_manager.covering_mounts();
+
+        for child in children.into_iter().rev() {
+            let child_path = [path, &['/'], child.name()].concat();
+            let mount_path = VfsPath::from(&child_path);
+            let child = if covering.iter().any(|(mount, _)| *mount == mount_path) {
+                self.vfs.write().get_file(&mount_path)?
+            } else {
+                child
+            };
+            self.pending.push((child_path, child, depth + 1));
+        }
+        Ok(())
+    }
+}
+
+impl Iterator for VfsWalk {
+    type Item = Result<(Vec<char>, VfsFile), VfsError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(e) = self.error.take() {
+            return Some(Err(e));
+        }
+        let (path, file, depth) = self.pending.pop()?;
+        if depth < self.max_depth && (file.is_directory() || file.is_mount_point()) {
+            self.error = self.push_children(&path, &file, depth).err();
+        }
+        Some(Ok((path, file)))
+    }
+}
+
+/// A file opened by `Vfs::open_path`, `handle` belongs to `fs` and must be closed with
+/// `close_path_handle`
+#[derive(Debug)]
+pub struct VfsOpenFile {
+    pub fs: Arcrwb<dyn FileSystem>,
+    pub file: VfsFile,
+    pub handle: u64,
+}
+
+/// Handles opened through `Vfs::open_path` and not closed yet, by file system
+static OPEN_PATH_HANDLES: Mutex<BTreeMap<u64, usize>> = Mutex::new(BTreeMap::new());
+
+/// (fs, handle) of the files opened through `open_path` for writing
+static WRITABLE_PATH_HANDLES: Mutex<BTreeSet<(u64, u64)>> = Mutex::new(BTreeSet::new());
+
+/// File systems detached by `Vfs::unmount_lazy`, waiting for their last handle to be closed
+static DEFERRED_UNMOUNTS: Mutex<Vec<DeferredUnmount>> = Mutex::new(Vec::new());
+
+struct DeferredUnmount {
+    id: u64,
+    fs: Arcrwb<dyn FileSystem>,
+    fs_by_id: Arcrwb<BTreeMap<u64, Arcrwb<dyn FileSystem>>>,
+}
+
+pub fn open_path_handles(fs: u64) -> usize {
+    OPEN_PATH_HANDLES.lock().get(&fs).copied().unwrap_or(0)
+}
+
+pub fn writable_path_handles(fs: u64) -> usize {
+    WRITABLE_PATH_HANDLES
+        .lock()
+        .range((fs, 0)..=(fs, u64::MAX))
+        .count()
+}
+
+fn acquire_path_handle(fs: u64, handle: u64, mode: u64) {
+    *OPEN_PATH_HANDLES.lock().entry(fs).or_insert(0) += 1;
+    if mode & (OPEN_MODE_WRITE | OPEN_MODE_APPEND) != 0 {
+        WRITABLE_PATH_HANDLES.lock().insert((fs, handle));
+    }
+}
+
+fn release_path_handle(fs: u64) {
+    let mut handles = OPEN_PATH_HANDLES.lock();
+    let Some(count) = handles.get_mut(&fs) else {
+        log_warn!(target: "vfs", "Closed more path handles than opened on fs {}", fs);
+        return;
+    };
+    *count -= 1;
+    if *count != 0 {
+        return;
+    }
+    handles.remove(&fs);
+    drop(handles);
+
+    let mut deferred = DEFERRED_UNMOUNTS.lock();
+    let Some(index) = deferred.iter().position(|unmount| unmount.id == fs) else {
+        return;
+    };
+    let unmount = deferred.swap_remove(index);
+    drop(deferred);
+    if let Err(e) = finish_unmount(&unmount.fs, &unmount.fs_by_id) {
+        log_error!(target: "vfs", "Deferred unmount of fs {} failed: {:?}", fs, e);
+    }
+}
+
+/// Closes a handle of a `VfsOpenFile`, which may complete a lazy unmount of its file system
+pub fn close_path_handle(fs: &Arcrwb<dyn FileSystem>, handle: u64) -> Result<(), VfsError> {
+    let mut guard = fs.write();
+    let id = guard.os_id();
+    let result = guard.fclose(handle);
+    drop(guard);
+    WRITABLE_PATH_HANDLES.lock().remove(&(id, handle));
+    release_path_handle(id);
+    result
+}
+
+fn finish_unmount(
+    fs: &Arcrwb<dyn FileSystem>,
+    fs_by_id: &Arcrwb<BTreeMap<u64, Arcrwb<dyn FileSystem>>>,
+) -> Result<(), VfsError> {
+    let mut guard = fs.write();
+    let id = guard.os_id();
+    guard.on_pre_unmount()?;
+    guard.on_unmount()?;
+    drop(guard);
+    FILE_LOCKS.lock().release_fs(id);
+    fs_by_id.write().remove(&id);
+    Ok(())
 }
 
 #[derive(Debug, Default)]
@@ -909,6 +2011,38 @@ pub struct VfsSpecificFileData;
 
 impl FsSpecificFileData for VfsSpecificFileData {}
 
+/// Directories and mount points of the vfs itself, `path` is where they are in the mount tree
+#[derive(Debug)]
+pub struct VfsTreeFileData {
+    path: VfsPath,
+}
+
+impl FsSpecificFileData for VfsTreeFileData {}
+
+impl VfsTreeFileData {
+    fn file(kind: VfsFileKind, path: VfsPath, parent_fs: u64, fs: u64) -> VfsFile {
+        VfsFile {
+            kind,
+            name: path
+                .file_name()
+                .map_or_else(|| alloc::vec!['/'], |name| name.to_chars()),
+            size: 0,
+            parent_fs,
+            fs,
+            fs_specific: Arc::new(VfsTreeFileData { path }),
+        }
+    }
+
+    fn path_of(file: &VfsFile) -> Result<VfsPath, VfsError> {
+        let data = file.get_fs_specific_data();
+        (*data)
+            .as_any()
+            .downcast_ref::<VfsTreeFileData>()
+            .map(|data| data.path.clone())
+            .ok_or(VfsError::FileSystemMismatch)
+    }
+}
+
 impl<T: Any> AsAny for T {
     fn as_any(&self) -> &dyn Any {
         self
@@ -925,7 +2059,7 @@ impl<T: Any> AsAny for T {
 
 macro_rules! default_get_file_implementation {
     () => {
-        fn get_file(&mut self, path: &[char]) -> Result<VfsFile, VfsError> {
+        fn get_file(&mut self, path: &$crate::data::path::VfsPath) -> Result<VfsFile, VfsError> {
             let mut traverse = $crate::drivers::vfs::PathTraverse::new_owned(path, self)?;
             if traverse.is_done() {
                 return self.get_root();
@@ -959,69 +2093,66 @@ impl FileSystem for Vfs {
     }
 
     fn get_root(&mut self) -> Result<VfsFile, VfsError> {
-        Ok(VfsFile {
-            kind: VfsFileKind::Directory,
-            name: "/".chars().collect(),
-            size: 0,
-            parent_fs: self.os_id(),
-            fs: self.os_id(),
-            fs_specific: Arc::new(VfsSpecificFileData),
-        })
+        Ok(VfsTreeFileData::file(
+            VfsFileKind::Directory,
+            VfsPath::from("/"),
+            self.os_id(),
+            self.os_id(),
+        ))
     }
 
     fn get_mount_point(&mut self) -> Result<Option<VfsFile>, VfsError> {
         Ok(None)
     }
 
-    fn get_child(&mut self, file: &VfsFile, child: &[char]) -> Result<VfsFile, VfsError> {
-        if file.fs != self.os_id() {
-            return Err(VfsError::FileSystemMismatch);
-        }
-        if file.fs != self.os_id() {
-            return Err(VfsError::FileSystemMismatch);
-        }
+    fn get_child(&mut self, file: &VfsFile, child: &VfsName) -> Result<VfsFile, VfsError> {
+        // The mount point is a file of the vfs, the mounted fs only knows its own root
         if file.is_mount_point() {
-            return file
+            let fs = file
                 .get_mounted_fs()
-                .ok_or(VfsError::FileSystemNotMounted)?
-                .write()
-                .get_child(file, child);
+                .ok_or(VfsError::FileSystemNotMounted)?;
+            let mut guard = fs.write();
+
+            let root = guard.get_root()?;
+            return guard.get_child(&root, child);
+        }
+        if file.fs != self.os_id() {
+            let fs = self
+                .get_fs_by_id(file.fs)
+                .ok_or(VfsError::FileSystemNotMounted)?;
+            return fs.write().get_child(file, child);
         }
 
+        let os_id = self.os_id();
+        let mut path = VfsTreeFileData::path_of(file)?;
         let mut node = &self.mounting_points_manager.tree;
-        let mut splitter = PathSplitter::new(file.name());
-        while !splitter.is_done() {
-            let part = splitter.next_part();
-            match node.children.get(part) {
+        for part in path.components() {
+            match node.children.get(part.as_bytes()) {
                 None => return Err(VfsError::PathNotFound),
                 Some(child) => node = child,
             }
         }
 
-        match node.children.get(child) {
+        let node = node.children.get(child.as_bytes());
+        path.push(child);
+        match node {
             None => Err(VfsError::PathNotFound),
             Some(c) => match &c.contents {
-                None => Ok(VfsFile {
-                    kind: VfsFileKind::Directory,
-                    name: [file.name(), &['/'], child].concat(),
-                    size: 0,
-                    parent_fs: self.os_id(),
-                    fs: self.os_id(),
-                    fs_specific: Arc::new(VfsSpecificFileData),
-                }),
+                None => Ok(VfsTreeFileData::file(
+                    VfsFileKind::Directory,
+                    path,
+                    os_id,
+                    os_id,
+                )),
                 Some(fs) => {
                     let fs = fs.upgrade().ok_or(VfsError::UnknownError)?;
-                    let mut guard = fs.write();
-                    Ok(VfsFile {
-                        kind: VfsFileKind::MountPoint {
-                            mounted_fs: fs.clone(),
-                        },
-                        name: [file.name(), &['/'], child].concat(),
-                        size: 0,
-                        parent_fs: self.os_id(),
-                        fs: guard.os_id(),
-                        fs_specific: Arc::new(VfsSpecificFileData),
-                    })
+                    let fs_id = fs.write().os_id();
+                    Ok(VfsTreeFileData::file(
+                        VfsFileKind::MountPoint { mounted_fs: fs },
+                        path,
+                        os_id,
+                        fs_id,
+                    ))
                 }
             },
         }
@@ -1048,11 +2179,10 @@ impl FileSystem for Vfs {
         }
         let os_id = self.os_id();
 
+        let path = VfsTreeFileData::path_of(file)?;
         let mut node = &self.mounting_points_manager.tree;
-        let mut splitter = PathSplitter::new(file.name());
-        while !splitter.is_done() {
-            let part = splitter.next_part();
-            match node.children.get(part) {
+        for part in path.components() {
+            match node.children.get(part.as_bytes()) {
                 None => return Err(VfsError::PathNotFound),
                 Some(child) => node = child,
             }
@@ -1061,43 +2191,65 @@ impl FileSystem for Vfs {
         Ok(node
             .children
             .iter()
-            .filter_map(|(k, node)| match &node.contents {
-                None => Some(VfsFile {
-                    kind: VfsFileKind::Directory,
-                    name: [file.name(), &['/'], k].concat(),
-                    size: 0,
-                    parent_fs: os_id,
-                    fs: os_id,
-                    fs_specific: Arc::new(VfsSpecificFileData),
-                }),
-                Some(fs) => {
-                    let fs = fs.upgrade()?;
-                    let os_id = fs.write().os_id();
-                    Some(VfsFile {
-                        kind: VfsFileKind::MountPoint {
-                            mounted_fs: fs.clone(),
-                        },
-                        name: k.to_vec(),
-                        size: 0,
-                        parent_fs: os_id,
-                        fs: os_id,
-                        fs_specific: Arc::new(VfsSpecificFileData),
-                    })
+            .filter_map(|(k, node)| {
+                let path = path.join(k);
+                match &node.contents {
+                    None => Some(VfsTreeFileData::file(
+                        VfsFileKind::Directory,
+                        path,
+                        os_id,
+                        os_id,
+                    )),
+                    Some(fs) => {
+                        let fs = fs.upgrade()?;
+                        let fs_id = fs.write().os_id();
+                        Some(VfsTreeFileData::file(
+                            VfsFileKind::MountPoint { mounted_fs: fs },
+                            path,
+                            os_id,
+                            fs_id,
+                        ))
+                    }
                 }
             })
             .collect::<Vec<_>>())
     }
 
-    default_get_file_implementation!();
+    fn get_file(&mut self, path: &VfsPath) -> Result<VfsFile, VfsError> {
+        self.lookup(path, true)
+    }
+
+    fn read_link(&mut self, file: &VfsFile) -> Result<VfsPath, VfsError> {
+        let fs = self
+            .get_fs_by_id(file.fs)
+            .ok_or(VfsError::FileSystemNotMounted)?;
+        let target = fs.write().read_link(file);
+        target
+    }
+
+    fn get_stats(&mut self, file: &VfsFile) -> Result<FileStat, VfsError> {
+        if file.is_mount_point() {
+            let fs = file
+                .get_mounted_fs()
+                .ok_or(VfsError::FileSystemNotMounted)?;
+            let mut guard = fs.write();
 
-    fn get_stats(&mut self, _file: &VfsFile) -> Result<FileStat, VfsError> {
+            let root = guard.get_root()?;
+            return guard.get_stats(&root);
+        }
+        if file.fs != self.os_id() {
+            let fs = self
+                .get_fs_by_id(file.fs)
+                .ok_or(VfsError::FileSystemNotMounted)?;
+            return fs.write().get_stats(file);
+        }
         Err(VfsError::ActionNotAllowed)
     }
 
     fn create_child(
         &mut self,
         directory: &VfsFile,
-        _name: &[char],
+        _name: &VfsName,
         _kind: VfsFileKind,
     ) -> Result<VfsFile, VfsError> {
         if directory.fs != self.os_id() {
@@ -1179,56 +2331,140 @@ impl FileSystem for Vfs {
     }
 }
 
+/// Marks a live handle, zeroed when it is freed
+const HANDLE_MAGIC: u64 = 0x4844_4c5f_4346_5356;
+
+/// Ids given to the types stored behind handles, in order of first use
+static HANDLE_TYPES: Mutex<BTreeMap<TypeId, u64>> = Mutex::new(BTreeMap::new());
+
+/// XORed with the heap address of handles, so their values don't leak kernel pointers
+static HANDLE_COOKIE: AtomicU64 = AtomicU64::new(0);
+
+fn handle_type_id<T: 'static>() -> u64 {
+    let mut types = HANDLE_TYPES.lock();
+    let next = types.len() as u64 + 1;
+    *types.entry(TypeId::of::<T>()).or_insert(next)
+}
+
+fn handle_cookie() -> u64 {
+    let cookie = HANDLE_COOKIE.load(Ordering::Relaxed);
+    if cookie != 0 {
+        return cookie;
+    }
+    // The low bits stay clear so the handles keep the alignment of addresses
+    let cookie = (random_u64() | 1 << 63) & !0xf;
+    match HANDLE_COOKIE.compare_exchange(0, cookie, Ordering::Relaxed, Ordering::Relaxed) {
+        Ok(_) => cookie,
+        Err(current) => current,
+    }
+}
+
 #[repr(C)]
 #[derive(Debug, Clone)]
-pub struct VfsHandleData<T: Sized + Clone + Debug> {
+struct VfsHandleHeader {
+    magic: u64,
+    type_id: u64,
+    /// os_id of the file system that allocated the handle
+    owner: u64,
     layout: Layout,
+}
+
+/// The header comes first so it can be read without knowing `T`
+#[repr(C)]
+#[derive(Debug, Clone)]
+pub struct VfsHandleData<T: Sized + Clone + Debug> {
+    header: VfsHandleHeader,
     data: T,
 }
 
 #[derive(Debug, Default)]
 pub struct FileHandleAllocator {
     handles: BTreeSet<u64>,
+    owner: u64,
 }
 
 impl FileHandleAllocator {
-    pub fn alloc_file_handle<T: Sized + Clone + Debug>(&mut self, data: T) -> u64 {
+    /// Sets the file system owning the handles, once its os_id is known
+    pub fn set_owner(&mut self, os_id: u64) {
+        self.owner = os_id;
+        for handle in self.handles.iter() {
+            unsafe { (*self.header(*handle)).owner = os_id };
+        }
+    }
+
+    fn header(&self, handle: u64) -> *mut VfsHandleHeader {
+        (handle ^ handle_cookie()) as *mut VfsHandleHeader
+    }
+
+    /// The handle is live in this allocator and holds a `T`
+    fn check_handle<T: 'static>(&self, handle: u64) -> bool {
+        if !self.handles.contains(&handle) {
+            return false;
+        }
+        let header = unsafe { &*self.header(handle) };
+        header.magic == HANDLE_MAGIC
+            && header.type_id == handle_type_id::<T>()
+            && header.owner == self.owner
+    }
+
+    pub fn alloc_file_handle<T: Sized + Clone + Debug + 'static>(&mut self, data: T) -> u64 {
         let handle = unsafe {
             let layout = Layout::from_size_align_unchecked(
                 size_of::<VfsHandleData<T>>(),
                 align_of::<VfsHandleData<T>>(),
             );
             let handle = alloc(layout) as *mut VfsHandleData<T>;
-            handle.write(VfsHandleData { data, layout });
-            handle as u64
+            handle.write(VfsHandleData {
+                header: VfsHandleHeader {
+                    magic: HANDLE_MAGIC,
+                    type_id: handle_type_id::<T>(),
+                    owner: self.owner,
+                    layout,
+                },
+                data,
+            });
+            handle as u64 ^ handle_cookie()
         };
         self.handles.insert(handle);
         handle
     }
 
+    /// Returns `None` if the handle isn't live in this allocator or holds another type
+    ///
     /// # Safety
-    /// Caller must ensure that the handle is valid and was allocated using `alloc_file_handle` with the same `T` type
-    pub unsafe fn get_handle_data<T: Sized + Clone + Debug>(&self, handle: u64) -> Option<*mut T> {
-        if !self.handles.contains(&handle) {
+    /// The returned pointer is only valid until the handle is deallocated
+    pub unsafe fn get_handle_data<T: Sized + Clone + Debug + 'static>(
+        &self,
+        handle: u64,
+    ) -> Option<*mut T> {
+        if !self.check_handle::<T>(handle) {
             return None;
         }
-        let handle_data = handle as *mut VfsHandleData<T>;
+        let handle_data = self.header(handle) as *mut VfsHandleData<T>;
         Some(&mut (*handle_data).data as *mut T)
     }
 
-    pub fn dealloc_file_handle<T: Sized + Clone + Debug>(&mut self, handle: u64) -> bool {
-        if self.handles.contains(&handle) {
-            unsafe {
-                dealloc(
-                    handle as *mut u8,
-                    (*(handle as *mut VfsHandleData<T>)).layout,
-                )
-            };
-            self.handles.remove(&handle);
-            true
-        } else {
-            false
+    pub fn dealloc_file_handle<T: Sized + Clone + Debug + 'static>(&mut self, handle: u64) -> bool {
+        if !self.handles.contains(&handle) {
+            log_warn!(target: "vfs", "free of unknown or already freed file handle {:#x}", handle);
+            return false;
         }
+        if !self.check_handle::<T>(handle) {
+            log_error!(
+                target: "vfs",
+                "file handle {:#x} freed as a {}",
+                handle,
+                core::any::type_name::<T>()
+            );
+            return false;
+        }
+        unsafe {
+            let header = self.header(handle);
+            (*header).magic = 0;
+            dealloc(header as *mut u8, (*header).layout)
+        };
+        self.handles.remove(&handle);
+        true
     }
 
     pub fn iter(&self) -> btree_set::Iter<u64> {
@@ -1240,36 +2476,173 @@ impl FileHandleAllocator {
     }
 }
 
-static mut VFS: Option<Arcrwb<Vfs>> = None;
+static VFS: Once<Arcrwb<Vfs>> = Once::new();
+/// Core that mounts the file systems of the vfs
+static VFS_INITIALIZER: AtomicU32 = AtomicU32::new(u32::MAX);
+static VFS_READY: AtomicBool = AtomicBool::new(false);
 
+fn current_core() -> u32 {
+    if is_per_cpu_initialized() {
+        core_id() as u32
+    } else {
+        0
+    }
+}
+
+/// The vfs is published before its file systems are mounted, so the mounts can get it again on
+/// the core that runs them. The other cores wait for the mounts
 pub fn get_vfs() -> Arcrwb<Vfs> {
-    unsafe {
-        match VFS {
-            Some(ref v) => v.clone(),
-            None => {
-                let v = Vfs {
-                    fs_by_id: arcrwb_new(BTreeMap::new()),
-                    mounting_points_manager: MountingPointsManager::new(),
-                    root_fs: None,
-                    os_id_count: 1,
-                };
-                VFS = Some(arcrwb_new(v));
-                #[allow(static_mut_refs)]
-                let ptr = VFS.clone().unwrap();
-                let iptr = Some(Arc::downgrade(&ptr.clone()));
-                let mut wguard = ptr.write();
-                wguard.root_fs = iptr;
-
-                init_vfs(&mut wguard);
-
-                #[allow(static_mut_refs)]
-                VFS.clone().unwrap()
-            }
+    let mut initializer = false;
+    let vfs = VFS.call_once(|| {
+        initializer = true;
+        VFS_INITIALIZER.store(current_core(), Ordering::Release);
+        let vfs = arcrwb_with_class(
+            Box::new(Vfs {
+                fs_by_id: arcrwb_new(BTreeMap::new()),
+                mounting_points_manager: MountingPointsManager::new(),
+                binds: BTreeMap::new(),
+                root_fs: None,
+                os_id_count: 1,
+            }),
+            LockClass::Vfs,
+        );
+        vfs.write().root_fs = Some(Arc::downgrade(&vfs));
+        vfs
+    });
+
+    if initializer {
+        init_vfs(&mut vfs.write());
+        VFS_READY.store(true, Ordering::Release);
+    } else {
+        while !VFS_READY.load(Ordering::Acquire)
+            && VFS_INITIALIZER.load(Ordering::Acquire) != current_core()
+        {
+            core::hint::spin_loop();
+        }
+    }
+    vfs.clone()
+}
+
+/// Size of the buffer of the copies between files that go through `fread_at` and `fwrite_at`
+pub const COPY_BUFFER_SIZE: usize = 64 * 1024;
+
+/// Most bytes a single copy between files moves, larger copies return a short count
+static MAX_COPY_CHUNK: AtomicU64 = AtomicU64::new(8 * 1024 * 1024);
+
+pub fn max_copy_chunk() -> u64 {
+    MAX_COPY_CHUNK.load(Ordering::Relaxed)
+}
+
+pub fn set_max_copy_chunk(bytes: u64) {
+    MAX_COPY_CHUNK.store(bytes.max(1), Ordering::Relaxed);
+}
+
+/// Copies `len` bytes through a buffer. `read` and `write` get how many bytes were copied so
+/// far and return how many they moved, a short count ends the copy. An error after some bytes
+/// were copied ends it too, the next call reports it
+pub fn copy_through_buffer<C: ?Sized>(
+    context: &mut C,
+    len: u64,
+    mut read: impl FnMut(&mut C, u64, &mut [u8]) -> Result<u64, VfsError>,
+    mut write: impl FnMut(&mut C, u64, &[u8]) -> Result<u64, VfsError>,
+) -> Result<u64, VfsError> {
+    let mut buffer = alloc::vec![0; len.min(COPY_BUFFER_SIZE as u64) as usize];
+    let mut copied = 0;
+    while copied < len {
+        let chunk = (len - copied).min(buffer.len() as u64) as usize;
+        let read = match read(context, copied, &mut buffer[..chunk]) {
+            Ok(0) => break,
+            Ok(read) => read,
+            Err(e) if copied == 0 => return Err(e),
+            Err(_) => break,
+        };
+        let written = match write(context, copied, &buffer[..read as usize]) {
+            Ok(written) => written,
+            Err(e) if copied == 0 => return Err(e),
+            Err(_) => break,
+        };
+        copied += written;
+        if written < read {
+            break;
+        }
+    }
+    Ok(copied)
+}
+
+/// One side of a copy between files, `offset` is `None` for a stream
+#[derive(Debug, Clone, Copy)]
+pub struct CopyEnd<'a> {
+    pub fs: &'a Arcrwb<dyn FileSystem>,
+    pub handle: u64,
+    pub offset: Option<u64>,
+}
+
+/// Copies up to `len` bytes between two files inside the kernel, at most `max_copy_chunk` at
+/// once. Files of the same file system use `FileSystem::fcopy_range`
+pub fn copy_file_data(src: CopyEnd, dst: CopyEnd, len: u64) -> Result<u64, VfsError> {
+    let len = len.min(max_copy_chunk());
+    if let (Some(src_offset), Some(dst_offset)) = (src.offset, dst.offset) {
+        if Arc::ptr_eq(src.fs, dst.fs) {
+            return src
+                .fs
+                .write()
+                .fcopy_range(src.handle, src_offset, dst.handle, dst_offset, len);
+        }
+    }
+    copy_through_buffer(
+        &mut (),
+        len,
+        |_, done, buf| match src.offset {
+            Some(offset) => src.fs.write().fread_at(src.handle, offset + done, buf),
+            None => src.fs.write().fread(src.handle, buf),
+        },
+        |_, done, buf| match dst.offset {
+            Some(offset) => dst.fs.write().fwrite_at(dst.handle, offset + done, buf),
+            None => dst.fs.write().fwrite(dst.handle, buf),
+        },
+    )
+}
+
+/// Runs `FileSystem::writeback` on every mounted file system. File systems that are in use are
+/// skipped, the next run will retry
+pub fn writeback_file_systems() {
+    let vfs = get_vfs();
+    let Some(vguard) = vfs.try_read() else {
+        return;
+    };
+    let file_systems = vguard.mounted_file_systems();
+    drop(vguard);
+    for fs in file_systems {
+        let Some(mut wguard) = fs.try_write() else {
+            continue;
+        };
+        if let Err(e) = wguard.writeback() {
+            log_error!(target: "vfs", "Write back failed: {:?}", e);
+        }
+    }
+}
+
+/// Runs `FileSystem::shrink_caches` on every mounted file system that isn't in use, when memory
+/// runs out
+pub fn shrink_file_system_caches() {
+    let vfs = get_vfs();
+    let Some(vguard) = vfs.try_read() else {
+        return;
+    };
+    let file_systems = vguard.mounted_file_systems();
+    drop(vguard);
+    for fs in file_systems {
+        if let Some(guard) = fs.try_read() {
+            guard.shrink_caches();
         }
     }
 }
 
 fn init_vfs(vfs: &mut Vfs) {
     init_devfs(vfs);
+    init_ptyfs(vfs);
     init_pipefs(vfs);
+    init_unixsockfs(vfs);
+    init_timerfd_fs(vfs);
+    init_procfs(vfs);
 }
@@ -1,12 +1,20 @@
+pub mod console;
+pub mod text;
+
 use core::{alloc::Layout, panic};
 
-use alloc::{alloc::alloc_zeroed, boxed::Box, collections::BTreeSet, sync::Arc};
-use spin::RwLock;
+use alloc::{alloc::alloc_zeroed, boxed::Box, collections::BTreeSet, sync::Arc, vec::Vec};
+use spin::{Once, RwLock};
 
 use crate::{
     paging::{self, get_kernel_page_table},
     permissions,
-    vesa::{get_mode_info, VesaModeInfoStructure},
+    vesa::{get_mode_info, VesaModeInfoStructure, VESA_MODE_GRAPHICS},
+};
+
+use self::{
+    console::{install_console, Console, SCROLLBACK_PAGES},
+    text::VgaTextBackend,
 };
 
 use super::{
@@ -351,7 +359,7 @@ impl DevFsDriver for VgaDriver {
         dev_fs: &mut DevFs,
         pci_device: &PciDevice,
         device_id: usize,
-    ) -> Result<(), VfsError> {
+    ) -> Result<Vec<Vec<char>>, VfsError> {
         if !self.handles_device(dev_fs, pci_device) {
             return Err(VfsError::ActionNotAllowed);
         }
@@ -372,8 +380,9 @@ impl DevFsDriver for VgaDriver {
             DevFsHookKind::Device,
             0,
             device_id as u64,
+            0,
         );
-        Ok(())
+        Ok(alloc::vec!["vga".chars().collect()])
     }
 
     fn fopen(
@@ -382,7 +391,7 @@ impl DevFsDriver for VgaDriver {
         hook: Arc<DevFsHook>,
         mode: u64,
     ) -> Result<u64, VfsError> {
-        if hook.file.name() != ['v', 'g', 'a'] {
+        if hook.device_key != 0 {
             return Err(VfsError::PathNotFound);
         }
 
@@ -467,9 +476,7 @@ impl DevFsDriver for VgaDriver {
         let device = handle_data.device.read();
         let device = &**device;
 
-        let bytes_read = device.read_chars(handle_data.position, buf)?;
-        handle_data.position += bytes_read;
-        Ok(bytes_read)
+        device.read_at_position(&mut handle_data.position, buf)
     }
 
     fn fwrite(&mut self, dev_fs: &mut DevFs, handle: u64, buf: &[u8]) -> Result<u64, VfsError> {
@@ -489,9 +496,7 @@ impl DevFsDriver for VgaDriver {
         let mut device = handle_data.device.write();
         let device = &mut **device;
 
-        let bytes_written = device.write_chars(handle_data.position, buf)?;
-        handle_data.position += bytes_written;
-        Ok(bytes_written)
+        device.write_at_position(&mut handle_data.position, buf)
     }
 
     fn ftruncate(&mut self, _dev_fs: &mut DevFs, handle: u64) -> Result<u64, VfsError> {
@@ -530,22 +535,25 @@ impl Drop for VgaDriver {
     }
 }
 
-static mut VGA_DRIVER: Option<Arcrwb<dyn DevFsDriver>> = None;
+static VGA_DRIVER: Once<Arcrwb<dyn DevFsDriver>> = Once::new();
 
-#[allow(static_mut_refs)]
 pub fn get_vga_driver() -> Arcrwb<dyn DevFsDriver> {
-    unsafe {
-        if VGA_DRIVER.is_none() {
-            let mode_info = get_mode_info();
-            VGA_DRIVER = Some(arcrwb_new_from_box(Box::new(VgaDriver::from_device(
-                VgaCharDevice::new(mode_info),
-            ))));
-        }
-        VGA_DRIVER.clone().unwrap()
-    }
+    VGA_DRIVER
+        .call_once(|| {
+            arcrwb_new_from_box(Box::new(VgaDriver::from_device(VgaCharDevice::new(
+                get_mode_info(),
+            ))))
+        })
+        .clone()
 }
 
 pub fn init_vga(devfs: &mut DevFs) {
+    if get_mode_info().attributes & VESA_MODE_GRAPHICS == 0 {
+        install_console(Console::with_backend(
+            Box::new(VgaTextBackend::new()),
+            SCROLLBACK_PAGES,
+        ));
+    }
     let driver = get_vga_driver();
     devfs.register_driver(driver).unwrap();
 }
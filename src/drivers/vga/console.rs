@@ -0,0 +1,672 @@
+//! Text console shared by the display back-ends
+//!
+//! Output goes through an ANSI/VT100 parser, so every back-end understands the same escape
+//! sequences: SGR colors, cursor movement, erasing and cursor save/restore. Sequences split across
+//! writes are kept in the parser until the rest arrives. Lines scrolled off the screen are kept in
+//! a scrollback buffer, which the keyboard driver pages through with Shift+PageUp/PageDown.
+//!
+//! Output is UTF-8, cells hold characters and each back-end picks the glyph showing them.
+
+use core::sync::atomic::{AtomicBool, AtomicI64, Ordering};
+
+use alloc::{boxed::Box, collections::VecDeque, vec, vec::Vec};
+use spin::Mutex;
+
+/// Pages of output kept in the scrollback buffer
+pub const SCROLLBACK_PAGES: usize = 8;
+
+const MAX_CSI_PARAMS: usize = 8;
+const TAB_WIDTH: usize = 8;
+
+const DEFAULT_FOREGROUND: u8 = 7;
+const DEFAULT_BACKGROUND: u8 = 0;
+
+/// VGA palette index of the ANSI colors, which are ordered differently
+const ANSI_TO_VGA: [u8; 8] = [0, 4, 2, 6, 1, 5, 3, 7];
+
+/// A character and its VGA attribute byte: foreground in the low nibble, background in the high one
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cell {
+    pub ch: char,
+    pub attribute: u8,
+}
+
+impl Cell {
+    pub const fn new(ch: char, attribute: u8) -> Self {
+        Self { ch, attribute }
+    }
+
+    pub const fn foreground(&self) -> u8 {
+        self.attribute & 0xF
+    }
+
+    pub const fn background(&self) -> u8 {
+        self.attribute >> 4
+    }
+}
+
+/// Colors and bold state set by SGR sequences
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Rendition {
+    foreground: u8,
+    background: u8,
+    bold: bool,
+}
+
+impl Default for Rendition {
+    fn default() -> Self {
+        Self {
+            foreground: DEFAULT_FOREGROUND,
+            background: DEFAULT_BACKGROUND,
+            bold: false,
+        }
+    }
+}
+
+impl Rendition {
+    /// Bold is shown as the bright variant of the foreground
+    fn attribute(&self) -> u8 {
+        let foreground = if self.bold {
+            self.foreground | 8
+        } else {
+            self.foreground
+        };
+        (self.background << 4) | foreground
+    }
+
+    fn apply_sgr(&mut self, params: &[u16]) {
+        if params.is_empty() {
+            *self = Self::default();
+        }
+        for param in params {
+            match *param {
+                0 => *self = Self::default(),
+                1 => self.bold = true,
+                22 => self.bold = false,
+                30..=37 => self.foreground = ANSI_TO_VGA[*param as usize - 30],
+                39 => self.foreground = DEFAULT_FOREGROUND,
+                40..=47 => self.background = ANSI_TO_VGA[*param as usize - 40],
+                49 => self.background = DEFAULT_BACKGROUND,
+                90..=97 => self.foreground = ANSI_TO_VGA[*param as usize - 90] | 8,
+                100..=107 => self.background = ANSI_TO_VGA[*param as usize - 100] | 8,
+                _ => {}
+            }
+        }
+    }
+}
+
+/// UTF-8 decoder fed one byte at a time, sequences split across writes are kept until the rest
+/// arrives. Invalid bytes and sequences cut short decode to U+FFFD
+#[derive(Debug)]
+pub struct Utf8Decoder {
+    /// Bits of the code point read so far
+    value: u32,
+    /// Continuation bytes still expected
+    remaining: u8,
+    /// Range of the next continuation byte, narrower after some lead bytes to refuse overlong
+    /// encodings, surrogates and code points past U+10FFFF
+    lower: u8,
+    upper: u8,
+}
+
+impl Default for Utf8Decoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Utf8Decoder {
+    pub const fn new() -> Self {
+        Self {
+            value: 0,
+            remaining: 0,
+            lower: 0x80,
+            upper: 0xBF,
+        }
+    }
+
+    /// Whether a sequence was started but isn't complete yet
+    pub fn is_pending(&self) -> bool {
+        self.remaining != 0
+    }
+
+    /// Passes the decoded characters to `output`, a byte breaking a sequence gives U+FFFD and is
+    /// then decoded on its own
+    pub fn feed(&mut self, byte: u8, mut output: impl FnMut(char)) {
+        if self.remaining != 0 {
+            if !(self.lower..=self.upper).contains(&byte) {
+                *self = Self::new();
+                output(char::REPLACEMENT_CHARACTER);
+                return self.feed(byte, output);
+            }
+            self.lower = 0x80;
+            self.upper = 0xBF;
+            self.value = (self.value << 6) | (byte & 0x3F) as u32;
+            self.remaining -= 1;
+            if self.remaining == 0 {
+                output(char::from_u32(self.value).unwrap_or(char::REPLACEMENT_CHARACTER));
+            }
+            return;
+        }
+        let (value, remaining) = match byte {
+            0..=0x7F => return output(byte as char),
+            0xC2..=0xDF => (byte & 0x1F, 1),
+            0xE0..=0xEF => (byte & 0x0F, 2),
+            0xF0..=0xF4 => (byte & 0x07, 3),
+            _ => return output(char::REPLACEMENT_CHARACTER),
+        };
+        match byte {
+            0xE0 => self.lower = 0xA0,
+            0xED => self.upper = 0x9F,
+            0xF0 => self.lower = 0x90,
+            0xF4 => self.upper = 0x8F,
+            _ => {}
+        }
+        self.value = value as u32;
+        self.remaining = remaining;
+    }
+}
+
+/// A complete control sequence, `ESC [ params command`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CsiSequence {
+    pub command: u8,
+    /// Whether the parameters started with `?`
+    pub private: bool,
+    params: [u16; MAX_CSI_PARAMS],
+    param_count: usize,
+}
+
+impl CsiSequence {
+    pub fn params(&self) -> &[u16] {
+        &self.params[..self.param_count]
+    }
+
+    /// Parameter `index`, `default` when missing or 0
+    pub fn param(&self, index: usize, default: u16) -> u16 {
+        match self.params().get(index) {
+            Some(0) | None => default,
+            Some(value) => *value,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AnsiAction {
+    Print(char),
+    /// C0 control character, like a new line or a backspace
+    Control(u8),
+    Csi(CsiSequence),
+    SaveCursor,
+    RestoreCursor,
+    Reset,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ParserState {
+    Ground,
+    Escape,
+    Csi,
+}
+
+/// VT100 parser, fed one character at a time. Unknown sequences are consumed and ignored
+#[derive(Debug)]
+pub struct AnsiParser {
+    state: ParserState,
+    sequence: CsiSequence,
+    /// Digits of the parameter being read
+    current: Option<u16>,
+}
+
+impl Default for AnsiParser {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl AnsiParser {
+    pub const fn new() -> Self {
+        Self {
+            state: ParserState::Ground,
+            sequence: CsiSequence {
+                command: 0,
+                private: false,
+                params: [0; MAX_CSI_PARAMS],
+                param_count: 0,
+            },
+            current: None,
+        }
+    }
+
+    /// Whether a sequence was started but isn't complete yet
+    pub fn is_pending(&self) -> bool {
+        self.state != ParserState::Ground
+    }
+
+    /// Sequences are ASCII, other characters only print
+    pub fn feed(&mut self, ch: char) -> Option<AnsiAction> {
+        if !ch.is_ascii() {
+            // The C1 controls aren't supported
+            return (self.state == ParserState::Ground && !ch.is_control())
+                .then_some(AnsiAction::Print(ch));
+        }
+        let byte = ch as u8;
+        match (self.state, byte) {
+            // CAN and SUB abort a sequence, ESC restarts one
+            (_, 0x18 | 0x1A) => {
+                self.state = ParserState::Ground;
+                None
+            }
+            (_, 0x1B) => {
+                self.state = ParserState::Escape;
+                None
+            }
+            (ParserState::Ground, 0x7F) => None,
+            (ParserState::Ground, 0..=0x1F) => Some(AnsiAction::Control(byte)),
+            (ParserState::Ground, _) => Some(AnsiAction::Print(ch)),
+            (ParserState::Escape, b'[') => {
+                self.state = ParserState::Csi;
+                self.sequence.private = false;
+                self.sequence.param_count = 0;
+                self.current = None;
+                None
+            }
+            (ParserState::Escape, _) => {
+                self.state = ParserState::Ground;
+                match byte {
+                    b'7' => Some(AnsiAction::SaveCursor),
+                    b'8' => Some(AnsiAction::RestoreCursor),
+                    b'c' => Some(AnsiAction::Reset),
+                    _ => None,
+                }
+            }
+            // Control characters inside a sequence are run as if they came before it
+            (ParserState::Csi, 0..=0x1F) => Some(AnsiAction::Control(byte)),
+            (ParserState::Csi, b'0'..=b'9') => {
+                let digit = (byte - b'0') as u16;
+                let value = self.current.unwrap_or(0);
+                self.current = Some(value.saturating_mul(10).saturating_add(digit));
+                None
+            }
+            (ParserState::Csi, b';') => {
+                self.push_param();
+                None
+            }
+            (ParserState::Csi, b'<'..=b'?') => {
+                self.sequence.private = true;
+                None
+            }
+            (ParserState::Csi, 0x40..=0x7E) => {
+                if self.current.is_some() || self.sequence.param_count > 0 {
+                    self.push_param();
+                }
+                self.state = ParserState::Ground;
+                self.sequence.command = byte;
+                Some(AnsiAction::Csi(self.sequence))
+            }
+            // Intermediate bytes, none of the supported sequences use them
+            (ParserState::Csi, _) => None,
+        }
+    }
+
+    /// Extra parameters are dropped
+    fn push_param(&mut self) {
+        let value = self.current.take().unwrap_or(0);
+        if self.sequence.param_count < MAX_CSI_PARAMS {
+            self.sequence.params[self.sequence.param_count] = value;
+            self.sequence.param_count += 1;
+        }
+    }
+}
+
+/// Where the console is displayed
+pub trait ConsoleBackend: Send {
+    /// Columns and rows
+    fn size(&self) -> (usize, usize);
+
+    fn draw_cell(&mut self, x: usize, y: usize, cell: Cell);
+
+    /// Moves the hardware cursor, `None` hides it
+    fn set_cursor(&mut self, position: Option<(usize, usize)>);
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConsoleControl {
+    ScrollUp,
+    ScrollDown,
+}
+
+pub struct Console {
+    width: usize,
+    height: usize,
+    /// Rows of the screen, one after another
+    screen: Vec<Cell>,
+    /// Lines scrolled off the top of the screen, oldest first
+    scrollback: VecDeque<Box<[Cell]>>,
+    scrollback_limit: usize,
+    /// Lines the view is scrolled back by, 0 when showing the screen
+    view_offset: usize,
+    cursor: (usize, usize),
+    saved_cursor: (usize, usize),
+    cursor_visible: bool,
+    /// The last column was written, the next character goes to the next line
+    pending_wrap: bool,
+    rendition: Rendition,
+    decoder: Utf8Decoder,
+    parser: AnsiParser,
+    backend: Option<Box<dyn ConsoleBackend>>,
+}
+
+impl Console {
+    /// A console with nothing displaying it
+    pub fn new(width: usize, height: usize, scrollback_pages: usize) -> Self {
+        let blank = Cell::new(' ', Rendition::default().attribute());
+        Self {
+            width,
+            height,
+            screen: vec![blank; width * height],
+            scrollback: VecDeque::new(),
+            scrollback_limit: scrollback_pages * height,
+            view_offset: 0,
+            cursor: (0, 0),
+            saved_cursor: (0, 0),
+            cursor_visible: true,
+            pending_wrap: false,
+            rendition: Rendition::default(),
+            decoder: Utf8Decoder::new(),
+            parser: AnsiParser::new(),
+            backend: None,
+        }
+    }
+
+    pub fn with_backend(backend: Box<dyn ConsoleBackend>, scrollback_pages: usize) -> Self {
+        let (width, height) = backend.size();
+        let mut console = Self::new(width, height, scrollback_pages);
+        console.backend = Some(backend);
+        console.redraw();
+        console
+    }
+
+    pub fn size(&self) -> (usize, usize) {
+        (self.width, self.height)
+    }
+
+    /// Column and row
+    pub fn cursor(&self) -> (usize, usize) {
+        self.cursor
+    }
+
+    pub fn cell(&self, x: usize, y: usize) -> Cell {
+        self.screen[y * self.width + x]
+    }
+
+    pub fn scrollback_len(&self) -> usize {
+        self.scrollback.len()
+    }
+
+    pub fn view_offset(&self) -> usize {
+        self.view_offset
+    }
+
+    /// Cell shown at `x`, `y`, which is in the scrollback when the view is scrolled
+    pub fn visible_cell(&self, x: usize, y: usize) -> Cell {
+        let line = self.scrollback.len() - self.view_offset + y;
+        match self.scrollback.get(line) {
+            Some(cells) => cells[x],
+            None => self.cell(x, line - self.scrollback.len()),
+        }
+    }
+
+    /// Output brings the view back to the screen
+    pub fn write(&mut self, bytes: &[u8]) {
+        if self.view_offset != 0 && !bytes.is_empty() {
+            self.view_offset = 0;
+            self.redraw();
+        }
+        let mut decoder = core::mem::take(&mut self.decoder);
+        for byte in bytes {
+            decoder.feed(*byte, |ch| {
+                if let Some(action) = self.parser.feed(ch) {
+                    self.apply(action);
+                }
+            });
+        }
+        self.decoder = decoder;
+        self.update_cursor();
+    }
+
+    pub fn control(&mut self, control: ConsoleControl) {
+        let page = self.height.max(1);
+        self.view_offset = match control {
+            ConsoleControl::ScrollUp => (self.view_offset + page).min(self.scrollback.len()),
+            ConsoleControl::ScrollDown => self.view_offset.saturating_sub(page),
+        };
+        self.redraw();
+        self.update_cursor();
+    }
+
+    fn apply(&mut self, action: AnsiAction) {
+        match action {
+            AnsiAction::Print(ch) => self.print(ch),
+            AnsiAction::Control(b'\n' | 0x0B | 0x0C) => {
+                self.pending_wrap = false;
+                self.line_feed();
+            }
+            AnsiAction::Control(b'\r') => self.move_to(0, self.cursor.1),
+            AnsiAction::Control(0x08) => {
+                self.move_to(self.cursor.0.saturating_sub(1), self.cursor.1)
+            }
+            AnsiAction::Control(b'\t') => {
+                let column = (self.cursor.0 / TAB_WIDTH + 1) * TAB_WIDTH;
+                self.move_to(column, self.cursor.1);
+            }
+            AnsiAction::Control(_) => {}
+            AnsiAction::Csi(sequence) => self.apply_csi(&sequence),
+            AnsiAction::SaveCursor => self.saved_cursor = self.cursor,
+            AnsiAction::RestoreCursor => self.move_to(self.saved_cursor.0, self.saved_cursor.1),
+            AnsiAction::Reset => {
+                self.rendition = Rendition::default();
+                self.cursor_visible = true;
+                self.erase(0, self.screen.len());
+                self.move_to(0, 0);
+            }
+        }
+    }
+
+    fn apply_csi(&mut self, sequence: &CsiSequence) {
+        let (x, y) = self.cursor;
+        let count = |index| sequence.param(index, 1) as usize;
+        match (sequence.private, sequence.command) {
+            (true, b'h' | b'l') => {
+                if sequence.params().contains(&25) {
+                    self.cursor_visible = sequence.command == b'h';
+                }
+            }
+            (true, _) => {}
+            (_, b'm') => self.rendition.apply_sgr(sequence.params()),
+            (_, b'H' | b'f') => self.move_to(count(1) - 1, count(0) - 1),
+            (_, b'A') => self.move_to(x, y.saturating_sub(count(0))),
+            (_, b'B') => self.move_to(x, y.saturating_add(count(0))),
+            (_, b'C') => self.move_to(x.saturating_add(count(0)), y),
+            (_, b'D') => self.move_to(x.saturating_sub(count(0)), y),
+            (_, b'J') => {
+                let position = y * self.width + x;
+                match sequence.param(0, 0) {
+                    0 => self.erase(position, self.screen.len()),
+                    1 => self.erase(0, position + 1),
+                    2 => self.erase(0, self.screen.len()),
+                    3 => {
+                        self.scrollback.clear();
+                        self.erase(0, self.screen.len());
+                    }
+                    _ => {}
+                }
+            }
+            (_, b'K') => {
+                let line = y * self.width;
+                match sequence.param(0, 0) {
+                    0 => self.erase(line + x, line + self.width),
+                    1 => self.erase(line, line + x + 1),
+                    2 => self.erase(line, line + self.width),
+                    _ => {}
+                }
+            }
+            (_, b's') => self.saved_cursor = self.cursor,
+            (_, b'u') => self.move_to(self.saved_cursor.0, self.saved_cursor.1),
+            _ => {}
+        }
+    }
+
+    fn print(&mut self, ch: char) {
+        if self.width == 0 || self.height == 0 {
+            return;
+        }
+        if self.pending_wrap {
+            self.pending_wrap = false;
+            self.cursor.0 = 0;
+            self.line_feed();
+        }
+        let (x, y) = self.cursor;
+        self.set_cell(
+            y * self.width + x,
+            Cell::new(ch, self.rendition.attribute()),
+        );
+        if x + 1 == self.width {
+            self.pending_wrap = true;
+        } else {
+            self.cursor.0 += 1;
+        }
+    }
+
+    /// Clamped to the screen
+    fn move_to(&mut self, x: usize, y: usize) {
+        self.pending_wrap = false;
+        self.cursor = (
+            x.min(self.width.saturating_sub(1)),
+            y.min(self.height.saturating_sub(1)),
+        );
+    }
+
+    fn line_feed(&mut self) {
+        if self.cursor.1 + 1 < self.height {
+            self.cursor.1 += 1;
+        } else {
+            self.scroll();
+        }
+    }
+
+    /// Moves the first row to the scrollback
+    fn scroll(&mut self) {
+        if self.height == 0 {
+            return;
+        }
+        if self.scrollback_limit > 0 {
+            if self.scrollback.len() == self.scrollback_limit {
+                self.scrollback.pop_front();
+            }
+            let line = self.screen[..self.width].to_vec().into_boxed_slice();
+            self.scrollback.push_back(line);
+        }
+        self.screen.copy_within(self.width.., 0);
+        let blank = self.blank();
+        let last_line = self.screen.len() - self.width;
+        self.screen[last_line..].fill(blank);
+        self.redraw();
+    }
+
+    /// Erases the cells from `begin` to `end` of the screen, with the current background
+    fn erase(&mut self, begin: usize, end: usize) {
+        let blank = self.blank();
+        for position in begin..end.min(self.screen.len()) {
+            self.set_cell(position, blank);
+        }
+    }
+
+    fn blank(&self) -> Cell {
+        let background = Rendition {
+            bold: false,
+            ..self.rendition
+        };
+        Cell::new(' ', background.attribute())
+    }
+
+    fn set_cell(&mut self, position: usize, cell: Cell) {
+        self.screen[position] = cell;
+        if self.view_offset == 0 {
+            if let Some(backend) = &mut self.backend {
+                backend.draw_cell(position % self.width, position / self.width, cell);
+            }
+        }
+    }
+
+    fn redraw(&mut self) {
+        let Some(mut backend) = self.backend.take() else {
+            return;
+        };
+        for y in 0..self.height {
+            for x in 0..self.width {
+                backend.draw_cell(x, y, self.visible_cell(x, y));
+            }
+        }
+        self.backend = Some(backend);
+    }
+
+    /// The cursor is hidden while looking at the scrollback
+    fn update_cursor(&mut self) {
+        let position = (self.cursor_visible && self.view_offset == 0).then_some(self.cursor);
+        if let Some(backend) = &mut self.backend {
+            backend.set_cursor(position);
+        }
+    }
+}
+
+static CONSOLE: Mutex<Option<Console>> = Mutex::new(None);
+static CONSOLE_INSTALLED: AtomicBool = AtomicBool::new(false);
+
+/// Pages to scroll back by, requested by the keyboard and not handled yet
+static PENDING_SCROLL: AtomicI64 = AtomicI64::new(0);
+
+/// Makes `console` the one receiving the kernel output
+pub fn install_console(console: Console) {
+    *CONSOLE.lock() = Some(console);
+    CONSOLE_INSTALLED.store(true, Ordering::Release);
+}
+
+pub fn is_console_installed() -> bool {
+    CONSOLE_INSTALLED.load(Ordering::Acquire)
+}
+
+pub fn write_console(bytes: &[u8]) {
+    let mut lock = CONSOLE.lock();
+    if let Some(console) = lock.as_mut() {
+        console.write(bytes);
+        apply_pending_controls(console);
+    }
+}
+
+/// Called from the keyboard interrupt. Applied right away unless the console is busy, in which
+/// case the writer picks it up
+pub fn send_control(control: ConsoleControl) {
+    let pages = match control {
+        ConsoleControl::ScrollUp => 1,
+        ConsoleControl::ScrollDown => -1,
+    };
+    PENDING_SCROLL.fetch_add(pages, Ordering::AcqRel);
+    if let Some(mut lock) = CONSOLE.try_lock() {
+        if let Some(console) = lock.as_mut() {
+            apply_pending_controls(console);
+        }
+    }
+}
+
+fn apply_pending_controls(console: &mut Console) {
+    let pages = PENDING_SCROLL.swap(0, Ordering::AcqRel);
+    let control = if pages > 0 {
+        ConsoleControl::ScrollUp
+    } else {
+        ConsoleControl::ScrollDown
+    };
+    for _ in 0..pages.unsigned_abs() {
+        console.control(control);
+    }
+}
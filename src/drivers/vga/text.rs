@@ -0,0 +1,115 @@
+//! Console back-end for the VGA text mode, used when the boot loader didn't set a graphics mode
+
+use crate::{
+    io::{inb, outb},
+    paging::DIRECT_MAPPING_OFFSET,
+};
+
+use super::console::{Cell, ConsoleBackend};
+
+const TEXT_BUFFER: u64 = 0xB8000;
+const TEXT_WIDTH: usize = 80;
+const TEXT_HEIGHT: usize = 25;
+
+const CRTC_INDEX: u16 = 0x3D4;
+const CRTC_DATA: u16 = 0x3D5;
+const CRTC_CURSOR_START: u8 = 0x0A;
+const CRTC_CURSOR_LOCATION_HIGH: u8 = 0x0E;
+const CRTC_CURSOR_LOCATION_LOW: u8 = 0x0F;
+/// Bit of the cursor start register that turns the cursor off
+const CURSOR_DISABLE: u8 = 0x20;
+
+/// Glyphs of code page 437, the font of the text mode, from 0x80
+const CP437_HIGH: [char; 128] = [
+    'Ç', 'ü', 'é', 'â', 'ä', 'à', 'å', 'ç', 'ê', 'ë', 'è', 'ï', 'î', 'ì', 'Ä', 'Å', //
+    'É', 'æ', 'Æ', 'ô', 'ö', 'ò', 'û', 'ù', 'ÿ', 'Ö', 'Ü', '¢', '£', '¥', '₧', 'ƒ', //
+    'á', 'í', 'ó', 'ú', 'ñ', 'Ñ', 'ª', 'º', '¿', '⌐', '¬', '½', '¼', '¡', '«', '»', //
+    '░', '▒', '▓', '│', '┤', '╡', '╢', '╖', '╕', '╣', '║', '╗', '╝', '╜', '╛', '┐', //
+    '└', '┴', '┬', '├', '─', '┼', '╞', '╟', '╚', '╔', '╩', '╦', '╠', '═', '╬', '╧', //
+    '╨', '╤', '╥', '╙', '╘', '╒', '╓', '╫', '╪', '┘', '┌', '█', '▄', '▌', '▐', '▀', //
+    'α', 'ß', 'Γ', 'π', 'Σ', 'σ', 'µ', 'τ', 'Φ', 'Θ', 'Ω', 'δ', '∞', 'φ', 'ε', '∩', //
+    '≡', '±', '≥', '≤', '⌠', '⌡', '÷', '≈', '°', '∙', '·', '√', 'ⁿ', '²', '■', '\u{A0}', //
+];
+
+/// Glyphs of code page 437 in place of the control characters, from 0x01
+const CP437_LOW: [char; 31] = [
+    '☺', '☻', '♥', '♦', '♣', '♠', '•', '◘', '○', '◙', '♂', '♀', '♪', '♫', '☼', //
+    '►', '◄', '↕', '‼', '¶', '§', '▬', '↨', '↑', '↓', '→', '←', '∟', '↔', '▲', '▼', //
+];
+
+/// Shown for the characters the font doesn't have, in place of U+FFFD
+pub const REPLACEMENT_GLYPH: u8 = 0xFE;
+
+/// Code page 437 glyph showing `ch`
+pub fn cp437_glyph(ch: char) -> u8 {
+    match ch {
+        ' '..='~' => ch as u8,
+        '⌂' => 0x7F,
+        _ => {
+            if let Some(index) = CP437_HIGH.iter().position(|glyph| *glyph == ch) {
+                0x80 + index as u8
+            } else if let Some(index) = CP437_LOW.iter().position(|glyph| *glyph == ch) {
+                0x01 + index as u8
+            } else {
+                REPLACEMENT_GLYPH
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct VgaTextBackend {
+    buffer: *mut u16,
+}
+
+// The text buffer is only accessed through the console lock
+unsafe impl Send for VgaTextBackend {}
+
+impl Default for VgaTextBackend {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl VgaTextBackend {
+    pub fn new() -> Self {
+        Self {
+            buffer: (TEXT_BUFFER + DIRECT_MAPPING_OFFSET) as *mut u16,
+        }
+    }
+
+    fn write_crtc(register: u8, value: u8) {
+        outb(CRTC_INDEX, register);
+        outb(CRTC_DATA, value);
+    }
+
+    fn read_crtc(register: u8) -> u8 {
+        outb(CRTC_INDEX, register);
+        inb(CRTC_DATA)
+    }
+}
+
+impl ConsoleBackend for VgaTextBackend {
+    fn size(&self) -> (usize, usize) {
+        (TEXT_WIDTH, TEXT_HEIGHT)
+    }
+
+    fn draw_cell(&mut self, x: usize, y: usize, cell: Cell) {
+        let value = ((cell.attribute as u16) << 8) | cp437_glyph(cell.ch) as u16;
+        unsafe {
+            core::ptr::write_volatile(self.buffer.add(y * TEXT_WIDTH + x), value);
+        }
+    }
+
+    fn set_cursor(&mut self, position: Option<(usize, usize)>) {
+        let start = Self::read_crtc(CRTC_CURSOR_START);
+        let Some((x, y)) = position else {
+            Self::write_crtc(CRTC_CURSOR_START, start | CURSOR_DISABLE);
+            return;
+        };
+        let location = (y * TEXT_WIDTH + x) as u16;
+        Self::write_crtc(CRTC_CURSOR_LOCATION_HIGH, (location >> 8) as u8);
+        Self::write_crtc(CRTC_CURSOR_LOCATION_LOW, location as u8);
+        Self::write_crtc(CRTC_CURSOR_START, start & !CURSOR_DISABLE);
+    }
+}
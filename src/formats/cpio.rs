@@ -0,0 +1,191 @@
+//! CPIO archives in the "newc" format (and its "crc" variant), as produced by
+//! `find . | cpio -o -H newc`. Used for the initramfs
+//!
+//! Every entry is a 110 byte ASCII header, the name padded to a multiple of 4 bytes (counting
+//! the header) and the data padded the same way. The archive ends with an entry named
+//! `TRAILER!!!`.
+
+pub const CPIO_NEWC_MAGIC: &[u8; 6] = b"070701";
+pub const CPIO_CRC_MAGIC: &[u8; 6] = b"070702";
+pub const CPIO_TRAILER: &[u8] = b"TRAILER!!!";
+
+const HEADER_SIZE: usize = 110;
+
+/// File type bits of `mode`
+pub const CPIO_TYPE_MASK: u32 = 0o170000;
+pub const CPIO_TYPE_DIRECTORY: u32 = 0o040000;
+pub const CPIO_TYPE_FILE: u32 = 0o100000;
+pub const CPIO_TYPE_SYMLINK: u32 = 0o120000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CpioError {
+    /// The archive ends inside an entry
+    Truncated {
+        offset: usize,
+    },
+    BadMagic {
+        offset: usize,
+    },
+    /// A header field isn't 8 hex digits
+    BadField {
+        offset: usize,
+    },
+    /// No terminating NUL, or an empty name
+    BadName {
+        offset: usize,
+    },
+    /// Data after the last entry without a trailer
+    MissingTrailer,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CpioEntryKind {
+    Directory,
+    File,
+    Symlink,
+    /// Devices, pipes and sockets, which an initramfs has no use for
+    Other,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct CpioEntry<'a> {
+    pub ino: u32,
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub nlink: u32,
+    pub mtime: u32,
+    /// Path in the archive, without the terminating NUL. Usually relative, like `./bin/sh`
+    pub name: &'a [u8],
+    /// Contents of a file, target of a symlink
+    pub data: &'a [u8],
+}
+
+impl CpioEntry<'_> {
+    pub fn kind(&self) -> CpioEntryKind {
+        match self.mode & CPIO_TYPE_MASK {
+            CPIO_TYPE_DIRECTORY => CpioEntryKind::Directory,
+            CPIO_TYPE_FILE => CpioEntryKind::File,
+            CPIO_TYPE_SYMLINK => CpioEntryKind::Symlink,
+            _ => CpioEntryKind::Other,
+        }
+    }
+
+    /// Permission bits, `0o7777`
+    pub fn permissions(&self) -> u32 {
+        self.mode & 0o7777
+    }
+
+    /// The name with the leading `./` and `/` removed, empty for the root
+    pub fn path(&self) -> &[u8] {
+        let mut name = self.name;
+        loop {
+            if let Some(rest) = name.strip_prefix(b"./") {
+                name = rest;
+            } else if let Some(rest) = name.strip_prefix(b"/") {
+                name = rest;
+            } else if name == b"." {
+                return &[];
+            } else {
+                return name;
+            }
+        }
+    }
+}
+
+/// Iterates over the entries of an archive, stops at the trailer or the first error
+pub struct CpioArchive<'a> {
+    data: &'a [u8],
+    offset: usize,
+    done: bool,
+}
+
+impl<'a> CpioArchive<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        Self {
+            data,
+            offset: 0,
+            done: false,
+        }
+    }
+
+    fn field(header: &[u8], index: usize, offset: usize) -> Result<u32, CpioError> {
+        let digits = &header[6 + index * 8..][..8];
+        let text = core::str::from_utf8(digits).map_err(|_| CpioError::BadField { offset })?;
+        u32::from_str_radix(text, 16).map_err(|_| CpioError::BadField { offset })
+    }
+
+    fn parse_entry(&mut self) -> Result<Option<CpioEntry<'a>>, CpioError> {
+        let offset = self.offset;
+        let truncated = CpioError::Truncated { offset };
+        let header = self
+            .data
+            .get(offset..)
+            .and_then(|rest| rest.get(..HEADER_SIZE))
+            .ok_or(truncated)?;
+        if &header[..6] != CPIO_NEWC_MAGIC && &header[..6] != CPIO_CRC_MAGIC {
+            return Err(CpioError::BadMagic { offset });
+        }
+        let field = |index| Self::field(header, index, offset);
+        let file_size = field(6)? as usize;
+        let name_size = field(11)? as usize;
+
+        let name_start = offset + HEADER_SIZE;
+        let name = name_start
+            .checked_add(name_size)
+            .and_then(|end| self.data.get(name_start..end))
+            .ok_or(truncated)?;
+        let Some((0, name)) = name.split_last().map(|(last, name)| (*last, name)) else {
+            return Err(CpioError::BadName { offset });
+        };
+        if name.is_empty() {
+            return Err(CpioError::BadName { offset });
+        }
+
+        let data_start = (name_start + name_size).next_multiple_of(4);
+        let data = data_start
+            .checked_add(file_size)
+            .and_then(|end| self.data.get(data_start..end))
+            .ok_or(truncated)?;
+        self.offset = (data_start + file_size).next_multiple_of(4);
+
+        if name == CPIO_TRAILER {
+            return Ok(None);
+        }
+        Ok(Some(CpioEntry {
+            ino: field(0)?,
+            mode: field(1)?,
+            uid: field(2)?,
+            gid: field(3)?,
+            nlink: field(4)?,
+            mtime: field(5)?,
+            name,
+            data,
+        }))
+    }
+}
+
+impl<'a> Iterator for CpioArchive<'a> {
+    type Item = Result<CpioEntry<'a>, CpioError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        if self.offset >= self.data.len() {
+            self.done = true;
+            return Some(Err(CpioError::MissingTrailer));
+        }
+        match self.parse_entry() {
+            Ok(Some(entry)) => Some(Ok(entry)),
+            Ok(None) => {
+                self.done = true;
+                None
+            }
+            Err(e) => {
+                self.done = true;
+                Some(Err(e))
+            }
+        }
+    }
+}
@@ -3,22 +3,19 @@ use core::fmt::Debug;
 use alloc::{boxed::Box, fmt, string::String, vec::Vec};
 
 use crate::{
+    config::get_kernel_config,
     data::{
-        alloc_boxed_slice, calloc_boxed_slice,
+        calloc_boxed_slice,
         file::File,
-        regs::rflags::{RFlag, RFlags},
+        permissions::{Credentials, Permissions},
     },
     debuggable_bitset_enum,
-    drivers::vfs::{SeekPosition, VfsError},
-    paging::{
-        align_down, align_up, PageTable, DIRECT_MAPPING_OFFSET, PAGE_ACCESSED, PAGE_PRESENT,
-        PAGE_RW, PAGE_SIZE, PAGE_USER,
-    },
+    drivers::vfs::{VfsError, OPEN_MODE_READ},
+    paging::{align_down, align_up, PageTable, PAGE_SIZE},
     process::{
-        executable::{ExecutableFileFormat, ExecutableInstantiateOptions},
-        memory::ThreadStack,
-        proc::{ProcessAllocatedCode, ThreadGPRegisters, ThreadState},
-        scheduler::{CreateProcessOptions, ProcessSyscallABI},
+        executable::{ExecutableFileFormat, ExecutableImage, ExecutableInstantiateOptions},
+        memory::{randomize_address, ThreadStack, PROC_PIE_BASE_TOP, PROC_SEGMENTS_TOP},
+        scheduler::CreateProcessOptions,
     },
 };
 
@@ -300,10 +297,19 @@ impl From<Elf64ProgramHeaderRaw> for Elf64ProgramHeader {
     }
 }
 
+/// Where the bytes of an ELF come from
+enum ElfSource {
+    Bytes(Box<[u8]>),
+    /// Read when needed, the file never has to fit in memory
+    File(File),
+}
+
 pub struct Elf64File {
-    contents: Box<[u8]>,
+    source: ElfSource,
+    size: u64,
 
     header: Elf64Header,
+    program_headers: Vec<Elf64ProgramHeader>,
 }
 
 impl fmt::Debug for Elf64File {
@@ -321,12 +327,58 @@ pub enum InvalidElfFileReason {
     InvalidField(&'static str),
 }
 
+#[derive(Debug)]
+pub enum InvalidSegmentReason {
+    /// Not entirely in canonical user space, below the stack region
+    OutsideUserSpace,
+    FileSizeAboveMemorySize,
+    /// The alignment isn't a power of two, or the address and offset disagree modulo it
+    BadAlignment,
+    /// Index of the load segment it overlaps
+    Overlapping(usize),
+    /// PT_INTERP doesn't hold an absolute path
+    InvalidInterpreterPath,
+}
+
 #[derive(Debug)]
 pub enum ElfError {
     InputOutput(VfsError),
     InvalidElfFile(InvalidElfFileReason),
-    InvalidPageTableAllocation,
-    InvalidSegmentOffset { offset: usize, filesz: usize },
+    InvalidSegmentOffset {
+        offset: usize,
+        filesz: usize,
+    },
+    /// The file ended before `len` bytes at `offset` could be read
+    SegmentReadError {
+        offset: u64,
+        len: u64,
+    },
+    /// `index` is the position in the program header table
+    InvalidSegment {
+        index: usize,
+        reason: InvalidSegmentReason,
+    },
+    /// The load segments take more memory than `exec.max_image_size`
+    ImageTooLarge {
+        size: u64,
+        limit: u64,
+    },
+    UnsupportedType(ElfType),
+    UnsupportedRelocation {
+        offset: u64,
+        kind: u32,
+    },
+    /// The relocation writes outside of the load segments
+    InvalidRelocationOffset(u64),
+    InvalidRelocationSection,
+    AddressSpaceRandomizationFailed,
+    /// The dynamic loader named by PT_INTERP couldn't be loaded
+    Interpreter {
+        path: String,
+        error: Box<ElfError>,
+    },
+    /// The dynamic loader names a dynamic loader itself
+    NestedInterpreter,
 }
 
 impl From<VfsError> for ElfError {
@@ -337,93 +389,501 @@ impl From<VfsError> for ElfError {
 
 pub const ELF_MAGIC: [u8; 4] = [0x7f, b'E', b'L', b'F'];
 
+pub const SHT_RELA: u32 = 4;
+pub const R_X86_64_NONE: u32 = 0;
+pub const R_X86_64_RELATIVE: u32 = 8;
+
+/// Keys of the aux vector passed by `build_stack`
+pub const AT_NULL: u64 = 0;
+pub const AT_PHDR: u64 = 3;
+pub const AT_PHENT: u64 = 4;
+pub const AT_PHNUM: u64 = 5;
+pub const AT_PAGESZ: u64 = 6;
+pub const AT_BASE: u64 = 7;
+pub const AT_ENTRY: u64 = 9;
+pub const AT_UID: u64 = 11;
+pub const AT_EUID: u64 = 12;
+pub const AT_GID: u64 = 13;
+pub const AT_EGID: u64 = 14;
+pub const AT_RANDOM: u64 = 25;
+pub const AT_EXECFN: u64 = 31;
+/// Campix specific: address of the time page, see `process::time_page`
+pub const AT_TIME_PAGE: u64 = 0x1000;
+
+#[repr(C, packed)]
+#[derive(Debug, Clone, Copy)]
+pub struct Elf64SectionHeaderRaw {
+    pub sh_name: u32,
+    pub sh_type: u32,
+    pub sh_flags: u64,
+    pub sh_addr: u64,
+    pub sh_offset: u64,
+    pub sh_size: u64,
+    pub sh_link: u32,
+    pub sh_info: u32,
+    pub sh_addralign: u64,
+    pub sh_entsize: u64,
+}
+
+#[repr(C, packed)]
+#[derive(Debug, Clone, Copy)]
+pub struct Elf64RelaRaw {
+    pub r_offset: u64,
+    pub r_info: u64,
+    pub r_addend: i64,
+}
+
+/// Relocations read from the file at once
+const RELOCATION_CHUNK: usize = PAGE_SIZE / size_of::<Elf64RelaRaw>();
+
+/// A load segment checked against the address space, already shifted by the load base
+#[derive(Debug, Clone, Copy)]
+struct LoadSegment {
+    vaddr: u64,
+    end: u64,
+    offset: usize,
+    filesz: usize,
+    begin_map: u64,
+    end_map: u64,
+}
+
 impl Elf64File {
-    pub fn try_parse(file: &File) -> Result<Self, ElfError> {
-        let mut buffer = [0; size_of::<Elf64HeaderRaw>()];
+    /// Reads the headers only, the segments are read from `file` when the image is loaded
+    pub fn try_parse(file: File) -> Result<Self, ElfError> {
+        let size = file.stats()?.size;
+        Self::parse(ElfSource::File(file), size)
+    }
 
-        file.seek(SeekPosition::FromStart(0))?;
-        let size = file.read(&mut buffer)?;
-        if size != size_of::<Elf64HeaderRaw>() as u64 {
+    pub fn from_bytes(contents: Box<[u8]>) -> Result<Self, ElfError> {
+        let size = contents.len() as u64;
+        Self::parse(ElfSource::Bytes(contents), size)
+    }
+
+    /// Checks the program header table against the file size before reading it
+    fn parse(source: ElfSource, size: u64) -> Result<Self, ElfError> {
+        let mut buffer = [0; size_of::<Elf64HeaderRaw>()];
+        if size < buffer.len() as u64 {
             return Err(ElfError::InvalidElfFile(InvalidElfFileReason::NoHeader));
         }
-
-        let stats = file.stats()?;
-
+        source.read_exact_at(0, &mut buffer)?;
         let header_raw =
-            unsafe { core::ptr::read_volatile(buffer.as_ptr() as *const Elf64HeaderRaw) };
+            unsafe { core::ptr::read_unaligned(buffer.as_ptr() as *const Elf64HeaderRaw) };
         if header_raw.magic != ELF_MAGIC {
             return Err(ElfError::InvalidElfFile(
                 InvalidElfFileReason::InvalidMagic(header_raw.magic),
             ));
         }
-
         let header = Elf64Header::try_from(header_raw)?;
 
-        file.seek(SeekPosition::FromStart(0))?;
+        let count = header.program_header_entry_count as u64;
+        let entry_size = header.program_header_entry_size as u64;
+        let table_end = header
+            .program_header_table_offset
+            .checked_add(count * entry_size);
+        if count != 0
+            && (entry_size < size_of::<Elf64ProgramHeaderRaw>() as u64
+                || table_end.is_none_or(|end| end > size))
+        {
+            return Err(ElfError::InvalidElfFile(
+                InvalidElfFileReason::InvalidField("program headers"),
+            ));
+        }
+        let mut program_headers = Vec::with_capacity(count as usize);
+        let mut raw = [0; size_of::<Elf64ProgramHeaderRaw>()];
+        for index in 0..count {
+            source.read_exact_at(
+                header.program_header_table_offset + index * entry_size,
+                &mut raw,
+            )?;
+            let raw =
+                unsafe { core::ptr::read_unaligned(raw.as_ptr() as *const Elf64ProgramHeaderRaw) };
+            program_headers.push(Elf64ProgramHeader::from(raw));
+        }
 
-        let mut elf_file = Self {
-            contents: alloc_boxed_slice(stats.size as usize),
+        Ok(Self {
+            source,
+            size,
             header,
+            program_headers,
+        })
+    }
+
+    pub fn get_header(&self) -> &Elf64Header {
+        &self.header
+    }
+
+    pub fn iter_program_headers(&self) -> impl Iterator<Item = Elf64ProgramHeader> + '_ {
+        self.program_headers.iter().copied()
+    }
+
+    /// Reads a `T` at `offset`, `None` if it goes past the end
+    fn read_struct<T: Copy>(&self, offset: u64) -> Option<T> {
+        let mut buffer = alloc::vec![0u8; size_of::<T>()];
+        self.source.read_exact_at(offset, &mut buffer).ok()?;
+        Some(unsafe { core::ptr::read_unaligned(buffer.as_ptr() as *const T) })
+    }
+
+    /// Bytes at `[offset, offset + len)`, checked against the file size before allocating
+    fn read_range(&self, offset: u64, len: u64) -> Result<Vec<u8>, ElfError> {
+        if offset.checked_add(len).is_none_or(|end| end > self.size) {
+            return Err(ElfError::SegmentReadError { offset, len });
+        }
+        let mut buffer = alloc::vec![0; len as usize];
+        self.source.read_exact_at(offset, &mut buffer)?;
+        Ok(buffer)
+    }
+
+    fn section_header(&self, idx: usize) -> Option<Elf64SectionHeaderRaw> {
+        let offset = (self.header.section_header_table_offset as usize)
+            .checked_add(idx.checked_mul(self.header.section_header_entry_size as usize)?)?;
+        self.read_struct(offset as u64)
+    }
+
+    /// Section header by name, `None` when the file has no section names
+    pub fn find_section(&self, name: &[u8]) -> Option<Elf64SectionHeaderRaw> {
+        let names =
+            self.section_header(self.header.index_of_section_header_string_table as usize)?;
+        let mut found = alloc::vec![0; name.len() + 1];
+        (0..self.header.section_header_entry_count as usize)
+            .filter_map(|idx| self.section_header(idx))
+            .find(|section| {
+                names
+                    .sh_offset
+                    .checked_add(section.sh_name as u64)
+                    .is_some_and(|offset| self.source.read_exact_at(offset, &mut found).is_ok())
+                    && found[..name.len()] == *name
+                    && found[name.len()] == 0
+            })
+    }
+
+    /// Checks the load segments and shifts them by `base`
+    fn load_segments(&self, base: u64) -> Result<Vec<LoadSegment>, ElfError> {
+        let mut segments: Vec<(usize, LoadSegment)> = Vec::new();
+        let mut total_size = 0u64;
+        let limit = get_kernel_config().exec_max_image_size;
+
+        for (index, ph) in self.iter_program_headers().enumerate() {
+            if ph.segment_type != ElfSegmentType::Load {
+                continue;
+            }
+            let invalid = |reason| ElfError::InvalidSegment { index, reason };
+
+            if ph.p_filesz > ph.p_memsz {
+                return Err(invalid(InvalidSegmentReason::FileSizeAboveMemorySize));
+            }
+            if ph.align > 1
+                && (!ph.align.is_power_of_two() || ph.p_vaddr % ph.align != ph.p_offset % ph.align)
+            {
+                return Err(invalid(InvalidSegmentReason::BadAlignment));
+            }
+
+            let range = base.checked_add(ph.p_vaddr).and_then(|vaddr| {
+                let end = vaddr.checked_add(ph.p_memsz)?;
+                Some((vaddr, end, align_up(end, PAGE_SIZE as u64)))
+            });
+            let Some((vaddr, end, end_map)) = range.filter(|(vaddr, _, end_map)| {
+                *vaddr >= PAGE_SIZE as u64 && *end_map <= PROC_SEGMENTS_TOP && *end_map != 0
+            }) else {
+                return Err(invalid(InvalidSegmentReason::OutsideUserSpace));
+            };
+
+            if let Some((other, _)) = segments
+                .iter()
+                .find(|(_, other)| vaddr < other.end && other.vaddr < end)
+            {
+                return Err(invalid(InvalidSegmentReason::Overlapping(*other)));
+            }
+
+            let begin_map = align_down(vaddr, PAGE_SIZE as u64);
+            total_size += end_map - begin_map;
+            if total_size > limit {
+                return Err(ElfError::ImageTooLarge {
+                    size: total_size,
+                    limit,
+                });
+            }
+
+            let offset = ph.p_offset as usize;
+            let filesz = ph.p_filesz as usize;
+            if offset
+                .checked_add(filesz)
+                .is_none_or(|end| end as u64 > self.size)
+            {
+                return Err(ElfError::InvalidSegmentOffset { offset, filesz });
+            }
+
+            segments.push((
+                index,
+                LoadSegment {
+                    vaddr,
+                    end,
+                    offset,
+                    filesz,
+                    begin_map,
+                    end_map,
+                },
+            ));
+        }
+        Ok(segments.into_iter().map(|(_, segment)| segment).collect())
+    }
+
+    /// Path of the dynamic loader named by PT_INTERP, `None` for static executables
+    pub fn interpreter_path(&self) -> Result<Option<String>, ElfError> {
+        let Some((index, ph)) = self
+            .iter_program_headers()
+            .enumerate()
+            .find(|(_, ph)| ph.segment_type == ElfSegmentType::Interpreter)
+        else {
+            return Ok(None);
         };
+        let invalid = ElfError::InvalidSegment {
+            index,
+            reason: InvalidSegmentReason::InvalidInterpreterPath,
+        };
+        if ph.p_filesz > PAGE_SIZE as u64 {
+            return Err(invalid);
+        }
+        let bytes = self.read_range(ph.p_offset, ph.p_filesz).map_err(|_| {
+            ElfError::InvalidSegmentOffset {
+                offset: ph.p_offset as usize,
+                filesz: ph.p_filesz as usize,
+            }
+        })?;
+        let path = bytes.split(|b| *b == 0).next().unwrap_or_default();
+        match core::str::from_utf8(path) {
+            Ok(path) if path.starts_with('/') => Ok(Some(String::from(path))),
+            _ => Err(invalid),
+        }
+    }
+
+    /// Random load base of a PIE, 0 for executables linked at a fixed address
+    fn choose_load_base(&self) -> Result<u64, ElfError> {
+        self.choose_load_base_avoiding(&[])
+    }
+
+    /// Like `choose_load_base`, keeping the segments out of the `avoid` ranges
+    fn choose_load_base_avoiding(&self, avoid: &[(u64, u64)]) -> Result<u64, ElfError> {
+        match self.header.elf_type {
+            ElfType::Executable => return Ok(0),
+            ElfType::Shared => {}
+            other => return Err(ElfError::UnsupportedType(other)),
+        }
+        let segments = self.load_segments(0)?;
+        let lowest = segments.iter().map(|s| s.begin_map).min().unwrap_or(0);
+        let highest = segments.iter().map(|s| s.end_map).max().unwrap_or(0);
+        let begin = randomize_address(PROC_PIE_BASE_TOP, 0, highest - lowest, avoid)
+            .ok_or(ElfError::AddressSpaceRandomizationFailed)?;
+        Ok(begin - lowest)
+    }
 
-        let size = file.read(&mut elf_file.contents)?;
-        if size != stats.size {
-            return Err(ElfError::InputOutput(VfsError::ShortRead));
+    /// Applies the R_X86_64_RELATIVE relocations of .rela.dyn, the only ones a PIE without
+    /// dynamic linking needs
+    fn apply_relocations(&self, image: &mut ExecutableImage, base: u64) -> Result<(), ElfError> {
+        let Some(section) = self.find_section(b".rela.dyn") else {
+            return Ok(());
+        };
+        if section.sh_type != SHT_RELA || section.sh_entsize != size_of::<Elf64RelaRaw>() as u64 {
+            return Err(ElfError::InvalidRelocationSection);
+        }
+        let begin = section.sh_offset;
+        let end = begin
+            .checked_add(section.sh_size)
+            .filter(|end| *end <= self.size)
+            .ok_or(ElfError::InvalidRelocationSection)?;
+        let count = (end - begin) as usize / size_of::<Elf64RelaRaw>();
+
+        let mut chunk = alloc::vec![0; RELOCATION_CHUNK.min(count) * size_of::<Elf64RelaRaw>()];
+        for first in (0..count).step_by(RELOCATION_CHUNK) {
+            let len = (count - first).min(RELOCATION_CHUNK) * size_of::<Elf64RelaRaw>();
+            self.source.read_exact_at(
+                begin + (first * size_of::<Elf64RelaRaw>()) as u64,
+                &mut chunk[..len],
+            )?;
+            let (relas, _) = chunk[..len].as_chunks::<{ size_of::<Elf64RelaRaw>() }>();
+            for rela in relas {
+                let rela =
+                    unsafe { core::ptr::read_unaligned(rela.as_ptr() as *const Elf64RelaRaw) };
+                Self::apply_relocation(image, base, rela)?;
+            }
         }
+        Ok(())
+    }
 
-        Ok(elf_file)
+    fn apply_relocation(
+        image: &mut ExecutableImage,
+        base: u64,
+        rela: Elf64RelaRaw,
+    ) -> Result<(), ElfError> {
+        let target = rela.r_offset;
+        match rela.r_info as u32 {
+            R_X86_64_NONE => Ok(()),
+            R_X86_64_RELATIVE => {
+                let value = base.wrapping_add_signed(rela.r_addend);
+                image
+                    .write(base.wrapping_add(target), &value.to_le_bytes())
+                    .map_err(|()| ElfError::InvalidRelocationOffset(target))
+            }
+            kind => Err(ElfError::UnsupportedRelocation {
+                offset: target,
+                kind,
+            }),
+        }
     }
 
-    pub fn get_header(&self) -> &Elf64Header {
-        &self.header
+    /// Address of the program headers when a load segment maps them, else a copy for the stack
+    fn program_headers_aux(&self, segments: &[LoadSegment]) -> Option<AuxValue> {
+        let offset = self.header.program_header_table_offset as usize;
+        let size = self.header.program_header_entry_size as usize
+            * self.header.program_header_entry_count as usize;
+        let end = offset.checked_add(size)?;
+        let mapped = segments
+            .iter()
+            .find(|segment| segment.offset <= offset && end <= segment.offset + segment.filesz);
+        match mapped {
+            Some(segment) => Some(AuxValue::Value(
+                segment.vaddr + (offset - segment.offset) as u64,
+            )),
+            None => Some(AuxValue::Bytes(
+                self.read_range(offset as u64, size as u64).ok()?,
+            )),
+        }
     }
 
-    pub fn get_contents(&self) -> &[u8] {
-        &self.contents
+    /// Validates the segments and builds their pages, relocated at `base`
+    pub fn load_image(&self, base: u64) -> Result<ExecutableImage, ElfError> {
+        self.load_image_with(base, true)
     }
 
-    pub fn get_contents_ptr(&self) -> *const u8 {
-        self.contents.as_ptr()
+    /// The relocations of a dynamically linked executable are left to its dynamic loader, which
+    /// relocates itself too
+    fn load_image_with(&self, base: u64, relocate: bool) -> Result<ExecutableImage, ElfError> {
+        let mut image = ExecutableImage::new(base.wrapping_add(self.header.entry_offset));
+
+        // Segments may share their first or last page
+        let segments = self.load_segments(base)?;
+        for segment in segments.iter() {
+            image.allocate(segment.begin_map, segment.end_map);
+            self.read_segment(&mut image, segment)?;
+        }
+
+        if base != 0 && relocate {
+            self.apply_relocations(&mut image, base)?;
+        }
+
+        if let Some(headers) = self.program_headers_aux(&segments) {
+            image.aux.push((AT_PHDR, headers));
+            image.aux.push((
+                AT_PHENT,
+                AuxValue::Value(self.header.program_header_entry_size as u64),
+            ));
+            image.aux.push((
+                AT_PHNUM,
+                AuxValue::Value(self.header.program_header_entry_count as u64),
+            ));
+        }
+        Ok(image)
     }
 
-    pub fn iter_program_headers<'a: 'b, 'b>(&'a self) -> Elf64ProgramHeaderIterator<'b> {
-        Elf64ProgramHeaderIterator::<'b>::new(self)
+    /// Reads the file range of a segment straight into its pages
+    fn read_segment(
+        &self,
+        image: &mut ExecutableImage,
+        segment: &LoadSegment,
+    ) -> Result<(), ElfError> {
+        let mut virt = segment.vaddr;
+        let mut offset = segment.offset as u64;
+        let end = offset + segment.filesz as u64;
+        while offset < end {
+            let page = align_down(virt, PAGE_SIZE as u64);
+            let in_page = (virt - page) as usize;
+            let len = ((end - offset) as usize).min(PAGE_SIZE - in_page);
+            let buffer = image
+                .pages
+                .get_mut(&page)
+                .ok_or(ElfError::InvalidSegmentOffset {
+                    offset: segment.offset,
+                    filesz: segment.filesz,
+                })?;
+            self.source
+                .read_exact_at(offset, &mut buffer[in_page..in_page + len])?;
+            offset += len as u64;
+            virt += len as u64;
+        }
+        Ok(())
     }
-}
 
-pub struct Elf64ProgramHeaderIterator<'a> {
-    elf: &'a Elf64File,
-    idx: usize,
-}
+    /// The image of the process: the executable alone when static, else the executable and its
+    /// dynamic loader, which is started first
+    pub fn load_process_image(&self) -> Result<ExecutableImage, ElfError> {
+        let Some(path) = self.interpreter_path()? else {
+            return self.load_image(self.choose_load_base()?);
+        };
+        let mut image = self.load_image_with(self.choose_load_base()?, false)?;
+        let (base, interpreter) =
+            Self::load_interpreter(&path, &image.ranges()).map_err(|error| {
+                ElfError::Interpreter {
+                    path,
+                    error: Box::new(error),
+                }
+            })?;
 
-impl<'a> Elf64ProgramHeaderIterator<'a> {
-    fn new(elf: &'a Elf64File) -> Self {
-        Self { elf, idx: 0 }
+        image.aux.push((AT_BASE, AuxValue::Value(base)));
+        image.aux.push((AT_ENTRY, AuxValue::Value(image.entry)));
+        image.entry = interpreter.entry;
+        image.pages.extend(interpreter.pages);
+        Ok(image)
     }
-}
 
-impl<'a> Iterator for Elf64ProgramHeaderIterator<'a> {
-    type Item = Elf64ProgramHeader;
+    /// Loads the dynamic loader at a random base, away from the `taken` ranges
+    fn load_interpreter(
+        path: &str,
+        taken: &[(u64, u64)],
+    ) -> Result<(u64, ExecutableImage), ElfError> {
+        let file = File::open(
+            path,
+            OPEN_MODE_READ,
+            Permissions::from_u64(0),
+            Credentials::Kernel,
+        )?;
+        let interpreter = Self::try_parse(file)?;
+
+        if interpreter.header.elf_type != ElfType::Shared {
+            return Err(ElfError::UnsupportedType(interpreter.header.elf_type));
+        }
+        if interpreter.interpreter_path()?.is_some() {
+            return Err(ElfError::NestedInterpreter);
+        }
+        let base = interpreter.choose_load_base_avoiding(taken)?;
+        Ok((base, interpreter.load_image_with(base, false)?))
+    }
+}
 
-    fn next(&mut self) -> Option<Self::Item> {
-        if self.idx >= self.elf.header.program_header_entry_count as usize {
-            None
-        } else {
-            let ptr = self.elf.header.program_header_table_offset as usize
-                + self.idx * self.elf.header.program_header_entry_size as usize;
-            if ptr >= self.elf.contents.len()
-                || ptr.wrapping_add(size_of::<Elf64ProgramHeaderRaw>()) > self.elf.contents.len()
-                || ptr.wrapping_add(size_of::<Elf64ProgramHeaderRaw>()) <= ptr
-            {
-                return None;
+impl ElfSource {
+    /// Fills `buf` from `offset`, a short read is a `SegmentReadError`
+    fn read_exact_at(&self, offset: u64, buf: &mut [u8]) -> Result<(), ElfError> {
+        let short = ElfError::SegmentReadError {
+            offset,
+            len: buf.len() as u64,
+        };
+        match self {
+            ElfSource::Bytes(contents) => {
+                let bytes = usize::try_from(offset)
+                    .ok()
+                    .and_then(|begin| contents.get(begin..begin.checked_add(buf.len())?))
+                    .ok_or(short)?;
+                buf.copy_from_slice(bytes);
             }
-            self.idx += 1;
-            unsafe {
-                let addr = self.elf.contents.as_ptr().add(ptr) as *const Elf64ProgramHeaderRaw;
-                let value_raw = core::ptr::read_volatile(addr);
-                Some(Elf64ProgramHeader::from(value_raw))
+            ElfSource::File(file) => {
+                let mut done = 0;
+                while done < buf.len() {
+                    match file.read_at(offset + done as u64, &mut buf[done..])? {
+                        0 => return Err(short),
+                        read => done += read as usize,
+                    }
+                }
             }
         }
+        Ok(())
     }
 }
 
@@ -433,6 +893,17 @@ impl From<ElfError> for Box<dyn Debug> {
     }
 }
 
+/// Value of an aux vector entry
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AuxValue {
+    Value(u64),
+    /// Copied on the stack, 16 bytes aligned, the entry holds its address
+    Bytes(Vec<u8>),
+}
+
+/// Alignment of the `AuxValue::Bytes` copies
+const AUX_DATA_ALIGN: usize = 16;
+
 /// Build the stack layout as requested.
 pub fn build_stack(
     stack_top: u64,
@@ -440,7 +911,7 @@ pub fn build_stack(
     flags: u64,
     args: &[String],
     env: &[String],
-    aux: &[(u64, u64)],
+    aux: &[(u64, AuxValue)],
 ) -> (ThreadStack, u64, u64, u64) {
     // Compute total size
     let argc_size = size_of::<u64>();
@@ -451,9 +922,22 @@ pub fn build_stack(
 
     let args_data_size: usize = args.iter().map(|s| s.len() + 1).sum();
     let env_data_size: usize = env.iter().map(|s| s.len() + 1).sum();
+    let aux_data_size: usize = aux
+        .iter()
+        .map(|(_, value)| match value {
+            AuxValue::Value(_) => 0,
+            AuxValue::Bytes(bytes) => bytes.len().next_multiple_of(AUX_DATA_ALIGN),
+        })
+        .sum::<usize>()
+        + AUX_DATA_ALIGN;
 
-    let total_size =
-        argc_size + argv_ptrs_size + envp_ptrs_size + auxv_size + args_data_size + env_data_size;
+    let total_size = argc_size
+        + argv_ptrs_size
+        + envp_ptrs_size
+        + auxv_size
+        + args_data_size
+        + env_data_size
+        + aux_data_size;
 
     // Compute page count
     let num_pages = total_size.div_ceil(PAGE_SIZE);
@@ -523,16 +1007,29 @@ pub fn build_stack(
     // envp null
     write_u64(&mut pages, tmp_idx - stack_bottom, 0);
 
-    // fill auxv entries
+    // fill auxv entries, their data goes after the strings
+    idx = idx.next_multiple_of(AUX_DATA_ALIGN);
     tmp_idx = auxv_ptr;
-    for &(key, val) in aux.iter() {
-        write_u64(&mut pages, tmp_idx - stack_bottom, key);
+    for (key, value) in aux.iter() {
+        let val = match value {
+            AuxValue::Value(val) => *val,
+            AuxValue::Bytes(bytes) => {
+                let addr = stack_bottom + idx;
+                for b in bytes {
+                    write_byte(&mut pages, idx, *b);
+                    idx += 1;
+                }
+                idx = idx.next_multiple_of(AUX_DATA_ALIGN);
+                addr as u64
+            }
+        };
+        write_u64(&mut pages, tmp_idx - stack_bottom, *key);
         tmp_idx += size_of::<u64>();
         write_u64(&mut pages, tmp_idx - stack_bottom, val);
         tmp_idx += size_of::<u64>();
     }
     // auxv null
-    write_u64(&mut pages, tmp_idx - stack_bottom, 0);
+    write_u64(&mut pages, tmp_idx - stack_bottom, AT_NULL);
     tmp_idx += size_of::<u64>();
     write_u64(&mut pages, tmp_idx - stack_bottom, 0);
 
@@ -569,130 +1066,6 @@ impl ExecutableFileFormat for Elf64File {
         &self,
         options: ExecutableInstantiateOptions,
     ) -> Result<CreateProcessOptions, Box<dyn Debug>> {
-        let ExecutableInstantiateOptions {
-            cmdline,
-            cwd,
-            environment,
-            gid,
-            name,
-            supplementary_gids,
-            uid,
-        } = options;
-
-        let mut pt = PageTable::alloc_new().ok_or(ElfError::InvalidPageTableAllocation)?;
-
-        pt.map_global_higher_half();
-
-        let mut allocated_code = Vec::new();
-
-        for ph in self.iter_program_headers() {
-            if ph.segment_type != ElfSegmentType::Load {
-                continue;
-            }
-
-            let offset = ph.p_offset as usize;
-            let filesz = ph.p_filesz as usize;
-
-            let end_code = ph.p_vaddr + ph.p_filesz;
-
-            let segment_data = self
-                .contents
-                .get(offset..offset + filesz)
-                .ok_or(ElfError::InvalidSegmentOffset { offset, filesz })?;
-
-            let begin_map = align_down(ph.p_vaddr, PAGE_SIZE as u64);
-            let end_map = align_up(ph.p_vaddr + ph.p_memsz, PAGE_SIZE as u64);
-
-            let mut code_i = 0;
-
-            for virt in (begin_map..end_map).step_by(PAGE_SIZE) {
-                let mut buffer = alloc_boxed_slice(PAGE_SIZE);
-                if virt < ph.p_vaddr {
-                    let zeros = (ph.p_vaddr - virt) as usize;
-                    let rem = (PAGE_SIZE - zeros).min(filesz - code_i);
-                    buffer[0..zeros].fill(0);
-                    if zeros + rem < PAGE_SIZE {
-                        buffer[zeros + rem..].fill(0);
-                    }
-                    buffer[zeros..zeros + rem].copy_from_slice(&segment_data[code_i..code_i + rem]);
-                    code_i += rem;
-                } else if virt + PAGE_SIZE as u64 >= end_code {
-                    let rem = filesz - code_i;
-                    buffer[0..rem].copy_from_slice(&segment_data[code_i..]);
-                    code_i += rem;
-                    buffer[rem..].fill(0);
-                } else if code_i >= filesz {
-                    buffer.fill(0);
-                    code_i += PAGE_SIZE;
-                } else {
-                    let rem = (filesz - code_i).min(PAGE_SIZE);
-                    buffer[0..rem].copy_from_slice(&segment_data[code_i..(code_i + rem)]);
-                    code_i += rem;
-                }
-
-                let flags = PAGE_USER | PAGE_ACCESSED | PAGE_RW | PAGE_PRESENT;
-
-                let phys = buffer.as_ptr() as u64 - DIRECT_MAPPING_OFFSET;
-                unsafe {
-                    pt.map_4kb(virt, phys, flags, false);
-                }
-
-                allocated_code.push((virt, buffer));
-            }
-        }
-
-        let stack_top: u64 = 0x0000_8000_0000_0000;
-
-        let (mut s, rsp, argv, envp) = build_stack(
-            stack_top,
-            &mut pt,
-            PAGE_ACCESSED | PAGE_USER | PAGE_RW | PAGE_PRESENT,
-            &cmdline,
-            &environment,
-            &[],
-        );
-        s.grow(&mut pt, PAGE_ACCESSED | PAGE_USER | PAGE_RW | PAGE_PRESENT);
-
-        Ok(CreateProcessOptions {
-            name,
-            cmdline: cmdline.to_vec(),
-            cwd,
-            uid,
-            gid,
-            supplementary_gids,
-            page_table: pt,
-            main_thread_state: ThreadState {
-                gpregs: ThreadGPRegisters {
-                    rdi: cmdline.len() as u64, // arg0 = argc
-                    rsi: argv,                 // arg1 =argv
-                    rdx: envp,                 // arg2 = envp
-                    rax: 0,
-                    rbx: 0,
-                    rcx: 0,
-                    r8: 0,
-                    r9: 0,
-                    r10: 0,
-                    r11: 0,
-                    r12: 0,
-                    r13: 0,
-                    r14: 0,
-                    r15: 0,
-                },
-                rip: self.header.entry_offset,
-                rbp: 0,
-                rsp,
-                rflags: RFlags::empty()
-                    .set(RFlag::InterruptFlag)
-                    .set(RFlag::IOPL3)
-                    .get(),
-                fs_base: 0,
-                gs_base: 0,
-            },
-            allocated_code: ProcessAllocatedCode {
-                allocs: allocated_code,
-            },
-            syscalls: ProcessSyscallABI::Linux,
-            main_thread_stack: s,
-        })
+        Ok(self.load_process_image()?.instantiate(options)?)
     }
 }
@@ -0,0 +1,142 @@
+//! A raw binary loaded as is at a fixed address, for tiny test programs.
+//! The whole file, header included, is mapped at `load_address`
+
+use core::fmt::Debug;
+
+use alloc::boxed::Box;
+
+use crate::{
+    config::get_kernel_config,
+    data::{file::File, try_alloc_boxed_slice_or_reclaim},
+    drivers::vfs::{shrink_file_system_caches, SeekPosition, VfsError},
+    paging::{align_up, PAGE_SIZE},
+    process::{
+        executable::{ExecutableFileFormat, ExecutableImage, ExecutableInstantiateOptions},
+        memory::PROC_SEGMENTS_TOP,
+        scheduler::CreateProcessOptions,
+    },
+};
+
+pub const FLAT_MAGIC: [u8; 8] = *b"CPXFLAT\0";
+
+#[repr(C, packed)]
+#[derive(Debug, Clone, Copy)]
+pub struct FlatHeader {
+    pub magic: [u8; 8],
+    /// Page aligned
+    pub load_address: u64,
+    /// From `load_address`, past the header
+    pub entry_offset: u64,
+}
+
+#[derive(Debug)]
+pub enum FlatError {
+    InputOutput(VfsError),
+    NoHeader,
+    InvalidMagic([u8; 8]),
+    /// Not page aligned, or the file doesn't fit below the segments top
+    InvalidLoadAddress(u64),
+    InvalidEntryOffset(u64),
+    ImageTooLarge {
+        size: u64,
+        limit: u64,
+    },
+}
+
+impl From<VfsError> for FlatError {
+    fn from(value: VfsError) -> Self {
+        FlatError::InputOutput(value)
+    }
+}
+
+impl From<FlatError> for Box<dyn Debug> {
+    fn from(value: FlatError) -> Self {
+        Box::new(value)
+    }
+}
+
+#[derive(Debug)]
+pub struct FlatBinary {
+    contents: Box<[u8]>,
+    header: FlatHeader,
+}
+
+impl FlatBinary {
+    pub fn try_parse(file: &File) -> Result<Self, FlatError> {
+        let stats = file.stats()?;
+        let limit = get_kernel_config().exec_max_image_size;
+        if stats.size > limit {
+            return Err(FlatError::ImageTooLarge {
+                size: stats.size,
+                limit,
+            });
+        }
+
+        file.seek(SeekPosition::FromStart(0))?;
+        let mut contents =
+            try_alloc_boxed_slice_or_reclaim(stats.size as usize, shrink_file_system_caches)
+                .ok_or(VfsError::OutOfMemory)?;
+        let size = file.read(&mut contents)?;
+        if size != stats.size {
+            return Err(FlatError::InputOutput(VfsError::ShortRead));
+        }
+
+        Self::from_bytes(contents)
+    }
+
+    pub fn from_bytes(contents: Box<[u8]>) -> Result<Self, FlatError> {
+        let header = contents
+            .get(..size_of::<FlatHeader>())
+            .map(|bytes| unsafe { core::ptr::read_unaligned(bytes.as_ptr() as *const FlatHeader) })
+            .ok_or(FlatError::NoHeader)?;
+        if header.magic != FLAT_MAGIC {
+            return Err(FlatError::InvalidMagic(header.magic));
+        }
+
+        let size = contents.len() as u64;
+        let limit = get_kernel_config().exec_max_image_size;
+        if size > limit {
+            return Err(FlatError::ImageTooLarge { size, limit });
+        }
+
+        let load_address = header.load_address;
+        if load_address % PAGE_SIZE as u64 != 0
+            || load_address < PAGE_SIZE as u64
+            || load_address
+                .checked_add(align_up(size, PAGE_SIZE as u64))
+                .is_none_or(|end| end > PROC_SEGMENTS_TOP)
+        {
+            return Err(FlatError::InvalidLoadAddress(load_address));
+        }
+
+        let entry_offset = header.entry_offset;
+        if entry_offset < size_of::<FlatHeader>() as u64 || entry_offset >= size {
+            return Err(FlatError::InvalidEntryOffset(entry_offset));
+        }
+
+        Ok(Self { contents, header })
+    }
+
+    pub fn get_header(&self) -> &FlatHeader {
+        &self.header
+    }
+
+    pub fn load_image(&self) -> ExecutableImage {
+        let load_address = self.header.load_address;
+        let mut image = ExecutableImage::new(load_address + self.header.entry_offset);
+        image.allocate(load_address, load_address + self.contents.len() as u64);
+        image
+            .write(load_address, &self.contents)
+            .expect("Flat binary pages were just allocated");
+        image
+    }
+}
+
+impl ExecutableFileFormat for FlatBinary {
+    fn create_process(
+        &self,
+        options: ExecutableInstantiateOptions,
+    ) -> Result<CreateProcessOptions, Box<dyn Debug>> {
+        Ok(self.load_image().instantiate(options)?)
+    }
+}
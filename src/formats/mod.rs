@@ -1 +1,4 @@
+pub mod cpio;
 pub mod elf;
+pub mod flat;
+pub mod script;
@@ -0,0 +1,142 @@
+//! `#!` interpreter scripts, executed by passing their path to the interpreter
+
+use core::fmt::Debug;
+
+use alloc::{
+    boxed::Box,
+    string::{String, ToString},
+    vec::Vec,
+};
+
+use crate::{
+    data::{file::File, permissions::Credentials},
+    drivers::vfs::{SeekPosition, VfsError},
+    process::{
+        executable::{
+            parse_executable_nested, ExecutableFileFormat, ExecutableInstantiateOptions,
+            MAX_SCRIPT_DEPTH,
+        },
+        scheduler::CreateProcessOptions,
+    },
+};
+
+pub const SCRIPT_MAGIC: &[u8] = b"#!";
+
+/// Longest `#!` line read, like linux's BINPRM_BUF_SIZE
+pub const MAX_SHEBANG_LEN: usize = 256;
+
+#[derive(Debug)]
+pub enum ScriptError {
+    InputOutput(VfsError),
+    InvalidMagic,
+    /// No newline in the first `MAX_SHEBANG_LEN` bytes
+    LineTooLong,
+    NoInterpreter,
+    NotUtf8,
+    /// The interpreter is itself a script, more than `MAX_SCRIPT_DEPTH` times
+    NestingTooDeep,
+}
+
+impl From<VfsError> for ScriptError {
+    fn from(value: VfsError) -> Self {
+        ScriptError::InputOutput(value)
+    }
+}
+
+impl From<ScriptError> for Box<dyn Debug> {
+    fn from(value: ScriptError) -> Self {
+        Box::new(value)
+    }
+}
+
+/// Splits the `#!` line of `contents` into the interpreter and its optional argument.
+/// Like linux, everything after the interpreter is a single argument
+pub fn parse_shebang(contents: &[u8]) -> Result<(String, Option<String>), ScriptError> {
+    let line = contents
+        .strip_prefix(SCRIPT_MAGIC)
+        .ok_or(ScriptError::InvalidMagic)?;
+    let line = match line.iter().position(|b| *b == b'\n') {
+        Some(end) => &line[..end],
+        None if contents.len() < MAX_SHEBANG_LEN => line,
+        None => return Err(ScriptError::LineTooLong),
+    };
+    let line = core::str::from_utf8(line).map_err(|_| ScriptError::NotUtf8)?;
+
+    let line = line.trim_matches([' ', '\t', '\r']);
+    let (interpreter, argument) = match line.find([' ', '\t']) {
+        Some(split) => (
+            &line[..split],
+            Some(line[split..].trim_matches([' ', '\t'])),
+        ),
+        None => (line, None),
+    };
+    if interpreter.is_empty() {
+        return Err(ScriptError::NoInterpreter);
+    }
+    Ok((
+        interpreter.to_string(),
+        argument.filter(|arg| !arg.is_empty()).map(str::to_string),
+    ))
+}
+
+#[derive(Debug)]
+pub struct ScriptExecutable {
+    path: String,
+    interpreter_path: String,
+    argument: Option<String>,
+    interpreter: Box<dyn ExecutableFileFormat>,
+}
+
+impl ScriptExecutable {
+    /// `depth` is the number of scripts already followed to reach `path`
+    pub fn try_parse(
+        file: &File,
+        path: &str,
+        credentials: Credentials,
+        depth: usize,
+    ) -> Result<Self, Vec<Box<dyn Debug>>> {
+        let mut buffer = [0; MAX_SHEBANG_LEN];
+        let size = file
+            .seek(SeekPosition::FromStart(0))
+            .and_then(|_| file.read(&mut buffer))
+            .map_err(|e| alloc::vec![ScriptError::from(e).into()])?;
+        let (interpreter_path, argument) =
+            parse_shebang(&buffer[..size as usize]).map_err(|e| alloc::vec![e.into()])?;
+
+        if depth >= MAX_SCRIPT_DEPTH {
+            return Err(alloc::vec![ScriptError::NestingTooDeep.into()]);
+        }
+        let interpreter = parse_executable_nested(&interpreter_path, credentials, depth + 1)?;
+
+        Ok(Self {
+            path: path.to_string(),
+            interpreter_path,
+            argument,
+            interpreter,
+        })
+    }
+
+    pub fn get_interpreter_path(&self) -> &str {
+        &self.interpreter_path
+    }
+
+    pub fn get_argument(&self) -> Option<&str> {
+        self.argument.as_deref()
+    }
+}
+
+impl ExecutableFileFormat for ScriptExecutable {
+    /// argv becomes `interpreter [argument] script argv[1..]`
+    fn create_process(
+        &self,
+        mut options: ExecutableInstantiateOptions,
+    ) -> Result<CreateProcessOptions, Box<dyn Debug>> {
+        let mut cmdline = Vec::with_capacity(options.cmdline.len() + 2);
+        cmdline.push(self.interpreter_path.clone());
+        cmdline.extend(self.argument.clone());
+        cmdline.push(self.path.clone());
+        cmdline.extend(options.cmdline.drain(..).skip(1));
+        options.cmdline = cmdline;
+        self.interpreter.create_process(options)
+    }
+}
@@ -8,7 +8,7 @@ use dc_access::{
 use flags::{GRANULARITY_4KB, IS_32BIT, LONG_MODE};
 
 use crate::{
-    println,
+    log_debug,
     process::task::{get_tss_addr, RawTaskStateSegment},
 };
 
@@ -294,27 +294,25 @@ pub(crate) unsafe fn init_gdtr() {
         );
     }
 
-    println!("Kernel GDT at {:#016x}", GDT.0 .0.as_ptr() as u64);
+    log_debug!(target: "gdt", "Kernel GDT at {:#016x}", GDT.0 .0.as_ptr() as u64);
     for i in 0..GDT.0 .0.len() {
-        println!("  Descriptor #{}: {:016x}", i, GDT.0 .0[i].into());
+        log_debug!(target: "gdt", "  Descriptor #{}: {:016x}", i, GDT.0 .0[i].into());
     }
-    println!("GDTR at {:#016x}", addr_of!(GDTR) as u64);
-
-    println!("Kernel code selector: {:#x}", KERNEL_CODE_SELECTOR);
-    println!("Kernel data selector: {:#x}", KERNEL_DATA_SELECTOR);
-    println!("Userland code32 selector: {:#x}", USERLAND_CODE32_SELECTOR);
-    println!("Userland data32 selector: {:#x}", USERLAND_DATA32_SELECTOR);
-    println!("Userland code64 selector: {:#x}", USERLAND_CODE64_SELECTOR);
-    println!("Userland data64 selector: {:#x}", USERLAND_DATA64_SELECTOR);
-    println!("TSS selector: {:#x}", TSS_SELECTOR);
-
-    println!("Kernel TSS at {:#016x}", get_tss_addr());
-    println!("TSS entry: {:#032x}", unsafe {
+    log_debug!(target: "gdt", "GDTR at {:#016x}", addr_of!(GDTR) as u64);
+
+    log_debug!(target: "gdt", "Kernel code selector: {:#x}", KERNEL_CODE_SELECTOR);
+    log_debug!(target: "gdt", "Kernel data selector: {:#x}", KERNEL_DATA_SELECTOR);
+    log_debug!(target: "gdt", "Userland code32 selector: {:#x}", USERLAND_CODE32_SELECTOR);
+    log_debug!(target: "gdt", "Userland data32 selector: {:#x}", USERLAND_DATA32_SELECTOR);
+    log_debug!(target: "gdt", "Userland code64 selector: {:#x}", USERLAND_CODE64_SELECTOR);
+    log_debug!(target: "gdt", "Userland data64 selector: {:#x}", USERLAND_DATA64_SELECTOR);
+    log_debug!(target: "gdt", "TSS selector: {:#x}", TSS_SELECTOR);
+
+    log_debug!(target: "gdt", "Kernel TSS at {:#016x}", get_tss_addr());
+    log_debug!(target: "gdt", "TSS entry: {:#032x}", unsafe {
         core::ptr::read_unaligned(&GDT.0 .1 as *const TssEntry as *const u128)
     });
 
-    println!();
-
     asm!("lgdt [{}]", in(reg) &GDTR, options(readonly, nostack, preserves_flags));
     asm!(
         "ltr {0:x}",
@@ -0,0 +1,106 @@
+//! Local APIC of the boot CPU, only what MSI delivery needs. The legacy PIC stays in use for the
+//! timer and keyboard, through the LINT0 virtual wire
+
+use core::{
+    arch::x86_64::__cpuid,
+    sync::atomic::{AtomicU64, AtomicU8, Ordering},
+};
+
+use crate::{
+    data::regs::msr::{rdmsr, wrmsr, IA32_APIC_BASE},
+    log_info, log_warn,
+    paging::{
+        get_kernel_page_table, physical_to_virtual, DIRECT_MAPPING_OFFSET, PAGE_ACCESSED,
+        PAGE_CACHE_DISABLE, PAGE_NO_EXECUTE, PAGE_PRESENT, PAGE_RW,
+    },
+};
+
+const CPUID_1_EDX_APIC: u32 = 1 << 9;
+
+const APIC_BASE_ENABLE: u64 = 1 << 11;
+const APIC_BASE_ADDRESS_MASK: u64 = 0x000F_FFFF_FFFF_F000;
+
+const APIC_ID: u64 = 0x20;
+const APIC_EOI: u64 = 0xB0;
+const APIC_SPURIOUS: u64 = 0xF0;
+const APIC_LVT_LINT0: u64 = 0x350;
+const APIC_LVT_LINT1: u64 = 0x360;
+
+const APIC_SOFTWARE_ENABLE: u32 = 1 << 8;
+const APIC_DELIVERY_EXTINT: u32 = 0b111 << 8;
+const APIC_DELIVERY_NMI: u32 = 0b100 << 8;
+
+/// Vector of the interrupts the APIC raises when the one it signaled went away
+pub const APIC_SPURIOUS_VECTOR: u8 = 0xFF;
+
+/// Virtual address of the registers, 0 until `init` found an APIC
+static APIC_REGISTERS: AtomicU64 = AtomicU64::new(0);
+static BOOT_CPU_APIC_ID: AtomicU8 = AtomicU8::new(0);
+
+fn read_register(offset: u64) -> u32 {
+    let base = APIC_REGISTERS.load(Ordering::Relaxed);
+    unsafe { core::ptr::read_volatile((base + offset) as *const u32) }
+}
+
+fn write_register(offset: u64, value: u32) {
+    let base = APIC_REGISTERS.load(Ordering::Relaxed);
+    unsafe { core::ptr::write_volatile((base + offset) as *mut u32, value) }
+}
+
+/// Maps and software enables the local APIC, keeping the PIC connected to LINT0
+pub fn init() {
+    #[allow(unused_unsafe)]
+    let leaf = unsafe { __cpuid(1) };
+    if leaf.edx & CPUID_1_EDX_APIC == 0 {
+        log_warn!(target: "apic", "No local APIC, MSI is unavailable");
+        return;
+    }
+
+    let base = unsafe { rdmsr(IA32_APIC_BASE) };
+    let phys = base & APIC_BASE_ADDRESS_MASK;
+    unsafe { wrmsr(IA32_APIC_BASE, base | APIC_BASE_ENABLE) };
+
+    let virt = phys + DIRECT_MAPPING_OFFSET;
+    let mut page_table = get_kernel_page_table().lock();
+    if page_table.translate(virt).is_none() {
+        unsafe {
+            page_table.map_4kb(
+                virt,
+                phys,
+                PAGE_PRESENT | PAGE_RW | PAGE_ACCESSED | PAGE_CACHE_DISABLE | PAGE_NO_EXECUTE,
+                true,
+            )
+        };
+    }
+    drop(page_table);
+    APIC_REGISTERS.store(physical_to_virtual(phys), Ordering::Relaxed);
+
+    write_register(APIC_LVT_LINT0, APIC_DELIVERY_EXTINT);
+    write_register(APIC_LVT_LINT1, APIC_DELIVERY_NMI);
+    write_register(
+        APIC_SPURIOUS,
+        APIC_SOFTWARE_ENABLE | APIC_SPURIOUS_VECTOR as u32,
+    );
+    BOOT_CPU_APIC_ID.store(id(), Ordering::Relaxed);
+    log_info!(target: "apic", "Local APIC {} enabled at {:#x}", id(), phys);
+}
+
+pub fn is_enabled() -> bool {
+    APIC_REGISTERS.load(Ordering::Relaxed) != 0
+}
+
+/// APIC id of the CPU running this
+pub fn id() -> u8 {
+    (read_register(APIC_ID) >> 24) as u8
+}
+
+pub fn boot_cpu_id() -> u8 {
+    BOOT_CPU_APIC_ID.load(Ordering::Relaxed)
+}
+
+/// Ends the interrupt being handled, for interrupts delivered by the APIC itself like MSIs
+pub fn send_eoi() {
+    if is_enabled() {
+        write_register(APIC_EOI, 0);
+    }
+}
@@ -0,0 +1,47 @@
+use crate::{
+    data::regs::rflags::RFlag,
+    interrupts::{
+        handlers::syscall::linux::SIGTRAP,
+        idt::{InterruptFrameContext, InterruptFrameExtra, InterruptFrameRegisters},
+    },
+    percpu::{get_per_cpu, InterruptSource},
+    println,
+    process::scheduler::{ProcThreadInfo, SCHEDULER},
+};
+
+/// Single step of a user thread, it stops with SIGTRAP for its tracer
+pub fn handler(
+    _interrupt_num: u64,
+    rsp: u64,
+    ifr: &mut InterruptFrameRegisters,
+    ifc: &mut InterruptFrameContext,
+    ife: Option<&mut InterruptFrameExtra>,
+) {
+    let per_cpu = get_per_cpu();
+    if let (Some(InterruptSource::User), Some(thread)) =
+        (per_cpu.interrupt_sources.last(), &per_cpu.running_thread)
+    {
+        let thread = thread.clone();
+        // PTRACE_SINGLESTEP sets it again for the next step
+        thread.thread.state.lock().rflags &= !(RFlag::TrapFlag as u64);
+        trap_user_thread(&thread);
+    }
+
+    println!("Debug exception.");
+
+    println!("rsp = {:#016x}", rsp);
+    println!("{:#?}", ifr);
+    println!("{:#?}", ifc);
+    println!("{:#?}", ife);
+
+    panic!("Debug exception dump complete.");
+}
+
+/// Stops a traced thread, otherwise SIGTRAP terminates its process
+pub fn trap_user_thread(thread: &ProcThreadInfo) -> ! {
+    if thread.thread.trace.lock().tracer.is_some() {
+        SCHEDULER.stop_current(thread, SIGTRAP)
+    }
+    SCHEDULER.signal_process(thread.pid, SIGTRAP);
+    SCHEDULER.schedule()
+}
@@ -0,0 +1,35 @@
+use crate::{
+    interrupts::{
+        handlers::exception::exc_1_debug::trap_user_thread,
+        idt::{InterruptFrameContext, InterruptFrameExtra, InterruptFrameRegisters},
+    },
+    percpu::{get_per_cpu, InterruptSource},
+    println,
+};
+
+/// `int3` of a user thread, it stops with SIGTRAP for its tracer and resumes after the
+/// instruction
+pub fn handler(
+    _interrupt_num: u64,
+    rsp: u64,
+    ifr: &mut InterruptFrameRegisters,
+    ifc: &mut InterruptFrameContext,
+    ife: Option<&mut InterruptFrameExtra>,
+) {
+    let per_cpu = get_per_cpu();
+    if let (Some(InterruptSource::User), Some(thread)) =
+        (per_cpu.interrupt_sources.last(), &per_cpu.running_thread)
+    {
+        let thread = thread.clone();
+        trap_user_thread(&thread);
+    }
+
+    println!("Breakpoint exception.");
+
+    println!("rsp = {:#016x}", rsp);
+    println!("{:#?}", ifr);
+    println!("{:#?}", ifc);
+    println!("{:#?}", ife);
+
+    panic!("Breakpoint exception dump complete.");
+}
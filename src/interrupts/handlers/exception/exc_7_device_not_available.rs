@@ -0,0 +1,33 @@
+use crate::{
+    interrupts::idt::{InterruptFrameContext, InterruptFrameExtra, InterruptFrameRegisters},
+    percpu::{get_per_cpu, InterruptSource},
+    println,
+    process::fpu::take_fpu,
+};
+
+/// First FPU instruction of a thread that doesn't own the FPU registers
+pub fn handler(
+    _interrupt_num: u64,
+    rsp: u64,
+    ifr: &mut InterruptFrameRegisters,
+    ifc: &mut InterruptFrameContext,
+    ife: Option<&mut InterruptFrameExtra>,
+) {
+    let per_cpu = get_per_cpu();
+    if let (Some(InterruptSource::User), Some(thread)) =
+        (per_cpu.interrupt_sources.last(), &per_cpu.running_thread)
+    {
+        let thread = thread.thread.clone();
+        take_fpu(&thread);
+        return;
+    }
+
+    println!("Device not available exception, the kernel used the FPU.");
+
+    println!("rsp = {:#016x}", rsp);
+    println!("{:#?}", ifr);
+    println!("{:#?}", ifc);
+    println!("{:#?}", ife);
+
+    panic!("Device not available exception dump complete.");
+}
@@ -0,0 +1,36 @@
+use crate::{
+    data::regs::cr::{Cr2, Cr3},
+    interrupts::idt::{InterruptFrameContext, InterruptFrameExtra, InterruptFrameRegisters},
+    println_irq,
+    process::memory::get_address_space,
+};
+
+/// Runs on its own IST stack, so it also gets here when the fault was caused by a broken kernel stack
+pub fn handler(
+    _interrupt_num: u64,
+    rsp: u64,
+    ifr: &mut InterruptFrameRegisters,
+    ifc: &mut InterruptFrameContext,
+    _ife: Option<&mut InterruptFrameExtra>,
+) {
+    // Long mode always pushes ss:rsp, not only on privilege changes
+    let faulting = unsafe {
+        core::ptr::read(
+            (rsp + size_of::<InterruptFrameContext>() as u64) as *const InterruptFrameExtra,
+        )
+    };
+    let faulting_rsp = faulting.rsp;
+
+    println_irq!("Double fault.");
+    println_irq!(
+        "Faulting rsp = {:#016x} in {:?}",
+        faulting_rsp,
+        get_address_space(faulting_rsp)
+    );
+    println_irq!("cr2 = {:#016x}", unsafe { Cr2::read() });
+    println_irq!("cr3 = {:#016x}", unsafe { Cr3::read() });
+    println_irq!("{:#?}", ifr);
+    println_irq!("{:#?}", ifc);
+
+    panic!("Double fault dump complete.");
+}
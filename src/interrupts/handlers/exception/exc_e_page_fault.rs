@@ -3,11 +3,12 @@ use crate::{
     interrupts::idt::{InterruptFrameContext, InterruptFrameExtra, InterruptFrameRegisters},
     paging::{PAGE_ACCESSED, PAGE_PRESENT, PAGE_RW, PAGE_SIZE, PAGE_USER},
     percpu::get_per_cpu,
-    printf, println,
+    printf, println_irq,
     process::{
+        limits::Resource,
         memory::{
             get_address_space, HigherHalfAddressSpace, LowerHalfAddressSpace, VirtualAddressSpace,
-            PROC_KERNEL_STACK_TOP, PROC_USER_STACK_TOP,
+            KERNEL_STACK_PATTERN,
         },
         scheduler::SCHEDULER,
     },
@@ -40,13 +41,13 @@ pub fn handler(
 
         macro_rules! print_info0 {
             () => {
-                println!("Page fault addr={:#016x} in {:?}", fault_addr, space);
-                println!("cr3 = {:#016x}", cr3);
-                println!("rsp = {:#016x}", rsp);
-                println!("{:#?}", ifr);
-                println!("{:#?}", ifc);
-                println!("{:#?}", ife);
-                println!("{:#?}", per_cpu);
+                println_irq!("Page fault addr={:#016x} in {:?}", fault_addr, space);
+                println_irq!("cr3 = {:#016x}", cr3);
+                println_irq!("rsp = {:#016x}", rsp);
+                println_irq!("{:#?}", ifr);
+                println_irq!("{:#?}", ifc);
+                println_irq!("{:#?}", ife);
+                println_irq!("{:#?}", per_cpu);
 
                 printf!("Error code: {:#b} -- ", ifc.exception_error_code);
                 if ifc.exception_error_code & CODE_SGX != 0 {
@@ -79,7 +80,7 @@ pub fn handler(
                 } else {
                     printf!("Present ");
                 }
-                println!();
+                println_irq!("");
             };
         }
 
@@ -91,7 +92,7 @@ pub fn handler(
         macro_rules! print_info1 {
             () => {
                 print_info0!();
-                println!("Running process thread: {:#?}", thread);
+                println_irq!("Running process thread: {:#?}", thread);
             };
         }
 
@@ -101,7 +102,7 @@ pub fn handler(
         {
             print_info1!();
             if is_process_fault {
-                println!("Segmentation fault");
+                println_irq!("Segmentation fault");
                 SCHEDULER.kill_process(thread.thread.pid);
                 SCHEDULER.schedule()
             }
@@ -112,16 +113,19 @@ pub fn handler(
 
         match space {
             Some(VirtualAddressSpace::HigherHalf(HigherHalfAddressSpace::ProcessKernelStack)) => {
-                if ifc.exception_error_code & CODE_USER == 0 {
+                // Every thread has its stack below its own top, a fault above it isn't growth
+                let kernel_stack_top = thread.thread.kernel_stack.lock().stack_top;
+                if ifc.exception_error_code & CODE_USER == 0 && fault_addr < kernel_stack_top {
                     // Only map more kernel stack pages if the fault was in kernel space
-                    let n = PROC_KERNEL_STACK_TOP - fault_addr;
+                    let n = kernel_stack_top - fault_addr;
                     let npages = n.div_ceil(PAGE_SIZE as u64);
 
                     if npages > tsettings.max_kernel_stack_pages {
                         print_info1!();
-                        println!(
+                        println_irq!(
                             "Kernel stack overflow npages={} max={}",
-                            npages, tsettings.max_kernel_stack_pages
+                            npages,
+                            tsettings.max_kernel_stack_pages
                         );
                         panic!("Unrecoverable page fault...");
                     }
@@ -134,7 +138,10 @@ pub fn handler(
                     let mut fixed = true;
 
                     while npages > kstack.stack_buffers.len() as u64 {
-                        if let Some(buffer) = per_cpu.free_allocated_buffers.pop() {
+                        if let Some(mut buffer) = per_cpu.free_allocated_buffers.pop() {
+                            if cfg!(debug_assertions) {
+                                buffer.fill(KERNEL_STACK_PATTERN);
+                            }
                             kstack.grow_using_existing_buffer(
                                 &mut pt,
                                 PAGE_PRESENT | PAGE_RW | PAGE_ACCESSED,
@@ -157,31 +164,52 @@ pub fn handler(
             Some(VirtualAddressSpace::LowerHalf(LowerHalfAddressSpace::ProcessStack)) => {
                 if ifc.exception_error_code & CODE_USER == CODE_USER {
                     // Only map more kernel stack pages if the fault was in kernel space
-                    let n = PROC_USER_STACK_TOP - fault_addr;
-                    let npages = n.div_ceil(PAGE_SIZE as u64);
-
-                    if npages > tsettings.max_user_stack_pages {
-                        print_info1!();
-                        println!(
-                            "User stack overflow npages={} max={}",
-                            npages, tsettings.max_user_stack_pages
-                        );
-                        panic!("Unrecoverable page fault...");
-                    }
-
                     let th = &thread.thread;
 
+                    // Taken before the stack lock, which resident_pages needs
+                    let limits = th.process.limits.lock().clone();
+                    let resident = th.process.resident_pages();
+                    let max_stack_pages = tsettings
+                        .max_user_stack_pages
+                        .min(limits.soft(Resource::Stack) / PAGE_SIZE as u64);
+                    let max_pages = limits.soft(Resource::AddressSpace) / PAGE_SIZE as u64;
+
                     let mut pt = th.process.page_table.lock();
                     let mut stack = th.stack.lock();
 
-                    while npages > stack.stack_buffers.len() as u64 {
-                        stack.grow(&mut pt, PAGE_PRESENT | PAGE_RW | PAGE_USER | PAGE_ACCESSED);
-                    }
+                    // The stack top is randomized per process, faults above it aren't stack growth
+                    if fault_addr < stack.stack_top {
+                        let n = stack.stack_top - fault_addr;
+                        let npages = n.div_ceil(PAGE_SIZE as u64);
+                        let grown = npages.saturating_sub(stack.stack_buffers.len() as u64);
+
+                        if npages > max_stack_pages || resident + grown > max_pages {
+                            drop(pt);
+                            drop(stack);
+                            println_irq!(
+                                "User stack overflow npages={} max={} resident={} max_resident={}",
+                                npages,
+                                max_stack_pages,
+                                resident,
+                                max_pages
+                            );
+                        } else {
+                            while npages > stack.stack_buffers.len() as u64 {
+                                stack.grow(
+                                    &mut pt,
+                                    PAGE_PRESENT | PAGE_RW | PAGE_USER | PAGE_ACCESSED,
+                                );
+                            }
 
-                    drop(pt);
-                    drop(stack);
+                            drop(pt);
+                            drop(stack);
 
-                    return;
+                            return;
+                        }
+                    } else {
+                        drop(pt);
+                        drop(stack);
+                    }
                 }
             }
             _ => (),
@@ -189,7 +217,7 @@ pub fn handler(
 
         print_info1!();
         if is_process_fault {
-            println!("Segmentation fault");
+            println_irq!("Segmentation fault");
             SCHEDULER.kill_process(thread.thread.pid);
             SCHEDULER.schedule()
         }
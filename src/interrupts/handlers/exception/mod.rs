@@ -1,2 +1,6 @@
+pub mod exc_1_debug;
+pub mod exc_3_breakpoint;
 pub mod exc_6_invalid_opcode;
+pub mod exc_7_device_not_available;
+pub mod exc_8_double_fault;
 pub mod exc_e_page_fault;
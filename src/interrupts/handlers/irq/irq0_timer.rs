@@ -1,13 +1,22 @@
+use core::sync::atomic::{AtomicPtr, Ordering};
+
 use crate::{
     interrupts::{
         self,
         idt::{InterruptFrameContext, InterruptFrameExtra, InterruptFrameRegisters},
         pic::pic_send_eoi,
     },
-    process::scheduler::SCHEDULER,
+    process::{scheduler::SCHEDULER, time_page::update_time_page},
 };
 
 static mut UPTIME: u64 = 0;
+/// Called on every tick from the interrupt handler, a `fn()`
+static TICK_HOOK: AtomicPtr<()> = AtomicPtr::new(core::ptr::null_mut());
+
+pub fn set_tick_hook(hook: Option<fn()>) {
+    let hook = hook.map_or(core::ptr::null_mut(), |hook| hook as *mut ());
+    TICK_HOOK.store(hook, Ordering::SeqCst);
+}
 
 pub fn handler(
     _ist: u64,
@@ -18,6 +27,13 @@ pub fn handler(
 ) {
     unsafe {
         UPTIME += 1;
+        update_time_page(UPTIME);
+
+        let hook = TICK_HOOK.load(Ordering::SeqCst);
+        if !hook.is_null() {
+            let hook: fn() = core::mem::transmute(hook);
+            hook();
+        }
 
         if ifc.cs & 0b11 != 0 {
             // If interrupted a userland process, switch to another one
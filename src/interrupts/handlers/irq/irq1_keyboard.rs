@@ -2,14 +2,15 @@ use alloc::vec::Vec;
 
 use crate::{
     drivers::keyboard::{
-        handle_keyboard_event, AcpiKey, Key, KeyModifiers, KeyboardEvent, KeyboardEventKind,
-        KeyboardLayout, MultimediaKey,
+        handle_keyboard_event, keymap::translate, AcpiKey, Key, KeyModifiers, KeyboardEvent,
+        KeyboardEventKind, KeyboardLayout, MultimediaKey,
     },
     interrupts::idt::{InterruptFrameContext, InterruptFrameExtra, InterruptFrameRegisters},
     io::inb,
 };
 
-fn read_keyboard_layout_en_us() -> Option<(Key, KeyboardEventKind)> {
+/// The key, event kind and make code, `0xE0xx` for the extended ones
+fn read_keyboard_layout_en_us() -> Option<(Key, KeyboardEventKind, u16)> {
     let scancode = inb(0x60);
 
     if scancode == 0xE0 {
@@ -59,6 +60,7 @@ fn read_keyboard_layout_en_us() -> Option<(Key, KeyboardEventKind)> {
             } else {
                 KeyboardEventKind::KeyDown
             },
+            0xE000 | (scancode & !0x80) as u16,
         ))
     } else {
         Some((
@@ -157,6 +159,7 @@ fn read_keyboard_layout_en_us() -> Option<(Key, KeyboardEventKind)> {
             } else {
                 KeyboardEventKind::KeyDown
             },
+            (scancode & !0x80) as u16,
         ))
     }
 }
@@ -190,7 +193,7 @@ pub fn handler(
         return;
     };
 
-    if let Some((key, kind)) = key {
+    if let Some((key, kind, scancode)) = key {
         let mut was_down = true;
 
         // Handle state
@@ -221,12 +224,13 @@ pub fn handler(
             _ => {}
         }
 
-        let mapped_key = layout.map(key, unsafe { MODIFIERS });
+        let (mapped_key, text) = translate(layout, scancode, key, kind, unsafe { MODIFIERS });
 
         // Make event
         let event = KeyboardEvent {
             raw_key: key,
             mapped_key,
+            text,
             kind: if was_down && kind == KeyboardEventKind::KeyDown {
                 KeyboardEventKind::KeyRepeat
             } else {
@@ -0,0 +1,88 @@
+//! Null syscall round trips, enabled by `syscall.benchmark`.
+//! The time between two consecutive getpid calls of a thread includes both privilege transitions,
+//! so with a tight getpid loop in userland the minimum is the cost of one round trip
+
+use core::arch::x86_64::_rdtsc;
+
+use spin::Mutex;
+
+use crate::{config::get_kernel_config, log_info};
+
+const LINUX_SYS_GETPID: u64 = 39;
+const SAMPLES_PER_REPORT: u64 = 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SyscallEntry {
+    Fast,
+    Interrupt,
+}
+
+impl SyscallEntry {
+    fn name(&self) -> &'static str {
+        match self {
+            SyscallEntry::Fast => "syscall/sysret",
+            SyscallEntry::Interrupt => "int 0x80/iretq",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct EntryStats {
+    last_tid: u32,
+    last_tsc: u64,
+    samples: u64,
+    total_cycles: u64,
+    min_cycles: u64,
+}
+
+impl EntryStats {
+    const fn new() -> Self {
+        Self {
+            last_tid: 0,
+            last_tsc: 0,
+            samples: 0,
+            total_cycles: 0,
+            min_cycles: u64::MAX,
+        }
+    }
+}
+
+static STATS: Mutex<[EntryStats; 2]> = Mutex::new([EntryStats::new(); 2]);
+
+/// Called when entering a syscall, before dispatching it
+pub fn record(entry: SyscallEntry, tid: u32, syscall: u64) {
+    if syscall != LINUX_SYS_GETPID || !get_kernel_config().syscall_benchmark {
+        return;
+    }
+    let now = unsafe { _rdtsc() };
+
+    let mut lock = STATS.lock();
+    let stats = &mut lock[entry as usize];
+    if stats.last_tid == tid && stats.last_tsc != 0 {
+        let cycles = now.wrapping_sub(stats.last_tsc);
+        stats.samples += 1;
+        stats.total_cycles += cycles;
+        stats.min_cycles = stats.min_cycles.min(cycles);
+    }
+    stats.last_tid = tid;
+    stats.last_tsc = now;
+
+    if stats.samples == SAMPLES_PER_REPORT {
+        let report = *stats;
+        *stats = EntryStats {
+            last_tid: tid,
+            last_tsc: now,
+            ..EntryStats::new()
+        };
+        drop(lock);
+
+        log_info!(
+            target: "syscall",
+            "getpid through {}: min {} cycles, avg {} cycles over {} calls",
+            entry.name(),
+            report.min_cycles,
+            report.total_cycles / report.samples,
+            report.samples
+        );
+    }
+}
@@ -2,12 +2,18 @@ use core::panic;
 
 use crate::{
     interrupts::{
-        handlers::syscall::linux::{linux_syscall, linux_syscall_fast},
+        handlers::syscall::{
+            bench::{self, SyscallEntry},
+            linux::{linux_syscall, linux_syscall_fast, ENOSYS},
+        },
         idt::{InterruptFrameContext, InterruptFrameExtra, InterruptFrameRegisters},
     },
     percpu::{get_per_cpu, InterruptSource},
     println,
-    process::scheduler::ProcessSyscallABI,
+    process::{
+        memory::LOWER_HALF_END,
+        scheduler::{ProcessSyscallABI, SCHEDULER},
+    },
 };
 
 pub fn handler(
@@ -37,13 +43,18 @@ pub fn handler(
         let lock = thread.thread.process.syscalls.lock();
         let abi: ProcessSyscallABI = *lock;
         drop(lock);
+        bench::record(SyscallEntry::Interrupt, thread.tid, ifr.rax);
 
         let ok = match &mut ife {
             Some(ife) => match abi {
-                ProcessSyscallABI::Linux => linux_syscall(ifr, ifc, Some(ife), thread),
+                ProcessSyscallABI::Linux | ProcessSyscallABI::LinuxInt80 => {
+                    linux_syscall(ifr, ifc, Some(ife), thread)
+                }
             },
             None => match abi {
-                ProcessSyscallABI::Linux => linux_syscall(ifr, ifc, None, thread),
+                ProcessSyscallABI::Linux | ProcessSyscallABI::LinuxInt80 => {
+                    linux_syscall(ifr, ifc, None, thread)
+                }
             },
         };
 
@@ -70,6 +81,7 @@ pub fn handler_fast() {
         let lock = thread.thread.process.syscalls.lock();
         let abi: ProcessSyscallABI = *lock;
         drop(lock);
+        bench::record(SyscallEntry::Fast, thread.tid, per_cpu.syscall_data.rax);
 
         unsafe {
             thread.thread.running_cpu.force_unlock();
@@ -80,9 +92,21 @@ pub fn handler_fast() {
         }
 
         match abi {
-            ProcessSyscallABI::Linux => linux_syscall_fast(thread),
+            ProcessSyscallABI::Linux => {
+                linux_syscall_fast(thread);
+            }
+            ProcessSyscallABI::LinuxInt80 => {
+                per_cpu.syscall_data.rax = (-(ENOSYS as i64)) as u64;
+            }
         };
 
+        // sysret with a non canonical rip faults in ring 0, on the user stack
+        if per_cpu.syscall_data.rcx >= LOWER_HALF_END {
+            println!("Bad syscall return address {:#x}", per_cpu.syscall_data.rcx);
+            SCHEDULER.kill_process(thread.pid);
+            SCHEDULER.schedule();
+        }
+
         per_cpu.interrupt_sources.pop();
 
         return;
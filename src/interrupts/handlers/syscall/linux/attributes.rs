@@ -0,0 +1,287 @@
+use alloc::vec::Vec;
+
+use crate::{
+    data::{
+        file::File,
+        permissions::{Credentials, Permissions},
+    },
+    drivers::{
+        time::get_unix_timestamp,
+        vfs::{close_path_handle, get_vfs, FileStat, FileSystem, FsStat},
+    },
+    interrupts::handlers::syscall::{
+        linux::{
+            poll::LinuxTimespec, vfs_err_to_linux_errno, EACCES, EBADF, EFAULT, EINVAL, ENOTSUP,
+            EPERM,
+        },
+        utils::{buffer::UserProcessBuffer, structure::UserProcessStructure},
+    },
+    linux_return_err_from_syscall,
+    paging::PageTable,
+    process::scheduler::ProcThreadInfo,
+};
+
+const MAX_PATH_LEN: u64 = 4096;
+
+pub const AT_FDCWD: i64 = -100;
+pub const UTIME_NOW: i64 = (1 << 30) - 1;
+pub const UTIME_OMIT: i64 = (1 << 30) - 2;
+
+/// The file whose attributes change
+enum Target {
+    Fd(u64),
+    Path(Vec<char>),
+}
+
+pub(super) fn copy_path(path: u64) -> Option<Vec<char>> {
+    let mut pt = PageTable::temporary_this();
+    let Some((user_buffer, true)) = UserProcessBuffer::copy_user_c_str(&mut pt, path, MAX_PATH_LEN)
+    else {
+        return None;
+    };
+    drop(pt);
+    Some(user_buffer.iter().map(|x| *x as char).collect())
+}
+
+/// Runs `change` with a handle of the target, paths are opened just for the change without
+/// any access check as changing attributes only depends on ownership
+fn change_attributes(
+    thread: &ProcThreadInfo,
+    target: Target,
+    change: impl FnOnce(&mut dyn FileSystem, u64, &FileStat, Credentials) -> Result<(), u64>,
+) -> u64 {
+    let access = thread.thread.process.access.lock().clone();
+
+    let (fs, handle, temporary) = match target {
+        Target::Fd(fd) => {
+            let io_ctx = thread.thread.process.io_context.lock();
+            match io_ctx.file_table.get(fd as usize) {
+                Some(file) => (file.fs.clone(), file.handle, false),
+                None => linux_return_err_from_syscall!(EBADF),
+            }
+        }
+        Target::Path(path) => {
+            match File::open_raw(&path, 0, Permissions::from_u64(0), Credentials::Kernel) {
+                Ok((fs, handle, _)) => (fs, handle, true),
+                Err(e) => linux_return_err_from_syscall!(vfs_err_to_linux_errno(e)),
+            }
+        }
+    };
+
+    let mut gfs = fs.write();
+    let result = gfs
+        .fstat(handle)
+        .map_err(vfs_err_to_linux_errno)
+        .and_then(|stat| change(&mut **gfs, handle, &stat, access.credentials()));
+    drop(gfs);
+    if temporary {
+        let _ = close_path_handle(&fs, handle);
+    }
+
+    match result {
+        Ok(()) => 0,
+        Err(errno) => linux_return_err_from_syscall!(errno),
+    }
+}
+
+fn chmod(thread: &ProcThreadInfo, target: Target, mode: u64) -> u64 {
+    if mode & !0o7777 != 0 {
+        linux_return_err_from_syscall!(EINVAL)
+    }
+    change_attributes(thread, target, |fs, handle, stat, credentials| {
+        if !credentials.can_change_mode(stat) {
+            return Err(EPERM);
+        }
+        fs.fchmod(handle, mode).map_err(vfs_err_to_linux_errno)
+    })
+}
+
+fn chown(thread: &ProcThreadInfo, target: Target, uid: u64, gid: u64) -> u64 {
+    // -1 keeps the current id
+    let uid = Some(uid as u32).filter(|uid| *uid != u32::MAX);
+    let gid = Some(gid as u32).filter(|gid| *gid != u32::MAX);
+    change_attributes(thread, target, |fs, handle, stat, credentials| {
+        if !credentials.can_change_owner(stat, uid, gid) {
+            return Err(EPERM);
+        }
+        fs.fchown(handle, uid, gid).map_err(vfs_err_to_linux_errno)
+    })
+}
+
+pub fn linux_sys_chmod(thread: &ProcThreadInfo, path: u64, mode: u64) -> u64 {
+    let Some(path) = copy_path(path) else {
+        linux_return_err_from_syscall!(EFAULT)
+    };
+    chmod(thread, Target::Path(path), mode)
+}
+
+pub fn linux_sys_fchmod(thread: &ProcThreadInfo, fd: u64, mode: u64) -> u64 {
+    chmod(thread, Target::Fd(fd), mode)
+}
+
+pub fn linux_sys_chown(thread: &ProcThreadInfo, path: u64, uid: u64, gid: u64) -> u64 {
+    let Some(path) = copy_path(path) else {
+        linux_return_err_from_syscall!(EFAULT)
+    };
+    chown(thread, Target::Path(path), uid, gid)
+}
+
+pub fn linux_sys_fchown(thread: &ProcThreadInfo, fd: u64, uid: u64, gid: u64) -> u64 {
+    chown(thread, Target::Fd(fd), uid, gid)
+}
+
+pub fn linux_sys_umask(thread: &ProcThreadInfo, mask: u64) -> u64 {
+    let mut umask = thread.thread.process.umask.lock();
+    let old = *umask;
+    *umask = mask as u32 & 0o777;
+    old as u64
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct LinuxStatfs {
+    pub f_type: i64,
+    pub f_bsize: i64,
+    pub f_blocks: u64,
+    pub f_bfree: u64,
+    pub f_bavail: u64,
+    pub f_files: u64,
+    pub f_ffree: u64,
+    pub f_fsid: [i32; 2],
+    pub f_namelen: i64,
+    pub f_frsize: i64,
+    pub f_flags: i64,
+    pub f_spare: [i64; 4],
+}
+
+impl From<FsStat> for LinuxStatfs {
+    fn from(stat: FsStat) -> Self {
+        Self {
+            f_type: stat.fs_type as i64,
+            f_bsize: stat.block_size as i64,
+            f_blocks: stat.total_blocks,
+            f_bfree: stat.free_blocks,
+            f_bavail: stat.available_blocks,
+            f_files: stat.total_inodes,
+            f_ffree: stat.free_inodes,
+            f_fsid: [0; 2],
+            f_namelen: stat.max_name_length as i64,
+            f_frsize: stat.block_size as i64,
+            f_flags: 0,
+            f_spare: [0; 4],
+        }
+    }
+}
+
+fn statfs(thread: &ProcThreadInfo, target: Target, buf: u64) -> u64 {
+    let Some(mut structure) = UserProcessStructure::new(buf as *mut LinuxStatfs) else {
+        linux_return_err_from_syscall!(EFAULT)
+    };
+    let stat = match target {
+        Target::Fd(fd) => {
+            let io_ctx = thread.thread.process.io_context.lock();
+            let Some(file) = io_ctx.file_table.get(fd as usize) else {
+                linux_return_err_from_syscall!(EBADF)
+            };
+            drop(io_ctx);
+            let mut guard = file.fs.write();
+            guard.statfs()
+        }
+        Target::Path(path) => get_vfs().write().statfs_path(&path),
+    };
+    let stat = match stat {
+        Ok(stat) => stat,
+        Err(e) => linux_return_err_from_syscall!(vfs_err_to_linux_errno(e)),
+    };
+
+    let mut ptlock = thread.thread.process.page_table.lock();
+    let Some(buf) = structure.verify_fully_mapped_mut(&mut ptlock) else {
+        linux_return_err_from_syscall!(EFAULT)
+    };
+    *buf = stat.into();
+    0
+}
+
+pub fn linux_sys_statfs(thread: &ProcThreadInfo, path: u64, buf: u64) -> u64 {
+    let Some(path) = copy_path(path) else {
+        linux_return_err_from_syscall!(EFAULT)
+    };
+    statfs(thread, Target::Path(path), buf)
+}
+
+pub fn linux_sys_fstatfs(thread: &ProcThreadInfo, fd: u64, buf: u64) -> u64 {
+    statfs(thread, Target::Fd(fd), buf)
+}
+
+/// Returns the new time in seconds, `None` for UTIME_OMIT
+fn timespec_seconds(timespec: &LinuxTimespec, now: u64) -> Result<Option<u64>, ()> {
+    match timespec.tv_nsec {
+        UTIME_OMIT => Ok(None),
+        UTIME_NOW => Ok(Some(now)),
+        0..1_000_000_000 if timespec.tv_sec >= 0 => Ok(Some(timespec.tv_sec as u64)),
+        _ => Err(()),
+    }
+}
+
+/// A null `path` changes the times of `dirfd` itself, which is how futimens is implemented.
+/// Only absolute paths or paths relative to AT_FDCWD are supported
+pub fn linux_sys_utimensat(
+    thread: &ProcThreadInfo,
+    dirfd: u64,
+    path: u64,
+    times: u64,
+    flags: u64,
+) -> u64 {
+    // AT_SYMLINK_NOFOLLOW is the only flag, symlinks have no times of their own so the ones
+    // of the target change either way
+    if flags & !0x100 != 0 {
+        linux_return_err_from_syscall!(EINVAL)
+    }
+
+    let target = if path == 0 {
+        Target::Fd(dirfd)
+    } else {
+        let Some(path) = copy_path(path) else {
+            linux_return_err_from_syscall!(EFAULT)
+        };
+        if path.first() != Some(&'/') && dirfd as i64 != AT_FDCWD {
+            linux_return_err_from_syscall!(ENOTSUP)
+        }
+        Target::Path(path)
+    };
+
+    let now = get_unix_timestamp();
+    let (atime, mtime, explicit) = if times == 0 {
+        (Some(now), Some(now), false)
+    } else {
+        let Some(times) = UserProcessStructure::<[LinuxTimespec; 2]>::new(times as *mut _) else {
+            linux_return_err_from_syscall!(EFAULT)
+        };
+        let mut ptlock = thread.thread.process.page_table.lock();
+        let Some(times) = times.verify_fully_mapped(&mut ptlock).copied() else {
+            linux_return_err_from_syscall!(EFAULT)
+        };
+        drop(ptlock);
+        let explicit = times
+            .iter()
+            .any(|time| !matches!(time.tv_nsec, UTIME_NOW | UTIME_OMIT));
+        match (
+            timespec_seconds(&times[0], now),
+            timespec_seconds(&times[1], now),
+        ) {
+            (Ok(atime), Ok(mtime)) => (atime, mtime, explicit),
+            _ => linux_return_err_from_syscall!(EINVAL),
+        }
+    };
+
+    change_attributes(thread, target, |fs, handle, stat, credentials| {
+        if !credentials.can_set_times(stat, explicit) {
+            return Err(if explicit { EPERM } else { EACCES });
+        }
+        if atime.is_none() && mtime.is_none() {
+            return Ok(());
+        }
+        fs.futimens(handle, atime, mtime)
+            .map_err(vfs_err_to_linux_errno)
+    })
+}
@@ -0,0 +1,101 @@
+use crate::{
+    interrupts::{
+        handlers::{
+            irq::irq0_timer::get_uptime_ticks,
+            syscall::{
+                linux::{
+                    poll::LinuxTimespec, timers::timespec_to_ms, EAGAIN, EFAULT, EINVAL, ENOSYS,
+                    ETIMEDOUT,
+                },
+                utils::structure::UserProcessStructure,
+            },
+        },
+        pit::ms_to_ticks,
+    },
+    linux_return_err_from_syscall,
+    process::{futex::LockedFutex, scheduler::ProcThreadInfo},
+};
+
+pub const SYS_FUTEX: u64 = 202;
+
+pub const FUTEX_WAIT: u64 = 0;
+pub const FUTEX_WAKE: u64 = 1;
+pub const FUTEX_PRIVATE_FLAG: u64 = 128;
+pub const FUTEX_CLOCK_REALTIME: u64 = 256;
+
+/// `FUTEX_WAIT` and `FUTEX_WAKE`, with or without `FUTEX_PRIVATE_FLAG` as every futex is private
+pub fn linux_sys_futex(
+    thread: &ProcThreadInfo,
+    uaddr: u64,
+    futex_op: u64,
+    val: u64,
+    timeout: u64,
+) -> u64 {
+    if futex_op & FUTEX_CLOCK_REALTIME != 0 {
+        linux_return_err_from_syscall!(ENOSYS)
+    }
+    if !uaddr.is_multiple_of(4) {
+        linux_return_err_from_syscall!(EINVAL)
+    }
+    match futex_op & !FUTEX_PRIVATE_FLAG {
+        FUTEX_WAIT => futex_wait(thread, uaddr, val as u32, timeout),
+        FUTEX_WAKE => LockedFutex::lock(thread.pid, uaddr).wake(val as u32 as u64),
+        _ => linux_return_err_from_syscall!(ENOSYS),
+    }
+}
+
+/// Sleeps while the word at `uaddr` holds `expected`, until a `FUTEX_WAKE` or the relative
+/// `timeout`. The syscall runs again once the thread is woken, and then returns at once
+fn futex_wait(thread: &ProcThreadInfo, uaddr: u64, expected: u32, timeout: u64) -> u64 {
+    let mut wait = thread.thread.wait.lock();
+    if wait.futex == Some(uaddr) {
+        let timed_out = wait.timed_out;
+        wait.finish();
+        drop(wait);
+        LockedFutex::lock(thread.pid, uaddr).prune();
+        if timed_out {
+            linux_return_err_from_syscall!(ETIMEDOUT)
+        }
+        return 0;
+    }
+    drop(wait);
+
+    let deadline = if timeout == 0 {
+        None
+    } else {
+        let Some(timespec) = UserProcessStructure::<LinuxTimespec>::new(timeout as *mut _) else {
+            linux_return_err_from_syscall!(EFAULT)
+        };
+        let mut ptlock = thread.thread.process.page_table.lock();
+        let Some(timespec) = timespec.verify_fully_mapped(&mut ptlock).copied() else {
+            linux_return_err_from_syscall!(EFAULT)
+        };
+        drop(ptlock);
+        let Some(ms) = timespec_to_ms(&timespec) else {
+            linux_return_err_from_syscall!(EINVAL)
+        };
+        Some(get_uptime_ticks() + ms_to_ticks(ms))
+    };
+
+    // The word is checked with the bucket locked, a waker changes it before it locks the bucket
+    let mut futex = LockedFutex::lock(thread.pid, uaddr);
+    let Some(word) = UserProcessStructure::<u32>::new(uaddr as *mut u32) else {
+        linux_return_err_from_syscall!(EFAULT)
+    };
+    let mut ptlock = thread.thread.process.page_table.lock();
+    let Some(word) = word.verify_fully_mapped(&mut ptlock) else {
+        linux_return_err_from_syscall!(EFAULT)
+    };
+    let value = unsafe { core::ptr::read_volatile(word) };
+    drop(ptlock);
+    if value != expected {
+        linux_return_err_from_syscall!(EAGAIN)
+    }
+
+    let mut wait = thread.thread.wait.lock();
+    wait.futex = Some(uaddr);
+    wait.timed_out = false;
+    drop(wait);
+    let queue = futex.queue();
+    queue.wait(thread, SYS_FUTEX, deadline, || drop(futex))
+}
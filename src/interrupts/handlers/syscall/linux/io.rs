@@ -1,18 +1,24 @@
-use ::alloc::vec::Vec;
+use ::alloc::{sync::Arc, vec::Vec};
 
 use crate::{
     data::{file::File, permissions::Permissions},
     debuggable_bitset_enum,
     drivers::{
-        fs::virt::pipefs::Pipe,
+        fs::{
+            lock::{FileLockKind, FILE_LOCKS},
+            virt::pipefs::Pipe,
+        },
+        tty::ioctl_argument_size,
         vfs::{
-            FileStat, SeekPosition, VfsFileKind, OPEN_MODE_APPEND, OPEN_MODE_CREATE,
-            OPEN_MODE_FAIL_IF_EXISTS, OPEN_MODE_READ, OPEN_MODE_WRITE,
+            close_path_handle, get_vfs, DeviceNumber, PollEvent, PollEvents, SeekPosition, Vfs,
+            VfsError, VfsOpenFile, OPEN_MODE_APPEND, OPEN_MODE_CREATE, OPEN_MODE_FAIL_IF_EXISTS,
+            OPEN_MODE_NONBLOCK, OPEN_MODE_READ, OPEN_MODE_WRITE,
         },
     },
     interrupts::handlers::syscall::{
         linux::{
-            vfs_err_to_linux_errno, EBADF, EINVAL, EMFILE, ENOENT, ENOTDIR, EPERM, WHENCE_CUR,
+            attributes::AT_FDCWD, vfs_err_to_linux_errno, EACCES, EBADF, EFAULT, EINVAL, EMFILE,
+            EMLINK, ENOENT, ENOTDIR, ENOTSUP, ENOTTY, EPERM, EWOULDBLOCK, EXDEV, WHENCE_CUR,
             WHENCE_END, WHENCE_SET,
         },
         utils::{buffer::UserProcessBuffer, structure::UserProcessStructure},
@@ -20,14 +26,28 @@ use crate::{
     linux_return_err_from_syscall,
     paging::PageTable,
     process::{
+        io::file_table::OpenFile,
         memory::{get_address_space, VirtualAddressSpace},
-        scheduler::ProcThreadInfo,
+        scheduler::{ProcThreadInfo, SCHEDULER},
     },
 };
 
 const MAX_PATH_LEN: u64 = 4096;
 const MAX_SINGLE_WRITE: u64 = 64 * 1024 * 1024; // 64MiB
 
+pub const SYS_READ: u64 = 0;
+pub const SYS_WRITE: u64 = 1;
+pub const SYS_PREAD64: u64 = 17;
+pub const SYS_PWRITE64: u64 = 18;
+pub const SYS_SENDFILE: u64 = 40;
+pub const SYS_FLOCK: u64 = 73;
+pub const SYS_COPY_FILE_RANGE: u64 = 326;
+
+pub const LOCK_SH: u64 = 1;
+pub const LOCK_EX: u64 = 2;
+pub const LOCK_NB: u64 = 4;
+pub const LOCK_UN: u64 = 8;
+
 debuggable_bitset_enum!(
     u64,
     pub enum LinuxOpenFlag {
@@ -37,6 +57,8 @@ debuggable_bitset_enum!(
         Excl = 1 << 7,
         Truncate = 1 << 9,
         Append = 1 << 10,
+        NonBlock = 1 << 11,
+        Directory = 1 << 16,
     },
     LinuxOpenFlags
 );
@@ -48,22 +70,54 @@ const SUPPORTED_OPEN_FLAGS: u64 = LinuxOpenFlags::empty()
     .set(LinuxOpenFlag::Excl)
     .set(LinuxOpenFlag::Truncate)
     .set(LinuxOpenFlag::Append)
+    .set(LinuxOpenFlag::NonBlock)
+    .set(LinuxOpenFlag::Directory)
     .get();
 
 const SUPPORTED_PERMISSION_FLAGS: u64 = 0o7777; // sticky, setuid, setgid, rwxrwxrwx
 
-pub enum IoAction {
-    Open(LinuxOpenFlags),
-    CreateChild(VfsFileKind, u64),
-    Rmdir,
-}
-
-pub fn cant(_thread: &ProcThreadInfo, _stat: &FileStat, _action: IoAction) -> bool {
-    // TODO: check perms
-    false
+/// Sleeps until `file` may be ready for `events` if it is a blocking stream, `syscall` then runs
+/// again. Fails with `EWOULDBLOCK` otherwise
+fn wait_for_file(
+    thread: &ProcThreadInfo,
+    file: Arc<OpenFile>,
+    syscall: u64,
+    events: PollEvents,
+) -> u64 {
+    let mut gfs = file.fs.write();
+    let queue = match gfs.fblocks(file.handle) {
+        true => gfs.fwait_queue(file.handle).ok().flatten(),
+        false => None,
+    };
+    let Some(queue) = queue else {
+        linux_return_err_from_syscall!(EWOULDBLOCK)
+    };
+    // The file may have become ready since it failed, the writers wake the queue with the file
+    // system locked so nothing is missed once it is checked again here
+    if gfs
+        .fpoll(file.handle, events)
+        .is_ok_and(|ready| !ready.is_empty())
+    {
+        drop(gfs);
+        drop(file);
+        SCHEDULER.restart_current(thread, syscall)
+    }
+    queue.register(thread);
+    drop(queue);
+    drop(gfs);
+    drop(file);
+    SCHEDULER.block_current(thread, syscall, None)
 }
 
-pub fn linux_sys_read(thread: &ProcThreadInfo, fd: u64, buf: u64, count: u64) -> u64 {
+/// Reads from `fd` at its position, or at `offset` without moving it
+fn read_fd(
+    thread: &ProcThreadInfo,
+    syscall: u64,
+    fd: u64,
+    buf: u64,
+    count: u64,
+    offset: Option<u64>,
+) -> u64 {
     let space = get_address_space(buf);
     let Some(end_addr) = buf.checked_add(count) else {
         linux_return_err_from_syscall!(EINVAL)
@@ -80,25 +134,37 @@ pub fn linux_sys_read(thread: &ProcThreadInfo, fd: u64, buf: u64, count: u64) ->
     let mut user_buffer = UserProcessBuffer::new(buf as *mut u8, count as usize);
     match user_buffer.verify_fully_mapped_mut(&mut ptlock) {
         Some(buf) => {
-            let mut io_ctx = thread.thread.process.io_context.lock();
-            let (fs, handle) = match io_ctx.file_table.get_fd(fd as usize) {
-                Some(Some((fs, handle))) => (fs, *handle),
-                _ => linux_return_err_from_syscall!(EBADF),
-            };
-            let mut gfs = fs.write();
-            let read = match gfs.fread(handle, buf) {
-                Ok(w) => w,
-                Err(e) => linux_return_err_from_syscall!(vfs_err_to_linux_errno(e)),
+            let io_ctx = thread.thread.process.io_context.lock();
+            let Some(file) = io_ctx.file_table.get(fd as usize) else {
+                linux_return_err_from_syscall!(EBADF)
             };
-            drop(gfs);
             drop(io_ctx);
-            read
+            let read = match offset {
+                None => file.read(buf),
+                Some(offset) => file.read_at(offset, buf),
+            };
+            match read {
+                Ok(read) => read,
+                Err(VfsError::WouldBlock) => {
+                    drop(ptlock);
+                    wait_for_file(thread, file, syscall, PollEvent::In.into())
+                }
+                Err(e) => linux_return_err_from_syscall!(vfs_err_to_linux_errno(e)),
+            }
         }
         None => linux_return_err_from_syscall!(EMFILE),
     }
 }
 
-pub fn linux_sys_write(thread: &ProcThreadInfo, fd: u64, buf: u64, count: u64) -> u64 {
+/// Writes to `fd` at its position, or at `offset` without moving it
+fn write_fd(
+    thread: &ProcThreadInfo,
+    syscall: u64,
+    fd: u64,
+    buf: u64,
+    count: u64,
+    offset: Option<u64>,
+) -> u64 {
     if count > MAX_SINGLE_WRITE {
         linux_return_err_from_syscall!(EINVAL)
     }
@@ -119,19 +185,23 @@ pub fn linux_sys_write(thread: &ProcThreadInfo, fd: u64, buf: u64, count: u64) -
     let user_buffer = UserProcessBuffer::new(buf as *mut u8, count as usize);
     match user_buffer.verify_fully_mapped(&mut ptlock) {
         Some(buf) => {
-            let mut io_ctx = thread.thread.process.io_context.lock();
-            let (fs, handle) = match io_ctx.file_table.get_fd(fd as usize) {
-                Some(Some((fs, handle))) => (fs, *handle),
-                _ => linux_return_err_from_syscall!(EBADF),
-            };
-            let mut gfs = fs.write();
-            let written = match gfs.fwrite(handle, buf) {
-                Ok(w) => w,
-                Err(e) => linux_return_err_from_syscall!(vfs_err_to_linux_errno(e)),
+            let io_ctx = thread.thread.process.io_context.lock();
+            let Some(file) = io_ctx.file_table.get(fd as usize) else {
+                linux_return_err_from_syscall!(EBADF)
             };
-            drop(gfs);
             drop(io_ctx);
-            written
+            let written = match offset {
+                None => file.write(buf),
+                Some(offset) => file.write_at(offset, buf),
+            };
+            match written {
+                Ok(written) => written,
+                Err(VfsError::WouldBlock) => {
+                    drop(ptlock);
+                    wait_for_file(thread, file, syscall, PollEvent::Out.into())
+                }
+                Err(e) => linux_return_err_from_syscall!(vfs_err_to_linux_errno(e)),
+            }
         }
         None => {
             linux_return_err_from_syscall!(EINVAL)
@@ -139,6 +209,151 @@ pub fn linux_sys_write(thread: &ProcThreadInfo, fd: u64, buf: u64, count: u64) -
     }
 }
 
+pub fn linux_sys_read(thread: &ProcThreadInfo, fd: u64, buf: u64, count: u64) -> u64 {
+    read_fd(thread, SYS_READ, fd, buf, count, None)
+}
+
+pub fn linux_sys_write(thread: &ProcThreadInfo, fd: u64, buf: u64, count: u64) -> u64 {
+    write_fd(thread, SYS_WRITE, fd, buf, count, None)
+}
+
+pub fn linux_sys_pread64(
+    thread: &ProcThreadInfo,
+    fd: u64,
+    buf: u64,
+    count: u64,
+    offset: u64,
+) -> u64 {
+    if (offset as i64) < 0 {
+        linux_return_err_from_syscall!(EINVAL)
+    }
+    read_fd(thread, SYS_PREAD64, fd, buf, count, Some(offset))
+}
+
+pub fn linux_sys_pwrite64(
+    thread: &ProcThreadInfo,
+    fd: u64,
+    buf: u64,
+    count: u64,
+    offset: u64,
+) -> u64 {
+    if (offset as i64) < 0 {
+        linux_return_err_from_syscall!(EINVAL)
+    }
+    write_fd(thread, SYS_PWRITE64, fd, buf, count, Some(offset))
+}
+
+/// Reads the offset a copy between files starts at, `None` for a null pointer
+fn read_copy_offset(thread: &ProcThreadInfo, ptr: u64) -> Result<Option<u64>, u64> {
+    if ptr == 0 {
+        return Ok(None);
+    }
+    let structure = UserProcessStructure::new(ptr as *mut i64).ok_or(EFAULT)?;
+    let mut ptlock = thread.thread.process.page_table.lock();
+    let offset = *structure.verify_fully_mapped(&mut ptlock).ok_or(EFAULT)?;
+    if offset < 0 {
+        return Err(EINVAL);
+    }
+    Ok(Some(offset as u64))
+}
+
+fn write_copy_offset(thread: &ProcThreadInfo, ptr: u64, offset: u64) -> Result<(), u64> {
+    let mut structure = UserProcessStructure::new(ptr as *mut i64).ok_or(EFAULT)?;
+    let mut ptlock = thread.thread.process.page_table.lock();
+    *structure
+        .verify_fully_mapped_mut(&mut ptlock)
+        .ok_or(EFAULT)? = offset as i64;
+    Ok(())
+}
+
+/// Copies between two fds inside the kernel, the offsets that were given are updated instead of
+/// the positions of the fds
+fn copy_fds(
+    thread: &ProcThreadInfo,
+    syscall: u64,
+    (fd_in, off_in): (u64, u64),
+    (fd_out, off_out): (u64, u64),
+    len: u64,
+) -> u64 {
+    let io_ctx = thread.thread.process.io_context.lock();
+    let (Some(file_in), Some(file_out)) = (
+        io_ctx.file_table.get(fd_in as usize),
+        io_ctx.file_table.get(fd_out as usize),
+    ) else {
+        linux_return_err_from_syscall!(EBADF)
+    };
+    drop(io_ctx);
+    if !file_in.is_seekable() {
+        linux_return_err_from_syscall!(EINVAL)
+    }
+    let (offset_in, offset_out) = match (
+        read_copy_offset(thread, off_in),
+        read_copy_offset(thread, off_out),
+    ) {
+        (Ok(offset_in), Ok(offset_out)) => (offset_in, offset_out),
+        (Err(errno), _) | (_, Err(errno)) => linux_return_err_from_syscall!(errno),
+    };
+
+    let copied = match file_in.copy_to(offset_in, &file_out, offset_out, len) {
+        Ok(copied) => copied,
+        Err(VfsError::WouldBlock) => {
+            drop(file_in);
+            return wait_for_file(thread, file_out, syscall, PollEvent::Out.into());
+        }
+        Err(e) => linux_return_err_from_syscall!(vfs_err_to_linux_errno(e)),
+    };
+    for (ptr, offset) in [(off_in, offset_in), (off_out, offset_out)] {
+        if let Some(offset) = offset {
+            if let Err(errno) = write_copy_offset(thread, ptr, offset + copied) {
+                linux_return_err_from_syscall!(errno)
+            }
+        }
+    }
+    copied
+}
+
+/// `in_fd` has to be a file, `out_fd` can be a stream and is always written at its position
+pub fn linux_sys_sendfile(
+    thread: &ProcThreadInfo,
+    out_fd: u64,
+    in_fd: u64,
+    offset: u64,
+    count: u64,
+) -> u64 {
+    copy_fds(thread, SYS_SENDFILE, (in_fd, offset), (out_fd, 0), count)
+}
+
+/// Both fds have to be files, `flags` must be 0
+pub fn linux_sys_copy_file_range(
+    thread: &ProcThreadInfo,
+    fd_in: u64,
+    off_in: u64,
+    fd_out: u64,
+    off_out: u64,
+    len: u64,
+    flags: u64,
+) -> u64 {
+    if flags != 0 {
+        linux_return_err_from_syscall!(EINVAL)
+    }
+    let io_ctx = thread.thread.process.io_context.lock();
+    let seekable = io_ctx
+        .file_table
+        .get(fd_out as usize)
+        .map(|file| file.is_seekable());
+    drop(io_ctx);
+    if seekable == Some(false) {
+        linux_return_err_from_syscall!(EINVAL)
+    }
+    copy_fds(
+        thread,
+        SYS_COPY_FILE_RANGE,
+        (fd_in, off_in),
+        (fd_out, off_out),
+        len,
+    )
+}
+
 pub fn linux_sys_open(thread: &ProcThreadInfo, path: u64, flags: u64, mode: u64) -> u64 {
     let mut pt = PageTable::temporary_this();
 
@@ -169,50 +384,82 @@ pub fn linux_sys_open(thread: &ProcThreadInfo, path: u64, flags: u64, mode: u64)
         if flags.has(LinuxOpenFlag::Excl) {
             open_mode |= OPEN_MODE_FAIL_IF_EXISTS;
         }
+        if flags.has(LinuxOpenFlag::Directory) {
+            linux_return_err_from_syscall!(EINVAL)
+        }
     } else if flags.has(LinuxOpenFlag::Excl) {
         linux_return_err_from_syscall!(EINVAL)
     }
     if flags.has(LinuxOpenFlag::Append) {
         open_mode |= OPEN_MODE_APPEND;
     }
+    if flags.has(LinuxOpenFlag::NonBlock) {
+        open_mode |= OPEN_MODE_NONBLOCK;
+    }
 
     let path = user_buffer
         .iter()
         .map(|x| *x as char)
         .collect::<Vec<char>>();
 
-    let (fs, handle, _) = match File::open_raw(&path, open_mode, Permissions::from_u64(mode)) {
-        Ok(f) => f,
-        Err(e) => linux_return_err_from_syscall!(vfs_err_to_linux_errno(e)),
-    };
-
-    let stat = match File::get_stats0(&path) {
-        Ok(Some(s)) => s,
-        Ok(None) => linux_return_err_from_syscall!(ENOENT),
+    let access = thread.thread.process.access.lock().clone();
+    let umask = *thread.thread.process.umask.lock() as u64;
+    let VfsOpenFile {
+        fs,
+        file: opened_file,
+        handle,
+    } = match Vfs::open_path(
+        &get_vfs(),
+        &path,
+        open_mode,
+        Permissions::from_u64(mode & !umask),
+        access.credentials(),
+    ) {
+        Ok(opened) => opened,
         Err(e) => linux_return_err_from_syscall!(vfs_err_to_linux_errno(e)),
     };
 
-    if cant(thread, &stat, IoAction::Open(flags)) {
-        linux_return_err_from_syscall!(EPERM)
+    if flags.has(LinuxOpenFlag::Directory) && !opened_file.is_directory() {
+        let _ = close_path_handle(&fs, handle);
+        linux_return_err_from_syscall!(ENOTDIR)
     }
 
-    if flags.has(LinuxOpenFlag::Truncate) {
-        if open_mode & OPEN_MODE_WRITE != OPEN_MODE_WRITE {
-            linux_return_err_from_syscall!(EINVAL)
-        }
+    // O_TRUNC on a read only open is left unspecified by posix, it does nothing here
+    if flags.has(LinuxOpenFlag::Truncate) && open_mode & OPEN_MODE_WRITE != 0 {
         let mut gfs = fs.write();
-        if let Err(e) = gfs.fseek(handle, SeekPosition::FromStart(0)) {
+        let truncated = gfs
+            .fseek(handle, SeekPosition::FromStart(0))
+            .and_then(|_| gfs.ftruncate(handle));
+        if let Err(e) = truncated {
+            drop(gfs);
+            let _ = close_path_handle(&fs, handle);
             linux_return_err_from_syscall!(vfs_err_to_linux_errno(e))
         }
     }
 
+    let mut gfs = fs.write();
+    let seekable = gfs.fseekable(handle);
+    let identity = gfs.file_identity(&opened_file);
+    drop(gfs);
+    let file = OpenFile::opened(
+        fs,
+        handle,
+        seekable,
+        open_mode & OPEN_MODE_APPEND != 0,
+        Some(identity),
+    );
+
     let mut io_ctx = thread.thread.process.io_context.lock();
     match io_ctx.file_table.alloc_fd() {
         Some((idx, f)) => {
-            *f = Some((fs, handle));
+            *f = Some(file);
             idx as u64
         }
-        None => linux_return_err_from_syscall!(EMFILE),
+        None => {
+            drop(io_ctx);
+            let _ = close_path_handle(&file.fs, file.handle);
+            linux_return_err_from_syscall!(EMFILE)
+        }
     }
 }
 
@@ -249,12 +496,12 @@ pub fn linux_sys_pipe(thread: &ProcThreadInfo, fds: u64) -> u64 {
             let Some(readfd) = io_ctx.file_table.get_fd(read) else {
                 linux_return_err_from_syscall!(EINVAL)
             };
-            *readfd = Some((pipe_fs.clone(), pipe_read));
+            *readfd = Some(OpenFile::stream(pipe_fs.clone(), pipe_read));
 
             let Some(writefs) = io_ctx.file_table.get_fd(write) else {
                 linux_return_err_from_syscall!(EINVAL)
             };
-            *writefs = Some((pipe_fs, pipe_write));
+            *writefs = Some(OpenFile::stream(pipe_fs, pipe_write));
 
             fds.read = read as u64;
             fds.write = write as u64;
@@ -267,66 +514,99 @@ pub fn linux_sys_pipe(thread: &ProcThreadInfo, fds: u64) -> u64 {
 
 pub fn linux_sys_close(thread: &ProcThreadInfo, fd: u64) -> u64 {
     let mut io_ctx = thread.thread.process.io_context.lock();
-    if let Some(Some((fs, handle))) = io_ctx.file_table.get_fd(fd as usize) {
-        let mut gfs = fs.write();
-        match gfs.fclose(*handle) {
-            Ok(_) => {}
-            Err(e) => linux_return_err_from_syscall!(vfs_err_to_linux_errno(e)),
-        }
-        drop(gfs);
-        0
-    } else {
+    match io_ctx.file_table.close_fd(fd as usize) {
+        Ok(()) => 0,
+        Err(VfsError::BadHandle) => linux_return_err_from_syscall!(EBADF),
+        Err(e) => linux_return_err_from_syscall!(vfs_err_to_linux_errno(e)),
+    }
+}
+
+pub fn linux_sys_dup(thread: &ProcThreadInfo, fd: u64) -> u64 {
+    let mut io_ctx = thread.thread.process.io_context.lock();
+    if io_ctx.file_table.get(fd as usize).is_none() {
         linux_return_err_from_syscall!(EBADF)
     }
+    match io_ctx.file_table.dup(fd as usize) {
+        Some(new_fd) => new_fd as u64,
+        None => linux_return_err_from_syscall!(EMFILE),
+    }
+}
+
+/// Only the terminal requests are known, their argument is copied from and to `arg` as a whole
+pub fn linux_sys_ioctl(thread: &ProcThreadInfo, fd: u64, request: u64, arg: u64) -> u64 {
+    let Some(size) = ioctl_argument_size(request) else {
+        linux_return_err_from_syscall!(ENOTTY)
+    };
+    let io_ctx = thread.thread.process.io_context.lock();
+    let Some(file) = io_ctx.file_table.get(fd as usize) else {
+        linux_return_err_from_syscall!(EBADF)
+    };
+    drop(io_ctx);
+
+    if !matches!(
+        get_address_space(arg),
+        Some(VirtualAddressSpace::LowerHalf(..))
+    ) {
+        linux_return_err_from_syscall!(EFAULT)
+    }
+    let mut ptlock = thread.thread.process.page_table.lock();
+    let mut user_buffer = UserProcessBuffer::new(arg as *mut u8, size);
+    let Some(arg) = user_buffer.verify_fully_mapped_mut(&mut ptlock) else {
+        linux_return_err_from_syscall!(EFAULT)
+    };
+    match file.ioctl(request, arg) {
+        Ok(result) => result,
+        Err(e) => linux_return_err_from_syscall!(vfs_err_to_linux_errno(e)),
+    }
+}
+
+/// Advisory lock shared by every fd duplicated from the same open, released with the last of them
+pub fn linux_sys_flock(thread: &ProcThreadInfo, fd: u64, operation: u64) -> u64 {
+    let kind = match operation & !LOCK_NB {
+        LOCK_SH => Some(FileLockKind::Shared),
+        LOCK_EX => Some(FileLockKind::Exclusive),
+        LOCK_UN => None,
+        _ => linux_return_err_from_syscall!(EINVAL),
+    };
+
+    let io_ctx = thread.thread.process.io_context.lock();
+    let Some(file) = io_ctx.file_table.get(fd as usize) else {
+        linux_return_err_from_syscall!(EBADF)
+    };
+    drop(io_ctx);
+    let key = file.lock_key();
+    let owner = file.lock_owner();
+    drop(file);
+
+    let mut locks = FILE_LOCKS.lock();
+    let Some(kind) = kind else {
+        locks.unlock(key, owner);
+        return 0;
+    };
+    match locks.try_lock(key, owner, kind) {
+        Ok(()) => 0,
+        Err(_) if operation & LOCK_NB != 0 => linux_return_err_from_syscall!(EWOULDBLOCK),
+        // Registered before releasing the table, so an unlock can't be missed
+        Err(queue) => queue.wait(thread, SYS_FLOCK, None, || drop(locks)),
+    }
 }
 
 pub fn linux_sys_lseek(thread: &ProcThreadInfo, fd: u64, offset: u64, whence: u64) -> u64 {
-    match whence {
-        WHENCE_SET => {
-            let mut io_ctx = thread.thread.process.io_context.lock();
-            if let Some(Some((fs, handle))) = io_ctx.file_table.get_fd(fd as usize) {
-                let mut gfs = fs.write();
-                match gfs.fseek(*handle, SeekPosition::FromStart(offset)) {
-                    Ok(_) => {}
-                    Err(e) => linux_return_err_from_syscall!(vfs_err_to_linux_errno(e)),
-                }
-                drop(gfs);
-                0
-            } else {
-                linux_return_err_from_syscall!(EBADF)
-            }
-        }
-        WHENCE_CUR => {
-            let mut io_ctx = thread.thread.process.io_context.lock();
-            if let Some(Some((fs, handle))) = io_ctx.file_table.get_fd(fd as usize) {
-                let mut gfs = fs.write();
-                match gfs.fseek(*handle, SeekPosition::FromCurrent(offset as i64)) {
-                    Ok(_) => {}
-                    Err(e) => linux_return_err_from_syscall!(vfs_err_to_linux_errno(e)),
-                }
-                drop(gfs);
-                0
-            } else {
-                linux_return_err_from_syscall!(EBADF)
-            }
-        }
-        WHENCE_END => {
-            let mut io_ctx = thread.thread.process.io_context.lock();
-            if let Some(Some((fs, handle))) = io_ctx.file_table.get_fd(fd as usize) {
-                let mut gfs = fs.write();
-                match gfs.fseek(*handle, SeekPosition::FromEnd(offset)) {
-                    Ok(_) => {}
-                    Err(e) => linux_return_err_from_syscall!(vfs_err_to_linux_errno(e)),
-                }
-                drop(gfs);
-                0
-            } else {
-                linux_return_err_from_syscall!(EBADF)
-            }
-        }
-        _ => {
-            linux_return_err_from_syscall!(EINVAL)
-        }
+    let position = match whence {
+        WHENCE_SET => SeekPosition::FromStart(offset),
+        WHENCE_CUR => SeekPosition::FromCurrent(offset as i64),
+        WHENCE_END => SeekPosition::FromEnd(offset),
+        _ => linux_return_err_from_syscall!(EINVAL),
+    };
+
+    let io_ctx = thread.thread.process.io_context.lock();
+    let Some(file) = io_ctx.file_table.get(fd as usize) else {
+        linux_return_err_from_syscall!(EBADF)
+    };
+    drop(io_ctx);
+    match file.seek(position) {
+        Ok(position) => position,
+        Err(e) => linux_return_err_from_syscall!(vfs_err_to_linux_errno(e)),
     }
 }
 
@@ -364,12 +644,9 @@ pub fn linux_sys_mkdir(thread: &ProcThreadInfo, path: u64, mode: u64) -> u64 {
         Err(e) => linux_return_err_from_syscall!(vfs_err_to_linux_errno(e)),
     };
 
-    if cant(
-        thread,
-        &parent,
-        IoAction::CreateChild(VfsFileKind::Directory, mode),
-    ) {
-        linux_return_err_from_syscall!(EPERM)
+    let access = thread.thread.process.access.lock().clone();
+    if !access.credentials().can_create_in(&parent) {
+        linux_return_err_from_syscall!(EACCES)
     }
 
     if !parent.is_directory {
@@ -411,16 +688,95 @@ pub fn linux_sys_rmdir(thread: &ProcThreadInfo, path: u64) -> u64 {
         Err(e) => linux_return_err_from_syscall!(vfs_err_to_linux_errno(e)),
     };
 
-    if cant(thread, &file, IoAction::Rmdir) {
-        linux_return_err_from_syscall!(EPERM)
-    }
-
     if !file.is_directory {
         linux_return_err_from_syscall!(ENOTDIR)
     }
 
-    match File::delete0(&user_cstr) {
+    let access = thread.thread.process.access.lock().clone();
+    match File::delete0(&user_cstr, access.credentials()) {
         Ok(_) => 0,
         Err(e) => linux_return_err_from_syscall!(vfs_err_to_linux_errno(e)),
     }
 }
+
+const AT_SYMLINK_FOLLOW: u64 = 0x400;
+
+fn copy_path(path: u64) -> Option<Vec<char>> {
+    let mut pt = PageTable::temporary_this();
+    let Some((user_buffer, true)) = UserProcessBuffer::copy_user_c_str(&mut pt, path, MAX_PATH_LEN)
+    else {
+        return None;
+    };
+    drop(pt);
+    Some(user_buffer.iter().map(|x| *x as char).collect())
+}
+
+fn link(thread: &ProcThreadInfo, existing: &[char], new_path: &[char]) -> u64 {
+    let access = thread.thread.process.access.lock().clone();
+    match File::link0(existing, new_path, access.credentials()) {
+        Ok(()) => 0,
+        Err(VfsError::FileSystemMismatch) => linux_return_err_from_syscall!(EXDEV),
+        Err(VfsError::MaximumSizeReached) => linux_return_err_from_syscall!(EMLINK),
+        Err(e) => linux_return_err_from_syscall!(vfs_err_to_linux_errno(e)),
+    }
+}
+
+pub fn linux_sys_link(thread: &ProcThreadInfo, old_path: u64, new_path: u64) -> u64 {
+    let (Some(old_path), Some(new_path)) = (copy_path(old_path), copy_path(new_path)) else {
+        linux_return_err_from_syscall!(EFAULT)
+    };
+    link(thread, &old_path, &new_path)
+}
+
+/// Only absolute paths or paths relative to AT_FDCWD are supported
+pub fn linux_sys_linkat(
+    thread: &ProcThreadInfo,
+    old_dirfd: u64,
+    old_path: u64,
+    new_dirfd: u64,
+    new_path: u64,
+    flags: u64,
+) -> u64 {
+    // There are no symlinks yet, following them changes nothing
+    if flags & !AT_SYMLINK_FOLLOW != 0 {
+        linux_return_err_from_syscall!(EINVAL)
+    }
+    let (Some(old_path), Some(new_path)) = (copy_path(old_path), copy_path(new_path)) else {
+        linux_return_err_from_syscall!(EFAULT)
+    };
+    for (dirfd, path) in [(old_dirfd, &old_path), (new_dirfd, &new_path)] {
+        if path.first() != Some(&'/') && dirfd as i64 != AT_FDCWD {
+            linux_return_err_from_syscall!(ENOTSUP)
+        }
+    }
+    link(thread, &old_path, &new_path)
+}
+
+const S_IFMT: u64 = 0o170000;
+const S_IFCHR: u64 = 0o020000;
+const S_IFBLK: u64 = 0o060000;
+
+/// Only device nodes can be made, by root. The permission bits of `mode` are ignored
+pub fn linux_sys_mknod(thread: &ProcThreadInfo, path: u64, mode: u64, dev: u64) -> u64 {
+    let block = match mode & S_IFMT {
+        S_IFCHR => false,
+        S_IFBLK => true,
+        _ => linux_return_err_from_syscall!(EINVAL),
+    };
+    let access = thread.thread.process.access.lock().clone();
+    if !access.is_root() {
+        linux_return_err_from_syscall!(EPERM)
+    }
+    let Some(path) = copy_path(path) else {
+        linux_return_err_from_syscall!(EFAULT)
+    };
+    // Same encoding as glibc's makedev
+    let number = DeviceNumber::new(
+        (((dev >> 8) & 0xfff) | ((dev >> 32) & !0xfff)) as u32,
+        ((dev & 0xff) | ((dev >> 12) & !0xff)) as u32,
+    );
+    match File::mknod0(&path, block, number, access.credentials()) {
+        Ok(()) => 0,
+        Err(e) => linux_return_err_from_syscall!(vfs_err_to_linux_errno(e)),
+    }
+}
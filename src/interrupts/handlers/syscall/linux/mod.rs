@@ -1,16 +1,42 @@
-use processes::linux_sys_exit;
-
 use crate::{
     drivers::vfs::VfsError,
     interrupts::{
         handlers::syscall::linux::{
+            attributes::{
+                linux_sys_chmod, linux_sys_chown, linux_sys_fchmod, linux_sys_fchown,
+                linux_sys_fstatfs, linux_sys_statfs, linux_sys_umask, linux_sys_utimensat,
+            },
+            futex::linux_sys_futex,
             io::{
-                linux_sys_close, linux_sys_lseek, linux_sys_mkdir, linux_sys_open, linux_sys_pipe,
-                linux_sys_read, linux_sys_write,
+                linux_sys_close, linux_sys_copy_file_range, linux_sys_dup, linux_sys_flock,
+                linux_sys_ioctl, linux_sys_link, linux_sys_linkat, linux_sys_lseek,
+                linux_sys_mkdir, linux_sys_mknod, linux_sys_open, linux_sys_pipe,
+                linux_sys_pread64, linux_sys_pwrite64, linux_sys_read, linux_sys_sendfile,
+                linux_sys_write,
             },
             kernel_info::linux_sys_uname,
+            mount::{linux_sys_mount, linux_sys_umount2},
+            poll::{linux_sys_poll, linux_sys_ppoll},
+            power::linux_sys_reboot,
             processes::{
-                linux_sys_arch_prctl, linux_sys_get_pid, linux_sys_get_tid, linux_sys_sched_yield,
+                linux_sys_arch_prctl, linux_sys_clone, linux_sys_exit, linux_sys_exit_group,
+                linux_sys_get_egid, linux_sys_get_euid, linux_sys_get_gid, linux_sys_get_pid,
+                linux_sys_get_ppid, linux_sys_get_tid, linux_sys_get_uid, linux_sys_sched_yield,
+                linux_sys_set_tid_address, linux_sys_setgid, linux_sys_setuid, linux_sys_wait4,
+            },
+            ptrace::linux_sys_ptrace,
+            random::linux_sys_getrandom,
+            resources::{
+                linux_sys_getrlimit, linux_sys_getrusage, linux_sys_prlimit64, linux_sys_setrlimit,
+                linux_sys_times,
+            },
+            socket::{
+                linux_sys_accept, linux_sys_bind, linux_sys_connect, linux_sys_listen,
+                linux_sys_recvfrom, linux_sys_sendto, linux_sys_socket, linux_sys_socketpair,
+            },
+            timers::{
+                linux_sys_alarm, linux_sys_clock_gettime, linux_sys_setitimer,
+                linux_sys_timerfd_create, linux_sys_timerfd_settime,
             },
         },
         idt::{InterruptFrameContext, InterruptFrameExtra, InterruptFrameRegisters},
@@ -20,30 +46,72 @@ use crate::{
     process::scheduler::ProcThreadInfo,
 };
 
+pub mod attributes;
+pub mod futex;
 pub mod io;
 pub mod kernel_info;
+pub mod mount;
+pub mod poll;
+pub mod power;
 pub mod processes;
+pub mod ptrace;
+pub mod random;
+pub mod resources;
+pub mod socket;
+pub mod timers;
 
 pub const EPERM: u64 = 1;
 pub const ENOENT: u64 = 2;
+pub const ESRCH: u64 = 3;
+pub const EINTR: u64 = 4;
 pub const EIO: u64 = 5;
+pub const ENXIO: u64 = 6;
 pub const EBADF: u64 = 9;
+pub const ECHILD: u64 = 10;
 pub const EWOULDBLOCK: u64 = 11;
+pub const EAGAIN: u64 = EWOULDBLOCK;
+pub const ENOMEM: u64 = 12;
+pub const EACCES: u64 = 13;
+pub const EFAULT: u64 = 14;
+pub const ENOTBLK: u64 = 15;
+pub const EBUSY: u64 = 16;
 pub const EEXIST: u64 = 17;
+pub const EXDEV: u64 = 18;
+pub const ENODEV: u64 = 19;
 pub const ENOTDIR: u64 = 20;
 pub const EISDIR: u64 = 21;
 pub const EINVAL: u64 = 22;
 pub const EMFILE: u64 = 24;
+pub const ENOTTY: u64 = 25;
+pub const EFBIG: u64 = 27;
 pub const ENOSPC: u64 = 28;
 pub const ESPIPE: u64 = 29;
 pub const EROFS: u64 = 30;
+pub const EMLINK: u64 = 31;
 pub const EPIPE: u64 = 32;
+pub const ENAMETOOLONG: u64 = 36;
 pub const ENOSYS: u64 = 38;
 pub const ENOTEMPTY: u64 = 39;
+pub const ELOOP: u64 = 40;
 pub const ENODATA: u64 = 61;
+pub const ENOTSOCK: u64 = 88;
+pub const EDESTADDRREQ: u64 = 89;
+pub const EMSGSIZE: u64 = 90;
+pub const EPROTONOSUPPORT: u64 = 93;
+pub const ESOCKTNOSUPPORT: u64 = 94;
 pub const ENOTSUP: u64 = 95;
+pub const EAFNOSUPPORT: u64 = 97;
+pub const EADDRINUSE: u64 = 98;
+pub const EADDRNOTAVAIL: u64 = 99;
+pub const ENETUNREACH: u64 = 101;
+pub const ETIMEDOUT: u64 = 110;
+pub const EISCONN: u64 = 106;
+pub const ECONNREFUSED: u64 = 111;
 
+pub const SIGTRAP: u64 = 5;
 pub const SIGKILL: u64 = 9;
+pub const SIGALRM: u64 = 14;
+pub const SIGSTOP: u64 = 19;
 
 pub const WHENCE_SET: u64 = 0;
 pub const WHENCE_CUR: u64 = 1;
@@ -76,9 +144,9 @@ fn linux_syscall0(
     arg0: u64,
     arg1: u64,
     arg2: u64,
-    _arg3: u64,
-    _arg4: u64,
-    _arg5: u64,
+    arg3: u64,
+    arg4: u64,
+    arg5: u64,
     thread: &ProcThreadInfo,
 ) -> u64 {
     match intno {
@@ -86,15 +154,70 @@ fn linux_syscall0(
         1 => linux_sys_write(thread, arg0, arg1, arg2),
         2 => linux_sys_open(thread, arg0, arg1, arg2),
         3 => linux_sys_close(thread, arg0),
+        7 => linux_sys_poll(thread, arg0, arg1, arg2),
         8 => linux_sys_lseek(thread, arg0, arg1, arg2),
+        16 => linux_sys_ioctl(thread, arg0, arg1, arg2),
+        17 => linux_sys_pread64(thread, arg0, arg1, arg2, arg3),
+        18 => linux_sys_pwrite64(thread, arg0, arg1, arg2, arg3),
         22 => linux_sys_pipe(thread, arg0),
         24 => linux_sys_sched_yield(thread),
+        32 => linux_sys_dup(thread, arg0),
+        37 => linux_sys_alarm(thread, arg0),
+        38 => linux_sys_setitimer(thread, arg0, arg1, arg2),
         39 => linux_sys_get_pid(thread),
-        60 => linux_sys_exit(thread.tid, arg0),
+        40 => linux_sys_sendfile(thread, arg0, arg1, arg2, arg3),
+        41 => linux_sys_socket(thread, arg0, arg1, arg2),
+        42 => linux_sys_connect(thread, arg0, arg1, arg2),
+        43 => linux_sys_accept(thread, arg0, arg1, arg2),
+        44 => linux_sys_sendto(thread, arg0, arg1, arg2, arg3, arg4, arg5),
+        45 => linux_sys_recvfrom(thread, arg0, arg1, arg2, arg3, arg4, arg5),
+        49 => linux_sys_bind(thread, arg0, arg1, arg2),
+        50 => linux_sys_listen(thread, arg0, arg1),
+        53 => linux_sys_socketpair(thread, arg0, arg1, arg2, arg3),
+        56 => linux_sys_clone(thread, arg0, arg1, arg2, arg3, arg4),
+        60 => linux_sys_exit(thread, arg0),
+        61 => linux_sys_wait4(thread, arg0, arg1, arg2, arg3),
         63 => linux_sys_uname(thread, arg0),
+        73 => linux_sys_flock(thread, arg0, arg1),
         83 => linux_sys_mkdir(thread, arg0, arg1),
+        86 => linux_sys_link(thread, arg0, arg1),
+        90 => linux_sys_chmod(thread, arg0, arg1),
+        91 => linux_sys_fchmod(thread, arg0, arg1),
+        92 => linux_sys_chown(thread, arg0, arg1, arg2),
+        93 => linux_sys_fchown(thread, arg0, arg1, arg2),
+        95 => linux_sys_umask(thread, arg0),
+        97 => linux_sys_getrlimit(thread, arg0, arg1),
+        98 => linux_sys_getrusage(thread, arg0, arg1),
+        100 => linux_sys_times(thread, arg0),
+        101 => linux_sys_ptrace(thread, arg0, arg1, arg2, arg3),
+        102 => linux_sys_get_uid(thread),
+        104 => linux_sys_get_gid(thread),
+        105 => linux_sys_setuid(thread, arg0),
+        106 => linux_sys_setgid(thread, arg0),
+        107 => linux_sys_get_euid(thread),
+        108 => linux_sys_get_egid(thread),
+        110 => linux_sys_get_ppid(thread),
+        133 => linux_sys_mknod(thread, arg0, arg1, arg2),
+        137 => linux_sys_statfs(thread, arg0, arg1),
+        138 => linux_sys_fstatfs(thread, arg0, arg1),
         158 => linux_sys_arch_prctl(thread, arg0, arg1),
+        160 => linux_sys_setrlimit(thread, arg0, arg1),
+        165 => linux_sys_mount(thread, arg0, arg1, arg2, arg3, arg4),
+        166 => linux_sys_umount2(thread, arg0, arg1),
+        169 => linux_sys_reboot(thread, arg0, arg1, arg2),
         186 => linux_sys_get_tid(thread),
+        202 => linux_sys_futex(thread, arg0, arg1, arg2, arg3),
+        218 => linux_sys_set_tid_address(thread, arg0),
+        228 => linux_sys_clock_gettime(thread, arg0, arg1),
+        231 => linux_sys_exit_group(thread, arg0),
+        265 => linux_sys_linkat(thread, arg0, arg1, arg2, arg3, arg4),
+        271 => linux_sys_ppoll(thread, arg0, arg1, arg2),
+        280 => linux_sys_utimensat(thread, arg0, arg1, arg2, arg3),
+        283 => linux_sys_timerfd_create(thread, arg0, arg1),
+        286 => linux_sys_timerfd_settime(thread, arg0, arg1, arg2, arg3),
+        302 => linux_sys_prlimit64(thread, arg0, arg1, arg2, arg3),
+        318 => linux_sys_getrandom(thread, arg0, arg1, arg2),
+        326 => linux_sys_copy_file_range(thread, arg0, arg1, arg2, arg3, arg4, arg5),
         _ => {
             if cfg!(debug_assertions) {
                 println!("Unknown syscall: {}", intno);
@@ -139,6 +262,7 @@ pub fn linux_syscall_fast(thread: &ProcThreadInfo) -> bool {
     (res as i64) >= 0
 }
 
+/// No catch-all arm, so a new variant doesn't build until it has an errno
 pub fn vfs_err_to_linux_errno(err: VfsError) -> u64 {
     match err {
         VfsError::PathNotFound | VfsError::EntryNotFound => ENOENT,
@@ -150,15 +274,24 @@ pub fn vfs_err_to_linux_errno(err: VfsError) -> u64 {
         VfsError::DirectoryNotEmpty => ENOTEMPTY,
         VfsError::NotDirectory => ENOTDIR,
         VfsError::NotFile => EISDIR,
-        VfsError::BrokenPipe => ESPIPE,
-        VfsError::WouldBlock => EWOULDBLOCK,
-        VfsError::AlreadyMounted => EEXIST,
-        VfsError::NameTooLong => EINVAL,
+        VfsError::BrokenPipe => EPIPE,
+        VfsError::WouldBlock => EAGAIN,
+        VfsError::Interrupted => EINTR,
+        VfsError::PermissionDenied => EACCES,
+        VfsError::AlreadyMounted => EBUSY,
+        VfsError::Busy => EBUSY,
+        VfsError::NoSuchDevice => ENXIO,
+        VfsError::NotTerminal => ENOTTY,
+        VfsError::HungUp => EIO,
+        VfsError::StaleDevice => ENXIO,
+        VfsError::NameTooLong => ENAMETOOLONG,
+        VfsError::SymlinkLoop => ELOOP,
         VfsError::FileSystemMismatch => EINVAL,
         VfsError::FileSystemNotMounted => ENOENT,
         VfsError::ReadOnly => EROFS,
         VfsError::OutOfSpace => ENOSPC,
-        VfsError::MaximumSizeReached => EINVAL,
+        VfsError::OutOfMemory => ENOMEM,
+        VfsError::MaximumSizeReached => EFBIG,
         VfsError::InvalidDataStructure => EINVAL,
         VfsError::NotMountPoint => EINVAL,
         VfsError::OutOfBounds => EINVAL,
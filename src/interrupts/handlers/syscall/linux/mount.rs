@@ -0,0 +1,176 @@
+use alloc::{
+    string::{String, ToString},
+    vec::Vec,
+};
+
+use crate::{
+    data::path::VfsPath,
+    drivers::{
+        fs::mount::{mount_device, MountError, MountOptions},
+        vfs::{get_vfs, VfsError, MS_RDONLY},
+    },
+    interrupts::handlers::syscall::{
+        linux::{
+            attributes::copy_path, vfs_err_to_linux_errno, EBUSY, EFAULT, EINVAL, ENODEV, ENOENT,
+            ENOTBLK, ENOTDIR, EPERM,
+        },
+        utils::buffer::UserProcessBuffer,
+    },
+    linux_return_err_from_syscall,
+    paging::PageTable,
+    process::scheduler::ProcThreadInfo,
+};
+
+pub const MS_REMOUNT: u64 = 32;
+pub const MS_BIND: u64 = 4096;
+// Accepted and ignored, mounts have no such restrictions yet
+pub const MS_NOSUID: u64 = 2;
+pub const MS_NODEV: u64 = 4;
+pub const MS_NOEXEC: u64 = 8;
+pub const MS_SILENT: u64 = 32768;
+
+pub const MNT_FORCE: u64 = 1;
+pub const MNT_DETACH: u64 = 2;
+pub const UMOUNT_NOFOLLOW: u64 = 8;
+
+const MAX_MOUNT_DATA_LEN: u64 = 4096;
+
+fn copy_string(addr: u64) -> Option<String> {
+    let mut pt = PageTable::temporary_this();
+    let Some((bytes, true)) = UserProcessBuffer::copy_user_c_str(&mut pt, addr, MAX_MOUNT_DATA_LEN)
+    else {
+        return None;
+    };
+    String::from_utf8(bytes).ok()
+}
+
+fn mount_err_to_linux_errno(err: MountError) -> u64 {
+    match err {
+        MountError::BadEntry(_) | MountError::BadOption(_) => EINVAL,
+        MountError::UnknownFileSystem(_) => ENODEV,
+        MountError::DeviceNotFound(_) => ENOENT,
+        MountError::NotBlockDevice(_) => ENOTBLK,
+        MountError::Vfs(e) => vfs_err_to_linux_errno(e),
+    }
+}
+
+/// The mount point, which has to be an existing directory below `/`
+fn mount_target(path: &[char]) -> Result<Vec<char>, u64> {
+    let stat = get_vfs()
+        .write()
+        .stat_path(path)
+        .map_err(vfs_err_to_linux_errno)?;
+    if !stat.is_directory {
+        return Err(ENOTDIR);
+    }
+    let name = VfsPath::from(path).to_chars();
+    let name = name
+        .iter()
+        .copied()
+        .skip_while(|c| *c == '/')
+        .collect::<Vec<_>>();
+    if name.is_empty() {
+        return Err(EBUSY);
+    }
+    Ok(name)
+}
+
+/// Mounts a block device, or with `MS_BIND` a directory, at `target`. `MS_REMOUNT` changes the
+/// read-only flag of an existing mount. Only root can mount
+pub fn linux_sys_mount(
+    thread: &ProcThreadInfo,
+    source: u64,
+    target: u64,
+    fstype: u64,
+    flags: u64,
+    data: u64,
+) -> u64 {
+    if !thread.thread.process.access.lock().is_root() {
+        linux_return_err_from_syscall!(EPERM)
+    }
+    if flags & !(MS_RDONLY | MS_REMOUNT | MS_BIND | MS_NOSUID | MS_NODEV | MS_NOEXEC | MS_SILENT)
+        != 0
+    {
+        linux_return_err_from_syscall!(EINVAL)
+    }
+    let Some(target) = copy_path(target) else {
+        linux_return_err_from_syscall!(EFAULT)
+    };
+    let target = match mount_target(&target) {
+        Ok(target) => target,
+        Err(errno) => linux_return_err_from_syscall!(errno),
+    };
+    let mount_flags = flags & MS_RDONLY;
+
+    if flags & MS_REMOUNT != 0 {
+        return match get_vfs().write().remount(&target, mount_flags) {
+            Ok(()) => 0,
+            Err(VfsError::PathNotFound) => linux_return_err_from_syscall!(EINVAL),
+            Err(e) => linux_return_err_from_syscall!(vfs_err_to_linux_errno(e)),
+        };
+    }
+
+    let Some(source) = copy_path(source) else {
+        linux_return_err_from_syscall!(EFAULT)
+    };
+    if flags & MS_BIND != 0 {
+        let result = get_vfs()
+            .write()
+            .bind_mount(&source, &target, mount_flags)
+            .map(|_| ());
+        return match result {
+            Ok(()) => 0,
+            Err(e) => linux_return_err_from_syscall!(vfs_err_to_linux_errno(e)),
+        };
+    }
+
+    let Some(fstype) = copy_string(fstype) else {
+        linux_return_err_from_syscall!(EFAULT)
+    };
+    let options = if data == 0 {
+        Ok(MountOptions::default())
+    } else {
+        let Some(data) = copy_string(data) else {
+            linux_return_err_from_syscall!(EFAULT)
+        };
+        MountOptions::parse(&data)
+    };
+    let source = VfsPath::from(&source).to_string();
+    match options.and_then(|options| mount_device(&source, &target, &fstype, &options, mount_flags))
+    {
+        Ok(()) => 0,
+        Err(e) => linux_return_err_from_syscall!(mount_err_to_linux_errno(e)),
+    }
+}
+
+/// `MNT_DETACH` unmounts once the last file open on the file system is closed, otherwise
+/// open files make it fail with EBUSY. Only root can unmount
+pub fn linux_sys_umount2(thread: &ProcThreadInfo, target: u64, flags: u64) -> u64 {
+    if !thread.thread.process.access.lock().is_root() {
+        linux_return_err_from_syscall!(EPERM)
+    }
+    if flags & !(MNT_FORCE | MNT_DETACH | UMOUNT_NOFOLLOW) != 0 {
+        linux_return_err_from_syscall!(EINVAL)
+    }
+    let Some(target) = copy_path(target) else {
+        linux_return_err_from_syscall!(EFAULT)
+    };
+    let target = match mount_target(&target) {
+        Ok(target) => target,
+        Err(errno) => linux_return_err_from_syscall!(errno),
+    };
+
+    let vfs = get_vfs();
+    let mut guard = vfs.write();
+    let result = if flags & MNT_DETACH != 0 {
+        guard.unmount_lazy(&target)
+    } else {
+        guard.unmount(&target)
+    };
+    match result {
+        Ok(()) => 0,
+        // Not a mount point
+        Err(VfsError::PathNotFound) => linux_return_err_from_syscall!(EINVAL),
+        Err(e) => linux_return_err_from_syscall!(vfs_err_to_linux_errno(e)),
+    }
+}
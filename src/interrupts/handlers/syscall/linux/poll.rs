@@ -0,0 +1,201 @@
+use alloc::{sync::Arc, vec::Vec};
+
+use crate::{
+    drivers::vfs::{PollEvent, PollEvents, VfsError, POLL_ALWAYS_REPORTED},
+    interrupts::{
+        handlers::{
+            irq::irq0_timer::get_uptime_ticks,
+            syscall::{
+                linux::{EFAULT, EINVAL},
+                utils::{buffer::UserProcessBuffer, structure::UserProcessStructure},
+            },
+        },
+        pit::{ms_to_ticks, ticks_to_ms},
+    },
+    linux_return_err_from_syscall,
+    process::{
+        memory::{get_address_space, VirtualAddressSpace},
+        scheduler::{ProcThreadInfo, SCHEDULER},
+        wait::WaitQueue,
+    },
+};
+
+pub const SYS_POLL: u64 = 7;
+pub const SYS_PPOLL: u64 = 271;
+
+const MAX_POLL_FDS: u64 = 1024;
+const POLLFD_SIZE: u64 = 8;
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct LinuxTimespec {
+    pub tv_sec: i64,
+    pub tv_nsec: i64,
+}
+
+impl LinuxTimespec {
+    pub fn from_ticks(ticks: u64) -> Self {
+        let ms = ticks_to_ms(ticks);
+        Self {
+            tv_sec: (ms / 1000) as i64,
+            tv_nsec: (ms % 1000 * 1_000_000) as i64,
+        }
+    }
+}
+
+enum PollOutcome {
+    Done(u64),
+    Block(Vec<Arc<WaitQueue>>),
+}
+
+pub fn linux_sys_poll(thread: &ProcThreadInfo, fds: u64, nfds: u64, timeout: u64) -> u64 {
+    // Any negative timeout means infinite
+    let timeout = timeout as i32;
+    let timeout = if timeout < 0 {
+        None
+    } else {
+        Some(ms_to_ticks(timeout as u64))
+    };
+    linux_poll_common(thread, SYS_POLL, fds, nfds, timeout)
+}
+
+/// The signal mask is ignored as there are no signals yet
+pub fn linux_sys_ppoll(thread: &ProcThreadInfo, fds: u64, nfds: u64, timeout: u64) -> u64 {
+    let timeout = if timeout == 0 {
+        None
+    } else {
+        let Some(timespec) = UserProcessStructure::<LinuxTimespec>::new(timeout as *mut _) else {
+            linux_return_err_from_syscall!(EFAULT)
+        };
+        let mut ptlock = thread.thread.process.page_table.lock();
+        let Some(timespec) = timespec.verify_fully_mapped(&mut ptlock).copied() else {
+            linux_return_err_from_syscall!(EFAULT)
+        };
+        drop(ptlock);
+        if timespec.tv_sec < 0 || !(0..1_000_000_000).contains(&timespec.tv_nsec) {
+            linux_return_err_from_syscall!(EINVAL)
+        }
+        let ms = (timespec.tv_sec as u64)
+            .saturating_mul(1000)
+            .saturating_add((timespec.tv_nsec as u64).div_ceil(1_000_000));
+        Some(ms_to_ticks(ms))
+    };
+    linux_poll_common(thread, SYS_PPOLL, fds, nfds, timeout)
+}
+
+fn linux_poll_common(
+    thread: &ProcThreadInfo,
+    syscall: u64,
+    fds: u64,
+    nfds: u64,
+    timeout: Option<u64>,
+) -> u64 {
+    if nfds > MAX_POLL_FDS {
+        linux_return_err_from_syscall!(EINVAL)
+    }
+
+    let queues = match poll_fds(thread, fds, nfds) {
+        Ok(PollOutcome::Block(queues)) => queues,
+        Ok(PollOutcome::Done(ready)) => {
+            thread.thread.wait.lock().finish();
+            return ready;
+        }
+        Err(errno) => {
+            thread.thread.wait.lock().finish();
+            linux_return_err_from_syscall!(errno)
+        }
+    };
+
+    let mut wait = thread.thread.wait.lock();
+    if timeout == Some(0) || wait.timed_out {
+        // revents were already written back
+        wait.finish();
+        return 0;
+    }
+    if wait.deadline.is_none() {
+        wait.deadline = timeout.map(|ticks| get_uptime_ticks() + ticks);
+    }
+    let deadline = wait.deadline;
+    drop(wait);
+
+    for queue in queues.iter() {
+        queue.register(thread);
+    }
+    drop(queues);
+
+    // The syscall runs again when a queue fires or the deadline is reached
+    SCHEDULER.block_current(thread, syscall, deadline)
+}
+
+/// Writes back the revents of every pollfd, and returns the number of ready fds.
+/// If none is ready, returns the queues to wait on
+fn poll_fds(thread: &ProcThreadInfo, fds: u64, nfds: u64) -> Result<PollOutcome, u64> {
+    if nfds == 0 {
+        return Ok(PollOutcome::Block(Vec::new()));
+    }
+
+    let size = nfds * POLLFD_SIZE;
+    let Some(end_addr) = fds.checked_add(size) else {
+        return Err(EFAULT);
+    };
+    if !matches!(
+        get_address_space(fds),
+        Some(VirtualAddressSpace::LowerHalf(..))
+    ) || !matches!(
+        get_address_space(end_addr),
+        Some(VirtualAddressSpace::LowerHalf(..))
+    ) {
+        return Err(EFAULT);
+    }
+
+    let mut ptlock = thread.thread.process.page_table.lock();
+    let mut user_buffer = UserProcessBuffer::new(fds as *mut u8, size as usize);
+    let Some(user_buf) = user_buffer.verify_fully_mapped_mut(&mut ptlock) else {
+        return Err(EFAULT);
+    };
+
+    let io_ctx = thread.thread.process.io_context.lock();
+    let mut ready = 0;
+    let mut queues = Vec::new();
+    for pollfd in user_buf.as_chunks_mut::<{ POLLFD_SIZE as usize }>().0 {
+        let fd = i32::from_ne_bytes([pollfd[0], pollfd[1], pollfd[2], pollfd[3]]);
+        let events = PollEvents::from(u16::from_ne_bytes([pollfd[4], pollfd[5]]));
+
+        let revents = if fd < 0 {
+            // Ignored, per POSIX
+            PollEvents::empty()
+        } else {
+            match io_ctx.file_table.get(fd as usize) {
+                Some(file) => {
+                    let mut gfs = file.fs.write();
+                    let revents = match gfs.fpoll(file.handle, events) {
+                        Ok(revents) => revents,
+                        Err(VfsError::BadHandle) => PollEvent::Invalid.into(),
+                        Err(_) => PollEvent::Error.into(),
+                    };
+                    if revents.is_empty() {
+                        if let Ok(Some(queue)) = gfs.fwait_queue(file.handle) {
+                            queues.push(queue);
+                        }
+                    }
+                    drop(gfs);
+                    revents & (events | POLL_ALWAYS_REPORTED)
+                }
+                None => PollEvent::Invalid.into(),
+            }
+        };
+
+        pollfd[6..8].copy_from_slice(&revents.get().to_ne_bytes());
+        if !revents.is_empty() {
+            ready += 1;
+        }
+    }
+    drop(io_ctx);
+    drop(ptlock);
+
+    if ready > 0 {
+        Ok(PollOutcome::Done(ready))
+    } else {
+        Ok(PollOutcome::Block(queues))
+    }
+}
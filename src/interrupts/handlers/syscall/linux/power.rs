@@ -0,0 +1,39 @@
+use crate::{
+    interrupts::handlers::syscall::linux::{EINVAL, EPERM},
+    linux_return_err_from_syscall, power,
+    process::scheduler::ProcThreadInfo,
+};
+
+pub const LINUX_REBOOT_MAGIC1: u32 = 0xfee1dead;
+pub const LINUX_REBOOT_MAGIC2: u32 = 672274793;
+pub const LINUX_REBOOT_MAGIC2A: u32 = 85072278;
+pub const LINUX_REBOOT_MAGIC2B: u32 = 369367448;
+pub const LINUX_REBOOT_MAGIC2C: u32 = 537993216;
+
+pub const LINUX_REBOOT_CMD_RESTART: u32 = 0x01234567;
+pub const LINUX_REBOOT_CMD_HALT: u32 = 0xCDEF0123;
+pub const LINUX_REBOOT_CMD_POWER_OFF: u32 = 0x4321FEDC;
+
+pub fn linux_sys_reboot(thread: &ProcThreadInfo, magic1: u64, magic2: u64, cmd: u64) -> u64 {
+    if !thread.thread.process.access.lock().is_root() {
+        linux_return_err_from_syscall!(EPERM)
+    }
+    if magic1 as u32 != LINUX_REBOOT_MAGIC1
+        || !matches!(
+            magic2 as u32,
+            LINUX_REBOOT_MAGIC2
+                | LINUX_REBOOT_MAGIC2A
+                | LINUX_REBOOT_MAGIC2B
+                | LINUX_REBOOT_MAGIC2C
+        )
+    {
+        linux_return_err_from_syscall!(EINVAL)
+    }
+
+    match cmd as u32 {
+        LINUX_REBOOT_CMD_RESTART => power::reboot(),
+        LINUX_REBOOT_CMD_HALT => power::halt(),
+        LINUX_REBOOT_CMD_POWER_OFF => power::power_off(),
+        _ => linux_return_err_from_syscall!(EINVAL),
+    }
+}
@@ -1,19 +1,209 @@
+use alloc::vec::Vec;
+use core::sync::atomic::Ordering;
+
 use crate::{
     data::regs::fs_gs_base::{FsBase, KernelGsBase},
     interrupts::handlers::syscall::{
-        linux::{EINVAL, EPERM},
+        linux::{ECHILD, EFAULT, EINVAL, ENOSYS, EPERM},
         utils::structure::UserProcessStructure,
     },
     linux_return_err_from_syscall,
-    paging::PageTable,
-    process::scheduler::{ProcThreadInfo, SCHEDULER},
+    percpu::get_per_cpu,
+    process::{
+        futex::LockedFutex,
+        memory::LOWER_HALF_END,
+        proc::TaskState,
+        scheduler::{ProcThreadInfo, SCHEDULER},
+    },
 };
 
-pub fn linux_sys_exit(tid: u32, code: u64) -> ! {
-    SCHEDULER.handle_exit(tid, (code & 0xFF) << 8);
+pub const CLONE_VM: u64 = 0x100;
+pub const CLONE_FS: u64 = 0x200;
+pub const CLONE_FILES: u64 = 0x400;
+pub const CLONE_SIGHAND: u64 = 0x800;
+pub const CLONE_THREAD: u64 = 0x10000;
+pub const CLONE_SYSVSEM: u64 = 0x40000;
+pub const CLONE_SETTLS: u64 = 0x80000;
+pub const CLONE_PARENT_SETTID: u64 = 0x100000;
+pub const CLONE_CHILD_CLEARTID: u64 = 0x200000;
+pub const CLONE_DETACHED: u64 = 0x400000;
+pub const CLONE_CHILD_SETTID: u64 = 0x1000000;
+
+/// What pthread_create passes, the process state is shared by all its threads anyway
+const CLONE_THREAD_FLAGS: u64 = CLONE_VM | CLONE_FS | CLONE_FILES | CLONE_SIGHAND | CLONE_THREAD;
+const SUPPORTED_CLONE_FLAGS: u64 = CLONE_THREAD_FLAGS
+    | CLONE_SYSVSEM
+    | CLONE_SETTLS
+    | CLONE_PARENT_SETTID
+    | CLONE_CHILD_CLEARTID
+    | CLONE_DETACHED
+    | CLONE_CHILD_SETTID;
+
+/// Ends the calling thread, and the process with its last thread
+pub fn linux_sys_exit(thread: &ProcThreadInfo, code: u64) -> ! {
+    let clear_child_tid = thread.thread.clear_child_tid.swap(0, Ordering::Relaxed);
+    if clear_child_tid != 0 {
+        // How joiners learn the thread is gone
+        if let Some(mut tid) = UserProcessStructure::<u32>::new(clear_child_tid as *mut u32) {
+            let mut ptlock = thread.thread.process.page_table.lock();
+            if let Some(tid) = tid.verify_fully_mapped_mut(&mut ptlock) {
+                *tid = 0;
+                drop(ptlock);
+                LockedFutex::lock(thread.pid, clear_child_tid).wake(1);
+            }
+        }
+    }
+    SCHEDULER.handle_exit(thread.tid, (code & 0xFF) << 8);
+    SCHEDULER.schedule()
+}
+
+/// Ends every thread of the process
+pub fn linux_sys_exit_group(thread: &ProcThreadInfo, code: u64) -> ! {
+    SCHEDULER.teardown(thread.pid, (code & 0xFF) << 8);
     SCHEDULER.schedule()
 }
 
+pub const SYS_WAIT4: u64 = 61;
+
+pub const WNOHANG: u64 = 1;
+
+/// Reports the exit of a child, or a stop of a thread traced by the caller. `pid` selects one of
+/// them, any if it is 0 or negative. `rusage` is ignored
+pub fn linux_sys_wait4(
+    thread: &ProcThreadInfo,
+    pid: u64,
+    status: u64,
+    options: u64,
+    _rusage: u64,
+) -> u64 {
+    let wanted = (pid as i32 > 0).then_some(pid as u32);
+    let queue = thread.thread.process.child_events.clone();
+    // Registered before looking, an event that comes in between isn't missed
+    queue.register(thread);
+
+    let candidates = SCHEDULER
+        .wait_candidates(thread.pid)
+        .into_iter()
+        .filter(|candidate| wanted.is_none_or(|tid| candidate.tid == tid))
+        .collect::<Vec<_>>();
+    if candidates.is_empty() {
+        queue.remove(thread.tid);
+        linux_return_err_from_syscall!(ECHILD)
+    }
+
+    for candidate in candidates.iter() {
+        let exit_code = match *candidate.thread.task_state.lock() {
+            TaskState::Zombie { exit_code } => Some(exit_code),
+            _ => None,
+        };
+        let mut trace = candidate.thread.trace.lock();
+        let code = match (exit_code, trace.stopped) {
+            (Some(exit_code), _) => {
+                trace.tracer = None;
+                exit_code
+            }
+            (None, Some(signal)) if !trace.reported => {
+                trace.reported = true;
+                (signal << 8) | 0x7f
+            }
+            _ => continue,
+        };
+        drop(trace);
+        queue.remove(thread.tid);
+
+        if status != 0 {
+            let Some(mut structure) = UserProcessStructure::<u32>::new(status as *mut u32) else {
+                linux_return_err_from_syscall!(EFAULT)
+            };
+            let mut ptlock = thread.thread.process.page_table.lock();
+            let Some(out) = structure.verify_fully_mapped_mut(&mut ptlock) else {
+                linux_return_err_from_syscall!(EFAULT)
+            };
+            *out = code as u32;
+        }
+        // A traced thread of another process is reaped by its parent
+        if exit_code.is_some() && candidate.thread.process.parent_pid == thread.pid {
+            SCHEDULER.remove_zombie(candidate.tid);
+        }
+        return candidate.tid as u64;
+    }
+
+    if options & WNOHANG != 0 {
+        queue.remove(thread.tid);
+        return 0;
+    }
+    SCHEDULER.block_current(thread, SYS_WAIT4, None)
+}
+
+/// Only creates threads, `CLONE_VM` without `CLONE_THREAD` and fork style clones fail with ENOSYS
+pub fn linux_sys_clone(
+    thread: &ProcThreadInfo,
+    flags: u64,
+    stack: u64,
+    parent_tid: u64,
+    child_tid: u64,
+    tls: u64,
+) -> u64 {
+    if flags & CLONE_VM == 0 {
+        linux_return_err_from_syscall!(ENOSYS)
+    }
+    // The low byte is the signal sent to the parent when the child exits, threads send none
+    if flags & CLONE_THREAD_FLAGS != CLONE_THREAD_FLAGS
+        || flags & !SUPPORTED_CLONE_FLAGS != 0
+        || stack == 0
+    {
+        linux_return_err_from_syscall!(EINVAL)
+    }
+
+    let tid_pointer = |address: u64, flag: u64| -> Result<Option<UserProcessStructure<u32>>, ()> {
+        if flags & flag == 0 {
+            return Ok(None);
+        }
+        let mut structure = UserProcessStructure::<u32>::new(address as *mut u32).ok_or(())?;
+        let mut ptlock = thread.thread.process.page_table.lock();
+        structure.verify_fully_mapped_mut(&mut ptlock).ok_or(())?;
+        Ok(Some(structure))
+    };
+    let (Ok(parent_tid_ptr), Ok(child_tid_ptr)) = (
+        tid_pointer(parent_tid, CLONE_PARENT_SETTID),
+        tid_pointer(child_tid, CLONE_CHILD_SETTID),
+    ) else {
+        linux_return_err_from_syscall!(EFAULT)
+    };
+
+    let mut state = SCHEDULER.current_user_state(thread);
+    state.gpregs.rax = 0;
+    state.rsp = stack;
+    if flags & CLONE_SETTLS != 0 {
+        state.fs_base = tls;
+    }
+    let child = SCHEDULER.spawn_thread(thread, state);
+    if flags & CLONE_CHILD_CLEARTID != 0 {
+        child
+            .thread
+            .clear_child_tid
+            .store(child_tid, Ordering::Relaxed);
+    }
+
+    let mut ptlock = thread.thread.process.page_table.lock();
+    for mut pointer in [parent_tid_ptr, child_tid_ptr].into_iter().flatten() {
+        if let Some(tid) = pointer.verify_fully_mapped_mut(&mut ptlock) {
+            *tid = child.tid;
+        }
+    }
+    drop(ptlock);
+    child.tid as u64
+}
+
+/// Sets the `CLONE_CHILD_CLEARTID` address of the calling thread
+pub fn linux_sys_set_tid_address(thread: &ProcThreadInfo, tidptr: u64) -> u64 {
+    thread
+        .thread
+        .clear_child_tid
+        .store(tidptr, Ordering::Relaxed);
+    thread.tid as u64
+}
+
 pub fn linux_sys_get_pid(thread: &ProcThreadInfo) -> u64 {
     thread.pid as u64
 }
@@ -22,10 +212,66 @@ pub fn linux_sys_get_tid(thread: &ProcThreadInfo) -> u64 {
     thread.tid as u64
 }
 
+pub fn linux_sys_get_ppid(thread: &ProcThreadInfo) -> u64 {
+    thread.thread.process.parent_pid as u64
+}
+
+pub fn linux_sys_get_uid(thread: &ProcThreadInfo) -> u64 {
+    thread.thread.process.access.lock().uid as u64
+}
+
+pub fn linux_sys_get_gid(thread: &ProcThreadInfo) -> u64 {
+    thread.thread.process.access.lock().gid as u64
+}
+
+pub fn linux_sys_get_euid(thread: &ProcThreadInfo) -> u64 {
+    thread.thread.process.access.lock().euid as u64
+}
+
+pub fn linux_sys_get_egid(thread: &ProcThreadInfo) -> u64 {
+    thread.thread.process.access.lock().egid as u64
+}
+
+/// Root changes both the real and effective uid, other users can only set the effective uid back to the real one
+pub fn linux_sys_setuid(thread: &ProcThreadInfo, uid: u64) -> u64 {
+    let Ok(uid) = u32::try_from(uid) else {
+        linux_return_err_from_syscall!(EINVAL)
+    };
+    let mut access = thread.thread.process.access.lock();
+    if access.is_root() {
+        access.uid = uid;
+        access.euid = uid;
+    } else if uid == access.uid {
+        access.euid = uid;
+    } else {
+        linux_return_err_from_syscall!(EPERM)
+    }
+    0
+}
+
+/// Same rules as setuid, checked against the effective uid
+pub fn linux_sys_setgid(thread: &ProcThreadInfo, gid: u64) -> u64 {
+    let Ok(gid) = u32::try_from(gid) else {
+        linux_return_err_from_syscall!(EINVAL)
+    };
+    let mut access = thread.thread.process.access.lock();
+    if access.is_root() {
+        access.gid = gid;
+        access.egid = gid;
+    } else if gid == access.gid {
+        access.egid = gid;
+    } else {
+        linux_return_err_from_syscall!(EPERM)
+    }
+    0
+}
+
 pub fn linux_sys_sched_yield(thread: &ProcThreadInfo) -> ! {
     let mut state = thread.thread.state.lock();
     state.gpregs.rax = 0;
     drop(state);
+    // The scheduler saves the syscall registers over the thread state when entered through `syscall`
+    get_per_cpu().syscall_data.rax = 0;
 
     SCHEDULER.schedule();
 }
@@ -35,10 +281,13 @@ pub const ARCH_SET_FS: u64 = 0x1002;
 pub const ARCH_GET_FS: u64 = 0x1003;
 pub const ARCH_GET_GS: u64 = 0x1004;
 
+/// The bases are kept in the thread state, and loaded when it is switched to
 pub fn linux_sys_arch_prctl(thread: &ProcThreadInfo, code: u64, value: u64) -> u64 {
+    // TODO: ARCH_SET_CPUID, ARCH_GET_CPUID
     match code {
-        // TODO: ARCH_SET_CPUID
-        // TODO: ARCH_GET_CPUID
+        ARCH_SET_FS | ARCH_SET_GS if value >= LOWER_HALF_END => {
+            linux_return_err_from_syscall!(EPERM)
+        }
         ARCH_SET_FS => {
             thread.thread.state.lock().fs_base = value;
             unsafe {
@@ -46,44 +295,32 @@ pub fn linux_sys_arch_prctl(thread: &ProcThreadInfo, code: u64, value: u64) -> u
             }
             0
         }
-        ARCH_GET_FS => match UserProcessStructure::<u64>::new(value as *mut u64) {
-            Some(mut user_u64) => {
-                match user_u64.verify_fully_mapped_mut(&mut PageTable::temporary_this()) {
-                    Some(fs_base_ptr) => {
-                        *fs_base_ptr = thread.thread.state.lock().fs_base;
-                        0
-                    }
-                    None => linux_return_err_from_syscall!(EPERM),
-                }
-            }
-            None => {
-                linux_return_err_from_syscall!(EPERM)
-            }
-        },
         ARCH_SET_GS => {
             thread.thread.state.lock().gs_base = value;
             unsafe {
-                // Currently used gs base is the kernel one, when spawgs is run when switching back to user mode, user process will get the correct gs base
+                // The kernel gs is in use until the swapgs back to userland
                 KernelGsBase::set(value);
             }
             0
         }
-        ARCH_GET_GS => match UserProcessStructure::<u64>::new(value as *mut u64) {
-            Some(mut user_u64) => {
-                match user_u64.verify_fully_mapped_mut(&mut PageTable::temporary_this()) {
-                    Some(gs_base_ptr) => {
-                        *gs_base_ptr = thread.thread.state.lock().gs_base;
-                        0
-                    }
-                    None => linux_return_err_from_syscall!(EPERM),
+        ARCH_GET_FS | ARCH_GET_GS => {
+            // Userland may have changed them with wrfsbase/wrgsbase
+            let base = unsafe {
+                match code {
+                    ARCH_GET_FS => FsBase::get(),
+                    _ => KernelGsBase::get(),
                 }
-            }
-            None => {
-                linux_return_err_from_syscall!(EPERM)
-            }
-        },
-        _ => {
-            linux_return_err_from_syscall!(EINVAL)
+            };
+            let Some(mut structure) = UserProcessStructure::<u64>::new(value as *mut u64) else {
+                linux_return_err_from_syscall!(EFAULT)
+            };
+            let mut ptlock = thread.thread.process.page_table.lock();
+            let Some(user_base) = structure.verify_fully_mapped_mut(&mut ptlock) else {
+                linux_return_err_from_syscall!(EFAULT)
+            };
+            *user_base = base;
+            0
         }
+        _ => linux_return_err_from_syscall!(EINVAL),
     }
 }
@@ -0,0 +1,290 @@
+use crate::{
+    data::regs::rflags::RFlag,
+    gdt::{USERLAND_CODE64_SELECTOR, USERLAND_DATA64_SELECTOR},
+    interrupts::handlers::syscall::{
+        linux::{EFAULT, EIO, EPERM, ESRCH, SIGSTOP},
+        utils::structure::UserProcessStructure,
+    },
+    linux_return_err_from_syscall,
+    paging::{DIRECT_MAPPING_OFFSET, PAGE_RW, PAGE_USER},
+    process::{
+        memory::LOWER_HALF_END,
+        proc::{TaskState, ThreadState},
+        scheduler::{ProcThreadInfo, SCHEDULER},
+    },
+};
+
+pub const PTRACE_PEEKTEXT: u64 = 1;
+pub const PTRACE_PEEKDATA: u64 = 2;
+pub const PTRACE_POKETEXT: u64 = 4;
+pub const PTRACE_POKEDATA: u64 = 5;
+pub const PTRACE_CONT: u64 = 7;
+pub const PTRACE_SINGLESTEP: u64 = 9;
+pub const PTRACE_GETREGS: u64 = 12;
+pub const PTRACE_SETREGS: u64 = 13;
+pub const PTRACE_ATTACH: u64 = 16;
+pub const PTRACE_DETACH: u64 = 17;
+
+/// Highest signal number
+const NSIG: u64 = 64;
+
+/// Flags a tracer may change, the others keep their value
+const USER_RFLAGS: u64 = RFlag::CarryFlag as u64
+    | RFlag::ParityFlag as u64
+    | RFlag::AdjustFlag as u64
+    | RFlag::ZeroFlag as u64
+    | RFlag::SignFlag as u64
+    | RFlag::TrapFlag as u64
+    | RFlag::DirectionFlag as u64
+    | RFlag::OverflowFlag as u64;
+
+/// `struct user_regs_struct`
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct LinuxUserRegs {
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub rbp: u64,
+    pub rbx: u64,
+    pub r11: u64,
+    pub r10: u64,
+    pub r9: u64,
+    pub r8: u64,
+    pub rax: u64,
+    pub rcx: u64,
+    pub rdx: u64,
+    pub rsi: u64,
+    pub rdi: u64,
+    pub orig_rax: u64,
+    pub rip: u64,
+    pub cs: u64,
+    pub eflags: u64,
+    pub rsp: u64,
+    pub ss: u64,
+    pub fs_base: u64,
+    pub gs_base: u64,
+    pub ds: u64,
+    pub es: u64,
+    pub fs: u64,
+    pub gs: u64,
+}
+
+impl LinuxUserRegs {
+    pub fn from_state(state: &ThreadState) -> Self {
+        let gpregs = &state.gpregs;
+        let data = (USERLAND_DATA64_SELECTOR | 3) as u64;
+        Self {
+            r15: gpregs.r15,
+            r14: gpregs.r14,
+            r13: gpregs.r13,
+            r12: gpregs.r12,
+            rbp: state.rbp,
+            rbx: gpregs.rbx,
+            r11: gpregs.r11,
+            r10: gpregs.r10,
+            r9: gpregs.r9,
+            r8: gpregs.r8,
+            rax: gpregs.rax,
+            rcx: gpregs.rcx,
+            rdx: gpregs.rdx,
+            rsi: gpregs.rsi,
+            rdi: gpregs.rdi,
+            // The syscall number isn't kept apart
+            orig_rax: gpregs.rax,
+            rip: state.rip,
+            cs: (USERLAND_CODE64_SELECTOR | 3) as u64,
+            eflags: state.rflags,
+            rsp: state.rsp,
+            ss: data,
+            fs_base: state.fs_base,
+            gs_base: state.gs_base,
+            ds: data,
+            es: data,
+            fs: data,
+            gs: 0,
+        }
+    }
+
+    /// Copies the registers into `state`, fails if an address isn't in user space. Segment
+    /// selectors and system flags can't be changed
+    pub fn apply(&self, state: &mut ThreadState) -> Option<()> {
+        if [self.rip, self.fs_base, self.gs_base]
+            .iter()
+            .any(|addr| *addr >= LOWER_HALF_END)
+        {
+            return None;
+        }
+        let gpregs = &mut state.gpregs;
+        gpregs.r15 = self.r15;
+        gpregs.r14 = self.r14;
+        gpregs.r13 = self.r13;
+        gpregs.r12 = self.r12;
+        gpregs.rbx = self.rbx;
+        gpregs.r11 = self.r11;
+        gpregs.r10 = self.r10;
+        gpregs.r9 = self.r9;
+        gpregs.r8 = self.r8;
+        gpregs.rax = self.rax;
+        gpregs.rcx = self.rcx;
+        gpregs.rdx = self.rdx;
+        gpregs.rsi = self.rsi;
+        gpregs.rdi = self.rdi;
+        state.rbp = self.rbp;
+        state.rip = self.rip;
+        state.rsp = self.rsp;
+        state.rflags = (state.rflags & !USER_RFLAGS) | (self.eflags & USER_RFLAGS);
+        state.fs_base = self.fs_base;
+        state.gs_base = self.gs_base;
+        Some(())
+    }
+}
+
+/// Reads a word of the tracee's memory, or writes `value` there. Only user pages are accessed,
+/// and read-only ones aren't written
+fn access_tracee_word(tracee: &ProcThreadInfo, addr: u64, value: Option<u64>) -> Option<u64> {
+    let mut bytes = value.unwrap_or_default().to_le_bytes();
+    let mut ptlock = tracee.thread.process.page_table.lock();
+    for (offset, byte) in bytes.iter_mut().enumerate() {
+        let virt = addr
+            .checked_add(offset as u64)
+            .filter(|virt| *virt < LOWER_HALF_END)?;
+        let flags = ptlock.page_flags(virt)?;
+        if flags & PAGE_USER == 0 || (value.is_some() && flags & PAGE_RW == 0) {
+            return None;
+        }
+        let ptr = (ptlock.translate(virt)? + DIRECT_MAPPING_OFFSET) as *mut u8;
+        unsafe {
+            match value {
+                Some(_) => core::ptr::write_volatile(ptr, *byte),
+                None => *byte = core::ptr::read_volatile(ptr),
+            }
+        }
+    }
+    Some(u64::from_le_bytes(bytes))
+}
+
+/// Only a parent or a process with euid 0 may attach, and not to its own threads. The tracee
+/// stops with SIGSTOP, reported through `wait4`
+fn ptrace_attach(thread: &ProcThreadInfo, tid: u32) -> u64 {
+    let Some(tracee) = SCHEDULER.get_thread(tid) else {
+        linux_return_err_from_syscall!(ESRCH)
+    };
+    if matches!(*tracee.thread.task_state.lock(), TaskState::Zombie { .. }) {
+        linux_return_err_from_syscall!(ESRCH)
+    }
+    let privileged = thread.thread.process.access.lock().euid == 0;
+    if tracee.pid == thread.pid || (!privileged && tracee.thread.process.parent_pid != thread.pid) {
+        linux_return_err_from_syscall!(EPERM)
+    }
+
+    let mut trace = tracee.thread.trace.lock();
+    if trace.tracer.is_some() {
+        linux_return_err_from_syscall!(EPERM)
+    }
+    trace.tracer = Some(thread.pid);
+    drop(trace);
+    SCHEDULER.request_stop(tracee.tid, SIGSTOP);
+    0
+}
+
+/// `PTRACE_ATTACH`, `PTRACE_DETACH`, and for a stopped tracee `PTRACE_PEEKDATA`/`POKEDATA`,
+/// `PTRACE_GETREGS`/`SETREGS`, `PTRACE_CONT` and `PTRACE_SINGLESTEP`. Like the raw syscall,
+/// PEEK requests store the word at `data`
+pub fn linux_sys_ptrace(
+    thread: &ProcThreadInfo,
+    request: u64,
+    pid: u64,
+    addr: u64,
+    data: u64,
+) -> u64 {
+    if request == PTRACE_ATTACH {
+        return ptrace_attach(thread, pid as u32);
+    }
+
+    let Some(tracee) = SCHEDULER.get_thread(pid as u32) else {
+        linux_return_err_from_syscall!(ESRCH)
+    };
+    let trace = tracee.thread.trace.lock();
+    let stopped = trace.stopped.is_some();
+    if trace.tracer != Some(thread.pid) {
+        linux_return_err_from_syscall!(ESRCH)
+    }
+    drop(trace);
+    if request == PTRACE_DETACH {
+        SCHEDULER.detach(&tracee);
+        return 0;
+    }
+    if !stopped {
+        linux_return_err_from_syscall!(ESRCH)
+    }
+
+    match request {
+        PTRACE_PEEKTEXT | PTRACE_PEEKDATA => {
+            let Some(word) = access_tracee_word(&tracee, addr, None) else {
+                linux_return_err_from_syscall!(EIO)
+            };
+            let Some(mut structure) = UserProcessStructure::new(data as *mut u64) else {
+                linux_return_err_from_syscall!(EFAULT)
+            };
+            let mut ptlock = thread.thread.process.page_table.lock();
+            let Some(out) = structure.verify_fully_mapped_mut(&mut ptlock) else {
+                linux_return_err_from_syscall!(EFAULT)
+            };
+            *out = word;
+            0
+        }
+        PTRACE_POKETEXT | PTRACE_POKEDATA => {
+            if access_tracee_word(&tracee, addr, Some(data)).is_none() {
+                linux_return_err_from_syscall!(EIO)
+            }
+            0
+        }
+        PTRACE_GETREGS => {
+            let regs = LinuxUserRegs::from_state(&tracee.thread.state.lock());
+            let Some(mut structure) = UserProcessStructure::new(data as *mut LinuxUserRegs) else {
+                linux_return_err_from_syscall!(EFAULT)
+            };
+            let mut ptlock = thread.thread.process.page_table.lock();
+            let Some(out) = structure.verify_fully_mapped_mut(&mut ptlock) else {
+                linux_return_err_from_syscall!(EFAULT)
+            };
+            *out = regs;
+            0
+        }
+        PTRACE_SETREGS => {
+            let Some(structure) = UserProcessStructure::new(data as *mut LinuxUserRegs) else {
+                linux_return_err_from_syscall!(EFAULT)
+            };
+            let mut ptlock = thread.thread.process.page_table.lock();
+            let Some(regs) = structure.verify_fully_mapped(&mut ptlock).copied() else {
+                linux_return_err_from_syscall!(EFAULT)
+            };
+            drop(ptlock);
+            if regs.apply(&mut tracee.thread.state.lock()).is_none() {
+                linux_return_err_from_syscall!(EIO)
+            }
+            0
+        }
+        PTRACE_CONT | PTRACE_SINGLESTEP => {
+            if data > NSIG {
+                linux_return_err_from_syscall!(EIO)
+            }
+            let mut state = tracee.thread.state.lock();
+            if request == PTRACE_SINGLESTEP {
+                state.rflags |= RFlag::TrapFlag as u64;
+            } else {
+                state.rflags &= !(RFlag::TrapFlag as u64);
+            }
+            drop(state);
+            SCHEDULER.resume_stopped(tracee.tid);
+            // The signal the tracee stopped with, or another one, is delivered
+            if data != 0 {
+                SCHEDULER.signal_default_action(tracee.pid, data);
+            }
+            0
+        }
+        _ => linux_return_err_from_syscall!(EIO),
+    }
+}
@@ -0,0 +1,61 @@
+use crate::{
+    crypto::rng::{self, RNG_CHUNK_SIZE},
+    interrupts::handlers::syscall::{
+        linux::{EFAULT, EINVAL},
+        utils::buffer::UserProcessBuffer,
+    },
+    linux_return_err_from_syscall,
+    process::{
+        memory::{get_address_space, VirtualAddressSpace},
+        scheduler::ProcThreadInfo,
+    },
+};
+
+pub const GRND_NONBLOCK: u64 = 1 << 0;
+pub const GRND_RANDOM: u64 = 1 << 1;
+pub const GRND_INSECURE: u64 = 1 << 2;
+
+const SUPPORTED_GETRANDOM_FLAGS: u64 = GRND_NONBLOCK | GRND_RANDOM | GRND_INSECURE;
+const MAX_GETRANDOM_SIZE: u64 = 32 * 1024 * 1024; // 32MiB
+
+pub fn linux_sys_getrandom(thread: &ProcThreadInfo, buf: u64, count: u64, flags: u64) -> u64 {
+    if flags & !SUPPORTED_GETRANDOM_FLAGS != 0
+        || (flags & GRND_INSECURE != 0 && flags & GRND_RANDOM != 0)
+    {
+        linux_return_err_from_syscall!(EINVAL)
+    }
+    // The kernel generator is always seeded, so GRND_NONBLOCK doesn't change anything
+    let count = count.min(MAX_GETRANDOM_SIZE);
+    if count == 0 {
+        return 0;
+    }
+
+    let Some(end_addr) = buf.checked_add(count) else {
+        linux_return_err_from_syscall!(EFAULT)
+    };
+    if !matches!(
+        get_address_space(buf),
+        Some(VirtualAddressSpace::LowerHalf(..))
+    ) || !matches!(
+        get_address_space(end_addr),
+        Some(VirtualAddressSpace::LowerHalf(..))
+    ) {
+        linux_return_err_from_syscall!(EFAULT)
+    }
+
+    let mut ptlock = thread.thread.process.page_table.lock();
+    let mut user_buffer = UserProcessBuffer::new(buf as *mut u8, count as usize);
+    let Some(user_buf) = user_buffer.verify_fully_mapped_mut(&mut ptlock) else {
+        linux_return_err_from_syscall!(EFAULT)
+    };
+
+    let mut chunk = [0u8; RNG_CHUNK_SIZE];
+    for dst in user_buf.chunks_mut(RNG_CHUNK_SIZE) {
+        let src = &mut chunk[0..dst.len()];
+        rng::fill_bytes(src);
+        dst.copy_from_slice(src);
+    }
+    chunk.fill(0);
+
+    count
+}
@@ -0,0 +1,216 @@
+use crate::{
+    interrupts::{
+        handlers::{
+            irq::irq0_timer::get_uptime_ticks,
+            syscall::{
+                linux::{EFAULT, EINVAL, EPERM, ESRCH},
+                utils::structure::UserProcessStructure,
+            },
+        },
+        pit::ticks_to_ms,
+    },
+    linux_return_err_from_syscall,
+    memory::buddy_alloc::PAGE_SIZE,
+    process::{
+        limits::{LimitError, Resource, ResourceLimit, RLIM_INFINITY},
+        proc::CpuTimes,
+        scheduler::{ProcThreadInfo, SCHEDULER},
+    },
+};
+
+/// Clock ticks per second of the linux ABI, sysconf(_SC_CLK_TCK)
+pub const USER_HZ: u64 = 100;
+
+pub const RUSAGE_SELF: i64 = 0;
+pub const RUSAGE_CHILDREN: i64 = -1;
+pub const RUSAGE_THREAD: i64 = 1;
+
+/// Resources known to linux, only some are tracked
+const RLIM_NLIMITS: u64 = 16;
+
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+struct LinuxTms {
+    tms_utime: i64,
+    tms_stime: i64,
+    tms_cutime: i64,
+    tms_cstime: i64,
+}
+
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct LinuxTimeval {
+    pub tv_sec: i64,
+    pub tv_usec: i64,
+}
+
+impl LinuxTimeval {
+    pub fn from_ticks(ticks: u64) -> Self {
+        let ms = ticks_to_ms(ticks);
+        Self {
+            tv_sec: (ms / 1000) as i64,
+            tv_usec: (ms % 1000 * 1000) as i64,
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+struct LinuxRusage {
+    ru_utime: LinuxTimeval,
+    ru_stime: LinuxTimeval,
+    /// In KiB
+    ru_maxrss: i64,
+    /// Counters that aren't tracked, always 0
+    ru_unused: [i64; 13],
+}
+
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+struct LinuxRlimit {
+    rlim_cur: u64,
+    rlim_max: u64,
+}
+
+pub fn ticks_to_clock_t(ticks: u64) -> u64 {
+    ticks_to_ms(ticks) * USER_HZ / 1000
+}
+
+/// Children are never reaped yet, so their times are always 0
+pub fn linux_sys_times(thread: &ProcThreadInfo, buf: u64) -> u64 {
+    if buf != 0 {
+        let times = thread.thread.process.cpu_times();
+        let Some(mut structure) = UserProcessStructure::new(buf as *mut LinuxTms) else {
+            linux_return_err_from_syscall!(EFAULT)
+        };
+        let mut ptlock = thread.thread.process.page_table.lock();
+        let Some(tms) = structure.verify_fully_mapped_mut(&mut ptlock) else {
+            linux_return_err_from_syscall!(EFAULT)
+        };
+        *tms = LinuxTms {
+            tms_utime: ticks_to_clock_t(times.user) as i64,
+            tms_stime: ticks_to_clock_t(times.kernel) as i64,
+            ..LinuxTms::default()
+        };
+    }
+    ticks_to_clock_t(get_uptime_ticks())
+}
+
+pub fn linux_sys_getrusage(thread: &ProcThreadInfo, who: u64, usage: u64) -> u64 {
+    let process = &thread.thread.process;
+    let (times, max_resident_pages) = match who as i64 {
+        RUSAGE_SELF => (process.cpu_times(), process.update_max_resident_pages()),
+        RUSAGE_THREAD => (
+            *thread.thread.cpu_times.lock(),
+            process.update_max_resident_pages(),
+        ),
+        RUSAGE_CHILDREN => (CpuTimes::default(), 0),
+        _ => linux_return_err_from_syscall!(EINVAL),
+    };
+
+    let Some(mut structure) = UserProcessStructure::new(usage as *mut LinuxRusage) else {
+        linux_return_err_from_syscall!(EFAULT)
+    };
+    let mut ptlock = process.page_table.lock();
+    let Some(rusage) = structure.verify_fully_mapped_mut(&mut ptlock) else {
+        linux_return_err_from_syscall!(EFAULT)
+    };
+    *rusage = LinuxRusage {
+        ru_utime: LinuxTimeval::from_ticks(times.user),
+        ru_stime: LinuxTimeval::from_ticks(times.kernel),
+        ru_maxrss: (max_resident_pages * PAGE_SIZE / 1024) as i64,
+        ..LinuxRusage::default()
+    };
+    0
+}
+
+pub fn linux_sys_getrlimit(thread: &ProcThreadInfo, resource: u64, old_limit: u64) -> u64 {
+    linux_sys_prlimit64(thread, 0, resource, 0, old_limit)
+}
+
+pub fn linux_sys_setrlimit(thread: &ProcThreadInfo, resource: u64, new_limit: u64) -> u64 {
+    linux_sys_prlimit64(thread, 0, resource, new_limit, 0)
+}
+
+/// Resources below RLIM_NLIMITS that aren't tracked read as unlimited and can't be set
+pub fn linux_sys_prlimit64(
+    thread: &ProcThreadInfo,
+    pid: u64,
+    resource: u64,
+    new_limit: u64,
+    old_limit: u64,
+) -> u64 {
+    if resource >= RLIM_NLIMITS {
+        linux_return_err_from_syscall!(EINVAL)
+    }
+    let caller = thread.thread.process.access.lock().clone();
+    let process = match pid as u32 {
+        0 => thread.thread.process.clone(),
+        pid => match SCHEDULER.get_process(pid) {
+            Some(process) => process,
+            None => linux_return_err_from_syscall!(ESRCH),
+        },
+    };
+    if !caller.is_root() && !process.access.lock().owned_by(&caller) {
+        linux_return_err_from_syscall!(EPERM)
+    }
+
+    let new_limit = if new_limit != 0 {
+        let Some(structure) = UserProcessStructure::new(new_limit as *mut LinuxRlimit) else {
+            linux_return_err_from_syscall!(EFAULT)
+        };
+        let mut ptlock = thread.thread.process.page_table.lock();
+        let Some(rlimit) = structure.verify_fully_mapped(&mut ptlock) else {
+            linux_return_err_from_syscall!(EFAULT)
+        };
+        Some(ResourceLimit::new(rlimit.rlim_cur, rlimit.rlim_max))
+    } else {
+        None
+    };
+
+    let Some(resource) = Resource::from_linux(resource) else {
+        if new_limit.is_some() {
+            linux_return_err_from_syscall!(EINVAL)
+        }
+        return write_rlimit(
+            thread,
+            old_limit,
+            ResourceLimit::new(RLIM_INFINITY, RLIM_INFINITY),
+        );
+    };
+
+    let mut limits = process.limits.lock();
+    let old = limits.get(resource);
+    if let Some(limit) = new_limit {
+        match limits.set(resource, limit, caller.is_root()) {
+            Ok(()) => (),
+            Err(LimitError::InvalidLimit) => linux_return_err_from_syscall!(EINVAL),
+            Err(LimitError::NotPermitted) => linux_return_err_from_syscall!(EPERM),
+        }
+        drop(limits);
+        if resource == Resource::NoFile {
+            process.io_context.lock().file_table.max_fds = limit.soft as usize;
+        }
+    } else {
+        drop(limits);
+    }
+    write_rlimit(thread, old_limit, old)
+}
+
+fn write_rlimit(thread: &ProcThreadInfo, buf: u64, limit: ResourceLimit) -> u64 {
+    if buf == 0 {
+        return 0;
+    }
+    let Some(mut structure) = UserProcessStructure::new(buf as *mut LinuxRlimit) else {
+        linux_return_err_from_syscall!(EFAULT)
+    };
+    let mut ptlock = thread.thread.process.page_table.lock();
+    let Some(rlimit) = structure.verify_fully_mapped_mut(&mut ptlock) else {
+        linux_return_err_from_syscall!(EFAULT)
+    };
+    *rlimit = LinuxRlimit {
+        rlim_cur: limit.soft,
+        rlim_max: limit.hard,
+    };
+    0
+}
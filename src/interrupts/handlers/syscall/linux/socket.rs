@@ -0,0 +1,617 @@
+use alloc::{sync::Arc, vec::Vec};
+
+use crate::{
+    drivers::{
+        fs::virt::unixsock::{get_unix_sock_fs, UnixSockFs, UnixSocketError, UNIX_SOCKFS_MOUNT},
+        vfs::{Arcrwb, FileSystem, VfsError},
+    },
+    interrupts::handlers::syscall::{
+        linux::{
+            vfs_err_to_linux_errno, EADDRINUSE, EADDRNOTAVAIL, EAFNOSUPPORT, EBADF, ECONNREFUSED,
+            EDESTADDRREQ, EFAULT, EINVAL, EISCONN, EMFILE, EMSGSIZE, ENETUNREACH, ENOENT, ENOTSOCK,
+            EPROTONOSUPPORT, ESOCKTNOSUPPORT, ETIMEDOUT, EWOULDBLOCK,
+        },
+        utils::{buffer::UserProcessBuffer, structure::UserProcessStructure},
+    },
+    linux_return_err_from_syscall,
+    net::{
+        ipv4::{Ipv4Addr, SocketAddrV4},
+        socket::{get_net_sock_fs, NetSockFs},
+        NetError,
+    },
+    process::{
+        io::file_table::OpenFile,
+        memory::{get_address_space, VirtualAddressSpace},
+        scheduler::{ProcThreadInfo, SCHEDULER},
+        wait::WaitQueue,
+    },
+};
+
+pub const SYS_ACCEPT: u64 = 43;
+pub const SYS_CONNECT: u64 = 42;
+pub const SYS_RECVFROM: u64 = 45;
+
+pub const AF_UNIX: u64 = 1;
+pub const AF_INET: u64 = 2;
+pub const SOCK_STREAM: u64 = 1;
+pub const SOCK_DGRAM: u64 = 2;
+pub const SOCK_NONBLOCK: u64 = 0o4000;
+pub const SOCK_CLOEXEC: u64 = 0o2000000;
+pub const IPPROTO_UDP: u64 = 17;
+
+pub const MSG_DONTWAIT: u64 = 0x40;
+
+const SOCK_TYPE_MASK: u64 = 0xF;
+const SUN_PATH_OFFSET: usize = 2;
+const SOCKADDR_UN_SIZE: usize = 110;
+const SOCKADDR_IN_SIZE: usize = 16;
+const SOCKADDR_STORAGE_SIZE: u64 = 128;
+/// Bytes taken from userland by a single send
+const MAX_SEND_SIZE: u64 = 64 * 1024;
+
+/// Why a socket operation did not complete
+enum SocketFailure {
+    Errno(u64),
+    /// The socket is blocking, sleep on the queue and restart the syscall
+    Block(Arc<WaitQueue>),
+}
+
+impl From<UnixSocketError> for SocketFailure {
+    fn from(value: UnixSocketError) -> Self {
+        match value {
+            UnixSocketError::WouldBlock(queue) => SocketFailure::Block(queue),
+            UnixSocketError::Vfs(e) => SocketFailure::Errno(vfs_err_to_linux_errno(e)),
+            UnixSocketError::NotSocket => SocketFailure::Errno(ENOTSOCK),
+            UnixSocketError::InvalidState => SocketFailure::Errno(EINVAL),
+            UnixSocketError::AddressInUse => SocketFailure::Errno(EADDRINUSE),
+            UnixSocketError::NameNotFound => SocketFailure::Errno(ENOENT),
+            UnixSocketError::ConnectionRefused => SocketFailure::Errno(ECONNREFUSED),
+            UnixSocketError::AlreadyConnected => SocketFailure::Errno(EISCONN),
+            UnixSocketError::NotListening => SocketFailure::Errno(EINVAL),
+        }
+    }
+}
+
+impl From<NetError> for SocketFailure {
+    fn from(value: NetError) -> Self {
+        match value {
+            NetError::WouldBlock(queue) => SocketFailure::Block(queue),
+            NetError::Vfs(e) => SocketFailure::Errno(vfs_err_to_linux_errno(e)),
+            NetError::NotSocket => SocketFailure::Errno(ENOTSOCK),
+            NetError::InvalidArgument => SocketFailure::Errno(EINVAL),
+            NetError::AddressInUse => SocketFailure::Errno(EADDRINUSE),
+            NetError::AddressNotAvailable => SocketFailure::Errno(EADDRNOTAVAIL),
+            NetError::NetworkUnreachable => SocketFailure::Errno(ENETUNREACH),
+            NetError::MessageTooLong => SocketFailure::Errno(EMSGSIZE),
+            NetError::DestinationRequired => SocketFailure::Errno(EDESTADDRREQ),
+            NetError::TimedOut => SocketFailure::Errno(ETIMEDOUT),
+        }
+    }
+}
+
+impl From<VfsError> for SocketFailure {
+    fn from(value: VfsError) -> Self {
+        SocketFailure::Errno(vfs_err_to_linux_errno(value))
+    }
+}
+
+impl SocketFailure {
+    /// Nonblocking sockets fail instead of sleeping
+    fn or_would_block(self, nonblocking: bool) -> Self {
+        match self {
+            SocketFailure::Block(_) if nonblocking => SocketFailure::Errno(EWOULDBLOCK),
+            other => other,
+        }
+    }
+}
+
+fn socket_result(result: Result<u64, SocketFailure>) -> u64 {
+    match result {
+        Ok(ret) => ret,
+        Err(SocketFailure::Errno(errno)) => linux_return_err_from_syscall!(errno),
+        Err(SocketFailure::Block(_)) => linux_return_err_from_syscall!(EWOULDBLOCK),
+    }
+}
+
+/// Like `socket_result`, but the thread sleeps on the queue and `syscall` runs again
+fn blocking_socket_result(
+    thread: &ProcThreadInfo,
+    syscall: u64,
+    result: Result<u64, SocketFailure>,
+) -> u64 {
+    match result {
+        Err(SocketFailure::Block(queue)) => {
+            queue.register(thread);
+            drop(queue);
+            SCHEDULER.block_current(thread, syscall, None)
+        }
+        result => socket_result(result),
+    }
+}
+
+fn get_socket_fd(thread: &ProcThreadInfo, fd: u64) -> Result<(Arcrwb<dyn FileSystem>, u64), u64> {
+    let io_ctx = thread.thread.process.io_context.lock();
+    match io_ctx.file_table.get(fd as usize) {
+        Some(file) => Ok((file.fs.clone(), file.handle)),
+        None => Err(EBADF),
+    }
+}
+
+/// Address family of the socket behind `fd`
+fn socket_domain(thread: &ProcThreadInfo, fd: u64) -> Result<u64, u64> {
+    let (fs, _) = get_socket_fd(thread, fd)?;
+    let guard = fs.read();
+    let any = (**guard).as_any();
+    if any.is::<UnixSockFs>() {
+        Ok(AF_UNIX)
+    } else if any.is::<NetSockFs>() {
+        Ok(AF_INET)
+    } else {
+        Err(ENOTSOCK)
+    }
+}
+
+/// Runs `f` on the unix socket file system behind `fd`
+fn with_unix_socket<R>(
+    thread: &ProcThreadInfo,
+    fd: u64,
+    f: impl FnOnce(&mut UnixSockFs, u64) -> Result<R, SocketFailure>,
+) -> Result<R, SocketFailure> {
+    let (fs, handle) = get_socket_fd(thread, fd).map_err(SocketFailure::Errno)?;
+    let mut guard = fs.write();
+    let sockfs = (**guard)
+        .as_any_mut()
+        .downcast_mut::<UnixSockFs>()
+        .ok_or(SocketFailure::Errno(ENOTSOCK))?;
+    f(sockfs, handle)
+}
+
+/// Runs `f` on the internet socket file system behind `fd`
+fn with_net_socket<R>(
+    thread: &ProcThreadInfo,
+    fd: u64,
+    f: impl FnOnce(&mut NetSockFs, u64) -> Result<R, SocketFailure>,
+) -> Result<R, SocketFailure> {
+    let (fs, handle) = get_socket_fd(thread, fd).map_err(SocketFailure::Errno)?;
+    let mut guard = fs.write();
+    let sockfs = (**guard)
+        .as_any_mut()
+        .downcast_mut::<NetSockFs>()
+        .ok_or(SocketFailure::Errno(ENOTSOCK))?;
+    f(sockfs, handle)
+}
+
+/// Installs handles of streams (sockets, timer fds) in the file table, returns the fds
+pub(super) fn install_fds(
+    thread: &ProcThreadInfo,
+    fs: Arcrwb<dyn FileSystem>,
+    handles: &[u64],
+) -> Option<Vec<usize>> {
+    let mut io_ctx = thread.thread.process.io_context.lock();
+    let fds = io_ctx.file_table.alloc_fds(handles.len())?;
+    for (fd, handle) in fds.iter().zip(handles.iter()) {
+        *io_ctx.file_table.get_fd(*fd)? = Some(OpenFile::stream(fs.clone(), *handle));
+    }
+    Some(fds)
+}
+
+/// Returns the socket type and whether it is nonblocking
+fn check_socket_type(kind: u64) -> Result<(u64, bool), u64> {
+    if kind & !(SOCK_TYPE_MASK | SOCK_NONBLOCK | SOCK_CLOEXEC) != 0 {
+        return Err(EINVAL);
+    }
+    // There is no exec yet, SOCK_CLOEXEC can be ignored
+    Ok((kind & SOCK_TYPE_MASK, kind & SOCK_NONBLOCK != 0))
+}
+
+fn is_lower_half_range(addr: u64, len: u64) -> bool {
+    let Some(end_addr) = addr.checked_add(len) else {
+        return false;
+    };
+    matches!(
+        get_address_space(addr),
+        Some(VirtualAddressSpace::LowerHalf(..))
+    ) && matches!(
+        get_address_space(end_addr),
+        Some(VirtualAddressSpace::LowerHalf(..))
+    )
+}
+
+/// Copies a socket address from userland, checks its family
+pub fn read_sockaddr(
+    thread: &ProcThreadInfo,
+    addr: u64,
+    len: u64,
+    family: u64,
+) -> Result<Vec<u8>, u64> {
+    if !(SUN_PATH_OFFSET as u64..=SOCKADDR_STORAGE_SIZE).contains(&len) {
+        return Err(EINVAL);
+    }
+    if !is_lower_half_range(addr, len) {
+        return Err(EFAULT);
+    }
+
+    let mut ptlock = thread.thread.process.page_table.lock();
+    let user_buffer = UserProcessBuffer::new(addr as *mut u8, len as usize);
+    let Some(buf) = user_buffer.verify_fully_mapped(&mut ptlock) else {
+        return Err(EFAULT);
+    };
+    if u16::from_ne_bytes([buf[0], buf[1]]) as u64 != family {
+        return Err(EAFNOSUPPORT);
+    }
+    Ok(buf.to_vec())
+}
+
+/// Copies a socket address to userland. Like linux, it is truncated to the buffer
+/// size in `*addrlen`, which is then set to the full size
+pub fn write_sockaddr(
+    thread: &ProcThreadInfo,
+    addr: u64,
+    addrlen: u64,
+    sockaddr: &[u8],
+) -> Result<(), u64> {
+    if addr == 0 || addrlen == 0 {
+        return Ok(());
+    }
+    let mut ptlock = thread.thread.process.page_table.lock();
+    let Some(mut len) = UserProcessStructure::new(addrlen as *mut u32) else {
+        return Err(EFAULT);
+    };
+    let Some(len) = len.verify_fully_mapped_mut(&mut ptlock) else {
+        return Err(EFAULT);
+    };
+    let copy_len = (*len as usize).min(sockaddr.len());
+    if !is_lower_half_range(addr, copy_len as u64) {
+        return Err(EFAULT);
+    }
+    let mut user_buffer = UserProcessBuffer::new(addr as *mut u8, copy_len);
+    let Some(buf) = user_buffer.verify_fully_mapped_mut(&mut ptlock) else {
+        return Err(EFAULT);
+    };
+    buf.copy_from_slice(&sockaddr[..copy_len]);
+    *len = sockaddr.len() as u32;
+    Ok(())
+}
+
+/// Returns the name in the unix socket namespace.
+/// Paths must be directly inside `/sockets`, abstract names keep their leading nul char
+fn parse_sockaddr_un(sockaddr: &[u8]) -> Result<Vec<char>, u64> {
+    if sockaddr.len() <= SUN_PATH_OFFSET || sockaddr.len() > SOCKADDR_UN_SIZE {
+        return Err(EINVAL);
+    }
+    let path = &sockaddr[SUN_PATH_OFFSET..];
+    if path[0] == 0 {
+        return Ok(path.iter().map(|c| *c as char).collect());
+    }
+
+    let path = path.split(|c| *c == 0).next().unwrap_or(path);
+    let path = path.iter().map(|c| *c as char).collect::<Vec<char>>();
+    let prefix = [&['/'], &UNIX_SOCKFS_MOUNT[..], &['/']].concat();
+    match path.strip_prefix(&prefix[..]) {
+        Some(name) if !name.is_empty() => Ok(name.to_vec()),
+        _ => Err(ENOENT),
+    }
+}
+
+/// Peers of unix sockets are never bound, only the family is known
+fn encode_sockaddr_un_unnamed() -> [u8; SUN_PATH_OFFSET] {
+    (AF_UNIX as u16).to_ne_bytes()
+}
+
+/// Port and address are in network byte order
+fn parse_sockaddr_in(sockaddr: &[u8]) -> Result<SocketAddrV4, u64> {
+    if sockaddr.len() < SOCKADDR_IN_SIZE {
+        return Err(EINVAL);
+    }
+    Ok(SocketAddrV4::new(
+        Ipv4Addr([sockaddr[4], sockaddr[5], sockaddr[6], sockaddr[7]]),
+        u16::from_be_bytes([sockaddr[2], sockaddr[3]]),
+    ))
+}
+
+fn encode_sockaddr_in(addr: SocketAddrV4) -> [u8; SOCKADDR_IN_SIZE] {
+    let mut sockaddr = [0u8; SOCKADDR_IN_SIZE];
+    sockaddr[0..2].copy_from_slice(&(AF_INET as u16).to_ne_bytes());
+    sockaddr[2..4].copy_from_slice(&addr.port.to_be_bytes());
+    sockaddr[4..8].copy_from_slice(&addr.addr.0);
+    sockaddr
+}
+
+pub fn linux_sys_socket(thread: &ProcThreadInfo, domain: u64, kind: u64, protocol: u64) -> u64 {
+    let (kind, nonblocking) = match check_socket_type(kind) {
+        Ok(kind) => kind,
+        Err(errno) => linux_return_err_from_syscall!(errno),
+    };
+    let (fs, handle) = match (domain, kind) {
+        (AF_UNIX, SOCK_STREAM) => {
+            if protocol != 0 {
+                linux_return_err_from_syscall!(EPROTONOSUPPORT)
+            }
+            let fs = match get_unix_sock_fs() {
+                Ok(fs) => fs,
+                Err(e) => linux_return_err_from_syscall!(vfs_err_to_linux_errno(e)),
+            };
+            let mut guard = fs.write();
+            let Some(sockfs) = (**guard).as_any_mut().downcast_mut::<UnixSockFs>() else {
+                linux_return_err_from_syscall!(EINVAL)
+            };
+            let handle = sockfs.create_socket(nonblocking);
+            drop(guard);
+            (fs, handle)
+        }
+        (AF_INET, SOCK_DGRAM) => {
+            if protocol != 0 && protocol != IPPROTO_UDP {
+                linux_return_err_from_syscall!(EPROTONOSUPPORT)
+            }
+            let fs = match get_net_sock_fs() {
+                Ok(fs) => fs,
+                Err(e) => linux_return_err_from_syscall!(vfs_err_to_linux_errno(e)),
+            };
+            let mut guard = fs.write();
+            let Some(sockfs) = (**guard).as_any_mut().downcast_mut::<NetSockFs>() else {
+                linux_return_err_from_syscall!(EINVAL)
+            };
+            let handle = sockfs.create_udp_socket(nonblocking);
+            drop(guard);
+            (fs, handle)
+        }
+        (AF_UNIX | AF_INET, _) => linux_return_err_from_syscall!(ESOCKTNOSUPPORT),
+        _ => linux_return_err_from_syscall!(EAFNOSUPPORT),
+    };
+
+    match install_fds(thread, fs.clone(), &[handle]) {
+        Some(fds) => fds[0] as u64,
+        None => {
+            let _ = fs.write().fclose(handle);
+            linux_return_err_from_syscall!(EMFILE)
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct LinuxSocketPairFds {
+    pub a: i32,
+    pub b: i32,
+}
+
+pub fn linux_sys_socketpair(
+    thread: &ProcThreadInfo,
+    domain: u64,
+    kind: u64,
+    protocol: u64,
+    sv: u64,
+) -> u64 {
+    let (kind, nonblocking) = match check_socket_type(kind) {
+        Ok(kind) => kind,
+        Err(errno) => linux_return_err_from_syscall!(errno),
+    };
+    if domain != AF_UNIX {
+        linux_return_err_from_syscall!(EAFNOSUPPORT)
+    }
+    if kind != SOCK_STREAM {
+        linux_return_err_from_syscall!(ESOCKTNOSUPPORT)
+    }
+    if protocol != 0 {
+        linux_return_err_from_syscall!(EPROTONOSUPPORT)
+    }
+    let Some(mut structure) = UserProcessStructure::new(sv as *mut LinuxSocketPairFds) else {
+        linux_return_err_from_syscall!(EFAULT)
+    };
+    let mut ptlock = thread.thread.process.page_table.lock();
+    if structure.verify_fully_mapped_mut(&mut ptlock).is_none() {
+        linux_return_err_from_syscall!(EFAULT)
+    }
+    drop(ptlock);
+
+    let fs = match get_unix_sock_fs() {
+        Ok(fs) => fs,
+        Err(e) => linux_return_err_from_syscall!(vfs_err_to_linux_errno(e)),
+    };
+    let mut guard = fs.write();
+    let Some(sockfs) = (**guard).as_any_mut().downcast_mut::<UnixSockFs>() else {
+        linux_return_err_from_syscall!(EINVAL)
+    };
+    let (a, b) = sockfs.create_pair(nonblocking);
+    drop(guard);
+
+    let Some(fds) = install_fds(thread, fs.clone(), &[a, b]) else {
+        let mut guard = fs.write();
+        let _ = guard.fclose(a);
+        let _ = guard.fclose(b);
+        linux_return_err_from_syscall!(EMFILE)
+    };
+
+    let mut ptlock = thread.thread.process.page_table.lock();
+    match structure.verify_fully_mapped_mut(&mut ptlock) {
+        Some(sv) => {
+            sv.a = fds[0] as i32;
+            sv.b = fds[1] as i32;
+            0
+        }
+        None => linux_return_err_from_syscall!(EFAULT),
+    }
+}
+
+pub fn linux_sys_bind(thread: &ProcThreadInfo, fd: u64, addr: u64, len: u64) -> u64 {
+    let domain = match socket_domain(thread, fd) {
+        Ok(domain) => domain,
+        Err(errno) => linux_return_err_from_syscall!(errno),
+    };
+    let sockaddr = match read_sockaddr(thread, addr, len, domain) {
+        Ok(sockaddr) => sockaddr,
+        Err(errno) => linux_return_err_from_syscall!(errno),
+    };
+    let result = if domain == AF_UNIX {
+        parse_sockaddr_un(&sockaddr)
+            .map_err(SocketFailure::Errno)
+            .and_then(|name| with_unix_socket(thread, fd, |fs, handle| Ok(fs.bind(handle, name)?)))
+    } else {
+        parse_sockaddr_in(&sockaddr)
+            .map_err(SocketFailure::Errno)
+            .and_then(|addr| with_net_socket(thread, fd, |fs, handle| Ok(fs.bind(handle, addr)?)))
+    };
+    socket_result(result.map(|()| 0))
+}
+
+pub fn linux_sys_listen(thread: &ProcThreadInfo, fd: u64, backlog: u64) -> u64 {
+    // A negative backlog is clamped like on linux
+    let backlog = (backlog as i32).max(0) as usize;
+    let result = with_unix_socket(thread, fd, |fs, handle| Ok(fs.listen(handle, backlog)?));
+    socket_result(result.map(|()| 0))
+}
+
+pub fn linux_sys_connect(thread: &ProcThreadInfo, fd: u64, addr: u64, len: u64) -> u64 {
+    let sockaddr = match read_sockaddr(thread, addr, len, AF_UNIX) {
+        Ok(sockaddr) => sockaddr,
+        Err(errno) => linux_return_err_from_syscall!(errno),
+    };
+    let name = match parse_sockaddr_un(&sockaddr) {
+        Ok(name) => name,
+        Err(errno) => linux_return_err_from_syscall!(errno),
+    };
+    // When the listener backlog is full, try again once a connection was accepted
+    let result = with_unix_socket(thread, fd, |fs, handle| {
+        let nonblocking = fs.is_nonblocking(handle)?;
+        fs.connect(handle, &name)
+            .map_err(|e| SocketFailure::from(e).or_would_block(nonblocking))
+    });
+    blocking_socket_result(thread, SYS_CONNECT, result.map(|()| 0))
+}
+
+pub fn linux_sys_accept(thread: &ProcThreadInfo, fd: u64, addr: u64, addrlen: u64) -> u64 {
+    let result = with_unix_socket(thread, fd, |fs, handle| {
+        let nonblocking = fs.is_nonblocking(handle)?;
+        fs.accept(handle)
+            .map_err(|e| SocketFailure::from(e).or_would_block(nonblocking))
+    });
+    let handle = match result {
+        Ok(handle) => handle,
+        Err(failure) => return blocking_socket_result(thread, SYS_ACCEPT, Err(failure)),
+    };
+
+    // Accepted sockets live in the same fs as the listener
+    let fs = match get_unix_sock_fs() {
+        Ok(fs) => fs,
+        Err(e) => linux_return_err_from_syscall!(vfs_err_to_linux_errno(e)),
+    };
+    let Some(fds) = install_fds(thread, fs.clone(), &[handle]) else {
+        let _ = fs.write().fclose(handle);
+        linux_return_err_from_syscall!(EMFILE)
+    };
+
+    if let Err(errno) = write_sockaddr(thread, addr, addrlen, &encode_sockaddr_un_unnamed()) {
+        linux_return_err_from_syscall!(errno)
+    }
+    fds[0] as u64
+}
+
+/// Sends never wait (datagrams are delivered right away, streams do partial writes),
+/// so the flags are ignored
+pub fn linux_sys_sendto(
+    thread: &ProcThreadInfo,
+    fd: u64,
+    buf: u64,
+    len: u64,
+    _flags: u64,
+    dest_addr: u64,
+    addrlen: u64,
+) -> u64 {
+    let domain = match socket_domain(thread, fd) {
+        Ok(domain) => domain,
+        Err(errno) => linux_return_err_from_syscall!(errno),
+    };
+    let dest = match (domain, dest_addr) {
+        (AF_INET, 0) => linux_return_err_from_syscall!(EDESTADDRREQ),
+        (AF_INET, _) => match read_sockaddr(thread, dest_addr, addrlen, AF_INET)
+            .and_then(|sockaddr| parse_sockaddr_in(&sockaddr))
+        {
+            Ok(dest) => Some(dest),
+            Err(errno) => linux_return_err_from_syscall!(errno),
+        },
+        // Stream sockets send to their peer
+        (_, 0) => None,
+        _ => linux_return_err_from_syscall!(EISCONN),
+    };
+
+    let len = match dest {
+        Some(_) if len > MAX_SEND_SIZE => linux_return_err_from_syscall!(EMSGSIZE),
+        _ => len.min(MAX_SEND_SIZE),
+    };
+    if !is_lower_half_range(buf, len) {
+        linux_return_err_from_syscall!(EFAULT)
+    }
+    let mut ptlock = thread.thread.process.page_table.lock();
+    let user_buffer = UserProcessBuffer::new(buf as *mut u8, len as usize);
+    let Some(data) = user_buffer.verify_fully_mapped(&mut ptlock) else {
+        linux_return_err_from_syscall!(EFAULT)
+    };
+
+    let result = match dest {
+        Some(dest) => with_net_socket(thread, fd, |fs, handle| {
+            Ok(fs.send_to(handle, dest, data)? as u64)
+        }),
+        None => with_unix_socket(thread, fd, |fs, handle| Ok(fs.fwrite(handle, data)?)),
+    };
+    drop(ptlock);
+    socket_result(result)
+}
+
+pub fn linux_sys_recvfrom(
+    thread: &ProcThreadInfo,
+    fd: u64,
+    buf: u64,
+    len: u64,
+    flags: u64,
+    src_addr: u64,
+    addrlen: u64,
+) -> u64 {
+    let domain = match socket_domain(thread, fd) {
+        Ok(domain) => domain,
+        Err(errno) => linux_return_err_from_syscall!(errno),
+    };
+    if !is_lower_half_range(buf, len) {
+        linux_return_err_from_syscall!(EFAULT)
+    }
+    let mut ptlock = thread.thread.process.page_table.lock();
+    let mut user_buffer = UserProcessBuffer::new(buf as *mut u8, len as usize);
+    let Some(data) = user_buffer.verify_fully_mapped_mut(&mut ptlock) else {
+        linux_return_err_from_syscall!(EFAULT)
+    };
+
+    let dontwait = flags & MSG_DONTWAIT != 0;
+    let result = if domain == AF_INET {
+        with_net_socket(thread, fd, |fs, handle| {
+            let nonblocking = dontwait || fs.is_nonblocking(handle)?;
+            fs.recv_from(handle, data)
+                .map(|(read, src)| (read as u64, Some(src)))
+                .map_err(|e| SocketFailure::from(e).or_would_block(nonblocking))
+        })
+    } else {
+        with_unix_socket(thread, fd, |fs, handle| {
+            let nonblocking = dontwait || fs.is_nonblocking(handle)?;
+            match fs.fread(handle, data) {
+                Ok(read) => Ok((read, None)),
+                Err(VfsError::WouldBlock) if !nonblocking => match fs.fwait_queue(handle)? {
+                    Some(queue) => Err(SocketFailure::Block(queue)),
+                    None => Err(SocketFailure::Errno(EWOULDBLOCK)),
+                },
+                Err(e) => Err(e.into()),
+            }
+        })
+    };
+    drop(ptlock);
+
+    let (read, src) = match result {
+        Ok(received) => received,
+        Err(failure) => return blocking_socket_result(thread, SYS_RECVFROM, Err(failure)),
+    };
+    let written = match src {
+        Some(src) => write_sockaddr(thread, src_addr, addrlen, &encode_sockaddr_in(src)),
+        None => write_sockaddr(thread, src_addr, addrlen, &encode_sockaddr_un_unnamed()),
+    };
+    if let Err(errno) = written {
+        linux_return_err_from_syscall!(errno)
+    }
+    read
+}
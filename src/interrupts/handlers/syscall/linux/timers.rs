@@ -0,0 +1,281 @@
+use crate::{
+    drivers::{
+        fs::virt::timerfd::{get_timer_fd_fs, TimerClock, TimerFdFs},
+        time::get_unix_timestamp_ms,
+    },
+    interrupts::{
+        handlers::{
+            irq::irq0_timer::get_uptime_ticks,
+            syscall::{
+                linux::{
+                    poll::LinuxTimespec, resources::LinuxTimeval, socket::install_fds,
+                    vfs_err_to_linux_errno, EBADF, EFAULT, EINVAL, EMFILE,
+                },
+                utils::structure::UserProcessStructure,
+            },
+        },
+        pit::{ms_to_ticks, ticks_to_ms},
+    },
+    linux_return_err_from_syscall,
+    process::{
+        scheduler::ProcThreadInfo,
+        time_page::read_time_page,
+        timer::{TimerEvent, TIMERS},
+    },
+};
+
+pub const ITIMER_REAL: u64 = 0;
+
+pub const CLOCK_REALTIME: u64 = 0;
+pub const CLOCK_MONOTONIC: u64 = 1;
+pub const CLOCK_MONOTONIC_RAW: u64 = 4;
+pub const CLOCK_REALTIME_COARSE: u64 = 5;
+pub const CLOCK_MONOTONIC_COARSE: u64 = 6;
+pub const CLOCK_BOOTTIME: u64 = 7;
+
+pub const TFD_NONBLOCK: u64 = 0o4000;
+pub const TFD_CLOEXEC: u64 = 0o2000000;
+pub const TFD_TIMER_ABSTIME: u64 = 1;
+
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct LinuxItimerval {
+    pub it_interval: LinuxTimeval,
+    pub it_value: LinuxTimeval,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct LinuxItimerspec {
+    pub it_interval: LinuxTimespec,
+    pub it_value: LinuxTimespec,
+}
+
+fn timeval_to_ms(timeval: &LinuxTimeval) -> Option<u64> {
+    if timeval.tv_sec < 0 || !(0..1_000_000).contains(&timeval.tv_usec) {
+        return None;
+    }
+    Some(
+        (timeval.tv_sec as u64)
+            .saturating_mul(1000)
+            .saturating_add((timeval.tv_usec as u64).div_ceil(1000)),
+    )
+}
+
+pub fn timespec_to_ms(timespec: &LinuxTimespec) -> Option<u64> {
+    if timespec.tv_sec < 0 || !(0..1_000_000_000).contains(&timespec.tv_nsec) {
+        return None;
+    }
+    Some(
+        (timespec.tv_sec as u64)
+            .saturating_mul(1000)
+            .saturating_add((timespec.tv_nsec as u64).div_ceil(1_000_000)),
+    )
+}
+
+/// Arms the `ITIMER_REAL` of the process in `initial` ticks, disarms it if `initial` is 0.
+/// Returns the ticks left and the interval of the previous setting
+fn set_real_timer(thread: &ProcThreadInfo, initial: u64, interval: u64) -> (u64, u64) {
+    let mut real_timer = thread.thread.process.real_timer.lock();
+    let mut timers = TIMERS.lock();
+    let now = get_uptime_ticks();
+    let previous = real_timer
+        .take()
+        .and_then(|id| timers.cancel(id))
+        .map_or((0, 0), |(expires, interval)| {
+            (expires.saturating_sub(now), interval)
+        });
+    if initial != 0 {
+        *real_timer = Some(timers.add(
+            thread.pid,
+            now + initial,
+            interval,
+            TimerEvent::Alarm(thread.pid),
+        ));
+    }
+    previous
+}
+
+/// Returns the seconds left of the previous alarm, rounded like linux so a pending alarm never
+/// reports 0
+pub fn linux_sys_alarm(thread: &ProcThreadInfo, seconds: u64) -> u64 {
+    let seconds = seconds as u32 as u64;
+    let (remaining, _) = set_real_timer(thread, ms_to_ticks(seconds * 1000), 0);
+    if remaining == 0 {
+        return 0;
+    }
+    ticks_to_ms(remaining).div_ceil(1000).max(1)
+}
+
+/// Only `ITIMER_REAL` is supported, the CPU time timers fail with EINVAL
+pub fn linux_sys_setitimer(
+    thread: &ProcThreadInfo,
+    which: u64,
+    new_value: u64,
+    old_value: u64,
+) -> u64 {
+    if which != ITIMER_REAL {
+        linux_return_err_from_syscall!(EINVAL)
+    }
+    let Some(structure) = UserProcessStructure::new(new_value as *mut LinuxItimerval) else {
+        linux_return_err_from_syscall!(EFAULT)
+    };
+    let mut ptlock = thread.thread.process.page_table.lock();
+    let Some(new_value) = structure.verify_fully_mapped(&mut ptlock).copied() else {
+        linux_return_err_from_syscall!(EFAULT)
+    };
+    drop(ptlock);
+    let (Some(initial), Some(interval)) = (
+        timeval_to_ms(&new_value.it_value),
+        timeval_to_ms(&new_value.it_interval),
+    ) else {
+        linux_return_err_from_syscall!(EINVAL)
+    };
+
+    let (remaining, interval) = set_real_timer(thread, ms_to_ticks(initial), ms_to_ticks(interval));
+    if old_value == 0 {
+        return 0;
+    }
+    let Some(mut structure) = UserProcessStructure::new(old_value as *mut LinuxItimerval) else {
+        linux_return_err_from_syscall!(EFAULT)
+    };
+    let mut ptlock = thread.thread.process.page_table.lock();
+    let Some(old_value) = structure.verify_fully_mapped_mut(&mut ptlock) else {
+        linux_return_err_from_syscall!(EFAULT)
+    };
+    *old_value = LinuxItimerval {
+        it_interval: LinuxTimeval::from_ticks(interval),
+        it_value: LinuxTimeval::from_ticks(remaining),
+    };
+    0
+}
+
+/// Reads the time page, so it agrees with what userland computes from it. The CPU time clocks
+/// aren't supported
+pub fn linux_sys_clock_gettime(thread: &ProcThreadInfo, clock_id: u64, tp: u64) -> u64 {
+    let Some(time) = read_time_page() else {
+        linux_return_err_from_syscall!(EINVAL)
+    };
+    let ns = match clock_id {
+        CLOCK_REALTIME | CLOCK_REALTIME_COARSE => time.realtime_ns(),
+        CLOCK_MONOTONIC | CLOCK_MONOTONIC_RAW | CLOCK_MONOTONIC_COARSE | CLOCK_BOOTTIME => {
+            time.monotonic_ns()
+        }
+        _ => linux_return_err_from_syscall!(EINVAL),
+    };
+    let Some(mut structure) = UserProcessStructure::new(tp as *mut LinuxTimespec) else {
+        linux_return_err_from_syscall!(EFAULT)
+    };
+    let mut ptlock = thread.thread.process.page_table.lock();
+    let Some(tp) = structure.verify_fully_mapped_mut(&mut ptlock) else {
+        linux_return_err_from_syscall!(EFAULT)
+    };
+    *tp = LinuxTimespec {
+        tv_sec: (ns / 1_000_000_000) as i64,
+        tv_nsec: (ns % 1_000_000_000) as i64,
+    };
+    0
+}
+
+/// There is no exec yet, TFD_CLOEXEC is ignored
+pub fn linux_sys_timerfd_create(thread: &ProcThreadInfo, clockid: u64, flags: u64) -> u64 {
+    let clock = match clockid {
+        CLOCK_REALTIME => TimerClock::Realtime,
+        CLOCK_MONOTONIC => TimerClock::Monotonic,
+        _ => linux_return_err_from_syscall!(EINVAL),
+    };
+    if flags & !(TFD_NONBLOCK | TFD_CLOEXEC) != 0 {
+        linux_return_err_from_syscall!(EINVAL)
+    }
+    let fs = match get_timer_fd_fs() {
+        Ok(fs) => fs,
+        Err(e) => linux_return_err_from_syscall!(vfs_err_to_linux_errno(e)),
+    };
+    let mut guard = fs.write();
+    let Some(timerfd_fs) = (**guard).as_any_mut().downcast_mut::<TimerFdFs>() else {
+        linux_return_err_from_syscall!(EINVAL)
+    };
+    let handle = timerfd_fs.create_timer(thread.pid, clock, flags & TFD_NONBLOCK != 0);
+    drop(guard);
+
+    match install_fds(thread, fs.clone(), &[handle]) {
+        Some(fds) => fds[0] as u64,
+        None => {
+            let _ = fs.write().fclose(handle);
+            linux_return_err_from_syscall!(EMFILE)
+        }
+    }
+}
+
+/// An absolute expiration time is on the clock of the timer, one already passed expires at the
+/// next tick
+pub fn linux_sys_timerfd_settime(
+    thread: &ProcThreadInfo,
+    fd: u64,
+    flags: u64,
+    new_value: u64,
+    old_value: u64,
+) -> u64 {
+    if flags & !TFD_TIMER_ABSTIME != 0 {
+        linux_return_err_from_syscall!(EINVAL)
+    }
+    let Some(structure) = UserProcessStructure::new(new_value as *mut LinuxItimerspec) else {
+        linux_return_err_from_syscall!(EFAULT)
+    };
+    let mut ptlock = thread.thread.process.page_table.lock();
+    let Some(new_value) = structure.verify_fully_mapped(&mut ptlock).copied() else {
+        linux_return_err_from_syscall!(EFAULT)
+    };
+    drop(ptlock);
+    let (Some(value), Some(interval)) = (
+        timespec_to_ms(&new_value.it_value),
+        timespec_to_ms(&new_value.it_interval),
+    ) else {
+        linux_return_err_from_syscall!(EINVAL)
+    };
+
+    let io_ctx = thread.thread.process.io_context.lock();
+    let Some(file) = io_ctx.file_table.get(fd as usize) else {
+        linux_return_err_from_syscall!(EBADF)
+    };
+    drop(io_ctx);
+    let mut guard = file.fs.write();
+    let Some(timerfd_fs) = (**guard).as_any_mut().downcast_mut::<TimerFdFs>() else {
+        linux_return_err_from_syscall!(EINVAL)
+    };
+
+    let initial = if value == 0 {
+        0
+    } else if flags & TFD_TIMER_ABSTIME != 0 {
+        let now = match timerfd_fs.clock(file.handle) {
+            Ok(TimerClock::Realtime) => get_unix_timestamp_ms(),
+            Ok(TimerClock::Monotonic) => ticks_to_ms(get_uptime_ticks()),
+            Err(e) => linux_return_err_from_syscall!(vfs_err_to_linux_errno(e)),
+        };
+        ms_to_ticks(value.saturating_sub(now)).max(1)
+    } else {
+        ms_to_ticks(value)
+    };
+    let (remaining, interval) =
+        match timerfd_fs.set_time(file.handle, initial, ms_to_ticks(interval)) {
+            Ok(previous) => previous,
+            Err(e) => linux_return_err_from_syscall!(vfs_err_to_linux_errno(e)),
+        };
+    drop(guard);
+
+    if old_value == 0 {
+        return 0;
+    }
+    let Some(mut structure) = UserProcessStructure::new(old_value as *mut LinuxItimerspec) else {
+        linux_return_err_from_syscall!(EFAULT)
+    };
+    let mut ptlock = thread.thread.process.page_table.lock();
+    let Some(old_value) = structure.verify_fully_mapped_mut(&mut ptlock) else {
+        linux_return_err_from_syscall!(EFAULT)
+    };
+    *old_value = LinuxItimerspec {
+        it_interval: LinuxTimespec::from_ticks(interval),
+        it_value: LinuxTimespec::from_ticks(remaining),
+    };
+    0
+}
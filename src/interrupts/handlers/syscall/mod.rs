@@ -1,3 +1,4 @@
+pub mod bench;
 pub mod int80h;
 pub mod linux;
 pub mod utils;
@@ -9,16 +9,16 @@ use crate::{
     data::{calloc_boxed_slice, regs::fs_gs_base::GsBase},
     gdt::{KERNEL_CODE_SELECTOR, KERNEL_DATA_SELECTOR},
     interrupts::pic::pic_send_eoi,
+    log_debug, log_error,
     paging::{get_kernel_page_table, DIRECT_MAPPING_OFFSET, PAGE_ACCESSED, PAGE_PRESENT, PAGE_RW},
     percpu::{core_id, get_per_cpu, InterruptSource, PerCpu},
-    println,
     process::{
         memory::GLOB_KERNEL_STACK_TOP,
         task::{get_tss, set_tss},
     },
 };
 
-use super::handlers;
+use super::{apic, handlers, msi};
 
 pub const IDT_PRESENT: u8 = 1 << 7;
 pub const IDT_DPL0: u8 = 0 << 5;
@@ -109,9 +109,9 @@ unsafe fn load_idt(idt: &Idt) {
         limit: (core::mem::size_of::<Idt>() - 1) as u16,
         base: idt as *const _ as u64,
     };
-    println!("Loading IDT.");
-    println!("IDT Descriptor: {:#?}", descriptor);
-    println!("IDT Descriptor Address: {:?}", IDT_DESCRIPTOR.0.as_ptr());
+    log_debug!(target: "idt", "Loading IDT.");
+    log_debug!(target: "idt", "IDT Descriptor: {:#?}", descriptor);
+    log_debug!(target: "idt", "IDT Descriptor Address: {:?}", IDT_DESCRIPTOR.0.as_ptr());
     core::ptr::write_volatile(IDT_DESCRIPTOR.0.as_ptr() as *mut IdtDescriptor, descriptor);
     asm!(
         "lidt [{}]",
@@ -127,10 +127,10 @@ fn unhandled_interrupt(
     ifc: &mut InterruptFrameContext,
     ife: Option<&mut InterruptFrameExtra>,
 ) {
-    println!("Unhandled interrupt {:#02x}.", int);
-    println!("{:#?}", ifr);
-    println!("{:#?}", ifc);
-    println!("{:#?}", ife);
+    log_error!(target: "idt", "Unhandled interrupt {:#02x}.", int);
+    log_error!(target: "idt", "{:#?}", ifr);
+    log_error!(target: "idt", "{:#?}", ifc);
+    log_error!(target: "idt", "{:#?}", ife);
     panic!("Unhandled interrupt dump complete.");
 }
 
@@ -173,6 +173,7 @@ pub unsafe extern "C" fn fast_syscall_entry() {
         "mov gs:[{offset_r15}], r15",
 
         "mov rsp, gs:[{offset_kernel_rsp}]",
+        // Doesn't return when the scheduler switches threads, the next one is resumed with iretq
         "call {fn_syscall_handler}",
 
         "mov rax, gs:[{offset_rax}]",
@@ -191,7 +192,6 @@ pub unsafe extern "C" fn fast_syscall_entry() {
         "mov r13, gs:[{offset_r13}]",
         "mov r14, gs:[{offset_r14}]",
         "mov r15, gs:[{offset_r15}]",
-        "mov rbp, rsp",
         "swapgs",
         "sysretq",
 
@@ -327,6 +327,12 @@ struct IstStack {
 }
 
 static mut IST_STACKS: [Option<IstStack>; 7] = [const { None }; 7];
+/// IST indices are 1-based, 0 means the interrupt uses the current stack
+const IST_DOUBLE_FAULT: u8 = 1;
+const IST_NMI: u8 = 2;
+const IST_MACHINE_CHECK: u8 = 3;
+/// Page faults grow kernel stacks, so they can't run on the stack that faulted
+const IST_PAGE_FAULT: u8 = 4;
 const STACK_SEPARATION: u64 = 8 * 1024 * 1024 * 1024; // 8GiB
 
 pub fn init_interrupts() {
@@ -335,6 +341,8 @@ pub fn init_interrupts() {
             IDT.entries[i].set_handler(*f, KERNEL_CODE_SELECTOR as u16, 0, KERNEL_INT_FLAGS);
         }
         IDT.entries[0x80].flags = USER_INT_FLAGS;
+        // int3 from user mode
+        IDT.entries[0x03].flags = USER_INT_FLAGS;
 
         let mut curr_ist_top = GLOB_KERNEL_STACK_TOP - STACK_SEPARATION;
         let mut kpages = get_kernel_page_table().lock();
@@ -361,17 +369,28 @@ pub fn init_interrupts() {
         }
         set_tss(&tss);
 
-        IDT.entries[0x0E].ist = 1;
-        IDT.entries[0x08].ist = 2;
+        IDT.entries[0x08].ist = IST_DOUBLE_FAULT;
+        IDT.entries[0x02].ist = IST_NMI;
+        IDT.entries[0x12].ist = IST_MACHINE_CHECK;
+        IDT.entries[0x0E].ist = IST_PAGE_FAULT;
 
         HANDLERS[0x20] = handlers::irq::irq0_timer::handler;
         HANDLERS[0x21] = handlers::irq::irq1_keyboard::handler;
 
+        HANDLERS[0x01] = handlers::exception::exc_1_debug::handler;
+        HANDLERS[0x03] = handlers::exception::exc_3_breakpoint::handler;
         HANDLERS[0x06] = handlers::exception::exc_6_invalid_opcode::handler;
+        HANDLERS[0x07] = handlers::exception::exc_7_device_not_available::handler;
+        HANDLERS[0x08] = handlers::exception::exc_8_double_fault::handler;
         HANDLERS[0x0E] = handlers::exception::exc_e_page_fault::handler;
 
         HANDLERS[0x80] = handlers::syscall::int80h::handler;
 
+        for vector in 0..msi::MSI_VECTOR_COUNT {
+            HANDLERS[msi::MSI_VECTOR_BASE as usize + vector] = msi::handler;
+        }
+        HANDLERS[apic::APIC_SPURIOUS_VECTOR as usize] = msi::spurious_handler;
+
         #[allow(static_mut_refs)]
         load_idt(&IDT);
     }
@@ -469,7 +488,7 @@ fn common_enter_interrupt(
     per_cpu.interrupt_sources.push(if ifc.cs & 0b11 == 0 {
         InterruptSource::Kernel
     } else {
-        InterruptSource::Syscall
+        InterruptSource::User
     });
 
     if ifc.cs & 0b11 != 0 {
@@ -1,15 +1,20 @@
 use core::arch::asm;
 
+use crate::data::regs::rflags::{RFlag, RFlags};
+
+pub mod apic;
 pub mod handlers;
 pub mod idt;
+pub mod msi;
 pub mod pic;
 pub mod pit;
 
 pub fn init() {
     pic::pic_remap(0x20, 0x28);
-    pit::init_pit(u16::MAX);
+    pit::init_pit(pit::PIT_FREQUENCY_DIVIDER);
 
     idt::init_interrupts();
+    apic::init();
 
     pic::pic_unmask(0);
     pic::pic_unmask(1);
@@ -19,6 +24,21 @@ pub fn init() {
     }
 }
 
+/// Runs `f` with interrupts disabled, they are enabled again only if they were before
+pub fn without_interrupts<R>(f: impl FnOnce() -> R) -> R {
+    let enabled = RFlags::read().has(RFlag::InterruptFlag);
+    unsafe {
+        asm!("cli");
+    }
+    let result = f();
+    if enabled {
+        unsafe {
+            asm!("sti");
+        }
+    }
+    result
+}
+
 pub fn run_without_interrupts<F>(f: F)
 where
     F: FnOnce(),
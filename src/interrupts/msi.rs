@@ -0,0 +1,147 @@
+//! Message signaled interrupts. Devices write `MsiAllocation::data` to `MsiAllocation::address`,
+//! which the local APIC delivers as an interrupt on the allocated vector
+//!
+//! Once the reserved vectors are used up, new handlers share the least used one. Every handler of
+//! a vector runs when it fires and reports whether its device raised the interrupt.
+
+use alloc::vec::Vec;
+use spin::Mutex;
+
+use crate::log_warn;
+
+use super::{
+    apic,
+    idt::{InterruptFrameContext, InterruptFrameExtra, InterruptFrameRegisters},
+};
+
+/// First vector handed out for MSIs, below the syscall vector
+pub const MSI_VECTOR_BASE: u8 = 0x50;
+pub const MSI_VECTOR_COUNT: usize = 0x30;
+
+const MSI_ADDRESS_BASE: u64 = 0xFEE0_0000;
+const MSI_ADDRESS_DESTINATION_SHIFT: u64 = 12;
+
+/// Called with the context given to `alloc_msi_vector`, returns whether its device raised the
+/// interrupt
+pub type MsiHandlerFn = fn(u64) -> bool;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MsiError {
+    /// There is no local APIC to deliver them
+    Unsupported,
+    /// The device has no MSI capability
+    NoCapability,
+}
+
+/// A vector handed out to a device, to program into its MSI capability
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MsiAllocation {
+    pub vector: u8,
+    /// CPU the interrupt is delivered to
+    pub cpu: u8,
+    pub address: u64,
+    pub data: u32,
+    /// Identifies the handler for `free_msi_vector`
+    id: u64,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct MsiHandler {
+    id: u64,
+    handler: MsiHandlerFn,
+    context: u64,
+}
+
+struct MsiVectors {
+    handlers: [Vec<MsiHandler>; MSI_VECTOR_COUNT],
+    next_id: u64,
+}
+
+static MSI_VECTORS: Mutex<MsiVectors> = Mutex::new(MsiVectors {
+    handlers: [const { Vec::new() }; MSI_VECTOR_COUNT],
+    next_id: 0,
+});
+
+/// Runs `f` with interrupts off, so a MSI can't find the table locked on this CPU
+fn with_vectors<R>(f: impl FnOnce(&mut MsiVectors) -> R) -> R {
+    let mut result = None;
+    super::run_without_interrupts(|| result = Some(f(&mut MSI_VECTORS.lock())));
+    result.unwrap()
+}
+
+/// Picks a vector for `handler`, preferring a free one. Only CPU 0 receives interrupts for now,
+/// whatever `cpu` asks for
+pub fn alloc_msi_vector(
+    cpu: u8,
+    handler: MsiHandlerFn,
+    context: u64,
+) -> Result<MsiAllocation, MsiError> {
+    if !apic::is_enabled() {
+        return Err(MsiError::Unsupported);
+    }
+    let _ = cpu;
+    let cpu = 0;
+    let destination = apic::boot_cpu_id();
+
+    let (index, id) = with_vectors(|vectors| {
+        let index = (0..MSI_VECTOR_COUNT)
+            .min_by_key(|i| vectors.handlers[*i].len())
+            .unwrap();
+        let id = vectors.next_id;
+        vectors.next_id += 1;
+        vectors.handlers[index].push(MsiHandler {
+            id,
+            handler,
+            context,
+        });
+        (index, id)
+    });
+
+    let vector = MSI_VECTOR_BASE + index as u8;
+    Ok(MsiAllocation {
+        vector,
+        cpu,
+        address: MSI_ADDRESS_BASE | ((destination as u64) << MSI_ADDRESS_DESTINATION_SHIFT),
+        // Fixed delivery, edge triggered
+        data: vector as u32,
+        id,
+    })
+}
+
+/// Removes the handler, the vector is free again once it has no handler left. The device must
+/// not signal it anymore
+pub fn free_msi_vector(allocation: &MsiAllocation) {
+    let index = (allocation.vector - MSI_VECTOR_BASE) as usize;
+    with_vectors(|vectors| vectors.handlers[index].retain(|h| h.id != allocation.id));
+}
+
+pub fn handler(
+    int: u64,
+    _rsp: u64,
+    _ifr: &mut InterruptFrameRegisters,
+    _ifc: &mut InterruptFrameContext,
+    _ife: Option<&mut InterruptFrameExtra>,
+) {
+    let index = int as usize - MSI_VECTOR_BASE as usize;
+    let vectors = MSI_VECTORS.lock();
+    let mut handled = false;
+    for h in vectors.handlers[index].iter() {
+        handled |= (h.handler)(h.context);
+    }
+    drop(vectors);
+    if !handled {
+        log_warn!(target: "msi", "Unclaimed interrupt on vector {:#x}", int);
+    }
+    apic::send_eoi();
+}
+
+/// The APIC expects no EOI for its spurious vector
+pub fn spurious_handler(
+    int: u64,
+    _rsp: u64,
+    _ifr: &mut InterruptFrameRegisters,
+    _ifc: &mut InterruptFrameContext,
+    _ife: Option<&mut InterruptFrameExtra>,
+) {
+    log_warn!(target: "apic", "Spurious interrupt on vector {:#x}", int);
+}
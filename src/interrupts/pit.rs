@@ -3,6 +3,19 @@ use crate::io::outb;
 pub const PIT_COMMAND_PORT: u16 = 0x43;
 pub const PIT_CHANNEL0_DATA_PORT: u16 = 0x40;
 
+pub const PIT_BASE_FREQUENCY: u64 = 1_193_182;
+pub const PIT_FREQUENCY_DIVIDER: u16 = u16::MAX;
+
+/// Converts a duration to a number of timer ticks, rounded up
+pub const fn ms_to_ticks(ms: u64) -> u64 {
+    (ms.saturating_mul(PIT_BASE_FREQUENCY)).div_ceil(PIT_FREQUENCY_DIVIDER as u64 * 1000)
+}
+
+/// Converts a number of timer ticks to a duration, rounded down
+pub const fn ticks_to_ms(ticks: u64) -> u64 {
+    ticks.saturating_mul(PIT_FREQUENCY_DIVIDER as u64 * 1000) / PIT_BASE_FREQUENCY
+}
+
 pub fn init_pit(frequency_divider: u16) {
     outb(PIT_COMMAND_PORT, 0x36);
     outb(PIT_CHANNEL0_DATA_PORT, (frequency_divider & 0xFF) as u8);
@@ -1,5 +1,7 @@
 use core::arch::asm;
 
+use crate::config::try_get_kernel_config;
+
 pub fn outb(port: u16, value: u8) {
     unsafe {
         asm!("out dx, al", in("dx") port, in("al") value);
@@ -48,3 +50,37 @@ pub fn iowait() {
         outb(UNUSED_PORT, 0);
     }
 }
+
+pub const QEMU_EXIT_DEFAULT_PORT: u16 = 0xf4;
+/// isa-debug-exit makes qemu exit with `(code << 1) | 1`
+pub const QEMU_EXIT_SUCCESS: u32 = 0x10;
+pub const QEMU_EXIT_FAILURE: u32 = 0x11;
+pub const QEMU_EXIT_PANIC: u32 = 0x12;
+
+/// Only does something when qemu runs with an isa-debug-exit device at the `qemu.exit_port` port,
+/// returns otherwise
+pub fn qemu_exit(code: u32) {
+    let port = try_get_kernel_config()
+        .and_then(|config| u16::try_from(config.qemu_exit_port).ok())
+        .unwrap_or(QEMU_EXIT_DEFAULT_PORT);
+    outl(port, code);
+}
+
+const RESET_CONTROL_PORT: u16 = 0xcf9;
+const PS2_CONTROLLER_PORT: u16 = 0x64;
+
+/// The FADT isn't parsed, so this tries the reset control register chipsets usually expose as the
+/// ACPI reset register, then the keyboard controller reset line. Returns if neither worked
+pub fn reboot() {
+    outb(RESET_CONTROL_PORT, 0x02);
+    outb(RESET_CONTROL_PORT, 0x06);
+    iowait();
+
+    for _ in 0..0x10000 {
+        if inb(PS2_CONTROLLER_PORT) & 0x02 == 0 {
+            break;
+        }
+    }
+    outb(PS2_CONTROLLER_PORT, 0xfe);
+    iowait();
+}
@@ -0,0 +1,1807 @@
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use alloc::{
+    boxed::Box,
+    collections::{BTreeMap, VecDeque},
+    string::String,
+    sync::Arc,
+    vec::Vec,
+};
+
+use crate::{
+    cmdline::{KernelCommandLine, MAX_COMMAND_LINE_LEN, MAX_COMMAND_LINE_PARAMETERS},
+    config::KernelBaseConfig,
+    crypto::chacha::{chacha20_block, ChaCha20Rng, CHACHA_BLOCK_SIZE},
+    data::{
+        assign_once::AssignOnce,
+        bitmap::Bitmap,
+        decimal_chars_to_u64,
+        either::Either,
+        lock::{order_violations, DebugMutex, DebugRwLock, LockClass},
+        path::{VfsName, VfsPath},
+        permissions::{Credentials, PermissionLevel, PermissionType, Permissions},
+        regs::{
+            cr::{Cr0, CR0_TS},
+            fs_gs_base::{FsBase, GsBase, IA32_FS_BASE},
+            msr::rdmsr,
+            rflags::RFlag,
+        },
+    },
+    drivers::{
+        fs::virt::ptyfs::Pty,
+        keyboard::{
+            keymap::{Composer, KeySymbol, Keymap, KeymapError, KeymapLevel, KeymapSyntaxError},
+            KeyModifier, KeyModifiers,
+        },
+        pci::PciBar,
+        tty::{
+            process_output, LineDiscipline, Termios, TtySignal, ICANON, TCGETS, TCSETS,
+            TERMIOS_SIZE, TIOCGPTN, TIOCSPTLCK,
+        },
+        vfs::{get_vfs, FileStat, VfsError, OPEN_MODE_READ, OPEN_MODE_WRITE},
+        vga::{
+            console::{Cell, Console, ConsoleControl},
+            text::{cp437_glyph, REPLACEMENT_GLYPH},
+        },
+    },
+    formats::cpio::{CpioArchive, CpioEntryKind, CpioError},
+    formats::elf::{
+        build_stack, AuxValue, Elf64File, Elf64HeaderRaw, Elf64ProgramHeaderRaw, Elf64RelaRaw,
+        Elf64SectionHeaderRaw, ElfError, ElfProgramHeaderFlag, ElfProgramHeaderFlags,
+        InvalidElfFileReason, InvalidSegmentReason, AT_EXECFN, AT_NULL, AT_PAGESZ, AT_PHDR,
+        AT_PHENT, AT_PHNUM, AT_RANDOM, AT_TIME_PAGE, ELF_MAGIC, R_X86_64_RELATIVE, SHT_RELA,
+    },
+    formats::flat::{FlatBinary, FlatError, FlatHeader, FLAT_MAGIC},
+    formats::script::{parse_shebang, ScriptError},
+    interrupts::handlers::{
+        irq::irq0_timer::{get_uptime_ticks, set_tick_hook},
+        syscall::linux::ptrace::LinuxUserRegs,
+    },
+    kassert, kassert_eq, ktest,
+    log::{get_stdout, KernelLogLevel, KernelLogRing, KernelStdoutStats},
+    memory::mem::get_memory_stats,
+    obsiboot::{ObsiBootError, ObsiBootKernelParameters, OBSIBOOT_V1_SIZE, OBSIBOOT_V2_SIZE},
+    paging::{
+        PageTable, DIRECT_MAPPING_OFFSET, PAGE_ACCESSED, PAGE_PRESENT, PAGE_RW, PAGE_SIZE,
+        PAGE_USER,
+    },
+    percpu::{get_per_cpu, is_per_cpu_base},
+    permissions, printf, println, println_irq,
+    process::{
+        fpu::{flush_fpu_owner, fpu_area_size, FpuState},
+        futex::{futex_count, release_process_futexes, LockedFutex},
+        limits::{LimitError, Resource, ResourceLimit, ResourceLimits, RLIM_INFINITY},
+        memory::{PROC_TIME_PAGE, PROC_USER_STACK_TOP},
+        proc::{ProcessAccess, ThreadGPRegisters, ThreadState},
+        scheduler::SchedulerThreadSettings,
+        time_page::{map_time_page, read_time_page, time_page_phys, NS_PER_TICK},
+        timer::{TimerEvent, TimerWheel},
+    },
+};
+
+use super::{KTest, KTestContext};
+
+pub const KTESTS: &[KTest] = &[
+    ktest!(bitmap_set_get),
+    ktest!(bitmap_find),
+    ktest!(bitmap_count_set_until),
+    ktest!(bitset_enum_ops),
+    ktest!(either_conversions),
+    ktest!(either_accessors),
+    ktest!(assign_once_keeps_first),
+    ktest!(decimal_chars),
+    ktest!(permissions_layout),
+    ktest!(credentials_checks),
+    ktest!(credentials_sticky_delete),
+    ktest!(credentials_attribute_changes),
+    ktest!(resource_limit_rules),
+    ktest!(path_normalize),
+    ktest!(path_parent_and_name),
+    ktest!(path_bytes_roundtrip),
+    ktest!(path_lookup_allocations),
+    ktest!(pci_bar_decoding),
+    ktest!(chacha20_test_vectors),
+    ktest!(console_escape_sequences),
+    ktest!(console_scrollback),
+    ktest!(console_utf8),
+    ktest!(tty_line_discipline),
+    ktest!(keymap_parse_and_compose),
+    ktest!(pty_pair),
+    ktest!(timer_wheel),
+    ktest!(futex_table),
+    ktest!(segment_bases),
+    ktest!(fpu_state_switching),
+    ktest!(time_page_mapping),
+    ktest!(ptrace_registers),
+    ktest!(elf_segment_validation),
+    ktest!(elf_pie_relocation),
+    ktest!(elf_aux_vector),
+    ktest!(shebang_parsing),
+    ktest!(flat_binary_loading),
+    ktest!(cpio_newc_parsing),
+    ktest!(obsiboot_versions),
+    ktest!(command_line_parsing),
+    ktest!(lock_debugging),
+    ktest!(interrupt_safe_printing),
+    ktest!(log_ring_growth),
+];
+
+fn assign_once_keeps_first(t: &mut KTestContext) {
+    let value = AssignOnce::new();
+    kassert_eq!(t, value.get(), None);
+    value.set(1u32);
+    value.set(2);
+    kassert_eq!(t, value.get(), Some(&1));
+
+    // Initialized once, every caller shares it
+    kassert!(t, Arc::ptr_eq(&get_vfs(), &get_vfs()));
+}
+
+fn bitmap_set_get(t: &mut KTestContext) {
+    let mut bitmap = Bitmap::new(20);
+    kassert_eq!(t, bitmap.len(), 20);
+    kassert_eq!(t, bitmap.get_bit(0), Some(false));
+    kassert_eq!(t, bitmap.get_bit(20), None);
+
+    bitmap.set_bit(3, true);
+    bitmap.set_bit(19, true);
+    bitmap.set_bit(20, true);
+    kassert_eq!(t, bitmap.get_bit(3), Some(true));
+    kassert_eq!(t, bitmap.get_bit(19), Some(true));
+    kassert_eq!(t, bitmap.as_slice(), &[0x08, 0x00, 0x08][..]);
+
+    bitmap.toggle_bit(3);
+    bitmap.toggle_bit(4);
+    kassert_eq!(t, bitmap.get_bit(3), Some(false));
+    kassert_eq!(t, bitmap.get_bit(4), Some(true));
+
+    bitmap.set_bit(4, false);
+    bitmap.clear();
+    kassert_eq!(t, bitmap.get_bit(19), Some(false));
+}
+
+fn bitmap_find(t: &mut KTestContext) {
+    let mut bitmap = Bitmap::new(200);
+    kassert_eq!(t, bitmap.find_first_set(), None);
+    kassert_eq!(t, bitmap.find_first_unset(), Some(0));
+
+    bitmap.set_bit(150, true);
+    kassert_eq!(t, bitmap.find_first_set(), Some(150));
+
+    for i in 0..200 {
+        bitmap.toggle_bit(i);
+    }
+    kassert_eq!(t, bitmap.find_first_unset(), Some(150));
+    kassert_eq!(t, bitmap.find_first_set(), Some(0));
+
+    bitmap.set_bit(150, true);
+    kassert_eq!(t, bitmap.find_first_unset(), None);
+
+    // Padding bits past the end must not be reported
+    let mut small = Bitmap::new(5);
+    for i in 0..5 {
+        small.set_bit(i, true);
+    }
+    kassert_eq!(t, small.find_first_unset(), None);
+}
+
+fn bitmap_count_set_until(t: &mut KTestContext) {
+    let mut bitmap = Bitmap::new(20);
+    for i in [0, 7, 8, 15, 19] {
+        bitmap.set_bit(i, true);
+    }
+    kassert_eq!(t, bitmap.count_set_until(0), 0);
+    kassert_eq!(t, bitmap.count_set_until(1), 1);
+    kassert_eq!(t, bitmap.count_set_until(8), 2);
+    kassert_eq!(t, bitmap.count_set_until(9), 3);
+    kassert_eq!(t, bitmap.count_set_until(19), 4);
+    kassert_eq!(t, bitmap.count_set_until(1000), 5);
+}
+
+fn bitset_enum_ops(t: &mut KTestContext) {
+    let mut flags = ElfProgramHeaderFlags::empty();
+    kassert!(t, flags.is_empty());
+
+    flags
+        .set(ElfProgramHeaderFlag::Executable)
+        .set(ElfProgramHeaderFlag::Readable);
+    kassert_eq!(t, flags.get(), 5);
+    kassert!(t, flags.has(ElfProgramHeaderFlag::Readable));
+    kassert!(t, !flags.has(ElfProgramHeaderFlag::Writable));
+
+    flags
+        .unset(ElfProgramHeaderFlag::Executable)
+        .toggle(ElfProgramHeaderFlag::Writable);
+    kassert_eq!(t, flags.get(), 6);
+
+    let combined = ElfProgramHeaderFlags::from(ElfProgramHeaderFlag::Executable)
+        | ElfProgramHeaderFlag::Writable;
+    kassert_eq!(t, u32::from(combined), 3);
+    kassert_eq!(t, (combined & flags).get(), 2);
+    kassert_eq!(t, (combined ^ flags).get(), 5);
+    kassert_eq!(t, (!ElfProgramHeaderFlags::empty()).get(), u32::MAX);
+    kassert_eq!(
+        t,
+        ElfProgramHeaderFlag::from(ElfProgramHeaderFlags::from(4)),
+        ElfProgramHeaderFlag::Readable
+    );
+
+    flags.clear();
+    kassert!(t, flags.is_empty());
+}
+
+fn either_conversions(t: &mut KTestContext) {
+    let left: Either<u32, &str> = Either::new_left(3);
+    let right: Either<u32, &str> = Either::new_right("abc");
+
+    kassert_eq!(t, left.map(|a| a * 2, str::len), Either::A(6));
+    kassert_eq!(t, right.map(|a| a * 2, str::len), Either::B(3));
+    kassert_eq!(t, right.convert(|a| a as usize, str::len), 3);
+    kassert_eq!(t, left.transpose(), Either::B(3));
+    kassert_eq!(t, left.left_map(|a| a + 1).get_left(), Some(4));
+    kassert_eq!(t, left.right_map(str::len).get_right(), None);
+    kassert_eq!(t, right.referenced().copied(), right);
+    kassert!(t, left != Either::B("abc"));
+
+    let same: Either<u32, u32> = Either::B(7);
+    kassert_eq!(t, same.get(), 7);
+}
+
+fn either_accessors(t: &mut KTestContext) {
+    let mut left: Either<u32, &str> = Either::new_left(3);
+    let right: Either<u32, &str> = Either::new_right("abc");
+
+    kassert!(t, left.is_left() && !left.is_right());
+    kassert_eq!(t, left.left(), Some(&3));
+    kassert_eq!(t, right.right(), Some(&"abc"));
+    kassert_eq!(t, right.left(), None);
+    if let Some(value) = left.left_mut() {
+        *value = 5;
+    }
+    kassert_eq!(t, left.map_left(|a| a * 2), Either::A(10));
+    kassert_eq!(t, right.map_right(str::len), Either::B(3));
+    kassert_eq!(t, right.either(|a| a as usize, str::len), 3);
+    kassert_eq!(t, left.as_ref().copied(), left);
+    kassert_eq!(t, right.try_into_right(), Ok("abc"));
+    kassert_eq!(t, Result::from(left), Ok(5));
+    kassert_eq!(t, Either::from(Err::<u32, &str>("abc")), right);
+
+    type Numbers = Either<core::ops::Range<u32>, core::iter::Once<u32>>;
+    let range: Numbers = Either::new_left(1..4);
+    let once: Numbers = Either::new_right(core::iter::once(9));
+    kassert_eq!(t, range.chain(once).collect::<Vec<_>>(), [1, 2, 3, 9]);
+}
+
+fn decimal_chars(t: &mut KTestContext) {
+    let chars = |s: &str| s.chars().collect::<Vec<char>>();
+    kassert_eq!(t, decimal_chars_to_u64(&chars("1234")), Some(1234));
+    kassert_eq!(t, decimal_chars_to_u64(&chars("007")), Some(7));
+    kassert_eq!(t, decimal_chars_to_u64(&chars("")), Some(0));
+    kassert_eq!(t, decimal_chars_to_u64(&chars("12a")), None);
+    kassert_eq!(t, decimal_chars_to_u64(&chars("-1")), None);
+    kassert_eq!(
+        t,
+        decimal_chars_to_u64(&chars("18446744073709551615")),
+        Some(u64::MAX)
+    );
+    kassert_eq!(
+        t,
+        decimal_chars_to_u64(&chars("18446744073709551616")),
+        None
+    );
+}
+
+fn permissions_layout(t: &mut KTestContext) {
+    let permissions = permissions!(Owner:Read, Owner:Write, Group:Read, Other:Execute);
+    kassert_eq!(t, permissions.to_u64(), 0o641);
+
+    let mode = Permissions::from_u64(0o750);
+    kassert!(t, mode.can(PermissionLevel::Owner, PermissionType::Write));
+    kassert!(t, mode.can(PermissionLevel::Group, PermissionType::Execute));
+    kassert!(t, !mode.can(PermissionLevel::Group, PermissionType::Write));
+    kassert!(t, !mode.can(PermissionLevel::Other, PermissionType::Read));
+}
+
+fn file_stat(permissions: u64, owner_id: u64, group_id: u64, is_directory: bool) -> FileStat {
+    FileStat {
+        size: 0,
+        created_at: 0,
+        modified_at: 0,
+        permissions,
+        is_file: !is_directory,
+        is_directory,
+        is_symlink: false,
+        owner_id,
+        group_id,
+        flags: 0,
+    }
+}
+
+fn credentials_checks(t: &mut KTestContext) {
+    let stat = file_stat(0o640, 1000, 100, false);
+
+    let owner = ProcessAccess::new(1000, 1000, Vec::new());
+    kassert!(t, owner.credentials().can(&stat, PermissionType::Write));
+    kassert!(t, !owner.credentials().can(&stat, PermissionType::Execute));
+    kassert!(
+        t,
+        owner
+            .credentials()
+            .can_open(&stat, OPEN_MODE_READ | OPEN_MODE_WRITE)
+    );
+
+    let member = ProcessAccess::new(1001, 1001, alloc::vec![100]);
+    kassert!(t, member.credentials().can_open(&stat, OPEN_MODE_READ));
+    kassert!(t, !member.credentials().can_open(&stat, OPEN_MODE_WRITE));
+
+    let other = ProcessAccess::new(1002, 1002, Vec::new());
+    kassert!(t, !other.credentials().can(&stat, PermissionType::Read));
+
+    let root = ProcessAccess::new(0, 0, Vec::new());
+    kassert!(t, root.credentials().can(&stat, PermissionType::Write));
+    kassert!(t, !root.credentials().can(&stat, PermissionType::Execute));
+    kassert!(t, Credentials::Kernel.can(&stat, PermissionType::Execute));
+}
+
+fn credentials_sticky_delete(t: &mut KTestContext) {
+    let tmp = file_stat(0o1777, 0, 0, true);
+    let file = file_stat(0o644, 1000, 1000, false);
+
+    let owner = ProcessAccess::new(1000, 1000, Vec::new());
+    let other = ProcessAccess::new(1001, 1001, Vec::new());
+    kassert!(t, owner.credentials().can_delete(&tmp, &file));
+    kassert!(t, !other.credentials().can_delete(&tmp, &file));
+    kassert!(t, other.credentials().can_create_in(&tmp));
+
+    let shared = file_stat(0o777, 0, 0, true);
+    kassert!(t, other.credentials().can_delete(&shared, &file));
+
+    let private = file_stat(0o755, 1000, 1000, true);
+    kassert!(t, !other.credentials().can_create_in(&private));
+}
+
+fn credentials_attribute_changes(t: &mut KTestContext) {
+    let stat = file_stat(0o644, 1000, 1000, false);
+
+    let owner = ProcessAccess::new(1000, 1000, alloc::vec![100]);
+    let other = ProcessAccess::new(1001, 1001, Vec::new());
+    let root = ProcessAccess::new(0, 0, Vec::new());
+
+    kassert!(t, owner.credentials().can_change_mode(&stat));
+    kassert!(t, !other.credentials().can_change_mode(&stat));
+
+    kassert!(
+        t,
+        owner.credentials().can_change_owner(&stat, None, Some(100))
+    );
+    kassert!(
+        t,
+        !owner.credentials().can_change_owner(&stat, None, Some(200))
+    );
+    kassert!(
+        t,
+        !owner
+            .credentials()
+            .can_change_owner(&stat, Some(1001), None)
+    );
+    kassert!(
+        t,
+        owner
+            .credentials()
+            .can_change_owner(&stat, Some(1000), None)
+    );
+    kassert!(
+        t,
+        root.credentials()
+            .can_change_owner(&stat, Some(1001), Some(200))
+    );
+
+    kassert!(t, !other.credentials().can_set_times(&stat, false));
+    let shared = file_stat(0o666, 1000, 1000, false);
+    kassert!(t, other.credentials().can_set_times(&shared, false));
+    kassert!(t, !other.credentials().can_set_times(&shared, true));
+}
+
+fn resource_limit_rules(t: &mut KTestContext) {
+    let settings = SchedulerThreadSettings {
+        default_user_stack_pages: 1,
+        default_kernel_stack_pages: 1,
+        max_user_stack_pages: 256,
+        max_kernel_stack_pages: 32,
+    };
+    let mut limits = ResourceLimits::new(&settings);
+    kassert_eq!(t, limits.soft(Resource::Stack), 256 * 4096);
+    kassert_eq!(t, limits.soft(Resource::AddressSpace), RLIM_INFINITY);
+    kassert_eq!(t, Resource::from_linux(7), Some(Resource::NoFile));
+    kassert_eq!(t, Resource::from_linux(4), None);
+
+    let nofile = limits.get(Resource::NoFile);
+    kassert_eq!(
+        t,
+        limits.set(Resource::NoFile, ResourceLimit::new(64, 32), false),
+        Err(LimitError::InvalidLimit)
+    );
+    kassert_eq!(
+        t,
+        limits.set(
+            Resource::NoFile,
+            ResourceLimit::new(nofile.hard, nofile.hard),
+            false
+        ),
+        Ok(())
+    );
+    kassert_eq!(
+        t,
+        limits.set(Resource::NoFile, ResourceLimit::new(16, 32), false),
+        Ok(())
+    );
+    // Lowered hard limits only go back up for root, and never past the file table
+    kassert_eq!(
+        t,
+        limits.set(Resource::NoFile, ResourceLimit::new(16, 64), false),
+        Err(LimitError::NotPermitted)
+    );
+    kassert_eq!(
+        t,
+        limits.set(Resource::NoFile, ResourceLimit::new(16, 64), true),
+        Ok(())
+    );
+    kassert_eq!(
+        t,
+        limits.set(
+            Resource::NoFile,
+            ResourceLimit::new(16, RLIM_INFINITY),
+            true
+        ),
+        Err(LimitError::NotPermitted)
+    );
+    kassert_eq!(t, limits.get(Resource::NoFile), ResourceLimit::new(16, 64));
+}
+
+fn push_struct<T: Copy>(out: &mut Vec<u8>, value: T) {
+    let bytes =
+        unsafe { core::slice::from_raw_parts(&value as *const T as *const u8, size_of::<T>()) };
+    out.extend_from_slice(bytes);
+}
+
+/// An x86_64 ELF with the program headers right after the header, then `payload`
+pub(super) fn build_elf(elf_type: u16, segments: &[(u64, u64, u64)], payload: &[u8]) -> Vec<u8> {
+    let payload_offset =
+        (size_of::<Elf64HeaderRaw>() + segments.len() * size_of::<Elf64ProgramHeaderRaw>()) as u64;
+    let mut out = Vec::new();
+    push_struct(
+        &mut out,
+        Elf64HeaderRaw {
+            magic: ELF_MAGIC,
+            bits: 2,
+            endianness: 1,
+            header_version: 1,
+            elf_type,
+            instruction_set: 62,
+            elf_version: 1,
+            entry_offset: 0x1000,
+            program_header_table_offset: size_of::<Elf64HeaderRaw>() as u64,
+            header_size: size_of::<Elf64HeaderRaw>() as u16,
+            program_header_entry_size: size_of::<Elf64ProgramHeaderRaw>() as u16,
+            program_header_entry_count: segments.len() as u16,
+            ..Default::default()
+        },
+    );
+    for &(vaddr, filesz, memsz) in segments {
+        push_struct(
+            &mut out,
+            Elf64ProgramHeaderRaw {
+                segment_type: 1,
+                flags: 7,
+                p_offset: payload_offset,
+                p_vaddr: vaddr,
+                p_paddr: vaddr,
+                p_filesz: filesz,
+                p_memsz: memsz,
+                align: 0,
+            },
+        );
+    }
+    out.extend_from_slice(payload);
+    out
+}
+
+fn load_error(elf: Vec<u8>) -> Option<ElfError> {
+    Elf64File::from_bytes(elf.into_boxed_slice())
+        .and_then(|elf| elf.load_image(0))
+        .err()
+}
+
+fn path_normalize(t: &mut KTestContext) {
+    let normalize = |path: &str| VfsPath::from(path).normalize();
+    kassert_eq!(t, normalize("/a/./b//../c/"), VfsPath::from("/a/c"));
+    kassert_eq!(t, normalize("/../.."), VfsPath::from("/"));
+    kassert_eq!(t, normalize("../a/../../b"), VfsPath::from("../../b"));
+    kassert_eq!(t, normalize("./"), VfsPath::new());
+
+    let base = VfsPath::from("/usr/");
+    kassert_eq!(
+        t,
+        base.join(&VfsPath::from("lib//x")),
+        VfsPath::from("/usr/lib/x")
+    );
+    kassert_eq!(t, base.join(&VfsPath::from("/etc")), VfsPath::from("/etc"));
+    kassert_eq!(
+        t,
+        VfsPath::new().join(&VfsPath::from("a")),
+        VfsPath::from("a")
+    );
+}
+
+fn path_parent_and_name(t: &mut KTestContext) {
+    let path = VfsPath::from("/a/bc//");
+    kassert_eq!(t, path.file_name().map(|n| n.as_bytes()), Some(&b"bc"[..]));
+    kassert_eq!(t, path.parent(), Some(VfsPath::from("/a")));
+    kassert_eq!(t, VfsPath::from("/a").parent(), Some(VfsPath::from("/")));
+    kassert_eq!(t, VfsPath::from("a").parent(), Some(VfsPath::new()));
+    kassert_eq!(t, VfsPath::from("//").parent(), None);
+    kassert!(t, VfsPath::from("//").file_name().is_none());
+
+    kassert!(t, VfsName::new(b"").is_none());
+    kassert!(t, VfsName::new(b"a/b").is_none());
+    kassert!(t, VfsName::new(b"a\0b").is_none());
+    kassert!(t, VfsName::new(b"..").is_some_and(|n| n.is_dot_dot()));
+}
+
+fn path_bytes_roundtrip(t: &mut KTestContext) {
+    // Latin-1 and UTF-8 bytes, as stored in an ext2 directory
+    let bytes = b"/caf\xe9/\xc3\xa9t\xc3\xa9\xff".to_vec();
+    let path = VfsPath::from_bytes(bytes.clone());
+    kassert_eq!(t, VfsPath::from(&path.to_chars()).as_bytes(), &bytes[..]);
+
+    let name = path.file_name().unwrap();
+    kassert_eq!(t, name.as_bytes(), b"\xc3\xa9t\xc3\xa9\xff");
+    kassert_eq!(
+        t,
+        VfsPath::from(&name.to_chars()).as_bytes(),
+        name.as_bytes()
+    );
+
+    // Chars that aren't bytes are stored as UTF-8
+    let chars = ['/', 'a', '\u{20ac}'];
+    kassert_eq!(
+        t,
+        VfsPath::from(&chars[..]).as_bytes(),
+        "/a\u{20ac}".as_bytes()
+    );
+}
+
+/// Walking a path and looking its names up in a map keyed by paths doesn't allocate
+fn path_lookup_allocations(t: &mut KTestContext) {
+    let mut tree = BTreeMap::new();
+    tree.insert(VfsPath::from("dev"), 1);
+    tree.insert(VfsPath::from("disk"), 2);
+    let path = VfsPath::from("/dev//disk/missing/");
+
+    let before = get_memory_stats().heap_allocations;
+    let found = path
+        .components()
+        .filter_map(|name| tree.get(name.as_bytes()))
+        .sum::<i32>();
+    let last = path.components().next_back().map(|n| n.is_dot());
+    let allocations = get_memory_stats().heap_allocations - before;
+
+    kassert_eq!(t, found, 3);
+    kassert_eq!(t, last, Some(false));
+    kassert_eq!(t, allocations, 0);
+}
+
+/// RFC 8439 vectors, its 32 bit counter and 96 bit nonce map to the 64 bit counter and the last
+/// 8 bytes of the nonce here
+fn chacha20_test_vectors(t: &mut KTestContext) {
+    let hex = |s: &str| {
+        s.as_bytes()
+            .chunks(2)
+            .map(|pair| u8::from_str_radix(core::str::from_utf8(pair).unwrap(), 16).unwrap())
+            .collect::<Vec<u8>>()
+    };
+    let mut key = [0u8; 32];
+    for (i, byte) in key.iter_mut().enumerate() {
+        *byte = i as u8;
+    }
+
+    // 2.3.2, the first nonce word is the high half of the counter
+    let mut block = [0u8; CHACHA_BLOCK_SIZE];
+    chacha20_block(
+        &key,
+        &[0, 0, 0, 0x4a, 0, 0, 0, 0],
+        0x0900_0000_0000_0001,
+        &mut block,
+    );
+    let expected = hex(concat!(
+        "10f1e7e4d13b5915500fdd1fa32071c4c7d1f4c733c068030422aa9ac3d46c4e",
+        "d2826446079faa0914c2d705d98b02a2b5129cd1de164eb9cbd083e8a2503c4e",
+    ));
+    kassert_eq!(t, block[..], expected[..]);
+
+    // 2.4.2, two blocks of keystream from counter 1
+    let plaintext = b"Ladies and Gentlemen of the class of '99: If I could offer you only one \
+        tip for the future, sunscreen would be it.";
+    let nonce = [0, 0, 0, 0x4a, 0, 0, 0, 0];
+    let mut keystream = [0u8; 2 * CHACHA_BLOCK_SIZE];
+    for (counter, chunk) in (1..).zip(keystream.as_chunks_mut::<CHACHA_BLOCK_SIZE>().0) {
+        chacha20_block(&key, &nonce, counter, chunk);
+    }
+    let ciphertext = plaintext
+        .iter()
+        .zip(keystream)
+        .map(|(byte, key)| byte ^ key)
+        .collect::<Vec<u8>>();
+    let expected = hex(concat!(
+        "6e2e359a2568f98041ba0728dd0d6981e97e7aec1d4360c20a27afccfd9fae0b",
+        "f91b65c5524733ab8f593dabcd62b3571639d624e65152ab8f530c359f0861d8",
+        "07ca0dbf500d6a6156a38e088a22b65e52bc514d16ccf806818ce91ab7793736",
+        "5af90bbf74a35be6b40b8eedf2785e42874d",
+    ));
+    kassert_eq!(t, ciphertext, expected);
+
+    // The generator starts at counter 0 and follows the blocks in order
+    let mut rng = ChaCha20Rng::new(key, nonce);
+    let mut output = [0u8; 2 * CHACHA_BLOCK_SIZE];
+    rng.fill_bytes(&mut output);
+    let mut first = [0u8; CHACHA_BLOCK_SIZE];
+    chacha20_block(&key, &nonce, 0, &mut first);
+    kassert_eq!(t, output[..CHACHA_BLOCK_SIZE], first[..]);
+    kassert_eq!(
+        t,
+        output[CHACHA_BLOCK_SIZE..],
+        keystream[..CHACHA_BLOCK_SIZE]
+    );
+}
+
+fn pci_bar_decoding(t: &mut KTestContext) {
+    kassert_eq!(
+        t,
+        PciBar::decode(0xC001, 0xFFFF_FFE1, 0, 0),
+        Some(PciBar::Io {
+            port: 0xC000,
+            size: 0x20
+        })
+    );
+    // Only 64K of ports decoded
+    kassert_eq!(
+        t,
+        PciBar::decode(0xC001, 0xFFF1, 0, 0),
+        Some(PciBar::Io {
+            port: 0xC000,
+            size: 0x10
+        })
+    );
+    kassert_eq!(
+        t,
+        PciBar::decode(0xFEBF_0000, 0xFFFF_0000, 0, 0),
+        Some(PciBar::Memory {
+            address: 0xFEBF_0000,
+            size: 0x1_0000,
+            prefetchable: false,
+            is_64bit: false
+        })
+    );
+    kassert_eq!(
+        t,
+        PciBar::decode(0xE000_000C, 0xF000_000C, 0x1, 0xFFFF_FFFF),
+        Some(PciBar::Memory {
+            address: 0x1_E000_0000,
+            size: 0x1000_0000,
+            prefetchable: true,
+            is_64bit: true
+        })
+    );
+    kassert_eq!(t, PciBar::decode(0, 0, 0, 0), None);
+}
+
+fn console_escape_sequences(t: &mut KTestContext) {
+    let mut console = Console::new(10, 4, 0);
+    // Split across writes, bold red on blue
+    console.write(b"\x1b[1;3");
+    console.write(b"1;44mA\x1b");
+    console.write(b"[0mB");
+    kassert_eq!(t, console.cell(0, 0), Cell::new('A', 0x1C));
+    kassert_eq!(t, console.cell(1, 0), Cell::new('B', 0x07));
+
+    console.write(b"\x1b[3;5Hx");
+    kassert_eq!(t, console.cell(4, 2).ch, 'x');
+    kassert_eq!(t, console.cursor(), (5, 2));
+    console.write(b"\x1b[2A\x1b[3D\x1b[B\x1b[10C");
+    kassert_eq!(t, console.cursor(), (9, 1));
+
+    console.write(b"\x1b[s\x1b[H\x1b[u");
+    kassert_eq!(t, console.cursor(), (9, 1));
+
+    // Erase to the end of the line, then the whole screen before the cursor
+    console.write(b"\x1b[3;3H\x1b[K");
+    kassert_eq!(t, console.cell(4, 2).ch, ' ');
+    kassert_eq!(t, console.cell(0, 0).ch, 'A');
+    console.write(b"\x1b[1J");
+    kassert_eq!(t, console.cell(1, 0).ch, ' ');
+}
+
+fn console_scrollback(t: &mut KTestContext) {
+    let mut console = Console::new(4, 2, 2);
+    for line in [
+        b"l0\r\n", b"l1\r\n", b"l2\r\n", b"l3\r\n", b"l4\r\n", b"l5\r\n",
+    ] {
+        console.write(line);
+    }
+    // 2 pages of 2 lines, the oldest one was dropped
+    kassert_eq!(t, console.scrollback_len(), 4);
+    kassert_eq!(t, console.cell(0, 0).ch, 'l');
+    kassert_eq!(t, console.cell(1, 0).ch, '5');
+
+    console.control(ConsoleControl::ScrollUp);
+    kassert_eq!(t, console.visible_cell(1, 0).ch, '3');
+    console.control(ConsoleControl::ScrollUp);
+    console.control(ConsoleControl::ScrollUp);
+    kassert_eq!(t, console.view_offset(), 4);
+    kassert_eq!(t, console.visible_cell(1, 0).ch, '1');
+
+    console.control(ConsoleControl::ScrollDown);
+    kassert_eq!(t, console.view_offset(), 2);
+    console.write(b"x");
+    kassert_eq!(t, console.view_offset(), 0);
+    kassert_eq!(t, console.visible_cell(0, 1).ch, 'x');
+}
+
+fn console_utf8(t: &mut KTestContext) {
+    let mut console = Console::new(10, 2, 0);
+    let text = "é←😀".as_bytes();
+    // The arrow is split across writes
+    console.write(&text[..3]);
+    console.write(&text[3..]);
+    kassert_eq!(t, console.cell(0, 0).ch, 'é');
+    kassert_eq!(t, console.cell(1, 0).ch, '←');
+    kassert_eq!(t, console.cell(2, 0).ch, '😀');
+    kassert_eq!(t, console.cursor(), (3, 0));
+    kassert_eq!(t, cp437_glyph('é'), 0x82);
+    kassert_eq!(t, cp437_glyph('←'), 0x1B);
+    kassert_eq!(t, cp437_glyph('😀'), REPLACEMENT_GLYPH);
+
+    // A stray continuation byte, a sequence cut short by a character and an overlong encoding
+    console.write(b"\r\x80a\xc3b\xe0\x80");
+    let cells = (0..5).map(|x| console.cell(x, 0).ch).collect::<Vec<_>>();
+    kassert_eq!(t, cells, ['\u{FFFD}', 'a', '\u{FFFD}', 'b', '\u{FFFD}']);
+}
+
+const KEYMAP_FR: &str = "# azerty row
+key 0x10 a A æ
+key 0x12 e E €
+key 0x1a dead:^ dead:¨
+key 0x39 U+0020
+compose ^ e ê
+compose ¨ e ë
+";
+
+fn keymap_parse_and_compose(t: &mut KTestContext) {
+    let keymap = match Keymap::parse(KEYMAP_FR) {
+        Ok(keymap) => keymap,
+        Err(err) => {
+            kassert!(t, false, "parse failed: {:?}", err);
+            return;
+        }
+    };
+    let shift = KeyModifiers::from(KeyModifier::LeftShift);
+    kassert_eq!(
+        t,
+        keymap.symbol(0x12, KeymapLevel::from_modifiers(shift)),
+        Some(KeySymbol::Char('E'))
+    );
+    kassert_eq!(
+        t,
+        keymap.symbol(0x12, KeymapLevel::AltGr),
+        Some(KeySymbol::Char('€'))
+    );
+    kassert_eq!(
+        t,
+        keymap.symbol(0x39, KeymapLevel::Shift),
+        Some(KeySymbol::Char(' '))
+    );
+    kassert_eq!(t, keymap.symbol(0x11, KeymapLevel::Plain), None);
+
+    let mut composer = Composer::default();
+    let mut typed = alloc::string::String::new();
+    for symbol in [
+        KeySymbol::Dead('^'),
+        KeySymbol::Char('e'),
+        KeySymbol::Dead('¨'),
+        KeySymbol::Char('a'),
+        KeySymbol::Dead('^'),
+        KeySymbol::Dead('^'),
+        KeySymbol::Char('€'),
+    ] {
+        typed.push_str(composer.feed(Some(&keymap), symbol).as_str());
+    }
+    kassert_eq!(t, typed.as_str(), "ê¨a^€");
+    kassert!(t, !composer.is_pending());
+    kassert_eq!(
+        t,
+        composer.feed(None, KeySymbol::Char('€')).as_bytes(),
+        [0xe2, 0x82, 0xac]
+    );
+
+    for (text, line, reason) in [
+        (
+            "key 0x10 a\nkey 0x10 b",
+            2,
+            KeymapSyntaxError::DuplicateScancode,
+        ),
+        ("key 0x80 a", 1, KeymapSyntaxError::BadScancode),
+        ("key 0x10 ab", 1, KeymapSyntaxError::BadSymbol),
+        ("key 0x10 a b c d", 1, KeymapSyntaxError::SymbolCount),
+        (
+            "key 0x10 a\ncompose ^ e ê",
+            2,
+            KeymapSyntaxError::NotDeadKey,
+        ),
+        ("keys 0x10 a", 1, KeymapSyntaxError::UnknownDirective),
+    ] {
+        let result = Keymap::parse(text);
+        kassert!(
+            t,
+            matches!(result, Err(KeymapError::Syntax { line: l, reason: r }) if l == line && r == reason),
+            "{:?}: {:?}",
+            text,
+            result
+        );
+    }
+    kassert!(
+        t,
+        matches!(Keymap::parse("# nothing"), Err(KeymapError::Empty))
+    );
+}
+
+fn tty_line_discipline(t: &mut KTestContext) {
+    let termios = Termios::default();
+    let mut discipline = LineDiscipline::new();
+    let mut echo = Vec::new();
+    let mut buf = [0; 16];
+    for byte in b"lx\x7fs\r" {
+        kassert_eq!(t, discipline.receive(&termios, *byte, &mut echo), None);
+    }
+    kassert_eq!(t, echo, b"lx\x08 \x08s\n");
+    kassert_eq!(t, discipline.read(&termios, &mut buf), Some(3));
+    kassert_eq!(t, &buf[..3], b"ls\n");
+    kassert_eq!(t, discipline.read(&termios, &mut buf), None);
+
+    // ^C drops the line being edited
+    echo.clear();
+    discipline.receive(&termios, b'a', &mut echo);
+    kassert_eq!(
+        t,
+        discipline.receive(&termios, 0x03, &mut echo),
+        Some(TtySignal::Interrupt)
+    );
+    kassert_eq!(t, echo, b"a^C");
+    kassert!(t, !discipline.is_readable(&termios));
+
+    // ^D on an empty line is an end of file
+    discipline.receive(&termios, 0x04, &mut echo);
+    kassert_eq!(t, discipline.read(&termios, &mut buf), Some(0));
+
+    let mut raw = termios;
+    raw.lflag &= !ICANON;
+    discipline.receive(&termios, b'x', &mut echo);
+    discipline.set_termios(&termios, &raw);
+    kassert_eq!(t, discipline.read(&raw, &mut buf), Some(1));
+
+    let mut out = VecDeque::new();
+    kassert_eq!(t, process_output(&termios, b"a\nb", &mut out, 3), 2);
+    kassert_eq!(t, out, b"a\r\n");
+}
+
+fn pty_pair(t: &mut KTestContext) {
+    let pty = Pty::new(7);
+    kassert!(t, pty.open_slave().is_err(), "slave opened while locked");
+    let mut arg = [0; TERMIOS_SIZE];
+    kassert!(t, matches!(pty.ioctl(true, TIOCGPTN, &mut arg), Ok(0)));
+    kassert_eq!(t, arg[..4], 7u32.to_ne_bytes());
+    kassert!(t, pty.ioctl(false, TIOCGPTN, &mut arg).is_err());
+    kassert!(t, pty.ioctl(true, TIOCSPTLCK, &mut [0; 4]).is_ok());
+    kassert!(t, pty.open_slave().is_ok());
+
+    let mut buf = [0; 16];
+    kassert!(t, matches!(pty.master_write(b"hi\r"), Ok(3)));
+    kassert!(t, matches!(pty.master_read(&mut buf), Ok(4)));
+    kassert_eq!(t, &buf[..4], b"hi\r\n");
+    kassert!(t, matches!(pty.slave_read(&mut buf), Ok(3)));
+    kassert_eq!(t, &buf[..3], b"hi\n");
+    kassert!(
+        t,
+        matches!(pty.slave_read(&mut buf), Err(VfsError::WouldBlock))
+    );
+
+    // Turning echo and canonical mode off through the termios ioctls
+    kassert!(t, matches!(pty.ioctl(false, TCGETS, &mut arg), Ok(0)));
+    let mut termios = Termios::from_bytes(&arg).unwrap();
+    termios.lflag = 0;
+    arg = termios.to_bytes();
+    kassert!(t, matches!(pty.ioctl(false, TCSETS, &mut arg), Ok(0)));
+    kassert!(t, matches!(pty.master_write(b"q"), Ok(1)));
+    kassert!(
+        t,
+        matches!(pty.master_read(&mut buf), Err(VfsError::WouldBlock))
+    );
+    kassert!(t, matches!(pty.slave_read(&mut buf), Ok(1)));
+
+    pty.close_master();
+    kassert!(t, matches!(pty.slave_read(&mut buf), Ok(0)));
+    kassert!(t, matches!(pty.slave_write(b"x"), Err(VfsError::HungUp)));
+    pty.close_slave();
+}
+
+fn timer_wheel(t: &mut KTestContext) {
+    let count = |events: &[TimerEvent], pid: u32| {
+        events
+            .iter()
+            .filter(|event| matches!(event, TimerEvent::Alarm(p) if *p == pid))
+            .count()
+    };
+    let mut wheel = Box::new(TimerWheel::new(1000));
+    let once = wheel.add(1, 1010, 0, TimerEvent::Alarm(1));
+    let periodic = wheel.add(2, 1005, 100, TimerEvent::Alarm(2));
+    wheel.add(3, 71_000, 0, TimerEvent::Alarm(3));
+    let beyond_range = wheel.add(3, 20_001_000, 0, TimerEvent::Alarm(3));
+    kassert_eq!(t, wheel.len(), 4);
+
+    let events = wheel.advance(1009);
+    kassert_eq!(t, events.len(), 1);
+    kassert_eq!(t, count(&events, 2), 1);
+    kassert_eq!(t, count(&wheel.advance(1010), 1), 1);
+    kassert_eq!(t, wheel.get(once), None);
+    kassert_eq!(t, wheel.get(periodic), Some((1105, 100)));
+
+    // Cascades the level 2 timer down, the periodic one fires every 100 ticks on the way
+    let events = wheel.advance(71_000);
+    kassert_eq!(t, count(&events, 2), 699);
+    kassert_eq!(t, count(&events, 3), 1);
+    kassert_eq!(t, wheel.get(beyond_range), Some((20_001_000, 0)));
+    kassert_eq!(t, wheel.cancel(periodic), Some((71_005, 100)));
+
+    wheel.cancel_owner(3);
+    kassert!(t, wheel.is_empty());
+    kassert!(t, wheel.advance(20_001_000).is_empty());
+}
+
+fn futex_table(t: &mut KTestContext) {
+    // No such processes
+    let (pid, other_pid) = (u32::MAX - 1, u32::MAX - 2);
+    let before = futex_count();
+    let queue = LockedFutex::lock(pid, 0x1000).queue();
+    kassert!(
+        t,
+        Arc::ptr_eq(&queue, &LockedFutex::lock(pid, 0x1000).queue())
+    );
+    kassert!(
+        t,
+        !Arc::ptr_eq(&queue, &LockedFutex::lock(pid, 0x1004).queue())
+    );
+    kassert!(
+        t,
+        !Arc::ptr_eq(&queue, &LockedFutex::lock(other_pid, 0x1000).queue())
+    );
+    kassert_eq!(t, futex_count(), before + 3);
+
+    // Nobody waits, the queue is forgotten
+    kassert_eq!(t, LockedFutex::lock(pid, 0x1000).wake(1), 0);
+    kassert_eq!(t, futex_count(), before + 2);
+
+    release_process_futexes(pid);
+    kassert_eq!(t, futex_count(), before + 1);
+    release_process_futexes(other_pid);
+    kassert_eq!(t, futex_count(), before);
+}
+
+fn segment_bases(t: &mut KTestContext) {
+    // The kernel doesn't use fs
+    let saved = unsafe { FsBase::get() };
+    unsafe { FsBase::set(0x7fff_1234_5000) };
+    kassert_eq!(t, unsafe { FsBase::get() }, 0x7fff_1234_5000);
+    kassert_eq!(t, unsafe { rdmsr(IA32_FS_BASE) }, 0x7fff_1234_5000);
+    unsafe { FsBase::set(saved) };
+
+    kassert!(t, is_per_cpu_base(get_per_cpu() as *const _ as u64));
+    kassert!(t, is_per_cpu_base(unsafe { GsBase::get() }));
+    kassert!(t, !is_per_cpu_base(0x7fff_1234_5000));
+    kassert!(t, !is_per_cpu_base(unsafe { FsBase::get() }));
+}
+
+fn fpu_state_switching(t: &mut KTestContext) {
+    // Nothing else may use the registers during the test
+    flush_fpu_owner();
+    unsafe { Cr0::write(Cr0::read() & !CR0_TS) };
+    let write_xmm0 = |value: u64| unsafe { core::arch::asm!("movq xmm0, {}", in(reg) value) };
+    let read_xmm0 = || -> u64 {
+        let value;
+        unsafe { core::arch::asm!("movq {}, xmm0", out(reg) value) };
+        value
+    };
+
+    let mut first = FpuState::new();
+    let mut second = FpuState::new();
+    kassert_eq!(t, first.mxcsr(), 0x1F80);
+    kassert_eq!(t, first.as_bytes().len(), fpu_area_size());
+    kassert_eq!(t, first.as_bytes().as_ptr() as usize % 64, 0);
+    unsafe {
+        first.restore();
+        write_xmm0(0x1111_2222_3333_4444);
+        first.save();
+        second.restore();
+        kassert_eq!(t, read_xmm0(), 0);
+        write_xmm0(0x5555_6666_7777_8888);
+        second.save();
+
+        // Like two threads computing in turn
+        for _ in 0..3 {
+            first.restore();
+            kassert_eq!(t, read_xmm0(), 0x1111_2222_3333_4444);
+            second.restore();
+            kassert_eq!(t, read_xmm0(), 0x5555_6666_7777_8888);
+        }
+        first.clone().restore();
+        kassert_eq!(t, read_xmm0(), 0x1111_2222_3333_4444);
+        FpuState::new().restore();
+    }
+    unsafe { Cr0::write(Cr0::read() | CR0_TS) };
+}
+
+fn time_page_mapping(t: &mut KTestContext) {
+    let Some(time) = read_time_page() else {
+        kassert!(t, false, "time page not initialized");
+        return;
+    };
+    kassert_eq!(t, time.ns_per_tick, NS_PER_TICK);
+    kassert!(t, time.ticks <= get_uptime_ticks());
+    kassert!(t, get_uptime_ticks() - time.ticks <= 1);
+    kassert_eq!(
+        t,
+        time.realtime_ns() - time.monotonic_ns(),
+        time.boot_unix_ns
+    );
+
+    let Some(mut pt) = PageTable::alloc_new() else {
+        kassert!(t, false, "page table allocation failed");
+        return;
+    };
+    kassert!(t, map_time_page(&mut pt));
+    kassert_eq!(t, pt.translate(PROC_TIME_PAGE), time_page_phys());
+
+    // The aux vector follows argc, argv and envp
+    let args = [String::from("init")];
+    let aux = [
+        (AT_PAGESZ, AuxValue::Value(PAGE_SIZE as u64)),
+        (AT_TIME_PAGE, AuxValue::Value(PROC_TIME_PAGE)),
+    ];
+    let flags = PAGE_ACCESSED | PAGE_USER | PAGE_RW | PAGE_PRESENT;
+    let (_stack, rsp, _, _) = build_stack(PROC_USER_STACK_TOP, &mut pt, flags, &args, &[], &aux);
+    let mut read = |virt: u64| {
+        pt.translate(virt)
+            .map(|phys| unsafe { *((phys + DIRECT_MAPPING_OFFSET) as *const u64) })
+    };
+    let auxv = rsp + 4 * 8;
+    kassert_eq!(t, read(rsp), Some(1));
+    kassert_eq!(t, read(auxv), Some(AT_PAGESZ));
+    kassert_eq!(t, read(auxv + 8), Some(PAGE_SIZE as u64));
+    kassert_eq!(t, read(auxv + 16), Some(AT_TIME_PAGE));
+    kassert_eq!(t, read(auxv + 24), Some(PROC_TIME_PAGE));
+    kassert_eq!(t, read(auxv + 32), Some(AT_NULL));
+}
+
+fn ptrace_registers(t: &mut KTestContext) {
+    kassert_eq!(t, core::mem::size_of::<LinuxUserRegs>(), 27 * 8);
+    let interrupts = RFlag::InterruptFlag as u64;
+    let mut state = ThreadState {
+        gpregs: ThreadGPRegisters {
+            rax: 1,
+            rbx: 2,
+            rcx: 3,
+            rdx: 4,
+            rdi: 5,
+            rsi: 6,
+            r8: 8,
+            r9: 9,
+            r10: 10,
+            r11: 11,
+            r12: 12,
+            r13: 13,
+            r14: 14,
+            r15: 15,
+        },
+        rip: 0x40_1000,
+        rsp: 0x7fff_0000,
+        rbp: 0x7fff_0100,
+        rflags: interrupts | 0x2,
+        fs_base: 0x50_0000,
+        gs_base: 0,
+    };
+    let mut regs = LinuxUserRegs::from_state(&state);
+    kassert_eq!(t, (regs.rax, regs.rdi, regs.r15), (1, 5, 15));
+    kassert_eq!(
+        t,
+        (regs.rip, regs.rsp, regs.rbp),
+        (0x40_1000, 0x7fff_0000, 0x7fff_0100)
+    );
+    kassert_eq!(t, regs.cs & 3, 3);
+    kassert_eq!(t, regs.fs_base, 0x50_0000);
+
+    // A tracer can't clear IF, only set the arithmetic flags and TF
+    regs.rip = 0x40_2000;
+    regs.rsi = 0x66;
+    regs.eflags = RFlag::TrapFlag as u64 | RFlag::ZeroFlag as u64;
+    kassert!(t, regs.apply(&mut state).is_some());
+    kassert_eq!(t, state.rip, 0x40_2000);
+    kassert_eq!(t, state.gpregs.rsi, 0x66);
+    kassert_eq!(
+        t,
+        state.rflags,
+        interrupts | 0x2 | RFlag::TrapFlag as u64 | RFlag::ZeroFlag as u64
+    );
+
+    regs.rip = DIRECT_MAPPING_OFFSET;
+    kassert!(t, regs.apply(&mut state).is_none());
+    kassert_eq!(t, state.rip, 0x40_2000);
+
+    let Some(mut pt) = PageTable::alloc_new() else {
+        kassert!(t, false, "page table allocation failed");
+        return;
+    };
+    kassert!(t, map_time_page(&mut pt));
+    let flags = pt.page_flags(PROC_TIME_PAGE + 8);
+    kassert!(
+        t,
+        flags.is_some_and(|flags| flags & PAGE_USER != 0 && flags & PAGE_RW == 0)
+    );
+    kassert_eq!(t, pt.page_flags(PROC_TIME_PAGE + PAGE_SIZE as u64), None);
+}
+
+fn elf_segment_validation(t: &mut KTestContext) {
+    let elf = build_elf(2, &[(0x40_0000, 4, 0x2000)], b"code");
+    match Elf64File::from_bytes(elf.into_boxed_slice()).and_then(|elf| elf.load_image(0)) {
+        Ok(image) => {
+            kassert_eq!(t, image.pages.len(), 2);
+            kassert_eq!(t, image.ranges(), [(0x40_0000, 0x40_2000)]);
+            kassert_eq!(t, &image.pages[&0x40_0000][..5], b"code\0");
+        }
+        Err(e) => kassert!(t, false, "valid elf rejected: {:?}", e),
+    }
+
+    let outside = |reason: &Option<ElfError>| {
+        matches!(
+            reason,
+            Some(ElfError::InvalidSegment {
+                reason: InvalidSegmentReason::OutsideUserSpace,
+                ..
+            })
+        )
+    };
+    kassert!(
+        t,
+        outside(&load_error(build_elf(
+            2,
+            &[(0xFFFF_8000_0000_0000, 0, 0x1000)],
+            &[]
+        )))
+    );
+    kassert!(
+        t,
+        outside(&load_error(build_elf(2, &[(0x40_0000, 0, u64::MAX)], &[])))
+    );
+    kassert!(
+        t,
+        outside(&load_error(build_elf(2, &[(0, 0, 0x1000)], &[])))
+    );
+    kassert!(
+        t,
+        matches!(
+            load_error(build_elf(2, &[(0x40_0000, 4, 2)], b"code")),
+            Some(ElfError::InvalidSegment {
+                reason: InvalidSegmentReason::FileSizeAboveMemorySize,
+                ..
+            })
+        )
+    );
+    kassert!(
+        t,
+        matches!(
+            load_error(build_elf(
+                2,
+                &[(0x40_0000, 0, 0x2000), (0x40_1000, 0, 0x1000)],
+                &[]
+            )),
+            Some(ElfError::InvalidSegment {
+                index: 1,
+                reason: InvalidSegmentReason::Overlapping(0),
+            })
+        )
+    );
+    kassert!(
+        t,
+        matches!(
+            load_error(build_elf(2, &[(0x40_0000, 0, 0x1_0000_0000)], &[])),
+            Some(ElfError::ImageTooLarge { .. })
+        )
+    );
+    // The program header table is checked against the file before it is read
+    let mut truncated = build_elf(2, &[(0x40_0000, 0, 0x1000)], &[]);
+    truncated.truncate(truncated.len() - 1);
+    kassert!(
+        t,
+        matches!(
+            load_error(truncated),
+            Some(ElfError::InvalidElfFile(
+                InvalidElfFileReason::InvalidField("program headers")
+            ))
+        )
+    );
+}
+
+fn elf_aux_vector(t: &mut KTestContext) {
+    let phdr = size_of::<Elf64HeaderRaw>();
+    let phent = size_of::<Elf64ProgramHeaderRaw>();
+    let aux_of = |elf: Vec<u8>| {
+        Elf64File::from_bytes(elf.into_boxed_slice())
+            .and_then(|elf| elf.load_image(0))
+            .map(|image| image.aux)
+    };
+
+    // Headers outside of the segments are copied on the stack
+    let elf = build_elf(2, &[(0x40_0000, 4, 0x1000)], b"code");
+    let headers = elf[phdr..phdr + phent].to_vec();
+    match aux_of(elf) {
+        Ok(aux) => kassert_eq!(
+            t,
+            aux,
+            [
+                (AT_PHDR, AuxValue::Bytes(headers)),
+                (AT_PHENT, AuxValue::Value(phent as u64)),
+                (AT_PHNUM, AuxValue::Value(1)),
+            ]
+        ),
+        Err(e) => kassert!(t, false, "valid elf rejected: {:?}", e),
+    }
+
+    // A segment loading the start of the file maps them
+    let mut elf = build_elf(2, &[(0x40_0000, 0, 0x1000)], b"code");
+    let mut header =
+        unsafe { core::ptr::read_unaligned(elf[phdr..].as_ptr() as *const Elf64ProgramHeaderRaw) };
+    header.p_offset = 0;
+    header.p_filesz = elf.len() as u64;
+    unsafe {
+        core::ptr::write_unaligned(
+            elf[phdr..].as_mut_ptr() as *mut Elf64ProgramHeaderRaw,
+            header,
+        )
+    };
+    match aux_of(elf) {
+        Ok(aux) => kassert_eq!(
+            t,
+            aux[0],
+            (AT_PHDR, AuxValue::Value(0x40_0000 + phdr as u64))
+        ),
+        Err(e) => kassert!(t, false, "valid elf rejected: {:?}", e),
+    }
+
+    let Some(mut pt) = PageTable::alloc_new() else {
+        kassert!(t, false, "page table allocation failed");
+        return;
+    };
+    let random: Vec<u8> = (1..=16).collect();
+    let aux = [
+        (AT_RANDOM, AuxValue::Bytes(random.clone())),
+        (AT_EXECFN, AuxValue::Bytes(b"/bin/init\0".to_vec())),
+    ];
+    let flags = PAGE_ACCESSED | PAGE_USER | PAGE_RW | PAGE_PRESENT;
+    let args = [String::from("init")];
+    let env = [String::from("HOME=/")];
+    let (_stack, rsp, _, _) = build_stack(PROC_USER_STACK_TOP, &mut pt, flags, &args, &env, &aux);
+    let mut read = |virt: u64, len: usize| -> Option<Vec<u8>> {
+        (virt..virt + len as u64)
+            .map(|virt| {
+                pt.translate(virt)
+                    .map(|phys| unsafe { *((phys + DIRECT_MAPPING_OFFSET) as *const u8) })
+            })
+            .collect()
+    };
+
+    // argc, argv, null, envp, null
+    let auxv = rsp + 5 * 8;
+    let entries = read(auxv, 5 * 8).unwrap_or_default();
+    let entry = |idx: usize| u64::from_le_bytes(entries[idx * 8..idx * 8 + 8].try_into().unwrap());
+    kassert_eq!(t, entries.len(), 40);
+    if entries.len() != 40 {
+        return;
+    }
+    kassert_eq!(t, entry(0), AT_RANDOM);
+    kassert_eq!(t, entry(2), AT_EXECFN);
+    kassert_eq!(t, entry(4), AT_NULL);
+    let (random_ptr, execfn_ptr) = (entry(1), entry(3));
+    kassert_eq!(t, random_ptr % 16, 0);
+    kassert!(
+        t,
+        execfn_ptr < PROC_USER_STACK_TOP && random_ptr < PROC_USER_STACK_TOP
+    );
+    kassert_eq!(t, read(random_ptr, 16), Some(random));
+    kassert_eq!(
+        t,
+        read(execfn_ptr, 10).as_deref(),
+        Some(&b"/bin/init\0"[..])
+    );
+}
+
+fn elf_pie_relocation(t: &mut KTestContext) {
+    // Segment data, then .rela.dyn, .shstrtab and the section headers
+    let names = b"\0.rela.dyn\0.shstrtab\0";
+    let mut payload = Vec::new();
+    payload.extend_from_slice(&[0; 16]);
+    let rela_offset = payload.len();
+    push_struct(
+        &mut payload,
+        Elf64RelaRaw {
+            r_offset: 0x1008,
+            r_info: R_X86_64_RELATIVE as u64,
+            r_addend: 0x1234,
+        },
+    );
+    let names_offset = payload.len();
+    payload.extend_from_slice(names);
+    let sections_offset = payload.len();
+
+    let mut elf = build_elf(3, &[(0x1000, 16, 16)], &[]);
+    let base_offset = elf.len();
+    elf.extend_from_slice(&payload);
+    let section = |sh_name, sh_type, offset: usize, size: usize, entsize| Elf64SectionHeaderRaw {
+        sh_name,
+        sh_type,
+        sh_flags: 0,
+        sh_addr: 0,
+        sh_offset: (base_offset + offset) as u64,
+        sh_size: size as u64,
+        sh_link: 0,
+        sh_info: 0,
+        sh_addralign: 0,
+        sh_entsize: entsize,
+    };
+    push_struct(&mut elf, section(0, 0, 0, 0, 0));
+    push_struct(
+        &mut elf,
+        section(
+            1,
+            SHT_RELA,
+            rela_offset,
+            size_of::<Elf64RelaRaw>(),
+            size_of::<Elf64RelaRaw>() as u64,
+        ),
+    );
+    push_struct(&mut elf, section(11, 3, names_offset, names.len(), 0));
+
+    // Point the header at the section table
+    let mut header = unsafe { core::ptr::read_unaligned(elf.as_ptr() as *const Elf64HeaderRaw) };
+    header.section_header_table_offset = (base_offset + sections_offset) as u64;
+    header.section_header_entry_size = size_of::<Elf64SectionHeaderRaw>() as u16;
+    header.section_header_entry_count = 3;
+    header.index_of_section_header_string_table = 2;
+    unsafe { core::ptr::write_unaligned(elf.as_mut_ptr() as *mut Elf64HeaderRaw, header) };
+
+    let base = 0x1000_0000_0000;
+    match Elf64File::from_bytes(elf.into_boxed_slice()).and_then(|elf| elf.load_image(base)) {
+        Ok(image) => {
+            kassert_eq!(t, image.entry, base + 0x1000);
+            let page = &image.pages[&(base + 0x1000)];
+            let value = u64::from_le_bytes(page[8..16].try_into().unwrap());
+            kassert_eq!(t, value, base + 0x1234);
+        }
+        Err(e) => kassert!(t, false, "pie rejected: {:?}", e),
+    }
+}
+
+fn shebang_parsing(t: &mut KTestContext) {
+    let parsed = parse_shebang(b"#!/bin/sh\necho hi\n").ok();
+    kassert_eq!(t, parsed, Some(("/bin/sh".into(), None)));
+
+    // Everything after the interpreter is one argument
+    let parsed = parse_shebang(b"#! /usr/bin/env  python3 -u \r\n").ok();
+    kassert_eq!(
+        t,
+        parsed,
+        Some(("/usr/bin/env".into(), Some("python3 -u".into())))
+    );
+
+    let parsed = parse_shebang(b"#!/bin/true").ok();
+    kassert_eq!(t, parsed, Some(("/bin/true".into(), None)));
+
+    kassert!(
+        t,
+        matches!(parse_shebang(b"#!  \n"), Err(ScriptError::NoInterpreter))
+    );
+    kassert!(
+        t,
+        matches!(parse_shebang(b"\x7fELF"), Err(ScriptError::InvalidMagic))
+    );
+    let mut long = Vec::from(&b"#!/"[..]);
+    long.resize(300, b'a');
+    kassert!(
+        t,
+        matches!(parse_shebang(&long), Err(ScriptError::LineTooLong))
+    );
+}
+
+pub(super) fn build_flat(load_address: u64, entry_offset: u64, code: &[u8]) -> Vec<u8> {
+    let mut flat = Vec::new();
+    push_struct(
+        &mut flat,
+        FlatHeader {
+            magic: FLAT_MAGIC,
+            load_address,
+            entry_offset,
+        },
+    );
+    flat.extend_from_slice(code);
+    flat
+}
+
+fn flat_binary_loading(t: &mut KTestContext) {
+    let header_size = size_of::<FlatHeader>() as u64;
+    let flat = build_flat(0x40_0000, header_size, &[0x90, 0xeb, 0xfe]);
+    match FlatBinary::from_bytes(flat.into_boxed_slice()) {
+        Ok(flat) => {
+            let image = flat.load_image();
+            kassert_eq!(t, image.entry, 0x40_0000 + header_size);
+            kassert_eq!(t, image.ranges(), [(0x40_0000, 0x40_1000)]);
+            let page = &image.pages[&0x40_0000];
+            kassert_eq!(t, page[..8], FLAT_MAGIC);
+            kassert_eq!(t, page[header_size as usize], 0x90);
+        }
+        Err(e) => kassert!(t, false, "flat binary rejected: {:?}", e),
+    }
+
+    let invalid = |load_address, entry_offset| {
+        FlatBinary::from_bytes(build_flat(load_address, entry_offset, &[0x90]).into_boxed_slice())
+            .err()
+    };
+    kassert!(
+        t,
+        matches!(
+            invalid(0x40_0800, header_size),
+            Some(FlatError::InvalidLoadAddress(_))
+        )
+    );
+    kassert!(
+        t,
+        matches!(
+            invalid(0, header_size),
+            Some(FlatError::InvalidLoadAddress(_))
+        )
+    );
+    kassert!(
+        t,
+        matches!(
+            invalid(0x40_0000, 0),
+            Some(FlatError::InvalidEntryOffset(_))
+        )
+    );
+    kassert!(
+        t,
+        matches!(
+            invalid(0x40_0000, header_size + 1),
+            Some(FlatError::InvalidEntryOffset(_))
+        )
+    );
+}
+
+/// A newc archive of `(name, mode, data)` entries, with the trailer
+pub(super) fn build_cpio(entries: &[(&str, u32, &[u8])]) -> Vec<u8> {
+    let mut archive = Vec::new();
+    let trailer: (&str, u32, &[u8]) = ("TRAILER!!!", 0, &[]);
+    for (ino, (name, mode, data)) in entries.iter().chain([&trailer]).enumerate() {
+        let header = alloc::format!(
+            "070701{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}",
+            ino,
+            mode,
+            0,
+            0,
+            1,
+            0,
+            data.len(),
+            0,
+            0,
+            0,
+            0,
+            name.len() + 1,
+            0
+        );
+        archive.extend_from_slice(header.as_bytes());
+        archive.extend_from_slice(name.as_bytes());
+        archive.push(0);
+        archive.resize(archive.len().next_multiple_of(4), 0);
+        archive.extend_from_slice(data);
+        archive.resize(archive.len().next_multiple_of(4), 0);
+    }
+    archive
+}
+
+fn cpio_newc_parsing(t: &mut KTestContext) {
+    let archive = build_cpio(&[
+        (".", 0o040755, b""),
+        ("./bin", 0o040755, b""),
+        ("./bin/sh", 0o100755, b"hello"),
+        ("./sh", 0o120777, b"bin/sh"),
+    ]);
+    let entries = CpioArchive::new(&archive).collect::<Vec<_>>();
+    kassert_eq!(t, entries.len(), 4);
+    let Ok(entries) = entries.into_iter().collect::<Result<Vec<_>, _>>() else {
+        kassert!(t, false);
+        return;
+    };
+    kassert_eq!(t, entries[0].path(), b"");
+    kassert_eq!(t, entries[1].kind(), CpioEntryKind::Directory);
+    kassert_eq!(t, entries[2].path(), b"bin/sh");
+    kassert_eq!(t, entries[2].kind(), CpioEntryKind::File);
+    kassert_eq!(t, entries[2].permissions(), 0o755);
+    kassert_eq!(t, entries[2].data, b"hello");
+    kassert_eq!(t, entries[3].kind(), CpioEntryKind::Symlink);
+    kassert_eq!(t, entries[3].data, b"bin/sh");
+
+    // Cut inside the header of ./sh
+    let truncated = &archive[..archive.len() - 200];
+    kassert!(
+        t,
+        CpioArchive::new(truncated).any(|entry| matches!(entry, Err(CpioError::Truncated { .. })))
+    );
+    let mut bad_magic = archive.clone();
+    bad_magic[5] = b'9';
+    kassert!(
+        t,
+        matches!(
+            CpioArchive::new(&bad_magic).next(),
+            Some(Err(CpioError::BadMagic { offset: 0 }))
+        )
+    );
+    let mut bad_field = archive.clone();
+    bad_field[6] = b'g';
+    kassert!(
+        t,
+        matches!(
+            CpioArchive::new(&bad_field).next(),
+            Some(Err(CpioError::BadField { offset: 0 }))
+        )
+    );
+    // Without the trailer
+    let entry_end = archive.len() - 124;
+    kassert!(
+        t,
+        matches!(
+            CpioArchive::new(&archive[..entry_end]).last(),
+            Some(Err(CpioError::MissingTrailer))
+        )
+    );
+}
+
+/// A raw structure of `size` bytes with a valid checksum, `fields` are `(offset, value)` pairs
+fn build_obsiboot(version: u32, size: usize, fields: &[(usize, u32)]) -> Vec<u8> {
+    let mut bytes = alloc::vec![0u8; size];
+    bytes[..4].copy_from_slice(&(size as u32).to_le_bytes());
+    bytes[4..8].copy_from_slice(&version.to_le_bytes());
+    for (offset, value) in fields {
+        bytes[*offset..*offset + 4].copy_from_slice(&value.to_le_bytes());
+    }
+    let checksum = ObsiBootKernelParameters::calculate_checksum(&bytes);
+    for (i, word) in checksum.iter().enumerate() {
+        bytes[8 + i * 4..12 + i * 4].copy_from_slice(&word.to_le_bytes());
+    }
+    bytes
+}
+
+fn obsiboot_versions(t: &mut KTestContext) {
+    let initramfs = [
+        (OBSIBOOT_V1_SIZE, 0x20_0000),
+        (OBSIBOOT_V1_SIZE + 4, 0x1000),
+    ];
+
+    // Bytes after a version 1 structure aren't read as version 2 fields
+    let mut v1 = build_obsiboot(1, OBSIBOOT_V1_SIZE, &[]);
+    v1.resize(OBSIBOOT_V2_SIZE, 0xFF);
+    let parsed = ObsiBootKernelParameters::parse(&v1);
+    kassert!(t, parsed.as_ref().is_ok_and(|p| p.version() == 1));
+    kassert!(
+        t,
+        parsed.is_ok_and(|p| p.initramfs().is_none() && p.framebuffer().is_none())
+    );
+
+    let v2 = build_obsiboot(2, OBSIBOOT_V2_SIZE, &initramfs);
+    let parsed = ObsiBootKernelParameters::parse(&v2);
+    kassert!(
+        t,
+        parsed.is_ok_and(|p| p.initramfs() == Some((0x20_0000, 0x1000)) && p.acpi_rsdp().is_none())
+    );
+
+    let mut corrupted = v2.clone();
+    corrupted[OBSIBOOT_V1_SIZE] ^= 1;
+    kassert!(
+        t,
+        matches!(
+            ObsiBootKernelParameters::parse(&corrupted),
+            Err(ObsiBootError::BadChecksum { version: 2, .. })
+        )
+    );
+    // A version 2 structure with the size of version 1
+    let truncated = build_obsiboot(2, OBSIBOOT_V1_SIZE, &[]);
+    kassert!(
+        t,
+        matches!(
+            ObsiBootKernelParameters::parse(&truncated),
+            Err(ObsiBootError::Truncated { version: 2, .. })
+        )
+    );
+    kassert!(
+        t,
+        matches!(
+            ObsiBootKernelParameters::parse(&v2[..OBSIBOOT_V2_SIZE - 1]),
+            Err(ObsiBootError::Truncated { .. })
+        )
+    );
+    kassert!(
+        t,
+        matches!(
+            ObsiBootKernelParameters::parse(&build_obsiboot(3, OBSIBOOT_V2_SIZE, &[])),
+            Err(ObsiBootError::UnsupportedVersion(3))
+        )
+    );
+}
+
+fn command_line_parsing(t: &mut KTestContext) {
+    let line = KernelCommandLine::parse(
+        "  root=/dev/pata_ps  nosmp init=\"/initrd/my init\" kernel.loglevel=warn loglevel=debug ",
+    );
+    kassert_eq!(t, line.get("root"), Some("/dev/pata_ps"));
+    kassert_eq!(t, line.get("init"), Some("/initrd/my init"));
+    kassert!(t, line.has_flag("nosmp"));
+    kassert!(t, !line.has_flag("root"));
+    kassert_eq!(t, line.get("missing"), None);
+    kassert_eq!(t, line.values().count(), 4);
+
+    // The alias comes last and wins, unknown keys and flags are ignored
+    let config = KernelBaseConfig::parse("ktest", "kernel.loglevel = error\nsyscall.fast = false")
+        .apply_command_line(&line);
+    kassert_eq!(t, config.log_level.as_str(), "debug");
+    kassert!(t, !config.fast_syscalls);
+    let bad = KernelBaseConfig::defaults()
+        .apply_command_line(&KernelCommandLine::parse("syscall.fast=maybe"));
+    kassert!(t, bad.fast_syscalls);
+
+    kassert_eq!(t, KernelCommandLine::parse("").values().count(), 0);
+    let unterminated = KernelCommandLine::parse("a=\"b c");
+    kassert_eq!(t, unterminated.get("a"), Some("b c"));
+
+    let long = "x=1 ".repeat(MAX_COMMAND_LINE_LEN);
+    let long = KernelCommandLine::parse(&long);
+    kassert!(t, long.as_str().len() <= MAX_COMMAND_LINE_LEN);
+    let many = (0..MAX_COMMAND_LINE_PARAMETERS + 10)
+        .map(|i| alloc::format!("k{}", i))
+        .collect::<Vec<_>>()
+        .join(" ");
+    kassert_eq!(
+        t,
+        KernelCommandLine::parse(&many).get(&alloc::format!("k{}", MAX_COMMAND_LINE_PARAMETERS)),
+        None
+    );
+}
+
+fn lock_debugging(t: &mut KTestContext) {
+    let checked = cfg!(debug_assertions);
+    let vfs = DebugRwLock::with_class(0u32, LockClass::Vfs);
+    let scheduler = DebugMutex::with_class((), LockClass::Scheduler);
+    let unordered = DebugMutex::new(());
+    let violations = order_violations();
+
+    {
+        let _vfs = vfs.read();
+        let _again = vfs.read();
+        let _scheduler = scheduler.lock();
+        let _unordered = unordered.lock();
+        kassert_eq!(t, vfs.is_held_here(), checked);
+        kassert_eq!(t, scheduler.is_held_here(), checked);
+        // Would deadlock with `write`
+        kassert!(t, vfs.try_write().is_none());
+    }
+    kassert!(t, !vfs.is_held_here() && !scheduler.is_held_here());
+    kassert_eq!(t, order_violations(), violations);
+
+    {
+        let _scheduler = scheduler.lock();
+        let _unordered = unordered.lock();
+        let _vfs = vfs.write();
+    }
+    kassert_eq!(t, order_violations(), violations + checked as u64);
+    kassert!(t, !scheduler.is_held_here() && !unordered.is_held_here());
+
+    // Released out of order
+    let first = scheduler.lock();
+    let second = unordered.lock();
+    drop(first);
+    kassert_eq!(t, unordered.is_held_here(), checked);
+    drop(second);
+    kassert!(t, !unordered.is_held_here());
+}
+
+const PRINT_PAYLOAD: &str = "0123456789abcdefghijklmnopqrstuvwxyz";
+static TIMER_PRINTS: AtomicU64 = AtomicU64::new(0);
+
+fn print_from_timer() {
+    let n = TIMER_PRINTS.fetch_add(1, Ordering::Relaxed);
+    println_irq!("ktest-irq {} {}", n, PRINT_PAYLOAD);
+}
+
+fn interrupt_safe_printing(t: &mut KTestContext) {
+    const MAIN_PRINTS: u64 = 4;
+    let first = get_stdout().next_record_sequence();
+    TIMER_PRINTS.store(0, Ordering::Relaxed);
+    set_tick_hook(Some(print_from_timer));
+    // Every tick lands in the middle of a line
+    for i in 0..MAIN_PRINTS {
+        printf!("ktest-main {} [", i);
+        let tick = get_uptime_ticks();
+        let mut spins = 0u64;
+        while get_uptime_ticks() == tick && spins < 1_000_000_000 {
+            core::hint::spin_loop();
+            spins += 1;
+        }
+        println!("{}]", PRINT_PAYLOAD);
+    }
+    set_tick_hook(None);
+    let timer_prints = TIMER_PRINTS.load(Ordering::Relaxed);
+    kassert!(t, timer_prints >= MAIN_PRINTS);
+
+    let (mut main, mut irq) = (0, 0);
+    let mut sequence = first;
+    while let Some(record) = get_stdout().read_record(sequence) {
+        sequence = record.sequence + 1;
+        let message = record.message.as_slice();
+        if message.starts_with(b"ktest-main ") {
+            let expected = alloc::format!("ktest-main {} [{}]", main, PRINT_PAYLOAD);
+            kassert_eq!(t, message, expected.as_bytes());
+            main += 1;
+        } else if message.starts_with(b"ktest-irq ") {
+            let expected = alloc::format!("ktest-irq {} {}", irq, PRINT_PAYLOAD);
+            kassert_eq!(t, message, expected.as_bytes());
+            irq += 1;
+        }
+    }
+    kassert_eq!(t, main, MAIN_PRINTS);
+    kassert_eq!(t, irq, timer_prints);
+}
+
+fn log_ring_growth(t: &mut KTestContext) {
+    // 12 bytes of header and 20 of message, 32 per record
+    let mut ring = KernelLogRing::with_limit(32, 128);
+    for i in 0..6u8 {
+        ring.grow_for(20);
+        ring.push(i as u64, KernelLogLevel::Info, 0, &[b'a' + i; 20]);
+    }
+    kassert_eq!(t, ring.capacity(), 128);
+    kassert_eq!(
+        t,
+        ring.stats(),
+        KernelStdoutStats {
+            written: 6 * 32,
+            dropped: 2 * 32,
+            high_water: 128,
+            size: 128,
+            limit: 128,
+        }
+    );
+    let mut message = [0; 20];
+    let record = ring.copy_record(0, &mut message);
+    kassert_eq!(
+        t,
+        record,
+        Some((2, 2, KernelLogLevel::Info, 0, message.len()))
+    );
+    kassert_eq!(t, message, [b'c'; 20]);
+
+    // The limit doesn't go below the size
+    ring.set_limit(64);
+    kassert_eq!(t, ring.stats().limit, 128);
+}
@@ -0,0 +1,1093 @@
+use alloc::{format, string::String, vec, vec::Vec};
+use core::num::NonZeroUsize;
+
+use crate::{
+    config::get_kernel_config,
+    data::{
+        file::File,
+        path::VfsPath,
+        permissions::{Credentials, Permissions},
+    },
+    drivers::{
+        disk::ramdisk::{create_ramdisk, RamDisk},
+        fs::{
+            dirent::{DT_DIR, DT_REG},
+            mount::{mount_device, MountError, MountOptions},
+            phys::ext2::{
+                fsck::FsckFinding,
+                inode::{Inode, InodePermissions, InodeReadingLocation, InodeType, RawInode},
+                is_sparse_backup_group,
+                mkfs::{mkfs_ext2_minimal, MkfsOptions},
+                order::{WriteOrder, WriteStage},
+                sparse_backup_groups,
+                superblock::Superblock,
+                Ext2Volume,
+            },
+        },
+        vfs::{
+            get_vfs, BlockDevice, DeviceNumber, FileStat, FileSystem, SeekPosition, VfsError,
+            OPEN_MODE_APPEND, OPEN_MODE_CREATE, OPEN_MODE_READ, OPEN_MODE_WRITE,
+        },
+    },
+    kassert, kassert_eq, ktest, log_info,
+};
+
+use super::{vfs::read_records, KTest, KTestContext};
+
+pub const KTESTS: &[KTest] = &[
+    ktest!(inode_location_roundtrip),
+    ktest!(inode_location_advance),
+    ktest!(inode_attributes_roundtrip),
+    ktest!(device_number_encoding),
+    ktest!(write_order_barriers),
+    ktest!(relatime_staleness),
+    ktest!(ramdisk_mkfs_roundtrip),
+    ktest!(format_and_check),
+    ktest!(sparse_file_across_4gib),
+    ktest!(one_group_fill_and_free),
+    ktest!(alloc_goal_group),
+    ktest!(sparse_backup_group_list),
+    ktest!(metadata_backup_locations),
+    ktest!(mke2fs_backup_locations),
+    ktest!(unaligned_block_crossing_io),
+    ktest!(full_block_writes_without_seek),
+    ktest!(stat_fields),
+    ktest!(unsupported_ro_feature_opens),
+    ktest!(directory_handle_streaming),
+    ktest!(stale_device_goes_read_only),
+];
+
+const TABLE_SIZE: u32 = 4;
+/// 12 direct blocks then single, double and triple indirect ones
+const MAX_BLOCKS: u32 = 12 + TABLE_SIZE + TABLE_SIZE * TABLE_SIZE + TABLE_SIZE.pow(3);
+
+fn inode_location_roundtrip(t: &mut KTestContext) {
+    for block_idx in 0..MAX_BLOCKS {
+        let location = InodeReadingLocation::new(TABLE_SIZE, block_idx);
+        kassert_eq!(t, location.current_block_idx(), block_idx);
+    }
+    let large = InodeReadingLocation::new(256, 12 + 256 + 256 * 256 + 5);
+    kassert_eq!(t, large.current_block_idx(), 12 + 256 + 256 * 256 + 5);
+}
+
+fn inode_location_advance(t: &mut KTestContext) {
+    let mut location = InodeReadingLocation::new(TABLE_SIZE, 0);
+    for block_idx in 1..MAX_BLOCKS {
+        kassert!(
+            t,
+            location.advance(),
+            "advance failed before block {}",
+            block_idx
+        );
+        kassert_eq!(t, location.current_block_idx(), block_idx);
+    }
+    kassert!(
+        t,
+        !location.advance(),
+        "advanced past the last triple indirect block"
+    );
+}
+
+fn inode_attributes_roundtrip(t: &mut KTestContext) {
+    let mut raw = RawInode::empty();
+    raw.type_and_permissions = 0x8000 | 0o644;
+    let mut inode = Inode::from_raw(raw, 12, None);
+    kassert_eq!(t, inode.permissions.get(), 0o644);
+
+    inode.permissions = InodePermissions::from(0o4755);
+    inode.set_owner(0x12345, 100);
+    let raw = inode.get_raw();
+    kassert_eq!(t, { raw.type_and_permissions }, 0x8000 | 0o4755);
+    kassert_eq!(t, { raw.uid }, 0x2345);
+    kassert_eq!(t, &raw.ossv2[4..8], &[0x01, 0x00, 0x00, 0x00][..]);
+
+    let inode = Inode::from_raw(raw, 12, None);
+    kassert_eq!(t, inode.full_uid(), 0x12345);
+    kassert_eq!(t, inode.full_gid(), 100);
+}
+
+fn device_number_encoding(t: &mut KTestContext) {
+    let mut raw = RawInode::empty();
+    raw.type_and_permissions = 0x2000 | 0o600;
+    let mut inode = Inode::from_raw(raw, 12, None);
+
+    inode.set_device_number(DeviceNumber::new(1, 3));
+    kassert_eq!(t, inode.direct_block_pointers[0], 0x0103);
+    kassert_eq!(t, inode.device_number(), DeviceNumber::new(1, 3));
+
+    // Too large for the old format
+    let number = DeviceNumber::new(259, 0x12345);
+    inode.set_device_number(number);
+    kassert_eq!(t, inode.direct_block_pointers[0], 0);
+    kassert_eq!(t, inode.device_number(), number);
+}
+
+fn write_order_barriers(t: &mut KTestContext) {
+    let mut order = WriteOrder::default();
+    kassert!(t, !order.enter(WriteStage::Data));
+    order.record_write();
+    kassert!(t, !order.enter(WriteStage::Data));
+
+    // A later stage can't be written before the data it references is durable
+    kassert!(t, order.enter(WriteStage::Inode));
+    order.barrier();
+    order.record_write();
+
+    // Going back to an earlier stage is always allowed
+    kassert!(t, !order.enter(WriteStage::Allocation));
+    order.record_write();
+    kassert!(t, order.enter(WriteStage::Superblock));
+    kassert_eq!(t, order.violations(), 0);
+}
+
+fn relatime_staleness(t: &mut KTestContext) {
+    let mut raw = RawInode::empty();
+    raw.atime = 100;
+    raw.ctime = 100;
+    raw.mtime = 100;
+    let mut inode = Inode::from_raw(raw, 12, None);
+    kassert!(t, !inode.atime_stale());
+
+    inode.mtime = 150;
+    kassert!(t, inode.atime_stale());
+    inode.atime = 150;
+    kassert!(t, !inode.atime_stale());
+
+    // Attribute changes count too
+    inode.ctime = 200;
+    kassert!(t, inode.atime_stale());
+}
+
+fn ramdisk_mkfs_roundtrip(t: &mut KTestContext) {
+    let Ok(index) = create_ramdisk(256 * 1024) else {
+        kassert!(t, false, "failed to create the ram disk");
+        return;
+    };
+    let path = format!("/dev/ram{index}");
+    let vfs = get_vfs();
+    let device = vfs
+        .write()
+        .get_file(&VfsPath::from(path.as_str()))
+        .ok()
+        .and_then(|file| file.get_block_device());
+    let Some(device) = device else {
+        kassert!(t, false, "{} isn't a block device", path);
+        return;
+    };
+    kassert!(t, mkfs_ext2_minimal(&device).is_ok());
+    drop(device);
+
+    let Ok(mut disk) = MountedRamdisk::mount(path, "ktest_ram") else {
+        kassert!(t, false, "failed to mount the ram disk");
+        return;
+    };
+    match disk.open("hello.txt", OPEN_MODE_WRITE | OPEN_MODE_CREATE, 0o644) {
+        Ok(mut file) => kassert!(t, matches!(file.write(b"hello ram"), Ok(9))),
+        Err(err) => kassert!(t, false, "failed to create the file: {:?}", err),
+    }
+    kassert!(t, disk.unmount().is_ok());
+
+    kassert!(t, disk.remount().is_ok());
+    match disk.open("hello.txt", OPEN_MODE_READ, 0) {
+        Ok(file) => {
+            let mut buf = [0u8; 16];
+            kassert!(t, matches!(file.read(&mut buf), Ok(9)));
+            kassert_eq!(t, &buf[..9], b"hello ram");
+        }
+        Err(err) => kassert!(t, false, "failed to reopen the file: {:?}", err),
+    }
+    kassert!(t, disk.unmount().is_ok());
+}
+
+fn format_and_check(t: &mut KTestContext) {
+    // Three groups, the last one shorter
+    let Ok(index) = create_ramdisk(20 * 1024 * 1024) else {
+        kassert!(t, false, "failed to create the ram disk");
+        return;
+    };
+    let path = format!("/dev/ram{index}");
+    let open_device = || {
+        File::open(
+            &path,
+            OPEN_MODE_READ | OPEN_MODE_WRITE,
+            Permissions::from_u64(0),
+            Credentials::Kernel,
+        )
+    };
+    let check = |fix| open_device().and_then(|device| Ext2Volume::check(device, fix));
+
+    kassert!(
+        t,
+        matches!(
+            open_device().map(|device| Ext2Volume::format(device, MkfsOptions::default())),
+            Ok(Ok(()))
+        )
+    );
+    match check(false) {
+        Ok(report) => kassert!(t, report.is_clean(), "{:?}", report.findings),
+        Err(err) => kassert!(t, false, "check failed: {:?}", err),
+    }
+
+    match MountedRamdisk::mount(path.clone(), "ktest_fsck") {
+        Ok(mut disk) => {
+            if let Ok(mut file) = disk.open("a", OPEN_MODE_WRITE | OPEN_MODE_CREATE, 0o644) {
+                kassert!(t, matches!(file.write(&[7; 100]), Ok(100)));
+            }
+            kassert!(t, disk.unmount().is_ok());
+        }
+        Err(err) => kassert!(t, false, "failed to mount the ram disk: {:?}", err),
+    }
+
+    // Free the inode of the file behind the driver's back, its entry now dangles
+    let Ok(mut device) = open_device() else {
+        kassert!(t, false, "failed to open {}", path);
+        return;
+    };
+    let mut word = [0u8; 4];
+    kassert!(t, device.seek(SeekPosition::FromStart(2048 + 4)).is_ok());
+    kassert!(t, matches!(device.read(&mut word), Ok(4)));
+    // Inode 11 is bit 10 of the first group
+    let byte = u32::from_le_bytes(word) as u64 * 1024 + 1;
+    let mut bits = [0u8; 1];
+    kassert!(t, device.seek(SeekPosition::FromStart(byte)).is_ok());
+    kassert!(t, matches!(device.read(&mut bits), Ok(1)));
+    bits[0] &= !(1 << 2);
+    kassert!(t, device.seek(SeekPosition::FromStart(byte)).is_ok());
+    kassert!(t, matches!(device.write(&bits), Ok(1)));
+    drop(device);
+
+    let dangling = |finding: &FsckFinding| matches!(finding, FsckFinding::DanglingEntry { directory: 2, inode: 11, name } if name == "a");
+    let count = |finding: &FsckFinding| {
+        matches!(
+            finding,
+            FsckFinding::GroupCountMismatch { .. } | FsckFinding::SuperblockCountMismatch { .. }
+        )
+    };
+    match check(false) {
+        Ok(report) => {
+            kassert!(
+                t,
+                report.findings.iter().any(dangling),
+                "{:?}",
+                report.findings
+            );
+            kassert!(t, report.findings.iter().any(count));
+            kassert_eq!(t, report.repaired, 0);
+        }
+        Err(err) => kassert!(t, false, "check failed: {:?}", err),
+    }
+    match check(true) {
+        Ok(report) => kassert!(t, report.repaired >= 2, "{:?}", report),
+        Err(err) => kassert!(t, false, "repair failed: {:?}", err),
+    }
+    // The data block of the file stays marked in use, only that is left
+    match check(false) {
+        Ok(report) => kassert!(
+            t,
+            !report.findings.iter().any(|f| dangling(f) || count(f)),
+            "{:?}",
+            report.findings
+        ),
+        Err(err) => kassert!(t, false, "check failed: {:?}", err),
+    }
+}
+
+const GIB4: u64 = 1 << 32;
+
+/// A file of 4 GiB - 512 bytes where only the last block and its tables are allocated
+fn sparse_file_across_4gib(t: &mut KTestContext) {
+    let Ok(mut disk) = MountedRamdisk::format(1024 * 1024, "ktest_big") else {
+        kassert!(t, false, "failed to mount the ram disk");
+        return;
+    };
+
+    // Inode 11, empty
+    kassert!(
+        t,
+        disk.open("sparse", OPEN_MODE_WRITE | OPEN_MODE_CREATE, 0o644)
+            .is_ok()
+    );
+    kassert!(t, disk.unmount().is_ok());
+
+    let path = disk.device.clone();
+    let Ok(mut volume) = open_volume(&path) else {
+        kassert!(t, false, "failed to open the volume");
+        return;
+    };
+    if let Err(err) = sparse_boundary(t, &mut volume) {
+        kassert!(t, false, "sparse file I/O failed: {:?}", err);
+    }
+    kassert!(t, volume.flush().is_ok());
+    drop(volume);
+
+    match open_ramdisk(&path).and_then(|device| Ext2Volume::check(device, false)) {
+        Ok(report) => kassert!(t, report.is_clean(), "{:?}", report.findings),
+        Err(err) => kassert!(t, false, "check failed: {:?}", err),
+    }
+}
+
+fn sparse_boundary(t: &mut KTestContext, volume: &mut Ext2Volume) -> Result<(), VfsError> {
+    // 1K blocks, the last block below 4 GiB goes through the triple indirect table
+    let last = (GIB4 / 1024 - 1) as u32;
+    let triple = last - 12 - 256 - 256 * 256;
+    let path = [triple / (256 * 256), triple / 256 % 256, triple % 256];
+
+    let blocks = [
+        volume.alloc_block_any()?,
+        volume.alloc_block_any()?,
+        volume.alloc_block_any()?,
+        volume.alloc_block_any()?,
+    ];
+    for (level, idx) in path.iter().enumerate() {
+        let mut table = vec![0u8; 1024];
+        let entry = *idx as usize * 4;
+        table[entry..entry + 4].copy_from_slice(&blocks[level + 1].to_le_bytes());
+        volume.write_block(blocks[level] as u64, &table)?;
+    }
+    volume.write_block(blocks[3] as u64, &[0xab; 1024])?;
+
+    let mut inode = volume.get_inode(11, Some(2))?;
+    inode.triple_indirect_block_pointer = blocks[0];
+    inode.set_size(volume, GIB4 - 512)?;
+    inode.sectors_count = 4 * 2;
+    volume.update_inode(&inode)?;
+    let mut handle = volume.get_file_handle(inode, OPEN_MODE_READ | OPEN_MODE_WRITE)?;
+
+    let mut buf = [0u8; 1024];
+    kassert_eq!(t, handle.read_at(volume, 0, &mut buf)?, 1024);
+    kassert!(t, buf.iter().all(|b| *b == 0), "a hole must read as zeros");
+    kassert_eq!(t, handle.read_at(volume, GIB4 - 1024, &mut buf)?, 512);
+    kassert!(t, buf[..512].iter().all(|b| *b == 0xab));
+
+    // Growing past 4 GiB allocates one block, the tables are already there
+    kassert_eq!(t, handle.write_at(volume, GIB4 - 256, &[0xcd; 512])?, 512);
+    kassert_eq!(t, handle.get_size(), GIB4 + 256);
+    kassert_eq!(t, handle.get_inode().size_hi_or_dir_acl, 1);
+    kassert_eq!(t, handle.get_inode().size_lo, 256);
+    kassert_eq!(t, handle.get_inode().sectors_count, 5 * 2);
+
+    handle.seek(volume, SeekPosition::FromStart(GIB4 - 512))?;
+    kassert_eq!(t, handle.read(volume, &mut buf)?, 768);
+    kassert!(t, buf[..256].iter().all(|b| *b == 0xab));
+    kassert!(t, buf[256..768].iter().all(|b| *b == 0xcd));
+    kassert!(t, handle.is_eof());
+
+    handle.truncate(volume, GIB4 - 512)?;
+    kassert_eq!(t, handle.get_size(), GIB4 - 512);
+    kassert_eq!(t, handle.get_inode().size_hi_or_dir_acl, 0);
+    kassert_eq!(t, handle.get_inode().sectors_count, 4 * 2);
+    kassert_eq!(t, handle.read_at(volume, GIB4 - 1024, &mut buf)?, 512);
+    kassert!(t, buf[..512].iter().all(|b| *b == 0xab));
+
+    handle.flush(volume)?;
+    volume.update_inode(handle.get_inode())?;
+    kassert_eq!(
+        t,
+        volume.get_inode(11, Some(2))?.get_size(volume),
+        GIB4 - 512
+    );
+    Ok(())
+}
+
+/// Creates a ram disk of `size` bytes formatted with the default options, returns its path
+fn formatted_ramdisk(size: u64) -> Result<String, VfsError> {
+    let path = format!("/dev/ram{}", create_ramdisk(size)?);
+    Ext2Volume::format(open_ramdisk(&path)?, MkfsOptions::default())?;
+    Ok(path)
+}
+
+fn open_ramdisk(path: &str) -> Result<File, VfsError> {
+    File::open(
+        path,
+        OPEN_MODE_READ | OPEN_MODE_WRITE,
+        Permissions::from_u64(0),
+        Credentials::Kernel,
+    )
+}
+
+fn open_volume(path: &str) -> Result<Ext2Volume, VfsError> {
+    const CACHE: NonZeroUsize = NonZeroUsize::new(64 * 1024).unwrap();
+    Ext2Volume::from_device(open_ramdisk(path)?, CACHE, CACHE, CACHE, 0)
+}
+
+/// An ext2 ram disk mounted at `/<name>`, forcibly unmounted when dropped so a test returning
+/// early doesn't leave it behind
+struct MountedRamdisk {
+    /// Path of the ram disk in devfs
+    device: String,
+    mount_point: Vec<char>,
+    mounted: bool,
+}
+
+impl MountedRamdisk {
+    /// Mounts the ext2 volume on the ram disk at `device`
+    fn mount(device: String, name: &str) -> Result<Self, MountError> {
+        let mut disk = Self {
+            device,
+            mount_point: name.chars().collect(),
+            mounted: false,
+        };
+        disk.remount()?;
+        Ok(disk)
+    }
+
+    /// Mounts a ram disk of `size` bytes formatted with the default options
+    fn format(size: u64, name: &str) -> Result<Self, MountError> {
+        Self::mount(formatted_ramdisk(size)?, name)
+    }
+
+    fn remount(&mut self) -> Result<(), MountError> {
+        let options = MountOptions::default();
+        mount_device(&self.device, &self.mount_point, "ext2", &options, 0)?;
+        self.mounted = true;
+        Ok(())
+    }
+
+    /// Fails while files of the volume are open
+    fn unmount(&mut self) -> Result<(), VfsError> {
+        get_vfs().write().unmount(&self.mount_point)?;
+        self.mounted = false;
+        Ok(())
+    }
+
+    /// Absolute path of `name` in the volume, an empty name is its root
+    fn path(&self, name: &str) -> String {
+        let root = format!("/{}", self.mount_point.iter().collect::<String>());
+        match name {
+            "" => root,
+            name => format!("{root}/{name}"),
+        }
+    }
+
+    fn open(&self, name: &str, mode: u64, permissions: u64) -> Result<File, VfsError> {
+        File::open(
+            &self.path(name),
+            mode,
+            Permissions::from_u64(permissions),
+            Credentials::Kernel,
+        )
+    }
+}
+
+impl Drop for MountedRamdisk {
+    fn drop(&mut self) {
+        if self.mounted {
+            let _ = get_vfs().write().unmount_force(&self.mount_point);
+        }
+    }
+}
+
+fn one_group_fill_and_free(t: &mut KTestContext) {
+    // 1024 blocks, a single group
+    let Ok(path) = formatted_ramdisk(1024 * 1024) else {
+        kassert!(t, false, "failed to format the ram disk");
+        return;
+    };
+    let Ok(mut volume) = open_volume(&path) else {
+        kassert!(t, false, "failed to open the volume");
+        return;
+    };
+    let free = volume.get_superblock().unallocated_blocks;
+    kassert!(t, free > 0);
+
+    for round in 0..3 {
+        let mut blocks = Vec::new();
+        loop {
+            match volume.alloc_block_near(0) {
+                Ok(block) => blocks.push(block),
+                Err(VfsError::OutOfSpace) => break,
+                Err(err) => {
+                    kassert!(t, false, "round {}: allocation failed: {:?}", round, err);
+                    return;
+                }
+            }
+        }
+        kassert!(
+            t,
+            blocks.len() as u32 == free,
+            "round {}: {} blocks allocated, {} free",
+            round,
+            blocks.len(),
+            free
+        );
+        kassert!(t, blocks.iter().all(|block| *block < 1024));
+        for block in blocks {
+            let freed = volume
+                .get_block_allocator_for_group(0)
+                .and_then(|allocator| allocator.ok_or(VfsError::ReadOnly)?.dealloc_block(block));
+            kassert!(
+                t,
+                freed.is_ok(),
+                "round {}: failed to free {}",
+                round,
+                block
+            );
+        }
+        kassert!(t, volume.flush().is_ok());
+        kassert_eq!(t, { volume.get_superblock().unallocated_blocks }, free);
+    }
+    drop(volume);
+
+    match open_ramdisk(&path).and_then(|device| Ext2Volume::check(device, false)) {
+        Ok(report) => kassert!(t, report.is_clean(), "{:?}", report.findings),
+        Err(err) => kassert!(t, false, "check failed: {:?}", err),
+    }
+}
+
+fn alloc_goal_group(t: &mut KTestContext) {
+    // Groups of 8192, 8192 and 4095 blocks, group 1 has the most free ones as group 0 also
+    // holds the root directory
+    let Ok(path) = formatted_ramdisk(20 * 1024 * 1024) else {
+        kassert!(t, false, "failed to format the ram disk");
+        return;
+    };
+    let Ok(mut volume) = open_volume(&path) else {
+        kassert!(t, false, "failed to open the volume");
+        return;
+    };
+    let group = |block: Result<u32, VfsError>| block.map(|block| (block - 1) / 8192).ok();
+    kassert_eq!(t, group(volume.alloc_block_near(2)), Some(2));
+    kassert_eq!(t, group(volume.alloc_block_near(0)), Some(0));
+    kassert_eq!(t, group(volume.alloc_block_any()), Some(1));
+    // Past the last group
+    kassert_eq!(t, group(volume.alloc_block_near(3)), Some(1));
+    kassert!(t, volume.flush().is_ok());
+}
+
+fn sparse_backup_group_list(t: &mut KTestContext) {
+    let is_power = |group: u32, base: u32| {
+        let mut power = base;
+        while power < group {
+            power *= base;
+        }
+        power == group
+    };
+    for count in 0..800 {
+        let listed = sparse_backup_groups(count).collect::<Vec<_>>();
+        let expected = (0..count)
+            .filter(|group| *group <= 1 || [3, 5, 7].iter().any(|base| is_power(*group, *base)))
+            .collect::<Vec<_>>();
+        kassert!(t, listed == expected, "{} groups: {:?}", count, listed);
+        let matching = (0..count)
+            .filter(|group| is_sparse_backup_group(*group))
+            .collect::<Vec<_>>();
+        kassert!(t, matching == expected, "{} groups: {:?}", count, matching);
+    }
+    kassert_eq!(
+        t,
+        sparse_backup_groups(50).collect::<Vec<_>>(),
+        [0, 1, 3, 5, 7, 9, 25, 27, 49]
+    );
+    kassert_eq!(t, sparse_backup_groups(1).collect::<Vec<_>>(), [0]);
+    // Near the end of the range, 3^20 and 7^11 are the largest powers that fit
+    kassert!(t, is_sparse_backup_group(3u32.pow(20)));
+    kassert!(t, is_sparse_backup_group(7u32.pow(11)));
+    kassert!(t, !is_sparse_backup_group(3u32.pow(20) + 1));
+    kassert!(t, !is_sparse_backup_group(u32::MAX));
+}
+
+/// The superblock and descriptor table copies of a 20 MiB volume with 1 KiB blocks: the primary
+/// ones at 1 and 2, the backups of group 1 at 8193 and 8194, none in group 2.
+/// `mke2fs_backup_locations` compares the layout with a real mke2fs image
+fn metadata_backup_locations(t: &mut KTestContext) {
+    let Ok(path) = formatted_ramdisk(20 * 1024 * 1024) else {
+        kassert!(t, false, "failed to format the ram disk");
+        return;
+    };
+    let Ok(mut volume) = open_volume(&path) else {
+        kassert!(t, false, "failed to open the volume");
+        return;
+    };
+    kassert_eq!(t, volume.group_first_block(1), 8193);
+    kassert_eq!(t, volume.block_group(8193), 1);
+    kassert!(t, volume.block_group_contains_metadata_backup(1));
+    kassert!(t, !volume.block_group_contains_metadata_backup(2));
+    kassert!(t, !volume.block_group_contains_metadata_backup(3));
+
+    let mut superblock = volume.get_superblock().clone();
+    superblock.mount_count_since_fsck = 42;
+    kassert!(t, volume.set_superblock(superblock).is_ok());
+    let descriptor = volume.get_block_group_descriptor(2).map(|mut descriptor| {
+        descriptor.directory_count = 7;
+        descriptor
+    });
+    kassert!(
+        t,
+        descriptor
+            .is_some_and(|descriptor| volume.set_block_group_descriptor(2, descriptor).is_ok())
+    );
+    kassert!(t, volume.flush().is_ok());
+    drop(volume);
+
+    let Ok(device) = open_ramdisk(&path) else {
+        kassert!(t, false, "failed to open {}", path);
+        return;
+    };
+    let read_u16 = |offset: u64| {
+        let mut bytes = [0u8; 2];
+        let read = device
+            .seek(SeekPosition::FromStart(offset))
+            .and_then(|_| device.read(&mut bytes));
+        read.ok().map(|_| u16::from_le_bytes(bytes))
+    };
+    // Superblock magic at 56, mount count at 52 and group number at 90
+    for (block, group) in [(1u64, 0), (8193, 1)] {
+        kassert_eq!(t, read_u16(block * 1024 + 56), Some(0xef53));
+        kassert_eq!(t, read_u16(block * 1024 + 52), Some(42));
+        kassert_eq!(t, read_u16(block * 1024 + 90), Some(group));
+        // Directory count of the descriptor of group 2
+        kassert_eq!(t, read_u16((block + 1) * 1024 + 2 * 32 + 16), Some(7));
+    }
+    kassert!(t, read_u16(16385 * 1024 + 56) != Some(0xef53));
+}
+
+/// A little endian superblock field of up to 4 bytes, from the superblock at `block`
+fn read_superblock_field(device: &File, block: u64, offset: usize, len: usize) -> Option<u32> {
+    let mut bytes = [0u8; 4];
+    device
+        .seek(SeekPosition::FromStart(block * 1024 + offset as u64))
+        .ok()?;
+    match device.read(&mut bytes[..len]) {
+        Ok(read) if read == len as u64 => Some(u32::from_le_bytes(bytes)),
+        _ => None,
+    }
+}
+
+/// Whether `block` holds the copy of the superblock of `group`
+fn has_superblock_copy(device: &File, block: u64, group: u32) -> bool {
+    let field = |offset, len| read_superblock_field(device, block, offset, len);
+    field(core::mem::offset_of!(Superblock, signature), 2) == Some(0xef53)
+        && field(core::mem::offset_of!(Superblock, this_block_group), 2) == Some(group)
+}
+
+/// The groups of the mke2fs image given with `ktest.mke2fs_image` that hold a superblock copy must
+/// be the ones `is_sparse_backup_group` picks, and the ones of a volume formatted here
+fn mke2fs_backup_locations(t: &mut KTestContext) {
+    let path = get_kernel_config().ktest_mke2fs_image.clone();
+    if path.is_empty() {
+        log_info!(target: "ktest", "    no ktest.mke2fs_image, skipped");
+        return;
+    }
+    let Ok(image) = File::open(
+        &path,
+        OPEN_MODE_READ,
+        Permissions::from_u64(0),
+        Credentials::Kernel,
+    ) else {
+        kassert!(t, false, "failed to open {}", path);
+        return;
+    };
+    let field = |device: &File, offset, len| read_superblock_field(device, 1, offset, len);
+    let blocks_count = field(&image, core::mem::offset_of!(Superblock, blocks_count), 4);
+    let layout = (
+        field(&image, core::mem::offset_of!(Superblock, log_block_size), 4),
+        field(
+            &image,
+            core::mem::offset_of!(Superblock, superblock_block),
+            4,
+        ),
+        field(
+            &image,
+            core::mem::offset_of!(Superblock, blocks_per_group),
+            4,
+        ),
+        field(
+            &image,
+            core::mem::offset_of!(Superblock, readonly_or_support_features),
+            4,
+        )
+        .map(|features| features & 1),
+    );
+    // 1 KiB blocks, the first one in group 0, sparse_super
+    let Some(blocks_count) =
+        blocks_count.filter(|_| layout == (Some(0), Some(1), Some(8192), Some(1)))
+    else {
+        kassert!(
+            t,
+            false,
+            "{} isn't a sparse_super image with 1 KiB blocks: {:?}",
+            path,
+            layout
+        );
+        return;
+    };
+
+    let Ok(formatted) =
+        formatted_ramdisk(blocks_count as u64 * 1024).and_then(|path| open_ramdisk(&path))
+    else {
+        kassert!(
+            t,
+            false,
+            "failed to format a ram disk of {} blocks",
+            blocks_count
+        );
+        return;
+    };
+    let group_count = (blocks_count - 1).div_ceil(8192);
+    let formatted_count = field(
+        &formatted,
+        core::mem::offset_of!(Superblock, blocks_count),
+        4,
+    )
+    .map_or(0, |count| count.saturating_sub(1).div_ceil(8192));
+    for group in 0..group_count {
+        let block = 1 + group as u64 * 8192;
+        let expected = is_sparse_backup_group(group);
+        kassert!(
+            t,
+            has_superblock_copy(&image, block, group) == expected,
+            "mke2fs group {}",
+            group
+        );
+        kassert!(
+            t,
+            group >= formatted_count || has_superblock_copy(&formatted, block, group) == expected,
+            "mkfs group {}",
+            group
+        );
+    }
+}
+
+fn check_read(t: &mut KTestContext, file: &File, reference: &[u8], offset: u64, len: usize) {
+    let mut buf = vec![0u8; len];
+    let expected = reference.len().saturating_sub(offset as usize).min(len);
+    kassert!(t, file.seek(SeekPosition::FromStart(offset)).is_ok());
+    let read = file.read(&mut buf);
+    kassert!(
+        t,
+        read.as_ref().ok() == Some(&(expected as u64)),
+        "{} bytes at {}: {:?}",
+        len,
+        offset,
+        read
+    );
+    kassert!(
+        t,
+        buf[..expected] == reference[offset as usize..offset as usize + expected],
+        "{} bytes at {} differ",
+        len,
+        offset
+    );
+}
+
+fn unaligned_block_crossing_io(t: &mut KTestContext) {
+    let Ok(mut disk) = MountedRamdisk::format(1024 * 1024, "ktest_rw") else {
+        kassert!(t, false, "failed to mount the ram disk");
+        return;
+    };
+    let file = disk.open(
+        "data",
+        OPEN_MODE_READ | OPEN_MODE_WRITE | OPEN_MODE_CREATE,
+        0o644,
+    );
+    let Ok(mut file) = file else {
+        kassert!(t, false, "failed to create the file");
+        return;
+    };
+    // Ends 856 bytes into its 7th block
+    let mut reference = (0..7000u32)
+        .map(|i| (i * 7 % 251) as u8)
+        .collect::<Vec<_>>();
+    kassert!(t, matches!(file.write(&reference), Ok(7000)));
+
+    check_read(t, &file, &reference, 1020, 5000);
+    check_read(t, &file, &reference, 0, 7000);
+    check_read(t, &file, &reference, 1023, 2);
+    check_read(t, &file, &reference, 3, 1021);
+    // The end of the file in the middle of the buffer
+    check_read(t, &file, &reference, 6990, 100);
+    check_read(t, &file, &reference, 5000, 4096);
+    check_read(t, &file, &reference, 7000, 10);
+
+    // An unaligned write over 4 blocks, then small reads over the whole file
+    let patch = (0..3000u32)
+        .map(|i| (i % 13) as u8 + 200)
+        .collect::<Vec<_>>();
+    kassert!(t, file.seek(SeekPosition::FromStart(1500)).is_ok());
+    kassert!(t, matches!(file.write(&patch), Ok(3000)));
+    reference[1500..4500].copy_from_slice(&patch);
+    check_read(t, &file, &reference, 1020, 5000);
+
+    kassert!(t, file.seek(SeekPosition::FromStart(0)).is_ok());
+    let mut contents = Vec::new();
+    let mut buf = [0u8; 333];
+    while let Ok(read @ 1..) = file.read(&mut buf) {
+        contents.extend_from_slice(&buf[..read as usize]);
+    }
+    kassert!(
+        t,
+        contents == reference,
+        "{} bytes read back",
+        contents.len()
+    );
+
+    drop(file);
+    kassert!(t, disk.unmount().is_ok());
+}
+
+fn full_block_writes_without_seek(t: &mut KTestContext) {
+    let Ok(mut disk) = MountedRamdisk::format(1024 * 1024, "ktest_full") else {
+        kassert!(t, false, "failed to mount the ram disk");
+        return;
+    };
+    let file = disk.open(
+        "data",
+        OPEN_MODE_READ | OPEN_MODE_WRITE | OPEN_MODE_CREATE,
+        0o644,
+    );
+    let Ok(mut file) = file else {
+        kassert!(t, false, "failed to create the file");
+        return;
+    };
+    let mut reference = vec![0x11u8; 6000];
+    kassert!(t, matches!(file.write(&reference), Ok(6000)));
+
+    // The end of block 0, blocks 1 to 3 whole and the start of block 4, then more of block 4
+    // and a read after it, none of them seeking
+    let first = (0..3120u32).map(|i| (i % 241) as u8).collect::<Vec<_>>();
+    kassert!(t, file.seek(SeekPosition::FromStart(1000)).is_ok());
+    kassert!(t, matches!(file.write(&first), Ok(3120)));
+    reference[1000..4120].copy_from_slice(&first);
+    kassert!(t, matches!(file.write(&[0x22; 500]), Ok(500)));
+    reference[4120..4620].fill(0x22);
+    let mut buf = [0u8; 300];
+    kassert!(t, matches!(file.read(&mut buf), Ok(300)));
+    kassert!(t, buf[..] == reference[4620..4920]);
+
+    // Ends on a block boundary, the next write starts a new block
+    kassert!(t, file.seek(SeekPosition::FromStart(0)).is_ok());
+    kassert!(t, matches!(file.write(&[0x33; 3072]), Ok(3072)));
+    reference[..3072].fill(0x33);
+    kassert!(t, matches!(file.write(&[0x44; 10]), Ok(10)));
+    reference[3072..3082].fill(0x44);
+    kassert!(t, matches!(file.read(&mut buf), Ok(300)));
+    kassert!(t, buf[..] == reference[3082..3382]);
+
+    check_read(t, &file, &reference, 0, 6000);
+    drop(file);
+    kassert!(t, disk.unmount().is_ok());
+}
+
+fn stat_fields(t: &mut KTestContext) {
+    let Ok(mut disk) = MountedRamdisk::format(1024 * 1024, "ktest_stat") else {
+        kassert!(t, false, "failed to mount the ram disk");
+        return;
+    };
+
+    // Inode 11, 3000 bytes
+    let file = disk.open("file", OPEN_MODE_WRITE | OPEN_MODE_CREATE, 0o640);
+    kassert!(t, file.and_then(|mut file| file.write(&[7; 3000])).is_ok());
+    kassert!(t, File::mkdir(&disk.path("dir")).is_ok());
+    kassert!(t, disk.unmount().is_ok());
+
+    let Ok(mut volume) = open_volume(&disk.device) else {
+        kassert!(t, false, "failed to open the volume");
+        return;
+    };
+    let Ok(mut inode) = volume.get_inode(11, None) else {
+        kassert!(t, false, "failed to read inode 11");
+        return;
+    };
+    kassert!(t, inode.inode_type == InodeType::File);
+    inode.ctime = 1_000_000_000;
+    inode.mtime = 1_100_000_000;
+    inode.atime = 1_200_000_000;
+    inode.set_owner(70_000, 1_234);
+    kassert!(t, volume.update_inode(&inode).is_ok());
+    kassert!(t, volume.flush().is_ok());
+    drop(volume);
+
+    kassert!(t, disk.remount().is_ok());
+    let Ok(Some(stat)) = File::get_stats(&disk.path("file")) else {
+        kassert!(t, false, "no stats for the file");
+        return;
+    };
+    kassert_eq!(t, stat.size, 3000);
+    kassert_eq!(t, stat.created_at, 1_000_000_000);
+    kassert_eq!(t, stat.modified_at, 1_100_000_000);
+    kassert_eq!(t, stat.permissions, 0o640);
+    kassert_eq!(t, stat.owner_id, 70_000);
+    kassert_eq!(t, stat.group_id, 1_234);
+    kassert!(t, stat.is_file && !stat.is_directory && !stat.is_symlink);
+
+    match disk
+        .open("file", OPEN_MODE_READ, 0)
+        .and_then(|file| file.stats())
+    {
+        Ok(fstat) => {
+            kassert_eq!(t, fstat.size, stat.size);
+            kassert_eq!(t, fstat.created_at, stat.created_at);
+            kassert_eq!(t, fstat.modified_at, stat.modified_at);
+            kassert_eq!(t, fstat.owner_id, stat.owner_id);
+            kassert_eq!(t, fstat.group_id, stat.group_id);
+            kassert!(t, fstat.is_file && !fstat.is_directory);
+        }
+        Err(err) => kassert!(t, false, "fstat failed: {:?}", err),
+    }
+
+    // The size is the one on disk, not the one when the file was looked up
+    let file = disk.open("file", OPEN_MODE_WRITE | OPEN_MODE_APPEND, 0);
+    kassert!(t, file.and_then(|mut file| file.write(&[8; 2000])).is_ok());
+    let stat = File::get_stats(&disk.path("file"));
+    kassert!(t, matches!(stat, Ok(Some(FileStat { size: 5000, .. }))));
+
+    match File::get_stats(&disk.path("dir")) {
+        Ok(Some(stat)) => {
+            kassert!(t, stat.is_directory && !stat.is_file && !stat.is_symlink);
+            kassert_eq!(t, stat.size, 1024);
+        }
+        other => kassert!(t, false, "no stats for the directory: {:?}", other),
+    }
+    kassert!(t, disk.unmount().is_ok());
+}
+
+fn unsupported_ro_feature_opens(t: &mut KTestContext) {
+    let Ok(mut mounted) = MountedRamdisk::format(1024 * 1024, "ktest_ro") else {
+        kassert!(t, false, "failed to mount the ram disk");
+        return;
+    };
+
+    let file = mounted.open("file", OPEN_MODE_WRITE | OPEN_MODE_CREATE, 0o644);
+    kassert!(
+        t,
+        file.and_then(|mut file| file.write(b"read only")).is_ok()
+    );
+    kassert!(t, mounted.unmount().is_ok());
+
+    // A read-only compatible feature no driver knows about
+    let Ok(mut disk) = open_ramdisk(&mounted.device) else {
+        kassert!(t, false, "failed to open the ram disk");
+        return;
+    };
+    let offset = 1024 + core::mem::offset_of!(Superblock, readonly_or_support_features) as u64;
+    let mut features = [0u8; 4];
+    kassert!(t, disk.seek(SeekPosition::FromStart(offset)).is_ok());
+    kassert!(t, disk.read(&mut features).is_ok());
+    let features = (u32::from_le_bytes(features) | 1 << 31).to_le_bytes();
+    kassert!(t, disk.seek(SeekPosition::FromStart(offset)).is_ok());
+    kassert!(t, disk.write(&features).is_ok());
+    kassert!(t, disk.flush().is_ok());
+    drop(disk);
+
+    kassert!(t, mounted.remount().is_ok());
+    for mode in [
+        OPEN_MODE_WRITE,
+        OPEN_MODE_READ | OPEN_MODE_WRITE,
+        OPEN_MODE_WRITE | OPEN_MODE_APPEND,
+        OPEN_MODE_READ | OPEN_MODE_CREATE,
+    ] {
+        let file = mounted.open("file", mode, 0);
+        kassert!(
+            t,
+            matches!(file, Err(VfsError::ReadOnly)),
+            "mode {:#x} opened",
+            mode
+        );
+    }
+
+    let file = mounted.open("file", OPEN_MODE_READ, 0);
+    let mut buf = [0u8; 16];
+    match file.and_then(|file| file.read(&mut buf)) {
+        Ok(read) => kassert_eq!(t, &buf[..read as usize], b"read only"),
+        Err(err) => kassert!(t, false, "read failed: {:?}", err),
+    }
+    kassert!(t, mounted.unmount().is_ok());
+}
+
+fn directory_handle_streaming(t: &mut KTestContext) {
+    let Ok(mut disk) = MountedRamdisk::format(1024 * 1024, "ktest_dirent") else {
+        kassert!(t, false, "failed to mount the ram disk");
+        return;
+    };
+
+    // 36 bytes per entry, the directory spans three blocks
+    let names = (0..60)
+        .map(|i| format!("entry-with-a-long-name-{i:02}"))
+        .collect::<Vec<_>>();
+    for name in names.iter() {
+        let file = disk.open(name, OPEN_MODE_WRITE | OPEN_MODE_CREATE, 0o644);
+        kassert!(t, file.is_ok(), "failed to create {}", name);
+    }
+
+    let Ok(dir) = disk.open("", OPEN_MODE_READ, 0) else {
+        kassert!(t, false, "failed to open the directory");
+        return;
+    };
+    match read_records(&dir, 48) {
+        Ok(records) => {
+            kassert_eq!(t, records.len(), names.len() + 3);
+            kassert!(t, records.contains(&(2, DT_DIR, b".".to_vec())));
+            kassert!(t, records.contains(&(2, DT_DIR, b"..".to_vec())));
+            for name in names.iter() {
+                let found = records
+                    .iter()
+                    .any(|(_, kind, record)| *kind == DT_REG && record == name.as_bytes());
+                kassert!(t, found, "{} missing", name);
+            }
+        }
+        Err(err) => kassert!(t, false, "directory read failed: {:?}", err),
+    }
+
+    // A position the handle gave resumes at the same entry
+    kassert!(t, dir.seek(SeekPosition::FromStart(0)).is_ok());
+    let mut buf = [0u8; 512];
+    kassert!(t, dir.read(&mut buf).is_ok());
+    let Ok(position) = dir.seek(SeekPosition::FromCurrent(0)) else {
+        kassert!(t, false, "no position in the directory");
+        return;
+    };
+    let rest = read_records(&dir, 100);
+    kassert!(t, dir.seek(SeekPosition::FromStart(position)).is_ok());
+    let again = read_records(&dir, 1000);
+    kassert!(t, rest.is_ok() && rest.ok() == again.ok());
+
+    kassert!(t, File::mkdir(&disk.path("late")).is_ok());
+    kassert!(t, dir.seek(SeekPosition::FromStart(0)).is_ok());
+    match read_records(&dir, 4096) {
+        Ok(records) => {
+            kassert_eq!(t, records.len(), names.len() + 4);
+            kassert!(
+                t,
+                records
+                    .iter()
+                    .any(|(_, kind, name)| *kind == DT_DIR && name == b"late")
+            );
+        }
+        Err(err) => kassert!(t, false, "directory reread failed: {:?}", err),
+    }
+    drop(dir);
+    kassert!(t, disk.unmount().is_ok());
+}
+
+/// A media change under a mounted volume stops the writes instead of spreading the old state
+/// over the new media
+fn stale_device_goes_read_only(t: &mut KTestContext) {
+    let Ok(mut mounted) = MountedRamdisk::format(1024 * 1024, "ktest_stale") else {
+        kassert!(t, false, "failed to mount the ram disk");
+        return;
+    };
+    let create = |name: &str| mounted.open(name, OPEN_MODE_WRITE | OPEN_MODE_CREATE, 0o644);
+    let Some(device) = open_ramdisk(&mounted.device)
+        .ok()
+        .and_then(|disk| disk.get_vfs_file().get_block_device())
+    else {
+        kassert!(t, false, "failed to get the ram disk device");
+        return;
+    };
+    kassert!(t, create("before").is_ok());
+
+    match (**device.write()).as_any_mut().downcast_mut::<RamDisk>() {
+        Some(disk) => disk.bump_generation(),
+        None => kassert!(t, false, "the device isn't the ram disk"),
+    }
+    kassert!(t, create("after").is_err());
+    let again = create("again");
+    kassert!(
+        t,
+        matches!(again, Err(VfsError::ReadOnly)),
+        "{:?}",
+        again.err()
+    );
+    drop(device);
+    kassert!(t, mounted.unmount().is_ok());
+}
@@ -0,0 +1,131 @@
+//! Boot-time self tests, for code that `cargo test` can't reach in a no_std kernel
+
+use alloc::{string::String, vec::Vec};
+
+use crate::{
+    io::{qemu_exit, QEMU_EXIT_FAILURE, QEMU_EXIT_SUCCESS},
+    log_error, log_info,
+};
+
+pub mod data;
+pub mod ext2;
+pub mod net;
+pub mod vfs;
+
+pub struct KTest {
+    pub name: &'static str,
+    pub run: fn(&mut KTestContext),
+}
+
+/// Collects the failed assertions of a test, so a test keeps running after one fails
+#[derive(Debug, Default)]
+pub struct KTestContext {
+    failures: Vec<String>,
+}
+
+impl KTestContext {
+    pub fn fail(&mut self, file: &'static str, line: u32, message: String) {
+        log_error!(target: "ktest", "    {}:{}: {}", file, line, message);
+        self.failures.push(message);
+    }
+
+    pub fn failed(&self) -> bool {
+        !self.failures.is_empty()
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct KTestSummary {
+    pub passed: usize,
+    pub failed: usize,
+}
+
+/// `ktest!(name, function)`, or `ktest!(function)` to name the test after the function
+#[macro_export]
+macro_rules! ktest {
+    ($name:ident, $run:path) => {
+        $crate::ktest::KTest {
+            name: concat!(module_path!(), "::", stringify!($name)),
+            run: $run,
+        }
+    };
+    ($run:ident) => {
+        $crate::ktest!($run, $run)
+    };
+}
+
+#[macro_export]
+macro_rules! kassert {
+    ($ctx:expr, $cond:expr $(,)?) => {
+        $crate::kassert!($ctx, $cond, "assertion failed: {}", stringify!($cond))
+    };
+    ($ctx:expr, $cond:expr, $($arg:tt)+) => {
+        if !$cond {
+            $ctx.fail(file!(), line!(), $crate::alloc::format!($($arg)+));
+        }
+    };
+}
+
+#[macro_export]
+macro_rules! kassert_eq {
+    ($ctx:expr, $left:expr, $right:expr $(,)?) => {
+        match (&$left, &$right) {
+            (left, right) => {
+                if *left != *right {
+                    $ctx.fail(
+                        file!(),
+                        line!(),
+                        $crate::alloc::format!(
+                            "{} == {}: {:?} != {:?}",
+                            stringify!($left),
+                            stringify!($right),
+                            left,
+                            right
+                        ),
+                    );
+                }
+            }
+        }
+    };
+}
+
+const KTEST_SUITES: &[&[KTest]] = &[data::KTESTS, vfs::KTESTS, ext2::KTESTS, net::KTESTS];
+
+pub fn run_all() -> KTestSummary {
+    let mut summary = KTestSummary::default();
+    for test in KTEST_SUITES.iter().flat_map(|suite| suite.iter()) {
+        let mut ctx = KTestContext::default();
+        (test.run)(&mut ctx);
+        if ctx.failed() {
+            log_error!(target: "ktest", "FAIL {}", test.name);
+            summary.failed += 1;
+        } else {
+            log_info!(target: "ktest", "ok   {}", test.name);
+            summary.passed += 1;
+        }
+    }
+    log_info!(
+        target: "ktest",
+        "{} passed, {} failed",
+        summary.passed,
+        summary.failed
+    );
+    summary
+}
+
+/// Exits qemu with a pass/fail code, see `io::qemu_exit`
+pub fn exit_qemu(summary: &KTestSummary) {
+    qemu_exit(if summary.failed == 0 {
+        QEMU_EXIT_SUCCESS
+    } else {
+        QEMU_EXIT_FAILURE
+    });
+}
+
+/// Recurses until the kernel stack runs out, the fault handlers should print a dump and panic
+#[allow(unconditional_recursion)]
+#[inline(never)]
+pub fn overflow_kernel_stack(depth: u64) -> u64 {
+    let frame = core::hint::black_box([depth; 64]);
+    overflow_kernel_stack(depth + 1) + frame[0]
+}
@@ -0,0 +1,361 @@
+use alloc::{boxed::Box, collections::VecDeque, string::String, sync::Arc, vec::Vec};
+use spin::Mutex;
+
+use crate::{
+    kassert, kassert_eq, ktest,
+    net::{
+        arp::{ArpOperation, ArpPacket},
+        checksum::internet_checksum,
+        dhcp::{dhcp_status, stop_dhcp, DhcpState, DHCP_CLIENT_PORT, DHCP_SERVER_PORT},
+        ethernet::{
+            EthernetDevice, EthernetInterface, MacAddr, ETHERNET_HEADER_SIZE, ETHERTYPE_ARP,
+            ETHERTYPE_IPV4,
+        },
+        icmp::{ICMP_ECHO_REPLY, ICMP_ECHO_REQUEST},
+        interface::{register_interface, unregister_interface, Ipv4Config},
+        ipv4::{Ipv4Addr, Ipv4Header, IP_PROTOCOL_ICMP, IP_PROTOCOL_UDP},
+        poll, poll_interfaces, register_ethernet_interface, NetError,
+    },
+};
+
+use super::{KTest, KTestContext};
+
+pub const KTESTS: &[KTest] = &[ktest!(icmp_echo_reply), ktest!(dhcp_lease)];
+
+const NIC_MAC: MacAddr = MacAddr([0x52, 0x54, 0x00, 0x4B, 0x54, 0x01]);
+const PEER_MAC: MacAddr = MacAddr([0x52, 0x54, 0x00, 0x4B, 0x54, 0x02]);
+
+#[derive(Debug, Default)]
+struct TestNicQueues {
+    rx: VecDeque<Vec<u8>>,
+    tx: VecDeque<Vec<u8>>,
+}
+
+/// A NIC whose wire is a pair of queues, the test plays the rest of the network
+#[derive(Debug)]
+struct TestNic {
+    queues: Arc<Mutex<TestNicQueues>>,
+}
+
+impl EthernetDevice for TestNic {
+    fn mac(&self) -> MacAddr {
+        NIC_MAC
+    }
+
+    fn send_frame(&mut self, frame: &[u8]) -> Result<(), NetError> {
+        self.queues.lock().tx.push_back(frame.to_vec());
+        Ok(())
+    }
+
+    fn receive_frame(&mut self) -> Option<Vec<u8>> {
+        self.queues.lock().rx.pop_front()
+    }
+}
+
+fn test_nic() -> (Box<TestNic>, Arc<Mutex<TestNicQueues>>) {
+    let queues = Arc::new(Mutex::new(TestNicQueues::default()));
+    let nic = Box::new(TestNic {
+        queues: queues.clone(),
+    });
+    (nic, queues)
+}
+
+fn ethernet_frame(dst: MacAddr, ethertype: u16, payload: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(ETHERNET_HEADER_SIZE + payload.len());
+    frame.extend_from_slice(&dst.0);
+    frame.extend_from_slice(&PEER_MAC.0);
+    frame.extend_from_slice(&ethertype.to_be_bytes());
+    frame.extend_from_slice(payload);
+    frame
+}
+
+fn ipv4_packet(src: Ipv4Addr, dst: Ipv4Addr, protocol: u8, payload: &[u8]) -> Vec<u8> {
+    let mut packet = alloc::vec![0x45, 0];
+    packet.extend_from_slice(&((20 + payload.len()) as u16).to_be_bytes());
+    packet.extend_from_slice(&[0, 0, 0, 0, 64, protocol, 0, 0]);
+    packet.extend_from_slice(&src.0);
+    packet.extend_from_slice(&dst.0);
+    let checksum = internet_checksum(&packet);
+    packet[10..12].copy_from_slice(&checksum.to_be_bytes());
+    packet.extend_from_slice(payload);
+    packet
+}
+
+/// Feeds `frame` to the interfaces, returns the frames they sent in response
+fn exchange(queues: &Mutex<TestNicQueues>, frame: Vec<u8>) -> Vec<Vec<u8>> {
+    queues.lock().rx.push_back(frame);
+    poll_interfaces();
+    queues.lock().tx.drain(..).collect()
+}
+
+/// The destination, ethertype and payload of a sent frame
+fn split_frame(frame: &[u8]) -> (MacAddr, u16, &[u8]) {
+    (
+        MacAddr(frame[0..6].try_into().unwrap()),
+        u16::from_be_bytes([frame[12], frame[13]]),
+        &frame[ETHERNET_HEADER_SIZE..],
+    )
+}
+
+/// The value of DHCP option `code` in a BOOTP message
+fn dhcp_option(bootp: &[u8], code: u8) -> Option<&[u8]> {
+    let mut options = bootp.get(240..)?;
+    while let [option, rest @ ..] = options {
+        match *option {
+            0 => options = rest,
+            255 => return None,
+            _ => {
+                let (len, rest) = rest.split_first()?;
+                let value = rest.get(..*len as usize)?;
+                if *option == code {
+                    return Some(value);
+                }
+                options = &rest[*len as usize..];
+            }
+        }
+    }
+    None
+}
+
+/// The BOOTP message of a sent DHCP frame, after checking its addressing
+fn sent_dhcp_message<'a>(t: &mut KTestContext, frame: &'a [u8]) -> Option<&'a [u8]> {
+    let (dst, ethertype, packet) = split_frame(frame);
+    kassert_eq!(t, dst, MacAddr::BROADCAST);
+    kassert_eq!(t, ethertype, ETHERTYPE_IPV4);
+    let header = Ipv4Header::parse(packet)?;
+    kassert_eq!(t, header.src, Ipv4Addr::UNSPECIFIED);
+    kassert_eq!(t, header.dst, Ipv4Addr::BROADCAST);
+    kassert_eq!(t, header.protocol, IP_PROTOCOL_UDP);
+    let segment = &packet[header.header_len..header.total_len];
+    kassert_eq!(t, segment[0..2], DHCP_CLIENT_PORT.to_be_bytes());
+    kassert_eq!(t, segment[2..4], DHCP_SERVER_PORT.to_be_bytes());
+    let bootp = &segment[8..];
+    kassert_eq!(t, bootp[0], 1);
+    kassert_eq!(t, bootp[28..34], NIC_MAC.0);
+    Some(bootp)
+}
+
+/// A server reply of type `kind` offering `address`, broadcast like for a client without one
+fn dhcp_reply(kind: u8, xid: &[u8], server: Ipv4Addr, address: Ipv4Addr) -> Vec<u8> {
+    let mut bootp = alloc::vec![0u8; 236];
+    bootp[0..3].copy_from_slice(&[2, 1, 6]);
+    bootp[4..8].copy_from_slice(xid);
+    bootp[16..20].copy_from_slice(&address.0);
+    bootp[28..34].copy_from_slice(&NIC_MAC.0);
+    bootp.extend_from_slice(&[99, 130, 83, 99]);
+    bootp.extend_from_slice(&[53, 1, kind]);
+    bootp.extend_from_slice(&[54, 4]);
+    bootp.extend_from_slice(&server.0);
+    bootp.extend_from_slice(&[1, 4, 255, 255, 255, 0]);
+    bootp.extend_from_slice(&[3, 4]);
+    bootp.extend_from_slice(&server.0);
+    bootp.extend_from_slice(&[6, 8, 1, 1, 1, 1]);
+    bootp.extend_from_slice(&server.0);
+    bootp.extend_from_slice(&[51, 4]);
+    bootp.extend_from_slice(&3600u32.to_be_bytes());
+    bootp.push(255);
+
+    // A zero UDP checksum means the server didn't compute one
+    let mut segment = Vec::with_capacity(8 + bootp.len());
+    segment.extend_from_slice(&DHCP_SERVER_PORT.to_be_bytes());
+    segment.extend_from_slice(&DHCP_CLIENT_PORT.to_be_bytes());
+    segment.extend_from_slice(&((8 + bootp.len()) as u16).to_be_bytes());
+    segment.extend_from_slice(&[0, 0]);
+    segment.extend_from_slice(&bootp);
+    ethernet_frame(
+        MacAddr::BROADCAST,
+        ETHERTYPE_IPV4,
+        &ipv4_packet(server, Ipv4Addr::BROADCAST, IP_PROTOCOL_UDP, &segment),
+    )
+}
+
+/// Feeds `frame` to the interfaces and runs the DHCP clients, returns the frames sent
+fn exchange_dhcp(queues: &Mutex<TestNicQueues>, frame: Vec<u8>) -> Vec<Vec<u8>> {
+    queues.lock().rx.push_back(frame);
+    poll();
+    queues.lock().tx.drain(..).collect()
+}
+
+fn icmp_echo_reply(t: &mut KTestContext) {
+    let local = Ipv4Addr([10, 99, 0, 2]);
+    let peer = Ipv4Addr([10, 99, 0, 1]);
+    let (nic, queues) = test_nic();
+    let iface = register_interface(Box::new(EthernetInterface::new(
+        String::from("ktest0"),
+        nic,
+        Some(Ipv4Config {
+            address: local,
+            prefix_len: 24,
+            gateway: None,
+        }),
+    )));
+
+    // The peer resolves us first, which also teaches us its address
+    let request = ArpPacket {
+        operation: ArpOperation::Request,
+        sender_mac: PEER_MAC,
+        sender_ip: peer,
+        target_mac: MacAddr::ZERO,
+        target_ip: local,
+    };
+    let sent = exchange(
+        &queues,
+        ethernet_frame(MacAddr::BROADCAST, ETHERTYPE_ARP, &request.to_bytes()),
+    );
+    kassert_eq!(t, sent.len(), 1);
+    if let Some(frame) = sent.first() {
+        let (dst, ethertype, payload) = split_frame(frame);
+        kassert_eq!(t, dst, PEER_MAC);
+        kassert_eq!(t, ethertype, ETHERTYPE_ARP);
+        let reply = ArpPacket::parse(payload);
+        kassert!(t, reply.is_some());
+        if let Some(reply) = reply {
+            kassert_eq!(t, reply.operation, ArpOperation::Reply);
+            kassert_eq!(t, reply.sender_mac, NIC_MAC);
+            kassert_eq!(t, reply.sender_ip, local);
+            kassert_eq!(t, reply.target_mac, PEER_MAC);
+            kassert_eq!(t, reply.target_ip, peer);
+        }
+    }
+
+    let data = b"ktest echo";
+    let mut echo = alloc::vec![ICMP_ECHO_REQUEST, 0, 0, 0, 0x12, 0x34, 0x00, 0x07];
+    echo.extend_from_slice(data);
+    let checksum = internet_checksum(&echo);
+    echo[2..4].copy_from_slice(&checksum.to_be_bytes());
+
+    let sent = exchange(
+        &queues,
+        ethernet_frame(
+            NIC_MAC,
+            ETHERTYPE_IPV4,
+            &ipv4_packet(peer, local, IP_PROTOCOL_ICMP, &echo),
+        ),
+    );
+    kassert_eq!(t, sent.len(), 1);
+    if let Some(frame) = sent.first() {
+        let (dst, ethertype, packet) = split_frame(frame);
+        kassert_eq!(t, dst, PEER_MAC);
+        kassert_eq!(t, ethertype, ETHERTYPE_IPV4);
+        let header = Ipv4Header::parse(packet);
+        kassert!(t, header.is_some());
+        if let Some(header) = header {
+            kassert_eq!(t, header.src, local);
+            kassert_eq!(t, header.dst, peer);
+            kassert_eq!(t, header.protocol, IP_PROTOCOL_ICMP);
+            let reply = &packet[header.header_len..header.total_len];
+            kassert_eq!(t, internet_checksum(reply), 0);
+            kassert_eq!(t, reply[..2], [ICMP_ECHO_REPLY, 0]);
+            // Same identifier, sequence and data as the request
+            kassert_eq!(t, reply[4..], echo[4..]);
+        }
+    }
+
+    // Requests with a bad checksum, or for another host, are not answered
+    let mut corrupted = echo.clone();
+    corrupted[8] ^= 1;
+    let sent = exchange(
+        &queues,
+        ethernet_frame(
+            NIC_MAC,
+            ETHERTYPE_IPV4,
+            &ipv4_packet(peer, local, IP_PROTOCOL_ICMP, &corrupted),
+        ),
+    );
+    kassert!(t, sent.is_empty());
+    let sent = exchange(
+        &queues,
+        ethernet_frame(
+            NIC_MAC,
+            ETHERTYPE_IPV4,
+            &ipv4_packet(peer, Ipv4Addr([10, 99, 0, 3]), IP_PROTOCOL_ICMP, &echo),
+        ),
+    );
+    kassert!(t, sent.is_empty());
+
+    unregister_interface(&iface);
+}
+
+fn dhcp_lease(t: &mut KTestContext) {
+    const DHCP_OFFER: u8 = 2;
+    const DHCP_ACK: u8 = 5;
+    let server = Ipv4Addr([10, 98, 0, 1]);
+    let offered = Ipv4Addr([10, 98, 0, 50]);
+    let (nic, queues) = test_nic();
+    // Registering the interface of a NIC starts its DHCP client
+    let iface = register_ethernet_interface(String::from("ktest1"), nic);
+
+    poll();
+    let sent: Vec<Vec<u8>> = queues.lock().tx.drain(..).collect();
+    kassert_eq!(t, sent.len(), 1);
+    let Some(discover) = sent.first().and_then(|f| sent_dhcp_message(t, f)) else {
+        t.fail(file!(), line!(), String::from("no DHCPDISCOVER was sent"));
+        stop_dhcp(&iface);
+        unregister_interface(&iface);
+        return;
+    };
+    kassert_eq!(t, dhcp_option(discover, 53), Some(&[1][..]));
+    // Broadcast flag, we can't receive unicast yet
+    kassert_eq!(t, discover[10] & 0x80, 0x80);
+    let xid: [u8; 4] = discover[4..8].try_into().unwrap();
+    kassert_eq!(
+        t,
+        dhcp_status("ktest1").map(|(state, _)| state),
+        Some(DhcpState::Selecting)
+    );
+
+    let sent = exchange_dhcp(&queues, dhcp_reply(DHCP_OFFER, &xid, server, offered));
+    kassert_eq!(t, sent.len(), 1);
+    if let Some(request) = sent.first().and_then(|f| sent_dhcp_message(t, f)) {
+        kassert_eq!(t, request[4..8], xid);
+        kassert_eq!(t, request[12..16], [0; 4]);
+        kassert_eq!(t, dhcp_option(request, 53), Some(&[3][..]));
+        kassert_eq!(t, dhcp_option(request, 50), Some(&offered.0[..]));
+        kassert_eq!(t, dhcp_option(request, 54), Some(&server.0[..]));
+    }
+    kassert_eq!(
+        t,
+        dhcp_status("ktest1").map(|(state, lease)| (state, lease.is_some())),
+        Some((DhcpState::Requesting, false))
+    );
+
+    // Replies to another transaction are ignored
+    let other_xid = u32::from_be_bytes(xid).wrapping_add(1).to_be_bytes();
+    let sent = exchange_dhcp(&queues, dhcp_reply(DHCP_ACK, &other_xid, server, offered));
+    kassert!(t, sent.is_empty());
+    kassert_eq!(t, iface.read().ipv4_config(), None);
+
+    let sent = exchange_dhcp(&queues, dhcp_reply(DHCP_ACK, &xid, server, offered));
+    kassert!(t, sent.is_empty());
+    let status = dhcp_status("ktest1");
+    kassert_eq!(
+        t,
+        status.as_ref().map(|(state, _)| *state),
+        Some(DhcpState::Bound)
+    );
+    if let Some((_, Some(lease))) = status {
+        kassert_eq!(t, lease.address, offered);
+        kassert_eq!(t, lease.prefix_len, 24);
+        kassert_eq!(t, lease.router, Some(server));
+        kassert_eq!(t, lease.dns, [Ipv4Addr([1, 1, 1, 1]), server]);
+        kassert_eq!(t, lease.server, server);
+        kassert_eq!(t, lease.lease_secs, Some(3600));
+        // T1 and T2 default to 1/2 and 7/8 of the lease
+        kassert!(t, lease.renew_at() < lease.rebind_at());
+        kassert!(t, lease.rebind_at() < lease.expires_at());
+    } else {
+        t.fail(file!(), line!(), String::from("no lease after DHCPACK"));
+    }
+    kassert_eq!(
+        t,
+        iface.read().ipv4_config(),
+        Some(Ipv4Config {
+            address: offered,
+            prefix_len: 24,
+            gateway: Some(server),
+        })
+    );
+
+    stop_dhcp(&iface);
+    unregister_interface(&iface);
+}
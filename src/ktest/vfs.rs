@@ -0,0 +1,2080 @@
+use core::{
+    cell::RefCell,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use alloc::{boxed::Box, collections::BTreeSet, format, sync::Arc, vec, vec::Vec};
+
+use crate::{
+    config::get_kernel_config,
+    data::{
+        file::File,
+        path::{VfsName, VfsPath},
+        permissions::{Credentials, Permissions},
+        try_alloc_boxed_slice, try_alloc_boxed_slice_or_reclaim,
+    },
+    drivers::{
+        disk::{
+            loop_device::{attach_loop, detach_loop},
+            queue::{BlockRequest, BlockRequestKind, RequestQueue},
+            ramdisk::RamDisk,
+        },
+        fs::{
+            dirent::{DirectoryRecord, DT_DIR, DT_REG},
+            initramfs::unpack_cpio,
+            lock::{FileLockKind, FileLocks},
+            mount::{mount_device, parse_mount_table, MountEntry, MountError, MountOptions},
+            virt::{
+                devfs::{
+                    with_devfs, CharacterDeviceProvider, DevFs, DevFsDriver, DevFsHook,
+                    DevFsHookKind, VirtualDeviceFileProvider, DEVFS_MAGIC,
+                },
+                files::dev_pci,
+                pipefs::Pipe,
+                procfs::PROCFS_MAGIC,
+                tmpfs::{TmpFs, TMPFS_MAGIC},
+            },
+        },
+        pci::{self, PciDevice},
+        vfs::{
+            arcrwb_new_from_box, copy_file_data, copy_through_buffer, get_vfs, max_copy_chunk,
+            open_path_handles, set_max_copy_chunk, split_parent, BlockDevice,
+            BlockDeviceAsCharacterDevice, CharacterDevice, CopyEnd, FileHandleAllocator, FileStat,
+            FileSystem, FsSpecificFileData, PipeMode, SeekPosition, SubBlockDevice, Vfs, VfsError,
+            VfsFile, VfsFileKind, COPY_BUFFER_SIZE, MS_RDONLY, OPEN_MODE_READ, OPEN_MODE_WRITE,
+        },
+    },
+    formats::elf::{AuxValue, Elf64File, Elf64ProgramHeaderRaw, ElfError, AT_BASE, AT_ENTRY},
+    formats::flat::{FlatBinary, FlatHeader},
+    interrupts::handlers::syscall::linux::{
+        vfs_err_to_linux_errno, EACCES, EAGAIN, EBADF, EBUSY, EEXIST, EFBIG, EINTR, EINVAL, EIO,
+        EISDIR, ELOOP, ENAMETOOLONG, ENODATA, ENOENT, ENOMEM, ENOSPC, ENOTDIR, ENOTEMPTY, ENOTTY,
+        ENXIO, EPERM, EPIPE, EROFS, ESPIPE,
+    },
+    kassert, kassert_eq, ktest, log_info,
+    memory::mem::{get_memory_stats, set_heap_limit},
+    paging::DIRECT_MAPPING_OFFSET,
+    percpu::get_per_cpu,
+    process::{
+        executable::ExecutableInstantiateOptions, io::file_table::OpenFile, proc::TaskState,
+        scheduler::SCHEDULER,
+    },
+};
+
+use super::{
+    data::{build_cpio, build_elf, build_flat},
+    KTest, KTestContext,
+};
+
+pub const KTESTS: &[KTest] = &[
+    ktest!(path_components),
+    ktest!(path_components_back),
+    ktest!(path_split_parent),
+    ktest!(pipe_wraparound),
+    ktest!(copy_buffered),
+    ktest!(copy_between_pipes),
+    ktest!(pipefs_lookup_through_mount_point),
+    ktest!(listed_names_are_final),
+    ktest!(directory_handles),
+    ktest!(file_lock_conflicts),
+    ktest!(block_device_char_access),
+    ktest!(character_device_kinds),
+    ktest!(sub_block_device_offset),
+    ktest!(stale_device_generation),
+    ktest!(request_queue_order),
+    ktest!(scratch_disk_high_lba),
+    ktest!(pata_handle_modes),
+    ktest!(devfs_hooks_by_key),
+    ktest!(file_handle_checks),
+    ktest!(vfs_error_errnos),
+    ktest!(walk_procfs),
+    ktest!(procfs_cpu_stats),
+    ktest!(process_teardown_releases_everything),
+    ktest!(allocation_failures),
+    ktest!(mount_table_parsing),
+    ktest!(open_path_handle_count),
+    ktest!(bind_mount_procfs),
+    ktest!(statfs_virtual),
+    ktest!(read_only_bind_mount),
+    ktest!(mount_device_checks),
+    ktest!(initramfs_unpack),
+    ktest!(symlink_resolution),
+    ktest!(elf_interpreter_loading),
+    ktest!(memory_devices),
+    ktest!(devfs_pci_tree),
+    ktest!(loop_device_attach),
+];
+
+fn path_components(t: &mut KTestContext) {
+    let path = VfsPath::from("//a/bc//d/");
+    let mut components = path.components();
+    kassert_eq!(t, components.next().map(|n| n.as_bytes()), Some(&b"a"[..]));
+    kassert_eq!(t, components.next().map(|n| n.as_bytes()), Some(&b"bc"[..]));
+    kassert!(t, !components.is_done());
+    kassert_eq!(t, components.rest(), b"//d/");
+    kassert_eq!(t, components.next().map(|n| n.as_bytes()), Some(&b"d"[..]));
+    kassert!(t, components.is_done());
+    kassert!(t, components.next().is_none());
+
+    kassert!(t, VfsPath::from("/").components().is_done());
+    kassert!(t, VfsPath::new().is_root());
+}
+
+fn path_components_back(t: &mut KTestContext) {
+    let path = VfsPath::from("a/b/c/");
+    let mut components = path.components();
+
+    kassert_eq!(
+        t,
+        components.next_back().map(|n| n.as_bytes()),
+        Some(&b"c"[..])
+    );
+    kassert_eq!(t, components.next().map(|n| n.as_bytes()), Some(&b"a"[..]));
+    kassert_eq!(
+        t,
+        components.next_back().map(|n| n.as_bytes()),
+        Some(&b"b"[..])
+    );
+    kassert!(t, components.next().is_none());
+    kassert!(t, components.next_back().is_none());
+}
+
+fn path_split_parent(t: &mut KTestContext) {
+    let chars = |s: &str| s.chars().collect::<Vec<char>>();
+
+    let path = chars("/a/bc/d");
+    kassert_eq!(t, split_parent(&path), Some((&path[..5], &['d'][..])));
+
+    let path = chars("/a//");
+    kassert_eq!(t, split_parent(&path), Some((&[][..], &['a'][..])));
+
+    let path = chars("file");
+    kassert_eq!(t, split_parent(&path), Some((&[][..], &path[..])));
+
+    kassert_eq!(t, split_parent(&chars("//")), None);
+    kassert_eq!(t, split_parent(&[]), None);
+}
+
+fn pipe_wraparound(t: &mut KTestContext) {
+    let mut pipe = Pipe::new_anonymous(8);
+    kassert!(t, pipe.is_empty());
+    kassert_eq!(t, pipe.write(b"abcde"), 5);
+
+    let mut buf = [0u8; 8];
+    kassert_eq!(t, pipe.read(&mut buf[..3]), 3);
+    kassert_eq!(t, &buf[..3], b"abc");
+
+    // Goes past the end of the buffer and fills it
+    kassert_eq!(t, pipe.write(b"fghijkl"), 6);
+    kassert!(t, pipe.is_full());
+    kassert_eq!(t, pipe.write(b"x"), 0);
+    kassert_eq!(t, pipe.readable_bytes(), 8);
+
+    kassert_eq!(t, pipe.read(&mut buf), 8);
+    kassert_eq!(t, &buf, b"defghijk");
+    kassert!(t, pipe.is_empty());
+    kassert_eq!(t, pipe.read(&mut buf), 0);
+    kassert_eq!(t, pipe.writable_bytes(), 8);
+}
+
+fn copy_buffered(t: &mut KTestContext) {
+    let src = (0..COPY_BUFFER_SIZE * 2)
+        .map(|i| (i % 251) as u8)
+        .collect::<Vec<u8>>();
+    let limit = COPY_BUFFER_SIZE + 100;
+    let mut dst = Vec::new();
+    let read = |src: &mut (&[u8], &mut Vec<u8>), done: u64, buf: &mut [u8]| {
+        let data = &src.0[(done as usize).min(src.0.len())..];
+        let len = data.len().min(buf.len());
+        buf[..len].copy_from_slice(&data[..len]);
+        Ok(len as u64)
+    };
+    // The destination stops growing after `limit` bytes, the copy ends with a short count
+    let copied = copy_through_buffer(
+        &mut (&src[..], &mut dst),
+        u64::MAX,
+        read,
+        |context, _, buf| {
+            let len = buf.len().min(limit - context.1.len());
+            context.1.extend_from_slice(&buf[..len]);
+            Ok(len as u64)
+        },
+    );
+    kassert_eq!(t, copied.ok(), Some(limit as u64));
+    kassert!(t, dst[..] == src[..limit]);
+
+    let failing = |_: &mut (), done: u64, _: &[u8]| match done {
+        0 => Ok(COPY_BUFFER_SIZE as u64),
+        _ => Err(VfsError::OutOfSpace),
+    };
+    let zeros = |_: &mut (), _: u64, buf: &mut [u8]| Ok(buf.len() as u64);
+    let copied = copy_through_buffer(&mut (), u64::MAX, zeros, failing);
+    kassert_eq!(t, copied.ok(), Some(COPY_BUFFER_SIZE as u64));
+    let copied = copy_through_buffer(&mut (), 10, zeros, |_, _, _| Err(VfsError::OutOfSpace));
+    kassert!(t, matches!(copied, Err(VfsError::OutOfSpace)));
+}
+
+fn copy_between_pipes(t: &mut KTestContext) {
+    let pipes = unsafe { (Pipe::create_raw_fds(), Pipe::create_raw_fds()) };
+    let (Ok((_, a_read, a_write, fs)), Ok((_, b_read, b_write, _))) = pipes else {
+        kassert!(t, false, "pipe creation failed");
+        return;
+    };
+    kassert_eq!(t, fs.write().fwrite(a_write, b"hello").ok(), Some(5));
+
+    let previous_chunk = max_copy_chunk();
+    set_max_copy_chunk(3);
+    let copy = || {
+        copy_file_data(
+            CopyEnd {
+                fs: &fs,
+                handle: a_read,
+                offset: None,
+            },
+            CopyEnd {
+                fs: &fs,
+                handle: b_write,
+                offset: None,
+            },
+            100,
+        )
+    };
+    kassert_eq!(t, copy().ok(), Some(3));
+    kassert_eq!(t, copy().ok(), Some(2));
+    set_max_copy_chunk(previous_chunk);
+
+    let mut buf = [0; 8];
+    kassert_eq!(t, fs.write().fread(b_read, &mut buf).ok(), Some(5));
+    kassert_eq!(t, &buf[..5], b"hello");
+    for handle in [a_read, a_write, b_read, b_write] {
+        let _ = fs.write().fclose(handle);
+    }
+}
+
+fn pipefs_lookup_through_mount_point(t: &mut KTestContext) {
+    let Ok((id, _reader, _writer)) = Pipe::create() else {
+        kassert!(t, false, "pipe creation failed");
+        return;
+    };
+    let is_read_end = |file: &VfsFile| {
+        matches!(
+            file.kind(),
+            VfsFileKind::Pipe { mode: PipeMode::Read, pipe_id, .. } if *pipe_id == id
+        )
+    };
+    let vfs = get_vfs();
+    let mut guard = vfs.write();
+
+    let path = VfsPath::from_bytes(alloc::format!("/pipes/{id}/r").into_bytes());
+    match FileSystem::get_file(&mut **guard, &path) {
+        Ok(file) => kassert!(t, is_read_end(&file), "{:?}", file),
+        Err(err) => kassert!(t, false, "get_file failed: {:?}", err),
+    }
+
+    let id_name = alloc::format!("{id}");
+    let mount_point = guard
+        .get_root()
+        .and_then(|root| guard.get_child(&root, VfsName::new(b"pipes").unwrap()));
+    let Ok(mount_point) = mount_point else {
+        kassert!(t, false, "no /pipes mount point: {:?}", mount_point);
+        return;
+    };
+    kassert!(t, mount_point.is_mount_point());
+    let stats = FileSystem::get_stats(&mut **guard, &mount_point);
+    kassert!(
+        t,
+        stats.as_ref().is_ok_and(|s| s.is_directory),
+        "{:?}",
+        stats
+    );
+
+    let end = guard
+        .get_child(&mount_point, VfsName::new(id_name.as_bytes()).unwrap())
+        .and_then(|dir| guard.get_child(&dir, VfsName::new(b"r").unwrap()));
+    match end {
+        Ok(file) => kassert!(t, is_read_end(&file), "{:?}", file),
+        Err(err) => kassert!(t, false, "get_child failed: {:?}", err),
+    }
+}
+
+fn listed_names_are_final(t: &mut KTestContext) {
+    let Ok((id, _reader, _writer)) = Pipe::create() else {
+        kassert!(t, false, "pipe creation failed");
+        return;
+    };
+    let pipe_dir = alloc::format!("/pipes/{id}");
+    for dir in ["/", "/system", "/dev/net", &pipe_dir] {
+        let entries = match File::list_directory(dir) {
+            Ok(entries) => entries,
+            Err(VfsError::PathNotFound) => {
+                log_info!(target: "ktest", "    {} not found, skipped", dir);
+                continue;
+            }
+            Err(err) => {
+                kassert!(t, false, "listing {} failed: {:?}", dir, err);
+                continue;
+            }
+        };
+        kassert!(t, !entries.is_empty(), "{} is empty", dir);
+        for entry in entries {
+            let name = entry.get_vfs_file().name();
+            let display = name.iter().collect::<alloc::string::String>();
+            kassert!(
+                t,
+                !name.is_empty() && !name.contains(&'/'),
+                "{:?} in {}",
+                display,
+                dir
+            );
+
+            // The traversal knows the full path, the file found there has the same name
+            let path = VfsPath::from(dir).join(&VfsPath::from(name));
+            kassert!(t, VfsPath::from(entry.full_name()).normalize() == path);
+            let vfs = get_vfs();
+            let mut guard = vfs.write();
+            let Ok(mut traverse) = guard.path_traverse(&path) else {
+                kassert!(t, false, "no traversal of {}/{}", dir, display);
+                continue;
+            };
+            let mut found = None;
+            while !traverse.is_done() {
+                found = traverse.find_next().ok();
+                if found.is_none() {
+                    break;
+                }
+            }
+            kassert!(
+                t,
+                found.is_some_and(|file| file.name() == name) && *traverse.path() == path,
+                "{}/{} not found again",
+                dir,
+                display
+            );
+        }
+    }
+}
+
+/// Every record of the directory open as `file`, reading `buf_len` bytes at a time
+pub(super) fn read_records(
+    file: &File,
+    buf_len: usize,
+) -> Result<Vec<(u64, u8, Vec<u8>)>, VfsError> {
+    let mut buf = vec![0u8; buf_len];
+    let mut records = Vec::new();
+    loop {
+        let read = file.read(&mut buf)? as usize;
+        if read == 0 {
+            return Ok(records);
+        }
+        let mut offset = 0;
+        while offset < read {
+            let (record, size) =
+                DirectoryRecord::parse(&buf[offset..read]).ok_or(VfsError::InvalidDataStructure)?;
+            records.push((record.inode, record.kind, record.name.to_vec()));
+            offset += size;
+        }
+    }
+}
+
+fn open_directory(path: &str, mode: u64) -> Result<File, VfsError> {
+    File::open(path, mode, Permissions::from_u64(0), Credentials::Kernel)
+}
+
+fn directory_handles(t: &mut KTestContext) {
+    let chars = |s: &str| s.chars().collect::<Vec<char>>();
+    let vfs = get_vfs();
+    kassert!(
+        t,
+        vfs.write()
+            .mount(&chars("ktest_dirs"), Box::new(TmpFs::new()), 0)
+            .is_ok()
+    );
+    for name in ["/ktest_dirs/a", "/ktest_dirs/b"] {
+        let file = File::create(
+            name,
+            OPEN_MODE_WRITE,
+            Permissions::from_u64(0o644),
+            Credentials::Kernel,
+        );
+        kassert!(t, file.is_ok());
+    }
+    kassert!(t, File::mkdir("/ktest_dirs/c").is_ok());
+
+    kassert!(
+        t,
+        matches!(
+            open_directory("/ktest_dirs", OPEN_MODE_READ | OPEN_MODE_WRITE),
+            Err(VfsError::NotFile)
+        )
+    );
+    let Ok(dir) = open_directory("/ktest_dirs", OPEN_MODE_READ) else {
+        kassert!(t, false, "failed to open the tmpfs directory");
+        let _ = vfs.write().unmount(&chars("ktest_dirs"));
+        return;
+    };
+    // Records of one letter names take 12 bytes
+    let mut small = [0u8; 8];
+    kassert!(
+        t,
+        matches!(dir.read(&mut small), Err(VfsError::BadBufferSize))
+    );
+    match read_records(&dir, 16) {
+        Ok(records) => {
+            let kinds = records
+                .iter()
+                .map(|(_, kind, name)| (name.as_slice(), *kind))
+                .collect::<Vec<_>>();
+            kassert_eq!(
+                t,
+                kinds,
+                [
+                    (&b"a"[..], DT_REG),
+                    (&b"b"[..], DT_REG),
+                    (&b"c"[..], DT_DIR)
+                ]
+            );
+            kassert!(t, records.iter().all(|(inode, ..)| *inode != 0));
+        }
+        Err(err) => kassert!(t, false, "tmpfs directory read failed: {:?}", err),
+    }
+
+    // Entries created while the handle is open show up after a rewind
+    let file = File::create(
+        "/ktest_dirs/d",
+        OPEN_MODE_WRITE,
+        Permissions::from_u64(0o644),
+        Credentials::Kernel,
+    );
+    kassert!(t, file.is_ok());
+    kassert!(t, dir.seek(SeekPosition::FromStart(0)).is_ok());
+    let records = read_records(&dir, 256);
+    kassert!(t, records.is_ok_and(|records| records.len() == 4));
+    drop(dir);
+    kassert!(t, vfs.write().unmount(&chars("ktest_dirs")).is_ok());
+
+    let Ok(dev) = open_directory("/dev", OPEN_MODE_READ) else {
+        kassert!(t, false, "failed to open /dev");
+        return;
+    };
+    match (read_records(&dev, 64), File::list_directory("/dev")) {
+        (Ok(records), Ok(entries)) => {
+            let names = records
+                .into_iter()
+                .map(|(_, _, name)| name)
+                .collect::<Vec<_>>();
+            let listed = entries
+                .iter()
+                .map(|entry| VfsPath::from(entry.name()).into_bytes())
+                .collect::<Vec<_>>();
+            kassert_eq!(t, names, listed);
+        }
+        (records, entries) => kassert!(
+            t,
+            false,
+            "listing /dev failed: {:?} {:?}",
+            records.err(),
+            entries.err()
+        ),
+    }
+}
+
+fn file_lock_conflicts(t: &mut KTestContext) {
+    let mut locks = FileLocks::new();
+    let key = (1, 12);
+
+    kassert!(t, locks.try_lock(key, 1, FileLockKind::Shared).is_ok());
+    kassert!(t, locks.try_lock(key, 2, FileLockKind::Shared).is_ok());
+    kassert!(
+        t,
+        locks.try_lock((1, 13), 3, FileLockKind::Exclusive).is_ok()
+    );
+    kassert!(t, locks.try_lock(key, 3, FileLockKind::Exclusive).is_err());
+
+    // A conflicting upgrade drops the shared lock it had
+    kassert!(t, locks.try_lock(key, 1, FileLockKind::Exclusive).is_err());
+    kassert_eq!(t, locks.holders(key), (1, None));
+    kassert!(t, locks.try_lock(key, 2, FileLockKind::Exclusive).is_ok());
+    kassert_eq!(t, locks.holders(key), (0, Some(2)));
+
+    locks.try_lock(key, 2, FileLockKind::Shared).ok();
+    kassert!(t, locks.try_lock(key, 1, FileLockKind::Shared).is_ok());
+    locks.release_owner(1);
+    locks.unlock(key, 2);
+    kassert_eq!(t, locks.holders(key), (0, None));
+
+    locks.release_fs(1);
+    kassert!(
+        t,
+        locks.try_lock((1, 13), 1, FileLockKind::Exclusive).is_ok()
+    );
+}
+
+/// 4 blocks of 16 bytes in memory, counting the blocks read
+#[derive(Debug)]
+struct MemoryBlockDevice {
+    data: Vec<u8>,
+    reads: AtomicU64,
+}
+
+impl BlockDevice for MemoryBlockDevice {
+    fn get_generation(&self) -> u64 {
+        0
+    }
+
+    fn get_block_size(&self) -> u64 {
+        16
+    }
+
+    fn get_block_count(&self) -> u64 {
+        4
+    }
+
+    fn read_block(&self, lba: u64, buf: &mut [u8]) -> Result<u64, VfsError> {
+        self.reads.fetch_add(1, Ordering::Relaxed);
+        let begin = lba as usize * 16;
+        buf.copy_from_slice(&self.data[begin..begin + 16]);
+        Ok(16)
+    }
+
+    fn write_block(&mut self, lba: u64, buf: &[u8]) -> Result<u64, VfsError> {
+        let begin = lba as usize * 16;
+        self.data[begin..begin + 16].copy_from_slice(buf);
+        Ok(16)
+    }
+
+    fn flush(&mut self) -> Result<(), VfsError> {
+        Ok(())
+    }
+}
+
+fn character_device_kinds(t: &mut KTestContext) {
+    let Ok(mut null) = File::open(
+        "/dev/null",
+        OPEN_MODE_READ | OPEN_MODE_WRITE,
+        Permissions::from_u64(0),
+        Credentials::Kernel,
+    ) else {
+        kassert!(t, false, "failed to open /dev/null");
+        return;
+    };
+    kassert!(t, matches!(null.write(b"gone"), Ok(4)));
+    kassert!(t, matches!(null.read(&mut [0; 4]), Ok(0)));
+    kassert!(
+        t,
+        matches!(
+            null.seek(SeekPosition::FromStart(0)),
+            Err(VfsError::InvalidSeekPosition)
+        )
+    );
+    kassert!(t, null.close().is_ok());
+
+    let block_device =
+        arcrwb_new_from_box::<dyn BlockDevice>(alloc::boxed::Box::new(MemoryBlockDevice {
+            data: alloc::vec![0; 64],
+            reads: AtomicU64::new(0),
+        }));
+    let device = arcrwb_new_from_box::<dyn CharacterDevice>(Box::new(
+        BlockDeviceAsCharacterDevice::new(block_device),
+    ));
+    let stat = FileStat {
+        size: 64,
+        created_at: 0,
+        modified_at: 0,
+        permissions: 0,
+        is_file: true,
+        is_directory: false,
+        is_symlink: false,
+        owner_id: 0,
+        group_id: 0,
+        flags: 0,
+    };
+    let mut provider = CharacterDeviceProvider::new(0, vec!['m'], device, stat);
+    let (Ok(first), Ok(second)) = (provider.open(0), provider.open(0)) else {
+        kassert!(t, false, "failed to open the memory device");
+        return;
+    };
+    let mut first = first.write();
+    let mut second = second.write();
+    kassert!(t, matches!(first.write(b"abcdef"), Ok(6)));
+    kassert!(t, matches!(first.pos(), Ok(6)));
+    // Each handle has its own position
+    kassert!(t, matches!(second.pos(), Ok(0)));
+    kassert!(t, matches!(second.seek(SeekPosition::FromStart(2)), Ok(2)));
+    let mut buf = [0; 3];
+    kassert!(t, matches!(second.read(&mut buf), Ok(3)));
+    kassert_eq!(t, &buf, b"cde");
+    kassert!(t, matches!(second.pos(), Ok(5)));
+    kassert!(t, matches!(second.seek(SeekPosition::FromEnd(0)), Ok(64)));
+}
+
+fn block_device_char_access(t: &mut KTestContext) {
+    let block_device =
+        arcrwb_new_from_box::<dyn BlockDevice>(alloc::boxed::Box::new(MemoryBlockDevice {
+            data: alloc::vec![0; 64],
+            reads: AtomicU64::new(0),
+        }));
+    let reads = || {
+        block_device
+            .read()
+            .as_any()
+            .downcast_ref::<MemoryBlockDevice>()
+            .unwrap()
+            .reads
+            .load(Ordering::Relaxed)
+    };
+    let mut device = BlockDeviceAsCharacterDevice::new(block_device.clone());
+
+    // Aligned whole blocks skip the read back
+    let pattern = (0..32).collect::<Vec<u8>>();
+    kassert_eq!(t, device.write_chars(16, &pattern).ok(), Some(32));
+    kassert_eq!(t, reads(), 0);
+
+    kassert_eq!(t, device.write_chars(20, &[0xaa; 4]).ok(), Some(4));
+    kassert_eq!(t, reads(), 1);
+
+    let mut buf = [0; 32];
+    kassert_eq!(t, device.read_chars(16, &mut buf).ok(), Some(32));
+    kassert_eq!(t, reads(), 3);
+    kassert_eq!(t, buf[..4], pattern[..4]);
+    kassert_eq!(t, buf[4..8], [0xaa; 4]);
+    kassert_eq!(t, buf[8..], pattern[8..]);
+
+    let mut buf = [0; 8];
+    kassert_eq!(t, device.read_chars(60, &mut buf).ok(), Some(4));
+    kassert_eq!(t, device.read_chars(64, &mut buf).ok(), Some(0));
+    kassert!(
+        t,
+        matches!(
+            device.read_chars(65, &mut buf),
+            Err(VfsError::InvalidArgument)
+        )
+    );
+    kassert!(
+        t,
+        matches!(
+            device.write_chars(100, &buf),
+            Err(VfsError::InvalidArgument)
+        )
+    );
+}
+
+/// Sectors past LBA 2^24 need the high bytes of the LBA48 registers, the marker written there
+/// must come back from the same byte offset and nowhere else
+fn scratch_disk_high_lba(t: &mut KTestContext) {
+    let path = get_kernel_config().ktest_scratch_disk.clone();
+    if path.is_empty() {
+        log_info!(target: "ktest", "    no ktest.scratch_disk, skipped");
+        return;
+    }
+    let device = get_vfs()
+        .write()
+        .get_file(&VfsPath::from(path.as_str()))
+        .ok()
+        .and_then(|file| file.get_block_device());
+    let Some(device) = device else {
+        kassert!(t, false, "{} isn't a block device", path);
+        return;
+    };
+    let (block_size, block_count) = {
+        let guard = device.read();
+        (guard.get_block_size(), guard.get_block_count())
+    };
+    if block_count <= 1 << 25 {
+        log_info!(target: "ktest", "    {} is smaller than 16 GiB, skipped", path);
+        return;
+    }
+
+    let chars = BlockDeviceAsCharacterDevice::new(device.clone());
+    for lba in [(1 << 24) + 0x1234, block_count - 1] {
+        let mut marker = alloc::vec![0u8; block_size as usize];
+        marker[..8].copy_from_slice(&lba.to_le_bytes());
+        marker[8..16].copy_from_slice(b"CAMPIXHI");
+        kassert!(t, device.write().write_block(lba, &marker).is_ok());
+
+        let mut back = alloc::vec![0u8; block_size as usize];
+        kassert_eq!(
+            t,
+            chars.read_chars(lba * block_size, &mut back).ok(),
+            Some(block_size)
+        );
+        kassert!(t, back == marker, "sector {} came back wrong", lba);
+        // Dropping the high LBA bytes would have put it there
+        let low = lba & 0xff_ffff;
+        kassert_eq!(
+            t,
+            chars.read_chars(low * block_size, &mut back).ok(),
+            Some(block_size)
+        );
+        kassert!(
+            t,
+            back[..8] != lba.to_le_bytes(),
+            "sector {} aliases {}",
+            lba,
+            low
+        );
+    }
+}
+
+/// Empty buffers, so a broken check doesn't touch the disk
+fn pata_handle_modes(t: &mut KTestContext) {
+    let open = |mode| {
+        File::open(
+            "/dev/pata_pm",
+            mode,
+            Permissions::from_u64(0),
+            Credentials::Kernel,
+        )
+    };
+    let mut read_only = match open(OPEN_MODE_READ) {
+        Ok(file) => file,
+        Err(VfsError::PathNotFound) => {
+            log_info!(target: "ktest", "    no disk on the primary master, skipped");
+            return;
+        }
+        Err(err) => {
+            kassert!(t, false, "failed to open /dev/pata_pm: {:?}", err);
+            return;
+        }
+    };
+    kassert!(
+        t,
+        matches!(read_only.write(&[]), Err(VfsError::ActionNotAllowed))
+    );
+    kassert!(t, matches!(read_only.read(&mut []), Ok(0)));
+
+    match open(OPEN_MODE_WRITE) {
+        Ok(mut write_only) => {
+            kassert!(
+                t,
+                matches!(write_only.read(&mut []), Err(VfsError::ActionNotAllowed))
+            );
+            kassert!(t, matches!(write_only.write(&[]), Ok(0)));
+        }
+        Err(err) => kassert!(t, false, "failed to open write-only: {:?}", err),
+    }
+    kassert!(t, matches!(open(0), Err(VfsError::InvalidOpenMode)));
+}
+
+const KEYED: u64 = u64::from_be_bytes(*b"ktestkey");
+
+#[derive(Debug)]
+struct KeyedFileData;
+
+impl FsSpecificFileData for KeyedFileData {}
+
+/// Two devices hooked as `ktest_keyed_N`, or swapped names once renamed. Reads give the key
+#[derive(Debug)]
+struct KeyedDriver {
+    pci_device: PciDevice,
+    renamed: bool,
+    handles: BTreeSet<u64>,
+}
+
+impl DevFsDriver for KeyedDriver {
+    fn driver_id(&self) -> u64 {
+        KEYED
+    }
+
+    fn handles_device(&self, _dev_fs: &mut DevFs, pci_device: &PciDevice) -> bool {
+        self.pci_device == *pci_device
+    }
+
+    fn refresh_device_hooks(
+        &mut self,
+        dev_fs: &mut DevFs,
+        _pci_device: &PciDevice,
+        device_id: usize,
+    ) -> Result<Vec<Vec<char>>, VfsError> {
+        let mut hooks = Vec::new();
+        for device_key in 0..2 {
+            let path = match self.renamed {
+                false => format!("ktest_keyed_{device_key}"),
+                true => format!("ktest_keyed_{}_renamed", 1 - device_key),
+            }
+            .chars()
+            .collect::<Vec<_>>();
+            let file = VfsFile::new(
+                VfsFileKind::File,
+                path.clone(),
+                0,
+                dev_fs.os_id(),
+                dev_fs.os_id(),
+                Arc::new(KeyedFileData),
+            );
+            dev_fs.replace_hook(
+                path.clone(),
+                KEYED,
+                file,
+                DevFsHookKind::Device,
+                0,
+                device_id as u64,
+                device_key,
+            );
+            hooks.push(path);
+        }
+        Ok(hooks)
+    }
+
+    fn fopen(
+        &mut self,
+        dev_fs: &mut DevFs,
+        hook: Arc<DevFsHook>,
+        _mode: u64,
+    ) -> Result<u64, VfsError> {
+        let handle = dev_fs.alloc_file_handle(hook.device_key, hook);
+        self.handles.insert(handle);
+        Ok(handle)
+    }
+
+    fn fclose(&mut self, dev_fs: &mut DevFs, handle: u64) -> Result<(), VfsError> {
+        self.handles.remove(&handle);
+        dev_fs.dealloc_file_handle::<u64>(handle);
+        Ok(())
+    }
+
+    fn fread(&mut self, dev_fs: &mut DevFs, handle: u64, buf: &mut [u8]) -> Result<u64, VfsError> {
+        if !self.handles.contains(&handle) {
+            return Err(VfsError::BadHandle);
+        }
+        let key = unsafe {
+            *dev_fs
+                .get_handle_data::<u64>(handle)
+                .ok_or(VfsError::BadHandle)?
+        };
+        buf[0] = key as u8;
+        Ok(1)
+    }
+
+    fn fwrite(&mut self, _dev_fs: &mut DevFs, _handle: u64, _buf: &[u8]) -> Result<u64, VfsError> {
+        Err(VfsError::ActionNotAllowed)
+    }
+
+    fn ftruncate(&mut self, _dev_fs: &mut DevFs, _handle: u64) -> Result<u64, VfsError> {
+        Err(VfsError::ActionNotAllowed)
+    }
+
+    fn fflush(&mut self, _dev_fs: &mut DevFs, _handle: u64) -> Result<(), VfsError> {
+        Ok(())
+    }
+
+    fn fsync(&mut self, _dev_fs: &mut DevFs, _handle: u64) -> Result<(), VfsError> {
+        Ok(())
+    }
+
+    fn fstat(&mut self, _dev_fs: &DevFs, _handle: u64) -> Result<FileStat, VfsError> {
+        Err(VfsError::ActionNotAllowed)
+    }
+
+    fn fseek(
+        &mut self,
+        _dev_fs: &mut DevFs,
+        _handle: u64,
+        _position: SeekPosition,
+    ) -> Result<u64, VfsError> {
+        Err(VfsError::InvalidSeekPosition)
+    }
+}
+
+fn read_key(devfs: &mut DevFs, path: &str) -> Result<u8, VfsError> {
+    let file = devfs.get_file(&VfsPath::from(path))?;
+    let handle = devfs.fopen(&file, OPEN_MODE_READ)?;
+    let mut key = [0xFF];
+    let read = devfs.fread(handle, &mut key);
+    devfs.fclose(handle)?;
+    read?;
+    Ok(key[0])
+}
+
+fn devfs_hooks_by_key(t: &mut KTestContext) {
+    let Some(pci_device) = pci::get_devices().first().copied() else {
+        log_info!(target: "ktest", "    no pci device, skipped");
+        return;
+    };
+    let driver = arcrwb_new_from_box::<dyn DevFsDriver>(Box::new(KeyedDriver {
+        pci_device,
+        renamed: false,
+        handles: BTreeSet::new(),
+    }));
+    let paths = [
+        "ktest_keyed_0",
+        "ktest_keyed_1",
+        "ktest_keyed_0_renamed",
+        "ktest_keyed_1_renamed",
+    ];
+
+    let result = with_devfs(|_, devfs| {
+        devfs.register_driver(driver.clone())?;
+        let keys = [read_key(devfs, paths[0])?, read_key(devfs, paths[1])?];
+
+        let mut guard = driver.write();
+        if let Some(keyed) = (**guard).as_any_mut().downcast_mut::<KeyedDriver>() {
+            keyed.renamed = true;
+        }
+        let device_id = devfs
+            .device_index(&pci_device)
+            .ok_or(VfsError::NoSuchDevice)?;
+        devfs.refresh_hooks(&mut **guard, &pci_device, device_id)?;
+        drop(guard);
+        let renamed_keys = [read_key(devfs, paths[2])?, read_key(devfs, paths[3])?];
+
+        devfs.unregister_driver(KEYED)?;
+        let remaining = paths
+            .iter()
+            .filter(|path| devfs.get_file(&VfsPath::from(**path)).is_ok())
+            .count();
+        Ok((keys, renamed_keys, remaining))
+    });
+    let Ok((keys, renamed_keys, remaining)) = result else {
+        kassert!(t, false, "keyed driver failed: {:?}", result);
+        let _ = with_devfs(|_, devfs| devfs.unregister_driver(KEYED));
+        return;
+    };
+    kassert_eq!(t, keys, [0, 1]);
+    // `ktest_keyed_0_renamed` is the hook of device 1
+    kassert_eq!(t, renamed_keys, [1, 0]);
+    kassert_eq!(t, remaining, 0);
+}
+
+fn sub_block_device_offset(t: &mut KTestContext) {
+    let block_device =
+        arcrwb_new_from_box::<dyn BlockDevice>(alloc::boxed::Box::new(MemoryBlockDevice {
+            data: (0..64).collect(),
+            reads: AtomicU64::new(0),
+        }));
+    let mut partition = SubBlockDevice::new(block_device.clone(), 1, 3);
+    kassert_eq!(t, partition.get_block_count(), 2);
+    kassert_eq!(t, partition.description(), None);
+
+    let mut buf = [0; 16];
+    kassert_eq!(t, partition.read_block(0, &mut buf).ok(), Some(16));
+    kassert_eq!(t, buf[0], 16);
+    kassert!(
+        t,
+        matches!(
+            partition.read_block(2, &mut buf),
+            Err(VfsError::OutOfBounds)
+        )
+    );
+
+    kassert_eq!(t, partition.write_block(1, &[0xbb; 16]).ok(), Some(16));
+    let mut buf = [0; 16];
+    kassert_eq!(
+        t,
+        block_device.read().read_block(2, &mut buf).ok(),
+        Some(16)
+    );
+    kassert_eq!(t, buf, [0xbb; 16]);
+}
+
+/// Views of a ram disk taken before its generation moves on stop working, new ones don't
+fn stale_device_generation(t: &mut KTestContext) {
+    let Ok(disk) = RamDisk::new(8 * 512) else {
+        kassert!(t, false, "failed to create the ram disk");
+        return;
+    };
+    let device = arcrwb_new_from_box::<dyn BlockDevice>(Box::new(disk));
+    let mut partition = SubBlockDevice::new(device.clone(), 2, 6);
+    let mut bytes = BlockDeviceAsCharacterDevice::new(device.clone());
+    let mut buf = [0u8; 512];
+    kassert!(t, partition.read_block(0, &mut buf).is_ok());
+    kassert!(t, bytes.read_chars(100, &mut buf[..16]).is_ok());
+
+    match (**device.write()).as_any_mut().downcast_mut::<RamDisk>() {
+        Some(disk) => disk.bump_generation(),
+        None => {
+            kassert!(t, false, "the device isn't the ram disk");
+            return;
+        }
+    }
+    let stale = |result: Result<u64, VfsError>| matches!(result, Err(VfsError::StaleDevice));
+    kassert!(t, stale(partition.read_block(1, &mut buf)));
+    kassert!(t, stale(partition.write_block(1, &buf)));
+    kassert!(t, stale(bytes.read_chars(100, &mut buf[..16])));
+    kassert!(t, stale(bytes.write_chars(0, &buf)));
+
+    let request = BlockRequest::read(0, 1);
+    let completion = request.completion();
+    partition.submit(request);
+    kassert!(t, matches!(completion.wait(), Err(VfsError::StaleDevice)));
+
+    let mut partition = SubBlockDevice::new(device.clone(), 2, 6);
+    kassert!(t, partition.write_block(1, &buf).is_ok());
+    kassert!(
+        t,
+        BlockDeviceAsCharacterDevice::new(device)
+            .read_chars(0, &mut buf)
+            .is_ok()
+    );
+}
+
+fn request_queue_order(t: &mut KTestContext) {
+    let queue = RequestQueue::new();
+    let dispatched = RefCell::new(Vec::new());
+    let written = RefCell::new(Vec::new());
+    let mut dispatch = |kind, lba, buf: &mut [u8]| {
+        dispatched.borrow_mut().push((kind, lba, buf.len()));
+        if kind == BlockRequestKind::Write {
+            written.borrow_mut().extend_from_slice(buf);
+        }
+        for (block, lba) in buf.chunks_mut(16).zip(lba..) {
+            if kind == BlockRequestKind::Read {
+                block.fill(lba as u8);
+            }
+        }
+        Ok(())
+    };
+
+    let far = BlockRequest::read(8, 2);
+    let near = BlockRequest::read(3, 1);
+    let near_completion = near.completion();
+    queue.push(far);
+    queue.push(BlockRequest::read(2, 1));
+    queue.push(near);
+    queue.push(BlockRequest::write(4, 2, alloc::vec![1; 32]));
+    queue.push(BlockRequest::write(5, 1, alloc::vec![2; 16]));
+    queue.push(BlockRequest::barrier());
+    queue.push(BlockRequest::read(1, 1));
+    queue.kick(16, &mut dispatch);
+
+    // Sorted and merged up to the barrier, an overlapping write waits for the earlier one
+    kassert_eq!(
+        t,
+        *dispatched.borrow(),
+        [
+            (BlockRequestKind::Read, 2, 32),
+            (BlockRequestKind::Write, 4, 32),
+            (BlockRequestKind::Read, 8, 32),
+            (BlockRequestKind::Write, 5, 16),
+            (BlockRequestKind::Barrier, 0, 0),
+            (BlockRequestKind::Read, 1, 16),
+        ]
+    );
+    kassert_eq!(t, written.borrow()[..32], [1; 32]);
+    kassert_eq!(t, written.borrow()[32..], [2; 16]);
+    kassert_eq!(t, near_completion.wait().ok(), Some(alloc::vec![3; 16]));
+
+    // The elevator keeps going up from the last dispatch before wrapping around
+    dispatched.borrow_mut().clear();
+    let wrapped = BlockRequest::read(0, 1);
+    let wrapped_completion = wrapped.completion();
+    queue.push(wrapped);
+    queue.push(BlockRequest::read(6, 1));
+    kassert!(t, wrapped_completion.take_result().is_none());
+    queue.kick(16, &mut dispatch);
+    kassert!(
+        t,
+        wrapped_completion
+            .take_result()
+            .is_some_and(|result| result.is_ok_and(|data| data == [0; 16]))
+    );
+    kassert_eq!(
+        t,
+        *dispatched.borrow(),
+        [
+            (BlockRequestKind::Read, 6, 16),
+            (BlockRequestKind::Read, 0, 16),
+        ]
+    );
+}
+
+fn vfs_error_errnos(t: &mut KTestContext) {
+    let table = [
+        (VfsError::PathNotFound, ENOENT),
+        (VfsError::EntryNotFound, ENOENT),
+        (VfsError::FileSystemNotMounted, ENOENT),
+        (VfsError::InvalidArgument, EINVAL),
+        (VfsError::BadBufferSize, EINVAL),
+        (VfsError::InvalidOpenMode, EINVAL),
+        (VfsError::FileSystemMismatch, EINVAL),
+        (VfsError::MaximumSizeReached, EFBIG),
+        (VfsError::InvalidDataStructure, EINVAL),
+        (VfsError::NotMountPoint, EINVAL),
+        (VfsError::OutOfBounds, EINVAL),
+        (VfsError::ShortRead, EINVAL),
+        (VfsError::InvalidSeekPosition, ESPIPE),
+        (VfsError::ActionNotAllowed, EPERM),
+        (VfsError::BadHandle, EBADF),
+        (VfsError::FileAlreadyExists, EEXIST),
+        (VfsError::DirectoryNotEmpty, ENOTEMPTY),
+        (VfsError::NotDirectory, ENOTDIR),
+        (VfsError::NotFile, EISDIR),
+        (VfsError::BrokenPipe, EPIPE),
+        (VfsError::WouldBlock, EAGAIN),
+        (VfsError::Interrupted, EINTR),
+        (VfsError::PermissionDenied, EACCES),
+        (VfsError::AlreadyMounted, EBUSY),
+        (VfsError::Busy, EBUSY),
+        (VfsError::NoSuchDevice, ENXIO),
+        (VfsError::NotTerminal, ENOTTY),
+        (VfsError::HungUp, EIO),
+        (VfsError::StaleDevice, ENXIO),
+        (VfsError::NameTooLong, ENAMETOOLONG),
+        (VfsError::SymlinkLoop, ELOOP),
+        (VfsError::ReadOnly, EROFS),
+        (VfsError::OutOfSpace, ENOSPC),
+        (VfsError::OutOfMemory, ENOMEM),
+        (VfsError::UnknownError, EIO),
+        (VfsError::Done, ENODATA),
+        (VfsError::DriverError(Box::new("ktest")), EIO),
+    ];
+    for (err, errno) in table {
+        let name = format!("{:?}", err);
+        let mapped = vfs_err_to_linux_errno(err);
+        kassert!(
+            t,
+            mapped == errno,
+            "{} maps to {} instead of {}",
+            name,
+            mapped,
+            errno
+        );
+    }
+}
+
+fn file_handle_checks(t: &mut KTestContext) {
+    let mut handles = FileHandleAllocator::default();
+    handles.set_owner(7);
+    let handle = handles.alloc_file_handle::<u32>(42);
+
+    unsafe {
+        kassert_eq!(
+            t,
+            handles.get_handle_data::<u32>(handle).map(|d| *d),
+            Some(42)
+        );
+        kassert!(t, handles.get_handle_data::<u64>(handle).is_none());
+        kassert!(t, handles.get_handle_data::<u32>(handle ^ 0x10).is_none());
+    }
+
+    // Handles of another file system are rejected
+    let mut other = FileHandleAllocator::default();
+    other.set_owner(8);
+    kassert!(t, unsafe { other.get_handle_data::<u32>(handle) }.is_none());
+    kassert!(t, !other.dealloc_file_handle::<u32>(handle));
+
+    kassert!(t, !handles.dealloc_file_handle::<u64>(handle));
+    kassert!(t, handles.dealloc_file_handle::<u32>(handle));
+    kassert!(t, !handles.dealloc_file_handle::<u32>(handle));
+    kassert_eq!(t, handles.count(), 0);
+}
+
+fn walk_procfs(t: &mut KTestContext) {
+    let path = "/proc/".chars().collect::<Vec<char>>();
+    let walk = match Vfs::walk(&get_vfs(), &path) {
+        Ok(walk) => walk,
+        Err(e) => {
+            kassert!(t, false, "walk of /proc failed: {:?}", e);
+            return;
+        }
+    };
+    let entries = walk
+        .max_depth(1)
+        .filter_map(|entry| entry.ok())
+        .map(|(path, _)| path.into_iter().collect::<alloc::string::String>())
+        .collect::<Vec<_>>();
+
+    kassert_eq!(t, entries.first().map(|p| p.as_str()), Some("/proc"));
+    kassert!(t, entries.iter().any(|p| p == "/proc/uptime"));
+    kassert!(
+        t,
+        entries.iter().all(|p| p.matches('/').count() <= 2),
+        "walk went below max depth: {:?}",
+        entries
+    );
+}
+
+fn procfs_cpu_stats(t: &mut KTestContext) {
+    let per_cpu = get_per_cpu();
+    let top = per_cpu.idle_stack_top();
+    let stack = per_cpu.idle_stack.as_ref().map_or(0..0, |stack| {
+        stack.as_ptr() as u64..stack.as_ptr() as u64 + stack.len() as u64
+    });
+    kassert!(t, stack.contains(&top) && top % 16 == 8);
+
+    let mut buf = [0u8; 4096];
+    let read = File::open(
+        "/proc/stat",
+        OPEN_MODE_READ,
+        Permissions::from_u64(0),
+        Credentials::Kernel,
+    )
+    .and_then(|file| file.read(&mut buf));
+    let Ok(read) = read else {
+        kassert!(t, false, "failed to read /proc/stat");
+        return;
+    };
+    let Ok(text) = core::str::from_utf8(&buf[..read as usize]) else {
+        kassert!(t, false, "/proc/stat isn't text");
+        return;
+    };
+    let switches = text
+        .lines()
+        .filter(|line| line.starts_with("cpu"))
+        .filter_map(|line| line.rsplit(' ').next()?.parse::<u64>().ok())
+        .collect::<Vec<_>>();
+    let own = format!("cpu{} idle ", per_cpu.core_id);
+    kassert!(
+        t,
+        text.lines().any(|line| line.starts_with(&own)),
+        "{}",
+        text
+    );
+    kassert!(
+        t,
+        text.lines()
+            .last()
+            .is_some_and(|line| *line == format!("ctxt {}", switches.iter().sum::<u64>())),
+        "{}",
+        text
+    );
+}
+
+fn mount_table_parsing(t: &mut KTestContext) {
+    let entry = MountEntry::parse("PARTLABEL=data /mnt/data ext2 ro,cache=4096,read_ahead=0");
+    kassert!(t, entry.is_ok());
+    let entry = entry.unwrap();
+    kassert_eq!(t, entry.device.as_str(), "PARTLABEL=data");
+    kassert_eq!(t, entry.mount_point.as_str(), "/mnt/data");
+    kassert_eq!(t, entry.fs_type.as_str(), "ext2");
+    kassert!(t, entry.options.read_only);
+    kassert_eq!(t, entry.options.block_cache_size.get(), 4096);
+    kassert_eq!(t, entry.options.read_ahead_blocks, 0);
+
+    let defaults = MountEntry::parse("/dev/pata_ps_p0 /data ext2");
+    kassert!(
+        t,
+        defaults.is_ok_and(|entry| !entry.options.read_only && entry.options.barriers)
+    );
+
+    let unordered = MountEntry::parse("/dev/pata_ps_p0 /data ext2 nobarrier,check_order");
+    kassert!(
+        t,
+        unordered.is_ok_and(|entry| !entry.options.barriers && entry.options.check_order)
+    );
+
+    kassert!(
+        t,
+        matches!(
+            MountEntry::parse("/dev/pata_ps_p0 /data ext2 cache=0"),
+            Err(MountError::BadOption(_))
+        )
+    );
+    kassert!(
+        t,
+        matches!(
+            MountEntry::parse("/dev/pata_ps_p0 data ext2"),
+            Err(MountError::BadEntry(_))
+        )
+    );
+
+    let table = "# comment\n\n/dev/a /a ext2\nbad line\n/dev/b /b ext2 rw\n";
+    let lines = parse_mount_table("test", table)
+        .iter()
+        .map(|(line, _)| *line)
+        .collect::<Vec<_>>();
+    kassert_eq!(t, lines, [3, 5]);
+}
+
+fn open_path_handle_count(t: &mut KTestContext) {
+    let Ok(file) = File::open(
+        "/proc/uptime",
+        OPEN_MODE_READ,
+        Permissions::from_u64(0),
+        Credentials::Kernel,
+    ) else {
+        kassert!(t, false, "failed to open /proc/uptime");
+        return;
+    };
+    let fs = file.get_vfs_file().fs();
+    let open = open_path_handles(fs);
+    kassert!(t, open >= 1);
+
+    kassert!(t, file.close().is_ok());
+    kassert_eq!(t, open_path_handles(fs), open - 1);
+}
+
+fn bind_mount_procfs(t: &mut KTestContext) {
+    let chars = |s: &str| s.chars().collect::<Vec<char>>();
+    let vfs = get_vfs();
+    let mut guard = vfs.write();
+
+    kassert!(
+        t,
+        guard
+            .bind_mount(&chars("/proc"), &chars("/ktest_bind"), 0)
+            .is_ok()
+    );
+    kassert!(
+        t,
+        guard.get_file(&VfsPath::from("/ktest_bind/uptime")).is_ok()
+    );
+
+    // The source is needed by the bind mount
+    kassert!(
+        t,
+        matches!(guard.unmount(&chars("/proc")), Err(VfsError::Busy))
+    );
+    kassert!(
+        t,
+        matches!(
+            guard.bind_mount(&chars("/proc"), &chars("/ktest_bind/uptime"), 0),
+            Err(VfsError::NotDirectory)
+        )
+    );
+    kassert!(
+        t,
+        matches!(
+            guard.bind_mount(&chars("/proc"), &chars("/ktest_bind/missing"), 0),
+            Err(VfsError::PathNotFound)
+        )
+    );
+
+    kassert!(t, guard.unmount(&chars("/ktest_bind")).is_ok());
+    kassert!(
+        t,
+        guard
+            .get_file(&VfsPath::from("/ktest_bind/uptime"))
+            .is_err()
+    );
+}
+
+fn statfs_virtual(t: &mut KTestContext) {
+    let chars = |s: &str| s.chars().collect::<Vec<char>>();
+    let vfs = get_vfs();
+    let mut guard = vfs.write();
+
+    let proc = guard.statfs_path(&chars("/proc"));
+    kassert!(t, proc.is_ok_and(|stat| stat.fs_type == PROCFS_MAGIC));
+    // A file reports the file system it is on
+    let uptime = guard.statfs_path(&chars("/proc/uptime"));
+    kassert!(t, uptime.is_ok_and(|stat| stat.fs_type == PROCFS_MAGIC));
+    let dev = guard.statfs_path(&chars("/dev"));
+    kassert!(
+        t,
+        dev.is_ok_and(|stat| stat.fs_type == DEVFS_MAGIC && stat.free_blocks == 0)
+    );
+    kassert!(
+        t,
+        matches!(
+            guard.statfs_path(&chars("/proc/missing")),
+            Err(VfsError::PathNotFound)
+        )
+    );
+}
+
+fn read_only_bind_mount(t: &mut KTestContext) {
+    let chars = |s: &str| s.chars().collect::<Vec<char>>();
+    let vfs = get_vfs();
+    let mut guard = vfs.write();
+
+    kassert!(
+        t,
+        guard
+            .bind_mount(&chars("/proc"), &chars("/ktest_ro"), MS_RDONLY)
+            .is_ok()
+    );
+    kassert!(
+        t,
+        matches!(
+            guard.check_writable(&chars("/ktest_ro/uptime")),
+            Err(VfsError::ReadOnly)
+        )
+    );
+    // The source mount keeps its own flags
+    kassert!(t, guard.check_writable(&chars("/proc/uptime")).is_ok());
+    drop(guard);
+    kassert!(
+        t,
+        matches!(
+            Vfs::unlink_path(&vfs, &chars("/ktest_ro/uptime"), Credentials::Kernel),
+            Err(VfsError::ReadOnly)
+        )
+    );
+
+    let mut guard = vfs.write();
+    kassert!(t, guard.remount(&chars("/ktest_ro"), 0).is_ok());
+    kassert!(t, guard.check_writable(&chars("/ktest_ro/uptime")).is_ok());
+    kassert!(
+        t,
+        matches!(
+            guard.remount(&chars("/ktest_ro/uptime"), MS_RDONLY),
+            Err(VfsError::PathNotFound)
+        )
+    );
+    kassert!(t, guard.unmount(&chars("/ktest_ro")).is_ok());
+}
+
+fn mount_device_checks(t: &mut KTestContext) {
+    let chars = |s: &str| s.chars().collect::<Vec<char>>();
+    let options = MountOptions::default();
+    let target = chars("ktest_mnt");
+
+    kassert!(
+        t,
+        matches!(
+            mount_device("/proc/uptime", &target, "nofs", &options, 0),
+            Err(MountError::UnknownFileSystem(_))
+        )
+    );
+    kassert!(
+        t,
+        matches!(
+            mount_device("/proc/uptime", &target, "ext2", &options, 0),
+            Err(MountError::NotBlockDevice(_))
+        )
+    );
+    kassert!(
+        t,
+        matches!(
+            mount_device("/dev/missing", &target, "ext2", &options, 0),
+            Err(MountError::DeviceNotFound(_))
+        )
+    );
+}
+
+fn initramfs_unpack(t: &mut KTestContext) {
+    let chars = |s: &str| s.chars().collect::<Vec<char>>();
+    // bin/sh comes before its directory, which is created then gets the mode of its entry
+    let archive = build_cpio(&[
+        ("./bin/sh", 0o100750, b"hello"),
+        ("./bin", 0o040700, b""),
+        ("./sh", 0o120777, b"bin/sh"),
+        ("./dev/null", 0o020666, b""),
+    ]);
+    let mut fs = TmpFs::new();
+    kassert!(t, matches!(unpack_cpio(&mut fs, &archive), Ok(3)));
+    let vfs = get_vfs();
+    kassert!(
+        t,
+        vfs.write()
+            .mount(&chars("ktest_initrd"), Box::new(fs), 0)
+            .is_ok()
+    );
+
+    let sh = File::get_stats("/ktest_initrd/bin/sh");
+    kassert!(
+        t,
+        sh.is_ok_and(|s| s.is_some_and(|s| s.is_file && s.size == 5 && s.permissions == 0o750))
+    );
+    let bin = File::get_stats("/ktest_initrd/bin");
+    kassert!(
+        t,
+        bin.is_ok_and(|s| s.is_some_and(|s| s.is_directory && s.permissions == 0o700))
+    );
+    // Stats follow the link, the link itself is still there
+    let link = File::get_stats("/ktest_initrd/sh");
+    kassert!(
+        t,
+        link.is_ok_and(|s| s.is_some_and(|s| s.is_file && s.size == 5))
+    );
+    let link = vfs
+        .write()
+        .lookup(&VfsPath::from("/ktest_initrd/sh"), false);
+    kassert!(t, link.is_ok_and(|link| link.is_symlink()));
+    kassert!(
+        t,
+        matches!(File::get_stats("/ktest_initrd/dev/null"), Ok(None))
+    );
+
+    let mut buf = [0u8; 8];
+    let read = File::open(
+        "/ktest_initrd/bin/sh",
+        OPEN_MODE_READ,
+        Permissions::from_u64(0),
+        Credentials::Kernel,
+    )
+    .and_then(|file| file.read(&mut buf));
+    kassert!(t, read.is_ok_and(|read| &buf[..read as usize] == b"hello"));
+
+    let created = File::create(
+        "/ktest_initrd/new",
+        OPEN_MODE_WRITE,
+        Permissions::from_u64(0o644),
+        Credentials::Kernel,
+    )
+    .and_then(|mut file| file.write(b"data"));
+    kassert!(t, created.is_ok_and(|written| written == 4));
+    let statfs = vfs.write().statfs_path(&chars("/ktest_initrd"));
+    kassert!(t, statfs.is_ok_and(|stat| stat.fs_type == TMPFS_MAGIC));
+    kassert!(
+        t,
+        Vfs::unlink_path(&vfs, &chars("/ktest_initrd/new"), Credentials::Kernel).is_ok()
+    );
+    kassert!(t, vfs.write().unmount(&chars("ktest_initrd")).is_ok());
+
+    let mut fs = TmpFs::new();
+    let escaping = build_cpio(&[("../etc", 0o040755, b"")]);
+    kassert!(t, unpack_cpio(&mut fs, &escaping).is_err());
+}
+
+fn symlink_resolution(t: &mut KTestContext) {
+    let chars = |s: &str| s.chars().collect::<Vec<char>>();
+    let archive = build_cpio(&[
+        ("./dir", 0o040755, b""),
+        ("./dir/file", 0o100644, b"target"),
+        ("./first", 0o120777, b"second"),
+        ("./second", 0o120777, b"dir/../link_dir/file"),
+        ("./link_dir", 0o120777, b"/ktest_links/dir"),
+        ("./loop_a", 0o120777, b"loop_b"),
+        ("./loop_b", 0o120777, b"./loop_a"),
+        ("./dangling", 0o120777, b"missing"),
+    ]);
+    let mut fs = TmpFs::new();
+    kassert!(t, matches!(unpack_cpio(&mut fs, &archive), Ok(8)));
+    let vfs = get_vfs();
+    kassert!(
+        t,
+        vfs.write()
+            .mount(&chars("ktest_links"), Box::new(fs), 0)
+            .is_ok()
+    );
+    let open = |path: &str| {
+        File::open(
+            path,
+            OPEN_MODE_READ,
+            Permissions::from_u64(0),
+            Credentials::Kernel,
+        )
+    };
+
+    // A relative link to a relative link to an absolute directory link
+    let mut buf = [0u8; 8];
+    let read = open("/ktest_links/first").and_then(|file| file.read(&mut buf));
+    kassert!(t, read.is_ok_and(|read| &buf[..read as usize] == b"target"));
+    let stat = File::get_stats("/ktest_links/link_dir/file");
+    kassert!(
+        t,
+        stat.is_ok_and(|s| s.is_some_and(|s| s.is_file && s.size == 6))
+    );
+
+    let looping = open("/ktest_links/loop_a");
+    kassert!(t, matches!(looping, Err(VfsError::SymlinkLoop)));
+    kassert!(
+        t,
+        matches!(
+            vfs.write()
+                .get_file(&VfsPath::from("/ktest_links/loop_b/file")),
+            Err(VfsError::SymlinkLoop)
+        )
+    );
+    kassert_eq!(t, vfs_err_to_linux_errno(VfsError::SymlinkLoop), ELOOP);
+
+    kassert!(
+        t,
+        matches!(open("/ktest_links/dangling"), Err(VfsError::PathNotFound))
+    );
+    let dangling = vfs
+        .write()
+        .lookup(&VfsPath::from("/ktest_links/dangling"), false);
+    kassert!(t, dangling.is_ok_and(|link| link.is_symlink()));
+
+    // Unlinking removes the link, not its target
+    kassert!(
+        t,
+        Vfs::unlink_path(&vfs, &chars("/ktest_links/first"), Credentials::Kernel).is_ok()
+    );
+    kassert!(t, matches!(File::get_stats("/ktest_links/first"), Ok(None)));
+    kassert!(
+        t,
+        matches!(File::get_stats("/ktest_links/second"), Ok(Some(_)))
+    );
+    kassert!(t, vfs.write().unmount(&chars("ktest_links")).is_ok());
+}
+
+fn elf_interpreter_loading(t: &mut KTestContext) {
+    let chars = |s: &str| s.chars().collect::<Vec<char>>();
+    // The second program header becomes PT_INTERP, over the same payload as the load segment
+    let executable = |interpreter: &[u8]| {
+        let len = interpreter.len() as u64;
+        let mut elf = build_elf(2, &[(0x40_0000, len, 0x1000), (0, len, len)], interpreter);
+        let offset =
+            size_of::<crate::formats::elf::Elf64HeaderRaw>() + size_of::<Elf64ProgramHeaderRaw>();
+        elf[offset..offset + 4].copy_from_slice(&3u32.to_le_bytes());
+        Elf64File::from_bytes(elf.into_boxed_slice())
+    };
+
+    let loader = build_elf(3, &[(0x1000, 4, 0x1000)], b"ld.s");
+    let mut fs = TmpFs::new();
+    let archive = build_cpio(&[("./ld.so", 0o100755, &loader)]);
+    kassert!(t, unpack_cpio(&mut fs, &archive).is_ok());
+    let vfs = get_vfs();
+    kassert!(
+        t,
+        vfs.write()
+            .mount(&chars("ktest_interp"), Box::new(fs), 0)
+            .is_ok()
+    );
+
+    match executable(b"/ktest_interp/ld.so\0").and_then(|elf| elf.load_process_image()) {
+        Ok(image) => {
+            let base = image.aux.iter().find_map(|(key, value)| match value {
+                AuxValue::Value(base) if *key == AT_BASE => Some(*base),
+                _ => None,
+            });
+            kassert!(t, base.is_some_and(|base| base != 0));
+            let base = base.unwrap_or_default();
+            kassert_eq!(t, image.entry, base + 0x1000);
+            kassert!(
+                t,
+                image.aux.contains(&(AT_ENTRY, AuxValue::Value(0x40_1000)))
+            );
+            kassert!(t, image.pages.contains_key(&0x40_0000));
+            kassert_eq!(
+                t,
+                image.pages.get(&(base + 0x1000)).map(|page| &page[..4]),
+                Some(&b"ld.s"[..])
+            );
+        }
+        Err(e) => kassert!(t, false, "dynamic executable rejected: {:?}", e),
+    }
+
+    let missing =
+        executable(b"/ktest_interp/missing.so\0").and_then(|elf| elf.load_process_image());
+    kassert!(
+        t,
+        matches!(
+            missing,
+            Err(ElfError::Interpreter { ref path, ref error })
+                if path == "/ktest_interp/missing.so"
+                    && matches!(**error, ElfError::InputOutput(VfsError::PathNotFound))
+        )
+    );
+    kassert!(
+        t,
+        matches!(
+            executable(b"ld.so\0").and_then(|elf| elf.load_process_image()),
+            Err(ElfError::InvalidSegment { index: 1, .. })
+        )
+    );
+
+    kassert!(t, vfs.write().unmount(&chars("ktest_interp")).is_ok());
+}
+
+fn memory_devices(t: &mut KTestContext) {
+    let open = |path: &str, credentials: Credentials| {
+        File::open(
+            path,
+            OPEN_MODE_READ | OPEN_MODE_WRITE,
+            Permissions::from_u64(0),
+            credentials,
+        )
+    };
+
+    let Ok(mut zero) = open("/dev/zero", Credentials::Kernel) else {
+        kassert!(t, false, "failed to open /dev/zero");
+        return;
+    };
+    let mut buf = [0xAAu8; 16];
+    kassert!(t, matches!(zero.read(&mut buf), Ok(16)));
+    kassert_eq!(t, buf, [0; 16]);
+    kassert!(t, matches!(zero.write(b"discarded"), Ok(9)));
+    kassert!(t, zero.close().is_ok());
+
+    let Ok(mut full) = open("/dev/full", Credentials::Kernel) else {
+        kassert!(t, false, "failed to open /dev/full");
+        return;
+    };
+    buf.fill(0xAA);
+    kassert!(t, matches!(full.read(&mut buf), Ok(16)));
+    kassert_eq!(t, buf, [0; 16]);
+    kassert!(t, matches!(full.write(b"x"), Err(VfsError::OutOfSpace)));
+    kassert!(t, full.close().is_ok());
+
+    let user = Credentials::User {
+        uid: 1000,
+        gid: 1000,
+        supplementary_gids: &[],
+    };
+    kassert!(
+        t,
+        matches!(open("/dev/mem", user), Err(VfsError::PermissionDenied))
+    );
+    let Ok(mut mem) = open("/dev/mem", Credentials::Kernel) else {
+        kassert!(t, false, "failed to open /dev/mem");
+        return;
+    };
+    // Below the kernel image
+    kassert_eq!(
+        t,
+        mem.seek(SeekPosition::FromStart(0x1000)).ok(),
+        Some(0x1000)
+    );
+    kassert!(
+        t,
+        matches!(mem.read(&mut buf), Err(VfsError::PermissionDenied))
+    );
+
+    let word = Box::new(0x0123_4567_89ab_cdefu64);
+    let phys = &*word as *const u64 as u64 - DIRECT_MAPPING_OFFSET;
+    let mut read = [0u8; 8];
+    kassert_eq!(t, mem.seek(SeekPosition::FromStart(phys)).ok(), Some(phys));
+    kassert!(t, matches!(mem.read(&mut read), Ok(8)));
+    kassert_eq!(t, u64::from_le_bytes(read), 0x0123_4567_89ab_cdef);
+    kassert_eq!(t, mem.seek(SeekPosition::FromCurrent(-8)).ok(), Some(phys));
+    kassert!(t, matches!(mem.write(&0x1111u64.to_le_bytes()), Ok(8)));
+    kassert_eq!(t, unsafe { core::ptr::read_volatile(&*word) }, 0x1111);
+    kassert_eq!(
+        t,
+        mem.seek(SeekPosition::FromStart(u64::MAX - 4)).ok(),
+        Some(u64::MAX - 4)
+    );
+    kassert!(t, mem.read(&mut read).is_err());
+    kassert!(t, mem.close().is_ok());
+}
+
+fn devfs_pci_tree(t: &mut KTestContext) {
+    let open = |path: &str, mode: u64, credentials: Credentials| {
+        File::open(path, mode, Permissions::from_u64(0), credentials)
+    };
+    let user = Credentials::User {
+        uid: 1000,
+        gid: 1000,
+        supplementary_gids: &[],
+    };
+
+    let Some(device) = pci::device_iterator().next() else {
+        log_info!("No PCI device, skipping");
+        return;
+    };
+    let directory = format!("/dev/{}", dev_pci::pci_device_path(&device));
+
+    let Ok(mut vendor) = open(&format!("{directory}/vendor"), OPEN_MODE_READ, user) else {
+        kassert!(t, false, "failed to open the vendor file");
+        return;
+    };
+    let mut buf = [0u8; 16];
+    let expected = format!("{:#06x}\n", device.vendor_id);
+    kassert!(t, matches!(vendor.read(&mut buf), Ok(7)));
+    kassert_eq!(t, &buf[..7], expected.as_bytes());
+    kassert!(t, vendor.write(b"0").is_err());
+    kassert!(t, vendor.close().is_ok());
+
+    let config_path = format!("{directory}/config");
+    kassert!(
+        t,
+        matches!(
+            open(&config_path, OPEN_MODE_WRITE, user),
+            Err(VfsError::PermissionDenied)
+        )
+    );
+    let Ok(config) = open(&config_path, OPEN_MODE_READ, user) else {
+        kassert!(t, false, "failed to open the config file");
+        return;
+    };
+    kassert!(t, matches!(config.read(&mut buf[..4]), Ok(4)));
+    kassert_eq!(
+        t,
+        u32::from_le_bytes(buf[..4].try_into().unwrap()),
+        device.read_config(0)
+    );
+    kassert!(t, config.close().is_ok());
+
+    let generation = pci::scan_generation();
+    let Ok(mut rescan) = open("/dev/pci/rescan", OPEN_MODE_WRITE, Credentials::Kernel) else {
+        kassert!(t, false, "failed to open the rescan file");
+        return;
+    };
+    kassert!(t, matches!(rescan.write(b"1"), Ok(1)));
+    kassert!(t, rescan.close().is_ok());
+    kassert!(t, pci::scan_generation() > generation);
+    kassert!(
+        t,
+        open(&format!("{directory}/vendor"), OPEN_MODE_READ, user).is_ok_and(|f| f.close().is_ok())
+    );
+}
+
+fn loop_device_attach(t: &mut KTestContext) {
+    let chars = |s: &str| s.chars().collect::<Vec<char>>();
+    // Block i is filled with i
+    let image = (0..4u8).flat_map(|block| [block; 512]).collect::<Vec<u8>>();
+    let mut fs = TmpFs::new();
+    let archive = build_cpio(&[("./disk.img", 0o100644, &image)]);
+    kassert!(t, unpack_cpio(&mut fs, &archive).is_ok());
+    let vfs = get_vfs();
+    kassert!(
+        t,
+        vfs.write()
+            .mount(&chars("ktest_loop"), Box::new(fs), 0)
+            .is_ok()
+    );
+    let open = |path: &str| {
+        File::open(
+            path,
+            OPEN_MODE_READ | OPEN_MODE_WRITE,
+            Permissions::from_u64(0),
+            Credentials::Kernel,
+        )
+    };
+
+    let Ok(backing) = open("/ktest_loop/disk.img") else {
+        kassert!(t, false, "failed to open the image");
+        return;
+    };
+    kassert!(
+        t,
+        matches!(attach_loop(backing, 500), Err(VfsError::InvalidArgument))
+    );
+    let Ok(Ok(index)) = open("/ktest_loop/disk.img").map(|file| attach_loop(file, 512)) else {
+        kassert!(t, false, "failed to attach the image");
+        return;
+    };
+    let path = alloc::format!("/dev/loop{index}");
+    let device = vfs
+        .write()
+        .get_file(&VfsPath::from(path.as_str()))
+        .ok()
+        .and_then(|file| file.get_block_device());
+    let Some(device) = device else {
+        kassert!(t, false, "{} isn't a block device", path);
+        return;
+    };
+    let mut block = [0u8; 512];
+    kassert_eq!(t, device.read().get_block_count(), 4);
+    kassert!(
+        t,
+        matches!(device.read().read_block(2, &mut block), Ok(512))
+    );
+    kassert_eq!(t, block, [2; 512]);
+    kassert!(
+        t,
+        matches!(device.write().write_block(1, &[0xEE; 512]), Ok(512))
+    );
+    kassert!(
+        t,
+        matches!(
+            device.read().read_block(4, &mut block),
+            Err(VfsError::OutOfBounds)
+        )
+    );
+    drop(device);
+
+    // Written through to the file
+    if let Ok(file) = open("/ktest_loop/disk.img") {
+        let mut bytes = [0u8; 4];
+        kassert!(t, file.seek(SeekPosition::FromStart(510)).is_ok());
+        kassert!(t, matches!(file.read(&mut bytes), Ok(4)));
+        kassert_eq!(t, bytes, [0, 0, 0xEE, 0xEE]);
+    }
+
+    // Not an ext2 image, but accepted as a device
+    let options = MountOptions::default();
+    kassert!(
+        t,
+        matches!(
+            mount_device(&path, &chars("ktest_loop_mnt"), "ext2", &options, 0),
+            Err(MountError::Vfs(_))
+        )
+    );
+
+    let Ok(opened) = open(&path) else {
+        kassert!(t, false, "failed to open {}", path);
+        return;
+    };
+    let mut bytes = [0u8; 2];
+    kassert!(t, opened.seek(SeekPosition::FromStart(1023)).is_ok());
+    kassert!(t, matches!(opened.read(&mut bytes), Ok(2)));
+    kassert_eq!(t, bytes, [0xEE, 2]);
+    kassert!(t, matches!(detach_loop(index), Err(VfsError::Busy)));
+    kassert!(t, opened.close().is_ok());
+    kassert!(t, detach_loop(index).is_ok());
+    kassert!(t, matches!(detach_loop(index), Err(VfsError::NoSuchDevice)));
+    kassert!(
+        t,
+        vfs.write().get_file(&VfsPath::from(path.as_str())).is_err()
+    );
+
+    // The device closed the image
+    kassert!(t, vfs.write().unmount(&chars("ktest_loop")).is_ok());
+}
+
+fn process_teardown_releases_everything(t: &mut KTestContext) {
+    // Frames and handles are compared exactly, only debug builds run it
+    if !cfg!(debug_assertions) {
+        return;
+    }
+    let chars = |s: &str| s.chars().collect::<Vec<char>>();
+    let vfs = get_vfs();
+    kassert!(
+        t,
+        vfs.write()
+            .mount(&chars("ktest_teardown"), Box::new(TmpFs::new()), 0)
+            .is_ok()
+    );
+    let Ok(file) = File::create(
+        "/ktest_teardown/f",
+        OPEN_MODE_WRITE,
+        Permissions::from_u64(0o644),
+        Credentials::Kernel,
+    ) else {
+        kassert!(t, false, "failed to create the file");
+        let _ = vfs.write().unmount(&chars("ktest_teardown"));
+        return;
+    };
+    let fs = file.get_vfs_file().fs();
+    drop(file);
+
+    // The first process also grows the scheduler queues, which stay allocated
+    spawn_and_reap(t);
+    let frames = get_memory_stats().free_pages;
+    let handles = open_path_handles(fs);
+    spawn_and_reap(t);
+    kassert_eq!(t, get_memory_stats().free_pages, frames);
+    kassert_eq!(t, open_path_handles(fs), handles);
+
+    // Fails while a handle on it is still open
+    kassert!(t, vfs.write().unmount(&chars("ktest_teardown")).is_ok());
+}
+
+/// Creates a process holding a file behind two fds, ends it without running it and reaps it
+fn spawn_and_reap(t: &mut KTestContext) {
+    let header_size = size_of::<FlatHeader>() as u64;
+    let flat = build_flat(0x40_0000, header_size, &[0xeb, 0xfe]);
+    let Ok(flat) = FlatBinary::from_bytes(flat.into_boxed_slice()) else {
+        kassert!(t, false, "flat binary rejected");
+        return;
+    };
+    let options = flat.load_image().instantiate(ExecutableInstantiateOptions {
+        name: "ktest".into(),
+        path: "ktest".into(),
+        parent_pid: 0,
+        cmdline: vec!["ktest".into()],
+        cwd: "/".into(),
+        environment: Vec::new(),
+        uid: 0,
+        gid: 0,
+        supplementary_gids: Vec::new(),
+    });
+    let Ok(options) = options else {
+        kassert!(t, false, "failed to instantiate the flat binary");
+        return;
+    };
+    let Ok(stdin) = File::open(
+        "/dev/null",
+        OPEN_MODE_READ,
+        Permissions::from_u64(0),
+        Credentials::Kernel,
+    ) else {
+        kassert!(t, false, "failed to open /dev/null");
+        return;
+    };
+    let Ok((pid, stdout, stderr)) = SCHEDULER.create_process(options, stdin, None, None) else {
+        kassert!(t, false, "failed to create the process");
+        return;
+    };
+
+    let path = "/ktest_teardown/f".chars().collect::<Vec<char>>();
+    let opened = Vfs::open_path(
+        &get_vfs(),
+        &path,
+        OPEN_MODE_READ,
+        Permissions::from_u64(0),
+        Credentials::Kernel,
+    );
+    let fs = match (&opened, SCHEDULER.get_process(pid)) {
+        (Ok(opened), Some(process)) => {
+            let mut gfs = opened.fs.write();
+            let seekable = gfs.fseekable(opened.handle);
+            let identity = gfs.file_identity(&opened.file);
+            let id = gfs.os_id();
+            drop(gfs);
+            let file = OpenFile::opened(
+                opened.fs.clone(),
+                opened.handle,
+                seekable,
+                false,
+                Some(identity),
+            );
+            let mut io = process.io_context.lock();
+            let fd = io.file_table.alloc_fd().map(|(fd, slot)| {
+                *slot = Some(file);
+                fd
+            });
+            kassert!(t, fd.and_then(|fd| io.file_table.dup(fd)).is_some());
+            Some(id)
+        }
+        _ => {
+            kassert!(t, false, "failed to open a file for the process");
+            None
+        }
+    };
+    let open = fs.map(open_path_handles);
+
+    SCHEDULER.teardown(pid, 0);
+    kassert!(t, SCHEDULER.get_process(pid).is_none());
+    let zombie = SCHEDULER.get_zombie_thread(pid);
+    kassert!(
+        t,
+        zombie.is_some_and(|zombie| matches!(
+            *zombie.thread.process.state.lock(),
+            TaskState::Zombie { exit_code: 0 }
+        ))
+    );
+    // Closed once, with the second fd
+    kassert_eq!(t, fs.map(open_path_handles), open.map(|open| open - 1));
+
+    SCHEDULER.remove_zombie(pid);
+    kassert!(t, SCHEDULER.get_zombie_thread(pid).is_none());
+    drop((stdout, stderr));
+}
+
+fn allocation_failures(t: &mut KTestContext) {
+    const SIZE: usize = 2 * 1024 * 1024;
+    let chars = |s: &str| s.chars().collect::<Vec<char>>();
+    let vfs = get_vfs();
+    kassert!(
+        t,
+        vfs.write()
+            .mount(&chars("ktest_oom"), Box::new(TmpFs::new()), 0)
+            .is_ok()
+    );
+    let elf = build_elf(2, &[(0x40_0000, 4, 0x1000)], &vec![0x90; SIZE]);
+    let written = File::create(
+        "/ktest_oom/big",
+        OPEN_MODE_WRITE,
+        Permissions::from_u64(0o755),
+        Credentials::Kernel,
+    )
+    .and_then(|mut file| file.write(&elf));
+    kassert_eq!(t, written.ok(), Some(elf.len() as u64));
+    drop(elf);
+    let Ok(file) = File::open(
+        "/ktest_oom/big",
+        OPEN_MODE_READ,
+        Permissions::from_u64(0),
+        Credentials::Kernel,
+    ) else {
+        kassert!(t, false, "failed to open the executable");
+        let _ = vfs.write().unmount(&chars("ktest_oom"));
+        return;
+    };
+    let mut held = try_alloc_boxed_slice::<u8>(SIZE);
+    kassert!(t, held.is_some());
+
+    // Room for what the interrupts allocate meanwhile, not for another big buffer. Nothing
+    // allocates infallibly until the limit is lifted
+    set_heap_limit(Some(
+        get_memory_stats().heap_allocated_bytes + SIZE as u64 / 2,
+    ));
+    let failed = try_alloc_boxed_slice::<u8>(SIZE).is_none();
+    // Consumers report the failure instead of panicking
+    let pipe = Pipe::try_new_anonymous(SIZE).map(|_| ());
+    // The segments are read straight into their pages, the file is never held in memory
+    let loaded = Elf64File::try_parse(file)
+        .and_then(|elf| elf.load_image(0))
+        .map(|image| image.pages.len());
+    let mut reclaimed = false;
+    let retried = try_alloc_boxed_slice_or_reclaim::<u8>(SIZE, || {
+        reclaimed = true;
+        held = None;
+    });
+    set_heap_limit(None);
+
+    kassert!(t, failed);
+    kassert!(t, matches!(pipe, Err(VfsError::OutOfMemory)), "{:?}", pipe);
+    kassert!(t, matches!(loaded, Ok(1)), "{:?}", loaded);
+    kassert!(t, reclaimed);
+    kassert!(t, retried.is_some());
+    drop(retried);
+
+    kassert!(t, vfs.write().unmount(&chars("ktest_oom")).is_ok());
+}
@@ -3,24 +3,25 @@
 #![feature(unsafe_cell_access)]
 #![feature(sync_unsafe_cell)]
 
-use core::num::NonZeroUsize;
-
-use alloc::{boxed::Box, format, string::ToString, vec::Vec};
+use alloc::{format, string::ToString};
 use data::file::File;
 use drivers::{
-    fs::phys::ext2::Ext2Volume,
+    fs::{
+        initramfs,
+        mount::{self, SYSTEM_READ_AHEAD_BLOCKS},
+    },
     pci,
-    vfs::{get_vfs, OPEN_MODE_READ, OPEN_MODE_WRITE},
+    vfs::{OPEN_MODE_READ, OPEN_MODE_WRITE},
 };
 use memory::mem::OsMemoryRegion;
-use obsiboot::ObsiBootKernelParameters;
+use obsiboot::{ObsiBootError, ObsiBootKernelParameters};
 use paging::{init_paging, physical_to_virtual};
 use process::{executable::parse_executable, scheduler::SCHEDULER};
 
 use crate::{
     bios::{get_bda, BiosDataArea},
     config::{get_kernel_config, init_kernel_config},
-    data::permissions::Permissions,
+    data::permissions::{Credentials, Permissions},
     drivers::{
         ports::parallel::lpt1,
         vfs::{self, OPEN_MODE_APPEND},
@@ -32,122 +33,171 @@ use crate::{
 extern crate alloc;
 
 pub mod bios;
+pub mod cmdline;
 pub mod config;
+pub mod crypto;
 pub mod data;
 pub mod drivers;
 pub mod formats;
 pub mod gdt;
 pub mod interrupts;
 pub mod io;
+pub mod ktest;
 pub mod log;
 pub mod memory;
+pub mod net;
 pub mod obsiboot;
 pub mod paging;
 pub mod percpu;
+pub mod power;
 pub mod process;
 pub mod syscalls;
 pub mod vesa;
 
-fn _start_with_log_buffer(obsiboot: &mut ObsiBootKernelParameters, bios_data: &BiosDataArea) {
-    unsafe {
-        let mut buffer = [0u8; 16384];
-        get_stdout().unsafe_set_fixed_size_buffer(buffer.as_mut_ptr(), buffer.len());
-
-        println!("Campix Kernel");
-        println!("{:#?}", obsiboot);
-        println!("{:#?}", bios_data);
-        println!();
+/// Holds the log until the heap is up, too big for the boot stack
+static mut BOOT_LOG_BUFFER: [u8; 16384] = [0; 16384];
 
-        if obsiboot.obsiboot_struct_version != 1 {
-            let version = obsiboot.obsiboot_struct_version;
-            panic!("Unsupported ObsiBoot struct version: {}", version);
-        }
+fn _start_with_log_buffer(obsiboot_ptr: u64, bios_data: &BiosDataArea) -> ObsiBootKernelParameters {
+    unsafe {
+        let buffer = &raw mut BOOT_LOG_BUFFER;
+        get_stdout().unsafe_set_fixed_size_buffer(buffer as *mut u8, (*buffer).len());
 
-        if !obsiboot.verify_checksum() {
-            panic!("Invalid ObsiBoot struct checksum");
-        }
+        log_info!("Campix Kernel");
+        log_debug!("{:#?}", bios_data);
 
+        let obsiboot = match ObsiBootKernelParameters::read(obsiboot_ptr) {
+            Ok(obsiboot) => obsiboot,
+            Err(ObsiBootError::UnsupportedVersion(version)) => {
+                panic!("Unsupported ObsiBoot struct version: {}", version)
+            }
+            Err(ObsiBootError::Truncated {
+                version,
+                size,
+                expected,
+            }) => panic!(
+                "ObsiBoot struct version {} is truncated: {} bytes, expected at least {}",
+                version, size, expected
+            ),
+            Err(ObsiBootError::BadChecksum {
+                version,
+                expected,
+                computed,
+            }) => panic!(
+                "Invalid ObsiBoot struct version {} checksum: expected {:x?}, computed {:x?}",
+                version, expected, computed
+            ),
+        };
+        log_debug!("{:#?}", obsiboot);
+
+        let (memory_layout, memory_layout_entries) = obsiboot.memory_layout();
+        let (arena_current, arena_last) = obsiboot.page_table_arena();
         init_paging(
-            obsiboot.ptr_to_memory_layout as *const OsMemoryRegion,
-            obsiboot.memory_layout_entry_count as u64,
-            obsiboot.pml4_base_address as u64,
-            obsiboot.page_tables_page_allocator_current_free_page as u64,
-            obsiboot.page_tables_page_allocator_last_usable_page as u64,
-            obsiboot.kernel_stack_pointer,
+            memory_layout as *const OsMemoryRegion,
+            memory_layout_entries,
+            obsiboot.pml4_base_address(),
+            arena_current,
+            arena_last,
+            obsiboot.kernel_stack_pointer(),
         );
-        println!("Paging initialized");
+        log_info!("Paging initialized");
 
         gdt::init_gdtr();
-        println!("GDT initialized");
+        log_info!("GDT initialized");
 
         memory::mem::init(
-            physical_to_virtual(obsiboot.ptr_to_memory_layout as u64) as *const OsMemoryRegion,
-            obsiboot.memory_layout_entry_count as u64,
-            obsiboot.pml4_base_address as u64,
-            obsiboot.usable_kernel_memory_start as u64,
+            physical_to_virtual(memory_layout) as *const OsMemoryRegion,
+            memory_layout_entries,
+            obsiboot.pml4_base_address(),
+            obsiboot.usable_kernel_memory_start(),
         );
-        println!("Memory allocator initialized");
+        log_info!("Memory allocator initialized");
+
+        memory::entropy::init_entropy();
+        log_info!("Entropy source initialized");
 
         get_stdout().switch_to_heap();
+        obsiboot
     }
 }
 
 #[no_mangle]
 pub fn _start(obsiboot_ptr: u64) -> ! {
-    let mut obsiboot =
-        unsafe { core::ptr::read_volatile(obsiboot_ptr as *const ObsiBootKernelParameters) };
-
     let bios_data = get_bda();
 
-    _start_with_log_buffer(&mut obsiboot, bios_data);
+    let obsiboot = _start_with_log_buffer(obsiboot_ptr, bios_data);
 
     unsafe {
         percpu::init_per_cpu(0);
-        println!("Per-CPU initialized");
+        log_info!("Per-CPU initialized");
+
+        let fpu_area_size = process::fpu::init_fpu();
+        log_info!(
+            "FPU initialized, {} byte state with {}",
+            fpu_area_size,
+            if process::fpu::uses_xsave() {
+                "xsave"
+            } else {
+                "fxsave"
+            }
+        );
+
+        process::time_page::init_time_page();
+        log_info!("Time page initialized");
 
         interrupts::init();
-        println!("Interrupts initialized");
+        log_info!("Interrupts initialized");
 
         {
-            println!("\nEnumerating PCI devices:");
+            log_debug!(target: "pci", "Enumerating PCI devices");
             let devices = pci::scan_bus();
             for device in devices.iter() {
-                println!("{:?}", device);
+                log_debug!(target: "pci", "{:?}", device);
             }
         }
 
         vesa::parse_current_mode(&obsiboot);
-        println!("VESA initialized");
+        log_info!("VESA initialized");
+
+        if let Some(name) = obsiboot.bootloader_name() {
+            let [major, minor, patch, build] = obsiboot.bootloader_version();
+            log_info!(
+                "Booted by {} {}.{}.{}.{} (ObsiBoot v{})",
+                obsiboot::read_boot_string(name),
+                major,
+                minor,
+                patch,
+                build,
+                obsiboot.version()
+            );
+        }
+        if let Some(command_line) = obsiboot.command_line() {
+            cmdline::init_command_line(&obsiboot::read_boot_string(command_line));
+            log_info!("Command line: {}", cmdline::get_command_line().as_str());
+        }
+        if let Some(rsdp) = obsiboot.acpi_rsdp() {
+            drivers::acpi::set_boot_rsdp(rsdp);
+        }
 
         vfs::get_vfs();
-        println!("VFS initialized");
+        log_info!("VFS initialized");
 
         syscalls::init();
-        println!("Syscalls initialized");
+        log_info!("Syscalls initialized");
 
-        {
-            let file = File::open(
-                "/dev/pata_pm_p0",
-                OPEN_MODE_READ | OPEN_MODE_WRITE,
-                Permissions::from_u64(0),
-            )
-            .unwrap();
-            let ext2 = Ext2Volume::from_device(
-                file,
-                NonZeroUsize::new(1024 * 1024).unwrap(),
-                NonZeroUsize::new(1024 * 1024).unwrap(),
-                NonZeroUsize::new(1024 * 1024).unwrap(),
-            )
-            .unwrap();
-
-            let vfs = get_vfs();
-            let mut wguard = vfs.write();
-            wguard
-                .mount(&"system".chars().collect::<Vec<char>>(), Box::new(ext2))
-                .unwrap();
-            drop(wguard);
+        if data::regs::fs_gs_base::init_fsgsbase() {
+            log_info!("FSGSBASE enabled");
         }
 
+        net::init();
+        log_info!("Network initialized");
+
+        if initramfs::mount_initramfs(&obsiboot) {
+            log_info!("Initramfs mounted");
+        }
+
+        mount::mount_all();
+        log_info!("File systems mounted");
+
         kmain(obsiboot);
     }
 }
@@ -174,12 +224,21 @@ pub fn kpanic_no_log(msg: &[u8]) {
 fn panic(info: &core::panic::PanicInfo) -> ! {
     unsafe {
         _handle_panic(info);
+        if let Some(config) = config::try_get_kernel_config() {
+            if config.panic_exits_qemu {
+                io::qemu_exit(io::QEMU_EXIT_PANIC);
+            }
+            if config.reboot_on_panic {
+                io::reboot();
+            }
+        }
         core::arch::asm!("cli", "hlt");
     }
     loop {}
 }
 
 unsafe fn _handle_panic(info: &core::panic::PanicInfo) {
+    get_stdout().panic_flush_console();
     if cfg!(debug_assertions) {
         if let Some(lpt) = lpt1() {
             get_stdout().panic_dump_to(lpt);
@@ -214,22 +273,51 @@ unsafe fn _handle_panic(info: &core::panic::PanicInfo) {
 unsafe fn kmain(obsiboot: ObsiBootKernelParameters) -> ! {
     let mode = vesa::get_mode_info();
 
-    println!("Kernel display using vesa mode {:#?}", mode);
-    println!("Available modes:");
+    log_debug!(target: "vesa", "Kernel display using vesa mode {:#?}", mode);
+    log_debug!(target: "vesa", "Available modes:");
     for (mode, info) in vesa::iter_modes(&obsiboot) {
         let vesa::VesaModeInfoStructure {
             width, height, bpp, ..
         } = info;
-        println!("{}: {}x{}:{}bpp", mode, width, height, bpp);
+        log_debug!(target: "vesa", "{}: {}x{}:{}bpp", mode, width, height, bpp);
     }
-    println!();
 
     init_kernel_config();
+    let config = get_kernel_config();
+    net::arp::set_arp_cache_timeout(config.arp_cache_timeout_ms);
+    get_stdout().set_ring_limit(config.log_buffer_limit as usize);
+    match u8::try_from(config.kmsg_write_level)
+        .ok()
+        .and_then(log::KernelLogLevel::from_u8)
+    {
+        Some(level) => log::set_kmsg_write_level(level),
+        None => log_warn!(target: "config", "Invalid kmsg.write_level {}", config.kmsg_write_level),
+    }
+    match log::LogLevel::from_name(&config.log_level) {
+        Some(level) => log::set_log_level(None, level).unwrap(),
+        None => log_warn!(target: "config", "Invalid kernel.loglevel {}", config.log_level),
+    }
+    if config.ramdisk_size != 0 {
+        if let Err(err) = drivers::disk::ramdisk::create_ramdisk(config.ramdisk_size) {
+            log_warn!(target: "config", "Could not create /dev/ram0: {:?}", err);
+        }
+    }
+    if !config.keymap.is_empty() {
+        if let Err(err) = drivers::keyboard::keymap::set_keymap(Some(&config.keymap)) {
+            log_warn!(
+                target: "config",
+                "Could not load the keymap {}, using the built-in one: {:?}",
+                config.keymap,
+                err
+            );
+        }
+    }
     let mut log_file = match File::get_stats(&get_kernel_config().kernel_log_file).unwrap() {
         Some(_) => File::open(
             &get_kernel_config().kernel_log_file,
             OPEN_MODE_WRITE | OPEN_MODE_APPEND,
             Permissions::from_u64(0),
+            Credentials::Kernel,
         )
         .unwrap(),
 
@@ -239,6 +327,7 @@ unsafe fn kmain(obsiboot: ObsiBootKernelParameters) -> ! {
                     "/dev/lpt1",
                     OPEN_MODE_WRITE | OPEN_MODE_APPEND,
                     Permissions::from_u64(0),
+                    Credentials::Kernel,
                 )
                 .unwrap()
             } else {
@@ -246,6 +335,7 @@ unsafe fn kmain(obsiboot: ObsiBootKernelParameters) -> ! {
                     "/dev/null",
                     OPEN_MODE_WRITE | OPEN_MODE_APPEND,
                     Permissions::from_u64(0),
+                    Credentials::Kernel,
                 )
                 .unwrap()
             }
@@ -257,40 +347,59 @@ unsafe fn kmain(obsiboot: ObsiBootKernelParameters) -> ! {
 
     get_stdout().switch_to_pipe(log_file);
 
-    let stats = match File::get_stats("/system/sysinit") {
+    if cfg!(debug_assertions) || get_kernel_config().ktest_enabled {
+        let summary = ktest::run_all();
+        if get_kernel_config().ktest_exit_qemu {
+            ktest::exit_qemu(&summary);
+        }
+    }
+    if cfg!(debug_assertions) && get_kernel_config().ktest_stack_overflow {
+        ktest::overflow_kernel_stack(0);
+    }
+
+    let sysinit = initramfs::find_sysinit();
+    let stats = match File::get_stats(sysinit) {
         Ok(Some(stats)) => stats,
         Ok(None) => {
-            println!("Initial executable /system/sysinit not found, make sure it exists in the system partition, then reboot.");
-            println!();
+            log_error!("Initial executable {} not found, make sure it exists in the system partition, then reboot.", sysinit);
             panic!("Campix: failed to boot...");
         }
         Err(err) => {
-            println!("Could not get stats for /system/sysinit");
-            println!("Error: {:#?}", err);
-            println!();
+            log_error!("Could not get stats for {}", sysinit);
+            log_error!("Error: {:#?}", err);
             panic!("Campix: failed to boot...");
         }
     };
 
     if !stats.is_file {
-        println!("Initial executable /system/sysinit is not a file, make sure it exists in the system partition and that it is not a symlink.");
-        println!();
+        log_error!("Initial executable {} is not a file, make sure it exists in the system partition and that it is not a symlink.", sysinit);
         panic!("Campix: failed to boot...");
     }
 
-    let executable = match parse_executable("/system/sysinit") {
+    let load_begin = unsafe { core::arch::x86_64::_rdtsc() };
+    let executable = match parse_executable(sysinit, Credentials::Kernel) {
         Ok(executable) => executable,
         Err(err) => {
-            println!("Could not parse /system/sysinit");
-            println!("Errors: {:#?}", err);
-            println!();
+            log_error!("Could not parse {}", sysinit);
+            log_error!("Errors: {:#?}", err);
             panic!("Campix: failed to boot...");
         }
     };
+    if cfg!(debug_assertions) {
+        // Compare with SYSTEM_READ_AHEAD_BLOCKS = 0 to measure the read-ahead gain
+        log_debug!(
+            "Loaded {} in {} TSC cycles (read-ahead: {} blocks)",
+            sysinit,
+            unsafe { core::arch::x86_64::_rdtsc() } - load_begin,
+            SYSTEM_READ_AHEAD_BLOCKS
+        );
+    }
 
     let options = match executable.create_process(ExecutableInstantiateOptions {
         name: "sysinit".to_string(),
-        cmdline: alloc::vec!["/system/sysinit".to_string()],
+        path: sysinit.to_string(),
+        parent_pid: 0,
+        cmdline: alloc::vec![sysinit.to_string()],
         cwd: "/".to_string(),
         environment: alloc::vec![],
         uid: 0,
@@ -299,9 +408,8 @@ unsafe fn kmain(obsiboot: ObsiBootKernelParameters) -> ! {
     }) {
         Ok(options) => options,
         Err(err) => {
-            println!("Could not create process /system/sysinit");
-            println!("Error: {:#?}", err);
-            println!();
+            log_error!("Could not create process {}", sysinit);
+            log_error!("Error: {:#?}", err);
             panic!("Campix: failed to boot...");
         }
     };
@@ -309,22 +417,42 @@ unsafe fn kmain(obsiboot: ObsiBootKernelParameters) -> ! {
     SCHEDULER
         .create_process(
             options,
-            File::open("/dev/null", OPEN_MODE_READ, Permissions::from_u64(0)).unwrap(),
+            File::open(
+                "/dev/null",
+                OPEN_MODE_READ,
+                Permissions::from_u64(0),
+                Credentials::Kernel,
+            )
+            .unwrap(),
             Some((
-                File::open("/dev/null", OPEN_MODE_READ, Permissions::from_u64(0)).unwrap(),
+                File::open(
+                    "/dev/null",
+                    OPEN_MODE_READ,
+                    Permissions::from_u64(0),
+                    Credentials::Kernel,
+                )
+                .unwrap(),
                 File::open(
                     &get_kernel_config().sysinit_stdout,
                     OPEN_MODE_READ,
                     Permissions::from_u64(0),
+                    Credentials::Kernel,
                 )
                 .unwrap(),
             )),
             Some((
-                File::open("/dev/null", OPEN_MODE_READ, Permissions::from_u64(0)).unwrap(),
+                File::open(
+                    "/dev/null",
+                    OPEN_MODE_READ,
+                    Permissions::from_u64(0),
+                    Credentials::Kernel,
+                )
+                .unwrap(),
                 File::open(
                     &get_kernel_config().sysinit_stderr,
                     OPEN_MODE_READ,
                     Permissions::from_u64(0),
+                    Credentials::Kernel,
                 )
                 .unwrap(),
             )),
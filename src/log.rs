@@ -1,75 +1,460 @@
-use core::cell::SyncUnsafeCell;
+use core::{
+    cell::SyncUnsafeCell,
+    sync::atomic::{AtomicU64, AtomicU8, Ordering},
+};
 
-use alloc::{boxed::Box, format, vec::Vec};
-use spin::rwlock::RwLock;
+use alloc::{boxed::Box, format, string::String, vec, vec::Vec};
+use spin::{rwlock::RwLock, Mutex};
 
 use crate::{
-    data::{alloc_boxed_slice, calloc_boxed_slice, file::File},
-    drivers::ports::parallel::ParallelPort,
+    data::{calloc_boxed_slice, file::File},
+    drivers::{ports::parallel::ParallelPort, vfs::VfsError, vga::console::write_console},
+    interrupts::{handlers::irq::irq0_timer::get_uptime_ticks, without_interrupts},
     kpanic_no_log,
-    paging::PAGE_SIZE,
+    percpu::{get_per_cpu, is_per_cpu_initialized, InterruptSource},
 };
 
+/// Size of the log ring when the heap comes up, it doubles when full up to its limit
+pub const KERNEL_LOG_RING_INITIAL_SIZE: usize = 16 * 1024;
+/// How much of the kernel log is retained until `kernel.log_buffer_limit` is read, older records
+/// are dropped first past it
+pub const KERNEL_LOG_RING_DEFAULT_LIMIT: usize = 256 * 1024;
+/// Longer lines are truncated
+pub const KERNEL_LOG_MAX_RECORD_LEN: usize = 1024;
+
+/// Interrupt nesting levels with their own `LogLine`, deeper handlers share the last one
+pub const LOG_LINE_LEVELS: usize = 4;
+
+// Record layout in the ring: message length (u16), level (u8), flags (u8), timestamp (u64), message
+const KERNEL_LOG_RECORD_HEADER_SIZE: usize = 12;
+/// Not written to the console and the log file
+const KERNEL_LOG_RECORD_RING_ONLY: u8 = 1;
+
+/// Same values as the linux printk levels
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum KernelLogLevel {
+    Emergency = 0,
+    Alert = 1,
+    Critical = 2,
+    Error = 3,
+    Warning = 4,
+    Notice = 5,
+    Info = 6,
+    Debug = 7,
+}
+
+impl KernelLogLevel {
+    pub fn from_u8(value: u8) -> Option<Self> {
+        Some(match value {
+            0 => Self::Emergency,
+            1 => Self::Alert,
+            2 => Self::Critical,
+            3 => Self::Error,
+            4 => Self::Warning,
+            5 => Self::Notice,
+            6 => Self::Info,
+            7 => Self::Debug,
+            _ => return None,
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct KernelLogRecord {
+    pub sequence: u64,
+    /// Uptime in ticks when the record was started
+    pub timestamp: u64,
+    pub level: KernelLogLevel,
+    pub message: Vec<u8>,
+}
+
+impl KernelLogRecord {
+    /// "ts,level,message" followed by a new line
+    pub fn format(&self) -> String {
+        format!(
+            "{},{},{}\n",
+            self.timestamp,
+            self.level as u8,
+            String::from_utf8_lossy(&self.message)
+        )
+    }
+}
+
+/// Sizes in bytes of the buffer holding the log, records take a header in the ring
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct KernelStdoutStats {
+    pub written: u64,
+    /// Overwritten or evicted to make room, the console and the log file may have got them
+    pub dropped: u64,
+    /// Most the buffer held at once
+    pub high_water: u64,
+    pub size: u64,
+    /// Size the buffer may grow to
+    pub limit: u64,
+}
+
+/// Variable sized records packed in a buffer, which doubles when full until it reaches its limit.
+/// The oldest records are dropped from then on
+pub struct KernelLogRing {
+    buffer: Box<[u8]>,
+    head: usize,
+    len: usize,
+    first_sequence: u64,
+    next_sequence: u64,
+    limit: usize,
+    written: u64,
+    dropped: u64,
+    high_water: usize,
+}
+
+impl KernelLogRing {
+    pub fn new(size: usize) -> Self {
+        Self::with_limit(size, size)
+    }
+
+    pub fn with_limit(size: usize, limit: usize) -> Self {
+        Self {
+            buffer: calloc_boxed_slice(size),
+            head: 0,
+            len: 0,
+            first_sequence: 0,
+            next_sequence: 0,
+            limit: limit.max(size),
+            written: 0,
+            dropped: 0,
+            high_water: 0,
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.buffer.len()
+    }
+
+    /// Never below the current size, the ring doesn't shrink
+    pub fn set_limit(&mut self, limit: usize) {
+        self.limit = limit.max(self.buffer.len());
+    }
+
+    pub fn stats(&self) -> KernelStdoutStats {
+        KernelStdoutStats {
+            written: self.written,
+            dropped: self.dropped,
+            high_water: self.high_water as u64,
+            size: self.buffer.len() as u64,
+            limit: self.limit as u64,
+        }
+    }
+
+    /// Doubles the buffer until a message of `len` bytes fits without dropping older records, as
+    /// far as the limit allows. Keeps the buffer if the heap can't give a bigger one.
+    /// Allocates, so not for interrupt handlers that interrupted the kernel
+    pub fn grow_for(&mut self, len: usize) {
+        let size = KERNEL_LOG_RECORD_HEADER_SIZE + len.min(KERNEL_LOG_MAX_RECORD_LEN);
+        let mut new_size = self.buffer.len();
+        while new_size - self.len < size && new_size < self.limit {
+            new_size = new_size.saturating_mul(2).clamp(1, self.limit);
+        }
+        if new_size == self.buffer.len() {
+            return;
+        }
+        let mut buffer = Vec::new();
+        if buffer.try_reserve_exact(new_size).is_err() {
+            return;
+        }
+        buffer.resize(new_size, 0);
+        let (a, b) = self.bytes(self.head, self.len);
+        buffer[..a.len()].copy_from_slice(a);
+        buffer[a.len()..self.len].copy_from_slice(b);
+        self.buffer = buffer.into_boxed_slice();
+        self.head = 0;
+    }
+
+    fn write_bytes(&mut self, offset: usize, data: &[u8]) {
+        let offset = offset % self.buffer.len();
+        let first = data.len().min(self.buffer.len() - offset);
+        self.buffer[offset..offset + first].copy_from_slice(&data[..first]);
+        self.buffer[..data.len() - first].copy_from_slice(&data[first..]);
+    }
+
+    /// Returns the bytes at `offset` as two slices, the second one is not empty when wrapping around
+    fn bytes(&self, offset: usize, len: usize) -> (&[u8], &[u8]) {
+        let offset = offset % self.buffer.len();
+        let first = len.min(self.buffer.len() - offset);
+        (
+            &self.buffer[offset..offset + first],
+            &self.buffer[..len - first],
+        )
+    }
+
+    /// Returns the message length, level, timestamp and flags of the record at `offset`
+    fn header(&self, offset: usize) -> (usize, KernelLogLevel, u64, u8) {
+        let mut header = [0u8; KERNEL_LOG_RECORD_HEADER_SIZE];
+        let (a, b) = self.bytes(offset, KERNEL_LOG_RECORD_HEADER_SIZE);
+        header[..a.len()].copy_from_slice(a);
+        header[a.len()..].copy_from_slice(b);
+        (
+            u16::from_le_bytes([header[0], header[1]]) as usize,
+            KernelLogLevel::from_u8(header[2]).unwrap_or(KernelLogLevel::Info),
+            u64::from_le_bytes(header[4..12].try_into().unwrap()),
+            header[3],
+        )
+    }
+
+    pub fn push(&mut self, timestamp: u64, level: KernelLogLevel, flags: u8, message: &[u8]) {
+        let message = &message[..message.len().min(KERNEL_LOG_MAX_RECORD_LEN)];
+        let size = KERNEL_LOG_RECORD_HEADER_SIZE + message.len();
+        self.written += size as u64;
+        if size > self.buffer.len() {
+            self.dropped += size as u64;
+            return;
+        }
+
+        while self.buffer.len() - self.len < size {
+            let (len, _, _, _) = self.header(self.head);
+            let evicted = KERNEL_LOG_RECORD_HEADER_SIZE + len;
+            self.head = (self.head + evicted) % self.buffer.len();
+            self.len -= evicted;
+            self.first_sequence += 1;
+            self.dropped += evicted as u64;
+        }
+
+        let mut header = [0u8; KERNEL_LOG_RECORD_HEADER_SIZE];
+        header[0..2].copy_from_slice(&(message.len() as u16).to_le_bytes());
+        header[2] = level as u8;
+        header[3] = flags;
+        header[4..12].copy_from_slice(&timestamp.to_le_bytes());
+
+        let tail = self.head + self.len;
+        self.write_bytes(tail, &header);
+        self.write_bytes(tail + KERNEL_LOG_RECORD_HEADER_SIZE, message);
+        self.len += size;
+        self.high_water = self.high_water.max(self.len);
+        self.next_sequence += 1;
+    }
+
+    /// Calls `f` with the timestamp, level and message of every retained record, oldest first
+    pub fn for_each<F>(&self, mut f: F)
+    where
+        F: FnMut(u64, KernelLogLevel, (&[u8], &[u8])),
+    {
+        let mut offset = self.head;
+        for _ in self.first_sequence..self.next_sequence {
+            let (len, level, timestamp, _) = self.header(offset);
+            f(
+                timestamp,
+                level,
+                self.bytes(offset + KERNEL_LOG_RECORD_HEADER_SIZE, len),
+            );
+            offset += KERNEL_LOG_RECORD_HEADER_SIZE + len;
+        }
+    }
+
+    /// Copies the message of the record `sequence`, or of the oldest retained one if it was
+    /// already dropped, into `out`. Returns its sequence, timestamp, level, flags and length
+    pub fn copy_record(
+        &self,
+        sequence: u64,
+        out: &mut [u8],
+    ) -> Option<(u64, u64, KernelLogLevel, u8, usize)> {
+        let sequence = sequence.max(self.first_sequence);
+        if sequence >= self.next_sequence {
+            return None;
+        }
+
+        let mut offset = self.head;
+        for _ in self.first_sequence..sequence {
+            let (len, _, _, _) = self.header(offset);
+            offset += KERNEL_LOG_RECORD_HEADER_SIZE + len;
+        }
+
+        let (len, level, timestamp, flags) = self.header(offset);
+        let len = len.min(out.len());
+        let (a, b) = self.bytes(offset + KERNEL_LOG_RECORD_HEADER_SIZE, len);
+        out[..a.len()].copy_from_slice(a);
+        out[a.len()..len].copy_from_slice(b);
+        Some((sequence, timestamp, level, flags, len))
+    }
+}
+
+/// A line being printed. Every CPU has one per interrupt nesting level, so a handler printing
+/// while the code it interrupted is in the middle of a line doesn't mix the two
+#[derive(Clone)]
+pub struct LogLine {
+    buffer: [u8; KERNEL_LOG_MAX_RECORD_LEN],
+    len: usize,
+    started: bool,
+    level: KernelLogLevel,
+    timestamp: u64,
+}
+
+impl Default for LogLine {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl LogLine {
+    pub const fn new() -> Self {
+        Self {
+            buffer: [0; KERNEL_LOG_MAX_RECORD_LEN],
+            len: 0,
+            started: false,
+            level: KernelLogLevel::Info,
+            timestamp: 0,
+        }
+    }
+
+    fn push_byte(&mut self, c: u8) {
+        if !self.started {
+            self.started = true;
+            self.timestamp = get_uptime_ticks();
+        }
+        if self.len < self.buffer.len() {
+            self.buffer[self.len] = c;
+            self.len += 1;
+        }
+    }
+
+    fn message(&self) -> &[u8] {
+        &self.buffer[..self.len]
+    }
+
+    fn reset(&mut self) {
+        self.len = 0;
+        self.started = false;
+        self.level = KernelLogLevel::Info;
+    }
+}
+
+/// Line of the code running on this CPU, at its interrupt nesting level
+fn with_current_line<R>(f: impl FnOnce(&mut LogLine) -> R) -> R {
+    // Before the per-CPU lines exist interrupts are still disabled
+    static EARLY_LINE: SyncUnsafeCell<LogLine> = SyncUnsafeCell::new(LogLine::new());
+
+    if is_per_cpu_initialized() {
+        let per_cpu = get_per_cpu();
+        let depth = per_cpu.interrupt_sources.len().min(LOG_LINE_LEVELS - 1);
+        if let Some(lines) = &mut per_cpu.log_lines {
+            return f(&mut lines[depth]);
+        }
+    }
+    f(unsafe { &mut *EARLY_LINE.get() })
+}
+
+/// Whether this is an interrupt handler that interrupted the kernel, which may be writing to
+/// the console or the log file
+fn in_kernel_interrupt() -> bool {
+    is_per_cpu_initialized()
+        && matches!(
+            get_per_cpu().interrupt_sources.last(),
+            Some(InterruptSource::Kernel)
+        )
+}
+
 pub enum KernelStdoutState {
     Uninitialized,
+    /// Output before the heap exists, the oldest bytes are overwritten once the buffer is full
     FixedSizeBuffer {
         buffer: *mut u8,
         size: usize,
+        /// Bytes written so far, the buffer holds the last `size` of them
         pos: usize,
     },
-    GrowableBuffer {
-        past_buffers: Vec<Box<[u8]>>,
-        current_buffer: Box<[u8]>,
-        current_buffer_pos: usize,
-    },
+    /// Only the ring and the console receive the output
+    RingBuffer,
     PipeTo {
         file: File,
+        /// Next record to write to the file
+        next_sequence: u64,
     },
 }
 
 impl KernelStdoutState {
+    /// Output before the ring exists
     pub fn write_char_impl(&mut self, c: u8) {
         match self {
             KernelStdoutState::Uninitialized => {
                 kpanic_no_log(b"kernel stdout not initialized");
             }
             KernelStdoutState::FixedSizeBuffer { buffer, size, pos } => {
-                if *pos < *size {
+                if *size != 0 {
                     unsafe {
-                        *buffer.add(*pos) = c;
+                        *buffer.add(*pos % *size) = c;
                     }
-                    *pos += 1;
-                } else {
-                    kpanic_no_log(b"kernel stdout buffer overflow");
                 }
+                *pos += 1;
             }
-            KernelStdoutState::GrowableBuffer {
-                past_buffers,
-                current_buffer,
-                current_buffer_pos,
-            } => {
-                if *current_buffer_pos < current_buffer.len() {
-                    current_buffer[*current_buffer_pos] = c;
+            KernelStdoutState::RingBuffer | KernelStdoutState::PipeTo { .. } => {}
+        }
+    }
+
+    /// Contents of the early boot buffer, oldest first
+    fn early_output(&self) -> (&[u8], &[u8]) {
+        match self {
+            KernelStdoutState::FixedSizeBuffer { buffer, size, pos } => {
+                let buffer = unsafe { core::slice::from_raw_parts(*buffer, *size) };
+                if *pos <= *size {
+                    (&buffer[..*pos], &[])
                 } else {
-                    let mut new_buf = calloc_boxed_slice(PAGE_SIZE);
-                    new_buf[0] = c;
-                    let filled_buf = core::mem::replace(current_buffer, new_buf);
-                    past_buffers.push(filled_buf);
-                    *current_buffer_pos = 1;
+                    let start = *pos % *size;
+                    (&buffer[start..], &buffer[..start])
                 }
             }
-            KernelStdoutState::PipeTo { file } => match file.write(&[c]) {
-                Ok(_) => {}
-                Err(e) => {
-                    kpanic_no_log(format!("Failed to write to pipe: {e:?}").as_bytes());
+            _ => (&[], &[]),
+        }
+    }
+
+    /// Whole lines of the early boot buffer, without the one partly overwritten
+    fn early_lines(&self) -> Vec<u8> {
+        let (a, b) = self.early_output();
+        let output = [a, b].concat();
+        match self {
+            KernelStdoutState::FixedSizeBuffer { size, pos, .. } if pos > size => {
+                match output.iter().position(|c| *c == b'\n') {
+                    Some(end) => output[end + 1..].to_vec(),
+                    None => Vec::new(),
                 }
+            }
+            _ => output,
+        }
+    }
+
+    fn early_stats(&self) -> KernelStdoutStats {
+        match self {
+            KernelStdoutState::FixedSizeBuffer { size, pos, .. } => KernelStdoutStats {
+                written: *pos as u64,
+                dropped: pos.saturating_sub(*size) as u64,
+                high_water: (*pos).min(*size) as u64,
+                size: *size as u64,
+                limit: *size as u64,
             },
+            _ => KernelStdoutStats::default(),
         }
     }
 }
 
+/// Lines are formatted into the `LogLine` of the CPU, then copied whole into the ring. The ring
+/// lock is only taken with interrupts disabled, so printing is safe from interrupt handlers.
+/// The console and the log file are written by `flush`, outside of interrupt handlers
 pub struct KernelStdout {
     state: RwLock<KernelStdoutState>,
+    ring: Mutex<Option<KernelLogRing>>,
+    /// Next record to write to the console
+    console_sequence: AtomicU64,
+    /// Record being flushed, only used with `state` locked
+    flush_buffer: SyncUnsafeCell<[u8; KERNEL_LOG_MAX_RECORD_LEN + 2]>,
+}
+
+struct LptWriter(ParallelPort);
+
+impl core::fmt::Write for LptWriter {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        for b in s.bytes() {
+            unsafe { self.0.write_byte(b) };
+        }
+        Ok(())
+    }
 }
 
 impl KernelStdout {
@@ -89,139 +474,314 @@ impl KernelStdout {
         };
     }
 
+    /// Allocates the log ring, and moves the content of the early boot buffer into it. The ring
+    /// is installed before the state changes, a panic in between dumps it
     pub fn switch_to_heap(&mut self) {
         let mut lock = self.state.write();
 
         match &*lock {
-            KernelStdoutState::Uninitialized => {
-                *lock = KernelStdoutState::GrowableBuffer {
-                    past_buffers: Vec::new(),
-                    current_buffer: calloc_boxed_slice(PAGE_SIZE),
-                    current_buffer_pos: 0,
-                };
-            }
-            KernelStdoutState::GrowableBuffer {..} => {}
+            KernelStdoutState::Uninitialized | KernelStdoutState::FixedSizeBuffer { .. } => {}
+            KernelStdoutState::RingBuffer => return,
             KernelStdoutState::PipeTo { .. } => panic!("Invalid operation: switch kernel logger to heap buffer when virtual file system is initialized"),
-            KernelStdoutState::FixedSizeBuffer { buffer, size, pos } => {
-                let mut buffers = Vec::new();
-
-                let count = (*pos).min(*size).div_ceil(PAGE_SIZE);
-
-                let mut rem = (*pos).min(*size);
-                let mut pos = 0;
+        }
+        let early = lock.early_lines();
+        let early_stats = lock.early_stats();
 
-                for _ in 0..count {
-                    let mut alloc_buffer = alloc_boxed_slice(PAGE_SIZE);
-                    let copy = rem.min(PAGE_SIZE);
-                    unsafe {
-                        core::ptr::copy::<u8>(
-                            (*buffer).add(pos),
-                            alloc_buffer.as_mut_ptr(),
-                            copy,
-                        );
-                        if copy < PAGE_SIZE {
-                            alloc_buffer[copy..].fill(0);
-                        }
-                    }
-                    pos += copy;
-                    rem -= copy;
-                    buffers.push(alloc_buffer);
-                }
+        let mut ring =
+            KernelLogRing::with_limit(KERNEL_LOG_RING_INITIAL_SIZE, KERNEL_LOG_RING_DEFAULT_LIMIT);
+        for line in early.split(|c| *c == b'\n').filter(|line| !line.is_empty()) {
+            let line = line.strip_suffix(b"\r").unwrap_or(line);
+            ring.grow_for(line.len());
+            ring.push(0, KernelLogLevel::Info, 0, line);
+        }
+        ring.written = early_stats.written;
+        ring.dropped = early_stats.dropped;
+        ring.high_water = ring.high_water.max(early_stats.high_water as usize);
+        // Already on the console
+        self.console_sequence
+            .store(ring.next_sequence, Ordering::Relaxed);
+        if early_stats.dropped != 0 {
+            let message = format!(
+                "log: {} bytes of early boot output were dropped",
+                early_stats.dropped
+            );
+            ring.push(0, KernelLogLevel::Warning, 0, message.as_bytes());
+        }
+        without_interrupts(|| *self.ring.lock() = Some(ring));
 
-                if buffers.is_empty() {
-                    *lock = KernelStdoutState::GrowableBuffer { past_buffers: buffers, current_buffer: calloc_boxed_slice(PAGE_SIZE), current_buffer_pos: 0 };
-                } else {
-                    let last_buf = buffers.pop().unwrap();
-                    let pos = pos % PAGE_SIZE;
+        *lock = KernelStdoutState::RingBuffer;
+    }
 
-                    *lock = KernelStdoutState::GrowableBuffer { past_buffers: buffers, current_buffer: last_buf, current_buffer_pos: pos };
-                }
+    /// Size the log ring may grow to
+    pub fn set_ring_limit(&self, limit: usize) {
+        without_interrupts(|| {
+            if let Some(ring) = &mut *self.ring.lock() {
+                ring.set_limit(limit);
             }
+        });
+    }
+
+    /// Counters of the ring, or of the early boot buffer before the heap is up
+    pub fn stats(&self) -> KernelStdoutStats {
+        if let Some(stats) =
+            without_interrupts(|| self.ring.lock().as_ref().map(KernelLogRing::stats))
+        {
+            return stats;
         }
+        self.state
+            .try_read()
+            .map_or_else(KernelStdoutStats::default, |state| state.early_stats())
     }
 
+    /// Writes the log to `file` from now on, starting with the records the ring retained
     pub fn switch_to_pipe(&mut self, mut file: File) {
         let mut lock = self.state.write();
-        match &*lock {
-            KernelStdoutState::Uninitialized => {}
-            KernelStdoutState::FixedSizeBuffer { buffer, size, pos } => {
-                match file.write(unsafe { core::slice::from_raw_parts(*buffer, (*size).min(*pos)) })
-                {
+        let next_sequence = match &*lock {
+            KernelStdoutState::FixedSizeBuffer { .. } => {
+                match file.write(&lock.early_lines()) {
                     Ok(_) => {}
                     Err(e) => {
                         kpanic_no_log(format!("Failed to write to pipe: {e:?}").as_bytes());
                     }
                 }
+                0
             }
-            KernelStdoutState::GrowableBuffer {
-                past_buffers,
-                current_buffer,
-                current_buffer_pos,
-            } => {
-                for buf in past_buffers.iter() {
-                    match file.write(buf) {
-                        Ok(_) => {}
-                        Err(e) => {
-                            kpanic_no_log(format!("Failed to write to pipe: {e:?}").as_bytes());
-                        }
-                    }
-                }
-                if *current_buffer_pos > 0 {
-                    match file.write(&current_buffer[..*current_buffer_pos]) {
-                        Ok(_) => {}
-                        Err(e) => {
-                            kpanic_no_log(format!("Failed to write to pipe: {e:?}").as_bytes());
-                        }
-                    }
-                }
+            KernelStdoutState::PipeTo { .. } => return,
+            KernelStdoutState::Uninitialized | KernelStdoutState::RingBuffer => {
+                without_interrupts(|| {
+                    self.ring
+                        .lock()
+                        .as_ref()
+                        .map_or(0, |ring| ring.first_sequence)
+                })
+            }
+        };
+        *lock = KernelStdoutState::PipeTo {
+            file,
+            next_sequence,
+        };
+        drop(lock);
+        self.flush();
+    }
+
+    /// Stops writing to the log file, which is returned so it can be closed before its file system goes away
+    pub fn switch_to_ring(&mut self) -> Option<File> {
+        self.flush();
+        let mut lock = self.state.write();
+        match core::mem::replace(&mut *lock, KernelStdoutState::RingBuffer) {
+            KernelStdoutState::PipeTo { file, .. } => Some(file),
+            other => {
+                *lock = other;
+                None
             }
-            KernelStdoutState::PipeTo { .. } => {}
         }
-        *lock = KernelStdoutState::PipeTo { file };
     }
 
+    /// Dumps the ring once it exists, even in the middle of `switch_to_heap`, the early boot
+    /// buffer before. Then the line being printed
     pub fn panic_dump_to(&mut self, lpt: ParallelPort) {
-        match self.state.get_mut() {
-            KernelStdoutState::Uninitialized | KernelStdoutState::PipeTo { .. } => {}
-            KernelStdoutState::FixedSizeBuffer { buffer, size, pos } => {
-                for i in 0..(*pos).min(*size) {
-                    unsafe { lpt.write_byte((*buffer).add(i).read_volatile()) };
+        use core::fmt::Write;
+
+        if let Some(ring) = self.ring.get_mut() {
+            let mut writer = LptWriter(lpt);
+            ring.for_each(|timestamp, level, (a, b)| {
+                let _ = write!(writer, "<{}>[{:>10}] ", level as u8, timestamp);
+                for c in a.iter().chain(b.iter()) {
+                    unsafe { lpt.write_byte(*c) };
                 }
+                let _ = writer.write_str("\r\n");
+            });
+        } else {
+            let (a, b) = self.state.get_mut().early_output();
+            for c in a.iter().chain(b.iter()) {
+                unsafe { lpt.write_byte(*c) };
             }
-            KernelStdoutState::GrowableBuffer {
-                past_buffers,
-                current_buffer,
-                current_buffer_pos,
-            } => {
-                for buf in past_buffers.iter() {
-                    for i in 0..buf.len() {
-                        unsafe { lpt.write_byte(buf[i]) };
-                    }
+        }
+        with_current_line(|line| {
+            for c in line.message() {
+                unsafe { lpt.write_byte(*c) };
+            }
+        });
+    }
+
+    /// Writes the records the console didn't get yet, without locking anything
+    pub fn panic_flush_console(&mut self) {
+        let console_sequence = self.console_sequence.get_mut();
+        if let Some(ring) = self.ring.get_mut() {
+            let buffer = self.flush_buffer.get_mut();
+            while let Some((sequence, _, _, flags, len)) =
+                ring.copy_record(*console_sequence, buffer)
+            {
+                if flags & KERNEL_LOG_RECORD_RING_ONLY == 0 {
+                    write_console(&buffer[..len]);
+                    write_console(b"\r\n");
                 }
-                for i in 0..*current_buffer_pos {
-                    unsafe { lpt.write_byte(current_buffer[i]) };
+                *console_sequence = sequence + 1;
+            }
+        }
+    }
+
+    /// Writes a whole line at the given level
+    pub fn printk(&mut self, level: KernelLogLevel, args: core::fmt::Arguments) {
+        use core::fmt::Write;
+
+        with_current_line(|line| {
+            if line.started {
+                self.commit_line(line);
+            }
+            line.level = level;
+        });
+        let _ = self.write_fmt(args);
+        let _ = self.write_str("\n");
+        self.flush();
+    }
+
+    /// Adds a record to the ring only, the console and the log file don't get it
+    pub fn inject_record(&self, level: KernelLogLevel, message: &[u8]) {
+        self.push_record(
+            get_uptime_ticks(),
+            level,
+            KERNEL_LOG_RECORD_RING_ONLY,
+            message,
+        );
+    }
+
+    /// Returns false before the ring exists. The ring only grows outside of interrupt handlers
+    /// that interrupted the kernel, which may be in the middle of an allocation
+    fn push_record(
+        &self,
+        timestamp: u64,
+        level: KernelLogLevel,
+        flags: u8,
+        message: &[u8],
+    ) -> bool {
+        let can_grow = !in_kernel_interrupt();
+        without_interrupts(|| match &mut *self.ring.lock() {
+            Some(ring) => {
+                if can_grow {
+                    ring.grow_for(message.len());
                 }
+                ring.push(timestamp, level, flags, message);
+                true
             }
+            None => false,
+        })
+    }
+
+    /// Returns the record `sequence` if still retained, or the oldest one after it
+    pub fn read_record(&self, sequence: u64) -> Option<KernelLogRecord> {
+        let mut message = vec![0; KERNEL_LOG_MAX_RECORD_LEN];
+        let (sequence, timestamp, level, _, len) = without_interrupts(|| {
+            self.ring
+                .lock()
+                .as_ref()?
+                .copy_record(sequence, &mut message)
+        })?;
+        message.truncate(len);
+        Some(KernelLogRecord {
+            sequence,
+            timestamp,
+            level,
+            message,
+        })
+    }
+
+    /// Sequence the next record will get
+    pub fn next_record_sequence(&self) -> u64 {
+        without_interrupts(|| {
+            self.ring
+                .lock()
+                .as_ref()
+                .map_or(0, |ring| ring.next_sequence)
+        })
+    }
+
+    fn commit_line(&self, line: &mut LogLine) {
+        let message = line.message();
+        if !self.push_record(line.timestamp, line.level, 0, message) {
+            let mut lock = self.state.write();
+            for c in message.iter().chain(b"\r\n") {
+                lock.write_char_impl(*c);
+            }
+            write_console(message);
+            write_console(b"\r\n");
         }
+        line.reset();
     }
-}
 
-impl core::fmt::Write for KernelStdout {
-    fn write_str(&mut self, s: &str) -> core::fmt::Result {
-        let mut lock = self.state.write();
+    /// Writes the records added since the last flush to the console and the log file. Does
+    /// nothing from an interrupt handler that interrupted the kernel, or while another flush
+    /// is running, the next flush writes them
+    pub fn flush(&self) {
+        if in_kernel_interrupt() {
+            return;
+        }
+        let Some(mut lock) = self.state.try_write() else {
+            return;
+        };
+        let buffer = unsafe { &mut *self.flush_buffer.get() };
+        let mut console_sequence = self.console_sequence.load(Ordering::Relaxed);
+        // The file system of the log file is busy when logging from inside it
+        let mut file_busy = false;
+        loop {
+            let start = match &*lock {
+                KernelStdoutState::PipeTo { next_sequence, .. } if !file_busy => {
+                    console_sequence.min(*next_sequence)
+                }
+                _ => console_sequence,
+            };
+            let Some((sequence, _, _, flags, len)) =
+                without_interrupts(|| self.ring.lock().as_ref()?.copy_record(start, buffer))
+            else {
+                break;
+            };
+            let ring_only = flags & KERNEL_LOG_RECORD_RING_ONLY != 0;
 
-        for c in s.chars() {
-            if c == '\n' {
-                lock.write_char_impl(b'\r');
+            if sequence >= console_sequence {
+                if !ring_only {
+                    write_console(&buffer[..len]);
+                    write_console(b"\r\n");
+                }
+                console_sequence = sequence + 1;
+            }
+            if let KernelStdoutState::PipeTo {
+                file,
+                next_sequence,
+            } = &mut *lock
+            {
+                if sequence >= *next_sequence && !file_busy {
+                    buffer[len..len + 2].copy_from_slice(b"\r\n");
+                    let written = if ring_only {
+                        Some(Ok(0))
+                    } else {
+                        file.try_write(&buffer[..len + 2])
+                    };
+                    match written {
+                        None => file_busy = true,
+                        Some(Ok(_)) => *next_sequence = sequence + 1,
+                        Some(Err(e)) => {
+                            kpanic_no_log(format!("Failed to write to pipe: {e:?}").as_bytes());
+                        }
+                    }
+                }
             }
-            lock.write_char_impl(c as u8);
         }
-        Ok(())
+        self.console_sequence
+            .store(console_sequence, Ordering::Relaxed);
     }
+}
 
-    fn write_char(&mut self, c: char) -> core::fmt::Result {
-        let mut lock = self.state.write();
-        lock.write_char_impl(c as u8);
+impl core::fmt::Write for KernelStdout {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        with_current_line(|line| {
+            for c in s.bytes() {
+                match c {
+                    b'\r' => {}
+                    b'\n' => self.commit_line(line),
+                    c => line.push_byte(c),
+                }
+            }
+        });
         Ok(())
     }
 
@@ -235,23 +795,221 @@ unsafe impl Sync for KernelStdout {}
 
 pub static KERNEL_STDOUT: SyncUnsafeCell<KernelStdout> = SyncUnsafeCell::new(KernelStdout {
     state: RwLock::new(KernelStdoutState::Uninitialized),
+    ring: Mutex::new(None),
+    console_sequence: AtomicU64::new(0),
+    flush_buffer: SyncUnsafeCell::new([0; KERNEL_LOG_MAX_RECORD_LEN + 2]),
 });
 
 pub fn get_stdout() -> &'static mut KernelStdout {
     unsafe { &mut *KERNEL_STDOUT.get() }
 }
 
+pub fn stdout_stats() -> KernelStdoutStats {
+    get_stdout().stats()
+}
+
+static KMSG_WRITE_LEVEL: AtomicU8 = AtomicU8::new(KernelLogLevel::Info as u8);
+
+/// Level of the records written to /dev/kmsg without a "<N>" prefix
+pub fn set_kmsg_write_level(level: KernelLogLevel) {
+    KMSG_WRITE_LEVEL.store(level as u8, Ordering::Relaxed);
+}
+
+pub fn kmsg_write_level() -> KernelLogLevel {
+    KernelLogLevel::from_u8(KMSG_WRITE_LEVEL.load(Ordering::Relaxed))
+        .unwrap_or(KernelLogLevel::Info)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum LogLevel {
+    Error = 1,
+    Warn = 2,
+    Info = 3,
+    Debug = 4,
+    Trace = 5,
+}
+
+impl LogLevel {
+    pub fn from_u8(value: u8) -> Option<Self> {
+        Some(match value {
+            1 => Self::Error,
+            2 => Self::Warn,
+            3 => Self::Info,
+            4 => Self::Debug,
+            5 => Self::Trace,
+            _ => return None,
+        })
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Some(match name {
+            "error" => Self::Error,
+            "warn" => Self::Warn,
+            "info" => Self::Info,
+            "debug" => Self::Debug,
+            "trace" => Self::Trace,
+            _ => return None,
+        })
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Error => "error",
+            Self::Warn => "warn",
+            Self::Info => "info",
+            Self::Debug => "debug",
+            Self::Trace => "trace",
+        }
+    }
+
+    pub fn kernel_level(&self) -> KernelLogLevel {
+        match self {
+            Self::Error => KernelLogLevel::Error,
+            Self::Warn => KernelLogLevel::Warning,
+            Self::Info => KernelLogLevel::Info,
+            Self::Debug | Self::Trace => KernelLogLevel::Debug,
+        }
+    }
+}
+
+/// More verbose records are compiled out
+pub const LOG_STATIC_LEVEL: LogLevel = if cfg!(debug_assertions) {
+    LogLevel::Trace
+} else {
+    LogLevel::Debug
+};
+
+pub const LOG_DEFAULT_LEVEL: LogLevel = LogLevel::Info;
+pub const LOG_FILTER_MAX_TARGETS: usize = 32;
+pub const LOG_TARGET_MAX_LEN: usize = 16;
+
+#[derive(Debug, Clone, Copy)]
+struct LogFilter {
+    target: [u8; LOG_TARGET_MAX_LEN],
+    len: usize,
+    level: LogLevel,
+}
+
+impl LogFilter {
+    fn target(&self) -> &[u8] {
+        &self.target[..self.len]
+    }
+}
+
+/// Per module levels, small enough to be scanned before formatting every record
+#[derive(Debug)]
+pub struct LogFilterTable {
+    default: LogLevel,
+    filters: [LogFilter; LOG_FILTER_MAX_TARGETS],
+    count: usize,
+}
+
+impl LogFilterTable {
+    const fn new() -> Self {
+        Self {
+            default: LOG_DEFAULT_LEVEL,
+            filters: [LogFilter {
+                target: [0; LOG_TARGET_MAX_LEN],
+                len: 0,
+                level: LOG_DEFAULT_LEVEL,
+            }; LOG_FILTER_MAX_TARGETS],
+            count: 0,
+        }
+    }
+
+    #[inline(always)]
+    pub fn level_for(&self, target: &str) -> LogLevel {
+        for filter in self.filters[..self.count].iter() {
+            if filter.target() == target.as_bytes() {
+                return filter.level;
+            }
+        }
+        self.default
+    }
+
+    fn max_level(&self) -> LogLevel {
+        self.filters[..self.count]
+            .iter()
+            .map(|filter| filter.level)
+            .fold(self.default, LogLevel::max)
+    }
+
+    /// Sets the level of `target`, or the default level of modules without their own
+    pub fn set(&mut self, target: Option<&str>, level: LogLevel) -> Result<(), VfsError> {
+        let Some(target) = target else {
+            self.default = level;
+            return Ok(());
+        };
+        if target.len() > LOG_TARGET_MAX_LEN {
+            return Err(VfsError::NameTooLong);
+        }
+        if let Some(filter) = self.filters[..self.count]
+            .iter_mut()
+            .find(|filter| filter.target() == target.as_bytes())
+        {
+            filter.level = level;
+            return Ok(());
+        }
+        if self.count == LOG_FILTER_MAX_TARGETS {
+            return Err(VfsError::OutOfSpace);
+        }
+        let filter = &mut self.filters[self.count];
+        filter.target[..target.len()].copy_from_slice(target.as_bytes());
+        filter.len = target.len();
+        filter.level = level;
+        self.count += 1;
+        Ok(())
+    }
+
+    /// One "target=level" line per module, the first one is the default level
+    pub fn format(&self) -> String {
+        let mut out = format!("default={}\n", self.default.name());
+        for filter in self.filters[..self.count].iter() {
+            out.push_str(&format!(
+                "{}={}\n",
+                String::from_utf8_lossy(filter.target()),
+                filter.level.name()
+            ));
+        }
+        out
+    }
+}
+
+static LOG_FILTERS: RwLock<LogFilterTable> = RwLock::new(LogFilterTable::new());
+/// Most verbose level enabled for any module, checked before looking at the table
+static LOG_MAX_LEVEL: AtomicU8 = AtomicU8::new(LOG_DEFAULT_LEVEL as u8);
+
+#[inline(always)]
+pub fn log_enabled(level: LogLevel, target: &str) -> bool {
+    level <= LOG_STATIC_LEVEL
+        && level as u8 <= LOG_MAX_LEVEL.load(Ordering::Relaxed)
+        && level <= LOG_FILTERS.read().level_for(target)
+}
+
+pub fn set_log_level(target: Option<&str>, level: LogLevel) -> Result<(), VfsError> {
+    let mut filters = LOG_FILTERS.write();
+    filters.set(target, level)?;
+    LOG_MAX_LEVEL.store(filters.max_level() as u8, Ordering::Relaxed);
+    Ok(())
+}
+
+pub fn format_log_levels() -> String {
+    LOG_FILTERS.read().format()
+}
+
 #[macro_export]
 macro_rules! printf {
     ($fmt: expr) => {{
         use core::fmt::Write;
         let writer = $crate::log::get_stdout();
         write!(writer, $fmt).unwrap();
+        writer.flush();
     }};
     ($fmt: expr, $( $arg: expr ),*) => {{
         use core::fmt::Write;
         let writer = $crate::log::get_stdout();
         write!(writer, $fmt, $( $arg ),*).unwrap();
+        writer.flush();
     }};
 }
 
@@ -265,11 +1023,87 @@ macro_rules! println {
         let writer = $crate::log::get_stdout();
         write!(writer, $fmt).unwrap();
         write!(writer, "\n").unwrap();
+        writer.flush();
     }};
     ($fmt: expr, $( $arg: expr ),*) => {{
         use core::fmt::Write;
         let writer = $crate::log::get_stdout();
         write!(writer, $fmt, $( $arg ),*).unwrap();
         write!(writer, "\n").unwrap();
+        writer.flush();
+    }};
+}
+
+/// Like `println!`, but only adds the line to the ring, for interrupt handlers. The console and
+/// the log file get it on the next flush
+#[macro_export]
+macro_rules! println_irq {
+    ($fmt: expr $(, $arg: expr)* $(,)?) => {{
+        use core::fmt::Write;
+        let writer = $crate::log::get_stdout();
+        write!(writer, concat!($fmt, "\n") $(, $arg)*).unwrap();
+    }};
+}
+
+/// Like `println!`, with the level of the record as first argument
+#[macro_export]
+macro_rules! printk {
+    ($level: expr, $fmt: expr) => {{
+        $crate::log::get_stdout().printk($level, format_args!($fmt));
+    }};
+    ($level: expr, $fmt: expr, $( $arg: expr ),*) => {{
+        $crate::log::get_stdout().printk($level, format_args!($fmt, $( $arg ),*));
+    }};
+}
+
+/// `log!(level, target: "module", fmt, args...)`, the target defaults to "kernel"
+#[macro_export]
+macro_rules! log {
+    ($level: expr, target: $target: expr, $fmt: expr $(, $arg: expr)* $(,)?) => {{
+        let level: $crate::log::LogLevel = $level;
+        if $crate::log::log_enabled(level, $target) {
+            $crate::log::get_stdout().printk(
+                level.kernel_level(),
+                format_args!(concat!("{}: ", $fmt), $target $(, $arg)*),
+            );
+        }
+    }};
+    ($level: expr, $fmt: expr $(, $arg: expr)* $(,)?) => {{
+        $crate::log!($level, target: "kernel", $fmt $(, $arg)*)
+    }};
+}
+
+#[macro_export]
+macro_rules! log_error {
+    ($( $tt: tt )*) => {{
+        $crate::log!($crate::log::LogLevel::Error, $( $tt )*)
+    }};
+}
+
+#[macro_export]
+macro_rules! log_warn {
+    ($( $tt: tt )*) => {{
+        $crate::log!($crate::log::LogLevel::Warn, $( $tt )*)
+    }};
+}
+
+#[macro_export]
+macro_rules! log_info {
+    ($( $tt: tt )*) => {{
+        $crate::log!($crate::log::LogLevel::Info, $( $tt )*)
+    }};
+}
+
+#[macro_export]
+macro_rules! log_debug {
+    ($( $tt: tt )*) => {{
+        $crate::log!($crate::log::LogLevel::Debug, $( $tt )*)
+    }};
+}
+
+#[macro_export]
+macro_rules! log_trace {
+    ($( $tt: tt )*) => {{
+        $crate::log!($crate::log::LogLevel::Trace, $( $tt )*)
     }};
 }
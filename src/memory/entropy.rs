@@ -0,0 +1,124 @@
+use core::arch::x86_64::{__cpuid, _rdtsc};
+
+use spin::Mutex;
+
+const CPUID_1_ECX_RDRAND: u32 = 1 << 30;
+const RDRAND_RETRIES: usize = 10;
+
+struct EntropyState {
+    initialized: bool,
+    has_rdrand: bool,
+    state: u64,
+}
+
+static ENTROPY: Mutex<EntropyState> = Mutex::new(EntropyState {
+    initialized: false,
+    has_rdrand: false,
+    state: 0,
+});
+
+fn cpu_has_rdrand() -> bool {
+    #[allow(unused_unsafe)]
+    let leaf = unsafe { __cpuid(1) };
+    leaf.ecx & CPUID_1_ECX_RDRAND != 0
+}
+
+fn rdtsc() -> u64 {
+    #[allow(unused_unsafe)]
+    unsafe {
+        _rdtsc()
+    }
+}
+
+fn rdrand64() -> Option<u64> {
+    for _ in 0..RDRAND_RETRIES {
+        let value: u64;
+        let ok: u8;
+        unsafe {
+            core::arch::asm!("rdrand {0}", "setc {1}", out(reg) value, out(reg_byte) ok, options(nomem, nostack));
+        }
+        if ok != 0 {
+            return Some(value);
+        }
+    }
+    None
+}
+
+/// splitmix64 finalizer
+fn mix64(mut z: u64) -> u64 {
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    z ^ (z >> 31)
+}
+
+/// Collects TSC jitter by timing a short busy loop a few times
+fn tsc_jitter() -> u64 {
+    let mut acc = rdtsc();
+    for i in 0..64u64 {
+        let begin = rdtsc();
+        let mut x = i;
+        for _ in 0..(16 + (begin & 0xF)) {
+            x = core::hint::black_box(x.wrapping_mul(6364136223846793005).wrapping_add(1));
+        }
+        let delta = rdtsc().wrapping_sub(begin);
+        acc = mix64(acc ^ delta.rotate_left((i % 64) as u32) ^ x);
+    }
+    acc
+}
+
+impl EntropyState {
+    fn init(&mut self) {
+        if self.initialized {
+            return;
+        }
+        self.has_rdrand = cpu_has_rdrand() && rdrand64().is_some();
+        self.state = tsc_jitter();
+        if self.has_rdrand {
+            if let Some(seed) = rdrand64() {
+                self.state ^= seed;
+            }
+        }
+        self.initialized = true;
+    }
+
+    fn next(&mut self) -> u64 {
+        self.init();
+        if self.has_rdrand {
+            if let Some(value) = rdrand64() {
+                return value;
+            }
+        }
+        self.state = self
+            .state
+            .wrapping_add(0x9E37_79B9_7F4A_7C15)
+            .wrapping_add(rdtsc().rotate_left(17));
+        mix64(self.state)
+    }
+}
+
+/// Detects RDRAND and mixes TSC jitter into the fallback pool. Called once at boot,
+/// but `random_u64` will initialize lazily if it wasn't.
+pub fn init_entropy() {
+    ENTROPY.lock().init();
+}
+
+/// Returns whether the entropy source is backed by RDRAND
+pub fn has_hardware_rng() -> bool {
+    let mut guard = ENTROPY.lock();
+    guard.init();
+    guard.has_rdrand
+}
+
+/// Returns 64 random bits from the kernel entropy source, this never blocks.
+pub fn random_u64() -> u64 {
+    ENTROPY.lock().next()
+}
+
+/// Fills `buf` with random bytes from the kernel entropy source
+pub fn fill_random(buf: &mut [u8]) {
+    let mut guard = ENTROPY.lock();
+    for chunk in buf.chunks_mut(8) {
+        let value = guard.next().to_le_bytes();
+        chunk.copy_from_slice(&value[0..chunk.len()]);
+    }
+}
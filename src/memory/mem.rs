@@ -1,7 +1,13 @@
+use core::{
+    ops::Range,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
 use crate::{
+    data::assign_once::AssignOnce,
+    log_debug, log_info,
     memory::buddy_alloc::{self, BuddyPageAllocator},
     paging::{align_up, physical_to_virtual, MB2},
-    printf, println,
 };
 
 #[derive(Default)]
@@ -24,10 +30,22 @@ unsafe impl core::alloc::GlobalAlloc for GlobalAlloc {
                 "Try to allocate memory without an allocator !\n{:#?}",
                 layout
             ),
-            Some(allocator) => allocator
-                .alloc(layout.size().max(1) as u64)
-                .map(|addr| addr as *mut u8)
-                .unwrap_or(core::ptr::null_mut()),
+            Some(_)
+                if HEAP_ALLOCATED_BYTES
+                    .load(Ordering::Relaxed)
+                    .saturating_add(layout.size() as u64)
+                    > HEAP_LIMIT.load(Ordering::Relaxed) =>
+            {
+                core::ptr::null_mut()
+            }
+            Some(allocator) => match allocator.alloc(layout.size().max(1) as u64) {
+                Some(addr) => {
+                    HEAP_ALLOCATED_BYTES.fetch_add(layout.size() as u64, Ordering::Relaxed);
+                    HEAP_ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+                    addr as *mut u8
+                }
+                None => core::ptr::null_mut(),
+            },
         }
     }
 
@@ -41,7 +59,10 @@ unsafe impl core::alloc::GlobalAlloc for GlobalAlloc {
         #[allow(static_mut_refs)]
         match &mut MAIN_BUDDY_ALLOCATOR {
             None => {}
-            Some(allocator) => allocator.free(ptr as u64),
+            Some(allocator) => {
+                HEAP_ALLOCATED_BYTES.fetch_sub(layout.size() as u64, Ordering::Relaxed);
+                allocator.free(ptr as u64)
+            }
         }
     }
 }
@@ -148,6 +169,54 @@ impl ExtendedBuddyPageAllocator {
 
 static mut MAIN_BUDDY_ALLOCATOR: Option<ExtendedBuddyPageAllocator> = None;
 
+/// Bytes requested through the global allocator and not freed yet
+static HEAP_ALLOCATED_BYTES: AtomicU64 = AtomicU64::new(0);
+/// Allocations made through the global allocator since boot
+static HEAP_ALLOCATIONS: AtomicU64 = AtomicU64::new(0);
+/// Allocations that would take `HEAP_ALLOCATED_BYTES` past it fail
+static HEAP_LIMIT: AtomicU64 = AtomicU64::new(u64::MAX);
+
+/// Makes the heap look full past `limit` allocated bytes, `None` lifts the limit. For tests of
+/// the out of memory paths
+pub fn set_heap_limit(limit: Option<u64>) {
+    HEAP_LIMIT.store(limit.unwrap_or(u64::MAX), Ordering::Relaxed);
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct MemoryStats {
+    pub total_pages: u64,
+    pub free_pages: u64,
+    pub heap_allocated_bytes: u64,
+    pub heap_allocations: u64,
+}
+
+pub fn get_memory_stats() -> MemoryStats {
+    #[allow(static_mut_refs)]
+    let (total_pages, free_pages) = match unsafe { &MAIN_BUDDY_ALLOCATOR } {
+        Some(allocator) => (
+            allocator.allocator.get_page_count(),
+            allocator.allocator.get_free_page_count(),
+        ),
+        None => (0, 0),
+    };
+    MemoryStats {
+        total_pages,
+        free_pages,
+        heap_allocated_bytes: HEAP_ALLOCATED_BYTES.load(Ordering::Relaxed),
+        heap_allocations: HEAP_ALLOCATIONS.load(Ordering::Relaxed),
+    }
+}
+
+/// Physical memory given to the allocator: usable, and after the kernel image and boot page tables
+static ALLOCATABLE_MEMORY: AssignOnce<Range<u64>> = AssignOnce::new();
+
+/// Whether the physical range `start..end` is entirely allocatable memory
+pub fn is_allocatable_memory(start: u64, end: u64) -> bool {
+    ALLOCATABLE_MEMORY
+        .get()
+        .is_some_and(|memory| start >= memory.start && end <= memory.end && start <= end)
+}
+
 /// # Safety
 /// `memory_layout_ptr` must point to a valid memory layout, and `memory_layout_entries` must be a valid number
 pub unsafe fn init(
@@ -156,16 +225,18 @@ pub unsafe fn init(
     pml4_ptr_phys: u64,
     begin_usable_memory: u64,
 ) {
-    printf!(
-        "Memory layout at: {:?} ({} entries)\n=== BEGIN MEMORY LAYOUT DUMP ===\n",
+    log_debug!(
+        target: "memory",
+        "Memory layout at: {:?} ({} entries)",
         memory_layout_ptr,
         memory_layout_entries
     );
     for i in 0..memory_layout_entries {
         let region = core::ptr::read_volatile(memory_layout_ptr.offset(i as isize));
         let (s, e, u) = (region.start, region.end, region.usable);
-        printf!(
-            "REGION: {:016x} --> {:016x} (usable:{})\n",
+        log_debug!(
+            target: "memory",
+            "REGION: {:016x} --> {:016x} (usable:{})",
             s,
             e,
             match u {
@@ -174,8 +245,6 @@ pub unsafe fn init(
             }
         );
     }
-    printf!("===  END MEMORY LAYOUT DUMP  ===\n\n");
-
     for i in 0..memory_layout_entries {
         let region = core::ptr::read_volatile(memory_layout_ptr.offset(i as isize));
         let (s, e, u) = (region.start, region.end, region.usable);
@@ -192,12 +261,18 @@ pub unsafe fn init(
         let start = physical_to_virtual(s);
         let end = physical_to_virtual(e);
 
-        println!("Found usable memory region: {:#x} --> {:#x}", start, end);
+        log_info!(
+            target: "memory",
+            "Found usable memory region: {:#x} --> {:#x}",
+            start,
+            end
+        );
 
         #[allow(static_mut_refs)]
         match MAIN_BUDDY_ALLOCATOR {
             None => {
                 let alloc = BuddyPageAllocator::new(start, (end - start) / 4096);
+                ALLOCATABLE_MEMORY.set(s..e);
                 MAIN_BUDDY_ALLOCATOR = Some(
                     ExtendedBuddyPageAllocator::new(alloc)
                         .expect("Failed to initialize main buddy allocator."),
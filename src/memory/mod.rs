@@ -1,2 +1,5 @@
 pub mod buddy_alloc;
+pub mod entropy;
 pub mod mem;
+
+pub use entropy::random_u64;
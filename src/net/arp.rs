@@ -0,0 +1,135 @@
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use alloc::collections::BTreeMap;
+
+use crate::{
+    interrupts::pit::ms_to_ticks,
+    net::{ethernet::MacAddr, ipv4::Ipv4Addr},
+};
+
+pub const ARP_PACKET_SIZE: usize = 28;
+pub const ARP_DEFAULT_CACHE_TIMEOUT_MS: u64 = 60_000;
+/// Unanswered requests are sent again after a second
+pub const ARP_REQUEST_INTERVAL_TICKS: u64 = ms_to_ticks(1000);
+
+const ARP_HTYPE_ETHERNET: u16 = 1;
+const ARP_PTYPE_IPV4: u16 = 0x0800;
+
+static ARP_CACHE_TIMEOUT_TICKS: AtomicU64 = AtomicU64::new(0);
+
+/// How long a learned neighbor stays valid
+pub fn set_arp_cache_timeout(ms: u64) {
+    ARP_CACHE_TIMEOUT_TICKS.store(ms_to_ticks(ms), Ordering::Relaxed);
+}
+
+pub fn arp_cache_timeout_ticks() -> u64 {
+    match ARP_CACHE_TIMEOUT_TICKS.load(Ordering::Relaxed) {
+        0 => ms_to_ticks(ARP_DEFAULT_CACHE_TIMEOUT_MS),
+        ticks => ticks,
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArpOperation {
+    Request = 1,
+    Reply = 2,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct ArpPacket {
+    pub operation: ArpOperation,
+    pub sender_mac: MacAddr,
+    pub sender_ip: Ipv4Addr,
+    pub target_mac: MacAddr,
+    pub target_ip: Ipv4Addr,
+}
+
+impl ArpPacket {
+    /// Only ethernet/IPv4 packets are accepted
+    pub fn parse(data: &[u8]) -> Option<Self> {
+        if data.len() < ARP_PACKET_SIZE
+            || u16::from_be_bytes([data[0], data[1]]) != ARP_HTYPE_ETHERNET
+            || u16::from_be_bytes([data[2], data[3]]) != ARP_PTYPE_IPV4
+            || data[4] != 6
+            || data[5] != 4
+        {
+            return None;
+        }
+        let operation = match u16::from_be_bytes([data[6], data[7]]) {
+            1 => ArpOperation::Request,
+            2 => ArpOperation::Reply,
+            _ => return None,
+        };
+        Some(Self {
+            operation,
+            sender_mac: MacAddr(data[8..14].try_into().unwrap()),
+            sender_ip: Ipv4Addr(data[14..18].try_into().unwrap()),
+            target_mac: MacAddr(data[18..24].try_into().unwrap()),
+            target_ip: Ipv4Addr(data[24..28].try_into().unwrap()),
+        })
+    }
+
+    pub fn to_bytes(&self) -> [u8; ARP_PACKET_SIZE] {
+        let mut data = [0u8; ARP_PACKET_SIZE];
+        data[0..2].copy_from_slice(&ARP_HTYPE_ETHERNET.to_be_bytes());
+        data[2..4].copy_from_slice(&ARP_PTYPE_IPV4.to_be_bytes());
+        data[4] = 6;
+        data[5] = 4;
+        data[6..8].copy_from_slice(&(self.operation as u16).to_be_bytes());
+        data[8..14].copy_from_slice(&self.sender_mac.0);
+        data[14..18].copy_from_slice(&self.sender_ip.0);
+        data[18..24].copy_from_slice(&self.target_mac.0);
+        data[24..28].copy_from_slice(&self.target_ip.0);
+        data
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct ArpEntry {
+    pub mac: MacAddr,
+    pub updated_at: u64,
+}
+
+/// Neighbors of an interface, entries expire after `arp_cache_timeout_ticks`
+#[derive(Debug, Default)]
+pub struct ArpCache {
+    entries: BTreeMap<Ipv4Addr, ArpEntry>,
+}
+
+impl ArpCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn insert(&mut self, ip: Ipv4Addr, mac: MacAddr, now: u64) {
+        self.entries.insert(
+            ip,
+            ArpEntry {
+                mac,
+                updated_at: now,
+            },
+        );
+    }
+
+    pub fn contains(&self, ip: Ipv4Addr) -> bool {
+        self.entries.contains_key(&ip)
+    }
+
+    /// Expired entries are removed on lookup
+    pub fn lookup(&mut self, ip: Ipv4Addr, now: u64) -> Option<MacAddr> {
+        let entry = *self.entries.get(&ip)?;
+        if now.saturating_sub(entry.updated_at) >= arp_cache_timeout_ticks() {
+            self.entries.remove(&ip);
+            return None;
+        }
+        Some(entry.mac)
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&Ipv4Addr, &ArpEntry)> {
+        self.entries.iter()
+    }
+}
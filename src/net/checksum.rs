@@ -0,0 +1,29 @@
+/// Adds `data` to a one's complement sum, an odd trailing byte is padded with zero
+pub fn ones_complement_sum(mut sum: u32, data: &[u8]) -> u32 {
+    let (words, rest) = data.as_chunks::<2>();
+    for word in words {
+        sum += u16::from_be_bytes(*word) as u32;
+    }
+    if let [last] = rest {
+        sum += u16::from_be_bytes([*last, 0]) as u32;
+    }
+    // Keep it folded so sums can be chained (pseudo headers)
+    while sum > 0xFFFF {
+        sum = (sum & 0xFFFF) + (sum >> 16);
+    }
+    sum
+}
+
+/// Folds the sum and takes its complement
+pub fn finish_checksum(sum: u32) -> u16 {
+    let mut sum = sum;
+    while sum > 0xFFFF {
+        sum = (sum & 0xFFFF) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+/// RFC 1071 internet checksum, a buffer containing its valid checksum sums to 0
+pub fn internet_checksum(data: &[u8]) -> u16 {
+    finish_checksum(ones_complement_sum(0, data))
+}
@@ -0,0 +1,557 @@
+use alloc::{string::String, sync::Arc, vec::Vec};
+use spin::{rwlock::RwLock, Mutex};
+
+use crate::{
+    crypto::rng,
+    drivers::vfs::Arcrwb,
+    interrupts::{handlers::irq::irq0_timer::get_uptime_ticks, pit::ms_to_ticks},
+    net::{
+        ethernet::MacAddr,
+        interface::{Ipv4Config, NetInterface},
+        ipv4::{Ipv4Addr, SocketAddrV4},
+        udp::{self, UdpSocket},
+        NetError,
+    },
+};
+
+pub const DHCP_SERVER_PORT: u16 = 67;
+pub const DHCP_CLIENT_PORT: u16 = 68;
+
+const DHCP_INITIAL_RETRY_MS: u64 = 4_000;
+const DHCP_MAX_RETRY_MS: u64 = 64_000;
+/// Unanswered requests before starting over with a discover
+const DHCP_REQUEST_ATTEMPTS: u32 = 4;
+
+const BOOTP_REQUEST: u8 = 1;
+const BOOTP_REPLY: u8 = 2;
+const BOOTP_FIXED_SIZE: usize = 236;
+const BOOTP_FLAG_BROADCAST: u16 = 0x8000;
+const DHCP_MAGIC_COOKIE: [u8; 4] = [99, 130, 83, 99];
+const DHCP_MAX_MESSAGE_SIZE: usize = 1500;
+
+const OPTION_PAD: u8 = 0;
+const OPTION_NETMASK: u8 = 1;
+const OPTION_ROUTER: u8 = 3;
+const OPTION_DNS: u8 = 6;
+const OPTION_REQUESTED_ADDRESS: u8 = 50;
+const OPTION_LEASE_TIME: u8 = 51;
+const OPTION_MESSAGE_TYPE: u8 = 53;
+const OPTION_SERVER_ID: u8 = 54;
+const OPTION_PARAMETER_LIST: u8 = 55;
+const OPTION_RENEWAL_TIME: u8 = 58;
+const OPTION_REBINDING_TIME: u8 = 59;
+const OPTION_END: u8 = 255;
+
+/// Lease time meaning the address never expires
+const DHCP_INFINITE_LEASE: u32 = u32::MAX;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DhcpMessageType {
+    Discover = 1,
+    Offer = 2,
+    Request = 3,
+    Decline = 4,
+    Ack = 5,
+    Nak = 6,
+    Release = 7,
+}
+
+impl DhcpMessageType {
+    fn from_u8(value: u8) -> Option<Self> {
+        Some(match value {
+            1 => Self::Discover,
+            2 => Self::Offer,
+            3 => Self::Request,
+            4 => Self::Decline,
+            5 => Self::Ack,
+            6 => Self::Nak,
+            7 => Self::Release,
+            _ => return None,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DhcpState {
+    Init,
+    Selecting,
+    Requesting,
+    Bound,
+    Renewing,
+    Rebinding,
+}
+
+impl DhcpState {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DhcpState::Init => "init",
+            DhcpState::Selecting => "selecting",
+            DhcpState::Requesting => "requesting",
+            DhcpState::Bound => "bound",
+            DhcpState::Renewing => "renewing",
+            DhcpState::Rebinding => "rebinding",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct DhcpLease {
+    pub address: Ipv4Addr,
+    pub prefix_len: u8,
+    pub router: Option<Ipv4Addr>,
+    pub dns: Vec<Ipv4Addr>,
+    pub server: Ipv4Addr,
+    /// None for infinite leases
+    pub lease_secs: Option<u32>,
+    pub renewal_secs: Option<u32>,
+    pub rebinding_secs: Option<u32>,
+    pub acquired_at: u64,
+}
+
+impl DhcpLease {
+    fn deadline(&self, secs: Option<u32>) -> Option<u64> {
+        secs.map(|secs| self.acquired_at + ms_to_ticks(secs as u64 * 1000))
+    }
+
+    /// T1, when the client starts renewing with its server
+    pub fn renew_at(&self) -> Option<u64> {
+        self.deadline(self.renewal_secs.or(self.lease_secs.map(|secs| secs / 2)))
+    }
+
+    /// T2, when the client asks any server to extend the lease
+    pub fn rebind_at(&self) -> Option<u64> {
+        self.deadline(
+            self.rebinding_secs
+                .or(self.lease_secs.map(|secs| (secs as u64 * 7 / 8) as u32)),
+        )
+    }
+
+    pub fn expires_at(&self) -> Option<u64> {
+        self.deadline(self.lease_secs)
+    }
+}
+
+#[derive(Debug)]
+struct DhcpMessage {
+    kind: DhcpMessageType,
+    xid: u32,
+    chaddr: MacAddr,
+    yiaddr: Ipv4Addr,
+    netmask: Option<Ipv4Addr>,
+    router: Option<Ipv4Addr>,
+    dns: Vec<Ipv4Addr>,
+    server: Option<Ipv4Addr>,
+    lease_secs: Option<u32>,
+    renewal_secs: Option<u32>,
+    rebinding_secs: Option<u32>,
+}
+
+fn read_addr(value: &[u8]) -> Option<Ipv4Addr> {
+    Some(Ipv4Addr(value.get(..4)?.try_into().ok()?))
+}
+
+fn read_u32(value: &[u8]) -> Option<u32> {
+    Some(u32::from_be_bytes(value.get(..4)?.try_into().ok()?))
+}
+
+impl DhcpMessage {
+    fn parse(data: &[u8]) -> Option<Self> {
+        if data.len() < BOOTP_FIXED_SIZE + DHCP_MAGIC_COOKIE.len()
+            || data[0] != BOOTP_REPLY
+            || data[1] != 1
+            || data[2] != 6
+            || data[BOOTP_FIXED_SIZE..BOOTP_FIXED_SIZE + 4] != DHCP_MAGIC_COOKIE
+        {
+            return None;
+        }
+
+        let mut message = DhcpMessage {
+            kind: DhcpMessageType::Offer,
+            xid: u32::from_be_bytes(data[4..8].try_into().ok()?),
+            chaddr: MacAddr(data[28..34].try_into().ok()?),
+            yiaddr: read_addr(&data[16..20])?,
+            netmask: None,
+            router: None,
+            dns: Vec::new(),
+            server: None,
+            lease_secs: None,
+            renewal_secs: None,
+            rebinding_secs: None,
+        };
+        let mut kind = None;
+        let mut options = &data[BOOTP_FIXED_SIZE + 4..];
+        while let [code, rest @ ..] = options {
+            match *code {
+                OPTION_PAD => {
+                    options = rest;
+                    continue;
+                }
+                OPTION_END => break,
+                _ => {}
+            }
+            let [len, rest @ ..] = rest else {
+                break;
+            };
+            let Some(value) = rest.get(..*len as usize) else {
+                break;
+            };
+            match *code {
+                OPTION_MESSAGE_TYPE => {
+                    kind = value.first().and_then(|v| DhcpMessageType::from_u8(*v))
+                }
+                OPTION_NETMASK => message.netmask = read_addr(value),
+                OPTION_ROUTER => message.router = read_addr(value),
+                OPTION_DNS => {
+                    message.dns = value
+                        .as_chunks::<4>()
+                        .0
+                        .iter()
+                        .map(|a| Ipv4Addr(*a))
+                        .collect()
+                }
+                OPTION_SERVER_ID => message.server = read_addr(value),
+                OPTION_LEASE_TIME => message.lease_secs = read_u32(value),
+                OPTION_RENEWAL_TIME => message.renewal_secs = read_u32(value),
+                OPTION_REBINDING_TIME => message.rebinding_secs = read_u32(value),
+                _ => {}
+            }
+            options = &rest[*len as usize..];
+        }
+        message.kind = kind?;
+        Some(message)
+    }
+
+    fn to_lease(&self, now: u64) -> Option<DhcpLease> {
+        let netmask = self
+            .netmask
+            .unwrap_or(Ipv4Addr([255, 255, 255, 0]))
+            .to_u32();
+        // Non contiguous masks are invalid
+        if netmask.leading_ones() + netmask.trailing_zeros() != 32 {
+            return None;
+        }
+        Some(DhcpLease {
+            address: self.yiaddr,
+            prefix_len: netmask.leading_ones() as u8,
+            router: self.router,
+            dns: self.dns.clone(),
+            server: self.server?,
+            lease_secs: self.lease_secs.filter(|secs| *secs != DHCP_INFINITE_LEASE),
+            renewal_secs: self.renewal_secs,
+            rebinding_secs: self.rebinding_secs,
+            acquired_at: now,
+        })
+    }
+}
+
+/// Configures one interface, driven by `poll_dhcp`
+#[derive(Debug)]
+pub struct DhcpClient {
+    iface: Arcrwb<dyn NetInterface>,
+    name: String,
+    mac: MacAddr,
+    state: DhcpState,
+    xid: u32,
+    offer: Option<DhcpLease>,
+    lease: Option<DhcpLease>,
+    next_send: u64,
+    retry_ms: u64,
+    attempts: u32,
+}
+
+static DHCP_CLIENTS: Mutex<Vec<DhcpClient>> = Mutex::new(Vec::new());
+/// Shared by all the clients, replies are matched by transaction id
+static DHCP_SOCKET: Mutex<Option<Arc<RwLock<UdpSocket>>>> = Mutex::new(None);
+
+impl DhcpClient {
+    fn build_message(&self, kind: DhcpMessageType, ciaddr: Ipv4Addr) -> Vec<u8> {
+        let mut data = alloc::vec![0u8; BOOTP_FIXED_SIZE];
+        data[0] = BOOTP_REQUEST;
+        data[1] = 1;
+        data[2] = 6;
+        data[4..8].copy_from_slice(&self.xid.to_be_bytes());
+        // Replies can't be unicast to an address we don't have yet
+        if ciaddr.is_unspecified() {
+            data[10..12].copy_from_slice(&BOOTP_FLAG_BROADCAST.to_be_bytes());
+        }
+        data[12..16].copy_from_slice(&ciaddr.0);
+        data[28..34].copy_from_slice(&self.mac.0);
+        data.extend_from_slice(&DHCP_MAGIC_COOKIE);
+
+        data.extend_from_slice(&[OPTION_MESSAGE_TYPE, 1, kind as u8]);
+        if kind == DhcpMessageType::Request && ciaddr.is_unspecified() {
+            if let Some(offer) = &self.offer {
+                data.extend_from_slice(&[OPTION_REQUESTED_ADDRESS, 4]);
+                data.extend_from_slice(&offer.address.0);
+                data.extend_from_slice(&[OPTION_SERVER_ID, 4]);
+                data.extend_from_slice(&offer.server.0);
+            }
+        }
+        data.extend_from_slice(&[
+            OPTION_PARAMETER_LIST,
+            6,
+            OPTION_NETMASK,
+            OPTION_ROUTER,
+            OPTION_DNS,
+            OPTION_LEASE_TIME,
+            OPTION_RENEWAL_TIME,
+            OPTION_REBINDING_TIME,
+        ]);
+        data.push(OPTION_END);
+        data
+    }
+
+    fn send(
+        &self,
+        socket: &Arc<RwLock<UdpSocket>>,
+        kind: DhcpMessageType,
+        ciaddr: Ipv4Addr,
+        unicast_to: Option<Ipv4Addr>,
+    ) {
+        let message = self.build_message(kind, ciaddr);
+        let result = match unicast_to {
+            // Renewals go through the normal route, the server may be behind the gateway
+            Some(server) => udp::send_to(
+                socket,
+                SocketAddrV4::new(server, DHCP_SERVER_PORT),
+                &message,
+            ),
+            None => udp::send_on(
+                socket,
+                &self.iface,
+                ciaddr,
+                SocketAddrV4::new(Ipv4Addr::BROADCAST, DHCP_SERVER_PORT),
+                Ipv4Addr::BROADCAST,
+                &message,
+            ),
+        };
+        if let Err(e) = result {
+            crate::println!("DHCP on {}: failed to send {:?}: {:?}", self.name, kind, e);
+        }
+    }
+
+    fn schedule_retry(&mut self, now: u64) {
+        self.next_send = now + ms_to_ticks(self.retry_ms);
+        self.retry_ms = (self.retry_ms * 2).min(DHCP_MAX_RETRY_MS);
+    }
+
+    fn reset_retry(&mut self) {
+        self.retry_ms = DHCP_INITIAL_RETRY_MS;
+        self.attempts = 0;
+    }
+
+    fn set_config(&mut self, config: Option<Ipv4Config>) {
+        if let Err(e) = self.iface.write().set_ipv4_config(config) {
+            crate::println!("DHCP on {}: failed to configure: {:?}", self.name, e);
+        }
+    }
+
+    fn restart(&mut self) {
+        if self.lease.take().is_some() {
+            self.set_config(None);
+        }
+        self.offer = None;
+        self.state = DhcpState::Init;
+    }
+
+    fn tick(&mut self, socket: &Arc<RwLock<UdpSocket>>, now: u64) {
+        match self.state {
+            DhcpState::Init => {
+                self.xid = rng::next_u64() as u32;
+                self.reset_retry();
+                self.state = DhcpState::Selecting;
+                self.send(
+                    socket,
+                    DhcpMessageType::Discover,
+                    Ipv4Addr::UNSPECIFIED,
+                    None,
+                );
+                self.schedule_retry(now);
+            }
+            DhcpState::Selecting if now >= self.next_send => {
+                self.send(
+                    socket,
+                    DhcpMessageType::Discover,
+                    Ipv4Addr::UNSPECIFIED,
+                    None,
+                );
+                self.schedule_retry(now);
+            }
+            DhcpState::Requesting if now >= self.next_send => {
+                self.attempts += 1;
+                if self.attempts >= DHCP_REQUEST_ATTEMPTS {
+                    self.restart();
+                    return;
+                }
+                self.send(
+                    socket,
+                    DhcpMessageType::Request,
+                    Ipv4Addr::UNSPECIFIED,
+                    None,
+                );
+                self.schedule_retry(now);
+            }
+            DhcpState::Bound | DhcpState::Renewing | DhcpState::Rebinding => {
+                let Some(lease) = self.lease.clone() else {
+                    self.restart();
+                    return;
+                };
+                if lease.expires_at().is_some_and(|at| now >= at) {
+                    crate::println!("DHCP on {}: lease of {} expired", self.name, lease.address);
+                    self.restart();
+                } else if self.state != DhcpState::Rebinding
+                    && lease.rebind_at().is_some_and(|at| now >= at)
+                {
+                    self.state = DhcpState::Rebinding;
+                    self.reset_retry();
+                    self.send(socket, DhcpMessageType::Request, lease.address, None);
+                    self.schedule_retry(now);
+                } else if self.state == DhcpState::Bound
+                    && lease.renew_at().is_some_and(|at| now >= at)
+                {
+                    self.state = DhcpState::Renewing;
+                    self.reset_retry();
+                    self.send(
+                        socket,
+                        DhcpMessageType::Request,
+                        lease.address,
+                        Some(lease.server),
+                    );
+                    self.schedule_retry(now);
+                } else if self.state != DhcpState::Bound && now >= self.next_send {
+                    let unicast_to = (self.state == DhcpState::Renewing).then_some(lease.server);
+                    self.send(socket, DhcpMessageType::Request, lease.address, unicast_to);
+                    self.schedule_retry(now);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn handle(&mut self, socket: &Arc<RwLock<UdpSocket>>, message: DhcpMessage, now: u64) {
+        match (self.state, message.kind) {
+            (DhcpState::Selecting, DhcpMessageType::Offer) => {
+                let Some(offer) = message.to_lease(now) else {
+                    return;
+                };
+                self.offer = Some(offer);
+                self.state = DhcpState::Requesting;
+                self.reset_retry();
+                self.send(
+                    socket,
+                    DhcpMessageType::Request,
+                    Ipv4Addr::UNSPECIFIED,
+                    None,
+                );
+                self.schedule_retry(now);
+            }
+            (
+                DhcpState::Requesting | DhcpState::Renewing | DhcpState::Rebinding,
+                DhcpMessageType::Ack,
+            ) => {
+                let Some(lease) = message.to_lease(now) else {
+                    return;
+                };
+                if self.lease.as_ref().map(|l| l.address) != Some(lease.address) {
+                    crate::println!(
+                        "DHCP on {}: bound to {}/{}",
+                        self.name,
+                        lease.address,
+                        lease.prefix_len
+                    );
+                }
+                self.set_config(Some(Ipv4Config {
+                    address: lease.address,
+                    prefix_len: lease.prefix_len,
+                    gateway: lease.router,
+                }));
+                self.lease = Some(lease);
+                self.offer = None;
+                self.state = DhcpState::Bound;
+            }
+            (
+                DhcpState::Requesting | DhcpState::Renewing | DhcpState::Rebinding,
+                DhcpMessageType::Nak,
+            ) => {
+                crate::println!("DHCP on {}: request refused, starting over", self.name);
+                self.restart();
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Starts configuring `iface` with DHCP, the interface needs a hardware address
+pub fn start_dhcp(iface: Arcrwb<dyn NetInterface>) -> Result<(), NetError> {
+    let guard = iface.read();
+    let mac = guard.hardware_address().ok_or(NetError::InvalidArgument)?;
+    let name = String::from(guard.name());
+    drop(guard);
+
+    let mut socket = DHCP_SOCKET.lock();
+    if socket.is_none() {
+        let new_socket = Arc::new(RwLock::new(UdpSocket::new(true)));
+        udp::bind(
+            &new_socket,
+            SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, DHCP_CLIENT_PORT),
+        )?;
+        *socket = Some(new_socket);
+    }
+    drop(socket);
+
+    DHCP_CLIENTS.lock().push(DhcpClient {
+        iface,
+        name,
+        mac,
+        state: DhcpState::Init,
+        xid: 0,
+        offer: None,
+        lease: None,
+        next_send: 0,
+        retry_ms: DHCP_INITIAL_RETRY_MS,
+        attempts: 0,
+    });
+    Ok(())
+}
+
+/// Stops configuring `iface`, the address it was given is kept
+pub fn stop_dhcp(iface: &Arcrwb<dyn NetInterface>) {
+    DHCP_CLIENTS
+        .lock()
+        .retain(|client| !Arc::ptr_eq(&client.iface, iface));
+}
+
+/// Handles the received replies, then sends what the timers ask for
+pub fn poll_dhcp() {
+    let Some(socket) = DHCP_SOCKET.lock().clone() else {
+        return;
+    };
+    let now = get_uptime_ticks();
+    let mut clients = DHCP_CLIENTS.lock();
+
+    let mut buf = alloc::vec![0u8; DHCP_MAX_MESSAGE_SIZE];
+    while let Ok((len, _)) = udp::recv_from(&socket, &mut buf) {
+        let Some(message) = DhcpMessage::parse(&buf[..len]) else {
+            continue;
+        };
+        if let Some(client) = clients
+            .iter_mut()
+            .find(|c| c.xid == message.xid && c.mac == message.chaddr)
+        {
+            client.handle(&socket, message, now);
+        }
+    }
+
+    for client in clients.iter_mut() {
+        client.tick(&socket, now);
+    }
+}
+
+/// The state of the client of interface `name` and its current lease
+pub fn dhcp_status(name: &str) -> Option<(DhcpState, Option<DhcpLease>)> {
+    DHCP_CLIENTS
+        .lock()
+        .iter()
+        .find(|c| c.name == name)
+        .map(|c| (c.state, c.lease.clone()))
+}
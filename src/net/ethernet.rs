@@ -0,0 +1,231 @@
+use alloc::{boxed::Box, collections::BTreeMap, string::String, vec::Vec};
+
+use crate::{
+    interrupts::handlers::irq::irq0_timer::get_uptime_ticks,
+    net::{
+        arp::{ArpCache, ArpOperation, ArpPacket, ARP_REQUEST_INTERVAL_TICKS},
+        interface::{Ipv4Config, NetInterface},
+        ipv4::Ipv4Addr,
+        NetError,
+    },
+};
+
+pub const ETHERNET_HEADER_SIZE: usize = 14;
+/// Frames shorter than this (without the FCS) are padded
+pub const ETHERNET_MIN_FRAME_SIZE: usize = 60;
+pub const ETHERNET_DEFAULT_MTU: usize = 1500;
+
+pub const ETHERTYPE_IPV4: u16 = 0x0800;
+pub const ETHERTYPE_ARP: u16 = 0x0806;
+
+/// Packets kept per neighbor while its address is being resolved
+const MAX_PENDING_PER_NEIGHBOR: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+pub struct MacAddr(pub [u8; 6]);
+
+impl MacAddr {
+    pub const BROADCAST: MacAddr = MacAddr([0xFF; 6]);
+    pub const ZERO: MacAddr = MacAddr([0; 6]);
+}
+
+impl core::fmt::Display for MacAddr {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        let m = &self.0;
+        write!(
+            f,
+            "{:02x}:{:02x}:{:02x}:{:02x}:{:02x}:{:02x}",
+            m[0], m[1], m[2], m[3], m[4], m[5]
+        )
+    }
+}
+
+/// A NIC driver, sends and receives raw ethernet frames (without the FCS)
+pub trait EthernetDevice: core::fmt::Debug + Send + Sync {
+    fn mac(&self) -> MacAddr;
+
+    fn mtu(&self) -> usize {
+        ETHERNET_DEFAULT_MTU
+    }
+
+    fn send_frame(&mut self, frame: &[u8]) -> Result<(), NetError>;
+
+    fn receive_frame(&mut self) -> Option<Vec<u8>>;
+}
+
+#[derive(Debug)]
+struct PendingNeighbor {
+    packets: Vec<Vec<u8>>,
+    last_request: u64,
+}
+
+/// IPv4 over an ethernet device, neighbors are resolved with ARP
+#[derive(Debug)]
+pub struct EthernetInterface {
+    name: String,
+    device: Box<dyn EthernetDevice>,
+    config: Option<Ipv4Config>,
+    arp: ArpCache,
+    pending: BTreeMap<Ipv4Addr, PendingNeighbor>,
+}
+
+impl EthernetInterface {
+    pub fn new(name: String, device: Box<dyn EthernetDevice>, config: Option<Ipv4Config>) -> Self {
+        Self {
+            name,
+            device,
+            config,
+            arp: ArpCache::new(),
+            pending: BTreeMap::new(),
+        }
+    }
+
+    pub fn arp_cache(&self) -> &ArpCache {
+        &self.arp
+    }
+
+    fn send(&mut self, dst: MacAddr, ethertype: u16, payload: &[u8]) -> Result<(), NetError> {
+        let mut frame =
+            Vec::with_capacity(ETHERNET_MIN_FRAME_SIZE.max(ETHERNET_HEADER_SIZE + payload.len()));
+        frame.extend_from_slice(&dst.0);
+        frame.extend_from_slice(&self.device.mac().0);
+        frame.extend_from_slice(&ethertype.to_be_bytes());
+        frame.extend_from_slice(payload);
+        if frame.len() < ETHERNET_MIN_FRAME_SIZE {
+            frame.resize(ETHERNET_MIN_FRAME_SIZE, 0);
+        }
+        self.device.send_frame(&frame)
+    }
+
+    fn send_arp(
+        &mut self,
+        operation: ArpOperation,
+        dst: MacAddr,
+        target_mac: MacAddr,
+        target_ip: Ipv4Addr,
+    ) -> Result<(), NetError> {
+        let Some(config) = self.config else {
+            return Err(NetError::AddressNotAvailable);
+        };
+        let packet = ArpPacket {
+            operation,
+            sender_mac: self.device.mac(),
+            sender_ip: config.address,
+            target_mac,
+            target_ip,
+        };
+        self.send(dst, ETHERTYPE_ARP, &packet.to_bytes())
+    }
+
+    fn handle_arp(&mut self, payload: &[u8]) {
+        let (Some(packet), Some(config)) = (ArpPacket::parse(payload), self.config) else {
+            return;
+        };
+        let now = get_uptime_ticks();
+        let for_us = packet.target_ip == config.address;
+        // RFC 826: refresh known entries, only learn new ones when we are the target
+        if (for_us || self.arp.contains(packet.sender_ip)) && !packet.sender_ip.is_unspecified() {
+            self.arp.insert(packet.sender_ip, packet.sender_mac, now);
+        }
+        if !for_us {
+            return;
+        }
+        if packet.operation == ArpOperation::Request {
+            let _ = self.send_arp(
+                ArpOperation::Reply,
+                packet.sender_mac,
+                packet.sender_mac,
+                packet.sender_ip,
+            );
+        }
+        if let Some(pending) = self.pending.remove(&packet.sender_ip) {
+            for ip_packet in pending.packets {
+                let _ = self.send(packet.sender_mac, ETHERTYPE_IPV4, &ip_packet);
+            }
+        }
+    }
+}
+
+impl NetInterface for EthernetInterface {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn mtu(&self) -> usize {
+        self.device.mtu()
+    }
+
+    fn ipv4_config(&self) -> Option<Ipv4Config> {
+        self.config
+    }
+
+    fn set_ipv4_config(&mut self, config: Option<Ipv4Config>) -> Result<(), NetError> {
+        if self.config.map(|c| c.address) != config.map(|c| c.address) {
+            self.arp.clear();
+            self.pending.clear();
+        }
+        self.config = config;
+        Ok(())
+    }
+
+    fn hardware_address(&self) -> Option<MacAddr> {
+        Some(self.device.mac())
+    }
+
+    fn transmit_ipv4(&mut self, next_hop: Ipv4Addr, packet: &[u8]) -> Result<(), NetError> {
+        let now = get_uptime_ticks();
+        let is_broadcast = next_hop == Ipv4Addr::BROADCAST
+            || self
+                .config
+                .is_some_and(|c| c.broadcast_address() == next_hop);
+        if is_broadcast {
+            return self.send(MacAddr::BROADCAST, ETHERTYPE_IPV4, packet);
+        }
+        if let Some(mac) = self.arp.lookup(next_hop, now) {
+            return self.send(mac, ETHERTYPE_IPV4, packet);
+        }
+
+        // Queued until the neighbor answers, the request is repeated if it doesn't
+        let pending = self
+            .pending
+            .entry(next_hop)
+            .or_insert_with(|| PendingNeighbor {
+                packets: Vec::new(),
+                last_request: 0,
+            });
+        if pending.packets.len() < MAX_PENDING_PER_NEIGHBOR {
+            pending.packets.push(packet.to_vec());
+        }
+        let resend = pending.packets.len() == 1
+            || now.saturating_sub(pending.last_request) >= ARP_REQUEST_INTERVAL_TICKS;
+        if resend {
+            pending.last_request = now;
+            self.send_arp(
+                ArpOperation::Request,
+                MacAddr::BROADCAST,
+                MacAddr::ZERO,
+                next_hop,
+            )?;
+        }
+        Ok(())
+    }
+
+    fn receive_ipv4(&mut self) -> Option<Vec<u8>> {
+        while let Some(frame) = self.device.receive_frame() {
+            if frame.len() < ETHERNET_HEADER_SIZE {
+                continue;
+            }
+            let dst = MacAddr(frame[0..6].try_into().unwrap());
+            if dst != self.device.mac() && dst != MacAddr::BROADCAST {
+                continue;
+            }
+            let payload = &frame[ETHERNET_HEADER_SIZE..];
+            match u16::from_be_bytes([frame[12], frame[13]]) {
+                ETHERTYPE_IPV4 => return Some(payload.to_vec()),
+                ETHERTYPE_ARP => self.handle_arp(payload),
+                _ => {}
+            }
+        }
+        None
+    }
+}
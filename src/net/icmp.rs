@@ -0,0 +1,99 @@
+use core::sync::atomic::{AtomicU16, Ordering};
+
+use alloc::{collections::BTreeMap, vec::Vec};
+use spin::Mutex;
+
+use crate::{
+    interrupts::{handlers::irq::irq0_timer::get_uptime_ticks, pit::ms_to_ticks},
+    net::{
+        checksum::internet_checksum,
+        ipv4::{self, Ipv4Addr, IP_PROTOCOL_ICMP},
+        poll_interfaces, NetError,
+    },
+};
+
+pub const ICMP_HEADER_SIZE: usize = 8;
+pub const ICMP_ECHO_REPLY: u8 = 0;
+pub const ICMP_ECHO_REQUEST: u8 = 8;
+
+const PING_PAYLOAD: &[u8] = b"Campix ping";
+
+/// Echo requests sent by `ping`, keyed by (identifier, sequence).
+/// The value is set to the tick the reply arrived at
+static PENDING_PINGS: Mutex<BTreeMap<(u16, u16), Option<u64>>> = Mutex::new(BTreeMap::new());
+static NEXT_PING_SEQUENCE: AtomicU16 = AtomicU16::new(1);
+const PING_IDENTIFIER: u16 = 0xCA11;
+
+fn build_echo(kind: u8, identifier: u16, sequence: u16, data: &[u8]) -> Vec<u8> {
+    let mut message = Vec::with_capacity(ICMP_HEADER_SIZE + data.len());
+    message.push(kind);
+    message.push(0);
+    message.extend_from_slice(&[0, 0]);
+    message.extend_from_slice(&identifier.to_be_bytes());
+    message.extend_from_slice(&sequence.to_be_bytes());
+    message.extend_from_slice(data);
+    let checksum = internet_checksum(&message);
+    message[2..4].copy_from_slice(&checksum.to_be_bytes());
+    message
+}
+
+pub fn input(src: Ipv4Addr, dst: Ipv4Addr, message: &[u8]) {
+    if message.len() < ICMP_HEADER_SIZE || internet_checksum(message) != 0 {
+        return;
+    }
+    let identifier = u16::from_be_bytes([message[4], message[5]]);
+    let sequence = u16::from_be_bytes([message[6], message[7]]);
+    match message[0] {
+        ICMP_ECHO_REQUEST if message[1] == 0 => {
+            let reply = build_echo(
+                ICMP_ECHO_REPLY,
+                identifier,
+                sequence,
+                &message[ICMP_HEADER_SIZE..],
+            );
+            // Requests sent to a broadcast address are answered from the interface address
+            let src_addr = if ipv4::is_broadcast_address(dst) {
+                None
+            } else {
+                Some(dst)
+            };
+            let _ = ipv4::output(src_addr, src, IP_PROTOCOL_ICMP, &reply);
+        }
+        ICMP_ECHO_REPLY => {
+            if let Some(received) = PENDING_PINGS.lock().get_mut(&(identifier, sequence)) {
+                received.get_or_insert(get_uptime_ticks());
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Sends an echo request to `addr` and waits for the reply, returns the round trip in ticks.
+/// Interrupts must be enabled, the timeout is measured with the timer
+pub fn ping(addr: Ipv4Addr, timeout_ms: u64) -> Result<u64, NetError> {
+    let sequence = NEXT_PING_SEQUENCE.fetch_add(1, Ordering::Relaxed);
+    let key = (PING_IDENTIFIER, sequence);
+    PENDING_PINGS.lock().insert(key, None);
+
+    let sent_at = get_uptime_ticks();
+    let deadline = sent_at + ms_to_ticks(timeout_ms);
+    let request = build_echo(ICMP_ECHO_REQUEST, PING_IDENTIFIER, sequence, PING_PAYLOAD);
+    if let Err(e) = ipv4::output(None, addr, IP_PROTOCOL_ICMP, &request) {
+        PENDING_PINGS.lock().remove(&key);
+        return Err(e);
+    }
+
+    loop {
+        poll_interfaces();
+        let received = PENDING_PINGS.lock().get(&key).copied().flatten();
+        if let Some(received) = received {
+            PENDING_PINGS.lock().remove(&key);
+            return Ok(received - sent_at);
+        }
+        if get_uptime_ticks() >= deadline {
+            PENDING_PINGS.lock().remove(&key);
+            return Err(NetError::TimedOut);
+        }
+        core::hint::spin_loop();
+    }
+}
@@ -0,0 +1,89 @@
+use alloc::{boxed::Box, sync::Arc, vec::Vec};
+use spin::rwlock::RwLock;
+
+use crate::{
+    drivers::vfs::{arcrwb_new_from_box, Arcrwb},
+    net::{ethernet::MacAddr, ipv4::Ipv4Addr, NetError},
+};
+
+/// Address assigned to an interface, with the prefix length of its subnet
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Ipv4Config {
+    pub address: Ipv4Addr,
+    pub prefix_len: u8,
+    /// Next hop for destinations outside of the subnet
+    pub gateway: Option<Ipv4Addr>,
+}
+
+impl Ipv4Config {
+    pub fn netmask(&self) -> u32 {
+        match self.prefix_len {
+            0 => 0,
+            len => u32::MAX << (32 - len.min(32)),
+        }
+    }
+
+    pub fn broadcast_address(&self) -> Ipv4Addr {
+        Ipv4Addr::from_u32(self.address.to_u32() | !self.netmask())
+    }
+
+    /// Whether `addr` is in the subnet of this interface
+    pub fn contains(&self, addr: Ipv4Addr) -> bool {
+        (addr.to_u32() ^ self.address.to_u32()) & self.netmask() == 0
+    }
+}
+
+pub trait NetInterface: core::fmt::Debug + Send + Sync {
+    fn name(&self) -> &str;
+
+    /// Largest IPv4 packet the interface can send, headers included
+    fn mtu(&self) -> usize;
+
+    fn ipv4_config(&self) -> Option<Ipv4Config>;
+
+    fn set_ipv4_config(&mut self, _config: Option<Ipv4Config>) -> Result<(), NetError> {
+        Err(NetError::InvalidArgument)
+    }
+
+    /// Link layer address, used as the DHCP client identifier
+    fn hardware_address(&self) -> Option<MacAddr> {
+        None
+    }
+
+    fn is_loopback(&self) -> bool {
+        false
+    }
+
+    /// Sends an IPv4 packet, `next_hop` is the neighbor it should be delivered to
+    fn transmit_ipv4(&mut self, next_hop: Ipv4Addr, packet: &[u8]) -> Result<(), NetError>;
+
+    /// Returns the next received IPv4 packet, if any
+    fn receive_ipv4(&mut self) -> Option<Vec<u8>>;
+}
+
+static INTERFACES: RwLock<Vec<Arcrwb<dyn NetInterface>>> = RwLock::new(Vec::new());
+
+pub fn register_interface(iface: Box<dyn NetInterface>) -> Arcrwb<dyn NetInterface> {
+    let iface = arcrwb_new_from_box(iface);
+    INTERFACES.write().push(iface.clone());
+    iface
+}
+
+/// Removes an interface, packets routed through it before are still sent
+pub fn unregister_interface(iface: &Arcrwb<dyn NetInterface>) {
+    INTERFACES
+        .write()
+        .retain(|registered| !Arc::ptr_eq(registered, iface));
+}
+
+pub fn interfaces() -> Vec<Arcrwb<dyn NetInterface>> {
+    INTERFACES.read().clone()
+}
+
+pub fn get_interface(name: &str) -> Option<Arcrwb<dyn NetInterface>> {
+    INTERFACES
+        .read()
+        .iter()
+        .find(|iface| iface.read().name() == name)
+        .cloned()
+}
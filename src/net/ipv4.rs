@@ -0,0 +1,271 @@
+use core::sync::atomic::{AtomicU16, Ordering};
+
+use alloc::vec::Vec;
+
+use crate::{
+    drivers::vfs::Arcrwb,
+    net::{
+        checksum::internet_checksum,
+        icmp,
+        interface::{interfaces, Ipv4Config, NetInterface},
+        poll_interfaces, udp, NetError,
+    },
+};
+
+pub const IPV4_HEADER_SIZE: usize = 20;
+pub const IPV4_DEFAULT_TTL: u8 = 64;
+
+pub const IP_PROTOCOL_ICMP: u8 = 1;
+pub const IP_PROTOCOL_UDP: u8 = 17;
+
+const IPV4_FLAG_DONT_FRAGMENT: u16 = 0x4000;
+const IPV4_FLAG_MORE_FRAGMENTS: u16 = 0x2000;
+const IPV4_FRAGMENT_OFFSET_MASK: u16 = 0x1FFF;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+pub struct Ipv4Addr(pub [u8; 4]);
+
+impl Ipv4Addr {
+    pub const UNSPECIFIED: Ipv4Addr = Ipv4Addr([0, 0, 0, 0]);
+    pub const LOCALHOST: Ipv4Addr = Ipv4Addr([127, 0, 0, 1]);
+    pub const BROADCAST: Ipv4Addr = Ipv4Addr([255, 255, 255, 255]);
+
+    pub fn from_u32(addr: u32) -> Self {
+        Self(addr.to_be_bytes())
+    }
+
+    pub fn to_u32(self) -> u32 {
+        u32::from_be_bytes(self.0)
+    }
+
+    pub fn is_unspecified(self) -> bool {
+        self == Self::UNSPECIFIED
+    }
+
+    /// 127.0.0.0/8
+    pub fn is_loopback(self) -> bool {
+        self.0[0] == 127
+    }
+}
+
+impl core::fmt::Display for Ipv4Addr {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "{}.{}.{}.{}", self.0[0], self.0[1], self.0[2], self.0[3])
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+pub struct SocketAddrV4 {
+    pub addr: Ipv4Addr,
+    pub port: u16,
+}
+
+impl SocketAddrV4 {
+    pub fn new(addr: Ipv4Addr, port: u16) -> Self {
+        Self { addr, port }
+    }
+}
+
+impl core::fmt::Display for SocketAddrV4 {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "{}:{}", self.addr, self.port)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Ipv4Header {
+    pub header_len: usize,
+    pub total_len: usize,
+    pub flags_fragment: u16,
+    pub ttl: u8,
+    pub protocol: u8,
+    pub src: Ipv4Addr,
+    pub dst: Ipv4Addr,
+}
+
+impl Ipv4Header {
+    pub fn is_fragment(&self) -> bool {
+        self.flags_fragment & (IPV4_FLAG_MORE_FRAGMENTS | IPV4_FRAGMENT_OFFSET_MASK) != 0
+    }
+
+    /// Returns None if the header is malformed or its checksum is wrong
+    pub fn parse(packet: &[u8]) -> Option<Self> {
+        if packet.len() < IPV4_HEADER_SIZE || packet[0] >> 4 != 4 {
+            return None;
+        }
+        let header_len = ((packet[0] & 0xF) as usize) * 4;
+        let total_len = u16::from_be_bytes([packet[2], packet[3]]) as usize;
+        if header_len < IPV4_HEADER_SIZE || total_len < header_len || total_len > packet.len() {
+            return None;
+        }
+        if internet_checksum(&packet[..header_len]) != 0 {
+            return None;
+        }
+        Some(Self {
+            header_len,
+            total_len,
+            flags_fragment: u16::from_be_bytes([packet[6], packet[7]]),
+            ttl: packet[8],
+            protocol: packet[9],
+            src: Ipv4Addr([packet[12], packet[13], packet[14], packet[15]]),
+            dst: Ipv4Addr([packet[16], packet[17], packet[18], packet[19]]),
+        })
+    }
+}
+
+static NEXT_IDENTIFICATION: AtomicU16 = AtomicU16::new(1);
+
+/// Where a packet is sent: the interface, the source address and the neighbor to deliver it to
+#[derive(Debug, Clone)]
+pub struct Ipv4Route {
+    pub iface: Arcrwb<dyn NetInterface>,
+    pub src: Ipv4Addr,
+    pub next_hop: Ipv4Addr,
+}
+
+/// Finds the route to `dst`. Local addresses go through the loopback interface,
+/// then the subnets of the interfaces are tried before their gateways
+pub fn route(dst: Ipv4Addr) -> Option<Ipv4Route> {
+    let ifaces = interfaces();
+    let is_local = dst.is_loopback()
+        || ifaces
+            .iter()
+            .any(|iface| iface.read().ipv4_config().map(|c| c.address) == Some(dst));
+    for iface in ifaces.iter() {
+        let guard = iface.read();
+        let Some(config) = guard.ipv4_config() else {
+            continue;
+        };
+        let matches = if is_local {
+            guard.is_loopback()
+        } else {
+            !guard.is_loopback() && config.contains(dst)
+        };
+        if matches {
+            let src = if is_local && !dst.is_loopback() {
+                dst
+            } else {
+                config.address
+            };
+            drop(guard);
+            return Some(Ipv4Route {
+                iface: iface.clone(),
+                src,
+                next_hop: dst,
+            });
+        }
+    }
+    if is_local {
+        return None;
+    }
+    for iface in ifaces {
+        let config = iface.read().ipv4_config();
+        if let Some(Ipv4Config {
+            address,
+            gateway: Some(gateway),
+            ..
+        }) = config
+        {
+            return Some(Ipv4Route {
+                iface,
+                src: address,
+                next_hop: gateway,
+            });
+        }
+    }
+    None
+}
+
+/// Limited broadcast, or the broadcast address of an interface subnet
+pub fn is_broadcast_address(addr: Ipv4Addr) -> bool {
+    addr == Ipv4Addr::BROADCAST
+        || interfaces().iter().any(|iface| {
+            let guard = iface.read();
+            !guard.is_loopback() && guard.ipv4_config().map(|c| c.broadcast_address()) == Some(addr)
+        })
+}
+
+/// Whether packets sent to `addr` are for this host
+pub fn is_local_address(addr: Ipv4Addr) -> bool {
+    addr.is_loopback()
+        || is_broadcast_address(addr)
+        || interfaces()
+            .iter()
+            .any(|iface| iface.read().ipv4_config().map(|c| c.address) == Some(addr))
+}
+
+/// Sends `payload` to `dst`, `src` is picked from the route when not given.
+/// Packets are never fragmented, they must fit in the interface MTU
+pub fn output(
+    src: Option<Ipv4Addr>,
+    dst: Ipv4Addr,
+    protocol: u8,
+    payload: &[u8],
+) -> Result<(), NetError> {
+    let route = route(dst).ok_or(NetError::NetworkUnreachable)?;
+    let src = src.unwrap_or(route.src);
+    output_on(&route.iface, src, dst, route.next_hop, protocol, payload)
+}
+
+/// Sends `payload` through `iface` without looking up a route,
+/// needed before the interface has an address
+pub fn output_on(
+    iface: &Arcrwb<dyn NetInterface>,
+    src: Ipv4Addr,
+    dst: Ipv4Addr,
+    next_hop: Ipv4Addr,
+    protocol: u8,
+    payload: &[u8],
+) -> Result<(), NetError> {
+    let total_len = IPV4_HEADER_SIZE + payload.len();
+    let mut guard = iface.write();
+    if total_len > guard.mtu() || total_len > u16::MAX as usize {
+        return Err(NetError::MessageTooLong);
+    }
+
+    let mut packet = Vec::with_capacity(total_len);
+    packet.push(0x45);
+    packet.push(0);
+    packet.extend_from_slice(&(total_len as u16).to_be_bytes());
+    packet.extend_from_slice(
+        &NEXT_IDENTIFICATION
+            .fetch_add(1, Ordering::Relaxed)
+            .to_be_bytes(),
+    );
+    packet.extend_from_slice(&IPV4_FLAG_DONT_FRAGMENT.to_be_bytes());
+    packet.push(IPV4_DEFAULT_TTL);
+    packet.push(protocol);
+    packet.extend_from_slice(&[0, 0]);
+    packet.extend_from_slice(&src.0);
+    packet.extend_from_slice(&dst.0);
+    let checksum = internet_checksum(&packet);
+    packet[10..12].copy_from_slice(&checksum.to_be_bytes());
+    packet.extend_from_slice(payload);
+
+    let is_loopback = guard.is_loopback();
+    guard.transmit_ipv4(next_hop, &packet)?;
+    drop(guard);
+
+    // Nothing else drains lo
+    if is_loopback {
+        poll_interfaces();
+    }
+    Ok(())
+}
+
+/// Processes a received packet, packets not addressed to this host are dropped.
+/// There is no reassembly, fragments are dropped too
+pub fn input(packet: &[u8]) {
+    let Some(header) = Ipv4Header::parse(packet) else {
+        return;
+    };
+    if header.is_fragment() || !is_local_address(header.dst) {
+        return;
+    }
+    let payload = &packet[header.header_len..header.total_len];
+    match header.protocol {
+        IP_PROTOCOL_ICMP => icmp::input(header.src, header.dst, payload),
+        IP_PROTOCOL_UDP => udp::input(header.src, header.dst, payload),
+        _ => {}
+    }
+}
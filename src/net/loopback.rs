@@ -0,0 +1,57 @@
+use alloc::{collections::VecDeque, vec::Vec};
+
+use crate::net::{
+    interface::{Ipv4Config, NetInterface},
+    ipv4::Ipv4Addr,
+    NetError,
+};
+
+pub const LOOPBACK_MTU: usize = 65536;
+/// Packets queued on `lo` before new ones are dropped
+pub const LOOPBACK_QUEUE_SIZE: usize = 1024;
+
+/// The `lo` interface, every transmitted packet is received back
+#[derive(Debug, Default)]
+pub struct LoopbackInterface {
+    queue: VecDeque<Vec<u8>>,
+}
+
+impl LoopbackInterface {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl NetInterface for LoopbackInterface {
+    fn name(&self) -> &str {
+        "lo"
+    }
+
+    fn mtu(&self) -> usize {
+        LOOPBACK_MTU
+    }
+
+    fn ipv4_config(&self) -> Option<Ipv4Config> {
+        Some(Ipv4Config {
+            address: Ipv4Addr::LOCALHOST,
+            prefix_len: 8,
+            gateway: None,
+        })
+    }
+
+    fn is_loopback(&self) -> bool {
+        true
+    }
+
+    fn transmit_ipv4(&mut self, _next_hop: Ipv4Addr, packet: &[u8]) -> Result<(), NetError> {
+        // Like a full NIC queue, the packet is silently lost
+        if self.queue.len() < LOOPBACK_QUEUE_SIZE {
+            self.queue.push_back(packet.to_vec());
+        }
+        Ok(())
+    }
+
+    fn receive_ipv4(&mut self) -> Option<Vec<u8>> {
+        self.queue.pop_front()
+    }
+}
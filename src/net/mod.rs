@@ -0,0 +1,131 @@
+use alloc::{boxed::Box, format, string::String, sync::Arc};
+
+use crate::{
+    drivers::vfs::{get_vfs, Arcrwb, VfsError},
+    interrupts::{handlers::irq::irq0_timer::get_uptime_ticks, pit::ticks_to_ms},
+    net::{
+        ethernet::{EthernetDevice, EthernetInterface},
+        interface::{interfaces, register_interface, NetInterface},
+        loopback::LoopbackInterface,
+        socket::{NetSockFs, NET_SOCKFS_MOUNT},
+    },
+    process::wait::WaitQueue,
+};
+
+pub mod arp;
+pub mod checksum;
+pub mod dhcp;
+pub mod ethernet;
+pub mod icmp;
+pub mod interface;
+pub mod ipv4;
+pub mod loopback;
+pub mod socket;
+pub mod udp;
+
+#[derive(Debug)]
+pub enum NetError {
+    Vfs(VfsError),
+    NotSocket,
+    InvalidArgument,
+    AddressInUse,
+    AddressNotAvailable,
+    NetworkUnreachable,
+    MessageTooLong,
+    DestinationRequired,
+    TimedOut,
+    /// The operation has to wait, the caller can sleep on the queue and try again
+    WouldBlock(Arc<WaitQueue>),
+}
+
+impl From<VfsError> for NetError {
+    fn from(value: VfsError) -> Self {
+        NetError::Vfs(value)
+    }
+}
+
+/// Hands every packet received by the interfaces to the IPv4 layer
+pub fn poll_interfaces() {
+    for iface in interfaces() {
+        loop {
+            // The interface lock is released before the packet is processed, input may transmit
+            let Some(packet) = iface.write().receive_ipv4() else {
+                break;
+            };
+            ipv4::input(&packet);
+        }
+    }
+}
+
+/// Processes received packets and runs the protocol timers
+pub fn poll() {
+    poll_interfaces();
+    dhcp::poll_dhcp();
+}
+
+/// Registers the interface of a NIC driver, its address is then configured with DHCP
+pub fn register_ethernet_interface(
+    name: String,
+    device: Box<dyn EthernetDevice>,
+) -> Arcrwb<dyn NetInterface> {
+    let iface = register_interface(Box::new(EthernetInterface::new(name, device, None)));
+    if let Err(e) = dhcp::start_dhcp(iface.clone()) {
+        crate::println!("Failed to start DHCP: {:?}", e);
+    }
+    iface
+}
+
+/// One line per interface: name, address, gateway, DNS servers and DHCP lease state
+pub fn format_config() -> String {
+    let mut out = String::new();
+    for iface in interfaces() {
+        let guard = iface.read();
+        let name = String::from(guard.name());
+        let config = guard.ipv4_config();
+        let mac = guard.hardware_address();
+        drop(guard);
+
+        out.push_str(&name);
+        if let Some(mac) = mac {
+            out.push_str(&format!(" ether {}", mac));
+        }
+        match config {
+            Some(config) => {
+                out.push_str(&format!(" inet {}/{}", config.address, config.prefix_len));
+                if let Some(gateway) = config.gateway {
+                    out.push_str(&format!(" gateway {}", gateway));
+                }
+            }
+            None => out.push_str(" inet none"),
+        }
+        if let Some((state, lease)) = dhcp::dhcp_status(&name) {
+            out.push_str(&format!(" dhcp {}", state.as_str()));
+            if let Some(lease) = lease {
+                for dns in lease.dns.iter() {
+                    out.push_str(&format!(" dns {}", dns));
+                }
+                if let Some(expires_at) = lease.expires_at() {
+                    let remaining = expires_at.saturating_sub(get_uptime_ticks());
+                    out.push_str(&format!(" lease {}", ticks_to_ms(remaining) / 1000));
+                }
+            }
+        }
+        out.push('\n');
+    }
+    out
+}
+
+/// Sends an echo request to `addr`, returns the round trip time in ticks
+pub fn ping(addr: ipv4::Ipv4Addr, timeout_ms: u64) -> Result<u64, NetError> {
+    icmp::ping(addr, timeout_ms)
+}
+
+pub fn init() {
+    register_interface(Box::new(LoopbackInterface::new()));
+
+    let vfs = get_vfs();
+    let mut guard = vfs.write();
+    guard
+        .mount(&NET_SOCKFS_MOUNT, Box::new(NetSockFs::new()), 0)
+        .unwrap();
+}
@@ -0,0 +1,325 @@
+use alloc::string::ToString;
+use alloc::sync::Arc;
+use alloc::{string::String, vec::Vec};
+use spin::rwlock::RwLock;
+
+use crate::data::path::{VfsName, VfsPath};
+use crate::drivers::vfs::{
+    default_get_file_implementation, get_vfs, Arcrwb, BlockDevice, FileHandleAllocator, FileStat,
+    FileSystem, FsSpecificFileData, FsStat, PollEvent, PollEvents, SeekPosition, Vfs, VfsError,
+    VfsFile, VfsFileKind, WeakArcrwb, FLAG_SYSTEM, FLAG_VIRTUAL, POLL_ALWAYS_REPORTED,
+};
+use crate::net::{
+    ipv4::SocketAddrV4,
+    udp::{self, UdpSocket},
+    NetError,
+};
+use crate::permissions;
+use crate::process::wait::WaitQueue;
+
+#[derive(Debug, Clone)]
+pub struct NetSockFsHandle {
+    socket: Arc<RwLock<UdpSocket>>,
+}
+
+#[derive(Debug)]
+pub enum NetSockFsSpecificFileData {
+    NetSockFsRoot,
+}
+
+impl FsSpecificFileData for NetSockFsSpecificFileData {}
+
+/// Internet sockets. They are only reachable through the socket syscalls, the mount point
+/// is what gives their handles a file system in the fd table.
+#[derive(Debug)]
+pub struct NetSockFs {
+    os_id: u64,
+    parent_fs_os_id: u64,
+    mnt: Option<VfsFile>,
+    root_fs: Option<WeakArcrwb<Vfs>>,
+
+    handles: FileHandleAllocator,
+}
+
+pub const NET_SOCKFS_MOUNT: [char; 3] = ['n', 'e', 't'];
+pub const SOCKFS_MAGIC: u64 = 0x534F_434B;
+
+/// Returns the internet socket file system mounted at `/net`
+pub fn get_net_sock_fs() -> Result<Arcrwb<dyn FileSystem>, VfsError> {
+    let vfs = get_vfs();
+    let mut guard = vfs.write();
+    let file = guard.get_file(&VfsPath::from(&NET_SOCKFS_MOUNT[..]))?;
+    drop(guard);
+    file.get_mounted_fs().ok_or(VfsError::FileSystemNotMounted)
+}
+
+impl Default for NetSockFs {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl NetSockFs {
+    pub fn new() -> Self {
+        Self {
+            os_id: 0,
+            parent_fs_os_id: 0,
+            mnt: None,
+            root_fs: None,
+            handles: FileHandleAllocator::default(),
+        }
+    }
+
+    fn socket(&self, handle: u64) -> Result<Arc<RwLock<UdpSocket>>, VfsError> {
+        unsafe {
+            let handle = self
+                .handles
+                .get_handle_data::<NetSockFsHandle>(handle)
+                .ok_or(VfsError::BadHandle)?;
+            Ok((*handle).socket.clone())
+        }
+    }
+
+    pub fn create_udp_socket(&mut self, nonblocking: bool) -> u64 {
+        self.handles.alloc_file_handle(NetSockFsHandle {
+            socket: Arc::new(RwLock::new(UdpSocket::new(nonblocking))),
+        })
+    }
+
+    pub fn is_nonblocking(&self, handle: u64) -> Result<bool, VfsError> {
+        Ok(self.socket(handle)?.read().nonblocking)
+    }
+
+    pub fn bind(&mut self, handle: u64, addr: SocketAddrV4) -> Result<(), NetError> {
+        udp::bind(&self.socket(handle)?, addr)
+    }
+
+    pub fn send_to(
+        &mut self,
+        handle: u64,
+        dst: SocketAddrV4,
+        data: &[u8],
+    ) -> Result<usize, NetError> {
+        udp::send_to(&self.socket(handle)?, dst, data)
+    }
+
+    pub fn recv_from(
+        &mut self,
+        handle: u64,
+        buf: &mut [u8],
+    ) -> Result<(usize, SocketAddrV4), NetError> {
+        udp::recv_from(&self.socket(handle)?, buf)
+    }
+}
+
+impl FileSystem for NetSockFs {
+    fn os_id(&mut self) -> u64 {
+        self.os_id
+    }
+
+    fn fs_type(&mut self) -> String {
+        "netsock".to_string()
+    }
+
+    fn fs_flush(&mut self) -> Result<(), VfsError> {
+        Err(VfsError::ActionNotAllowed)
+    }
+
+    fn host_block_device(&mut self) -> Option<Arcrwb<dyn BlockDevice>> {
+        None
+    }
+
+    fn statfs(&mut self) -> Result<FsStat, VfsError> {
+        Ok(FsStat::virtual_fs(
+            SOCKFS_MAGIC,
+            self.handles.count() as u64,
+        ))
+    }
+
+    fn get_root(&mut self) -> Result<VfsFile, VfsError> {
+        Ok(VfsFile::new(
+            VfsFileKind::Directory,
+            alloc::vec!['/'],
+            0,
+            self.parent_fs_os_id,
+            self.os_id,
+            Arc::new(NetSockFsSpecificFileData::NetSockFsRoot),
+        ))
+    }
+
+    fn get_mount_point(&mut self) -> Result<Option<VfsFile>, VfsError> {
+        Ok(Some(
+            self.mnt
+                .as_ref()
+                .ok_or(VfsError::FileSystemNotMounted)?
+                .clone(),
+        ))
+    }
+
+    fn get_child(&mut self, file: &VfsFile, _child: &VfsName) -> Result<VfsFile, VfsError> {
+        if file.fs() != self.os_id {
+            return Err(VfsError::FileSystemMismatch);
+        }
+        if file.name() != ['/'] {
+            return Err(VfsError::NotDirectory);
+        }
+        Err(VfsError::PathNotFound)
+    }
+
+    fn list_children(&mut self, file: &VfsFile) -> Result<Vec<VfsFile>, VfsError> {
+        if file.fs() != self.os_id {
+            return Err(VfsError::FileSystemMismatch);
+        }
+        if file.name() != ['/'] {
+            return Err(VfsError::NotDirectory);
+        }
+        Ok(Vec::new())
+    }
+
+    default_get_file_implementation!();
+
+    fn get_stats(&mut self, file: &VfsFile) -> Result<FileStat, VfsError> {
+        if file.fs() != self.os_id {
+            return Err(VfsError::FileSystemMismatch);
+        }
+        Ok(FileStat {
+            size: 0,
+            created_at: 0,
+            modified_at: 0,
+            permissions: permissions!(Owner:Read, Group:Read, Other:Read).to_u64(),
+            is_file: false,
+            is_directory: true,
+            is_symlink: false,
+            owner_id: 0,
+            group_id: 0,
+            flags: FLAG_VIRTUAL | FLAG_SYSTEM,
+        })
+    }
+
+    fn create_child(
+        &mut self,
+        _directory: &VfsFile,
+        _name: &VfsName,
+        _kind: VfsFileKind,
+    ) -> Result<VfsFile, VfsError> {
+        Err(VfsError::ActionNotAllowed)
+    }
+
+    fn delete_file(&mut self, _file: &VfsFile) -> Result<(), VfsError> {
+        Err(VfsError::ActionNotAllowed)
+    }
+
+    fn on_mount(
+        &mut self,
+        mount_point: &VfsFile,
+        os_id: u64,
+        root_fs: WeakArcrwb<Vfs>,
+    ) -> Result<VfsFile, VfsError> {
+        self.root_fs = Some(root_fs);
+        self.parent_fs_os_id = mount_point.fs();
+        self.mnt = Some(mount_point.clone());
+        self.os_id = os_id;
+        self.handles.set_owner(os_id);
+        self.get_root()
+    }
+
+    fn on_pre_unmount(&mut self) -> Result<bool, VfsError> {
+        Ok(true)
+    }
+
+    fn on_unmount(&mut self) -> Result<(), VfsError> {
+        self.mnt = None;
+        self.os_id = 0;
+        self.parent_fs_os_id = 0;
+        for h in self.handles.iter().copied().collect::<Vec<u64>>() {
+            self.fclose(h)?;
+        }
+        Ok(())
+    }
+
+    fn get_vfs(&mut self) -> Result<WeakArcrwb<Vfs>, VfsError> {
+        Ok(self
+            .root_fs
+            .as_ref()
+            .ok_or(VfsError::FileSystemNotMounted)?
+            .clone())
+    }
+
+    fn fopen(&mut self, _file: &VfsFile, _mode: u64) -> Result<u64, VfsError> {
+        Err(VfsError::ActionNotAllowed)
+    }
+
+    fn fclose(&mut self, handle: u64) -> Result<(), VfsError> {
+        udp::close(&self.socket(handle)?);
+        if self.handles.dealloc_file_handle::<NetSockFsHandle>(handle) {
+            Ok(())
+        } else {
+            Err(VfsError::BadHandle)
+        }
+    }
+
+    fn fseek(&mut self, _handle: u64, _position: SeekPosition) -> Result<u64, VfsError> {
+        Err(VfsError::InvalidSeekPosition)
+    }
+
+    /// Reads the next datagram, the sender is lost
+    fn fread(&mut self, handle: u64, buf: &mut [u8]) -> Result<u64, VfsError> {
+        match self.recv_from(handle, buf) {
+            Ok((read, _)) => Ok(read as u64),
+            Err(NetError::WouldBlock(_)) => Err(VfsError::WouldBlock),
+            Err(NetError::Vfs(e)) => Err(e),
+            Err(_) => Err(VfsError::UnknownError),
+        }
+    }
+
+    /// There is no connected peer to send to
+    fn fwrite(&mut self, handle: u64, _buf: &[u8]) -> Result<u64, VfsError> {
+        self.socket(handle)?;
+        Err(VfsError::ActionNotAllowed)
+    }
+
+    fn fflush(&mut self, handle: u64) -> Result<(), VfsError> {
+        self.socket(handle)?;
+        Ok(())
+    }
+
+    fn fsync(&mut self, handle: u64) -> Result<(), VfsError> {
+        self.socket(handle)?;
+        Ok(())
+    }
+
+    fn fstat(&self, handle: u64) -> Result<FileStat, VfsError> {
+        let socket = self.socket(handle)?;
+        let size = socket.read().pending_size() as u64;
+        Ok(FileStat {
+            size,
+            created_at: 0,
+            modified_at: 0,
+            permissions: permissions!(Owner:Read, Owner:Write).to_u64(),
+            is_file: true,
+            is_directory: false,
+            is_symlink: false,
+            owner_id: 0,
+            group_id: 0,
+            flags: FLAG_VIRTUAL | FLAG_SYSTEM,
+        })
+    }
+
+    fn ftruncate(&mut self, _handle: u64) -> Result<u64, VfsError> {
+        Err(VfsError::ActionNotAllowed)
+    }
+
+    fn fpoll(&mut self, handle: u64, events: PollEvents) -> Result<PollEvents, VfsError> {
+        let socket = self.socket(handle)?;
+        // Datagrams are sent right away, writing never waits
+        let mut ready: PollEvents = PollEvent::Out.into();
+        if socket.read().has_pending() {
+            ready.set(PollEvent::In);
+        }
+        Ok(ready & (events | POLL_ALWAYS_REPORTED))
+    }
+
+    fn fwait_queue(&mut self, handle: u64) -> Result<Option<Arc<WaitQueue>>, VfsError> {
+        Ok(Some(self.socket(handle)?.read().wait_queue.clone()))
+    }
+}
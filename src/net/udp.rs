@@ -0,0 +1,254 @@
+use alloc::{collections::BTreeMap, collections::VecDeque, sync::Arc, vec::Vec};
+use spin::{rwlock::RwLock, Mutex};
+
+use crate::{
+    drivers::vfs::Arcrwb,
+    net::{
+        checksum::{finish_checksum, ones_complement_sum},
+        interface::NetInterface,
+        ipv4::{self, is_local_address, route, Ipv4Addr, SocketAddrV4, IP_PROTOCOL_UDP},
+        NetError,
+    },
+    process::wait::WaitQueue,
+};
+
+pub const UDP_HEADER_SIZE: usize = 8;
+/// Bytes of pending datagrams a socket holds before dropping new ones
+pub const UDP_RECEIVE_BUFFER_SIZE: usize = 64 * 1024;
+pub const UDP_EPHEMERAL_PORT_FIRST: u16 = 49152;
+pub const UDP_EPHEMERAL_PORT_LAST: u16 = 65535;
+
+#[derive(Debug, Clone)]
+pub struct UdpDatagram {
+    pub src: SocketAddrV4,
+    pub data: Vec<u8>,
+}
+
+#[derive(Debug)]
+pub struct UdpSocket {
+    pub local: Option<SocketAddrV4>,
+    pub nonblocking: bool,
+    /// Woken when a datagram is received
+    pub wait_queue: Arc<WaitQueue>,
+    rx: VecDeque<UdpDatagram>,
+    rx_bytes: usize,
+}
+
+impl UdpSocket {
+    pub fn new(nonblocking: bool) -> Self {
+        Self {
+            local: None,
+            nonblocking,
+            wait_queue: Arc::new(WaitQueue::new()),
+            rx: VecDeque::new(),
+            rx_bytes: 0,
+        }
+    }
+
+    pub fn has_pending(&self) -> bool {
+        !self.rx.is_empty()
+    }
+
+    /// Size of the next datagram
+    pub fn pending_size(&self) -> usize {
+        self.rx.front().map(|d| d.data.len()).unwrap_or(0)
+    }
+}
+
+#[derive(Debug)]
+struct UdpPorts {
+    bound: BTreeMap<u16, Arc<RwLock<UdpSocket>>>,
+    next_ephemeral: u16,
+}
+
+static UDP_PORTS: Mutex<UdpPorts> = Mutex::new(UdpPorts {
+    bound: BTreeMap::new(),
+    next_ephemeral: UDP_EPHEMERAL_PORT_FIRST,
+});
+
+impl UdpPorts {
+    fn alloc_ephemeral(&mut self) -> Option<u16> {
+        let count = (UDP_EPHEMERAL_PORT_LAST - UDP_EPHEMERAL_PORT_FIRST) as usize + 1;
+        for _ in 0..count {
+            let port = self.next_ephemeral;
+            self.next_ephemeral = if port == UDP_EPHEMERAL_PORT_LAST {
+                UDP_EPHEMERAL_PORT_FIRST
+            } else {
+                port + 1
+            };
+            if !self.bound.contains_key(&port) {
+                return Some(port);
+            }
+        }
+        None
+    }
+}
+
+/// Binds the socket to `addr`, port 0 picks an ephemeral port
+pub fn bind(socket: &Arc<RwLock<UdpSocket>>, addr: SocketAddrV4) -> Result<(), NetError> {
+    let mut guard = socket.write();
+    if guard.local.is_some() {
+        return Err(NetError::InvalidArgument);
+    }
+    if !addr.addr.is_unspecified() && !is_local_address(addr.addr) {
+        return Err(NetError::AddressNotAvailable);
+    }
+
+    let mut ports = UDP_PORTS.lock();
+    let port = match addr.port {
+        0 => ports.alloc_ephemeral().ok_or(NetError::AddressInUse)?,
+        port if ports.bound.contains_key(&port) => return Err(NetError::AddressInUse),
+        port => port,
+    };
+    ports.bound.insert(port, socket.clone());
+    guard.local = Some(SocketAddrV4::new(addr.addr, port));
+    Ok(())
+}
+
+/// Releases the port of a closed socket
+pub fn close(socket: &Arc<RwLock<UdpSocket>>) {
+    let mut guard = socket.write();
+    if let Some(local) = guard.local.take() {
+        UDP_PORTS.lock().bound.remove(&local.port);
+    }
+    guard.rx.clear();
+    guard.rx_bytes = 0;
+    let wait_queue = guard.wait_queue.clone();
+    drop(guard);
+    wait_queue.wake_all();
+}
+
+fn udp_checksum(src: Ipv4Addr, dst: Ipv4Addr, segment: &[u8]) -> u16 {
+    let mut pseudo_header = [0u8; 12];
+    pseudo_header[0..4].copy_from_slice(&src.0);
+    pseudo_header[4..8].copy_from_slice(&dst.0);
+    pseudo_header[9] = IP_PROTOCOL_UDP;
+    pseudo_header[10..12].copy_from_slice(&(segment.len() as u16).to_be_bytes());
+    finish_checksum(ones_complement_sum(
+        ones_complement_sum(0, &pseudo_header),
+        segment,
+    ))
+}
+
+/// Sends a datagram, unbound sockets are bound to an ephemeral port first
+pub fn send_to(
+    socket: &Arc<RwLock<UdpSocket>>,
+    dst: SocketAddrV4,
+    data: &[u8],
+) -> Result<usize, NetError> {
+    if dst.port == 0 {
+        return Err(NetError::InvalidArgument);
+    }
+    if socket.read().local.is_none() {
+        bind(socket, SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0))?;
+    }
+    let Some(local) = socket.read().local else {
+        return Err(NetError::InvalidArgument);
+    };
+    let src = if local.addr.is_unspecified() {
+        route(dst.addr).ok_or(NetError::NetworkUnreachable)?.src
+    } else {
+        local.addr
+    };
+
+    let segment = build_segment(local.port, src, dst, data)?;
+    ipv4::output(Some(src), dst.addr, IP_PROTOCOL_UDP, &segment)?;
+    Ok(data.len())
+}
+
+/// Sends a datagram from `src` through `iface` without looking up a route,
+/// the socket must already be bound
+pub fn send_on(
+    socket: &Arc<RwLock<UdpSocket>>,
+    iface: &Arcrwb<dyn NetInterface>,
+    src: Ipv4Addr,
+    dst: SocketAddrV4,
+    next_hop: Ipv4Addr,
+    data: &[u8],
+) -> Result<usize, NetError> {
+    let Some(local) = socket.read().local else {
+        return Err(NetError::InvalidArgument);
+    };
+    let segment = build_segment(local.port, src, dst, data)?;
+    ipv4::output_on(iface, src, dst.addr, next_hop, IP_PROTOCOL_UDP, &segment)?;
+    Ok(data.len())
+}
+
+fn build_segment(
+    src_port: u16,
+    src: Ipv4Addr,
+    dst: SocketAddrV4,
+    data: &[u8],
+) -> Result<Vec<u8>, NetError> {
+    let len = UDP_HEADER_SIZE + data.len();
+    if len > u16::MAX as usize {
+        return Err(NetError::MessageTooLong);
+    }
+    let mut segment = Vec::with_capacity(len);
+    segment.extend_from_slice(&src_port.to_be_bytes());
+    segment.extend_from_slice(&dst.port.to_be_bytes());
+    segment.extend_from_slice(&(len as u16).to_be_bytes());
+    segment.extend_from_slice(&[0, 0]);
+    segment.extend_from_slice(data);
+    // 0 means no checksum, it is sent as all ones instead
+    let checksum = match udp_checksum(src, dst.addr, &segment) {
+        0 => 0xFFFF,
+        c => c,
+    };
+    segment[6..8].copy_from_slice(&checksum.to_be_bytes());
+    Ok(segment)
+}
+
+/// Takes the next datagram, the part that doesn't fit in `buf` is discarded
+pub fn recv_from(
+    socket: &Arc<RwLock<UdpSocket>>,
+    buf: &mut [u8],
+) -> Result<(usize, SocketAddrV4), NetError> {
+    let mut guard = socket.write();
+    let Some(datagram) = guard.rx.pop_front() else {
+        return Err(NetError::WouldBlock(guard.wait_queue.clone()));
+    };
+    guard.rx_bytes -= datagram.data.len();
+    let len = datagram.data.len().min(buf.len());
+    buf[..len].copy_from_slice(&datagram.data[..len]);
+    Ok((len, datagram.src))
+}
+
+/// Delivers a received segment to the socket bound to its port
+pub fn input(src: Ipv4Addr, dst: Ipv4Addr, segment: &[u8]) {
+    if segment.len() < UDP_HEADER_SIZE {
+        return;
+    }
+    let src_port = u16::from_be_bytes([segment[0], segment[1]]);
+    let dst_port = u16::from_be_bytes([segment[2], segment[3]]);
+    let len = u16::from_be_bytes([segment[4], segment[5]]) as usize;
+    if len < UDP_HEADER_SIZE || len > segment.len() {
+        return;
+    }
+    let segment = &segment[..len];
+    let checksum = u16::from_be_bytes([segment[6], segment[7]]);
+    if checksum != 0 && udp_checksum(src, dst, segment) != 0 {
+        return;
+    }
+
+    let Some(socket) = UDP_PORTS.lock().bound.get(&dst_port).cloned() else {
+        return;
+    };
+    let mut guard = socket.write();
+    match guard.local {
+        Some(local) if local.addr.is_unspecified() || local.addr == dst => {}
+        _ => return,
+    }
+    let data = &segment[UDP_HEADER_SIZE..];
+    if guard.rx_bytes + data.len() > UDP_RECEIVE_BUFFER_SIZE {
+        return;
+    }
+    guard.rx_bytes += data.len();
+    guard.rx.push_back(UdpDatagram {
+        src: SocketAddrV4::new(src, src_port),
+        data: data.to_vec(),
+    });
+    let wait_queue = guard.wait_queue.clone();
+    drop(guard);
+    wait_queue.wake_all();
+}
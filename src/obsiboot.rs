@@ -1,15 +1,21 @@
+use alloc::{string::String, vec::Vec};
+
+use crate::paging::physical_to_virtual;
+
 /// # ObsiBoot Kernel Parameters
 /// Contains information about the bootloader and the system
-/// Documentation for ObsiBoot struct version 1.
+/// Documentation for ObsiBoot struct version 2, version 1 ends before `initramfs_ptr`. <br>
+/// Fields are read through accessors, which return the defaults of the fields a version 1
+/// bootloader didn't provide.
 #[repr(C, packed)]
 #[derive(Debug)]
 pub struct ObsiBootKernelParameters {
     /// The size of this structure in bytes <br>
-    pub obsiboot_struct_size: u32,
+    obsiboot_struct_size: u32,
     /// The version of this structure <br>
-    pub obsiboot_struct_version: u32,
+    obsiboot_struct_version: u32,
     /// A checksum of this structure <br>
-    pub obsiboot_struct_checksum: [u32; 8],
+    obsiboot_struct_checksum: [u32; 8],
 
     /*
      *
@@ -19,24 +25,24 @@ pub struct ObsiBootKernelParameters {
     /// A pointer to a null terminated string containing the name of the bootloader <br>
     /// Note: This is a physical address <br>
     /// Note: Bootloaders may set this value either to a null pointer or to a pointer to a valid null terminated ASCII only string <br>
-    pub bootloader_name_ptr: u32,
+    bootloader_name_ptr: u32,
 
     /// The bootloader version, as [major, minor, patch, build] <br>
-    pub bootloader_version: [u8; 4],
+    bootloader_version: [u8; 4],
 
     /// The BIOS drive number of the boot drive <br>
-    pub bios_boot_drive: u32,
+    bios_boot_drive: u32,
     /// The BIOS Interrupt Descriptor Table pointer <br>
-    pub bios_idt_ptr: u32,
+    bios_idt_ptr: u32,
 
     /// A pointer to a sanitized memory layout given by the BIOS <br>
     /// Note: This is a physical address <br>
     /// Note: Any region that is marked as usable is fully usable by the kernel except for the one containing the address `usbale_kernel_memory_start`. See `usbale_kernel_memory_start` for more information. <br>
-    pub ptr_to_memory_layout: u32,
+    ptr_to_memory_layout: u32,
     /// The number of entries in the memory layout <br>
-    pub memory_layout_entry_count: u32,
+    memory_layout_entry_count: u32,
     /// The size of each memory layout entry in bytes (see `paging::OsMemoryRegion`) <br>
-    pub memory_layout_entry_size: u32,
+    memory_layout_entry_size: u32,
 
     /// The current address of the arena allocator for page tables <br>
     /// Note: This is a physical address <br>
@@ -44,37 +50,124 @@ pub struct ObsiBootKernelParameters {
     /// 1. Do not setup paging in the event of loading a 32-bit kernel (paging is mandatory for 64-bit kernels)
     /// 2. Do not use an arena allocator for allocating page tables
     /// 3. Decide to not set the value at all
-    pub page_tables_page_allocator_current_free_page: u32,
+    page_tables_page_allocator_current_free_page: u32,
     /// The address of the last page of the arena allocator for page tables <br>
     /// Note: This is a physical address <br>
     /// Note: Bootloaders may not set this value. See `page_tables_page_allocator_current_free_page` for more information. <br>
-    pub page_tables_page_allocator_last_usable_page: u32,
+    page_tables_page_allocator_last_usable_page: u32,
     /// The base address of PML4 <br>
-    pub pml4_base_address: u32,
+    pml4_base_address: u32,
 
     /// The address of the first kernel usable memory. <br>
     /// Note: This is a physical address that may not be aligned to anything <br>
     /// Note: The bootloader guarantees that the kernel can use any memory between `usable_kernel_memory_start` and the end of the memory region containing it <br>
-    pub usable_kernel_memory_start: u32,
+    usable_kernel_memory_start: u32,
 
     /// The address of the VBE info block gathered from the BIOS <br>
     /// Note: This is a physical address <br>
-    pub vbe_info_block_ptr: u32,
+    vbe_info_block_ptr: u32,
     /// A pointer to a list of [`VesaModeInfoStructure`]s gathered from the BIOS <br>
     /// Note: This is a physical address <br>
-    pub vbe_modes_info_ptr: u32,
+    vbe_modes_info_ptr: u32,
     /// The number of entries in the [`VesaModeInfoStructure`]s list <br>
     /// Note: Each entry is 256 bytes <br>
-    pub vbe_mode_info_block_entry_count: u32,
+    vbe_mode_info_block_entry_count: u32,
     /// The selected VESA mode <br>
-    pub vbe_selected_mode: u32,
+    vbe_selected_mode: u32,
 
     /// The initial stack pointer used to load the kernel
-    pub kernel_stack_pointer: u64,
+    kernel_stack_pointer: u64,
+
+    /*
+     *
+     *                  BEGIN OBSIBOOT VERSION 2 FIELDS
+     *
+     * */
+    /// A pointer to a CPIO archive (newc format) unpacked as the initramfs, 0 if there is none <br>
+    /// Note: This is a physical address <br>
+    /// Note: The bootloader must keep the archive out of the usable regions of the memory layout <br>
+    initramfs_ptr: u32,
+    /// The size of the initramfs archive in bytes <br>
+    initramfs_size: u32,
+
+    /// A pointer to the ACPI RSDP found by the bootloader, 0 if it didn't look for it <br>
+    /// Note: This is a physical address <br>
+    acpi_rsdp_ptr: u64,
+
+    /// A pointer to the null terminated ASCII command line of the kernel, 0 if there is none <br>
+    /// Note: This is a physical address <br>
+    command_line_ptr: u32,
+
+    /// The linear framebuffer set up by the bootloader, 0 if there is none. Used when there is no
+    /// VBE mode info, like on UEFI <br>
+    /// Note: This is a physical address <br>
+    framebuffer_ptr: u64,
+    /// In pixels <br>
+    framebuffer_width: u32,
+    /// In pixels <br>
+    framebuffer_height: u32,
+    /// Bytes per line <br>
+    framebuffer_pitch: u32,
+    /// Bits per pixel <br>
+    framebuffer_bpp: u32,
+}
+
+/// Longest bootloader name or command line read
+pub const MAX_BOOT_STRING_LEN: usize = 4096;
+
+/// Reads a null terminated UTF-8 string given by the bootloader, through the direct mapping
+///
+/// # Safety
+/// Paging must be initialized and `phys` must point to a string in mapped memory
+pub unsafe fn read_boot_string(phys: u64) -> String {
+    let start = physical_to_virtual(phys) as *const u8;
+    let mut bytes = Vec::new();
+    for i in 0..MAX_BOOT_STRING_LEN {
+        match core::ptr::read_volatile(start.add(i)) {
+            0 => break,
+            byte => bytes.push(byte),
+        }
+    }
+    String::from_utf8_lossy(&bytes).into_owned()
+}
+
+/// Size of the version 1 structure, without the version 2 fields
+pub const OBSIBOOT_V1_SIZE: usize = core::mem::offset_of!(ObsiBootKernelParameters, initramfs_ptr);
+pub const OBSIBOOT_V2_SIZE: usize = size_of::<ObsiBootKernelParameters>();
+
+const CHECKSUM_OFFSET: usize =
+    core::mem::offset_of!(ObsiBootKernelParameters, obsiboot_struct_checksum);
+const CHECKSUM_SIZE: usize = size_of::<[u32; 8]>();
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ObsiBootError {
+    UnsupportedVersion(u32),
+    /// `obsiboot_struct_size` is smaller than the layout of the version
+    Truncated {
+        version: u32,
+        size: u32,
+        expected: usize,
+    },
+    BadChecksum {
+        version: u32,
+        expected: [u32; 8],
+        computed: [u32; 8],
+    },
+}
+
+/// Framebuffer given by a version 2 bootloader
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ObsiBootFramebuffer {
+    /// Physical address
+    pub address: u64,
+    pub width: u32,
+    pub height: u32,
+    pub pitch: u32,
+    pub bpp: u32,
 }
 
 impl ObsiBootKernelParameters {
-    /// Computes the checksum, without modifying the structure. Does not set the checksum field.
+    /// Computes the checksum of the raw structure, the checksum field counting as zeros.
     /// ### Uses a custom checksum algorithm:
     /// 1. Start with 8 unsigned 32-bit zeros
     /// 2. For each byte in the structure, update the checksum using a custom update function.
@@ -83,10 +176,7 @@ impl ObsiBootKernelParameters {
     /// 2. Shift the checksum array: \[1..=7] -> \[0..=6]
     /// 3. result[7] = previously computed xor (step 1.)
     /// 4. result[7] += unsigned multiplication of the byte by 0x01100111 (no specific reason for that number except from spreading the byte to 32-bits)
-    pub fn calculate_checksum(&mut self) -> [u32; 8] {
-        let prev = self.obsiboot_struct_checksum;
-        self.obsiboot_struct_checksum = [0u32; 8];
-
+    pub fn calculate_checksum(bytes: &[u8]) -> [u32; 8] {
         let mut result = [0u32; 8];
         fn update(result: &mut [u32; 8], byte: u8) {
             let result0 = result[0];
@@ -97,20 +187,192 @@ impl ObsiBootKernelParameters {
             }
             result[7] = xored.wrapping_add((byte as u32).wrapping_mul(0x01100111));
         }
+        let checksum_field = CHECKSUM_OFFSET..CHECKSUM_OFFSET + CHECKSUM_SIZE;
+        for (i, byte) in bytes.iter().enumerate() {
+            update(
+                &mut result,
+                if checksum_field.contains(&i) {
+                    0
+                } else {
+                    *byte
+                },
+            );
+        }
+        result
+    }
+
+    /// Checks and copies the structure of a version 1 or 2 bootloader. The checksum covers
+    /// `obsiboot_struct_size` bytes, the fields of a version 1 structure are followed by zeros
+    pub fn parse(bytes: &[u8]) -> Result<Self, ObsiBootError> {
+        let read_u32 = |offset: usize| {
+            bytes
+                .get(offset..offset + 4)
+                .map(|b| u32::from_le_bytes(b.try_into().unwrap()))
+        };
+        let truncated = |version, size| ObsiBootError::Truncated {
+            version,
+            size,
+            expected: OBSIBOOT_V1_SIZE,
+        };
+        let (Some(size), Some(version)) = (read_u32(0), read_u32(4)) else {
+            return Err(truncated(0, bytes.len() as u32));
+        };
+        let expected = match version {
+            1 => OBSIBOOT_V1_SIZE,
+            2 => OBSIBOOT_V2_SIZE,
+            _ => return Err(ObsiBootError::UnsupportedVersion(version)),
+        };
+        if (size as usize) < expected || bytes.len() < size as usize {
+            return Err(ObsiBootError::Truncated {
+                version,
+                size,
+                expected,
+            });
+        }
+
+        let bytes = &bytes[..size as usize];
+        let computed = Self::calculate_checksum(bytes);
+        let mut expected_checksum = [0u32; 8];
+        for (i, word) in expected_checksum.iter_mut().enumerate() {
+            *word = read_u32(CHECKSUM_OFFSET + i * 4).unwrap();
+        }
+        if computed != expected_checksum {
+            return Err(ObsiBootError::BadChecksum {
+                version,
+                expected: expected_checksum,
+                computed,
+            });
+        }
+
+        // Every field is an integer, zeros are the defaults of the missing ones
+        let mut parameters = unsafe { core::mem::zeroed::<Self>() };
         unsafe {
-            let selfptr = self as *const Self as *const u8;
-            for i in 0..self.obsiboot_struct_size {
-                update(&mut result, *selfptr.add(i as usize))
-            }
+            core::ptr::copy_nonoverlapping(
+                bytes.as_ptr(),
+                &mut parameters as *mut Self as *mut u8,
+                expected,
+            );
         }
+        Ok(parameters)
+    }
 
-        self.obsiboot_struct_checksum = prev;
-        result
+    /// Parses the structure the bootloader left at `address`
+    ///
+    /// # Safety
+    /// `address` must be mapped, with `obsiboot_struct_size` readable bytes
+    pub unsafe fn read(address: u64) -> Result<Self, ObsiBootError> {
+        let size = core::ptr::read_unaligned(address as *const u32) as usize;
+        Self::parse(core::slice::from_raw_parts(
+            address as *const u8,
+            size.max(8),
+        ))
+    }
+
+    pub fn version(&self) -> u32 {
+        self.obsiboot_struct_version
+    }
+
+    /// Bytes covered by the checksum, at least the size of the version
+    pub fn struct_size(&self) -> u32 {
+        self.obsiboot_struct_size
+    }
+
+    /// Physical address of the null terminated bootloader name
+    pub fn bootloader_name(&self) -> Option<u64> {
+        let ptr = self.bootloader_name_ptr;
+        (ptr != 0).then_some(ptr as u64)
+    }
+
+    /// As \[major, minor, patch, build]
+    pub fn bootloader_version(&self) -> [u8; 4] {
+        self.bootloader_version
+    }
+
+    pub fn bios_boot_drive(&self) -> u32 {
+        self.bios_boot_drive
+    }
+
+    pub fn bios_idt_ptr(&self) -> u32 {
+        self.bios_idt_ptr
+    }
+
+    /// Physical address of the memory layout and its number of entries
+    pub fn memory_layout(&self) -> (u64, u64) {
+        (
+            self.ptr_to_memory_layout as u64,
+            self.memory_layout_entry_count as u64,
+        )
+    }
+
+    pub fn memory_layout_entry_size(&self) -> u32 {
+        self.memory_layout_entry_size
+    }
+
+    pub fn pml4_base_address(&self) -> u64 {
+        self.pml4_base_address as u64
+    }
+
+    /// Current and last page of the page table arena of the bootloader
+    pub fn page_table_arena(&self) -> (u64, u64) {
+        (
+            self.page_tables_page_allocator_current_free_page as u64,
+            self.page_tables_page_allocator_last_usable_page as u64,
+        )
+    }
+
+    pub fn usable_kernel_memory_start(&self) -> u64 {
+        self.usable_kernel_memory_start as u64
+    }
+
+    pub fn kernel_stack_pointer(&self) -> u64 {
+        self.kernel_stack_pointer
+    }
+
+    /// Physical address of the VBE info block, `None` if the bootloader didn't gather it
+    pub fn vbe_info_block(&self) -> Option<u64> {
+        let ptr = self.vbe_info_block_ptr;
+        (ptr != 0).then_some(ptr as u64)
+    }
+
+    /// Physical address of the mode info list and its number of entries
+    pub fn vbe_modes_info(&self) -> (u64, usize) {
+        (
+            self.vbe_modes_info_ptr as u64,
+            self.vbe_mode_info_block_entry_count as usize,
+        )
+    }
+
+    pub fn vbe_selected_mode(&self) -> u32 {
+        self.vbe_selected_mode
+    }
+
+    /// Physical address and size of the initramfs archive, `None` if the bootloader didn't load one
+    pub fn initramfs(&self) -> Option<(u64, u64)> {
+        let (ptr, size) = (self.initramfs_ptr, self.initramfs_size);
+        (ptr != 0 && size != 0).then_some((ptr as u64, size as u64))
+    }
+
+    /// Physical address of the ACPI RSDP, `None` if it has to be searched for
+    pub fn acpi_rsdp(&self) -> Option<u64> {
+        let ptr = self.acpi_rsdp_ptr;
+        (ptr != 0).then_some(ptr)
+    }
+
+    /// Physical address of the null terminated command line
+    pub fn command_line(&self) -> Option<u64> {
+        let ptr = self.command_line_ptr;
+        (ptr != 0).then_some(ptr as u64)
     }
 
-    pub fn verify_checksum(&mut self) -> bool {
-        let checksum = self.calculate_checksum();
-        let expected = self.obsiboot_struct_checksum;
-        checksum == expected
+    pub fn framebuffer(&self) -> Option<ObsiBootFramebuffer> {
+        let framebuffer = ObsiBootFramebuffer {
+            address: self.framebuffer_ptr,
+            width: self.framebuffer_width,
+            height: self.framebuffer_height,
+            pitch: self.framebuffer_pitch,
+            bpp: self.framebuffer_bpp,
+        };
+        (framebuffer.address != 0 && framebuffer.width != 0 && framebuffer.height != 0)
+            .then_some(framebuffer)
     }
 }
@@ -1,12 +1,15 @@
 use core::panic;
 use core::{alloc::Layout, arch::asm};
 
-use alloc::alloc::{alloc, dealloc};
+use alloc::{
+    alloc::{alloc, dealloc},
+    boxed::Box,
+};
 use spin::mutex::Mutex;
 
 use crate::data::assign_once::AssignOnce;
 use crate::data::regs::cr::Cr3;
-use crate::{memory::mem::OsMemoryRegion, println};
+use crate::{log_warn, memory::mem::OsMemoryRegion};
 
 #[repr(C, align(4096))]
 pub struct FreePage {
@@ -56,7 +59,7 @@ impl PageAllocator for SimplePageAllocator {
 
     fn free_page(&mut self, page: *mut u8) {
         if (page as usize) & 0xFFF != 0 {
-            println!("Trying to free unaligned page !");
+            log_warn!(target: "paging", "Trying to free unaligned page !");
             return;
         }
 
@@ -78,16 +81,15 @@ static mut ALLOCATOR: SimplePageAllocator = SimplePageAllocator {
     free_head: core::ptr::null_mut(),
 };
 
-#[allow(static_mut_refs)]
-static mut KERNEL_PAGE_TABLE: Mutex<PageTable> = Mutex::new(PageTable::new_with_alloc(0, unsafe {
-    (&mut ALLOCATOR) as *mut dyn PageAllocator
-}));
+static KERNEL_PAGE_TABLE: Mutex<PageTable> = Mutex::new(PageTable::new_with_alloc(
+    0,
+    &raw mut ALLOCATOR as *mut dyn PageAllocator,
+));
 
 static KERNEL_STACK_POINTER: AssignOnce<u64> = AssignOnce::new();
 
-#[allow(static_mut_refs)]
 pub fn get_kernel_page_table() -> &'static Mutex<PageTable> {
-    unsafe { &KERNEL_PAGE_TABLE }
+    &KERNEL_PAGE_TABLE
 }
 
 pub const DIRECT_MAPPING_OFFSET: u64 = 0xFFFF_A000_0000_0000;
@@ -224,7 +226,7 @@ pub unsafe fn init_paging(
     alloc.load();
 
     KERNEL_STACK_POINTER.set(kernel_stack_pointer);
-    KERNEL_PAGE_TABLE = Mutex::new(alloc);
+    *KERNEL_PAGE_TABLE.lock() = alloc;
 }
 
 #[repr(transparent)]
@@ -423,6 +425,17 @@ impl PageTable {
         }
     }
 
+    /// Maps nothing and frees nothing, left in place of a page table that was freed
+    pub const fn empty() -> Self {
+        Self {
+            allocator: core::ptr::null_mut::<()>() as *mut dyn PageAllocator,
+            owns_allocator: false,
+            pml4_phys: 0,
+            allocator_owns_pml4: false,
+            readonly: true,
+        }
+    }
+
     pub fn temporary_this() -> Self {
         Self {
             allocator: core::ptr::null_mut::<()>() as *mut dyn PageAllocator,
@@ -435,15 +448,11 @@ impl PageTable {
 
     pub fn alloc_new() -> Option<Self> {
         unsafe {
-            let allocator =
-                alloc(Layout::new::<KernelPageTablesAllocator>()) as *mut KernelPageTablesAllocator;
-            *allocator = KernelPageTablesAllocator;
+            // Zero sized, the box allocates nothing
+            let allocator = Box::into_raw(Box::new(KernelPageTablesAllocator));
 
             let Some(pml4) = (*allocator).alloc_page() else {
-                dealloc(
-                    allocator as u64 as *mut u8,
-                    Layout::new::<KernelPageTablesAllocator>(),
-                );
+                drop(Box::from_raw(allocator));
                 return None;
             };
 
@@ -726,7 +735,8 @@ impl PageTable {
         }
     }
 
-    pub fn translate(&mut self, virt: u64) -> Option<u64> {
+    /// Present entry mapping `virt`, with the size of its page
+    fn leaf_entry(&mut self, virt: u64) -> Option<(u64, u64)> {
         unsafe {
             let (pml4_idx, pdpt_idx, pd_idx, pt_idx) = split_virt_addr(virt);
 
@@ -738,19 +748,30 @@ impl PageTable {
 
             let pd_entry = *pd.get_entry(pd_idx);
             if (pd_entry & PAGE_PRESENT) == PAGE_PRESENT && (pd_entry & PAGE_HUGE) == PAGE_HUGE {
-                return Some((pd_entry & 0x000F_FFFF_FFFF_F000) + (virt % PAGE_SIZE_2MB as u64));
+                return Some((pd_entry, PAGE_SIZE_2MB as u64));
             }
 
             let pt = pd.get_table::<false>(pd_idx, allocator, 0, PAGE_HUGE)?;
             let pt_entry = *pt.get_entry(pt_idx);
             if (pt_entry & PAGE_PRESENT) == PAGE_PRESENT {
-                return Some((pt_entry & 0x000F_FFFF_FFFF_F000) + (virt % PAGE_SIZE as u64));
+                return Some((pt_entry, PAGE_SIZE as u64));
             }
 
             None
         }
     }
 
+    pub fn translate(&mut self, virt: u64) -> Option<u64> {
+        let (entry, page_size) = self.leaf_entry(virt)?;
+        Some((entry & 0x000F_FFFF_FFFF_F000) + (virt % page_size))
+    }
+
+    /// Flags of the page mapping `virt` (`PAGE_RW`, `PAGE_USER`...)
+    pub fn page_flags(&mut self, virt: u64) -> Option<u64> {
+        let (entry, _) = self.leaf_entry(virt)?;
+        Some(entry & !0x000F_FFFF_FFFF_F000)
+    }
+
     /// # Safety
     /// This function is unsafe because it modifies the CR3 register <br>
     /// Caller must make sure code is running in ring 0 and that the return address is mapped <br>
@@ -790,14 +811,11 @@ impl Drop for PageTable {
             if self.allocator_owns_pml4 {
                 (*self.allocator).free_page((self.pml4_phys + DIRECT_MAPPING_OFFSET) as *mut u8);
             }
-            // prevent double free
-            self.owns_allocator = false;
 
             if self.owns_allocator {
-                dealloc(
-                    self.allocator as *mut KernelPageTablesAllocator as *mut u8,
-                    Layout::new::<KernelPageTablesAllocator>(),
-                );
+                drop(Box::from_raw(
+                    self.allocator as *mut KernelPageTablesAllocator,
+                ));
             }
             // prevent double free
             self.owns_allocator = false;
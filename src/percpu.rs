@@ -1,13 +1,21 @@
-use core::{fmt::Debug, mem::offset_of};
+use core::{
+    fmt::Debug,
+    mem::offset_of,
+    sync::atomic::{AtomicBool, Ordering},
+};
 
-use alloc::{boxed::Box, vec::Vec};
+use alloc::{boxed::Box, sync::Weak, vec::Vec};
 
 use crate::{
     data::{
         calloc_boxed_slice,
+        lock::HeldLocks,
         regs::fs_gs_base::{GsBase, KernelGsBase},
     },
-    process::scheduler::ProcThreadInfo,
+    interrupts::handlers::irq::irq0_timer::get_uptime_ticks,
+    log::{LogLine, LOG_LINE_LEVELS},
+    paging::PAGE_SIZE,
+    process::{proc::Thread, scheduler::ProcThreadInfo},
 };
 
 #[derive(Default, Debug, Clone)]
@@ -74,6 +82,20 @@ pub struct PerCpu {
     pub syscall_data: SyscallData,
     pub kernel_rsp: u64,
     pub free_allocated_buffers: Vec<Box<[u8]>>,
+    /// Uptime tick at which the running thread, or the idle loop, got the CPU
+    pub running_since: u64,
+    /// Ticks of the idle thread, the time `schedule` had nothing to run
+    pub idle_ticks: u64,
+    /// Switches to another thread or to the idle thread
+    pub context_switches: u64,
+    /// Stack the idle thread of this CPU runs on
+    pub idle_stack: Option<Box<[u8]>>,
+    /// Locks taken by this CPU and not released yet, debug builds only
+    pub held_locks: HeldLocks,
+    /// Lines being printed, one per interrupt nesting level
+    pub log_lines: Option<Box<[LogLine]>>,
+    /// Thread whose FPU registers are loaded
+    pub fpu_owner: Option<Weak<Thread>>,
 }
 
 impl Debug for PerCpu {
@@ -85,6 +107,10 @@ impl Debug for PerCpu {
             .field("running_thread", &self.running_thread)
             .field("syscall_data", &self.syscall_data)
             .field("kernel_rsp", &self.kernel_rsp)
+            .field("running_since", &self.running_since)
+            .field("idle_ticks", &self.idle_ticks)
+            .field("context_switches", &self.context_switches)
+            .field("held_locks", &self.held_locks.count())
             .field(
                 "free_allocated_buffers",
                 &format_args!("[...] - {} elements", self.free_allocated_buffers.len()),
@@ -103,9 +129,25 @@ impl PerCpu {
             syscall_data: SyscallData::new(),
             kernel_rsp: 0,
             free_allocated_buffers: Vec::new(),
+            running_since: 0,
+            idle_ticks: 0,
+            context_switches: 0,
+            idle_stack: None,
+            held_locks: HeldLocks::new(),
+            log_lines: None,
+            fpu_owner: None,
         }
     }
 
+    /// Where the idle thread starts, aligned like a stack right after a call
+    pub fn idle_stack_top(&self) -> u64 {
+        let stack = self
+            .idle_stack
+            .as_ref()
+            .expect("idle stack of a CPU that wasn't initialized");
+        ((stack.as_ptr() as u64 + stack.len() as u64) & !0xf) - 8
+    }
+
     pub fn ensure_enough_allocated_buffers(&mut self, count: usize) {
         for _ in self.free_allocated_buffers.len()..count {
             self.free_allocated_buffers
@@ -114,6 +156,10 @@ impl PerCpu {
     }
 }
 
+/// Interrupts that wake the idle thread and the housekeeping it does between two sleeps run on
+/// its stack too
+pub const IDLE_STACK_SIZE: usize = 16 * PAGE_SIZE;
+
 static mut PER_CPU: [PerCpu; 256] = [const { PerCpu::new() }; 256];
 
 pub fn init_per_cpu(core_id: u8) {
@@ -126,11 +172,32 @@ pub fn init_per_cpu(core_id: u8) {
             syscall_data: SyscallData::new(),
             kernel_rsp: 0,
             free_allocated_buffers: Vec::new(),
+            running_since: get_uptime_ticks(),
+            idle_ticks: 0,
+            context_switches: 0,
+            idle_stack: Some(calloc_boxed_slice::<u8>(IDLE_STACK_SIZE)),
+            held_locks: HeldLocks::new(),
+            log_lines: Some((0..LOG_LINE_LEVELS).map(|_| LogLine::new()).collect()),
+            fpu_owner: None,
         };
 
         KernelGsBase::set(&PER_CPU[core_id as usize] as *const _ as u64);
         GsBase::use_kernel_base();
     }
+    PER_CPU_INITIALIZED.store(true, Ordering::Release);
+}
+
+static PER_CPU_INITIALIZED: AtomicBool = AtomicBool::new(false);
+
+/// Whether `base` is the gs base of a CPU in the kernel
+pub fn is_per_cpu_base(base: u64) -> bool {
+    let start = &raw const PER_CPU as u64;
+    (start..start + size_of::<[PerCpu; 256]>() as u64).contains(&base)
+}
+
+/// Whether `get_per_cpu` can be used, gs isn't set up before `init_per_cpu`
+pub fn is_per_cpu_initialized() -> bool {
+    PER_CPU_INITIALIZED.load(Ordering::Acquire)
 }
 
 #[inline(always)]
@@ -146,3 +213,38 @@ pub fn core_id() -> u8 {
 pub fn get_per_cpu() -> &'static mut PerCpu {
     unsafe { &mut PER_CPU[core_id() as usize] }
 }
+
+/// Idle ticks of every CPU
+pub fn total_idle_ticks() -> u64 {
+    #[allow(static_mut_refs)]
+    unsafe {
+        PER_CPU
+            .iter()
+            .filter(|per_cpu| per_cpu.exists)
+            .map(|per_cpu| per_cpu.idle_ticks)
+            .sum()
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct CpuStats {
+    pub core_id: u8,
+    pub idle_ticks: u64,
+    pub context_switches: u64,
+}
+
+/// Scheduler statistics of every CPU
+pub fn cpu_stats() -> Vec<CpuStats> {
+    #[allow(static_mut_refs)]
+    unsafe {
+        PER_CPU
+            .iter()
+            .filter(|per_cpu| per_cpu.exists)
+            .map(|per_cpu| CpuStats {
+                core_id: per_cpu.core_id,
+                idle_ticks: per_cpu.idle_ticks,
+                context_switches: per_cpu.context_switches,
+            })
+            .collect()
+    }
+}
@@ -0,0 +1,64 @@
+use core::arch::asm;
+
+use crate::{
+    drivers::{acpi, vfs::get_vfs},
+    io,
+    log::get_stdout,
+    log_error, log_info,
+};
+
+/// Flushes and unmounts every file system, so ext2 volumes are marked clean
+fn prepare_power_down() {
+    log_info!(target: "power", "Syncing and unmounting file systems");
+    // The log file lives on a file system about to be unmounted
+    drop(get_stdout().switch_to_ring());
+
+    let vfs = get_vfs();
+    let mut guard = vfs.write();
+    if let Err(e) = guard.sync_all() {
+        log_error!(target: "power", "Failed to sync file systems: {:?}", e);
+    }
+    for (path, e) in guard.unmount_all() {
+        log_error!(
+            target: "power",
+            "Failed to unmount {}: {:?}",
+            path.iter().collect::<alloc::string::String>(),
+            e
+        );
+    }
+}
+
+/// Loads an empty IDT and raises an exception, which resets the CPU
+unsafe fn triple_fault() -> ! {
+    let idtr = [0u8; 10];
+    asm!("lidt [{}]", "int3", in(reg) idtr.as_ptr());
+    loop {
+        asm!("cli", "hlt");
+    }
+}
+
+pub fn reboot() -> ! {
+    prepare_power_down();
+    log_info!(target: "power", "Rebooting");
+    io::reboot();
+    unsafe { triple_fault() }
+}
+
+pub fn power_off() -> ! {
+    prepare_power_down();
+    log_info!(target: "power", "Powering off");
+    acpi::power_off();
+    halt_now()
+}
+
+pub fn halt() -> ! {
+    prepare_power_down();
+    log_info!(target: "power", "System halted");
+    halt_now()
+}
+
+fn halt_now() -> ! {
+    loop {
+        unsafe { asm!("cli", "hlt") };
+    }
+}
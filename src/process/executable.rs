@@ -1,17 +1,46 @@
 use core::fmt::Debug;
 
-use alloc::{boxed::Box, string::String, vec::Vec};
+use alloc::{boxed::Box, collections::BTreeMap, string::String, vec::Vec};
 
 use crate::{
-    data::{file::File, permissions::Permissions},
-    drivers::vfs::{AsAny, OPEN_MODE_READ},
-    formats::elf::Elf64File,
+    config::get_kernel_config,
+    data::{
+        calloc_boxed_slice,
+        file::File,
+        permissions::{Credentials, PermissionType, Permissions},
+        regs::rflags::{RFlag, RFlags},
+    },
+    drivers::vfs::{AsAny, SeekPosition, VfsError, OPEN_MODE_READ},
+    formats::{
+        elf::{
+            build_stack, AuxValue, Elf64File, AT_EGID, AT_ENTRY, AT_EUID, AT_EXECFN, AT_GID,
+            AT_PAGESZ, AT_RANDOM, AT_TIME_PAGE, AT_UID, ELF_MAGIC,
+        },
+        flat::{FlatBinary, FLAT_MAGIC},
+        script::{ScriptExecutable, SCRIPT_MAGIC},
+    },
+    memory::random_u64,
+    paging::{
+        align_down, PageTable, DIRECT_MAPPING_OFFSET, PAGE_ACCESSED, PAGE_PRESENT, PAGE_RW,
+        PAGE_SIZE, PAGE_USER,
+    },
 };
 
-use super::scheduler::CreateProcessOptions;
+use super::{
+    memory::{
+        randomize_address, PROC_ASLR_WINDOW, PROC_MAPPED_CODE_TOP, PROC_TIME_PAGE,
+        PROC_USER_STACK_RESERVE, PROC_USER_STACK_TOP,
+    },
+    proc::{ProcessAllocatedCode, ThreadGPRegisters, ThreadState},
+    scheduler::{CreateProcessOptions, ProcessSyscallABI},
+    time_page::map_time_page,
+};
 
 pub struct ExecutableInstantiateOptions {
     pub name: String,
+    /// Path given to exec, passed as `AT_EXECFN`
+    pub path: String,
+    pub parent_pid: u32,
     pub cmdline: Vec<String>,
     pub cwd: String,
     pub environment: Vec<String>,
@@ -27,10 +56,249 @@ pub trait ExecutableFileFormat: AsAny + Debug {
     ) -> Result<CreateProcessOptions, Box<dyn Debug>>;
 }
 
-pub fn parse_executable(path: &str) -> Result<Box<dyn ExecutableFileFormat>, Vec<Box<dyn Debug>>> {
+/// Formats recognized by `parse_executable`, with their magic bytes
+pub const EXECUTABLE_FORMATS: &[(&str, &[u8])] = &[
+    ("elf", &ELF_MAGIC),
+    ("script", SCRIPT_MAGIC),
+    ("flat", &FLAT_MAGIC),
+];
+
+#[derive(Debug)]
+pub enum ExecutableError {
+    InvalidPageTableAllocation,
+    AddressSpaceRandomizationFailed,
+    /// None of `tried` matched the first bytes of the file
+    UnknownFormat {
+        magic: Vec<u8>,
+        tried: &'static [(&'static str, &'static [u8])],
+    },
+}
+
+impl From<ExecutableError> for Box<dyn Debug> {
+    fn from(value: ExecutableError) -> Self {
+        Box::new(value)
+    }
+}
+
+/// The pages of an executable, filled but not mapped yet
+#[derive(Debug, Default)]
+pub struct ExecutableImage {
+    pub entry: u64,
+    pub pages: BTreeMap<u64, Box<[u8]>>,
+    /// Format specific aux vector entries, such as the program headers of an ELF
+    pub aux: Vec<(u64, AuxValue)>,
+}
+
+impl ExecutableImage {
+    pub fn new(entry: u64) -> Self {
+        Self {
+            entry,
+            pages: BTreeMap::new(),
+            aux: Vec::new(),
+        }
+    }
+
+    /// Adds zeroed pages over `[begin, end)`, keeping the ones already there
+    pub fn allocate(&mut self, begin: u64, end: u64) {
+        for virt in (align_down(begin, PAGE_SIZE as u64)..end).step_by(PAGE_SIZE) {
+            self.pages
+                .entry(virt)
+                .or_insert_with(|| calloc_boxed_slice(PAGE_SIZE));
+        }
+    }
+
+    /// Copies `data` at the user address `virt`, fails if a page isn't allocated
+    pub(crate) fn write(&mut self, mut virt: u64, mut data: &[u8]) -> Result<(), ()> {
+        while !data.is_empty() {
+            let page = align_down(virt, PAGE_SIZE as u64);
+            let in_page = (virt - page) as usize;
+            let buffer = self.pages.get_mut(&page).ok_or(())?;
+            let len = data.len().min(PAGE_SIZE - in_page);
+            buffer[in_page..in_page + len].copy_from_slice(&data[..len]);
+            data = &data[len..];
+            virt += len as u64;
+        }
+        Ok(())
+    }
+
+    /// Ranges of allocated pages, merged when contiguous
+    pub fn ranges(&self) -> Vec<(u64, u64)> {
+        let mut ranges: Vec<(u64, u64)> = Vec::new();
+        for virt in self.pages.keys() {
+            match ranges.last_mut() {
+                Some((_, end)) if *end == *virt => *end += PAGE_SIZE as u64,
+                _ => ranges.push((*virt, virt + PAGE_SIZE as u64)),
+            }
+        }
+        ranges
+    }
+
+    /// Maps the pages in a new address space and builds the main thread
+    pub fn instantiate(
+        self,
+        options: ExecutableInstantiateOptions,
+    ) -> Result<CreateProcessOptions, ExecutableError> {
+        let ExecutableInstantiateOptions {
+            cmdline,
+            cwd,
+            environment,
+            gid,
+            name,
+            parent_pid,
+            path,
+            supplementary_gids,
+            uid,
+        } = options;
+
+        let segments = self.ranges();
+        let entry = self.entry;
+
+        let mut pt = PageTable::alloc_new().ok_or(ExecutableError::InvalidPageTableAllocation)?;
+
+        pt.map_global_higher_half();
+
+        let mut random = [0; 16];
+        random[..8].copy_from_slice(&random_u64().to_le_bytes());
+        random[8..].copy_from_slice(&random_u64().to_le_bytes());
+        let mut execfn = path.into_bytes();
+        execfn.push(0);
+
+        // A dynamic loader gets the entry of the executable from its format
+        let mut aux = self.aux;
+        if !aux.iter().any(|(key, _)| *key == AT_ENTRY) {
+            aux.push((AT_ENTRY, AuxValue::Value(entry)));
+        }
+        aux.extend([
+            (AT_PAGESZ, AuxValue::Value(PAGE_SIZE as u64)),
+            (AT_UID, AuxValue::Value(uid as u64)),
+            (AT_EUID, AuxValue::Value(uid as u64)),
+            (AT_GID, AuxValue::Value(gid as u64)),
+            (AT_EGID, AuxValue::Value(gid as u64)),
+            (AT_RANDOM, AuxValue::Bytes(random.to_vec())),
+            (AT_EXECFN, AuxValue::Bytes(execfn)),
+        ]);
+        if map_time_page(&mut pt) {
+            aux.push((AT_TIME_PAGE, AuxValue::Value(PROC_TIME_PAGE)));
+        }
+
+        let mut allocated_code = Vec::new();
+        for (virt, buffer) in self.pages {
+            let flags = PAGE_USER | PAGE_ACCESSED | PAGE_RW | PAGE_PRESENT;
+
+            let phys = buffer.as_ptr() as u64 - DIRECT_MAPPING_OFFSET;
+            unsafe {
+                pt.map_4kb(virt, phys, flags, false);
+            }
+
+            allocated_code.push((virt, buffer));
+        }
+
+        let stack_top =
+            randomize_address(PROC_USER_STACK_TOP, PROC_USER_STACK_RESERVE, 0, &segments)
+                .ok_or(ExecutableError::AddressSpaceRandomizationFailed)?;
+        let mmap_base = randomize_address(PROC_MAPPED_CODE_TOP + PROC_ASLR_WINDOW, 0, 0, &segments)
+            .ok_or(ExecutableError::AddressSpaceRandomizationFailed)?;
+
+        let (mut s, rsp, argv, envp) = build_stack(
+            stack_top,
+            &mut pt,
+            PAGE_ACCESSED | PAGE_USER | PAGE_RW | PAGE_PRESENT,
+            &cmdline,
+            &environment,
+            &aux,
+        );
+        s.grow(&mut pt, PAGE_ACCESSED | PAGE_USER | PAGE_RW | PAGE_PRESENT);
+
+        Ok(CreateProcessOptions {
+            name,
+            parent_pid,
+            cmdline: cmdline.to_vec(),
+            cwd,
+            uid,
+            gid,
+            supplementary_gids,
+            page_table: pt,
+            main_thread_state: ThreadState {
+                gpregs: ThreadGPRegisters {
+                    rdi: cmdline.len() as u64, // arg0 = argc
+                    rsi: argv,                 // arg1 =argv
+                    rdx: envp,                 // arg2 = envp
+                    rax: 0,
+                    rbx: 0,
+                    rcx: 0,
+                    r8: 0,
+                    r9: 0,
+                    r10: 0,
+                    r11: 0,
+                    r12: 0,
+                    r13: 0,
+                    r14: 0,
+                    r15: 0,
+                },
+                rip: entry,
+                rbp: 0,
+                rsp,
+                rflags: RFlags::empty()
+                    .set(RFlag::InterruptFlag)
+                    .set(RFlag::IOPL3)
+                    .get(),
+                fs_base: 0,
+                gs_base: 0,
+            },
+            allocated_code: ProcessAllocatedCode {
+                allocs: allocated_code,
+            },
+            syscalls: if get_kernel_config().fast_syscalls {
+                ProcessSyscallABI::Linux
+            } else {
+                ProcessSyscallABI::LinuxInt80
+            },
+            main_thread_stack: s,
+            mmap_base,
+        })
+    }
+}
+
+/// Scripts can name a script as interpreter, up to this depth
+pub const MAX_SCRIPT_DEPTH: usize = 4;
+
+/// `credentials` need the execute permission, the file doesn't have to be readable
+pub fn parse_executable(
+    path: &str,
+    credentials: Credentials,
+) -> Result<Box<dyn ExecutableFileFormat>, Vec<Box<dyn Debug>>> {
+    parse_executable_nested(path, credentials, 0)
+}
+
+/// `depth` is the number of scripts that led to `path`
+pub fn parse_executable_nested(
+    path: &str,
+    credentials: Credentials,
+    depth: usize,
+) -> Result<Box<dyn ExecutableFileFormat>, Vec<Box<dyn Debug>>> {
     let mut errs: Vec<Box<dyn Debug>> = Vec::new();
 
-    let file = match File::open(path, OPEN_MODE_READ, Permissions::from_u64(0)) {
+    if !matches!(credentials, Credentials::Kernel) {
+        let allowed = match File::get_stats(path) {
+            Ok(Some(stat)) => stat.is_file && credentials.can(&stat, PermissionType::Execute),
+            Ok(None) => false,
+            Err(e) => {
+                errs.push(Box::new(e));
+                return Err(errs);
+            }
+        };
+        if !allowed {
+            errs.push(Box::new(VfsError::PermissionDenied));
+            return Err(errs);
+        }
+    }
+
+    let file = match File::open(
+        path,
+        OPEN_MODE_READ,
+        Permissions::from_u64(0),
+        Credentials::Kernel,
+    ) {
         Ok(file) => file,
         Err(e) => {
             errs.push(Box::new(e));
@@ -38,11 +306,45 @@ pub fn parse_executable(path: &str) -> Result<Box<dyn ExecutableFileFormat>, Vec
         }
     };
 
-    match Elf64File::try_parse(&file) {
-        Ok(elf) => return Ok(Box::new(elf)),
+    let mut magic = [0; 8];
+    let magic = match file
+        .seek(SeekPosition::FromStart(0))
+        .and_then(|_| file.read(&mut magic))
+    {
+        Ok(read) => &magic[..read as usize],
         Err(e) => {
             errs.push(Box::new(e));
+            &[]
         }
+    };
+
+    if errs.is_empty() && magic.starts_with(&ELF_MAGIC) {
+        // The ELF keeps the file to read its segments from, dropping it closes the file
+        return Elf64File::try_parse(file)
+            .map(|elf| Box::new(elf) as Box<dyn ExecutableFileFormat>)
+            .map_err(|e| alloc::vec![e.into()]);
+    }
+
+    let parsed: Result<Box<dyn ExecutableFileFormat>, Vec<Box<dyn Debug>>> = if !errs.is_empty() {
+        Err(Vec::new())
+    } else if magic.starts_with(SCRIPT_MAGIC) {
+        ScriptExecutable::try_parse(&file, path, credentials, depth)
+            .map(|script| Box::new(script) as Box<dyn ExecutableFileFormat>)
+    } else if magic.starts_with(&FLAT_MAGIC) {
+        FlatBinary::try_parse(&file)
+            .map(|flat| Box::new(flat) as Box<dyn ExecutableFileFormat>)
+            .map_err(|e| alloc::vec![e.into()])
+    } else {
+        Err(alloc::vec![ExecutableError::UnknownFormat {
+            magic: magic.to_vec(),
+            tried: EXECUTABLE_FORMATS,
+        }
+        .into()])
+    };
+
+    match parsed {
+        Ok(executable) => return Ok(executable),
+        Err(e) => errs.extend(e),
     }
 
     match file.close() {
@@ -0,0 +1,258 @@
+//! x87/SSE/AVX registers of the user threads, switched lazily.
+//!
+//! The kernel is built without SSE (soft float target), so it never touches these registers and
+//! the state of the last thread that used them stays loaded until another thread needs them.
+//! Switching to a thread that doesn't own them sets CR0.TS, its first FPU instruction then traps
+//! with #NM, which saves the state of the owner and loads its own. Threads that never use the
+//! FPU never pay for a save. Kernel code that uses these registers has to own them first, with
+//! `flush_fpu_owner` and `FpuState::restore`.
+
+use alloc::{
+    alloc::{alloc_zeroed, dealloc, handle_alloc_error},
+    sync::{Arc, Weak},
+};
+use core::{
+    alloc::Layout,
+    arch::x86_64::{__cpuid, __cpuid_count},
+    ptr::NonNull,
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+};
+
+use crate::{
+    data::regs::cr::{
+        Cr0, Cr4, CR0_EM, CR0_MP, CR0_NE, CR0_TS, CR4_OSFXSR, CR4_OSXMMEXCPT, CR4_OSXSAVE,
+    },
+    percpu::get_per_cpu,
+    process::proc::Thread,
+};
+
+const CPUID_1_ECX_XSAVE: u32 = 1 << 26;
+const CPUID_1_EDX_FXSR: u32 = 1 << 24;
+
+/// x87, SSE and AVX, the components saved with xsave
+const XCR0_X87: u64 = 1 << 0;
+const XCR0_SSE: u64 = 1 << 1;
+const XCR0_AVX: u64 = 1 << 2;
+
+const FXSAVE_AREA_SIZE: usize = 512;
+const FPU_AREA_ALIGN: usize = 64;
+
+/// Initial control words, all exceptions masked
+const DEFAULT_FCW: u16 = 0x037F;
+const DEFAULT_MXCSR: u32 = 0x1F80;
+const FCW_OFFSET: usize = 0;
+const MXCSR_OFFSET: usize = 24;
+
+static USE_XSAVE: AtomicBool = AtomicBool::new(false);
+static FPU_AREA_SIZE: AtomicUsize = AtomicUsize::new(FXSAVE_AREA_SIZE);
+
+unsafe fn xgetbv(register: u32) -> u64 {
+    let (low, high): (u32, u32);
+    core::arch::asm!("xgetbv", in("ecx") register, out("eax") low, out("edx") high, options(nomem, nostack));
+    ((high as u64) << 32) | low as u64
+}
+
+unsafe fn xsetbv(register: u32, value: u64) {
+    core::arch::asm!("xsetbv", in("ecx") register, in("eax") value as u32, in("edx") (value >> 32) as u32, options(nomem, nostack));
+}
+
+/// Enables SSE for userland, and xsave if the CPU has it. Returns the size of a state area
+///
+/// # Safety
+/// Caller must ensure the code is running in ring 0, before any thread is created
+pub unsafe fn init_fpu() -> usize {
+    #[allow(unused_unsafe)]
+    let leaf = unsafe { __cpuid(1) };
+    if leaf.edx & CPUID_1_EDX_FXSR == 0 {
+        panic!("The CPU doesn't support fxsave");
+    }
+
+    Cr0::write((Cr0::read() & !CR0_EM) | CR0_MP | CR0_NE);
+    Cr4::write(Cr4::read() | CR4_OSFXSR | CR4_OSXMMEXCPT);
+
+    if leaf.ecx & CPUID_1_ECX_XSAVE != 0 {
+        Cr4::write(Cr4::read() | CR4_OSXSAVE);
+        #[allow(unused_unsafe)]
+        let xstate = unsafe { __cpuid_count(0xD, 0) };
+        let supported = ((xstate.edx as u64) << 32) | xstate.eax as u64;
+        xsetbv(0, supported & (XCR0_X87 | XCR0_SSE | XCR0_AVX));
+        // Size needed by the components enabled in XCR0
+        #[allow(unused_unsafe)]
+        let size = unsafe { __cpuid_count(0xD, 0) }.ebx as usize;
+        FPU_AREA_SIZE.store(size.max(FXSAVE_AREA_SIZE), Ordering::Relaxed);
+        USE_XSAVE.store(true, Ordering::Relaxed);
+    }
+
+    asm_fninit();
+    fpu_area_size()
+}
+
+unsafe fn asm_fninit() {
+    core::arch::asm!("clts", "fninit", options(nomem, nostack));
+}
+
+pub fn fpu_area_size() -> usize {
+    FPU_AREA_SIZE.load(Ordering::Relaxed)
+}
+
+pub fn uses_xsave() -> bool {
+    USE_XSAVE.load(Ordering::Relaxed)
+}
+
+/// Components enabled for xsave, 0 with fxsave
+pub fn xsave_components() -> u64 {
+    if uses_xsave() {
+        unsafe { xgetbv(0) }
+    } else {
+        0
+    }
+}
+
+/// Saved registers of a thread, in the fxsave or xsave format
+pub struct FpuState {
+    area: NonNull<u8>,
+    size: usize,
+}
+
+unsafe impl Send for FpuState {}
+unsafe impl Sync for FpuState {}
+
+impl core::fmt::Debug for FpuState {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("FpuState")
+            .field("size", &self.size)
+            .field("mxcsr", &format_args!("{:#x}", self.mxcsr()))
+            .finish()
+    }
+}
+
+impl FpuState {
+    fn layout(size: usize) -> Layout {
+        Layout::from_size_align(size, FPU_AREA_ALIGN).unwrap()
+    }
+
+    fn allocate(size: usize) -> Self {
+        let layout = Self::layout(size);
+        let area = NonNull::new(unsafe { alloc_zeroed(layout) })
+            .unwrap_or_else(|| handle_alloc_error(layout));
+        Self { area, size }
+    }
+
+    /// The state after `fninit`, with SSE exceptions masked. xrstor loads the initial state of the
+    /// components missing from the zeroed header
+    pub fn new() -> Self {
+        let mut state = Self::allocate(fpu_area_size());
+        let bytes = state.as_bytes_mut();
+        bytes[FCW_OFFSET..FCW_OFFSET + 2].copy_from_slice(&DEFAULT_FCW.to_le_bytes());
+        bytes[MXCSR_OFFSET..MXCSR_OFFSET + 4].copy_from_slice(&DEFAULT_MXCSR.to_le_bytes());
+        state
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        unsafe { core::slice::from_raw_parts(self.area.as_ptr(), self.size) }
+    }
+
+    fn as_bytes_mut(&mut self) -> &mut [u8] {
+        unsafe { core::slice::from_raw_parts_mut(self.area.as_ptr(), self.size) }
+    }
+
+    pub fn mxcsr(&self) -> u32 {
+        let bytes = &self.as_bytes()[MXCSR_OFFSET..MXCSR_OFFSET + 4];
+        u32::from_le_bytes(bytes.try_into().unwrap())
+    }
+
+    /// Saves the registers into this area
+    ///
+    /// # Safety
+    /// CR0.TS must be clear
+    pub unsafe fn save(&mut self) {
+        let area = self.area.as_ptr();
+        if uses_xsave() {
+            core::arch::asm!("xsave64 [{}]", in(reg) area, in("eax") u32::MAX, in("edx") u32::MAX, options(nostack));
+        } else {
+            core::arch::asm!("fxsave64 [{}]", in(reg) area, options(nostack));
+        }
+    }
+
+    /// Loads the registers from this area
+    ///
+    /// # Safety
+    /// CR0.TS must be clear
+    pub unsafe fn restore(&self) {
+        let area = self.area.as_ptr();
+        if uses_xsave() {
+            core::arch::asm!("xrstor64 [{}]", in(reg) area, in("eax") u32::MAX, in("edx") u32::MAX, options(nostack, readonly));
+        } else {
+            core::arch::asm!("fxrstor64 [{}]", in(reg) area, options(nostack, readonly));
+        }
+    }
+}
+
+impl Default for FpuState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clone for FpuState {
+    fn clone(&self) -> Self {
+        let mut state = Self::allocate(self.size);
+        state.as_bytes_mut().copy_from_slice(self.as_bytes());
+        state
+    }
+}
+
+impl Drop for FpuState {
+    fn drop(&mut self) {
+        unsafe { dealloc(self.area.as_ptr(), Self::layout(self.size)) }
+    }
+}
+
+fn set_task_switched(set: bool) {
+    unsafe {
+        let cr0 = Cr0::read();
+        let new = if set { cr0 | CR0_TS } else { cr0 & !CR0_TS };
+        if new != cr0 {
+            Cr0::write(new);
+        }
+    }
+}
+
+fn is_owner(owner: &Option<Weak<Thread>>, thread: &Thread) -> bool {
+    owner
+        .as_ref()
+        .is_some_and(|owner| core::ptr::eq(owner.as_ptr(), thread))
+}
+
+/// Called when switching to a user thread, its first FPU instruction traps unless its registers
+/// are still loaded
+pub fn switch_fpu_to(thread: &Thread) {
+    set_task_switched(!is_owner(&get_per_cpu().fpu_owner, thread));
+}
+
+/// #NM: saves the registers of their owner and loads the ones of `thread`
+pub fn take_fpu(thread: &Arc<Thread>) {
+    let per_cpu = get_per_cpu();
+    set_task_switched(false);
+    if is_owner(&per_cpu.fpu_owner, thread) {
+        return;
+    }
+    unsafe {
+        if let Some(owner) = per_cpu.fpu_owner.take().and_then(|owner| owner.upgrade()) {
+            owner.fpu.lock().save();
+        }
+        thread.fpu.lock().restore();
+    }
+    per_cpu.fpu_owner = Some(Arc::downgrade(thread));
+}
+
+/// Saves the loaded registers into their owner's area, the next FPU instruction of a user
+/// thread traps. Needed before reading the area of a thread that may own the registers
+pub fn flush_fpu_owner() {
+    let per_cpu = get_per_cpu();
+    if let Some(owner) = per_cpu.fpu_owner.take().and_then(|owner| owner.upgrade()) {
+        set_task_switched(false);
+        unsafe { owner.fpu.lock().save() };
+    }
+    set_task_switched(true);
+}
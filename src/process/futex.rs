@@ -0,0 +1,80 @@
+//! Queues of the threads sleeping on a futex, keyed by process and address. There is no memory
+//! shared between processes, so every futex is private to its process.
+
+use alloc::{collections::BTreeMap, sync::Arc};
+
+use spin::{Mutex, MutexGuard};
+
+use crate::process::wait::WaitQueue;
+
+const FUTEX_BUCKETS: usize = 64;
+
+pub type FutexKey = (u32, u64);
+
+type FutexBucket = BTreeMap<FutexKey, Arc<WaitQueue>>;
+
+static FUTEXES: [Mutex<FutexBucket>; FUTEX_BUCKETS] =
+    [const { Mutex::new(BTreeMap::new()) }; FUTEX_BUCKETS];
+
+fn bucket_index((pid, address): FutexKey) -> usize {
+    // Futex words are 4 byte aligned
+    let hash = (address >> 2) ^ (pid as u64).wrapping_mul(0x9e37_79b9_7f4a_7c15);
+    (hash ^ (hash >> 32)) as usize % FUTEX_BUCKETS
+}
+
+/// Bucket of a futex, waiting and waking on it are serialized by holding it
+pub struct LockedFutex {
+    key: FutexKey,
+    bucket: MutexGuard<'static, FutexBucket>,
+}
+
+impl LockedFutex {
+    pub fn lock(pid: u32, address: u64) -> Self {
+        let key = (pid, address);
+        Self {
+            key,
+            bucket: FUTEXES[bucket_index(key)].lock(),
+        }
+    }
+
+    /// Queue of the futex, created for the first waiter
+    pub fn queue(&mut self) -> Arc<WaitQueue> {
+        self.bucket.entry(self.key).or_default().clone()
+    }
+
+    /// Wakes up to `count` waiters, returns how many were woken
+    pub fn wake(&mut self, count: u64) -> u64 {
+        let Some(queue) = self.bucket.get(&self.key) else {
+            return 0;
+        };
+        let mut woken = 0;
+        while woken < count && queue.wake_one() {
+            woken += 1;
+        }
+        self.prune();
+        woken
+    }
+
+    /// Forgets the queue once nobody waits on it anymore
+    pub fn prune(&mut self) {
+        if self
+            .bucket
+            .get(&self.key)
+            .is_some_and(|queue| queue.is_empty())
+        {
+            self.bucket.remove(&self.key);
+        }
+    }
+}
+
+/// Forgets the futexes of an exiting process, its threads already left their queues
+pub fn release_process_futexes(pid: u32) {
+    for bucket in FUTEXES.iter() {
+        bucket.lock().retain(|(owner, _), _| *owner != pid);
+    }
+}
+
+/// Futexes that have waiters
+pub fn futex_count() -> usize {
+    FUTEXES.iter().map(|bucket| bucket.lock().len()).sum()
+}
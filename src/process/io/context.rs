@@ -1,15 +1,13 @@
+use alloc::sync::Arc;
+
 use crate::{
     data::file::File,
     drivers::{fs::virt::pipefs::Pipe, vfs::VfsError},
-    process::io::file_table::FileTable,
+    process::io::file_table::{FileTable, OpenFile},
 };
 
 #[derive(Debug)]
 pub struct ProcessIOContext {
-    pub stdin: File,  // fd 0
-    pub stdout: File, // fd 1
-    pub stderr: File, // fd 2
-
     pub file_table: FileTable,
 }
 
@@ -31,21 +29,25 @@ impl ProcessIOContext {
         })
     }
 
+    /// The files become fds 0, 1 and 2, closed with them
     pub fn new_with_stdio(stdin_read: File, stdout_write: File, stderr_write: File) -> Self {
         let mut ft = FileTable::new();
 
         ft.max_allocated_fd = 3;
-        unsafe {
-            ft.files[0] = Some((stdin_read.get_file_system(), stdin_read.get_handle()));
-            ft.files[1] = Some((stdout_write.get_file_system(), stdout_write.get_handle()));
-            ft.files[2] = Some((stderr_write.get_file_system(), stderr_write.get_handle()));
-        }
-
-        Self {
-            stdin: stdin_read,
-            stdout: stdout_write,
-            stderr: stderr_write,
-            file_table: ft,
-        }
+        ft.files[0] = Some(stdio_fd(stdin_read));
+        ft.files[1] = Some(stdio_fd(stdout_write));
+        ft.files[2] = Some(stdio_fd(stderr_write));
+
+        Self { file_table: ft }
     }
 }
+
+fn stdio_fd(file: File) -> Arc<OpenFile> {
+    let identity = file.is_path_handle().then(|| {
+        file.get_file_system()
+            .write()
+            .file_identity(file.get_vfs_file())
+    });
+    let (fs, handle) = file.into_handle();
+    OpenFile::opened(fs, handle, false, false, identity)
+}
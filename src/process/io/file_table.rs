@@ -1,15 +1,199 @@
 use core::fmt::Debug;
 
-use alloc::vec::Vec;
+use alloc::{sync::Arc, vec::Vec};
+use spin::Mutex;
 
-use crate::drivers::vfs::{Arcrwb, FileSystem};
+use crate::drivers::{
+    fs::{
+        lock::{FileLockKey, FILE_LOCKS},
+        virt::devfs::fseek_helper,
+    },
+    vfs::{close_path_handle, copy_file_data, Arcrwb, CopyEnd, FileSystem, SeekPosition, VfsError},
+};
 
 pub const MAX_FILES: usize = 4096;
 
+/// An open file, shared by every fd duplicated from it
+pub struct OpenFile {
+    pub fs: Arcrwb<dyn FileSystem>,
+    pub handle: u64,
+    /// Position of read, write and lseek. `None` for streams like pipes, sockets and devices,
+    /// their file system keeps the position
+    offset: Option<Mutex<u64>>,
+    append: bool,
+    /// `FileSystem::file_identity` of the file, `None` when it wasn't opened from a path
+    identity: Option<u64>,
+}
+
+impl OpenFile {
+    pub fn stream(fs: Arcrwb<dyn FileSystem>, handle: u64) -> Arc<Self> {
+        Self::opened(fs, handle, false, false, None)
+    }
+
+    /// A file opened from a path, `seekable` is `FileSystem::fseekable` of the handle.
+    /// Writes go to the end of the file when `append` is set
+    pub fn opened(
+        fs: Arcrwb<dyn FileSystem>,
+        handle: u64,
+        seekable: bool,
+        append: bool,
+        identity: Option<u64>,
+    ) -> Arc<Self> {
+        Arc::new(Self {
+            fs,
+            handle,
+            offset: seekable.then(|| Mutex::new(0)),
+            append: seekable && append,
+            identity,
+        })
+    }
+
+    /// Whether reads and writes can be at an offset, streams can't
+    pub fn is_seekable(&self) -> bool {
+        self.offset.is_some()
+    }
+
+    /// Owner of the advisory locks taken through this open file
+    pub fn lock_owner(self: &Arc<Self>) -> u64 {
+        Arc::as_ptr(self) as u64
+    }
+
+    /// Key of the advisory locks on this file, files without an identity only lock themselves
+    pub fn lock_key(self: &Arc<Self>) -> FileLockKey {
+        let fs = self.fs.write().os_id();
+        (fs, self.identity.unwrap_or_else(|| self.lock_owner()))
+    }
+
+    pub fn read(&self, buf: &mut [u8]) -> Result<u64, VfsError> {
+        let mut gfs = self.fs.write();
+        let Some(offset) = &self.offset else {
+            return gfs.fread(self.handle, buf);
+        };
+        let mut offset = offset.lock();
+        let read = gfs.fread_at(self.handle, *offset, buf)?;
+        *offset += read;
+        Ok(read)
+    }
+
+    pub fn write(&self, buf: &[u8]) -> Result<u64, VfsError> {
+        let mut gfs = self.fs.write();
+        let Some(offset) = &self.offset else {
+            return gfs.fwrite(self.handle, buf);
+        };
+        let mut offset = offset.lock();
+        if self.append {
+            *offset = gfs.fstat(self.handle)?.size;
+        }
+        let written = gfs.fwrite_at(self.handle, *offset, buf)?;
+        *offset += written;
+        Ok(written)
+    }
+
+    /// Returns the new position
+    pub fn seek(&self, position: SeekPosition) -> Result<u64, VfsError> {
+        let mut gfs = self.fs.write();
+        let Some(offset) = &self.offset else {
+            return gfs.fseek(self.handle, position);
+        };
+        let mut offset = offset.lock();
+        let size = gfs.fstat(self.handle)?.size;
+        *offset = fseek_helper(position, *offset, size).ok_or(VfsError::InvalidSeekPosition)?;
+        Ok(*offset)
+    }
+
+    pub fn ioctl(&self, request: u64, arg: &mut [u8]) -> Result<u64, VfsError> {
+        self.fs.write().fioctl(self.handle, request, arg)
+    }
+
+    /// Reads at `offset`, the position shared with other fds doesn't move
+    pub fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<u64, VfsError> {
+        if self.offset.is_none() {
+            return Err(VfsError::InvalidSeekPosition);
+        }
+        self.fs.write().fread_at(self.handle, offset, buf)
+    }
+
+    /// Writes at `offset`, the position shared with other fds doesn't move
+    pub fn write_at(&self, offset: u64, buf: &[u8]) -> Result<u64, VfsError> {
+        if self.offset.is_none() {
+            return Err(VfsError::InvalidSeekPosition);
+        }
+        self.fs.write().fwrite_at(self.handle, offset, buf)
+    }
+
+    /// Copies up to `len` bytes to `dst` inside the kernel. A `None` offset reads or writes at
+    /// the position shared with other fds and moves it, the only way streams can be used.
+    /// Overlapping ranges of the same file fail
+    pub fn copy_to(
+        &self,
+        src_offset: Option<u64>,
+        dst: &OpenFile,
+        dst_offset: Option<u64>,
+        len: u64,
+    ) -> Result<u64, VfsError> {
+        let same_file = core::ptr::eq(self, dst)
+            || (Arc::ptr_eq(&self.fs, &dst.fs)
+                && self.identity.is_some()
+                && self.identity == dst.identity);
+        if core::ptr::eq(self, dst) && src_offset.is_none() && dst_offset.is_none() {
+            return Err(VfsError::InvalidArgument);
+        }
+
+        let mut src_guard = None;
+        let src_position = match (src_offset, &self.offset) {
+            (Some(_), None) => return Err(VfsError::InvalidSeekPosition),
+            (Some(offset), Some(_)) => Some(offset),
+            (None, Some(position)) => Some(**src_guard.insert(position.lock())),
+            (None, None) => None,
+        };
+        let mut dst_guard = None;
+        let dst_position = match (dst_offset, &dst.offset) {
+            (Some(_), None) => return Err(VfsError::InvalidSeekPosition),
+            (Some(offset), Some(_)) => Some(offset),
+            (None, Some(position)) => {
+                let guard = dst_guard.insert(position.lock());
+                if dst.append {
+                    **guard = dst.fs.write().fstat(dst.handle)?.size;
+                }
+                Some(**guard)
+            }
+            (None, None) => None,
+        };
+        if let (true, Some(src), Some(dst)) = (same_file, src_position, dst_position) {
+            if src < dst.saturating_add(len) && dst < src.saturating_add(len) {
+                return Err(VfsError::InvalidArgument);
+            }
+        }
+
+        let copied = copy_file_data(
+            CopyEnd {
+                fs: &self.fs,
+                handle: self.handle,
+                offset: src_position,
+            },
+            CopyEnd {
+                fs: &dst.fs,
+                handle: dst.handle,
+                offset: dst_position,
+            },
+            len,
+        )?;
+        if let Some(position) = &mut src_guard {
+            **position += copied;
+        }
+        if let Some(position) = &mut dst_guard {
+            **position += copied;
+        }
+        Ok(copied)
+    }
+}
+
 pub struct FileTable {
     pub files: Vec<OptionalFd>,
     pub max_allocated_fd: usize,
     pub available_fds: Vec<usize>,
+    /// Fds are allocated below this, the open files limit of the process
+    pub max_fds: usize,
 }
 
 impl Default for FileTable {
@@ -18,7 +202,7 @@ impl Default for FileTable {
     }
 }
 
-type Fd = (Arcrwb<dyn FileSystem>, u64);
+type Fd = Arc<OpenFile>;
 type OptionalFd = Option<Fd>;
 type AllocatedFdMutableRef<'a> = (usize, &'a mut OptionalFd);
 
@@ -28,6 +212,7 @@ impl FileTable {
             files: Vec::with_capacity(MAX_FILES),
             max_allocated_fd: 0,
             available_fds: Vec::new(),
+            max_fds: MAX_FILES,
         }
         .init()
     }
@@ -40,9 +225,11 @@ impl FileTable {
     }
 
     pub fn alloc_fd(&mut self) -> Option<AllocatedFdMutableRef<'_>> {
-        if let Some(fd) = self.available_fds.pop() {
+        let limit = self.max_fds.min(MAX_FILES);
+        if let Some(idx) = self.available_fds.iter().rposition(|fd| *fd < limit) {
+            let fd = self.available_fds.remove(idx);
             Some((fd, &mut self.files[fd]))
-        } else if self.max_allocated_fd < MAX_FILES {
+        } else if self.max_allocated_fd < limit {
             let fd = self.max_allocated_fd;
             self.max_allocated_fd += 1;
             Some((fd, &mut self.files[fd]))
@@ -57,9 +244,8 @@ impl FileTable {
             if let Some(fd) = self.alloc_fd() {
                 fds.push(fd.0);
             } else {
-                for idx in fds {
-                    self.free_fd(idx);
-                }
+                // Never filled, so free_fd would skip them
+                self.available_fds.extend(fds);
                 return None;
             }
         }
@@ -67,16 +253,53 @@ impl FileTable {
     }
 
     pub fn free_fd(&mut self, idx: usize) -> OptionalFd {
-        if idx >= self.files.len() {
-            return None;
-        }
+        let file = self.files.get_mut(idx)?.take()?;
         self.available_fds.push(idx);
-        self.files[idx].take()
+        Some(file)
     }
 
     pub fn get_fd(&mut self, idx: usize) -> Option<&mut OptionalFd> {
         self.files.get_mut(idx)
     }
+
+    /// The open file behind `idx`, `None` if the fd isn't open
+    pub fn get(&self, idx: usize) -> Option<Arc<OpenFile>> {
+        self.files.get(idx)?.clone()
+    }
+
+    /// Makes a new fd sharing the open file, and its position, of `idx`
+    pub fn dup(&mut self, idx: usize) -> Option<usize> {
+        let file = self.get(idx)?;
+        let (fd, slot) = self.alloc_fd()?;
+        *slot = Some(file);
+        Some(fd)
+    }
+
+    /// Frees `idx`, the handle is closed when it was the last fd sharing it
+    pub fn close_fd(&mut self, idx: usize) -> Result<(), VfsError> {
+        let file = self.free_fd(idx).ok_or(VfsError::BadHandle)?;
+        let owner = file.lock_owner();
+        match Arc::try_unwrap(file) {
+            Ok(file) => {
+                FILE_LOCKS.lock().release_owner(owner);
+                if file.identity.is_some() {
+                    close_path_handle(&file.fs, file.handle)
+                } else {
+                    file.fs.write().fclose(file.handle)
+                }
+            }
+            Err(_) => Ok(()),
+        }
+    }
+
+    /// Closes every fd, on exit. Each handle is closed once, with the last fd sharing it
+    pub fn close_all(&mut self) {
+        for fd in 0..self.max_allocated_fd {
+            if self.files[fd].is_some() {
+                let _ = self.close_fd(fd);
+            }
+        }
+    }
 }
 
 impl Debug for FileTable {
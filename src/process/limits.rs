@@ -0,0 +1,114 @@
+//! Resource limits of a process, the tracked subset of the linux rlimits
+
+use super::{io::file_table::MAX_FILES, scheduler::SchedulerThreadSettings};
+use crate::memory::buddy_alloc::PAGE_SIZE;
+
+pub const RLIM_INFINITY: u64 = u64::MAX;
+
+/// Soft limit of open files, like linux
+const DEFAULT_NOFILE: u64 = 1024;
+
+/// Same numbers as linux
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Resource {
+    Stack = 3,
+    NoFile = 7,
+    AddressSpace = 9,
+}
+
+impl Resource {
+    pub const ALL: [Resource; 3] = [Resource::Stack, Resource::NoFile, Resource::AddressSpace];
+
+    /// `None` for resources that aren't tracked
+    pub fn from_linux(resource: u64) -> Option<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|tracked| *tracked as u64 == resource)
+    }
+
+    /// Name and unit in `/proc/<pid>/limits`
+    pub fn description(&self) -> (&'static str, &'static str) {
+        match self {
+            Resource::Stack => ("Max stack size", "bytes"),
+            Resource::NoFile => ("Max open files", "files"),
+            Resource::AddressSpace => ("Max address space", "bytes"),
+        }
+    }
+
+    /// Highest limit the kernel can honor, even for root
+    fn ceiling(&self) -> u64 {
+        match self {
+            Resource::NoFile => MAX_FILES as u64,
+            _ => RLIM_INFINITY,
+        }
+    }
+
+    fn index(&self) -> usize {
+        match self {
+            Resource::Stack => 0,
+            Resource::NoFile => 1,
+            Resource::AddressSpace => 2,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ResourceLimit {
+    pub soft: u64,
+    pub hard: u64,
+}
+
+impl ResourceLimit {
+    pub const fn new(soft: u64, hard: u64) -> Self {
+        Self { soft, hard }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LimitError {
+    /// The soft limit is above the hard one
+    InvalidLimit,
+    /// Only root raises hard limits, and not past what the kernel supports
+    NotPermitted,
+}
+
+#[derive(Debug, Clone)]
+pub struct ResourceLimits {
+    limits: [ResourceLimit; 3],
+}
+
+impl ResourceLimits {
+    /// Limits of processes started by the kernel. The stack can't grow past the kernel maximum
+    /// whatever its limit
+    pub fn new(settings: &SchedulerThreadSettings) -> Self {
+        let mut limits = [ResourceLimit::new(RLIM_INFINITY, RLIM_INFINITY); 3];
+        limits[Resource::Stack.index()].soft = settings.max_user_stack_pages * PAGE_SIZE;
+        limits[Resource::NoFile.index()] = ResourceLimit::new(DEFAULT_NOFILE, MAX_FILES as u64);
+        Self { limits }
+    }
+
+    pub fn get(&self, resource: Resource) -> ResourceLimit {
+        self.limits[resource.index()]
+    }
+
+    pub fn soft(&self, resource: Resource) -> u64 {
+        self.get(resource).soft
+    }
+
+    pub fn set(
+        &mut self,
+        resource: Resource,
+        limit: ResourceLimit,
+        privileged: bool,
+    ) -> Result<(), LimitError> {
+        if limit.soft > limit.hard {
+            return Err(LimitError::InvalidLimit);
+        }
+        if limit.hard > resource.ceiling() || (limit.hard > self.get(resource).hard && !privileged)
+        {
+            return Err(LimitError::NotPermitted);
+        }
+        self.limits[resource.index()] = limit;
+        Ok(())
+    }
+}
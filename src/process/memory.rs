@@ -4,7 +4,8 @@ use alloc::{boxed::Box, fmt, vec::Vec};
 
 use crate::{
     data::calloc_boxed_slice,
-    paging::{PageTable, DIRECT_MAPPING_OFFSET, PAGE_SIZE},
+    memory::random_u64,
+    paging::{PageTable, DIRECT_MAPPING_OFFSET, PAGE_ACCESSED, PAGE_PRESENT, PAGE_RW, PAGE_SIZE},
 };
 
 #[derive(Debug, Clone, Copy)]
@@ -32,6 +33,11 @@ pub enum LowerHalfAddressSpace {
 }
 
 pub const PROC_KERNEL_STACK_TOP: u64 = 0xFFFF_D000_0000_0000;
+/// Kernel stacks are filled with it in debug builds, the deepest byte that changed tells how
+/// much of the stack was used
+pub const KERNEL_STACK_PATTERN: u8 = 0xA5;
+/// Address range of the kernel stack of each thread of a process, below `PROC_KERNEL_STACK_TOP`
+pub const PROC_KERNEL_STACK_SLOT: u64 = 0x10_0000;
 pub const GLOB_KERNEL_MMIO_TOP: u64 = 0xFFFF_C000_0000_0000;
 pub const GLOB_KERNEL_DIRECT_MAPPED_TOP: u64 = DIRECT_MAPPING_OFFSET + 0x0000_1000_0000_0000;
 pub const GLOB_KERNEL_STACK_TOP: u64 = 0xFFFF_A000_0000_0000;
@@ -45,6 +51,17 @@ pub const PROC_USER_STACK_TOP: u64 = 0x0000_2000_0000_0000;
 pub const PROC_MAPPED_CODE_TOP: u64 = 0x0000_3000_0000_0000;
 pub const PROC_HEAP_TOP: u64 = 0x0000_4000_0000_0000;
 
+/// Size of the window in which the user stack top and the mmap base get randomized
+pub const PROC_ASLR_WINDOW: u64 = 0x4000_0000;
+/// Address range kept free below the user stack top
+pub const PROC_USER_STACK_RESERVE: u64 = 0x0100_0000;
+/// Loaded segments have to end below the lowest possible user stack
+pub const PROC_SEGMENTS_TOP: u64 = PROC_USER_STACK_TOP - PROC_ASLR_WINDOW - PROC_USER_STACK_RESERVE;
+/// Top of the window in which position independent executables get their load base
+pub const PROC_PIE_BASE_TOP: u64 = LOWER_HALF_SAFEGUARD_END + PROC_ASLR_WINDOW;
+/// Read-only page with the time, mapped in every process below a guard page
+pub const PROC_TIME_PAGE: u64 = LOWER_HALF_END - 2 * PAGE_SIZE as u64;
+
 pub const fn get_address_space(addr: u64) -> Option<VirtualAddressSpace> {
     if addr >= HIGHER_HALF_BEGIN {
         if addr < GLOB_KERNEL_CODE_TOP {
@@ -95,12 +112,47 @@ pub const fn get_address_space(addr: u64) -> Option<VirtualAddressSpace> {
     }
 }
 
+/// Picks a random page aligned address in `[window_top - PROC_ASLR_WINDOW, window_top]` such that
+/// `[addr - size_below, addr + size_above)` stays in canonical user space and doesn't overlap any of the `avoid` ranges.
+pub fn randomize_address(
+    window_top: u64,
+    size_below: u64,
+    size_above: u64,
+    avoid: &[(u64, u64)],
+) -> Option<u64> {
+    const ATTEMPTS: usize = 32;
+
+    let window_pages = PROC_ASLR_WINDOW / PAGE_SIZE as u64;
+    for _ in 0..ATTEMPTS {
+        let offset = (random_u64() % window_pages) * PAGE_SIZE as u64;
+        let Some(addr) = window_top.checked_sub(offset) else {
+            continue;
+        };
+        let (Some(begin), Some(end)) = (addr.checked_sub(size_below), addr.checked_add(size_above))
+        else {
+            continue;
+        };
+        if begin < LOWER_HALF_SAFEGUARD_END || end > LOWER_HALF_END {
+            continue;
+        }
+        if avoid
+            .iter()
+            .all(|&(avoid_begin, avoid_end)| end <= avoid_begin || begin >= avoid_end)
+        {
+            return Some(addr);
+        }
+    }
+    None
+}
+
 #[derive(Debug, Default)]
-pub struct ProcessHeap {}
+pub struct ProcessHeap {
+    pub mmap_base: u64,
+}
 
 impl ProcessHeap {
-    pub fn new() -> Self {
-        ProcessHeap {}
+    pub fn new(mmap_base: u64) -> Self {
+        ProcessHeap { mmap_base }
     }
 
     pub fn free(&mut self, _pt: &mut PageTable) {}
@@ -144,6 +196,35 @@ impl ThreadStack {
         stack
     }
 
+    /// The kernel stack of a thread, filled with `KERNEL_STACK_PATTERN` in debug builds
+    pub fn new_kernel(stack_top: u64, num_pages: u64, table: &mut PageTable) -> Self {
+        let mut stack = Self::new_with_pages(
+            stack_top,
+            num_pages,
+            table,
+            PAGE_PRESENT | PAGE_RW | PAGE_ACCESSED,
+        );
+        if cfg!(debug_assertions) {
+            for page in stack.stack_buffers.iter_mut() {
+                page.fill(KERNEL_STACK_PATTERN);
+            }
+        }
+        stack
+    }
+
+    /// Bytes used at the deepest, for a stack filled with `KERNEL_STACK_PATTERN`
+    pub fn high_water_mark(&self) -> u64 {
+        // The last buffer is the lowest page
+        let untouched = self
+            .stack_buffers
+            .iter()
+            .rev()
+            .flat_map(|page| page.iter())
+            .take_while(|byte| **byte == KERNEL_STACK_PATTERN)
+            .count();
+        self.stack_size - untouched as u64
+    }
+
     pub fn build(stack_top: u64, data: &[u8], table: &mut PageTable, flags: u64) -> Self {
         let mut stack = ThreadStack::new(stack_top);
         for chunk in data.chunks(PAGE_SIZE) {
@@ -1,7 +1,13 @@
 pub mod executable;
+pub mod fpu;
+pub mod futex;
 pub mod io;
+pub mod limits;
 pub mod memory;
 pub mod proc;
 pub mod scheduler;
 pub mod task;
+pub mod time_page;
+pub mod timer;
 pub mod ui;
+pub mod wait;
@@ -1,14 +1,35 @@
-use core::mem::offset_of;
+use core::{
+    mem::offset_of,
+    sync::atomic::{AtomicU64, Ordering},
+};
 
-use alloc::{boxed::Box, fmt, format, string::String, sync::Arc, vec::Vec};
+use alloc::{
+    boxed::Box,
+    fmt, format,
+    string::String,
+    sync::{Arc, Weak},
+    vec::Vec,
+};
 use spin::Mutex;
 
 use crate::{
-    data::regs::fs_gs_base::{FsBase, GsBase},
+    data::{
+        permissions::Credentials,
+        regs::fs_gs_base::{FsBase, GsBase},
+    },
     gdt::{USERLAND_CODE64_SELECTOR, USERLAND_DATA64_SELECTOR},
+    memory::buddy_alloc::PAGE_SIZE,
     paging::PageTable,
     percpu::get_per_cpu,
-    process::{io::context::ProcessIOContext, task::get_tss_ref, ui::context::UiContext},
+    process::{
+        fpu::{switch_fpu_to, FpuState},
+        io::context::ProcessIOContext,
+        limits::ResourceLimits,
+        task::get_tss_ref,
+        timer::TimerId,
+        ui::context::UiContext,
+        wait::WaitQueue,
+    },
 };
 
 use super::{
@@ -37,13 +58,49 @@ impl fmt::Debug for ProcessAllocatedCode {
     }
 }
 
+pub const DEFAULT_UMASK: u32 = 0o022;
+
 #[derive(Debug, Clone)]
 pub struct ProcessAccess {
+    /// Real ids, of the user that started the process
+    pub uid: u32,
+    pub gid: u32,
+    /// Effective ids, used for permission checks
     pub euid: u32,
     pub egid: u32,
     pub supplementary_gids: Vec<u32>,
 }
 
+impl ProcessAccess {
+    pub fn new(uid: u32, gid: u32, supplementary_gids: Vec<u32>) -> Self {
+        Self {
+            uid,
+            gid,
+            euid: uid,
+            egid: gid,
+            supplementary_gids,
+        }
+    }
+
+    pub fn is_root(&self) -> bool {
+        self.euid == 0
+    }
+
+    /// Whether `other` runs as the same user and group as this process, which lets it change the
+    /// process without being root
+    pub fn owned_by(&self, other: &ProcessAccess) -> bool {
+        [self.uid, self.euid] == [other.uid; 2] && [self.gid, self.egid] == [other.gid; 2]
+    }
+
+    pub fn credentials(&self) -> Credentials<'_> {
+        Credentials::User {
+            uid: self.euid,
+            gid: self.egid,
+            supplementary_gids: &self.supplementary_gids,
+        }
+    }
+}
+
 #[derive(Debug)]
 pub enum TaskState {
     Init,
@@ -56,14 +113,15 @@ pub enum TaskState {
 #[derive(Debug)]
 pub struct Process {
     pub pid: u32,
+    /// 0 for processes started by the kernel
+    pub parent_pid: u32,
     pub name: String,
     pub cmdline: Vec<String>,
     pub cwd: Mutex<String>,
+    /// Permission bits cleared from the mode of files the process creates
+    pub umask: Mutex<u32>,
 
-    pub uid: u32,
-    pub gid: u32,
-
-    pub effective_process_access: Mutex<ProcessAccess>,
+    pub access: Mutex<ProcessAccess>,
 
     pub page_table: Mutex<PageTable>,
     pub pml4: u64,
@@ -78,6 +136,64 @@ pub struct Process {
     pub state: Mutex<TaskState>,
 
     pub io_context: Mutex<ProcessIOContext>,
+    pub limits: Mutex<ResourceLimits>,
+
+    /// Highest `resident_pages` seen, for ru_maxrss
+    pub max_resident_pages: AtomicU64,
+    /// `ITIMER_REAL`, also set by `alarm`
+    pub real_timer: Mutex<Option<TimerId>>,
+    /// Woken when a child exits or a tracee stops, for `wait4`
+    pub child_events: Arc<WaitQueue>,
+}
+
+impl Process {
+    /// Time used by every thread, exited ones included
+    pub fn cpu_times(&self) -> CpuTimes {
+        let threads = self.threads.lock().clone();
+        let zombies = self.zombie_threads.lock().clone();
+        threads
+            .iter()
+            .chain(zombies.iter())
+            .fold(CpuTimes::default(), |total, thread| {
+                total.add(*thread.cpu_times.lock())
+            })
+    }
+
+    /// Pages mapped for the process: its code and the stacks of its threads
+    pub fn resident_pages(&self) -> u64 {
+        let code = self.allocated_code.lock().allocs.len() as u64;
+        let stacks = self
+            .threads
+            .lock()
+            .iter()
+            .map(|thread| thread.stack.lock().stack_size / PAGE_SIZE)
+            .sum::<u64>();
+        code + stacks
+    }
+
+    /// Updates and returns `max_resident_pages`
+    pub fn update_max_resident_pages(&self) -> u64 {
+        let current = self.resident_pages();
+        self.max_resident_pages
+            .fetch_max(current, Ordering::Relaxed)
+            .max(current)
+    }
+}
+
+/// Timer ticks spent running, split by the privilege level the thread was switched away from
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct CpuTimes {
+    pub user: u64,
+    pub kernel: u64,
+}
+
+impl CpuTimes {
+    pub const fn add(self, other: CpuTimes) -> CpuTimes {
+        CpuTimes {
+            user: self.user + other.user,
+            kernel: self.kernel + other.kernel,
+        }
+    }
 }
 
 #[repr(C, packed(8))]
@@ -129,6 +245,57 @@ pub struct Thread {
     pub task_state: Mutex<TaskState>,
 
     pub ui_context: Mutex<UiContext>,
+
+    pub wait: Mutex<ThreadWait>,
+
+    pub cpu_times: Mutex<CpuTimes>,
+
+    /// `CLONE_CHILD_CLEARTID` address, 0 when unset. Zeroed and woken as a futex when the
+    /// thread exits
+    pub clear_child_tid: AtomicU64,
+
+    /// Its FPU registers while another thread uses them
+    pub fpu: Mutex<FpuState>,
+
+    pub trace: Mutex<ThreadTrace>,
+}
+
+/// State of a blocking syscall that is restarted every time the thread wakes up
+#[derive(Debug, Default)]
+pub struct ThreadWait {
+    /// Uptime tick at which the wait times out, kept across restarts
+    pub deadline: Option<u64>,
+    pub timed_out: bool,
+    /// Set when the thread is woken while it isn't blocked, its next block returns at once
+    pub woken: bool,
+    /// Wakes the thread at the deadline
+    pub timer: Option<TimerId>,
+    /// Queues the thread is registered on, it leaves them all when it is woken or exits
+    pub queues: Vec<Weak<WaitQueue>>,
+    /// Address of the futex the thread sleeps on, its wait is over when the syscall runs again
+    pub futex: Option<u64>,
+}
+
+/// ptrace state of a thread
+#[derive(Debug, Default)]
+pub struct ThreadTrace {
+    /// Pid of the process tracing the thread
+    pub tracer: Option<u32>,
+    /// Signal of the stop to enter the next time the thread is scheduled
+    pub pending_stop: Option<u64>,
+    /// Signal of the stop the thread is in, until its tracer resumes it
+    pub stopped: Option<u64>,
+    /// The stop was reported by `wait4`
+    pub reported: bool,
+}
+
+impl ThreadWait {
+    /// Ends a wait that restarted until it succeeded or timed out
+    pub fn finish(&mut self) {
+        self.deadline = None;
+        self.timed_out = false;
+        self.futex = None;
+    }
 }
 
 impl Thread {
@@ -159,6 +326,7 @@ impl Thread {
         let pml4 = self.process.pml4;
 
         let kstack = self.setup_tss_for_thread();
+        switch_fpu_to(self);
 
         let per_cpu = get_per_cpu();
 
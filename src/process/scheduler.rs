@@ -4,22 +4,142 @@ use alloc::{
     sync::Arc,
     vec::Vec,
 };
-use spin::{mutex::Mutex, RwLock};
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use spin::mutex::Mutex;
 
 use crate::{
-    data::file::File,
-    drivers::{fs::virt::pipefs::Pipe, vfs::VfsError},
-    interrupts::handlers::syscall::linux::SIGKILL,
-    paging::{get_kernel_page_table, PageTable, PAGE_ACCESSED, PAGE_PRESENT, PAGE_RW},
-    percpu::{core_id, get_per_cpu, InterruptSource},
-    process::{io::context::ProcessIOContext, ui::context::UiContext},
+    data::{
+        file::File,
+        lock::{DebugMutex, DebugRwLock, LockClass},
+        regs::fs_gs_base::{FsBase, KernelGsBase},
+    },
+    drivers::{
+        fs::virt::{devfs, pipefs::Pipe},
+        vfs::{writeback_file_systems, VfsError},
+    },
+    interrupts::handlers::{irq::irq0_timer::get_uptime_ticks, syscall::linux::SIGKILL},
+    log::get_stdout,
+    log_debug, net,
+    paging::{get_kernel_page_table, PageTable},
+    percpu::{core_id, get_per_cpu, InterruptSource, PerCpu},
+    process::{
+        fpu::{flush_fpu_owner, FpuState},
+        futex::release_process_futexes,
+        io::context::ProcessIOContext,
+        limits::{Resource, ResourceLimits},
+        timer::{run_expired_timers, TimerEvent, TIMERS},
+        ui::context::UiContext,
+    },
 };
 
 use super::{
-    memory::{ProcessHeap, ThreadStack, PROC_KERNEL_STACK_TOP},
-    proc::{Process, ProcessAccess, ProcessAllocatedCode, TaskState, Thread, ThreadState},
+    memory::{ProcessHeap, ThreadStack, PROC_KERNEL_STACK_SLOT, PROC_KERNEL_STACK_TOP},
+    proc::{
+        CpuTimes, Process, ProcessAccess, ProcessAllocatedCode, TaskState, Thread, ThreadState,
+        ThreadTrace, ThreadWait, DEFAULT_UMASK,
+    },
+    wait::WaitQueue,
 };
 
+/// About 2 seconds with the default PIT divider
+const DEVICE_POLL_INTERVAL_TICKS: u64 = 36;
+/// About 5 seconds
+const WRITEBACK_INTERVAL_TICKS: u64 = 91;
+
+static LAST_DEVICE_POLL: AtomicU64 = AtomicU64::new(0);
+static LAST_WRITEBACK: AtomicU64 = AtomicU64::new(0);
+
+/// Charges the ticks since `running_since` to the thread switched away from, by the privilege
+/// level it was interrupted in, or to the idle thread when nothing was running
+fn account_cpu_time(per_cpu: &mut PerCpu, now: u64) {
+    let elapsed = now.wrapping_sub(per_cpu.running_since);
+    per_cpu.running_since = now;
+    match (&per_cpu.running_thread, per_cpu.interrupt_sources.last()) {
+        (Some(thread), Some(InterruptSource::User)) => {
+            thread.thread.cpu_times.lock().user += elapsed
+        }
+        (Some(thread), _) => thread.thread.cpu_times.lock().kernel += elapsed,
+        (None, _) => per_cpu.idle_ticks += elapsed,
+    }
+}
+
+/// Leaves the stack `schedule` was called on for the one of the idle thread. Whatever was on the
+/// old stack is abandoned, like when jumping to userland
+fn enter_idle(per_cpu: &mut PerCpu) -> ! {
+    let top = per_cpu.idle_stack_top();
+    per_cpu.interrupt_sources.clear();
+    unsafe {
+        core::arch::asm!(
+            "mov rsp, {top}",
+            "xor ebp, ebp",
+            "jmp {idle}",
+            top = in(reg) top,
+            idle = sym idle_loop,
+            options(noreturn)
+        )
+    }
+}
+
+/// The idle thread of a CPU, it never is on the run queue. It sleeps until the next interrupt:
+/// the timer may have expired a blocked thread or an interrupt handler may have woken one. Then
+/// `schedule` looks again, and comes back here with a fresh stack if there still is nothing to run
+extern "C" fn idle_loop() -> ! {
+    // Use the time to look for device changes
+    let now = get_uptime_ticks();
+    if now.wrapping_sub(LAST_DEVICE_POLL.load(Ordering::Relaxed)) >= DEVICE_POLL_INTERVAL_TICKS {
+        LAST_DEVICE_POLL.store(now, Ordering::Relaxed);
+        devfs::poll_devices();
+    }
+    if now.wrapping_sub(LAST_WRITEBACK.load(Ordering::Relaxed)) >= WRITEBACK_INTERVAL_TICKS {
+        LAST_WRITEBACK.store(now, Ordering::Relaxed);
+        writeback_file_systems();
+    }
+    // There are no kernel threads, the network timers (DHCP leases, retries) run here too
+    net::poll();
+    // Records printed by interrupt handlers
+    get_stdout().flush();
+
+    unsafe {
+        core::arch::asm!("sti", "hlt", "cli");
+    }
+    SCHEDULER.schedule()
+}
+
+/// Saves the registers of a thread that entered the kernel through `syscall`
+fn copy_syscall_registers(per_cpu: &PerCpu, state: &mut ThreadState) {
+    state.gpregs.rax = per_cpu.syscall_data.rax;
+    state.gpregs.rbx = per_cpu.syscall_data.rbx;
+    state.gpregs.rdx = per_cpu.syscall_data.rdx;
+    state.gpregs.rsi = per_cpu.syscall_data.rsi;
+    state.gpregs.rdi = per_cpu.syscall_data.rdi;
+    state.gpregs.r8 = per_cpu.syscall_data.r8;
+    state.gpregs.r9 = per_cpu.syscall_data.r9;
+    state.gpregs.r10 = per_cpu.syscall_data.r10;
+    // syscall leaves the return address in rcx and rflags in r11, sysret doesn't restore them
+    state.gpregs.rcx = per_cpu.syscall_data.rcx;
+    state.gpregs.r11 = per_cpu.syscall_data.r11;
+    state.gpregs.r12 = per_cpu.syscall_data.r12;
+    state.gpregs.r13 = per_cpu.syscall_data.r13;
+    state.gpregs.r14 = per_cpu.syscall_data.r14;
+    state.gpregs.r15 = per_cpu.syscall_data.r15;
+
+    state.rip = per_cpu.syscall_data.rcx; // Syscall return address
+    state.rsp = per_cpu.syscall_data.rsp; // Syscall process stack
+    state.rbp = per_cpu.syscall_data.rbp; // Syscall process stack base
+    state.rflags = per_cpu.syscall_data.r11; // Syscall rflags
+}
+
+/// Saves the fs and gs bases of a thread interrupted in userland, it may have changed them with
+/// wrfsbase/wrgsbase. Its gs base is the swapped out one while in the kernel
+fn save_user_segment_bases(thread: &ProcThreadInfo) {
+    let mut state = thread.thread.state.lock();
+    unsafe {
+        state.fs_base = FsBase::get();
+        state.gs_base = KernelGsBase::get();
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct ProcThreadInfo {
     pub thread: Arc<Thread>,
@@ -34,15 +154,19 @@ pub struct SchedulerProcessCreateState {
 
 #[derive(Debug)]
 pub struct Scheduler {
-    processes: RwLock<BTreeMap<u32, Arc<Process>>>,
-    threads: RwLock<BTreeMap<u32, ProcThreadInfo>>,
-    proc_create_state: Mutex<SchedulerProcessCreateState>,
+    processes: DebugRwLock<BTreeMap<u32, Arc<Process>>>,
+    threads: DebugRwLock<BTreeMap<u32, ProcThreadInfo>>,
+    proc_create_state: DebugMutex<SchedulerProcessCreateState>,
 
-    task_queue: Mutex<VecDeque<ProcThreadInfo>>,
+    task_queue: DebugMutex<VecDeque<ProcThreadInfo>>,
+    /// Threads paused in a blocking syscall, with the tick at which they time out
+    blocked: DebugMutex<BTreeMap<u32, ProcThreadInfo>>,
+    /// Threads in a ptrace stop, until their tracer resumes them
+    stopped: DebugMutex<BTreeMap<u32, ProcThreadInfo>>,
 
-    thread_settings: Mutex<SchedulerThreadSettings>,
+    thread_settings: DebugMutex<SchedulerThreadSettings>,
 
-    focused_thread: Mutex<Option<ProcThreadInfo>>,
+    focused_thread: DebugMutex<Option<ProcThreadInfo>>,
 }
 
 #[derive(Debug, Clone)]
@@ -62,20 +186,28 @@ impl Default for Scheduler {
 impl Scheduler {
     pub const fn new() -> Scheduler {
         Scheduler {
-            processes: RwLock::new(BTreeMap::new()),
-            threads: RwLock::new(BTreeMap::new()),
-            proc_create_state: Mutex::new(SchedulerProcessCreateState { next_pid: 1 }),
-
-            task_queue: Mutex::new(VecDeque::new()),
-
-            thread_settings: Mutex::new(SchedulerThreadSettings {
-                default_user_stack_pages: 1,
-                default_kernel_stack_pages: 1,
-                max_user_stack_pages: 256,
-                max_kernel_stack_pages: 32,
-            }),
-
-            focused_thread: Mutex::new(None),
+            processes: DebugRwLock::with_class(BTreeMap::new(), LockClass::Scheduler),
+            threads: DebugRwLock::with_class(BTreeMap::new(), LockClass::Scheduler),
+            proc_create_state: DebugMutex::with_class(
+                SchedulerProcessCreateState { next_pid: 1 },
+                LockClass::Scheduler,
+            ),
+
+            task_queue: DebugMutex::with_class(VecDeque::new(), LockClass::Scheduler),
+            blocked: DebugMutex::with_class(BTreeMap::new(), LockClass::Scheduler),
+            stopped: DebugMutex::with_class(BTreeMap::new(), LockClass::Scheduler),
+
+            thread_settings: DebugMutex::with_class(
+                SchedulerThreadSettings {
+                    default_user_stack_pages: 1,
+                    default_kernel_stack_pages: 1,
+                    max_user_stack_pages: 256,
+                    max_kernel_stack_pages: 32,
+                },
+                LockClass::Scheduler,
+            ),
+
+            focused_thread: DebugMutex::with_class(None, LockClass::Scheduler),
         }
     }
 
@@ -83,6 +215,11 @@ impl Scheduler {
         self.processes.read().get(&pid).cloned()
     }
 
+    /// Returns the live processes, ordered by pid
+    pub fn list_processes(&self) -> Vec<Arc<Process>> {
+        self.processes.read().values().cloned().collect()
+    }
+
     pub fn get_thread(&self, tid: u32) -> Option<ProcThreadInfo> {
         self.threads.read().get(&tid).cloned()
     }
@@ -125,27 +262,39 @@ impl Scheduler {
             }
         };
 
+        // Inherited like on fork
+        let limits = match self.get_process(options.parent_pid) {
+            Some(parent) => parent.limits.lock().clone(),
+            None => ResourceLimits::new(&self.get_thread_settings()),
+        };
+        let mut io_context = ProcessIOContext::new_with_stdio(stdin, stdout.1, stderr.1);
+        io_context.file_table.max_fds = limits.soft(Resource::NoFile) as usize;
+
         let process = Arc::new(Process {
             name: options.name.clone(),
             cmdline: options.cmdline,
             cwd: Mutex::new(options.cwd),
+            umask: Mutex::new(DEFAULT_UMASK),
             pid,
+            parent_pid: options.parent_pid,
             page_table: Mutex::new(options.page_table),
             pml4,
-            heap: Mutex::new(ProcessHeap::new()),
-            uid: options.uid,
-            gid: options.gid,
-            effective_process_access: Mutex::new(ProcessAccess {
-                euid: options.uid,
-                egid: options.gid,
-                supplementary_gids: options.supplementary_gids,
-            }),
+            heap: Mutex::new(ProcessHeap::new(options.mmap_base)),
+            access: Mutex::new(ProcessAccess::new(
+                options.uid,
+                options.gid,
+                options.supplementary_gids,
+            )),
             allocated_code: Mutex::new(options.allocated_code),
             syscalls: Mutex::new(options.syscalls),
             threads: Mutex::new(Vec::new()),
             zombie_threads: Mutex::new(Vec::new()),
             state: Mutex::new(TaskState::Init),
-            io_context: Mutex::new(ProcessIOContext::new_with_stdio(stdin, stdout.1, stderr.1)),
+            io_context: Mutex::new(io_context),
+            limits: Mutex::new(limits),
+            max_resident_pages: AtomicU64::new(0),
+            real_timer: Mutex::new(None),
+            child_events: Arc::new(WaitQueue::new()),
         });
 
         let mut pt = process.page_table.lock();
@@ -155,17 +304,17 @@ impl Scheduler {
             tid: pid,
             name: options.name,
             process: process.clone(),
-            kernel_stack: Mutex::new(ThreadStack::new_with_pages(
-                PROC_KERNEL_STACK_TOP,
-                1,
-                &mut pt,
-                PAGE_PRESENT | PAGE_RW | PAGE_ACCESSED,
-            )),
+            kernel_stack: Mutex::new(ThreadStack::new_kernel(PROC_KERNEL_STACK_TOP, 1, &mut pt)),
             stack: Mutex::new(options.main_thread_stack),
             state: Mutex::new(options.main_thread_state),
             running_cpu: Mutex::new(None),
             task_state: Mutex::new(TaskState::Init),
             ui_context: Mutex::new(UiContext::pid_tid(pid, pid)),
+            wait: Mutex::new(ThreadWait::default()),
+            cpu_times: Mutex::new(CpuTimes::default()),
+            clear_child_tid: AtomicU64::new(0),
+            fpu: Mutex::new(FpuState::new()),
+            trace: Mutex::new(ThreadTrace::default()),
         });
 
         drop(pt);
@@ -187,6 +336,80 @@ impl Scheduler {
         Ok((pid, stdout.0, stderr.0))
     }
 
+    /// Starts another thread in the process of `parent`, sharing its address space and files.
+    /// It resumes in userland with `state`
+    pub fn spawn_thread(&self, parent: &ProcThreadInfo, state: ThreadState) -> ProcThreadInfo {
+        let tid = self.get_next_pid();
+        let process = parent.thread.process.clone();
+
+        // Every thread has its own kernel stack in the shared page table
+        let live = process.threads.lock().clone();
+        let zombies = process.zombie_threads.lock().clone();
+        let used = live
+            .iter()
+            .chain(zombies.iter())
+            .map(|thread| thread.kernel_stack.lock().stack_top)
+            .collect::<Vec<_>>();
+        let kernel_stack_top = (0..)
+            .map(|slot| PROC_KERNEL_STACK_TOP - slot * PROC_KERNEL_STACK_SLOT)
+            .find(|top| !used.contains(top))
+            .unwrap();
+        drop(live);
+        drop(zombies);
+
+        // The registers of the parent may still be loaded
+        flush_fpu_owner();
+        let fpu = parent.thread.fpu.lock().clone();
+
+        let kernel_stack_pages = self.get_thread_settings().default_kernel_stack_pages;
+        let mut pt = process.page_table.lock();
+        let thread = Arc::new(Thread {
+            pid: parent.pid,
+            tid,
+            name: parent.thread.name.clone(),
+            process: process.clone(),
+            kernel_stack: Mutex::new(ThreadStack::new_kernel(
+                kernel_stack_top,
+                kernel_stack_pages,
+                &mut pt,
+            )),
+            // Its stack is allocated by the caller, it never grows
+            stack: Mutex::new(ThreadStack::new(0)),
+            state: Mutex::new(state),
+            running_cpu: Mutex::new(None),
+            task_state: Mutex::new(TaskState::Init),
+            ui_context: Mutex::new(UiContext::pid_tid(parent.pid, tid)),
+            wait: Mutex::new(ThreadWait::default()),
+            cpu_times: Mutex::new(CpuTimes::default()),
+            clear_child_tid: AtomicU64::new(0),
+            fpu: Mutex::new(fpu),
+            trace: Mutex::new(ThreadTrace::default()),
+        });
+        drop(pt);
+
+        process.threads.lock().push(thread.clone());
+
+        let proct = ProcThreadInfo {
+            thread,
+            pid: parent.pid,
+            tid,
+        };
+        self.threads.write().insert(tid, proct.clone());
+        self.task_queue.lock().push_back(proct.clone());
+        proct
+    }
+
+    /// Userland registers of the thread running the current syscall, as they are when it returns
+    pub fn current_user_state(&self, thread: &ProcThreadInfo) -> ThreadState {
+        save_user_segment_bases(thread);
+        let mut state = thread.thread.state.lock().clone();
+        let per_cpu = get_per_cpu();
+        if let Some(InterruptSource::Syscall) = per_cpu.interrupt_sources.last() {
+            copy_syscall_registers(per_cpu, &mut state);
+        }
+        state
+    }
+
     pub fn get_thread_settings(&self) -> SchedulerThreadSettings {
         let guard = self.thread_settings.lock();
         let value = (*guard).clone();
@@ -217,6 +440,19 @@ impl Scheduler {
             *lock = None;
             drop(lock);
 
+            // The stack is about to be freed, keep the peak it reached
+            thread.process.update_max_resident_pages();
+            if cfg!(debug_assertions) {
+                let kernel_stack = thread.kernel_stack.lock();
+                log_debug!(
+                    target: "scheduler",
+                    "Thread {} used {} of its {} kernel stack bytes",
+                    tid,
+                    kernel_stack.high_water_mark(),
+                    kernel_stack.stack_size
+                );
+            }
+
             let mut lock = thread.stack.lock();
             lock.free(pt);
             drop(lock);
@@ -230,20 +466,45 @@ impl Scheduler {
             let mut lock = thread.process.zombie_threads.lock();
             lock.push(thread.clone());
             drop(lock);
+            // Only the main thread stands for the process once it exited, threads are joined
+            // through their clear_child_tid futex
+            if tid != thread.pid {
+                self.threads.write().remove(&tid);
+            }
 
             let mut lock = thread.task_state.lock();
             *lock = TaskState::Zombie { exit_code };
             drop(lock);
 
+            self.blocked.lock().remove(&tid);
+            self.stopped.lock().remove(&tid);
+            let mut wait = thread.wait.lock();
+            let timer = wait.timer.take();
+            let queues = core::mem::take(&mut wait.queues);
+            drop(wait);
+            if let Some(timer) = timer {
+                TIMERS.lock().cancel(timer);
+            }
+            for queue in queues.iter().filter_map(|queue| queue.upgrade()) {
+                queue.remove(tid);
+            }
+            let tracer = thread.trace.lock().tracer;
+            if let Some(tracer) = tracer {
+                self.notify_wait(tracer);
+            }
+
             if H && last {
                 drop(ptlock);
 
-                self.handle_process_exit(thread.process.pid, exit_code);
+                self.teardown(thread.process.pid, exit_code);
             }
         }
     }
 
-    pub fn handle_process_exit(&self, pid: u32, exit_code: u64) {
+    /// Ends every thread of the process and frees what it holds: fds, memory and page table,
+    /// timers and waits. It stays a zombie until its parent reaps it. Used by exits and fatal
+    /// signals alike
+    pub fn teardown(&self, pid: u32, exit_code: u64) {
         let mut kpt = get_kernel_page_table().lock();
         unsafe {
             kpt.load();
@@ -255,32 +516,37 @@ impl Scheduler {
             let process: Arc<Process> = p;
             drop(lock);
 
-            let mut ptlock = process.page_table.lock();
-            let pt: &mut PageTable = &mut ptlock;
-
-            let mut lock = process.allocated_code.lock();
-            lock.free(pt);
-            drop(lock);
-
-            let mut lock = process.heap.lock();
-            lock.free(pt);
-            drop(lock);
+            // Before the process state is freed, nothing may fire into it afterwards
+            TIMERS.lock().cancel_owner(pid);
+            process.real_timer.lock().take();
 
             let lock = process.threads.lock();
             let proc_tids = lock.iter().map(|t| t.tid).collect::<Vec<u32>>();
             drop(lock);
 
             for t in proc_tids.into_iter() {
-                drop(ptlock);
                 self.handle_exit_internal::<false>(t, exit_code);
-                ptlock = process.page_table.lock();
             }
+            self.task_queue.lock().retain(|thread| thread.pid != pid);
 
+            process.io_context.lock().file_table.close_all();
+
+            let mut ptlock = process.page_table.lock();
+            process.allocated_code.lock().free(&mut ptlock);
+            process.heap.lock().free(&mut ptlock);
+            // The kernel page table is loaded, nothing runs on this one anymore
+            let pt = core::mem::replace(&mut *ptlock, PageTable::empty());
             drop(ptlock);
+            drop(pt);
+
+            release_process_futexes(pid);
 
             let mut lock = process.state.lock();
             *lock = TaskState::Zombie { exit_code };
             drop(lock);
+
+            self.detach_tracees(pid);
+            self.notify_wait(process.parent_pid);
         }
     }
 
@@ -289,10 +555,35 @@ impl Scheduler {
     }
 
     pub fn remove_zombie(&self, tid: u32) {
-        self.threads.write().remove(&tid);
+        let Some(zombie) = self.threads.write().remove(&tid) else {
+            return;
+        };
+        // Exited threads point back to their process, which would never be freed
+        if tid == zombie.pid {
+            zombie.thread.process.zombie_threads.lock().clear();
+        }
     }
 
     pub fn kill_process(&self, pid: u32) {
+        self.signal_process(pid, SIGKILL)
+    }
+
+    /// There are no signal handlers yet, every signal sent has its default action of
+    /// terminating the process. A traced process stops instead, except for SIGKILL, and its
+    /// tracer decides whether the signal is delivered
+    pub fn signal_process(&self, pid: u32, signal: u64) {
+        let traced = self
+            .get_thread(pid)
+            .is_some_and(|thread| thread.thread.trace.lock().tracer.is_some());
+        if traced && signal != SIGKILL {
+            self.request_stop(pid, signal);
+            return;
+        }
+        self.signal_default_action(pid, signal)
+    }
+
+    /// Terminates the process, even when it is traced
+    pub fn signal_default_action(&self, pid: u32, signal: u64) {
         let lock = self.processes.read();
         let proc_syscall_abi = match lock.get(&pid) {
             Some(p) => {
@@ -309,26 +600,238 @@ impl Scheduler {
         drop(lock);
 
         match proc_syscall_abi {
-            ProcessSyscallABI::Linux => {
-                self.handle_process_exit(pid, 128 + SIGKILL);
+            ProcessSyscallABI::Linux | ProcessSyscallABI::LinuxInt80 => {
+                self.teardown(pid, 128 + signal);
+            }
+        }
+    }
+
+    /// Makes the thread running the current syscall execute it again when it resumes
+    fn rewind_syscall(thread: &ProcThreadInfo, syscall: u64) {
+        // `syscall` and `int 0x80` are both 2 bytes long
+        let per_cpu = get_per_cpu();
+        if let Some(InterruptSource::Syscall) = per_cpu.interrupt_sources.last() {
+            per_cpu.syscall_data.rcx -= 2;
+            per_cpu.syscall_data.rax = syscall;
+        } else {
+            let mut state = thread.thread.state.lock();
+            state.rip -= 2;
+            state.gpregs.rax = syscall;
+        }
+    }
+
+    /// Gives up the CPU, the current syscall runs again the next time the thread is scheduled
+    pub fn restart_current(&self, thread: &ProcThreadInfo, syscall: u64) -> ! {
+        Self::rewind_syscall(thread, syscall);
+        self.schedule()
+    }
+
+    /// Pauses the thread running the current syscall until it is woken with `unblock` (through a
+    /// `WaitQueue`) or `deadline` (uptime tick) is reached. The syscall is executed again when the
+    /// thread resumes. If the thread was woken since it last blocked, it consumes the wake token
+    /// and doesn't pause
+    pub fn block_current(&self, thread: &ProcThreadInfo, syscall: u64, deadline: Option<u64>) -> ! {
+        Self::rewind_syscall(thread, syscall);
+
+        let mut blocked = self.blocked.lock();
+        let mut wait = thread.thread.wait.lock();
+        if core::mem::take(&mut wait.woken) {
+            drop(wait);
+            drop(blocked);
+            self.schedule()
+        }
+        if let Some(deadline) = deadline {
+            let timer = TIMERS
+                .lock()
+                .add(thread.pid, deadline, 0, TimerEvent::Wake(thread.tid));
+            wait.timer = Some(timer);
+        }
+        drop(wait);
+
+        let mut lock = thread.thread.task_state.lock();
+        *lock = TaskState::Paused;
+        drop(lock);
+
+        blocked.insert(thread.tid, thread.clone());
+        drop(blocked);
+
+        self.schedule()
+    }
+
+    /// Makes a blocked thread runnable again and removes it from the queues it waits on. A thread
+    /// that isn't blocked yet keeps a wake token instead. Returns false if there is no such thread
+    pub fn unblock(&self, tid: u32) -> bool {
+        self.unblock_internal(tid, false)
+    }
+
+    /// Wakes a thread whose deadline was reached
+    pub fn unblock_timed_out(&self, tid: u32) {
+        self.unblock_internal(tid, true);
+    }
+
+    fn unblock_internal(&self, tid: u32, timed_out: bool) -> bool {
+        let mut blocked = self.blocked.lock();
+        let (thread, was_blocked) = match blocked.remove(&tid) {
+            Some(thread) => (thread, true),
+            None => match self.threads.read().get(&tid) {
+                Some(thread) => (thread.clone(), false),
+                None => return false,
+            },
+        };
+        let mut wait = thread.thread.wait.lock();
+        if !was_blocked {
+            if !matches!(*thread.thread.task_state.lock(), TaskState::Running) {
+                // Exited, or already woken
+                return false;
             }
+            wait.woken = true;
+        }
+        wait.timed_out |= timed_out;
+        let timer = wait.timer.take();
+        let queues = core::mem::take(&mut wait.queues);
+        drop(wait);
+        drop(blocked);
+
+        if let Some(timer) = timer {
+            TIMERS.lock().cancel(timer);
+        }
+        for queue in queues.iter().filter_map(|queue| queue.upgrade()) {
+            queue.remove(tid);
+        }
+        if !was_blocked {
+            return true;
+        }
+
+        let mut lock = thread.thread.task_state.lock();
+        if !matches!(*lock, TaskState::Paused) {
+            return false;
+        }
+        *lock = TaskState::Init;
+        drop(lock);
+
+        self.task_queue.lock().push_back(thread);
+        true
+    }
+
+    /// Stops a traced thread the next time it would run. A blocked thread is woken first, its
+    /// syscall runs again once it is resumed
+    pub fn request_stop(&self, tid: u32, signal: u64) {
+        let Some(thread) = self.get_thread(tid) else {
+            return;
+        };
+        let mut trace = thread.thread.trace.lock();
+        if trace.tracer.is_none() || trace.stopped.is_some() {
+            return;
+        }
+        trace.pending_stop = Some(signal);
+        drop(trace);
+        self.unblock(tid);
+    }
+
+    /// Stops the running thread if it is traced, for a trap
+    pub fn stop_current(&self, thread: &ProcThreadInfo, signal: u64) -> ! {
+        self.enter_stop(thread.clone(), signal);
+        self.schedule()
+    }
+
+    fn enter_stop(&self, thread: ProcThreadInfo, signal: u64) {
+        let mut trace = thread.thread.trace.lock();
+        let Some(tracer) = trace.tracer else {
+            return;
+        };
+        trace.pending_stop = None;
+        trace.stopped = Some(signal);
+        trace.reported = false;
+        drop(trace);
+
+        let mut lock = thread.thread.task_state.lock();
+        *lock = TaskState::Paused;
+        drop(lock);
+
+        self.stopped.lock().insert(thread.tid, thread);
+        self.notify_wait(tracer);
+    }
+
+    /// Resumes a thread in a ptrace stop, returns false if it isn't stopped
+    pub fn resume_stopped(&self, tid: u32) -> bool {
+        let Some(thread) = self.stopped.lock().remove(&tid) else {
+            return false;
+        };
+        thread.thread.trace.lock().stopped = None;
+
+        let mut lock = thread.thread.task_state.lock();
+        *lock = TaskState::Init;
+        drop(lock);
+
+        self.task_queue.lock().push_back(thread);
+        true
+    }
+
+    /// Stops tracing a thread, it resumes if it was stopped
+    pub fn detach(&self, thread: &ProcThreadInfo) {
+        *thread.thread.trace.lock() = ThreadTrace::default();
+        self.resume_stopped(thread.tid);
+    }
+
+    /// Detaches the threads traced by an exiting process
+    fn detach_tracees(&self, tracer: u32) {
+        let traced = self
+            .threads
+            .read()
+            .values()
+            .filter(|thread| thread.thread.trace.lock().tracer == Some(tracer))
+            .cloned()
+            .collect::<Vec<_>>();
+        for thread in traced.iter() {
+            self.detach(thread);
         }
     }
 
+    /// Wakes the `wait4` callers of a process, when a child exits or a tracee stops
+    pub fn notify_wait(&self, pid: u32) {
+        if let Some(process) = self.get_process(pid) {
+            process.child_events.wake_all();
+        }
+    }
+
+    /// Threads traced by a process, and the main threads of its children. Exited ones stay
+    /// until they are reaped
+    pub fn wait_candidates(&self, pid: u32) -> Vec<ProcThreadInfo> {
+        self.threads
+            .read()
+            .values()
+            .filter(|thread| {
+                thread.thread.trace.lock().tracer == Some(pid)
+                    || (thread.tid == thread.pid && thread.thread.process.parent_pid == pid)
+            })
+            .cloned()
+            .collect()
+    }
+
     pub fn schedule(&self) -> ! {
         unsafe {
             core::arch::asm!("cli");
         }
         'outer: loop {
+            let per_cpu = get_per_cpu();
+            account_cpu_time(per_cpu, get_uptime_ticks());
+            if let (Some(InterruptSource::User | InterruptSource::Syscall), Some(thread)) =
+                (per_cpu.interrupt_sources.last(), &per_cpu.running_thread)
+            {
+                save_user_segment_bases(thread);
+            }
+            // Before the running thread is requeued, an alarm may terminate its process
+            run_expired_timers(get_uptime_ticks());
+
             let mut guard = self.task_queue.lock();
 
-            let per_cpu = get_per_cpu();
             if let (Some(InterruptSource::User | InterruptSource::Syscall), Some(thread)) =
                 (per_cpu.interrupt_sources.last(), &per_cpu.running_thread)
             {
                 let mut ok = false;
                 let slock = thread.thread.task_state.lock();
-                if !matches!(*slock, TaskState::Zombie { .. }) {
+                // Not if it exited, blocked, or was woken by a timer above and is already queued
+                if matches!(*slock, TaskState::Running) {
                     let plock = thread.thread.process.state.lock();
                     if !matches!(*plock, TaskState::Zombie { .. }) {
                         ok = true;
@@ -340,32 +843,25 @@ impl Scheduler {
                     guard.push_back(thread.clone());
                 }
             }
-            let thread: Option<ProcThreadInfo> = guard.pop_front();
             drop(guard);
 
+            // Threads asked to stop by their tracer stop instead of running
+            let thread: Option<ProcThreadInfo> = loop {
+                let Some(thread) = self.task_queue.lock().pop_front() else {
+                    break None;
+                };
+                let stop = thread.thread.trace.lock().pending_stop.take();
+                match stop {
+                    Some(signal) => self.enter_stop(thread, signal),
+                    None => break Some(thread),
+                }
+            };
+
             if let (Some(InterruptSource::Syscall), Some(running)) =
                 (per_cpu.interrupt_sources.last(), &per_cpu.running_thread)
             {
                 let mut state = running.thread.state.lock();
-
-                state.gpregs.rax = per_cpu.syscall_data.rax;
-                state.gpregs.rbx = per_cpu.syscall_data.rbx;
-                state.gpregs.rdx = per_cpu.syscall_data.rdx;
-                state.gpregs.rsi = per_cpu.syscall_data.rsi;
-                state.gpregs.rdi = per_cpu.syscall_data.rdi;
-                state.gpregs.r8 = per_cpu.syscall_data.r8;
-                state.gpregs.r9 = per_cpu.syscall_data.r9;
-                state.gpregs.r10 = per_cpu.syscall_data.r10;
-                state.gpregs.r12 = per_cpu.syscall_data.r12;
-                state.gpregs.r13 = per_cpu.syscall_data.r13;
-                state.gpregs.r14 = per_cpu.syscall_data.r14;
-                state.gpregs.r15 = per_cpu.syscall_data.r15;
-
-                state.rip = per_cpu.syscall_data.rcx; // Syscall return address
-                state.rsp = per_cpu.syscall_data.rsp; // Syscall process stack
-                state.rbp = per_cpu.syscall_data.rbp; // Syscall process stack base
-                state.rflags = per_cpu.syscall_data.r11; // Syscall rflags
-
+                copy_syscall_registers(per_cpu, &mut state);
                 drop(state);
             }
 
@@ -390,7 +886,11 @@ impl Scheduler {
                 // Guard is not dropped here, it will be dropped when an interrupt interrupts this thread
                 core::mem::forget(lock);
 
+                if per_cpu.running_thread.as_ref().map(|running| running.tid) != Some(thread.tid) {
+                    per_cpu.context_switches += 1;
+                }
                 per_cpu.running_thread = Some(thread);
+                per_cpu.held_locks.leave_kernel();
                 if let Some(thread) = &per_cpu.running_thread {
                     thread.thread.jmp_to_userland();
                 } else {
@@ -398,14 +898,11 @@ impl Scheduler {
                 }
             }
 
-            // If there are no threads to run, sleep
-            // This loop will be interrupted by any next interrupt (probably a timer interrupt which will reschedule and never return to here)
-            unsafe {
-                core::arch::asm!("sti");
-            }
-            loop {
-                core::hint::spin_loop();
+            // The running thread (if any) is blocked or dead, forget it so it isn't queued twice
+            if per_cpu.running_thread.take().is_some() {
+                per_cpu.context_switches += 1;
             }
+            enter_idle(per_cpu)
         }
     }
 
@@ -421,11 +918,14 @@ impl Scheduler {
 #[derive(Debug, Clone, Copy)]
 pub enum ProcessSyscallABI {
     Linux = 0,
+    /// Linux syscalls through `int 0x80` only, `syscall` fails with ENOSYS
+    LinuxInt80 = 1,
 }
 
 #[derive(Debug)]
 pub struct CreateProcessOptions {
     pub name: String,
+    pub parent_pid: u32,
     pub cmdline: Vec<String>,
     pub cwd: String,
 
@@ -441,6 +941,7 @@ pub struct CreateProcessOptions {
     pub syscalls: ProcessSyscallABI,
 
     pub main_thread_stack: ThreadStack,
+    pub mmap_base: u64,
 }
 
 pub static SCHEDULER: Scheduler = Scheduler::new();
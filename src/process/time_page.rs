@@ -0,0 +1,168 @@
+//! Page shared read-only with every process, so userland can compute the time without a syscall.
+//!
+//! It is mapped at `PROC_TIME_PAGE`, passed as `AT_TIME_PAGE` in the aux vector, and starts with
+//! a `TimePageData`. The timer interrupt updates it under a sequence lock, readers retry while
+//! the sequence is odd or changed during their read:
+//!
+//! ```text
+//! do {
+//!     seq = page->sequence;            // acquire
+//!     ticks = page->ticks; ...
+//! } while ((seq & 1) || seq != page->sequence);
+//! monotonic_ns = ticks * ns_per_tick;
+//! realtime_ns = boot_unix_ns + monotonic_ns;
+//! ```
+//!
+//! The page belongs to no process: unmapping an address space never frees it, and a copy of an
+//! address space keeps sharing it.
+
+use core::{
+    alloc::Layout,
+    hint::spin_loop,
+    sync::atomic::{fence, AtomicPtr, AtomicU32, AtomicU64, Ordering},
+};
+
+use alloc::alloc::{alloc_zeroed, handle_alloc_error};
+
+use crate::{
+    drivers::time::get_unix_timestamp_ms,
+    interrupts::{
+        handlers::irq::irq0_timer::get_uptime_ticks,
+        pit::{PIT_BASE_FREQUENCY, PIT_FREQUENCY_DIVIDER},
+    },
+    paging::{
+        PageTable, DIRECT_MAPPING_OFFSET, PAGE_ACCESSED, PAGE_NO_EXECUTE, PAGE_PRESENT, PAGE_SIZE,
+        PAGE_USER,
+    },
+    process::memory::PROC_TIME_PAGE,
+};
+
+/// Bumped when the layout of `TimePageData` changes
+pub const TIME_PAGE_VERSION: u32 = 1;
+
+/// Length of a timer tick, rounded down. Both the page and `clock_gettime` use it
+pub const NS_PER_TICK: u64 = PIT_FREQUENCY_DIVIDER as u64 * 1_000_000_000 / PIT_BASE_FREQUENCY;
+
+#[repr(C)]
+#[derive(Debug)]
+pub struct TimePageData {
+    /// Odd while the record is being written
+    pub sequence: AtomicU32,
+    pub version: u32,
+    /// Timer ticks since boot
+    pub ticks: AtomicU64,
+    /// Nanoseconds from the unix epoch to boot
+    pub boot_unix_ns: AtomicU64,
+    pub ns_per_tick: AtomicU64,
+}
+
+/// A consistent copy of the record
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimeSnapshot {
+    pub ticks: u64,
+    pub boot_unix_ns: u64,
+    pub ns_per_tick: u64,
+}
+
+impl TimeSnapshot {
+    pub fn monotonic_ns(&self) -> u64 {
+        self.ticks.saturating_mul(self.ns_per_tick)
+    }
+
+    pub fn realtime_ns(&self) -> u64 {
+        self.boot_unix_ns.saturating_add(self.monotonic_ns())
+    }
+}
+
+static TIME_PAGE: AtomicPtr<TimePageData> = AtomicPtr::new(core::ptr::null_mut());
+
+fn time_page() -> Option<&'static TimePageData> {
+    unsafe { TIME_PAGE.load(Ordering::Acquire).as_ref() }
+}
+
+/// Allocates the page, it is never freed
+pub fn init_time_page() {
+    if time_page().is_some() {
+        return;
+    }
+    let layout = Layout::from_size_align(PAGE_SIZE, PAGE_SIZE).unwrap();
+    let page = unsafe { alloc_zeroed(layout) } as *mut TimePageData;
+    if page.is_null() {
+        handle_alloc_error(layout);
+    }
+    unsafe {
+        (*page).version = TIME_PAGE_VERSION;
+        (*page).ns_per_tick.store(NS_PER_TICK, Ordering::Relaxed);
+    }
+    TIME_PAGE.store(page, Ordering::Release);
+
+    let uptime_ns = get_uptime_ticks().saturating_mul(NS_PER_TICK);
+    let now_ns = get_unix_timestamp_ms().saturating_mul(1_000_000);
+    write_record(|page| {
+        page.boot_unix_ns
+            .store(now_ns.saturating_sub(uptime_ns), Ordering::Relaxed);
+        page.ticks.store(get_uptime_ticks(), Ordering::Relaxed);
+    });
+}
+
+/// Runs `update` with the sequence odd. Only called from the timer interrupt or with interrupts
+/// disabled, so writers never overlap
+fn write_record(update: impl FnOnce(&TimePageData)) {
+    let Some(page) = time_page() else {
+        return;
+    };
+    let sequence = page.sequence.load(Ordering::Relaxed);
+    page.sequence
+        .store(sequence.wrapping_add(1), Ordering::Relaxed);
+    fence(Ordering::Release);
+    update(page);
+    page.sequence
+        .store(sequence.wrapping_add(2), Ordering::Release);
+}
+
+/// Called on every timer tick
+pub fn update_time_page(ticks: u64) {
+    write_record(|page| page.ticks.store(ticks, Ordering::Relaxed));
+}
+
+/// Reads the record like userland does, None before `init_time_page`
+pub fn read_time_page() -> Option<TimeSnapshot> {
+    let page = time_page()?;
+    loop {
+        let sequence = page.sequence.load(Ordering::Acquire);
+        if sequence & 1 != 0 {
+            spin_loop();
+            continue;
+        }
+        let snapshot = TimeSnapshot {
+            ticks: page.ticks.load(Ordering::Relaxed),
+            boot_unix_ns: page.boot_unix_ns.load(Ordering::Relaxed),
+            ns_per_tick: page.ns_per_tick.load(Ordering::Relaxed),
+        };
+        fence(Ordering::Acquire);
+        if page.sequence.load(Ordering::Relaxed) == sequence {
+            return Some(snapshot);
+        }
+    }
+}
+
+/// Physical address of the page
+pub fn time_page_phys() -> Option<u64> {
+    time_page().map(|page| page as *const TimePageData as u64 - DIRECT_MAPPING_OFFSET)
+}
+
+/// Maps the page read-only at `PROC_TIME_PAGE`, returns false before `init_time_page`
+pub fn map_time_page(pt: &mut PageTable) -> bool {
+    let Some(phys) = time_page_phys() else {
+        return false;
+    };
+    unsafe {
+        pt.map_4kb(
+            PROC_TIME_PAGE,
+            phys,
+            PAGE_USER | PAGE_ACCESSED | PAGE_PRESENT | PAGE_NO_EXECUTE,
+            false,
+        )
+        .is_some()
+    }
+}
@@ -0,0 +1,254 @@
+//! Timers of the processes (`alarm`, `setitimer`, timer fds), kept in a hierarchical timer wheel.
+//!
+//! Level `n` of the wheel has slots of `64^n` ticks. A timer is placed in the lowest level whose
+//! range covers it, and moves down a level when the slot it is in comes up, so advancing the wheel
+//! by a tick only looks at the timers expiring during that tick.
+
+use alloc::{
+    collections::{BTreeMap, BTreeSet},
+    sync::{Arc, Weak},
+    vec::Vec,
+};
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use spin::Mutex;
+
+use crate::{
+    interrupts::handlers::syscall::linux::SIGALRM,
+    process::{scheduler::SCHEDULER, wait::WaitQueue},
+};
+
+pub type TimerId = u64;
+
+const WHEEL_BITS: u32 = 6;
+const WHEEL_SLOTS: usize = 1 << WHEEL_BITS;
+const WHEEL_LEVELS: usize = 4;
+/// Timers further away wait in the last level and are placed again when their slot comes up
+const WHEEL_RANGE: u64 = 1 << (WHEEL_BITS * WHEEL_LEVELS as u32);
+
+/// Expirations of a timer fd not read yet
+#[derive(Debug, Default)]
+pub struct ExpirationCounter {
+    count: AtomicU64,
+    pub wait_queue: Arc<WaitQueue>,
+}
+
+impl ExpirationCounter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count.load(Ordering::Relaxed)
+    }
+
+    /// Returns the expirations counted so far and starts again from 0
+    pub fn take(&self) -> u64 {
+        self.count.swap(0, Ordering::Relaxed)
+    }
+
+    pub fn reset(&self) {
+        self.count.store(0, Ordering::Relaxed);
+    }
+
+    fn expire(&self) {
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.wait_queue.wake_all();
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum TimerEvent {
+    /// `ITIMER_REAL` of the process, sends `SIGALRM`
+    Alarm(u32),
+    /// A timer fd, dropped with the fd
+    Count(Weak<ExpirationCounter>),
+    /// Deadline of a blocked thread
+    Wake(u32),
+}
+
+impl TimerEvent {
+    fn fire(&self) {
+        match self {
+            TimerEvent::Alarm(pid) => SCHEDULER.signal_process(*pid, SIGALRM),
+            TimerEvent::Count(counter) => {
+                if let Some(counter) = counter.upgrade() {
+                    counter.expire();
+                }
+            }
+            TimerEvent::Wake(tid) => SCHEDULER.unblock_timed_out(*tid),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Timer {
+    id: TimerId,
+    owner: u32,
+    /// Uptime tick
+    expires: u64,
+    /// Ticks between expirations, 0 for a one shot timer
+    interval: u64,
+    event: TimerEvent,
+}
+
+#[derive(Debug)]
+pub struct TimerWheel {
+    /// Last tick processed
+    now: u64,
+    next_id: TimerId,
+    levels: [[Vec<Timer>; WHEEL_SLOTS]; WHEEL_LEVELS],
+    /// Level and slot of every pending timer
+    positions: BTreeMap<TimerId, (usize, usize)>,
+    /// Pending timers of every process, cancelled when it exits
+    owned: BTreeMap<u32, BTreeSet<TimerId>>,
+}
+
+impl Default for TimerWheel {
+    fn default() -> Self {
+        Self::new(0)
+    }
+}
+
+impl TimerWheel {
+    pub const fn new(now: u64) -> Self {
+        Self {
+            now,
+            next_id: 1,
+            levels: [const { [const { Vec::new() }; WHEEL_SLOTS] }; WHEEL_LEVELS],
+            positions: BTreeMap::new(),
+            owned: BTreeMap::new(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.positions.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.positions.is_empty()
+    }
+
+    fn position(&self, expires: u64) -> (usize, usize) {
+        let expires = expires.clamp(self.now, self.now + WHEEL_RANGE - 1);
+        let delta = expires - self.now;
+        let level = (0..WHEEL_LEVELS)
+            .find(|level| delta < 1 << (WHEEL_BITS * (*level as u32 + 1)))
+            .unwrap_or(WHEEL_LEVELS - 1);
+        let slot = (expires >> (WHEEL_BITS * level as u32)) as usize & (WHEEL_SLOTS - 1);
+        (level, slot)
+    }
+
+    fn insert(&mut self, timer: Timer) {
+        let (level, slot) = self.position(timer.expires);
+        self.positions.insert(timer.id, (level, slot));
+        self.levels[level][slot].push(timer);
+    }
+
+    /// Arms a timer expiring at the uptime tick `expires` (the next tick at the earliest), then
+    /// every `interval` ticks if it isn't 0
+    pub fn add(&mut self, owner: u32, expires: u64, interval: u64, event: TimerEvent) -> TimerId {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.owned.entry(owner).or_default().insert(id);
+        self.insert(Timer {
+            id,
+            owner,
+            expires: expires.max(self.now + 1),
+            interval,
+            event,
+        });
+        id
+    }
+
+    fn find(&self, id: TimerId) -> Option<&Timer> {
+        let (level, slot) = *self.positions.get(&id)?;
+        self.levels[level][slot].iter().find(|timer| timer.id == id)
+    }
+
+    /// Tick of the next expiration and interval of a pending timer
+    pub fn get(&self, id: TimerId) -> Option<(u64, u64)> {
+        self.find(id).map(|timer| (timer.expires, timer.interval))
+    }
+
+    /// Disarms a timer, returns what `get` returned
+    pub fn cancel(&mut self, id: TimerId) -> Option<(u64, u64)> {
+        let (level, slot) = self.positions.remove(&id)?;
+        let bucket = &mut self.levels[level][slot];
+        let timer = bucket.swap_remove(bucket.iter().position(|timer| timer.id == id)?);
+        self.forget_owner(timer.owner, id);
+        Some((timer.expires, timer.interval))
+    }
+
+    fn forget_owner(&mut self, owner: u32, id: TimerId) {
+        if let Some(ids) = self.owned.get_mut(&owner) {
+            ids.remove(&id);
+            if ids.is_empty() {
+                self.owned.remove(&owner);
+            }
+        }
+    }
+
+    /// Disarms every timer of an exiting process
+    pub fn cancel_owner(&mut self, owner: u32) {
+        for id in self.owned.remove(&owner).unwrap_or_default() {
+            if let Some((level, slot)) = self.positions.remove(&id) {
+                self.levels[level][slot].retain(|timer| timer.id != id);
+            }
+        }
+    }
+
+    /// Moves the timers of a slot to the lower levels
+    fn cascade(&mut self, level: usize, slot: usize) {
+        for timer in core::mem::take(&mut self.levels[level][slot]) {
+            self.insert(timer);
+        }
+    }
+
+    /// Processes the ticks until `now`, returns the events of the timers that expired, once per
+    /// expiration
+    pub fn advance(&mut self, now: u64) -> Vec<TimerEvent> {
+        let mut expired = Vec::new();
+        while self.now < now {
+            if self.positions.is_empty() {
+                self.now = now;
+                break;
+            }
+            self.now += 1;
+
+            // Higher levels first, their timers may land in the slot cascaded next
+            let mut levels = 1;
+            while levels < WHEEL_LEVELS && self.now & ((1 << (WHEEL_BITS * levels as u32)) - 1) == 0
+            {
+                levels += 1;
+            }
+            for level in (1..levels).rev() {
+                let slot = (self.now >> (WHEEL_BITS * level as u32)) as usize & (WHEEL_SLOTS - 1);
+                self.cascade(level, slot);
+            }
+
+            let slot = self.now as usize & (WHEEL_SLOTS - 1);
+            for mut timer in core::mem::take(&mut self.levels[0][slot]) {
+                expired.push(timer.event.clone());
+                if timer.interval == 0 {
+                    self.positions.remove(&timer.id);
+                    self.forget_owner(timer.owner, timer.id);
+                } else {
+                    timer.expires = self.now + timer.interval;
+                    self.insert(timer);
+                }
+            }
+        }
+        expired
+    }
+}
+
+pub static TIMERS: Mutex<TimerWheel> = Mutex::new(TimerWheel::new(0));
+
+/// Fires the timers expired at the uptime tick `now`, called by the scheduler
+pub fn run_expired_timers(now: u64) {
+    let expired = TIMERS.lock().advance(now);
+    for event in expired {
+        event.fire();
+    }
+}
@@ -0,0 +1,84 @@
+use alloc::{collections::VecDeque, sync::Arc};
+use spin::Mutex;
+
+use crate::process::scheduler::{ProcThreadInfo, SCHEDULER};
+
+/// Threads blocked until the state of an object changes (data written to a pipe, new device event...)
+///
+/// A woken thread runs its syscall again, which checks the state again and may go back to sleep.
+/// A wake that comes after a thread registered but before it blocked isn't lost, the thread
+/// keeps a wake token and doesn't block
+#[derive(Debug, Default)]
+pub struct WaitQueue {
+    waiters: Mutex<VecDeque<u32>>,
+}
+
+impl WaitQueue {
+    pub const fn new() -> Self {
+        Self {
+            waiters: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    /// Registers a thread, it will be woken by the next `wake_one` or `wake_all`. To wait on
+    /// several queues at once, register on each then `Scheduler::block_current`
+    pub fn register(self: &Arc<Self>, thread: &ProcThreadInfo) {
+        let mut waiters = self.waiters.lock();
+        if waiters.contains(&thread.tid) {
+            return;
+        }
+        waiters.push_back(thread.tid);
+        drop(waiters);
+        thread.thread.wait.lock().queues.push(Arc::downgrade(self));
+    }
+
+    /// Registers the thread, calls `guard_release` to release the lock protecting the state it
+    /// waits on, then blocks it until it is woken or `deadline` (uptime tick) is reached.
+    /// `syscall` runs again when the thread resumes
+    pub fn wait(
+        self: Arc<Self>,
+        thread: &ProcThreadInfo,
+        syscall: u64,
+        deadline: Option<u64>,
+        guard_release: impl FnOnce(),
+    ) -> ! {
+        self.register(thread);
+        guard_release();
+        // Nothing is dropped after blocking
+        drop(self);
+        SCHEDULER.block_current(thread, syscall, deadline)
+    }
+
+    /// Forgets a thread, when it was woken by another queue or exits
+    pub fn remove(&self, tid: u32) {
+        self.waiters.lock().retain(|waiter| *waiter != tid);
+    }
+
+    pub fn len(&self) -> usize {
+        self.waiters.lock().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.waiters.lock().is_empty()
+    }
+
+    /// Wakes the thread registered first, returns false if there is none
+    pub fn wake_one(&self) -> bool {
+        loop {
+            let Some(tid) = self.waiters.lock().pop_front() else {
+                return false;
+            };
+            if SCHEDULER.unblock(tid) {
+                return true;
+            }
+        }
+    }
+
+    /// Wakes every registered thread, they have to register again if they go back to sleep
+    pub fn wake_all(&self) {
+        let waiters = core::mem::take(&mut *self.waiters.lock());
+        for tid in waiters {
+            SCHEDULER.unblock(tid);
+        }
+    }
+}
@@ -1,4 +1,13 @@
-use crate::{obsiboot::ObsiBootKernelParameters, paging::DIRECT_MAPPING_OFFSET};
+use crate::{
+    obsiboot::{ObsiBootFramebuffer, ObsiBootKernelParameters},
+    paging::DIRECT_MAPPING_OFFSET,
+};
+
+pub const VESA_MODE_SUPPORTED: u16 = 1 << 0;
+/// Bit of the mode attributes set for graphics modes
+pub const VESA_MODE_GRAPHICS: u16 = 1 << 4;
+pub const VESA_MODE_LINEAR_FRAMEBUFFER: u16 = 1 << 7;
+pub const VESA_MEMORY_MODEL_DIRECT_COLOR: u8 = 6;
 
 #[repr(C, packed)]
 pub struct VbeInfoBlock {
@@ -62,8 +71,11 @@ impl Iterator for VesaModeIterator {
     type Item = (u16, VesaModeInfoStructure);
 
     fn next(&mut self) -> Option<Self::Item> {
+        if self.index >= self.modes_count {
+            return None;
+        }
         let mode = unsafe { core::ptr::read_volatile(self.video_mode_ptr.add(self.index)) };
-        if self.index >= self.modes_count || mode == 0xFFFF {
+        if mode == 0xFFFF {
             None
         } else {
             let mode_info =
@@ -74,10 +86,20 @@ impl Iterator for VesaModeIterator {
     }
 }
 
+/// Empty when the bootloader didn't gather the VBE info
 pub fn iter_modes(obsiboot: &ObsiBootKernelParameters) -> VesaModeIterator {
+    let (modes_info_ptr, modes_count) = obsiboot.vbe_modes_info();
+    let Some(vbe_info_block_ptr) = obsiboot.vbe_info_block() else {
+        return VesaModeIterator {
+            video_mode_ptr: core::ptr::null(),
+            modes_info_ptr: core::ptr::null(),
+            modes_count: 0,
+            index: 0,
+        };
+    };
     let vbe_info_block = unsafe {
         core::ptr::read_volatile(
-            (obsiboot.vbe_info_block_ptr as u64 + DIRECT_MAPPING_OFFSET) as *const VbeInfoBlock,
+            (vbe_info_block_ptr + DIRECT_MAPPING_OFFSET) as *const VbeInfoBlock,
         )
     };
 
@@ -85,21 +107,41 @@ pub fn iter_modes(obsiboot: &ObsiBootKernelParameters) -> VesaModeIterator {
         + vbe_info_block.video_mode_ptr[0] as u64
         + DIRECT_MAPPING_OFFSET) as *const u16;
 
-    let modes_info_ptr = (obsiboot.vbe_modes_info_ptr as u64 + DIRECT_MAPPING_OFFSET)
-        as *const VesaModeInfoStructure;
+    let modes_info_ptr = (modes_info_ptr + DIRECT_MAPPING_OFFSET) as *const VesaModeInfoStructure;
 
     VesaModeIterator {
         video_mode_ptr,
         modes_info_ptr,
-        modes_count: obsiboot.vbe_mode_info_block_entry_count as usize,
+        modes_count,
         index: 0,
     }
 }
 
+/// A graphics mode describing the framebuffer a version 2 bootloader set up without VBE
+fn framebuffer_mode(framebuffer: ObsiBootFramebuffer) -> VesaModeInfoStructure {
+    let mut mode: VesaModeInfoStructure = unsafe { core::mem::zeroed() };
+    mode.attributes = VESA_MODE_SUPPORTED | VESA_MODE_GRAPHICS | VESA_MODE_LINEAR_FRAMEBUFFER;
+    mode.pitch = framebuffer.pitch as u16;
+    mode.width = framebuffer.width as u16;
+    mode.height = framebuffer.height as u16;
+    mode.planes = 1;
+    mode.bpp = framebuffer.bpp as u8;
+    mode.memory_model = VESA_MEMORY_MODEL_DIRECT_COLOR;
+    mode.framebuffer = framebuffer.address as u32;
+    mode
+}
+
 pub fn parse_current_mode(obsiboot: &ObsiBootKernelParameters) {
+    let Some(vbe_info_block_ptr) = obsiboot.vbe_info_block() else {
+        let Some(framebuffer) = obsiboot.framebuffer() else {
+            panic!("The bootloader gave neither VBE info nor a framebuffer !");
+        };
+        unsafe { CURRENT_MODE = Some(framebuffer_mode(framebuffer)) };
+        return;
+    };
     let vbe_info_block = unsafe {
         core::ptr::read_volatile(
-            (obsiboot.vbe_info_block_ptr as u64 + DIRECT_MAPPING_OFFSET) as *const VbeInfoBlock,
+            (vbe_info_block_ptr + DIRECT_MAPPING_OFFSET) as *const VbeInfoBlock,
         )
     };
 
@@ -107,7 +149,7 @@ pub fn parse_current_mode(obsiboot: &ObsiBootKernelParameters) {
         + vbe_info_block.video_mode_ptr[0] as u64
         + DIRECT_MAPPING_OFFSET) as *const u16;
 
-    let selected_mode = obsiboot.vbe_selected_mode;
+    let selected_mode = obsiboot.vbe_selected_mode();
 
     let mut i = 0;
     let selected_mode_idx = loop {
@@ -122,9 +164,9 @@ pub fn parse_current_mode(obsiboot: &ObsiBootKernelParameters) {
         i += 1;
     };
 
-    let modes_info_ptr = (obsiboot.vbe_modes_info_ptr as u64 + DIRECT_MAPPING_OFFSET)
-        as *const VesaModeInfoStructure;
-    if i >= obsiboot.vbe_mode_info_block_entry_count as usize {
+    let (modes_info_ptr, modes_count) = obsiboot.vbe_modes_info();
+    let modes_info_ptr = (modes_info_ptr + DIRECT_MAPPING_OFFSET) as *const VesaModeInfoStructure;
+    if i >= modes_count {
         panic!(
             "Vesa mode {} not found in modes info block !",
             selected_mode